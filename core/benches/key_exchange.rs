@@ -0,0 +1,56 @@
+//! Session setup and message costs per cipher suite
+//!
+//! `cargo bench --bench key_exchange` measures X25519 alone;
+//! add `--features pq-hybrid` to compare the hybrid suite.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use privmsg_core::crypto::{CipherSuite, CryptoEngine};
+
+fn engine(suite: CipherSuite) -> CryptoEngine {
+    let engine = CryptoEngine::with_suites(&[suite]).unwrap();
+    engine.generate_identity().unwrap();
+    engine
+}
+
+fn bench_suite(c: &mut Criterion, suite: CipherSuite) {
+    let scratch = engine(suite);
+    c.bench_function(&format!("{}/generate_identity", suite.id()), |b| {
+        b.iter(|| scratch.generate_identity().unwrap())
+    });
+
+    let alice = engine(suite);
+    let bob = engine(suite);
+    let bob_pub = bob.get_public_key().unwrap();
+    alice.establish_session("bob", &bob_pub).unwrap();
+    bob.establish_session("alice", &alice.get_public_key().unwrap()).unwrap();
+    let message = "x".repeat(256);
+
+    c.bench_function(&format!("{}/establish_session", suite.id()), |b| {
+        b.iter(|| alice.establish_session("bob", &bob_pub).unwrap())
+    });
+    c.bench_function(&format!("{}/encrypt", suite.id()), |b| {
+        b.iter(|| alice.encrypt_for("bob", &message).unwrap())
+    });
+    let encrypted = alice.encrypt_for("bob", &message).unwrap();
+    c.bench_function(&format!("{}/decrypt", suite.id()), |b| {
+        b.iter(|| bob.decrypt_from("alice", &encrypted).unwrap())
+    });
+    println!(
+        "{}: public key {} chars, {}-byte message {} chars",
+        suite.id(),
+        bob_pub.len(),
+        message.len(),
+        encrypted.len()
+    );
+}
+
+fn key_exchange(c: &mut Criterion) {
+    for suite in CipherSuite::ALL {
+        if suite.is_implemented() {
+            bench_suite(c, suite);
+        }
+    }
+}
+
+criterion_group!(benches, key_exchange);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+//! Feed arbitrary input to every `CryptoEngine` entry point that parses
+//! base64 or ciphertext from the network. Errors are fine; panics aren't.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use privmsg_core::crypto::CryptoEngine;
+
+const PEER: &str = "peer";
+const FILE_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+fuzz_target!(|data: &[u8]| {
+    let engine = CryptoEngine::new();
+    engine.generate_identity().unwrap();
+    let public = engine.get_public_key().unwrap();
+    engine.establish_session(PEER, &public).unwrap();
+
+    let text = String::from_utf8_lossy(data);
+
+    let _ = engine.decrypt_from(PEER, &text);
+    let _ = engine.verify_session_mac(PEER, data, &text);
+    let _ = engine.unwrap_key_from(PEER, &text);
+    let _ = engine.establish_session("other", &text);
+    let _ = engine.decrypt_file(data, FILE_KEY);
+    let _ = engine.decrypt_file(data, &text);
+    if let Ok(mut reader) = engine.decrypting_reader(data, FILE_KEY) {
+        let _ = std::io::Read::read_to_end(&mut reader, &mut Vec::new());
+    }
+
+    // Last, since a successful import replaces the identity
+    let _ = engine.import_identity(&text);
+});
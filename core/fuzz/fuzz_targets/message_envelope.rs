@@ -0,0 +1,19 @@
+//! Parse arbitrary bytes as the client's `MessageEnvelope`; anything that
+//! parses must survive a round trip through JSON. The same bytes are also
+//! decoded as a canonical envelope, which must re-encode to exactly them.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use privmsg_core::models::{CanonicalEnvelope, MessageEnvelope};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(envelope) = serde_json::from_slice::<MessageEnvelope>(data) {
+        let json = serde_json::to_vec(&envelope).expect("parsed envelope re-serializes");
+        serde_json::from_slice::<MessageEnvelope>(&json).expect("serialized envelope parses");
+    }
+
+    if let Ok(envelope) = CanonicalEnvelope::decode(data) {
+        assert_eq!(envelope.encode(), data, "canonical encoding is unique");
+    }
+});
@@ -0,0 +1,49 @@
+//! Android glue
+
+use crate::notifications::{Notification, NotificationSink};
+use jni::objects::{GlobalRef, JObject, JValue};
+use jni::{JNIEnv, JavaVM};
+
+/// Hands notifications to a Java/Kotlin object implementing
+/// `void onNotification(String conversationId, String title, String body)`,
+/// which posts them through `NotificationManager`
+pub struct JniNotificationSink {
+    vm: JavaVM,
+    callback: GlobalRef,
+}
+
+impl JniNotificationSink {
+    pub fn new(env: &mut JNIEnv, callback: JObject) -> jni::errors::Result<Self> {
+        Ok(Self {
+            vm: env.get_java_vm()?,
+            callback: env.new_global_ref(callback)?,
+        })
+    }
+
+    fn post(&self, notification: &Notification) -> jni::errors::Result<()> {
+        // Events arrive on runtime threads the JVM hasn't seen yet
+        let mut env = self.vm.attach_current_thread()?;
+        let conversation_id = env.new_string(&notification.conversation_id)?;
+        let title = env.new_string(notification.title())?;
+        let body = env.new_string(notification.body())?;
+        env.call_method(
+            &self.callback,
+            "onNotification",
+            "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+            &[
+                JValue::Object(&conversation_id),
+                JValue::Object(&title),
+                JValue::Object(&body),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+impl NotificationSink for JniNotificationSink {
+    fn notify(&self, notification: &Notification) {
+        if let Err(e) = self.post(notification) {
+            log::warn!("Failed to post notification: {}", e);
+        }
+    }
+}
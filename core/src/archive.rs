@@ -0,0 +1,236 @@
+//! Conversation archives
+//!
+//! An archive moves conversations between devices and apps without the
+//! server. It is a directory:
+//!
+//! ```text
+//! manifest.json               ArchiveManifest
+//! conversations/0.jsonl       one Message per line, oldest first
+//! conversations/1.jsonl
+//! attachments/<message id>-<file name>
+//! ```
+//!
+//! Messages are stored exactly as this device has them, so ids, statuses,
+//! timestamps, metadata and system events survive a round trip. An
+//! attachment whose file is on this device is copied in, and its
+//! `local_path` is rewritten relative to the archive; on import it is
+//! copied out again and pointed at the new copy. Readers reject a newer
+//! `version` than they know and ignore fields they don't.
+
+use crate::error::{Error, Result};
+use crate::models::{Conversation, Message};
+use crate::storage::LocalStorage;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::{Component, Path};
+
+/// `format` of every archive manifest
+pub const ARCHIVE_FORMAT: &str = "privmsg-archive";
+
+/// Newest archive version this build reads and the one it writes
+pub const ARCHIVE_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const CONVERSATIONS_DIR: &str = "conversations";
+const ATTACHMENTS_DIR: &str = "attachments";
+
+/// Messages written per transaction on import
+const IMPORT_BATCH: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    /// Always [`ARCHIVE_FORMAT`]
+    pub format: String,
+    pub version: u32,
+    /// Unix millis
+    pub created_at: i64,
+    /// Owner of the exported conversations, when logged in
+    #[serde(default)]
+    pub user_id: Option<String>,
+    pub conversations: Vec<ArchivedConversation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedConversation {
+    pub conversation: Conversation,
+    /// Path of its JSONL file, relative to the archive
+    pub messages_file: String,
+    pub message_count: usize,
+}
+
+/// Write `conversation_ids` to a new archive directory at `dest`. It is
+/// written next to `dest` and renamed into place once complete.
+pub(crate) fn export(
+    storage: &LocalStorage,
+    user_id: Option<String>,
+    conversation_ids: &[String],
+    dest: &Path,
+) -> Result<ArchiveManifest> {
+    if dest.exists() {
+        return Err(Error::InvalidArchive(format!(
+            "{} already exists",
+            dest.display()
+        )));
+    }
+    let part = dest.with_extension("part");
+    let _ = std::fs::remove_dir_all(&part);
+
+    let written = write(storage, user_id, conversation_ids, &part).and_then(|manifest| {
+        std::fs::rename(&part, dest)
+            .map(|()| manifest)
+            .map_err(Error::from)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_dir_all(&part);
+    }
+    written
+}
+
+fn write(
+    storage: &LocalStorage,
+    user_id: Option<String>,
+    conversation_ids: &[String],
+    dir: &Path,
+) -> Result<ArchiveManifest> {
+    std::fs::create_dir_all(dir.join(CONVERSATIONS_DIR))?;
+    std::fs::create_dir_all(dir.join(ATTACHMENTS_DIR))?;
+
+    let mut conversations = Vec::with_capacity(conversation_ids.len());
+    for id in conversation_ids {
+        // One at a time, so a large history is never all in memory
+        let Some(export) = storage
+            .export_conversations(std::slice::from_ref(id))?
+            .pop()
+        else {
+            continue;
+        };
+        let messages_file = format!("{}/{}.jsonl", CONVERSATIONS_DIR, conversations.len());
+        let mut file = std::io::BufWriter::new(std::fs::File::create(dir.join(&messages_file))?);
+        for mut message in export.messages.iter().cloned() {
+            if let Some(attachment) = message.attachment.as_mut() {
+                attachment.local_path = attachment
+                    .local_path
+                    .take()
+                    .filter(|path| Path::new(path).is_file())
+                    .map(|path| -> Result<String> {
+                        let name = format!(
+                            "{}/{}-{}",
+                            ATTACHMENTS_DIR,
+                            file_safe(&message.message_id),
+                            file_safe(&attachment.file_name)
+                        );
+                        std::fs::copy(&path, dir.join(&name))?;
+                        Ok(name)
+                    })
+                    .transpose()?;
+            }
+            serde_json::to_writer(&mut file, &message)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+
+        conversations.push(ArchivedConversation {
+            conversation: export.conversation,
+            messages_file,
+            message_count: export.messages.len(),
+        });
+    }
+
+    let manifest = ArchiveManifest {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        created_at: chrono::Utc::now().timestamp_millis(),
+        user_id,
+        conversations,
+    };
+    let mut file = std::io::BufWriter::new(std::fs::File::create(dir.join(MANIFEST_FILE))?);
+    serde_json::to_writer_pretty(&mut file, &manifest)?;
+    file.flush()?;
+    Ok(manifest)
+}
+
+/// Read the archive at `src` into `storage`, copying attachments into
+/// `attachments_dir`. Messages already stored with the same id are
+/// replaced by the archived ones.
+pub(crate) fn import(
+    storage: &LocalStorage,
+    src: &Path,
+    attachments_dir: &Path,
+) -> Result<ArchiveManifest> {
+    let manifest: ArchiveManifest = serde_json::from_reader(std::io::BufReader::new(
+        std::fs::File::open(src.join(MANIFEST_FILE))?,
+    ))?;
+    if manifest.format != ARCHIVE_FORMAT {
+        return Err(Error::InvalidArchive(format!(
+            "Unknown format {}",
+            manifest.format
+        )));
+    }
+    if manifest.version > ARCHIVE_VERSION {
+        return Err(Error::InvalidArchive(format!(
+            "Version {} is newer than this app supports",
+            manifest.version
+        )));
+    }
+
+    for archived in &manifest.conversations {
+        let file = std::fs::File::open(src.join(inside_archive(&archived.messages_file)?))?;
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut message: Message = serde_json::from_str(&line)?;
+            if let Some(attachment) = message.attachment.as_mut() {
+                if let Some(path) = attachment.local_path.take() {
+                    let archived_file = src.join(inside_archive(&path)?);
+                    if archived_file.is_file() {
+                        std::fs::create_dir_all(attachments_dir)?;
+                        let copy =
+                            attachments_dir.join(archived_file.file_name().unwrap_or_default());
+                        std::fs::copy(&archived_file, &copy)?;
+                        attachment.local_path = Some(copy.to_string_lossy().into_owned());
+                    }
+                }
+            }
+            batch.push(message);
+            if batch.len() == IMPORT_BATCH {
+                storage.save_messages(&batch)?;
+                batch.clear();
+            }
+        }
+        storage.save_messages(&batch)?;
+        // Last, as saving messages counts them unread and moves the preview
+        storage.save_conversation(&archived.conversation)?;
+    }
+    Ok(manifest)
+}
+
+/// A path from the archive, refused if it could point outside of it
+fn inside_archive(path: &str) -> Result<&Path> {
+    let relative = Path::new(path);
+    if relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        Ok(relative)
+    } else {
+        Err(Error::InvalidArchive(format!(
+            "Path {} leaves the archive",
+            path
+        )))
+    }
+}
+
+fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
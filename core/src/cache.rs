@@ -0,0 +1,216 @@
+//! Size-bounded in-memory caches
+//!
+//! Maps keyed by peer grow with every user a long-running client meets.
+//! [`LruCache`] keeps at most a set number of entries, dropping the least
+//! recently used, and counts hits, misses and evictions for
+//! `PrivMsgClient::stats`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Counters of one cache, see `PrivMsgClient::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub len: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Share of lookups that found an entry, 0 before the first
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+/// What the client keeps in memory, see `PrivMsgClient::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    pub sessions: CacheStats,
+    pub profiles: CacheStats,
+    pub device_lists: CacheStats,
+    /// Empty while LAN delivery is off
+    pub lan_peers: CacheStats,
+    /// Envelopes received over the local network and not yet polled
+    pub lan_inbox: usize,
+}
+
+/// Capacities of the client's in-memory caches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLimits {
+    /// Encryption sessions; every session is also kept in the database,
+    /// so evicted ones are loaded again when needed
+    pub sessions: usize,
+    /// When each peer's profile was last fetched
+    pub profiles: usize,
+    /// Peers' device lists, each kept for a minute after it is fetched
+    pub device_lists: usize,
+    /// Addresses of contacts' devices seen on the local network
+    pub lan_peers: usize,
+    /// Envelopes received over the local network and not yet polled; more
+    /// are refused, so senders fall back to the server
+    pub lan_inbox: usize,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self {
+            sessions: 1024,
+            profiles: 1024,
+            device_lists: 1024,
+            lan_peers: 256,
+            lan_inbox: 1000,
+        }
+    }
+}
+
+/// A map holding at most `capacity` entries. Inserting into a full cache
+/// evicts the least recently used entry.
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    /// Entries with the tick they were last used at
+    entries: HashMap<K, (V, u64)>,
+    /// Keys by last use, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    /// A capacity of 0 is taken as 1
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.get_mut(key) {
+            self.order.remove(used);
+            *used = self.tick;
+            self.order.insert(self.tick, key.clone());
+        }
+    }
+
+    /// Look up an entry, counting a hit or miss and marking it used
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        if self.entries.contains_key(key) {
+            self.hits += 1;
+            self.touch(key);
+            self.entries.get(key).map(|(value, _)| value)
+        } else {
+            self.misses += 1;
+            None
+        }
+    }
+
+    /// Look up an entry without counting or marking it used
+    pub(crate) fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Insert or replace an entry, evicting the least recently used one
+    /// if the cache is full
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.tick, key);
+        self.shrink();
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+        Some(value)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (value, _))| (key, value))
+    }
+
+    /// Change the capacity, evicting entries that no longer fit
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.shrink();
+    }
+
+    fn shrink(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if self.entries.remove(&key).is_some() {
+                self.evictions += 1;
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            len: self.entries.len(),
+            capacity: self.capacity,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = LruCache::new(2);
+
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        // "a" becomes the most recently used, so "b" goes first
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("c", 3);
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.get(&"b"), None);
+
+        // Replacing and removing aren't evictions
+        cache.insert("c", 4);
+        assert_eq!(cache.remove(&"a"), Some(1));
+        assert_eq!(cache.peek(&"c"), Some(&4));
+
+        cache.insert("d", 5);
+        cache.insert("e", 6);
+        assert_eq!(cache.stats().evictions, 2);
+        assert_eq!(cache.peek(&"c"), None);
+        cache.set_capacity(1);
+        assert_eq!(cache.peek(&"d"), None);
+        assert_eq!(cache.peek(&"e"), Some(&6));
+
+        let stats = cache.stats();
+        assert_eq!((stats.len, stats.capacity), (1, 1));
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 3));
+        assert_eq!(stats.hit_rate(), 0.5);
+    }
+}
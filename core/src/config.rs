@@ -0,0 +1,541 @@
+//! Client configuration for PrivMsg
+
+use crate::cache::CacheLimits;
+use crate::error::{Error, Result};
+use privmsg_proto::flags::{Flag, FlagValues};
+use std::time::Duration;
+
+// ============================================================================
+// Retry Policy
+// ============================================================================
+
+/// Exponential backoff for idempotent requests and WebSocket connects
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before retry number `attempt` (0-based)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+// ============================================================================
+// Typing and Presence
+// ============================================================================
+
+/// Limits on how often typing and presence updates go over the WebSocket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PresencePolicy {
+    /// While the user keeps typing, "typing" is repeated at most this often
+    pub typing_interval: Duration,
+    /// "Stopped typing" is sent after this long without a keystroke
+    pub typing_idle_timeout: Duration,
+    /// Presence changes within this window are merged into the last one
+    pub presence_interval: Duration,
+}
+
+impl Default for PresencePolicy {
+    fn default() -> Self {
+        Self {
+            typing_interval: Duration::from_secs(3),
+            typing_idle_timeout: Duration::from_secs(5),
+            presence_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+// ============================================================================
+// Endpoints
+// ============================================================================
+
+/// One address the server can be reached at
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    #[serde(default = "default_use_tls")]
+    pub use_tls: bool,
+}
+
+fn default_use_tls() -> bool {
+    true
+}
+
+impl Endpoint {
+    pub fn new(host: &str, port: u16, use_tls: bool) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            use_tls,
+        }
+    }
+
+    pub fn http_url(&self) -> String {
+        let scheme = if self.use_tls { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+
+    pub fn ws_url(&self) -> String {
+        let scheme = if self.use_tls { "wss" } else { "ws" };
+        format!("{}://{}:{}/ws", scheme, self.host, self.port)
+    }
+
+    /// Whether the host names this machine; it is not resolved
+    pub fn is_loopback(&self) -> bool {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        host == "localhost"
+            || host.ends_with(".localhost")
+            || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+}
+
+// ============================================================================
+// Transport Security
+// ============================================================================
+
+/// Which endpoints may be reached without TLS
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportSecurity {
+    /// Every endpoint must use TLS
+    #[default]
+    RequireTls,
+    /// Plain connections to this machine only, e.g. a server under development
+    AllowLocalhost,
+    /// Plain connections anywhere; messages stay end-to-end encrypted, but
+    /// tokens and metadata go over the network in the clear
+    AllowInsecure,
+}
+
+impl TransportSecurity {
+    /// Whether `endpoint` may be connected to under this policy
+    pub fn permits(self, endpoint: &Endpoint) -> bool {
+        match self {
+            Self::RequireTls => endpoint.use_tls,
+            Self::AllowLocalhost => endpoint.use_tls || endpoint.is_loopback(),
+            Self::AllowInsecure => true,
+        }
+    }
+}
+
+// ============================================================================
+// Client Config
+// ============================================================================
+
+/// Client configuration
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub server_host: String,
+    pub server_port: u16,
+    pub use_tls: bool,
+    /// Tried in order when the primary server can't be reached
+    pub fallbacks: Vec<Endpoint>,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub retry: RetryPolicy,
+    pub user_agent: String,
+    /// HTTP proxy URL, used for both API requests and WebSocket CONNECT tunnels
+    pub proxy: Option<String>,
+    /// Hex SHA-256 fingerprints of accepted server certificates (DER).
+    /// When set, a matching certificate is trusted instead of the system roots.
+    pub certificate_pins: Vec<String>,
+    /// Interval between WebSocket pings; `None` disables keepalive
+    pub ws_keepalive: Option<Duration>,
+    /// Ping interval while the device runs on battery, see
+    /// `PrivMsgClient::set_power_source`
+    pub battery_keepalive: Option<Duration>,
+    /// Negotiate HTTP/2 via ALPN so API calls share one multiplexed connection
+    pub http2: bool,
+    /// Idle connections kept open per host for reuse
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle pooled connection is kept; `None` keeps it indefinitely
+    pub pool_idle_timeout: Option<Duration>,
+    /// TCP keepalive probe interval for HTTP and WebSocket sockets
+    pub tcp_keepalive: Option<Duration>,
+    /// Send envelopes as binary WebSocket frames in the canonical encoding
+    /// (see [`privmsg_proto::envelope`]) instead of JSON
+    pub binary_envelopes: bool,
+    /// Base64 Ed25519 key the server's client policy must be signed with.
+    /// Without one, the key of the first policy received is pinned.
+    pub policy_key: Option<String>,
+    /// Endpoints without TLS are refused unless this allows them. Certificates
+    /// are always verified; pin a self-signed one instead.
+    pub transport_security: TransportSecurity,
+    /// Capacities of the in-memory caches; see `PrivMsgClient::stats`
+    pub cache_limits: CacheLimits,
+    /// Feature flags to set; `PRIVMSG_FLAGS` and the client policy win over
+    /// these. `binary_envelopes` turns its flag on unless it is set here.
+    pub flags: FlagValues,
+}
+
+impl ClientConfig {
+    pub fn new(host: &str, port: u16, use_tls: bool) -> Self {
+        Self {
+            server_host: host.to_string(),
+            server_port: port,
+            use_tls,
+            fallbacks: Vec::new(),
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            retry: RetryPolicy::default(),
+            user_agent: concat!("privmsg-core/", env!("CARGO_PKG_VERSION")).to_string(),
+            proxy: None,
+            certificate_pins: Vec::new(),
+            ws_keepalive: Some(Duration::from_secs(30)),
+            battery_keepalive: Some(Duration::from_secs(120)),
+            http2: true,
+            pool_max_idle_per_host: 4,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            binary_envelopes: false,
+            policy_key: None,
+            transport_security: TransportSecurity::default(),
+            cache_limits: CacheLimits::default(),
+            flags: FlagValues::new(),
+        }
+    }
+
+    pub fn builder(host: &str, port: u16) -> ClientConfigBuilder {
+        ClientConfigBuilder {
+            config: Self::new(host, port, true),
+        }
+    }
+
+    /// Configuration for the endpoints published by `domain`; see
+    /// [`discover`](crate::discovery::discover)
+    pub fn discover(domain: &str) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::Runtime(e.to_string()))?;
+        let config = crate::block_on(&runtime, Self::discover_async(domain));
+        // Dropping a runtime panics in async code; this one has nothing left
+        runtime.shutdown_background();
+        config
+    }
+
+    /// `discover`, for async callers
+    pub async fn discover_async(domain: &str) -> Result<Self> {
+        let mut endpoints = crate::discovery::discover(domain).await?.into_iter();
+
+        let primary = endpoints
+            .next()
+            .ok_or_else(|| Error::InvalidConfig(format!("No endpoints found for {}", domain)))?;
+        let mut config = Self::new(&primary.host, primary.port, primary.use_tls);
+        config.fallbacks = endpoints.collect();
+        Ok(config)
+    }
+
+    /// The primary endpoint followed by the fallbacks
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        let primary = Endpoint::new(&self.server_host, self.server_port, self.use_tls);
+        std::iter::once(primary)
+            .chain(self.fallbacks.iter().cloned())
+            .collect()
+    }
+
+    /// Whether any endpoint is reached without TLS, so the user should be
+    /// warned
+    pub fn is_insecure(&self) -> bool {
+        self.endpoints().iter().any(|e| !e.use_tls)
+    }
+
+    /// Refuse endpoints without TLS that [`transport_security`](Self::transport_security)
+    /// doesn't allow
+    pub fn check_transport_security(&self) -> Result<()> {
+        match self
+            .endpoints()
+            .into_iter()
+            .find(|e| !self.transport_security.permits(e))
+        {
+            Some(endpoint) => Err(Error::InvalidConfig(format!(
+                "Refusing unencrypted connection to {}; use TLS or allow it with transport_security",
+                endpoint.http_url()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn http_url(&self) -> String {
+        let scheme = if self.use_tls { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, self.server_host, self.server_port)
+    }
+
+    pub fn ws_url(&self) -> String {
+        let scheme = if self.use_tls { "wss" } else { "ws" };
+        format!("{}://{}:{}/ws", scheme, self.server_host, self.server_port)
+    }
+}
+
+// ============================================================================
+// Builder
+// ============================================================================
+
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+}
+
+impl ClientConfigBuilder {
+    pub fn use_tls(mut self, use_tls: bool) -> Self {
+        self.config.use_tls = use_tls;
+        self
+    }
+
+    /// Add an endpoint to try when the ones before it can't be reached
+    pub fn fallback(mut self, host: &str, port: u16, use_tls: bool) -> Self {
+        self.config.fallbacks.push(Endpoint::new(host, port, use_tls));
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = timeout;
+        self
+    }
+
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.config.retry = policy;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.config.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.config.proxy = Some(url.to_string());
+        self
+    }
+
+    /// Pin a certificate by the hex SHA-256 of its DER encoding (colons allowed)
+    pub fn pin_certificate(mut self, sha256: &str) -> Self {
+        self.config
+            .certificate_pins
+            .push(sha256.replace(':', "").to_lowercase());
+        self
+    }
+
+    pub fn ws_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.config.ws_keepalive = interval;
+        self
+    }
+
+    pub fn battery_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.config.battery_keepalive = interval;
+        self
+    }
+
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.config.http2 = enabled;
+        self
+    }
+
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.config.pool_max_idle_per_host = max;
+        self
+    }
+
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.pool_idle_timeout = timeout;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.config.tcp_keepalive = interval;
+        self
+    }
+
+    pub fn binary_envelopes(mut self, enabled: bool) -> Self {
+        self.config.binary_envelopes = enabled;
+        self
+    }
+
+    /// Only accept client policies signed with this base64 Ed25519 key
+    pub fn policy_key(mut self, public_key: &str) -> Self {
+        self.config.policy_key = Some(public_key.to_string());
+        self
+    }
+
+    /// Allow endpoints without TLS; see [`TransportSecurity`]
+    pub fn transport_security(mut self, policy: TransportSecurity) -> Self {
+        self.config.transport_security = policy;
+        self
+    }
+
+    pub fn cache_limits(mut self, limits: CacheLimits) -> Self {
+        self.config.cache_limits = limits;
+        self
+    }
+
+    pub fn flag(mut self, flag: Flag, enabled: bool) -> Self {
+        self.config.flags.insert(flag, enabled);
+        self
+    }
+
+    pub fn build(self) -> Result<ClientConfig> {
+        let config = self.config;
+
+        if config.server_host.is_empty() || config.fallbacks.iter().any(|e| e.host.is_empty()) {
+            return Err(Error::InvalidConfig("Server host is empty".into()));
+        }
+
+        if let Some(ref proxy) = config.proxy {
+            let url = url::Url::parse(proxy)
+                .map_err(|e| Error::InvalidConfig(format!("Invalid proxy URL: {}", e)))?;
+            if url.scheme() != "http" || url.host_str().is_none() {
+                return Err(Error::InvalidConfig(
+                    "Proxy must be an http:// URL with a host".into(),
+                ));
+            }
+        }
+
+        for pin in &config.certificate_pins {
+            if pin.len() != 64 || hex::decode(pin).is_err() {
+                return Err(Error::InvalidConfig(format!(
+                    "Certificate pin is not a hex SHA-256: {}",
+                    pin
+                )));
+            }
+        }
+
+        if config.ws_keepalive == Some(Duration::ZERO) || config.battery_keepalive == Some(Duration::ZERO) {
+            return Err(Error::InvalidConfig("Keepalive interval must be non-zero".into()));
+        }
+
+        if config.tcp_keepalive == Some(Duration::ZERO) {
+            return Err(Error::InvalidConfig("TCP keepalive interval must be non-zero".into()));
+        }
+
+        config.check_transport_security()?;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(10), Duration::from_secs(5));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let pin = "AB:".repeat(31) + "AB";
+        let config = ClientConfig::builder("example.org", 443)
+            .pin_certificate(&pin)
+            .proxy("http://127.0.0.1:3128")
+            .build()
+            .unwrap();
+        assert_eq!(config.certificate_pins, vec!["ab".repeat(32)]);
+
+        assert!(ClientConfig::builder("example.org", 443)
+            .pin_certificate("not-a-pin")
+            .build()
+            .is_err());
+        assert!(ClientConfig::builder("example.org", 443)
+            .proxy("socks5://127.0.0.1:1080")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_fallback_endpoints() {
+        let config = ClientConfig::builder("chat.example.org", 443)
+            .fallback("backup.example.org", 8443, true)
+            .fallback("10.0.0.5", 8080, false)
+            .transport_security(TransportSecurity::AllowInsecure)
+            .build()
+            .unwrap();
+
+        let endpoints = config.endpoints();
+        assert_eq!(endpoints.len(), 3);
+        assert_eq!(endpoints[0].http_url(), config.http_url());
+        assert_eq!(endpoints[1].ws_url(), "wss://backup.example.org:8443/ws");
+        assert_eq!(endpoints[2].http_url(), "http://10.0.0.5:8080");
+
+        assert!(ClientConfig::builder("chat.example.org", 443)
+            .fallback("", 443, true)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_transport_security() {
+        assert!(ClientConfig::builder("chat.example.org", 443).build().is_ok());
+        assert!(ClientConfig::builder("chat.example.org", 80)
+            .use_tls(false)
+            .build()
+            .is_err());
+        // A plain fallback is refused as well
+        assert!(ClientConfig::builder("chat.example.org", 443)
+            .fallback("backup.example.org", 80, false)
+            .build()
+            .is_err());
+
+        for host in ["localhost", "127.0.0.1", "::1", "[::1]", "dev.localhost"] {
+            let config = ClientConfig::builder(host, 8080)
+                .use_tls(false)
+                .transport_security(TransportSecurity::AllowLocalhost)
+                .build()
+                .unwrap();
+            assert!(config.is_insecure(), "{}", host);
+        }
+        assert!(ClientConfig::builder("192.168.1.10", 8080)
+            .use_tls(false)
+            .transport_security(TransportSecurity::AllowLocalhost)
+            .build()
+            .is_err());
+        assert!(ClientConfig::builder("localhost.example.org", 8080)
+            .use_tls(false)
+            .transport_security(TransportSecurity::AllowLocalhost)
+            .build()
+            .is_err());
+
+        let config = ClientConfig::builder("192.168.1.10", 8080)
+            .use_tls(false)
+            .transport_security(TransportSecurity::AllowInsecure)
+            .build()
+            .unwrap();
+        assert!(config.is_insecure());
+        assert!(!ClientConfig::builder("chat.example.org", 443).build().unwrap().is_insecure());
+    }
+}
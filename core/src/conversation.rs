@@ -0,0 +1,87 @@
+//! A handle on one conversation
+//!
+//! `ConversationHandle` wraps the flat `PrivMsgClient` calls that take a
+//! peer id, so code working within a conversation doesn't pass it around:
+//!
+//! ```no_run
+//! # fn demo(client: &privmsg_core::PrivMsgClient) -> privmsg_core::Result<()> {
+//! let chat = client.conversation("bob");
+//! chat.send_text("hi bob")?;
+//! let latest = chat.history(0..50)?;
+//! chat.mark_read()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::Result;
+use crate::events::Subscription;
+use crate::models::{Conversation, Message, PendingMessage, Quote};
+use crate::PrivMsgClient;
+use std::ops::Range;
+use std::path::Path;
+
+/// One conversation, from [`PrivMsgClient::conversation`]
+pub struct ConversationHandle<'a> {
+    client: &'a PrivMsgClient,
+    peer_id: String,
+}
+
+impl<'a> ConversationHandle<'a> {
+    pub(crate) fn new(client: &'a PrivMsgClient, peer_id: &str) -> Self {
+        Self {
+            client,
+            peer_id: peer_id.to_string(),
+        }
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    /// The conversation as listed, or `None` before any message
+    pub fn summary(&self) -> Result<Option<Conversation>> {
+        self.client.storage.get_conversation(&self.peer_id)
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<Message> {
+        self.client.send_message(&self.peer_id, text)
+    }
+
+    /// Send text replying to `quote`; see [`PrivMsgClient::send_reply`]
+    pub fn reply(&self, text: &str, quote: Quote) -> Result<Message> {
+        self.client.send_reply(&self.peer_id, text, quote)
+    }
+
+    /// Send a file from disk without reading it into memory
+    pub fn send_file(&self, path: &Path, mime_type: &str, caption: Option<&str>) -> Result<Message> {
+        self.client
+            .send_file_from_path(&self.peer_id, path, mime_type, caption)
+    }
+
+    pub fn mark_read(&self) -> Result<()> {
+        self.client.mark_conversation_read(&self.peer_id)
+    }
+
+    pub fn typing(&self, is_typing: bool) -> Result<()> {
+        self.client.send_typing(&self.peer_id, is_typing)
+    }
+
+    /// Stored messages by position counting back from the newest, returned
+    /// oldest first: `0..50` is the latest fifty, `50..100` the fifty before
+    pub fn history(&self, range: Range<usize>) -> Result<Vec<Message>> {
+        let limit = range.end.saturating_sub(range.start) as i64;
+        self.client
+            .get_messages(&self.peer_id, limit, range.start as i64)
+    }
+
+    /// Outgoing messages that haven't been sent yet
+    pub fn pending(&self) -> Result<Vec<PendingMessage>> {
+        self.client.pending_outgoing(&self.peer_id)
+    }
+
+    /// Events of this conversation: received messages, read positions from
+    /// other devices, call signals and ephemeral payloads
+    pub fn subscribe(&self) -> Subscription {
+        self.client.event_sender.subscribe(&self.peer_id)
+    }
+}
@@ -1,70 +1,517 @@
 //! E2EE Cryptography for PrivMsg
 //!
-//! Uses X25519 for key exchange and AES-256-GCM for encryption.
+//! Uses X25519 for key exchange and AES-256-GCM for encryption. Files are
+//! encrypted in fixed-size chunks (STREAM construction, shared with the
+//! other clients in `privmsg_proto::stream`) so they can be processed
+//! without holding them in memory.
+//!
+//! Public keys and message ciphertexts name the cipher suite they were made
+//! with, as `<suite>:<base64>`. Untagged values predate suite ids and are
+//! read as [`CipherSuite::X25519Aes256Gcm`]; peers that publish an untagged
+//! key are sent untagged ciphertexts so older clients can still read them.
+//!
+//! Classical sessions can also run a Double Ratchet (see `ratchet`), which
+//! gives every message its own key. Ratchet ciphertexts are written as
+//! `ratchet:<base64>` and only sent to peers known to read them; they are
+//! always accepted.
 
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
     Aes256Gcm, Nonce,
 };
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use curve25519_dalek::{edwards::EdwardsPoint, montgomery::MontgomeryPoint, scalar::Scalar};
+use hmac::{Hmac, Mac};
 use parking_lot::RwLock;
 use rand::RngCore;
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use x25519_dalek::{PublicKey, StaticSecret};
 
+use crate::cache::{CacheStats, LruCache};
 use crate::error::{Error, Result};
+#[cfg(feature = "pq-hybrid")]
+use crate::hybrid;
+use crate::ratchet::Ratchet;
+
+// ============================================================================
+// Cipher Suites
+// ============================================================================
+
+/// The algorithms a key or ciphertext was made with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CipherSuite {
+    /// X25519 key agreement, SHA-256 key derivation, AES-256-GCM
+    X25519Aes256Gcm,
+    /// X25519 combined with ML-KEM-768 (Kyber), so sessions stay safe
+    /// against a future quantum attacker. Needs the `pq-hybrid` feature;
+    /// without it, peers with hybrid keys get X25519 sessions.
+    X25519Kyber768Aes256Gcm,
+}
+
+impl CipherSuite {
+    /// Every suite with an id, implemented or not
+    pub const ALL: [CipherSuite; 2] = [CipherSuite::X25519Aes256Gcm, CipherSuite::X25519Kyber768Aes256Gcm];
+
+    /// Id written in front of keys and ciphertexts
+    pub fn id(self) -> &'static str {
+        match self {
+            CipherSuite::X25519Aes256Gcm => "x25519",
+            CipherSuite::X25519Kyber768Aes256Gcm => "x25519-kyber768",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|suite| suite.id() == id)
+    }
+
+    /// Whether this build can make keys and sessions with the suite
+    pub fn is_implemented(self) -> bool {
+        match self {
+            CipherSuite::X25519Aes256Gcm => true,
+            CipherSuite::X25519Kyber768Aes256Gcm => cfg!(feature = "pq-hybrid"),
+        }
+    }
+}
+
+/// Split a `<suite>:<base64>` value into its suite, body and whether it was
+/// tagged. The base64 alphabet has no `:`, so untagged values can't be
+/// mistaken for tagged ones.
+fn split_suite(value: &str) -> Result<(CipherSuite, &str, bool)> {
+    match value.split_once(':') {
+        Some((id, body)) => {
+            let suite = CipherSuite::from_id(id)
+                .ok_or_else(|| Error::Crypto(format!("Unknown cipher suite: {}", id)))?;
+            Ok((suite, body, true))
+        }
+        None => Ok((CipherSuite::X25519Aes256Gcm, value, false)),
+    }
+}
+
+fn tag_suite(suite: CipherSuite, body: &[u8]) -> String {
+    format!("{}:{}", suite.id(), URL_SAFE_NO_PAD.encode(body))
+}
+
+/// Nonce and AES-256-GCM ciphertext of `data`, for the session store
+fn seal(cipher: &Aes256Gcm, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad })
+        .map_err(|e| Error::Crypto(format!("Encryption failed: {}", e)))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn unseal(cipher: &Aes256Gcm, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < 12 {
+        return Err(Error::Crypto("Invalid stored session".into()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| Error::Crypto("Stored session doesn't open with this identity".into()))
+}
+
+/// Id of the session with one device of a peer, kept apart from the
+/// session with the peer's own key
+pub fn device_session_id(peer_id: &str, device_id: &str) -> String {
+    format!("{}#{}", peer_id, device_id)
+}
+
+fn ratchet_aad(peer_id: &str) -> Vec<u8> {
+    format!("{}/ratchet", peer_id).into_bytes()
+}
+
+// ============================================================================
+// Engine
+// ============================================================================
 
 /// Crypto engine for E2EE operations
 pub struct CryptoEngine {
+    /// Suites accepted from peers, most preferred first; new identities use
+    /// the first
+    suites: Vec<CipherSuite>,
+    identity_suite: RwLock<CipherSuite>,
     identity_secret: RwLock<Option<StaticSecret>>,
     identity_public: RwLock<Option<PublicKey>>,
-    sessions: RwLock<HashMap<String, SessionKeys>>,
+    #[cfg(feature = "pq-hybrid")]
+    identity_kem: RwLock<Option<hybrid::KemKeys>>,
+    sessions: parking_lot::Mutex<LruCache<String, Arc<SessionKeys>>>,
+    /// Where sessions are kept across restarts and evictions from
+    /// `sessions`
+    session_store: RwLock<Option<Arc<dyn SessionStore>>>,
+    /// Whether messages go out over ratchets where the peer reads them
+    ratchet: Arc<AtomicBool>,
+}
+
+/// Keeps the engine's sessions across restarts, see
+/// [`CryptoEngine::set_session_store`]
+pub trait SessionStore: Send + Sync {
+    fn save_session(&self, peer_id: &str, session: &StoredSession) -> Result<()>;
+    fn load_session(&self, peer_id: &str) -> Result<Option<StoredSession>>;
+    /// Every kept session with its peer, most recently made first
+    fn load_sessions(&self) -> Result<Vec<(String, StoredSession)>>;
+    fn delete_session(&self, peer_id: &str) -> Result<()>;
+}
+
+/// A classical session as kept by a [`SessionStore`]. The shared secret is
+/// sealed under a key derived from the identity key, so the store never
+/// holds it in the clear and sessions don't outlive the identity.
+#[derive(Clone)]
+pub struct StoredSession {
+    pub suite: CipherSuite,
+    pub tagged: bool,
+    /// Nonce and AES-256-GCM ciphertext of the shared secret. Sessions kept
+    /// before secrets were sealed hold the bare 32-byte secret.
+    pub sealed_secret: Vec<u8>,
+    /// The peer's X25519 identity key; none for sessions kept before
+    /// ratchets, which need it
+    pub peer_key: Option<[u8; 32]>,
+    /// The session's ratchet state, sealed like the secret
+    pub sealed_ratchet: Option<Vec<u8>>,
+    pub created_at: i64,
 }
 
+/// Label of the key stored sessions are sealed with
+const SESSION_STORE_LABEL: &[u8] = b"privmsg-session-store";
+/// Prefix of ratchet ciphertexts, in place of a suite id
+const RATCHET_TAG: &str = "ratchet:";
+/// Nonce, sealed secret and tag
+const SEALED_SECRET_LEN: usize = 12 + 32 + 16;
+
+/// Sessions kept in memory unless `set_session_capacity` says otherwise
+const DEFAULT_SESSION_CAPACITY: usize = 1024;
+
 struct SessionKeys {
+    /// Suite messages to the peer are encrypted with
+    suite: CipherSuite,
+    /// Whether the peer's key carried a suite id, i.e. whether it reads
+    /// tagged ciphertexts
+    tagged: bool,
+    /// X25519 secret; MACs and wrapped keys always use this
     shared_secret: [u8; 32],
+    /// The peer's X25519 identity key, which a ratchet starts from
+    peer_key: Option<[u8; 32]>,
+    /// Set once the peer is known to read ratchet ciphertexts
+    ratchet: parking_lot::Mutex<Option<Ratchet>>,
+    /// Hybrid sessions: the KEM ciphertext sent with every message to the
+    /// peer and the message key it gives
+    #[cfg(feature = "pq-hybrid")]
+    outbound: Option<(Vec<u8>, [u8; 32])>,
+    /// The peer's last KEM ciphertext and the key it gave; peers reuse one
+    /// for the whole session, so decapsulation is usually skipped
+    #[cfg(feature = "pq-hybrid")]
+    inbound: parking_lot::Mutex<Option<(Vec<u8>, [u8; 32])>>,
     created_at: i64,
 }
 
+impl SessionKeys {
+    /// Whether the session can be kept by a `SessionStore`. Hybrid sessions
+    /// aren't kept: their KEM state is never written out, so they are made
+    /// again from the peer's key.
+    fn is_storable(&self) -> bool {
+        #[cfg(feature = "pq-hybrid")]
+        if self.outbound.is_some() {
+            return false;
+        }
+        true
+    }
+
+    /// Key for messages to the peer and the KEM ciphertext to send with them
+    fn outbound_key(&self) -> (&[u8; 32], &[u8]) {
+        #[cfg(feature = "pq-hybrid")]
+        if let Some((ref ciphertext, ref key)) = self.outbound {
+            return (key, ciphertext);
+        }
+        (&self.shared_secret, &[])
+    }
+}
+
+/// Length of the ML-KEM-768 encapsulation key following the X25519 key in
+/// hybrid public keys
+const MLKEM_PUBLIC_KEY_LEN: usize = 1184;
+
 impl CryptoEngine {
+    /// Engine accepting every suite this build implements, hybrid first
     pub fn new() -> Self {
+        let suites: Vec<CipherSuite> = [CipherSuite::X25519Kyber768Aes256Gcm, CipherSuite::X25519Aes256Gcm]
+            .into_iter()
+            .filter(|suite| suite.is_implemented())
+            .collect();
+        Self::from_suites(suites)
+    }
+
+    /// Engine accepting only `suites`, most preferred first. Every suite
+    /// must be implemented.
+    pub fn with_suites(suites: &[CipherSuite]) -> Result<Self> {
+        if suites.is_empty() {
+            return Err(Error::Crypto("No cipher suites".into()));
+        }
+        if let Some(suite) = suites.iter().find(|suite| !suite.is_implemented()) {
+            return Err(Error::Crypto(format!("Cipher suite not implemented: {}", suite.id())));
+        }
+        Ok(Self::from_suites(suites.to_vec()))
+    }
+
+    fn from_suites(suites: Vec<CipherSuite>) -> Self {
         Self {
+            identity_suite: RwLock::new(suites[0]),
+            suites,
             identity_secret: RwLock::new(None),
             identity_public: RwLock::new(None),
-            sessions: RwLock::new(HashMap::new()),
+            #[cfg(feature = "pq-hybrid")]
+            identity_kem: RwLock::new(None),
+            sessions: parking_lot::Mutex::new(LruCache::new(DEFAULT_SESSION_CAPACITY)),
+            session_store: RwLock::new(None),
+            ratchet: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Generate new identity key pair
+    /// Switch for sending over ratchets, off by default. Peers' ratchet
+    /// ciphertexts are read either way.
+    pub fn set_ratchet_enabled(&self, enabled: bool) {
+        self.ratchet.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn ratchet_switch(&self) -> Arc<AtomicBool> {
+        self.ratchet.clone()
+    }
+
+    /// Keep every classical session in `store` as it is made, loading it
+    /// back when the peer is next seen or on [`Self::restore_sessions`]
+    pub fn set_session_store(&self, store: Arc<dyn SessionStore>) {
+        *self.session_store.write() = Some(store);
+    }
+
+    /// Load kept sessions into memory, the most recent ones first and no
+    /// more than fit; returns how many were loaded. Sessions that don't
+    /// open with the current identity are skipped and made again when the
+    /// peer is next seen.
+    pub fn restore_sessions(&self) -> Result<usize> {
+        let Some(store) = self.session_store.read().clone() else {
+            return Ok(0);
+        };
+        let cipher = self.store_cipher()?;
+        let capacity = self.sessions.lock().stats().capacity;
+
+        let mut restored = Vec::new();
+        for (peer_id, stored) in store.load_sessions()? {
+            if restored.len() == capacity {
+                break;
+            }
+            match Self::open_session(&cipher, &peer_id, &stored) {
+                Ok(session) => {
+                    if stored.sealed_secret.len() == 32 {
+                        self.persist(&peer_id, &session);
+                    }
+                    restored.push((peer_id, Arc::new(session)));
+                }
+                Err(e) => log::debug!("Skipping kept session with {}: {}", peer_id, e),
+            }
+        }
+
+        // Oldest first, so the most recent are the last to be evicted
+        let count = restored.len();
+        let mut sessions = self.sessions.lock();
+        for (peer_id, session) in restored.into_iter().rev() {
+            if sessions.peek(&peer_id).is_none() {
+                sessions.insert(peer_id, session);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Cipher stored sessions are sealed with, derived from the identity
+    /// key
+    fn store_cipher(&self) -> Result<Aes256Gcm> {
+        let guard = self.identity_secret.read();
+        let secret = guard.as_ref().ok_or(Error::Crypto("No identity".into()))?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(SESSION_STORE_LABEL);
+        hasher.update(secret.as_bytes());
+        Aes256Gcm::new_from_slice(&hasher.finalize())
+            .map_err(|e| Error::Crypto(format!("Cipher init failed: {}", e)))
+    }
+
+    /// Seal a session for the store. The peer id is authenticated with
+    /// the secret, so rows can't be swapped between peers.
+    fn seal_session(cipher: &Aes256Gcm, peer_id: &str, session: &SessionKeys) -> Result<StoredSession> {
+        let sealed_ratchet = match *session.ratchet.lock() {
+            Some(ref ratchet) => Some(seal(cipher, &ratchet_aad(peer_id), &ratchet.to_bytes()?)?),
+            None => None,
+        };
+        Ok(StoredSession {
+            suite: session.suite,
+            tagged: session.tagged,
+            sealed_secret: seal(cipher, peer_id.as_bytes(), &session.shared_secret)?,
+            peer_key: session.peer_key,
+            sealed_ratchet,
+            created_at: session.created_at,
+        })
+    }
+
+    fn open_session(cipher: &Aes256Gcm, peer_id: &str, stored: &StoredSession) -> Result<SessionKeys> {
+        let shared_secret = match stored.sealed_secret.len() {
+            32 => stored.sealed_secret.clone(),
+            SEALED_SECRET_LEN => unseal(cipher, peer_id.as_bytes(), &stored.sealed_secret)?,
+            _ => return Err(Error::Crypto("Invalid stored session".into())),
+        };
+        let shared_secret = <[u8; 32]>::try_from(shared_secret)
+            .map_err(|_| Error::Crypto("Invalid stored session".into()))?;
+        let ratchet = match stored.sealed_ratchet {
+            Some(ref sealed) => Some(Ratchet::from_bytes(&unseal(cipher, &ratchet_aad(peer_id), sealed)?)?),
+            None => None,
+        };
+
+        Ok(SessionKeys {
+            suite: stored.suite,
+            tagged: stored.tagged,
+            shared_secret,
+            peer_key: stored.peer_key,
+            ratchet: parking_lot::Mutex::new(ratchet),
+            #[cfg(feature = "pq-hybrid")]
+            outbound: None,
+            #[cfg(feature = "pq-hybrid")]
+            inbound: Default::default(),
+            created_at: stored.created_at,
+        })
+    }
+
+    /// Write a session through to the session store. A hybrid session
+    /// replaces any kept classical one, which would otherwise come back
+    /// after a restart as a downgrade.
+    fn persist(&self, peer_id: &str, session: &SessionKeys) {
+        let Some(store) = self.session_store.read().clone() else {
+            return;
+        };
+        let result = if session.is_storable() {
+            self.store_cipher()
+                .and_then(|cipher| Self::seal_session(&cipher, peer_id, session))
+                .and_then(|stored| store.save_session(peer_id, &stored))
+        } else {
+            store.delete_session(peer_id)
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to keep session with {}: {}", peer_id, e);
+        }
+    }
+
+    /// Number of sessions kept in memory
+    pub fn set_session_capacity(&self, capacity: usize) {
+        self.sessions.lock().set_capacity(capacity);
+    }
+
+    pub fn session_stats(&self) -> CacheStats {
+        self.sessions.lock().stats()
+    }
+
+    /// The session with a peer, loaded from the session store if it isn't
+    /// in memory
+    fn session(&self, peer_id: &str) -> Option<Arc<SessionKeys>> {
+        let mut sessions = self.sessions.lock();
+        if let Some(session) = sessions.get(&peer_id.to_string()) {
+            return Some(session.clone());
+        }
+        let store = self.session_store.read().clone()?;
+        let session = store.load_session(peer_id).and_then(|stored| {
+            let Some(stored) = stored else {
+                return Ok(None);
+            };
+            let session = Self::open_session(&self.store_cipher()?, peer_id, &stored)?;
+            // Sealed now if it was kept before secrets were
+            if stored.sealed_secret.len() == 32 {
+                self.persist(peer_id, &session);
+            }
+            Ok(Some(session))
+        });
+        let session = match session {
+            Ok(session) => Arc::new(session?),
+            Err(e) => {
+                log::warn!("Failed to load session with {}: {}", peer_id, e);
+                return None;
+            }
+        };
+        sessions.insert(peer_id.to_string(), session.clone());
+        Some(session)
+    }
+
+    fn require_session(&self, peer_id: &str) -> Result<Arc<SessionKeys>> {
+        self.session(peer_id)
+            .ok_or_else(|| Error::NoSession(peer_id.to_string()))
+    }
+
+    /// Suites accepted from peers, most preferred first
+    pub fn suites(&self) -> &[CipherSuite] {
+        &self.suites
+    }
+
+    /// Suite this engine's identity key is for
+    pub fn identity_suite(&self) -> CipherSuite {
+        *self.identity_suite.read()
+    }
+
+    fn check_suite(&self, suite: CipherSuite) -> Result<()> {
+        if !self.suites.contains(&suite) {
+            return Err(Error::Crypto(format!("Unsupported cipher suite: {}", suite.id())));
+        }
+        Ok(())
+    }
+
+    /// Generate new identity key pair for the most preferred suite
     pub fn generate_identity(&self) -> Result<()> {
+        let suite = self.suites[0];
         let secret = StaticSecret::random_from_rng(OsRng);
         let public = PublicKey::from(&secret);
 
         *self.identity_secret.write() = Some(secret);
         *self.identity_public.write() = Some(public);
+        #[cfg(feature = "pq-hybrid")]
+        {
+            *self.identity_kem.write() =
+                (suite == CipherSuite::X25519Kyber768Aes256Gcm).then(hybrid::KemKeys::generate);
+        }
+        *self.identity_suite.write() = suite;
 
         Ok(())
     }
 
     /// Import identity from base64 private key
     pub fn import_identity(&self, private_key_b64: &str) -> Result<()> {
+        let (suite, body, _) = split_suite(private_key_b64)?;
+        self.check_suite(suite)?;
         let bytes = URL_SAFE_NO_PAD
-            .decode(private_key_b64)
+            .decode(body)
             .map_err(|e| Error::Crypto(format!("Invalid base64: {}", e)))?;
 
-        if bytes.len() != 32 {
+        // Hybrid keys carry the ML-KEM key after the X25519 one
+        if bytes.len() < 32 || (suite == CipherSuite::X25519Aes256Gcm && bytes.len() != 32) {
             return Err(Error::Crypto("Invalid key length".into()));
         }
+        #[cfg(feature = "pq-hybrid")]
+        let kem = match suite {
+            CipherSuite::X25519Kyber768Aes256Gcm => Some(hybrid::KemKeys::from_bytes(&bytes[32..])?),
+            CipherSuite::X25519Aes256Gcm => None,
+        };
 
         let mut key_bytes = [0u8; 32];
-        key_bytes.copy_from_slice(&bytes);
+        key_bytes.copy_from_slice(&bytes[..32]);
 
         let secret = StaticSecret::from(key_bytes);
         let public = PublicKey::from(&secret);
 
         *self.identity_secret.write() = Some(secret);
         *self.identity_public.write() = Some(public);
+        #[cfg(feature = "pq-hybrid")]
+        {
+            *self.identity_kem.write() = kem;
+        }
+        *self.identity_suite.write() = suite;
 
         Ok(())
     }
@@ -73,28 +520,65 @@ impl CryptoEngine {
     pub fn export_identity(&self) -> Result<String> {
         let guard = self.identity_secret.read();
         let secret = guard.as_ref().ok_or(Error::Crypto("No identity".into()))?;
-        Ok(URL_SAFE_NO_PAD.encode(secret.as_bytes()))
+        #[allow(unused_mut)]
+        let mut bytes = secret.as_bytes().to_vec();
+        #[cfg(feature = "pq-hybrid")]
+        if let Some(ref kem) = *self.identity_kem.read() {
+            bytes.extend(kem.secret_bytes());
+        }
+        Ok(tag_suite(self.identity_suite(), &bytes))
     }
 
-    /// Get public key as base64
+    /// Forget the identity key and every session key
+    pub fn clear(&self) {
+        *self.identity_secret.write() = None;
+        *self.identity_public.write() = None;
+        #[cfg(feature = "pq-hybrid")]
+        {
+            *self.identity_kem.write() = None;
+        }
+        self.sessions.lock().clear();
+    }
+
+    /// Get public key as base64, tagged with its suite
     pub fn get_public_key(&self) -> Result<String> {
         let guard = self.identity_public.read();
         let public = guard.as_ref().ok_or(Error::Crypto("No identity".into()))?;
-        Ok(URL_SAFE_NO_PAD.encode(public.as_bytes()))
+        #[allow(unused_mut)]
+        let mut bytes = public.as_bytes().to_vec();
+        #[cfg(feature = "pq-hybrid")]
+        if let Some(ref kem) = *self.identity_kem.read() {
+            bytes.extend(kem.public_bytes());
+        }
+        Ok(tag_suite(self.identity_suite(), &bytes))
     }
 
     /// Establish session with another user
     pub fn establish_session(&self, peer_id: &str, peer_public_key_b64: &str) -> Result<()> {
+        let (key_suite, body, tagged) = split_suite(peer_public_key_b64)?;
         let peer_bytes = URL_SAFE_NO_PAD
-            .decode(peer_public_key_b64)
+            .decode(body)
             .map_err(|e| Error::Crypto(format!("Invalid peer key: {}", e)))?;
 
-        if peer_bytes.len() != 32 {
+        let key_len = match key_suite {
+            CipherSuite::X25519Aes256Gcm => 32,
+            CipherSuite::X25519Kyber768Aes256Gcm => 32 + MLKEM_PUBLIC_KEY_LEN,
+        };
+        if peer_bytes.len() != key_len {
             return Err(Error::Crypto("Invalid peer key length".into()));
         }
 
+        // A hybrid key starts with the peer's X25519 key, so without the
+        // hybrid suite fall back to that alone
+        let suite = if self.suites.contains(&key_suite) {
+            key_suite
+        } else {
+            CipherSuite::X25519Aes256Gcm
+        };
+        self.check_suite(suite)?;
+
         let mut peer_key_bytes = [0u8; 32];
-        peer_key_bytes.copy_from_slice(&peer_bytes);
+        peer_key_bytes.copy_from_slice(&peer_bytes[..32]);
         let peer_public = PublicKey::from(peer_key_bytes);
 
         let secret_guard = self.identity_secret.read();
@@ -103,6 +587,7 @@ impl CryptoEngine {
             .ok_or(Error::Crypto("No identity".into()))?;
 
         let shared = our_secret.diffie_hellman(&peer_public);
+        drop(secret_guard);
 
         // Derive 256-bit key using SHA-256
         let mut hasher = Sha256::new();
@@ -112,29 +597,125 @@ impl CryptoEngine {
         let mut shared_secret = [0u8; 32];
         shared_secret.copy_from_slice(&derived);
 
+        #[cfg(feature = "pq-hybrid")]
+        let outbound = match suite {
+            CipherSuite::X25519Kyber768Aes256Gcm => {
+                let (ciphertext, kem_secret) = hybrid::encapsulate(&peer_bytes[32..])?;
+                Some((ciphertext, hybrid::combine(&shared_secret, &kem_secret)))
+            }
+            CipherSuite::X25519Aes256Gcm => None,
+        };
+
+        // Making the same session again keeps its ratchet
+        let ratchet = self
+            .session(peer_id)
+            .filter(|old| old.suite == suite && old.shared_secret == shared_secret)
+            .and_then(|old| old.ratchet.lock().clone());
+
         let session = SessionKeys {
+            suite,
+            tagged,
             shared_secret,
+            peer_key: Some(peer_key_bytes),
+            ratchet: parking_lot::Mutex::new(ratchet),
+            #[cfg(feature = "pq-hybrid")]
+            outbound,
+            #[cfg(feature = "pq-hybrid")]
+            inbound: Default::default(),
             created_at: chrono::Utc::now().timestamp(),
         };
 
-        self.sessions.write().insert(peer_id.to_string(), session);
+        self.persist(peer_id, &session);
+        self.sessions.lock().insert(peer_id.to_string(), Arc::new(session));
 
         Ok(())
     }
 
+    /// Establish a session with one device of a peer, under
+    /// [`device_session_id`]. Messages to the device are encrypted with
+    /// `encrypt_for` on that id.
+    pub fn establish_device_session(
+        &self,
+        peer_id: &str,
+        device_id: &str,
+        device_public_key_b64: &str,
+    ) -> Result<()> {
+        self.establish_session(&device_session_id(peer_id, device_id), device_public_key_b64)
+    }
+
+    /// Forget the session with a peer; returns whether there was one
+    pub fn remove_session(&self, peer_id: &str) -> bool {
+        let mut removed = self.sessions.lock().remove(&peer_id.to_string()).is_some();
+        if let Some(store) = self.session_store.read().as_ref() {
+            removed |= matches!(store.load_session(peer_id), Ok(Some(_)));
+            if let Err(e) = store.delete_session(peer_id) {
+                log::warn!("Failed to delete session with {}: {}", peer_id, e);
+            }
+        }
+        removed
+    }
+
     /// Check if we have a session with a peer
     pub fn has_session(&self, peer_id: &str) -> bool {
-        self.sessions.read().contains_key(peer_id)
+        self.session(peer_id).is_some()
+    }
+
+    /// The peer said it reads ratchet ciphertexts: start a ratchet on the
+    /// session unless it has one. Returns whether the session has one now;
+    /// hybrid sessions don't ratchet.
+    pub fn start_ratchet(&self, peer_id: &str) -> Result<bool> {
+        let session = self.require_session(peer_id)?;
+        if !session.is_storable() {
+            return Ok(false);
+        }
+        let mut ratchet = session.ratchet.lock();
+        if ratchet.is_none() {
+            *ratchet = Some(self.new_ratchet(&session)?);
+            drop(ratchet);
+            self.persist(peer_id, &session);
+        }
+        Ok(true)
+    }
+
+    /// Whether the session with a peer ratchets
+    pub fn has_ratchet(&self, peer_id: &str) -> bool {
+        self.session(peer_id)
+            .is_some_and(|session| session.ratchet.lock().is_some())
+    }
+
+    /// Whether the session with a peer was kept before ratchets and lacks
+    /// the peer's key to start one. Making it again from the trusted key
+    /// gives the same session with the key.
+    pub fn needs_peer_key(&self, peer_id: &str) -> bool {
+        self.session(peer_id)
+            .is_some_and(|session| session.is_storable() && session.peer_key.is_none())
+    }
+
+    fn new_ratchet(&self, session: &SessionKeys) -> Result<Ratchet> {
+        let peer_key = session
+            .peer_key
+            .ok_or_else(|| Error::Crypto("Session has no peer key to ratchet from".into()))?;
+        let guard = self.identity_secret.read();
+        let secret = guard.as_ref().ok_or(Error::Crypto("No identity".into()))?;
+        Ratchet::new(&session.shared_secret, secret, &peer_key)
     }
 
     /// Encrypt message for peer
     pub fn encrypt_for(&self, peer_id: &str, plaintext: &str) -> Result<String> {
-        let sessions = self.sessions.read();
-        let session = sessions
-            .get(peer_id)
-            .ok_or_else(|| Error::NoSession(peer_id.to_string()))?;
+        let session = self.require_session(peer_id)?;
+
+        if self.ratchet.load(Ordering::Relaxed) {
+            let mut guard = session.ratchet.lock();
+            if let Some(ref mut ratchet) = *guard {
+                let message = ratchet.encrypt(plaintext.as_bytes())?;
+                drop(guard);
+                self.persist(peer_id, &session);
+                return Ok(format!("{}{}", RATCHET_TAG, URL_SAFE_NO_PAD.encode(message)));
+            }
+        }
 
-        let cipher = Aes256Gcm::new_from_slice(&session.shared_secret)
+        let (key, kem_ciphertext) = session.outbound_key();
+        let cipher = Aes256Gcm::new_from_slice(key)
             .map_err(|e| Error::Crypto(format!("Cipher init failed: {}", e)))?;
 
         // Generate random nonce
@@ -147,25 +728,66 @@ impl CryptoEngine {
             .encrypt(nonce, plaintext.as_bytes())
             .map_err(|e| Error::Crypto(format!("Encryption failed: {}", e)))?;
 
-        // Combine: nonce (12) + ciphertext + tag (16)
-        let mut combined = Vec::with_capacity(12 + ciphertext.len());
+        // Combine: KEM ciphertext (hybrid only) + nonce (12) + ciphertext + tag (16)
+        let mut combined = Vec::with_capacity(kem_ciphertext.len() + 12 + ciphertext.len());
+        combined.extend_from_slice(kem_ciphertext);
         combined.extend_from_slice(&nonce_bytes);
         combined.extend_from_slice(&ciphertext);
 
+        if session.tagged {
+            return Ok(tag_suite(session.suite, &combined));
+        }
         Ok(URL_SAFE_NO_PAD.encode(&combined))
     }
 
     /// Decrypt message from peer
     pub fn decrypt_from(&self, peer_id: &str, ciphertext_b64: &str) -> Result<String> {
-        let sessions = self.sessions.read();
-        let session = sessions
-            .get(peer_id)
-            .ok_or_else(|| Error::NoSession(peer_id.to_string()))?;
+        let session = self.require_session(peer_id)?;
+
+        if let Some(body) = ciphertext_b64.strip_prefix(RATCHET_TAG) {
+            return self.decrypt_ratchet(peer_id, &session, body);
+        }
 
+        let (suite, body, _) = split_suite(ciphertext_b64)?;
         let combined = URL_SAFE_NO_PAD
-            .decode(ciphertext_b64)
+            .decode(body)
             .map_err(|e| Error::Crypto(format!("Invalid ciphertext: {}", e)))?;
 
+        let (key, combined) = match suite {
+            CipherSuite::X25519Aes256Gcm => {
+                // When both keys are hybrid the peer sends hybrid
+                // ciphertexts, so a classical one would be a downgrade
+                if session.suite == CipherSuite::X25519Kyber768Aes256Gcm && self.identity_suite() == session.suite {
+                    return Err(Error::Crypto("Expected a hybrid ciphertext".into()));
+                }
+                (session.shared_secret, combined.as_slice())
+            }
+            #[cfg(feature = "pq-hybrid")]
+            CipherSuite::X25519Kyber768Aes256Gcm => {
+                let guard = self.identity_kem.read();
+                let kem = guard
+                    .as_ref()
+                    .ok_or_else(|| Error::Crypto("No hybrid identity".into()))?;
+                if combined.len() < hybrid::CIPHERTEXT_LEN {
+                    return Err(Error::Crypto("Ciphertext too short".into()));
+                }
+                let (kem_ciphertext, rest) = combined.split_at(hybrid::CIPHERTEXT_LEN);
+                let mut inbound = session.inbound.lock();
+                let key = match *inbound {
+                    Some((ref known, key)) if known == kem_ciphertext => key,
+                    _ => {
+                        let kem_secret = kem.decapsulate(kem_ciphertext)?;
+                        let key = hybrid::combine(&session.shared_secret, &kem_secret);
+                        *inbound = Some((kem_ciphertext.to_vec(), key));
+                        key
+                    }
+                };
+                (key, rest)
+            }
+            #[cfg(not(feature = "pq-hybrid"))]
+            suite => return Err(Error::Crypto(format!("Unsupported cipher suite: {}", suite.id()))),
+        };
+
         if combined.len() < 12 {
             return Err(Error::Crypto("Ciphertext too short".into()));
         }
@@ -173,7 +795,7 @@ impl CryptoEngine {
         let nonce = Nonce::from_slice(&combined[..12]);
         let ciphertext = &combined[12..];
 
-        let cipher = Aes256Gcm::new_from_slice(&session.shared_secret)
+        let cipher = Aes256Gcm::new_from_slice(&key)
             .map_err(|e| Error::Crypto(format!("Cipher init failed: {}", e)))?;
 
         let plaintext = cipher
@@ -183,49 +805,187 @@ impl CryptoEngine {
         String::from_utf8(plaintext).map_err(|e| Error::Crypto(format!("Invalid UTF-8: {}", e)))
     }
 
+    /// A ratchet ciphertext shows the peer reads them too, so the session
+    /// gets a ratchet if it has none
+    fn decrypt_ratchet(&self, peer_id: &str, session: &SessionKeys, body: &str) -> Result<String> {
+        if !session.is_storable() {
+            return Err(Error::Crypto("Hybrid sessions don't ratchet".into()));
+        }
+        let message = URL_SAFE_NO_PAD
+            .decode(body)
+            .map_err(|e| Error::Crypto(format!("Invalid ciphertext: {}", e)))?;
+
+        let mut guard = session.ratchet.lock();
+        let mut ratchet = match *guard {
+            Some(ref ratchet) => ratchet.clone(),
+            None => self.new_ratchet(session)?,
+        };
+        let plaintext = ratchet.decrypt(&message)?;
+        *guard = Some(ratchet);
+        drop(guard);
+        self.persist(peer_id, session);
+
+        String::from_utf8(plaintext).map_err(|e| Error::Crypto(format!("Invalid UTF-8: {}", e)))
+    }
+
+    /// Authenticate `data` under the session with a peer. Only the two
+    /// holders of the session's identity keys can produce the same MAC.
+    pub fn session_mac(&self, peer_id: &str, data: &[u8]) -> Result<String> {
+        let mac = self.session_hmac(peer_id, data)?.finalize().into_bytes();
+        Ok(URL_SAFE_NO_PAD.encode(mac))
+    }
+
+    /// Check a MAC produced by the peer's `session_mac`
+    pub fn verify_session_mac(&self, peer_id: &str, data: &[u8], mac_b64: &str) -> Result<bool> {
+        let Ok(mac) = URL_SAFE_NO_PAD.decode(mac_b64) else {
+            return Ok(false);
+        };
+        Ok(self.session_hmac(peer_id, data)?.verify_slice(&mac).is_ok())
+    }
+
+    fn session_hmac(&self, peer_id: &str, data: &[u8]) -> Result<Hmac<Sha256>> {
+        let key = self.session_subkey(peer_id, b"privmsg-session-mac")?;
+
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key)
+            .map_err(|e| Error::Crypto(format!("MAC init failed: {}", e)))?;
+        mac.update(data);
+        Ok(mac)
+    }
+
+    /// Key derived from the session with a peer for one purpose, kept
+    /// separate from the message encryption key
+    fn session_subkey(&self, peer_id: &str, label: &[u8]) -> Result<[u8; 32]> {
+        let session = self.require_session(peer_id)?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(label);
+        hasher.update(session.shared_secret);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Sign `data` with the identity key, so anyone holding the public key
+    /// can check it with [`verify_signature`]
+    pub fn sign(&self, data: &[u8]) -> Result<String> {
+        let guard = self.identity_secret.read();
+        let secret = guard.as_ref().ok_or(Error::Crypto("No identity".into()))?;
+        let mut random = [0u8; 64];
+        OsRng.fill_bytes(&mut random);
+        Ok(URL_SAFE_NO_PAD.encode(xeddsa_sign(&secret.to_bytes(), data, &random)))
+    }
+
+    /// Wrap a file key for each of `member_ids`, so one upload can be shared
+    /// with a whole group. Every member needs a session. The result is a
+    /// recipients block for the file payload's `wrapped_keys`.
+    pub fn wrap_key_for_members(&self, key_b64: &str, member_ids: &[&str]) -> Result<String> {
+        let key = decode_file_key(key_b64)?;
+
+        let mut block = Vec::with_capacity(1 + member_ids.len() * WRAPPED_ENTRY_LEN);
+        block.push(WRAP_VERSION);
+        for member_id in member_ids {
+            let (tag, cipher) = self.wrapping_cipher(member_id)?;
+            let mut nonce = [0u8; 12];
+            OsRng.fill_bytes(&mut nonce);
+            let wrapped = cipher
+                .encrypt(Nonce::from_slice(&nonce), Payload { msg: &key, aad: &tag })
+                .map_err(|e| Error::Crypto(format!("Key wrapping failed: {}", e)))?;
+
+            block.extend_from_slice(&tag);
+            block.extend_from_slice(&nonce);
+            block.extend_from_slice(&wrapped);
+        }
+
+        Ok(URL_SAFE_NO_PAD.encode(block))
+    }
+
+    /// Find and unwrap our file key in a recipients block from `sender_id`
+    pub fn unwrap_key_from(&self, sender_id: &str, block_b64: &str) -> Result<String> {
+        let block = URL_SAFE_NO_PAD
+            .decode(block_b64)
+            .map_err(|e| Error::Crypto(format!("Invalid recipients block: {}", e)))?;
+        let entries = match block.split_first() {
+            Some((&WRAP_VERSION, entries)) if entries.len() % WRAPPED_ENTRY_LEN == 0 => entries,
+            _ => return Err(Error::Crypto("Invalid recipients block".into())),
+        };
+
+        let (tag, cipher) = self.wrapping_cipher(sender_id)?;
+        let entry = entries
+            .chunks_exact(WRAPPED_ENTRY_LEN)
+            .find(|entry| entry[..WRAP_TAG_LEN] == tag)
+            .ok_or_else(|| Error::Crypto("File key not wrapped for us".into()))?;
+
+        let (nonce, wrapped) = entry[WRAP_TAG_LEN..].split_at(12);
+        let key = cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: wrapped, aad: &tag })
+            .map_err(|e| Error::Crypto(format!("Key unwrapping failed: {}", e)))?;
+        Ok(URL_SAFE_NO_PAD.encode(key))
+    }
+
+    /// Cipher wrapping file keys between us and a peer, and the tag marking
+    /// the peer's entry. Both sides of the session derive the same pair, and
+    /// nobody else can tell whose entry is whose.
+    fn wrapping_cipher(&self, peer_id: &str) -> Result<([u8; WRAP_TAG_LEN], Aes256Gcm)> {
+        let key = self.session_subkey(peer_id, b"privmsg-key-wrap")?;
+        let mut tag = [0u8; WRAP_TAG_LEN];
+        tag.copy_from_slice(&self.session_subkey(peer_id, b"privmsg-key-wrap-tag")?[..WRAP_TAG_LEN]);
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|e| Error::Crypto(format!("Cipher init failed: {}", e)))?;
+        Ok((tag, cipher))
+    }
+
     /// Generate random file encryption key
     pub fn generate_file_key(&self) -> Result<String> {
         let mut key = [0u8; 32];
         OsRng.fill_bytes(&mut key);
-        Ok(URL_SAFE_NO_PAD.encode(&key))
+        Ok(URL_SAFE_NO_PAD.encode(key))
     }
 
-    /// Encrypt file data
+    /// Encrypt file data held in memory (same format as `encrypting_writer`)
     pub fn encrypt_file(&self, data: &[u8], key_b64: &str) -> Result<Vec<u8>> {
-        let key_bytes = URL_SAFE_NO_PAD
-            .decode(key_b64)
-            .map_err(|e| Error::Crypto(format!("Invalid key: {}", e)))?;
-
-        let cipher = Aes256Gcm::new_from_slice(&key_bytes)
-            .map_err(|e| Error::Crypto(format!("Cipher init failed: {}", e)))?;
+        let mut writer = self.encrypting_writer(Vec::with_capacity(encrypted_len(data.len())), key_b64)?;
+        writer.write_all(data)?;
+        Ok(writer.finish()?)
+    }
 
-        let mut nonce_bytes = [0u8; 12];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+    /// Decrypt file data held in memory, streamed or legacy single-shot
+    pub fn decrypt_file(&self, encrypted: &[u8], key_b64: &str) -> Result<Vec<u8>> {
+        if encrypted.starts_with(STREAM_MAGIC) {
+            let mut plaintext = Vec::new();
+            let streamed = self
+                .decrypting_reader(encrypted, key_b64)
+                .and_then(|mut reader| Ok(reader.read_to_end(&mut plaintext)?));
+
+            // A legacy file can start with the magic bytes by chance
+            match streamed {
+                Ok(_) => return Ok(plaintext),
+                Err(e) => return self.decrypt_legacy_file(encrypted, key_b64).map_err(|_| e),
+            }
+        }
 
-        let ciphertext = cipher
-            .encrypt(nonce, data)
-            .map_err(|e| Error::Crypto(format!("Encryption failed: {}", e)))?;
+        self.decrypt_legacy_file(encrypted, key_b64)
+    }
 
-        let mut result = Vec::with_capacity(12 + ciphertext.len());
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
+    /// Encrypt everything written to `writer`; call `finish` when done
+    pub fn encrypting_writer<W: Write>(&self, writer: W, key_b64: &str) -> Result<EncryptingWriter<W>> {
+        Ok(EncryptingWriter::new(writer, file_cipher(key_b64)?))
+    }
 
-        Ok(result)
+    /// Decrypt a streamed file while reading it. Tampering, reordering and
+    /// truncation surface as `InvalidData` read errors.
+    pub fn decrypting_reader<R: Read>(&self, reader: R, key_b64: &str) -> Result<DecryptingReader<R>> {
+        let cipher = file_cipher(key_b64)?;
+        DecryptingReader::new(reader, cipher).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => Error::Crypto(e.to_string()),
+            _ => e.into(),
+        })
     }
 
-    /// Decrypt file data
-    pub fn decrypt_file(&self, encrypted: &[u8], key_b64: &str) -> Result<Vec<u8>> {
+    /// Files uploaded before chunking: nonce followed by one AES-GCM message
+    fn decrypt_legacy_file(&self, encrypted: &[u8], key_b64: &str) -> Result<Vec<u8>> {
         if encrypted.len() < 12 {
             return Err(Error::Crypto("Data too short".into()));
         }
 
-        let key_bytes = URL_SAFE_NO_PAD
-            .decode(key_b64)
-            .map_err(|e| Error::Crypto(format!("Invalid key: {}", e)))?;
-
-        let cipher = Aes256Gcm::new_from_slice(&key_bytes)
-            .map_err(|e| Error::Crypto(format!("Cipher init failed: {}", e)))?;
+        let cipher = file_cipher(key_b64)?;
 
         let nonce = Nonce::from_slice(&encrypted[..12]);
         let ciphertext = &encrypted[12..];
@@ -243,6 +1003,122 @@ impl CryptoEngine {
     }
 }
 
+// ============================================================================
+// Identity Signatures
+// ============================================================================
+
+// XEdDSA: Ed25519 signatures made and checked with X25519 keys, so an
+// identity needs no second key pair to sign with. The Edwards form of a
+// public key is the one with sign bit 0; the signer negates its secret if
+// needed to match.
+
+/// Check a signature made with [`CryptoEngine::sign`] by the holder of
+/// `public_key_b64`. A hybrid key signs with its X25519 part.
+pub fn verify_signature(public_key_b64: &str, data: &[u8], signature_b64: &str) -> Result<bool> {
+    let (_, body, _) = split_suite(public_key_b64)?;
+    let key_bytes = URL_SAFE_NO_PAD
+        .decode(body)
+        .map_err(|e| Error::Crypto(format!("Invalid key: {}", e)))?;
+    if key_bytes.len() < 32 {
+        return Err(Error::Crypto("Invalid key length".into()));
+    }
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature_b64) else {
+        return Ok(false);
+    };
+    let mut public = [0u8; 32];
+    public.copy_from_slice(&key_bytes[..32]);
+    Ok(xeddsa_verify(&public, data, &signature))
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+fn xeddsa_sign(secret: &[u8; 32], data: &[u8], random: &[u8; 64]) -> [u8; 64] {
+    let mut clamped = *secret;
+    clamped[0] &= 248;
+    clamped[31] &= 127;
+    clamped[31] |= 64;
+    let mut a = Scalar::from_bytes_mod_order(clamped);
+    let mut public = EdwardsPoint::mul_base(&a).compress();
+    if public.as_bytes()[31] & 0x80 != 0 {
+        a = -a;
+        public = EdwardsPoint::mul_base(&a).compress();
+    }
+
+    // Domain-separated from the challenge hash below
+    let mut prefix = [0xffu8; 32];
+    prefix[0] = 0xfe;
+    let r = hash_to_scalar(&[&prefix, a.as_bytes(), data, random]);
+    let big_r = EdwardsPoint::mul_base(&r).compress();
+    let h = hash_to_scalar(&[big_r.as_bytes(), public.as_bytes(), data]);
+    let s = r + h * a;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(big_r.as_bytes());
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
+
+fn xeddsa_verify(public: &[u8; 32], data: &[u8], signature: &[u8]) -> bool {
+    if signature.len() != 64 {
+        return false;
+    }
+    let Some(a) = MontgomeryPoint(*public).to_edwards(0) else {
+        return false;
+    };
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&signature[32..]);
+    let Some(s) = Option::<Scalar>::from(Scalar::from_canonical_bytes(s)) else {
+        return false;
+    };
+
+    let big_r = &signature[..32];
+    let h = hash_to_scalar(&[big_r, a.compress().as_bytes(), data]);
+    // R = sB - hA
+    let check = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-h, &a, &s).compress();
+    check.as_bytes() == big_r
+}
+
+// ============================================================================
+// Group Key Wrapping
+// ============================================================================
+
+/// Recipients block: a version byte, then per member a tag, a nonce and
+/// the wrapped 32-byte key with its GCM tag
+const WRAP_VERSION: u8 = 1;
+const WRAP_TAG_LEN: usize = 8;
+const WRAPPED_ENTRY_LEN: usize = WRAP_TAG_LEN + 12 + 32 + TAG_LEN;
+
+fn decode_file_key(key_b64: &str) -> Result<[u8; 32]> {
+    URL_SAFE_NO_PAD
+        .decode(key_b64)
+        .map_err(|e| Error::Crypto(format!("Invalid key: {}", e)))?
+        .try_into()
+        .map_err(|_| Error::Crypto("Invalid key length".into()))
+}
+
+// ============================================================================
+// Streaming File Encryption
+// ============================================================================
+
+// The format lives in privmsg-proto so every client writes the same files
+pub use privmsg_proto::stream::{encrypted_len, DecryptingReader, EncryptingWriter, FILE_CHUNK_SIZE};
+use privmsg_proto::stream::{STREAM_MAGIC, TAG_LEN};
+
+fn file_cipher(key_b64: &str) -> Result<Aes256Gcm> {
+    let key_bytes = URL_SAFE_NO_PAD
+        .decode(key_b64)
+        .map_err(|e| Error::Crypto(format!("Invalid key: {}", e)))?;
+
+    Aes256Gcm::new_from_slice(&key_bytes)
+        .map_err(|e| Error::Crypto(format!("Cipher init failed: {}", e)))
+}
+
 impl Default for CryptoEngine {
     fn default() -> Self {
         Self::new()
@@ -252,6 +1128,7 @@ impl Default for CryptoEngine {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use privmsg_proto::stream::STREAM_HEADER_LEN;
 
     #[test]
     fn test_key_generation() {
@@ -302,6 +1179,168 @@ mod tests {
         assert_eq!(plaintext, decrypted);
     }
 
+    #[test]
+    fn test_cipher_suites() {
+        let classical = || CryptoEngine::with_suites(&[CipherSuite::X25519Aes256Gcm]).unwrap();
+        let alice = classical();
+        alice.generate_identity().unwrap();
+        let alice_pub = alice.get_public_key().unwrap();
+        assert!(alice_pub.starts_with("x25519:"));
+
+        // A peer with an untagged key gets untagged ciphertexts
+        let legacy = classical();
+        legacy.generate_identity().unwrap();
+        let legacy_pub = legacy.get_public_key().unwrap();
+        let untagged = legacy_pub.strip_prefix("x25519:").unwrap();
+        alice.establish_session("legacy", untagged).unwrap();
+        legacy.establish_session("alice", &alice_pub).unwrap();
+        let encrypted = alice.encrypt_for("legacy", "hi").unwrap();
+        assert!(!encrypted.contains(':'));
+        assert_eq!(legacy.decrypt_from("alice", &encrypted).unwrap(), "hi");
+        assert!(legacy.encrypt_for("alice", "hi").unwrap().starts_with("x25519:"));
+
+        // Untagged exports still import
+        let exported = legacy.export_identity().unwrap();
+        let imported = classical();
+        imported.import_identity(exported.strip_prefix("x25519:").unwrap()).unwrap();
+        assert_eq!(imported.get_public_key().unwrap(), legacy_pub);
+
+        // Reserved and unknown suites are refused
+        let hybrid = format!("x25519-kyber768:{}", untagged);
+        assert!(alice.establish_session("pq", &hybrid).is_err());
+        assert!(alice.establish_session("pq", &format!("rsa:{}", untagged)).is_err());
+        assert!(CryptoEngine::with_suites(&[]).is_err());
+        assert_eq!(CipherSuite::from_id("x25519-kyber768"), Some(CipherSuite::X25519Kyber768Aes256Gcm));
+
+        // A ciphertext claiming another suite than the session's is refused
+        let tagged = legacy.encrypt_for("alice", "hi").unwrap();
+        let relabeled = tagged.replacen("x25519:", "x25519-kyber768:", 1);
+        assert!(alice.decrypt_from("legacy", &relabeled).is_err());
+    }
+
+    #[cfg(feature = "pq-hybrid")]
+    #[test]
+    fn test_hybrid_sessions() {
+        let hybrid = CryptoEngine::new();
+        hybrid.generate_identity().unwrap();
+        let hybrid_pub = hybrid.get_public_key().unwrap();
+        assert!(hybrid_pub.starts_with("x25519-kyber768:"));
+
+        let bob = CryptoEngine::new();
+        bob.generate_identity().unwrap();
+        let bob_pub = bob.get_public_key().unwrap();
+        hybrid.establish_session("bob", &bob_pub).unwrap();
+        bob.establish_session("alice", &hybrid_pub).unwrap();
+
+        let encrypted = hybrid.encrypt_for("bob", "quantum").unwrap();
+        assert!(encrypted.starts_with("x25519-kyber768:"));
+        assert_eq!(bob.decrypt_from("alice", &encrypted).unwrap(), "quantum");
+        assert_eq!(hybrid.decrypt_from("bob", &bob.encrypt_for("alice", "hi").unwrap()).unwrap(), "hi");
+
+        // Classical-only peers fall back to the X25519 half of a hybrid key
+        let classical = CryptoEngine::with_suites(&[CipherSuite::X25519Aes256Gcm]).unwrap();
+        classical.generate_identity().unwrap();
+        classical.establish_session("alice", &hybrid_pub).unwrap();
+        hybrid.establish_session("carol", &classical.get_public_key().unwrap()).unwrap();
+        let from_carol = classical.encrypt_for("alice", "classic").unwrap();
+        assert!(from_carol.starts_with("x25519:"));
+        assert_eq!(hybrid.decrypt_from("carol", &from_carol).unwrap(), "classic");
+        assert_eq!(classical.decrypt_from("alice", &hybrid.encrypt_for("carol", "ok").unwrap()).unwrap(), "ok");
+
+        // Between two hybrid identities a classical ciphertext is refused
+        let exported = hybrid.export_identity().unwrap();
+        let secret = URL_SAFE_NO_PAD.decode(exported.split_once(':').unwrap().1).unwrap();
+        let downgraded = CryptoEngine::with_suites(&[CipherSuite::X25519Aes256Gcm]).unwrap();
+        downgraded
+            .import_identity(&format!("x25519:{}", URL_SAFE_NO_PAD.encode(&secret[..32])))
+            .unwrap();
+        downgraded.establish_session("bob", &bob_pub).unwrap();
+        assert!(bob.decrypt_from("alice", &downgraded.encrypt_for("bob", "hi").unwrap()).is_err());
+
+        // Hybrid identities survive export and import
+        let restored = CryptoEngine::new();
+        restored.import_identity(&hybrid.export_identity().unwrap()).unwrap();
+        assert_eq!(restored.get_public_key().unwrap(), hybrid_pub);
+        restored.establish_session("bob", &bob_pub).unwrap();
+        assert_eq!(restored.decrypt_from("bob", &bob.encrypt_for("alice", "again").unwrap()).unwrap(), "again");
+    }
+
+    #[test]
+    fn test_session_mac() {
+        let alice = CryptoEngine::new();
+        alice.generate_identity().unwrap();
+        let bob = CryptoEngine::new();
+        bob.generate_identity().unwrap();
+        let mallory = CryptoEngine::new();
+        mallory.generate_identity().unwrap();
+
+        alice.establish_session("bob", &bob.get_public_key().unwrap()).unwrap();
+        bob.establish_session("alice", &alice.get_public_key().unwrap()).unwrap();
+        mallory.establish_session("bob", &bob.get_public_key().unwrap()).unwrap();
+
+        let mac = alice.session_mac("bob", b"hello").unwrap();
+        assert!(bob.verify_session_mac("alice", b"hello", &mac).unwrap());
+        assert!(!bob.verify_session_mac("alice", b"hullo", &mac).unwrap());
+
+        // A different identity can't speak for Alice
+        let forged = mallory.session_mac("bob", b"hello").unwrap();
+        assert!(!bob.verify_session_mac("alice", b"hello", &forged).unwrap());
+    }
+
+    #[test]
+    fn test_signatures() {
+        let alice = CryptoEngine::new();
+        let mallory = CryptoEngine::new();
+        mallory.generate_identity().unwrap();
+
+        // Both signs of the Edwards key come up across a few identities
+        for _ in 0..8 {
+            alice.generate_identity().unwrap();
+            let key = alice.get_public_key().unwrap();
+            let signature = alice.sign(b"hello").unwrap();
+            assert!(verify_signature(&key, b"hello", &signature).unwrap());
+            assert!(!verify_signature(&key, b"hullo", &signature).unwrap());
+
+            let forged = mallory.sign(b"hello").unwrap();
+            assert!(!verify_signature(&key, b"hello", &forged).unwrap());
+            assert!(!verify_signature(&key, b"hello", "not-a-signature").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_ratchet_sessions() {
+        let classical = [CipherSuite::X25519Aes256Gcm];
+        let alice = CryptoEngine::with_suites(&classical).unwrap();
+        alice.generate_identity().unwrap();
+        let bob = CryptoEngine::with_suites(&classical).unwrap();
+        bob.generate_identity().unwrap();
+        let alice_pub = alice.get_public_key().unwrap();
+        alice.establish_session("bob", &bob.get_public_key().unwrap()).unwrap();
+        bob.establish_session("alice", &alice_pub).unwrap();
+
+        // Only once enabled and the peer is known to read them
+        alice.set_ratchet_enabled(true);
+        assert!(!alice.encrypt_for("bob", "one").unwrap().starts_with(RATCHET_TAG));
+        assert!(alice.start_ratchet("bob").unwrap());
+        let first = alice.encrypt_for("bob", "two").unwrap();
+        assert!(first.starts_with(RATCHET_TAG));
+        assert_eq!(bob.decrypt_from("alice", &first).unwrap(), "two");
+        assert!(bob.has_ratchet("alice"));
+
+        // Making the session again keeps the ratchet going
+        bob.establish_session("alice", &alice_pub).unwrap();
+        bob.set_ratchet_enabled(true);
+        let reply = bob.encrypt_for("alice", "three").unwrap();
+        assert_eq!(alice.decrypt_from("bob", &reply).unwrap(), "three");
+        assert!(bob.decrypt_from("alice", &first).is_err());
+
+        // Classical ciphertexts are still read
+        bob.set_ratchet_enabled(false);
+        let classical = bob.encrypt_for("alice", "four").unwrap();
+        assert!(!classical.starts_with(RATCHET_TAG));
+        assert_eq!(alice.decrypt_from("bob", &classical).unwrap(), "four");
+    }
+
     #[test]
     fn test_file_encryption() {
         let engine = CryptoEngine::new();
@@ -313,4 +1352,81 @@ mod tests {
 
         assert_eq!(data.to_vec(), decrypted);
     }
+
+    #[test]
+    fn test_streamed_file_encryption() {
+        let engine = CryptoEngine::new();
+        let key = engine.generate_file_key().unwrap();
+
+        for len in [0, 1, FILE_CHUNK_SIZE, FILE_CHUNK_SIZE + 1, 3 * FILE_CHUNK_SIZE - 7] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+            let mut writer = engine.encrypting_writer(Vec::new(), &key).unwrap();
+            // Odd write sizes to exercise chunk boundaries
+            for piece in data.chunks(10_000) {
+                writer.write_all(piece).unwrap();
+            }
+            let encrypted = writer.finish().unwrap();
+            assert_eq!(encrypted.len(), encrypted_len(len));
+
+            let mut decrypted = Vec::new();
+            engine
+                .decrypting_reader(encrypted.as_slice(), &key)
+                .unwrap()
+                .read_to_end(&mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, data);
+
+            // Dropping the final chunk must not go unnoticed
+            if len > FILE_CHUNK_SIZE {
+                let truncated = &encrypted[..STREAM_HEADER_LEN + FILE_CHUNK_SIZE + TAG_LEN];
+                assert!(engine.decrypt_file(truncated, &key).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_legacy_file_decryption() {
+        let engine = CryptoEngine::new();
+        let key = engine.generate_file_key().unwrap();
+        let cipher = file_cipher(&key).unwrap();
+
+        let nonce = [7u8; 12];
+        let mut legacy = nonce.to_vec();
+        legacy.extend(cipher.encrypt(Nonce::from_slice(&nonce), &b"old upload"[..]).unwrap());
+
+        assert_eq!(engine.decrypt_file(&legacy, &key).unwrap(), b"old upload");
+    }
+
+    #[test]
+    fn test_wrap_key_for_members() {
+        let alice = CryptoEngine::new();
+        alice.generate_identity().unwrap();
+        let members: Vec<(&str, CryptoEngine)> = ["bob", "carol", "dave"]
+            .into_iter()
+            .map(|id| {
+                let engine = CryptoEngine::new();
+                engine.generate_identity().unwrap();
+                engine.establish_session("alice", &alice.get_public_key().unwrap()).unwrap();
+                alice.establish_session(id, &engine.get_public_key().unwrap()).unwrap();
+                (id, engine)
+            })
+            .collect();
+
+        let key = alice.generate_file_key().unwrap();
+        let block = alice.wrap_key_for_members(&key, &["bob", "carol"]).unwrap();
+        assert_eq!(URL_SAFE_NO_PAD.decode(&block).unwrap().len(), 1 + 2 * WRAPPED_ENTRY_LEN);
+
+        assert_eq!(members[0].1.unwrap_key_from("alice", &block).unwrap(), key);
+        assert_eq!(members[1].1.unwrap_key_from("alice", &block).unwrap(), key);
+        // Left out
+        assert!(members[2].1.unwrap_key_from("alice", &block).is_err());
+        // No session with a member
+        assert!(matches!(alice.wrap_key_for_members(&key, &["eve"]), Err(Error::NoSession(_))));
+
+        let mut tampered = URL_SAFE_NO_PAD.decode(&block).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(members[1].1.unwrap_key_from("alice", &URL_SAFE_NO_PAD.encode(&tampered)).is_err());
+        assert!(members[0].1.unwrap_key_from("alice", "AQ").is_err());
+    }
 }
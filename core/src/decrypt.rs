@@ -0,0 +1,321 @@
+//! Background decryption of incoming envelopes
+//!
+//! Envelopes are queued per conversation (sender). Each conversation is
+//! drained by one task at a time, so its messages are decrypted, stored and
+//! reported in arrival order, while up to `workers` conversations are
+//! processed in parallel on the client runtime. Results are delivered as
+//! `ClientEvent`s.
+
+use crate::crypto::CryptoEngine;
+use crate::devices;
+use crate::error::{Error, Result};
+use crate::events::{ClientEvent, EventSender};
+use crate::identity::PeerKeys;
+use crate::models::*;
+use crate::storage::LocalStorage;
+use crate::transport::{ApiTransport, WsTransport};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+
+/// Decrypt an envelope's content, fetching the sender's key if there is
+/// no session yet or the session's key no longer works, and trying the
+/// sender's other devices if that doesn't help either
+pub(crate) async fn decrypt_content(
+    peer_keys: &PeerKeys,
+    api: &dyn ApiTransport,
+    envelope: &MessageEnvelope,
+) -> Result<String> {
+    match decrypt_from_user(peer_keys, api, envelope).await {
+        Err(e) => devices::decrypt_from_devices(peer_keys, api, &envelope.sender_id, &envelope.encrypted_content)
+            .await
+            .ok_or(e),
+        result => result,
+    }
+}
+
+/// Decrypt under the session with the sender's key
+async fn decrypt_from_user(
+    peer_keys: &PeerKeys,
+    api: &dyn ApiTransport,
+    envelope: &MessageEnvelope,
+) -> Result<String> {
+    let crypto = peer_keys.crypto();
+    let had_session = crypto.has_session(&envelope.sender_id);
+    if !had_session {
+        let user = api.get_user(&envelope.sender_id).await?;
+        if let Some(pub_key) = user.public_key {
+            peer_keys.establish(&envelope.sender_id, &pub_key)?;
+        }
+    }
+    peer_keys.fill_peer_key(&envelope.sender_id)?;
+    match crypto.decrypt_from(&envelope.sender_id, &envelope.encrypted_content) {
+        Err(e) if had_session => {
+            // The sender may have a new key since the session was made
+            let user = api.get_user(&envelope.sender_id).await?;
+            match user.public_key {
+                Some(pub_key) if peer_keys.establish(&envelope.sender_id, &pub_key)? => {
+                    crypto.decrypt_from(&envelope.sender_id, &envelope.encrypted_content)
+                }
+                _ => Err(e),
+            }
+        }
+        result => result,
+    }
+}
+
+/// Decrypt an envelope into a message
+pub(crate) async fn decrypt_envelope(
+    peer_keys: &PeerKeys,
+    api: &dyn ApiTransport,
+    envelope: MessageEnvelope,
+) -> Result<Message> {
+    let decrypted = decrypt_content(peer_keys, api, &envelope).await?;
+    let content: serde_json::Value = serde_json::from_str(&decrypted)?;
+    if content["ratchet"].as_bool() == Some(true) {
+        if let Err(e) = peer_keys.start_ratchet(&envelope.sender_id) {
+            log::warn!("No ratchet with {}: {}", envelope.sender_id, e);
+        }
+    }
+
+    // File payloads carry attachment info and an optional caption
+    let attachment = content["file_id"].as_str().map(|file_id| Attachment {
+        file_id: file_id.to_string(),
+        file_name: content["file_name"].as_str().unwrap_or("file").to_string(),
+        file_size: content["file_size"].as_i64().unwrap_or(0),
+        mime_type: content["mime_type"]
+            .as_str()
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+        duration_ms: content["duration_ms"].as_i64(),
+        width: content["width"].as_i64().map(|w| w as i32),
+        height: content["height"].as_i64().map(|h| h as i32),
+        encryption_key: file_key(peer_keys.crypto(), &envelope.sender_id, &content),
+        local_path: None,
+        waveform: serde_json::from_value(content["waveform"].clone()).ok(),
+        sha256: content["sha256"].as_str().map(|h| h.to_string()),
+        expires_at: content["expires_at"].as_i64(),
+    });
+
+    let (message_type, text) = match attachment {
+        Some(ref a) => (MessageType::from_mime(&a.mime_type), a.file_name.clone()),
+        None => (MessageType::Text, content["text"].as_str().unwrap_or("").to_string()),
+    };
+
+    // Each member's and device's copy has its own envelope id
+    let (message_id, conversation_id) = match content["group_id"].as_str() {
+        Some(group_id) => (
+            content["group_message_id"]
+                .as_str()
+                .map_or(envelope.message_id, |id| id.to_string()),
+            group_id.to_string(),
+        ),
+        None => (
+            crate::message_id_of_copy(&envelope.message_id).to_string(),
+            envelope.sender_id.clone(),
+        ),
+    };
+
+    let unverified_key = peer_keys.is_unverified(&envelope.sender_id);
+    let message = Message {
+        message_id,
+        conversation_id,
+        sender_id: envelope.sender_id,
+        message_type,
+        content: text,
+        timestamp: envelope.timestamp,
+        status: MessageStatus::Delivered,
+        attachment,
+        caption: content["caption"].as_str().map(|c| c.to_string()),
+        is_outgoing: false,
+        metadata: serde_json::from_value(content["metadata"].clone()).unwrap_or_default(),
+        system_event: None,
+        sender_device_id: content["device_id"].as_str().map(|d| d.to_string()),
+        quote: serde_json::from_value(content["quote"].clone()).ok(),
+        is_starred: false,
+        unverified_key,
+    };
+
+    Ok(message)
+}
+
+/// Decrypt an envelope into a message, or `None` for a group message whose
+/// group the server doesn't give us or whose sender isn't in it, which
+/// should be acked and dropped
+pub(crate) async fn open_envelope(
+    peer_keys: &PeerKeys,
+    api: &dyn ApiTransport,
+    storage: &LocalStorage,
+    envelope: MessageEnvelope,
+) -> Result<Option<Message>> {
+    let message = decrypt_envelope(peer_keys, api, envelope).await?;
+    if message.conversation_id == message.sender_id {
+        return Ok(Some(message));
+    }
+
+    // The group is fetched when it is new to us or the sender joined since
+    let known = storage.get_group(&message.conversation_id)?;
+    let group = match known {
+        Some(group) if group.is_member(&message.sender_id) => group,
+        _ => match api.get_group(&message.conversation_id).await {
+            Ok(group) => {
+                storage.save_group(&group)?;
+                group
+            }
+            // Retried with the next fetch
+            Err(e @ (Error::Http(_) | Error::Network(_) | Error::RateLimited)) => return Err(e),
+            // Unknown or forged group ids would otherwise come back forever
+            Err(e) => {
+                log::warn!(
+                    "Dropping message {} for group {}: {}",
+                    message.message_id, message.conversation_id, e
+                );
+                return Ok(None);
+            }
+        },
+    };
+    if group.is_member(&message.sender_id) {
+        Ok(Some(message))
+    } else {
+        log::warn!(
+            "Dropping message {} from {}, who isn't in group {}",
+            message.message_id, message.sender_id, group.group_id
+        );
+        Ok(None)
+    }
+}
+
+/// The file key of a file payload: `encryption_key` when sent to one
+/// peer, or our entry in the `wrapped_keys` recipients block when shared
+/// with several (see `CryptoEngine::wrap_key_for_members`)
+fn file_key(crypto: &CryptoEngine, sender_id: &str, content: &serde_json::Value) -> Option<String> {
+    if let Some(key) = content["encryption_key"].as_str() {
+        return Some(key.to_string());
+    }
+    let block = content["wrapped_keys"].as_str()?;
+    match crypto.unwrap_key_from(sender_id, block) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            log::warn!("No usable file key from {}: {}", sender_id, e);
+            None
+        }
+    }
+}
+
+struct PoolInner {
+    peer_keys: Arc<PeerKeys>,
+    api: Arc<dyn ApiTransport>,
+    storage: Arc<LocalStorage>,
+    ws: Arc<RwLock<Option<Arc<dyn WsTransport>>>>,
+    events: EventSender,
+    permits: Semaphore,
+    /// Envelopes waiting per sender; an entry exists while a task drains it
+    queues: Mutex<HashMap<String, VecDeque<MessageEnvelope>>>,
+}
+
+pub(crate) struct DecryptPool {
+    inner: Arc<PoolInner>,
+    handle: Handle,
+}
+
+impl DecryptPool {
+    pub(crate) fn new(
+        handle: Handle,
+        workers: usize,
+        peer_keys: Arc<PeerKeys>,
+        api: Arc<dyn ApiTransport>,
+        storage: Arc<LocalStorage>,
+        ws: Arc<RwLock<Option<Arc<dyn WsTransport>>>>,
+        events: EventSender,
+    ) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                peer_keys,
+                api,
+                storage,
+                ws,
+                events,
+                permits: Semaphore::new(workers.max(1)),
+                queues: Mutex::new(HashMap::new()),
+            }),
+            handle,
+        }
+    }
+
+    /// Queue envelopes for decryption and return immediately
+    pub(crate) fn submit(&self, envelopes: Vec<MessageEnvelope>) {
+        let mut queues = self.inner.queues.lock();
+        for envelope in envelopes {
+            if let Some(queue) = queues.get_mut(&envelope.sender_id) {
+                // Already being drained; the running task picks it up in order
+                queue.push_back(envelope);
+                continue;
+            }
+
+            let sender_id = envelope.sender_id.clone();
+            queues.insert(sender_id.clone(), VecDeque::from([envelope]));
+            self.handle.spawn(drain(self.inner.clone(), sender_id));
+        }
+    }
+}
+
+async fn drain(inner: Arc<PoolInner>, sender_id: String) {
+    let Ok(_permit) = inner.permits.acquire().await else {
+        return;
+    };
+
+    loop {
+        let batch: Vec<MessageEnvelope> = {
+            let mut queues = inner.queues.lock();
+            match queues.get_mut(&sender_id) {
+                Some(queue) if !queue.is_empty() => queue.drain(..).collect(),
+                _ => {
+                    queues.remove(&sender_id);
+                    return;
+                }
+            }
+        };
+
+        let mut messages = Vec::with_capacity(batch.len());
+        let mut ids = Vec::with_capacity(batch.len());
+        let mut failures = Vec::new();
+        for envelope in batch {
+            let message_id = envelope.message_id.clone();
+            match open_envelope(&inner.peer_keys, inner.api.as_ref(), &inner.storage, envelope).await {
+                Ok(message) => {
+                    ids.push(message_id);
+                    messages.extend(message);
+                }
+                Err(e) => failures.push((message_id, e.to_string())),
+            }
+        }
+
+        // Undecryptable envelopes stay unacked so the server replays them
+        match inner.storage.save_messages(&messages) {
+            Ok(deleted) => messages.retain(|message| !deleted.contains(&message.message_id)),
+            Err(e) => {
+                log::error!("Failed to store messages from {}: {}", sender_id, e);
+                continue;
+            }
+        }
+        let ws = inner.ws.read().clone();
+        if let Some(ws) = ws.filter(|_| !ids.is_empty()) {
+            if let Err(e) = ws.send_ack(&ids).await {
+                log::warn!("Failed to ack messages: {}", e);
+            }
+        }
+
+        for message in messages {
+            inner.events.send(ClientEvent::MessageReceived(Box::new(message)));
+        }
+        for (message_id, error) in failures {
+            inner.events.send(ClientEvent::DecryptionFailed {
+                message_id,
+                sender_id: sender_id.clone(),
+                error,
+            });
+        }
+    }
+}
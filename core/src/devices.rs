@@ -0,0 +1,176 @@
+//! Encryption for each of a peer's devices
+//!
+//! Every signed-in device has an identity key of its own. A message goes
+//! out as one copy per device of the recipient, each encrypted under a
+//! session with that device and addressed to it with `recipient_device_id`.
+//! A device on the peer's trusted key shares the session with the peer, so
+//! key verification and ratchets carry on for it. When the server doesn't
+//! list devices, one copy goes to the peer's key as before.
+//!
+//! The server could list a device of its own, so other keys are only used
+//! when the peer's trusted key certified them (see [`certificate_data`])
+//! or the user accepted them, see `PeerKeys::check_device`.
+//!
+//! Device lists are kept for `DEVICE_LIST_TTL`, so a burst of messages to
+//! a peer costs one request.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::cache::{CacheLimits, CacheStats, LruCache};
+use crate::crypto::{device_session_id, verify_signature};
+use crate::error::Result;
+use crate::identity::PeerKeys;
+use crate::models::DeviceKey;
+use crate::transport::ApiTransport;
+
+/// How long a peer's device list is used before it is fetched again
+const DEVICE_LIST_TTL: Duration = Duration::from_secs(60);
+
+/// One encrypted copy of a message
+pub(crate) struct DeviceCopy {
+    /// The device it is addressed to; `None` for all of the peer's devices
+    pub(crate) device_id: Option<String>,
+    /// The session it is encrypted under
+    pub(crate) session_id: String,
+}
+
+/// What the user's identity key signs to certify one of their devices
+pub(crate) fn certificate_data(user_id: &str, device_id: &str, public_key: &str) -> Vec<u8> {
+    format!("privmsg-device-certificate\n{}\n{}\n{}", user_id, device_id, public_key).into_bytes()
+}
+
+/// Whether `key` carries a valid certificate from `trusted`, the identity
+/// key trusted for `user_id`
+pub(crate) fn is_certified(trusted: &str, user_id: &str, key: &DeviceKey) -> bool {
+    let Some(signature) = key.signature.as_deref() else {
+        return false;
+    };
+    let data = certificate_data(user_id, &key.device_id, &key.public_key);
+    verify_signature(trusted, &data, signature).unwrap_or(false)
+}
+
+/// Peers' device lists as last fetched, for the most recently used peers
+pub(crate) struct DeviceLists {
+    lists: Mutex<LruCache<String, (Instant, Vec<DeviceKey>)>>,
+}
+
+impl DeviceLists {
+    pub(crate) fn new() -> Self {
+        Self {
+            lists: Mutex::new(LruCache::new(CacheLimits::default().device_lists)),
+        }
+    }
+
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.lists.lock().set_capacity(capacity);
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.lists.lock().stats()
+    }
+
+    /// The devices of `user_id`, fetched unless a recent list is kept or
+    /// always with `refresh`. A failed fetch gives no devices.
+    async fn get(&self, api: &dyn ApiTransport, user_id: &str, refresh: bool) -> Vec<DeviceKey> {
+        if !refresh {
+            if let Some((fetched_at, keys)) = self.lists.lock().get(&user_id.to_string()) {
+                if fetched_at.elapsed() < DEVICE_LIST_TTL {
+                    return keys.clone();
+                }
+            }
+        }
+        match api.get_device_keys(user_id).await {
+            Ok(keys) => {
+                self.lists
+                    .lock()
+                    .insert(user_id.to_string(), (Instant::now(), keys.clone()));
+                keys
+            }
+            Err(e) => {
+                log::warn!("Devices of {} not fetched: {}", user_id, e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// The copies a message to `user_id` goes out as, with sessions made for
+/// devices new to us. Devices whose key can't be used or isn't trusted are
+/// left out.
+pub(crate) async fn copies_for(
+    peer_keys: &PeerKeys,
+    api: &dyn ApiTransport,
+    user_id: &str,
+) -> Result<Vec<DeviceCopy>> {
+    let keys = peer_keys.devices().get(api, user_id, false).await;
+    let trusted = peer_keys.trusted_key(user_id)?;
+    let crypto = peer_keys.crypto();
+    let mut copies = Vec::with_capacity(keys.len());
+    for key in keys {
+        let session_id = if trusted.as_deref() == Some(key.public_key.as_str()) {
+            user_id.to_string()
+        } else {
+            if !peer_keys.check_device(user_id, &key)? {
+                continue;
+            }
+            let session_id = device_session_id(user_id, &key.device_id);
+            if !crypto.has_session(&session_id) {
+                if let Err(e) = crypto.establish_device_session(user_id, &key.device_id, &key.public_key) {
+                    log::warn!("Leaving device {} of {} out: {}", key.device_id, user_id, e);
+                    continue;
+                }
+            }
+            session_id
+        };
+        copies.push(DeviceCopy {
+            device_id: Some(key.device_id),
+            session_id,
+        });
+    }
+    if copies.is_empty() {
+        copies.push(DeviceCopy {
+            device_id: None,
+            session_id: user_id.to_string(),
+        });
+    }
+    Ok(copies)
+}
+
+/// Decrypt a copy sent from one of `sender_id`'s devices not on their
+/// trusted key. The kept device list is tried first, then a fresh one;
+/// devices that aren't trusted are skipped.
+pub(crate) async fn decrypt_from_devices(
+    peer_keys: &PeerKeys,
+    api: &dyn ApiTransport,
+    sender_id: &str,
+    ciphertext: &str,
+) -> Option<String> {
+    let trusted = peer_keys.trusted_key(sender_id).ok().flatten();
+    let crypto = peer_keys.crypto();
+    let mut tried = Vec::new();
+    for refresh in [false, true] {
+        for key in peer_keys.devices().get(api, sender_id, refresh).await {
+            if trusted.as_deref() == Some(key.public_key.as_str()) || tried.contains(&key) {
+                continue;
+            }
+            if !peer_keys.check_device(sender_id, &key).unwrap_or(false) {
+                tried.push(key);
+                continue;
+            }
+            let session_id = device_session_id(sender_id, &key.device_id);
+            let usable = crypto.has_session(&session_id)
+                || crypto
+                    .establish_device_session(sender_id, &key.device_id, &key.public_key)
+                    .is_ok();
+            if usable {
+                if let Ok(plaintext) = crypto.decrypt_from(&session_id, ciphertext) {
+                    return Some(plaintext);
+                }
+            }
+            tried.push(key);
+        }
+    }
+    None
+}
@@ -0,0 +1,253 @@
+//! Self-checks for a debug screen or a bug report
+//!
+//! `PrivMsgClient::run_diagnostics` tests each thing the client depends on
+//! and returns a `DiagnosticsReport`. The report holds no message content,
+//! keys or tokens, so users can paste it into an issue as is.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+use privmsg_proto::turn_address;
+use serde::Serialize;
+use tokio::net::TcpStream;
+
+use crate::crypto::CryptoEngine;
+use crate::storage::LocalStorage;
+use crate::transport::{ApiTransport, WsConnector, WsTransport};
+
+/// Clock skew above this breaks nothing yet but is worth fixing
+const CLOCK_SKEW_WARNING_SECS: i64 = 30;
+/// Clock skew above this makes message ordering and expiry unreliable
+const CLOCK_SKEW_FAILURE_SECS: i64 = 300;
+/// How long a TURN server gets to accept a TCP connection
+const TURN_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Failed,
+    /// Couldn't run, e.g. it needs a login
+    Skipped,
+}
+
+impl CheckStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Failed => "failed",
+            CheckStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Outcome of one check
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticsReport {
+    /// Unix seconds
+    pub generated_at: i64,
+    pub client_version: String,
+    /// `os/arch`
+    pub platform: String,
+    pub checks: Vec<DiagnosticCheck>,
+}
+
+impl DiagnosticsReport {
+    /// True when no check failed
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Failed)
+    }
+
+    pub fn check(&self, name: &str) -> Option<&DiagnosticCheck> {
+        self.checks.iter().find(|c| c.name == name)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Plain text for pasting into an issue
+    pub fn to_text(&self) -> String {
+        let mut out = format!(
+            "PrivMsg diagnostics\nversion: {}\nplatform: {}\ngenerated: {}\n\n",
+            self.client_version,
+            self.platform,
+            chrono::DateTime::from_timestamp(self.generated_at, 0)
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default(),
+        );
+        for check in &self.checks {
+            out.push_str(&format!(
+                "[{}] {}: {} ({} ms)\n",
+                check.status.as_str(),
+                check.name,
+                check.detail,
+                check.duration_ms
+            ));
+        }
+        out
+    }
+}
+
+/// Everything the checks look at
+pub(crate) struct Targets<'a> {
+    pub api: &'a dyn ApiTransport,
+    pub connector: &'a dyn WsConnector,
+    pub ws: &'a RwLock<Option<Arc<dyn WsTransport>>>,
+    pub storage: &'a LocalStorage,
+    pub crypto: &'a CryptoEngine,
+}
+
+pub(crate) async fn run(targets: Targets<'_>) -> DiagnosticsReport {
+    let token = targets.storage.get_session().map(|s| s.token);
+
+    let mut checks = Vec::new();
+    checks.push(timed("server", check_server(targets.api)).await);
+    checks.push(timed("websocket", check_websocket(targets.connector, targets.ws, token.as_deref())).await);
+    checks.push(timed("clock", check_clock(targets.api)).await);
+    checks.push(timed("turn", check_turn(targets.api, token.is_some())).await);
+    checks.push(timed("database", async { check_database(targets.storage) }).await);
+    checks.push(timed("keys", async { check_keys(targets.crypto) }).await);
+
+    DiagnosticsReport {
+        generated_at: chrono::Utc::now().timestamp(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        platform: format!("{}/{}", std::env::consts::OS, std::env::consts::ARCH),
+        checks,
+    }
+}
+
+async fn timed(
+    name: &str,
+    check: impl std::future::Future<Output = (CheckStatus, String)>,
+) -> DiagnosticCheck {
+    let start = Instant::now();
+    let (status, detail) = check.await;
+    DiagnosticCheck {
+        name: name.to_string(),
+        status,
+        detail,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+async fn check_server(api: &dyn ApiTransport) -> (CheckStatus, String) {
+    match api.check_health().await {
+        Ok(true) => (CheckStatus::Ok, "health check passed".into()),
+        Ok(false) => (CheckStatus::Failed, "server unreachable or unhealthy".into()),
+        Err(e) => (CheckStatus::Failed, e.to_string()),
+    }
+}
+
+/// Uses the open connection if there is one, otherwise opens and closes a
+/// test connection without replacing the client's
+async fn check_websocket(
+    connector: &dyn WsConnector,
+    ws: &RwLock<Option<Arc<dyn WsTransport>>>,
+    token: Option<&str>,
+) -> (CheckStatus, String) {
+    if ws.read().as_ref().is_some_and(|ws| ws.is_connected()) {
+        return (CheckStatus::Ok, "connected".into());
+    }
+    let Some(token) = token else {
+        return (CheckStatus::Skipped, "not logged in".into());
+    };
+
+    match connector.connect(token).await {
+        Ok(test) => {
+            let _ = test.disconnect().await;
+            (CheckStatus::Warning, "test connection succeeded, but the client is disconnected".into())
+        }
+        Err(e) => (CheckStatus::Failed, e.to_string()),
+    }
+}
+
+async fn check_clock(api: &dyn ApiTransport) -> (CheckStatus, String) {
+    let before = chrono::Utc::now().timestamp_millis();
+    let server = match api.server_time().await {
+        Ok(Some(server)) => server,
+        Ok(None) => return (CheckStatus::Skipped, "transport doesn't report server time".into()),
+        Err(e) => return (CheckStatus::Failed, e.to_string()),
+    };
+    // Compare against the middle of the round trip
+    let local = (before + chrono::Utc::now().timestamp_millis()) / 2000;
+
+    let skew = local - server;
+    let detail = match skew {
+        0 => "in sync with the server".to_string(),
+        s if s > 0 => format!("{}s ahead of the server", s),
+        s => format!("{}s behind the server", -s),
+    };
+    let status = match skew.abs() {
+        s if s > CLOCK_SKEW_FAILURE_SECS => CheckStatus::Failed,
+        s if s > CLOCK_SKEW_WARNING_SECS => CheckStatus::Warning,
+        _ => CheckStatus::Ok,
+    };
+    (status, detail)
+}
+
+/// TCP connect to each TURN URL. UDP can't be checked this way, so an
+/// unreachable server is only a warning.
+async fn check_turn(api: &dyn ApiTransport, logged_in: bool) -> (CheckStatus, String) {
+    if !logged_in {
+        return (CheckStatus::Skipped, "not logged in".into());
+    }
+    let credentials = match api.get_turn_credentials().await {
+        Ok(credentials) => credentials,
+        Err(e) => return (CheckStatus::Failed, format!("no TURN credentials: {}", e)),
+    };
+    if credentials.urls.is_empty() {
+        return (CheckStatus::Warning, "server lists no TURN servers; calls need a direct path".into());
+    }
+
+    let mut status = CheckStatus::Ok;
+    let mut results = Vec::new();
+    for url in &credentials.urls {
+        let Some(addr) = turn_address(url) else {
+            status = CheckStatus::Warning;
+            results.push(format!("{}: not a turn: URL", url));
+            continue;
+        };
+        match tokio::time::timeout(TURN_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+            Ok(Ok(_)) => results.push(format!("{} reachable", addr)),
+            Ok(Err(e)) => {
+                status = CheckStatus::Warning;
+                results.push(format!("{} unreachable: {}", addr, e));
+            }
+            Err(_) => {
+                status = CheckStatus::Warning;
+                results.push(format!("{} timed out", addr));
+            }
+        }
+    }
+    (status, results.join("; "))
+}
+
+fn check_database(storage: &LocalStorage) -> (CheckStatus, String) {
+    match storage.integrity_check() {
+        Ok(problems) if problems.is_empty() => (CheckStatus::Ok, "integrity check passed".into()),
+        Ok(problems) => (CheckStatus::Failed, problems.join("; ")),
+        Err(e) => (CheckStatus::Failed, e.to_string()),
+    }
+}
+
+fn check_keys(crypto: &CryptoEngine) -> (CheckStatus, String) {
+    match crypto.get_public_key() {
+        Ok(_) => (
+            CheckStatus::Ok,
+            format!("identity key loaded ({})", crypto.identity_suite().id()),
+        ),
+        Err(_) => (CheckStatus::Failed, "no identity key; init_keys was never called".into()),
+    }
+}
@@ -0,0 +1,254 @@
+//! Finding a server's endpoints from its domain
+//!
+//! Two sources are consulted, in order:
+//! - DNS SRV records for `_privmsg._tcp.<domain>`, by priority and then
+//!   weight. SRV targets are expected to speak TLS.
+//! - `https://<domain>/.well-known/privmsg`, a JSON document of the form
+//!   `{"endpoints": [{"host": "chat.example.org", "port": 443, "use_tls": true}]}`
+//!
+//! The SRV query goes to the first nameserver in `/etc/resolv.conf`; where
+//! there is none, only the well-known document is used.
+
+use crate::config::Endpoint;
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const SRV_SERVICE: &str = "_privmsg._tcp";
+const WELL_KNOWN_PATH: &str = "/.well-known/privmsg";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Deserialize)]
+struct WellKnown {
+    endpoints: Vec<Endpoint>,
+}
+
+/// Endpoints published for `domain`, most preferred first
+pub async fn discover(domain: &str) -> Result<Vec<Endpoint>> {
+    let mut endpoints = lookup_srv(domain).await.unwrap_or_else(|e| {
+        log::debug!("SRV lookup for {} failed: {}", domain, e);
+        Vec::new()
+    });
+
+    match fetch_well_known(domain).await {
+        Ok(published) => {
+            for endpoint in published {
+                if !endpoints.contains(&endpoint) {
+                    endpoints.push(endpoint);
+                }
+            }
+        }
+        Err(e) => log::debug!("No well-known endpoints for {}: {}", domain, e),
+    }
+
+    if endpoints.is_empty() {
+        return Err(Error::Network(format!("No endpoints published for {}", domain)));
+    }
+    Ok(endpoints)
+}
+
+async fn fetch_well_known(domain: &str) -> Result<Vec<Endpoint>> {
+    let client = reqwest::Client::builder().timeout(LOOKUP_TIMEOUT).build()?;
+    let resp = client
+        .get(format!("https://{}{}", domain, WELL_KNOWN_PATH))
+        .send()
+        .await?
+        .error_for_status()?;
+    let document: WellKnown = resp.json().await?;
+    Ok(document.endpoints)
+}
+
+// ============================================================================
+// DNS SRV
+// ============================================================================
+
+async fn lookup_srv(domain: &str) -> Result<Vec<Endpoint>> {
+    let nameserver = system_nameserver()
+        .ok_or_else(|| Error::Network("No nameserver configured".into()))?;
+
+    let id: u16 = rand::random();
+    let query = srv_query(id, &format!("{}.{}", SRV_SERVICE, domain))?;
+
+    let bind = if nameserver.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = tokio::net::UdpSocket::bind(bind).await?;
+    socket.connect((nameserver, 53)).await?;
+    socket.send(&query).await?;
+
+    let mut buf = [0u8; 4096];
+    let len = tokio::time::timeout(LOOKUP_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| Error::Network("DNS lookup timed out".into()))??;
+    parse_srv_response(id, &buf[..len])
+}
+
+fn system_nameserver() -> Option<IpAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse().ok())
+}
+
+fn srv_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(64);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::InvalidConfig(format!("Invalid domain name: {}", name)));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+fn malformed() -> Error {
+    Error::Network("Malformed DNS response".into())
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16> {
+    packet
+        .get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(malformed)
+}
+
+fn parse_srv_response(id: u16, packet: &[u8]) -> Result<Vec<Endpoint>> {
+    if read_u16(packet, 0)? != id {
+        return Err(Error::Network("DNS response does not match the query".into()));
+    }
+    match read_u16(packet, 2)? & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(Error::Network(format!("DNS lookup failed with rcode {}", rcode))),
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(packet, pos)?;
+        let record_type = read_u16(packet, pos)?;
+        let data_len = read_u16(packet, pos + 8)? as usize;
+        let data = pos + 10;
+
+        if record_type == TYPE_SRV {
+            let priority = read_u16(packet, data)?;
+            let weight = read_u16(packet, data + 2)?;
+            let port = read_u16(packet, data + 4)?;
+            let target = read_name(packet, data + 6)?;
+            // A target of "." means the service isn't offered
+            if !target.is_empty() {
+                records.push((priority, weight, Endpoint::new(&target, port, true)));
+            }
+        }
+        pos = data + data_len;
+    }
+
+    records.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    Ok(records.into_iter().map(|(_, _, endpoint)| endpoint).collect())
+}
+
+/// Position just past the (possibly compressed) name at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        match *packet.get(pos).ok_or_else(malformed)? {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn read_name(packet: &[u8], mut pos: usize) -> Result<String> {
+    let mut labels = Vec::new();
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(pos).ok_or_else(malformed)?;
+        if len & 0xc0 == 0xc0 {
+            // Compression pointer; bounded so a pointer loop can't spin
+            jumps += 1;
+            if jumps > 16 {
+                return Err(malformed());
+            }
+            let low = *packet.get(pos + 1).ok_or_else(malformed)?;
+            pos = ((len as usize & 0x3f) << 8) | low as usize;
+            continue;
+        }
+        if len == 0 {
+            return Ok(labels.join("."));
+        }
+        let label = packet
+            .get(pos + 1..pos + 1 + len as usize)
+            .ok_or_else(malformed)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn srv_answer(priority: u16, weight: u16, port: u16, target: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        for n in [priority, weight, port] {
+            data.extend_from_slice(&n.to_be_bytes());
+        }
+        for label in target.split('.') {
+            data.push(label.len() as u8);
+            data.extend_from_slice(label.as_bytes());
+        }
+        data.push(0);
+
+        // Name points back at the question
+        let mut record = vec![0xc0, 12];
+        record.extend_from_slice(&TYPE_SRV.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&300u32.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(&data);
+        record
+    }
+
+    #[test]
+    fn test_parse_srv_response() {
+        let mut packet = srv_query(0x1234, "_privmsg._tcp.example.org").unwrap();
+        packet[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        packet[6..8].copy_from_slice(&3u16.to_be_bytes());
+        packet.extend(srv_answer(20, 0, 9443, "backup.example.org"));
+        packet.extend(srv_answer(10, 5, 443, "chat.example.org"));
+        packet.extend(srv_answer(10, 50, 8443, "chat2.example.org"));
+
+        let endpoints = parse_srv_response(0x1234, &packet).unwrap();
+        assert_eq!(
+            endpoints,
+            vec![
+                Endpoint::new("chat2.example.org", 8443, true),
+                Endpoint::new("chat.example.org", 443, true),
+                Endpoint::new("backup.example.org", 9443, true),
+            ]
+        );
+
+        assert!(parse_srv_response(0x4321, &packet).is_err());
+        assert!(parse_srv_response(0x1234, &packet[..packet.len() - 4]).is_err());
+
+        // NXDOMAIN is an empty answer, not an error
+        packet[2..4].copy_from_slice(&0x8183u16.to_be_bytes());
+        assert!(parse_srv_response(0x1234, &packet).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+//! Throttling of draft syncs between the user's own devices
+//!
+//! Frontends call `set_draft` on every keystroke. A conversation's draft
+//! goes to the other devices at most once per [`DRAFT_SYNC_INTERVAL`]; one
+//! typed in the meantime is held and the latest goes out when the window
+//! ends, on the next poll, or on `flush_drafts`.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Shortest time between two syncs of one conversation's draft
+pub const DRAFT_SYNC_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Default)]
+struct Windows {
+    last_sent: HashMap<String, Instant>,
+    /// Conversations with a draft newer than the last one sent
+    held: HashSet<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct DraftThrottle {
+    windows: Mutex<Windows>,
+}
+
+impl DraftThrottle {
+    /// Whether a conversation's new draft may be sent at `now`; if not it
+    /// is held for [`due`](Self::due)
+    pub(crate) fn try_send(&self, conversation_id: &str, now: Instant) -> bool {
+        let mut windows = self.windows.lock();
+        let open = windows
+            .last_sent
+            .get(conversation_id)
+            .is_none_or(|sent| now.duration_since(*sent) >= DRAFT_SYNC_INTERVAL);
+        if open {
+            windows.last_sent.insert(conversation_id.to_string(), now);
+            windows.held.remove(conversation_id);
+        } else {
+            windows.held.insert(conversation_id.to_string());
+        }
+        open
+    }
+
+    /// Take the held conversations whose window has ended at `now`, or all
+    /// of them with `all`
+    pub(crate) fn due(&self, now: Instant, all: bool) -> Vec<String> {
+        let mut windows = self.windows.lock();
+        let Windows { last_sent, held } = &mut *windows;
+        let due: Vec<String> = held
+            .iter()
+            .filter(|id| {
+                all || last_sent
+                    .get(*id)
+                    .is_none_or(|sent| now.duration_since(*sent) >= DRAFT_SYNC_INTERVAL)
+            })
+            .cloned()
+            .collect();
+        for id in &due {
+            held.remove(id);
+            last_sent.insert(id.clone(), now);
+        }
+        due
+    }
+
+    /// Don't hold a conversation's draft any more, e.g. once it was sent
+    /// as a message
+    pub(crate) fn forget(&self, conversation_id: &str) {
+        self.windows.lock().held.remove(conversation_id);
+    }
+}
@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+pub use privmsg_proto::{ErrorCode, RevocationReason, ServerError};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Crypto error: {0}")]
@@ -45,10 +47,94 @@ pub enum Error {
 
     #[error("HTTP error: {0}")]
     Http(String),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Invalid archive: {0}")]
+    InvalidArchive(String),
+
+    #[error("Transfer not found: {0}")]
+    TransferNotFound(String),
+
+    #[error("Transfer paused")]
+    TransferPaused,
+
+    #[error("Transfer cancelled")]
+    TransferCancelled,
+
+    /// The server no longer has the attachment's file; the sender can be
+    /// asked for it again with `PrivMsgClient::request_reupload`
+    #[error("Attachment expired on the server")]
+    AttachmentExpired,
+
+    #[error("Direct transfer failed: {0}")]
+    DirectTransfer(String),
+
+    #[error("LAN delivery failed: {0}")]
+    Lan(String),
+
+    /// Switched off or locked by the server operator's client policy
+    #[error("Not allowed by the server policy: {0}")]
+    Policy(String),
+
+    #[error("Rate limited by server")]
+    RateLimited,
+
+    #[error("Payload too large")]
+    PayloadTooLarge,
+
+    #[error("Recipient has blocked you")]
+    RecipientBlocked,
+
+    /// The recipient's account was deactivated or deleted; the
+    /// conversation is read-only
+    #[error("Recipient is no longer available")]
+    RecipientUnavailable,
+
+    /// The peer's identity key changed and the user hasn't accepted the
+    /// new one yet, see `PrivMsgClient::trust_new_key`
+    #[error("Security key of {0} changed and is not trusted yet")]
+    KeyConflict(String),
+
+    /// The server wants a proof of work with the login, or the one sent
+    /// had expired
+    #[error("Login needs a proof of work")]
+    ChallengeRequired,
+
+    /// The server is in maintenance mode; carries its message
+    #[error("{0}")]
+    Maintenance(String),
+
+    #[error("Server error: {0}")]
+    Server(ServerError),
+
+    /// The server ended this session; local session state has been cleared
+    #[error("{0}")]
+    SessionRevoked(RevocationReason),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Codes clients act on get their own variant; the rest keep the server's
+/// code and message
+impl From<ServerError> for Error {
+    fn from(e: ServerError) -> Self {
+        match e.code {
+            ErrorCode::Unauthorized | ErrorCode::InvalidCredentials | ErrorCode::AuthFailed => {
+                Error::InvalidCredentials
+            }
+            ErrorCode::RateLimited => Error::RateLimited,
+            ErrorCode::PayloadTooLarge => Error::PayloadTooLarge,
+            ErrorCode::RecipientBlocked => Error::RecipientBlocked,
+            ErrorCode::RecipientUnavailable => Error::RecipientUnavailable,
+            ErrorCode::ChallengeRequired => Error::ChallengeRequired,
+            ErrorCode::Maintenance => Error::Maintenance(e.message),
+            _ => Error::Server(e),
+        }
+    }
+}
+
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Self {
         Error::Http(e.to_string())
@@ -0,0 +1,218 @@
+//! Events delivered from background work to the application
+
+use crate::error::{RevocationReason, ServerError};
+use crate::models::{
+    Announcement, CallSignal, ClientPolicy, DeviceRenamed, Draft, EphemeralPayload, FileExpiry, Maintenance,
+    Message, MessageStatus, ReadPosition, ReuploadRequest,
+};
+use crate::notifications::Notifier;
+use parking_lot::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Something that happened without the application asking for it
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// An incoming message was decrypted and stored
+    MessageReceived(Box<Message>),
+    /// A `MessageType::System` notice was stored in a conversation
+    SystemMessage(Box<Message>),
+    /// A sent message moved on, e.g. to `MessageStatus::Delivered` once the
+    /// recipient's device received it; local storage is already updated
+    MessageStatusChanged {
+        conversation_id: String,
+        message_id: String,
+        status: MessageStatus,
+    },
+    /// The sender of a message deleted it for everyone, the user did on
+    /// another of their devices, or it disappeared at the end of the
+    /// conversation's message lifetime; it is already gone from local
+    /// storage
+    MessageDeleted {
+        conversation_id: String,
+        message_id: String,
+    },
+    /// The peer, or the user on another device, changed how long new
+    /// messages in a conversation are kept; `None` keeps them
+    ConversationTtlChanged {
+        conversation_id: String,
+        ttl_seconds: Option<u64>,
+    },
+    /// An incoming message could not be decrypted; it stays on the server
+    DecryptionFailed {
+        message_id: String,
+        sender_id: String,
+        error: String,
+    },
+    /// Another of the user's devices read a conversation; its unread count
+    /// in local storage is already updated
+    ConversationRead(ReadPosition),
+    /// Another of the user's devices edited or cleared a conversation's
+    /// draft; local storage is already updated
+    DraftChanged(Draft),
+    /// An online-only payload from a peer; nothing about it is stored
+    Ephemeral {
+        sender_id: String,
+        payload: EphemeralPayload,
+    },
+    /// The peer extended or deleted a file shared in the conversation; the
+    /// attachment in local storage is already updated
+    AttachmentExpiryChanged {
+        conversation_id: String,
+        expiry: FileExpiry,
+    },
+    /// The peer asked for an attachment they can no longer download to be
+    /// uploaded again. Confirm with `PrivMsgClient::reupload_attachment`;
+    /// with `set_auto_reupload` on, this only comes when there is no local
+    /// copy to upload or the upload failed.
+    ReuploadRequested {
+        conversation_id: String,
+        request: ReuploadRequest,
+    },
+    /// An attachment in the conversation points at a new upload: the peer
+    /// uploaded it again, or this client did on the peer's request. The
+    /// message in local storage is already updated.
+    AttachmentReuploaded {
+        conversation_id: String,
+        message_id: String,
+    },
+    /// A call signal from a peer; file transfer signals are handled internally
+    CallSignal(CallSignal),
+    /// The server rejected something sent over the WebSocket. Convert it
+    /// with `Error::from` to match on the codes clients handle.
+    ServerError(ServerError),
+    /// The server said the peer's account was deactivated or deleted, so
+    /// the conversation became read-only, or the peer turned out to be back
+    PeerAvailabilityChanged { peer_id: String, available: bool },
+    /// The server's client policy changed; core already applied what it
+    /// manages
+    PolicyChanged(Box<ClientPolicy>),
+    /// Another of the user's devices was renamed
+    DeviceRenamed(DeviceRenamed),
+    /// The banner to show changed: the server set or cleared its service
+    /// notice. `None` hides the banner.
+    AnnouncementChanged(Option<Announcement>),
+    /// The server turned maintenance mode on or off (`None`). Logins and
+    /// uploads fail with `Error::Maintenance` while it is on.
+    MaintenanceChanged(Option<Maintenance>),
+    /// A refreshed profile gave a conversation a new name or avatar; local
+    /// storage is already updated
+    ConversationProfileChanged {
+        conversation_id: String,
+        peer_name: Option<String>,
+        peer_avatar: Option<String>,
+    },
+    /// The server ended this session, e.g. because the device was removed.
+    /// Local session state is already cleared; the user has to log in again.
+    SessionRevoked(RevocationReason),
+}
+
+impl ClientEvent {
+    /// The conversation (peer id) the event belongs to, if any
+    pub fn conversation_id(&self) -> Option<&str> {
+        match self {
+            ClientEvent::MessageReceived(message) | ClientEvent::SystemMessage(message) => {
+                Some(&message.conversation_id)
+            }
+            ClientEvent::DecryptionFailed { sender_id, .. }
+            | ClientEvent::Ephemeral { sender_id, .. } => Some(sender_id),
+            ClientEvent::ConversationRead(position) => Some(&position.conversation_id),
+            ClientEvent::DraftChanged(draft) => Some(&draft.conversation_id),
+            ClientEvent::AttachmentExpiryChanged { conversation_id, .. }
+            | ClientEvent::ReuploadRequested { conversation_id, .. }
+            | ClientEvent::AttachmentReuploaded { conversation_id, .. }
+            | ClientEvent::MessageStatusChanged { conversation_id, .. }
+            | ClientEvent::MessageDeleted { conversation_id, .. }
+            | ClientEvent::ConversationTtlChanged { conversation_id, .. }
+            | ClientEvent::ConversationProfileChanged { conversation_id, .. } => Some(conversation_id),
+            ClientEvent::CallSignal(signal) => Some(&signal.sender_id),
+            ClientEvent::PeerAvailabilityChanged { peer_id, .. } => Some(peer_id),
+            ClientEvent::ServerError(_)
+            | ClientEvent::PolicyChanged(_)
+            | ClientEvent::DeviceRenamed(_)
+            | ClientEvent::AnnouncementChanged(_)
+            | ClientEvent::MaintenanceChanged(_)
+            | ClientEvent::SessionRevoked(_) => None,
+        }
+    }
+}
+
+/// Conversation id and where its events go
+type Subscriber = (String, Sender<ClientEvent>);
+
+#[derive(Clone)]
+pub(crate) struct EventSender {
+    events: Sender<ClientEvent>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    notifier: Arc<Notifier>,
+}
+
+impl EventSender {
+    pub(crate) fn send(&self, event: ClientEvent) {
+        // Message rules run in `announce`; subscribers see their effect
+        self.notifier.announce(&event);
+        self.notify_subscribers(&event);
+        // The receiver lives as long as the client, so this only fails during drop
+        let _ = self.events.send(event);
+    }
+
+    /// Pass `event` to the conversation's subscribers only, for things the
+    /// application already learns about another way
+    pub(crate) fn notify_subscribers(&self, event: &ClientEvent) {
+        let Some(conversation_id) = event.conversation_id() else {
+            return;
+        };
+        // Dropped subscriptions are removed as their sends fail
+        self.subscribers
+            .lock()
+            .retain(|(id, tx)| id != conversation_id || tx.send(event.clone()).is_ok());
+    }
+
+    pub(crate) fn subscribe(&self, conversation_id: &str) -> Subscription {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().push((conversation_id.to_string(), tx));
+        Subscription(rx)
+    }
+}
+
+/// Events of a single conversation, alongside the client's own event
+/// stream. Dropping it unsubscribes.
+pub struct Subscription(Receiver<ClientEvent>);
+
+impl Subscription {
+    /// All events that are ready, without blocking
+    pub fn drain(&self) -> Vec<ClientEvent> {
+        self.0.try_iter().collect()
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub fn next(&self, timeout: Duration) -> Option<ClientEvent> {
+        self.0.recv_timeout(timeout).ok()
+    }
+}
+
+/// Receiving end of the client's event stream
+pub(crate) struct EventQueue(Mutex<Receiver<ClientEvent>>);
+
+impl EventQueue {
+    /// All events that are ready, without blocking
+    pub(crate) fn drain(&self) -> Vec<ClientEvent> {
+        self.0.lock().try_iter().collect()
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub(crate) fn next(&self, timeout: Duration) -> Option<ClientEvent> {
+        self.0.lock().recv_timeout(timeout).ok()
+    }
+}
+
+pub(crate) fn channel(notifier: Arc<Notifier>) -> (EventSender, EventQueue) {
+    let (tx, rx) = mpsc::channel();
+    let sender = EventSender {
+        events: tx,
+        subscribers: Arc::default(),
+        notifier,
+    };
+    (sender, EventQueue(Mutex::new(rx)))
+}
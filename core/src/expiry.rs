@@ -0,0 +1,71 @@
+//! Disappearing messages
+//!
+//! A conversation can have a message lifetime, synced with the peer and
+//! the user's other devices. Each message saved while it is set gets an
+//! expiry of the time it was saved plus the lifetime, so a sender's clock
+//! can't shorten or stretch it; turning it off or changing it leaves
+//! earlier messages' expiries as they were. A background job deletes
+//! expired messages from local storage, along with opened copies of their
+//! attachments, and announces each with `ClientEvent::MessageDeleted`.
+
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+
+use crate::error::Result;
+use crate::events::{ClientEvent, EventSender};
+use crate::storage::LocalStorage;
+use crate::tempfiles::TempFiles;
+
+/// How often the background job looks for expired messages
+const EXPIRY_TICK: Duration = Duration::from_secs(1);
+
+pub(crate) struct MessageExpiry {
+    storage: Arc<LocalStorage>,
+    /// Weak, so the client's open files are still shredded when it is
+    /// dropped while the job runs
+    temp_files: Weak<TempFiles>,
+    event_sender: EventSender,
+}
+
+impl MessageExpiry {
+    pub(crate) fn new(storage: Arc<LocalStorage>, temp_files: Weak<TempFiles>, event_sender: EventSender) -> Self {
+        Self {
+            storage,
+            temp_files,
+            event_sender,
+        }
+    }
+
+    pub(crate) fn spawn(self: &Arc<Self>, handle: &Handle) {
+        let this = self.clone();
+        handle.spawn(async move {
+            let mut tick = tokio::time::interval(EXPIRY_TICK);
+            loop {
+                tick.tick().await;
+                if let Err(e) = this.expire(chrono::Utc::now().timestamp_millis()) {
+                    log::warn!("Deleting expired messages failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Delete messages expired by `now` (Unix milliseconds) and announce
+    /// them. Returns how many there were.
+    pub(crate) fn expire(&self, now: i64) -> Result<usize> {
+        let expired = self.storage.delete_expired_messages(now)?;
+        let count = expired.len();
+        let temp_files = self.temp_files.upgrade();
+        for message in expired {
+            if let (Some(temp_files), Some(file_id)) = (&temp_files, &message.file_id) {
+                temp_files.close_file(file_id);
+            }
+            self.event_sender.send(ClientEvent::MessageDeleted {
+                conversation_id: message.conversation_id,
+                message_id: message.message_id,
+            });
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,83 @@
+//! Feature flags gating experimental subsystems
+//!
+//! Values resolve as described in [`privmsg_proto::flags`]: configuration,
+//! then `PRIVMSG_FLAGS`, then the server's client policy. Subsystems that
+//! read a flag on every use ask [`FeatureFlags::is_enabled`]; those that
+//! keep their own switch bind it, and it is updated whenever a layer
+//! changes.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use privmsg_proto::flags::{self, Flag, FlagState, FlagValues};
+
+use crate::models::ClientPolicy;
+
+#[derive(Default)]
+struct Layers {
+    config: FlagValues,
+    env: FlagValues,
+    policy: FlagValues,
+}
+
+pub(crate) struct FeatureFlags {
+    layers: RwLock<Layers>,
+    bound: RwLock<Vec<(Flag, Arc<AtomicBool>)>>,
+}
+
+impl FeatureFlags {
+    /// Flags from the environment, with nothing configured yet
+    pub(crate) fn from_env() -> Self {
+        let env = flags::from_env();
+        if !env.is_empty() {
+            log::info!("Feature flags from {}: {:?}", flags::FLAGS_ENV, env);
+        }
+        Self {
+            layers: RwLock::new(Layers { env, ..Default::default() }),
+            bound: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn states(&self) -> Vec<FlagState> {
+        let layers = self.layers.read();
+        flags::resolve(&layers.config, &layers.env, &layers.policy)
+    }
+
+    pub(crate) fn is_enabled(&self, flag: Flag) -> bool {
+        self.states()
+            .into_iter()
+            .find(|state| state.flag == flag)
+            .is_some_and(|state| state.enabled)
+    }
+
+    /// Keep `switch` set to the value of `flag`
+    pub(crate) fn bind(&self, flag: Flag, switch: Arc<AtomicBool>) {
+        switch.store(self.is_enabled(flag), Ordering::Relaxed);
+        self.bound.write().push((flag, switch));
+    }
+
+    /// Set or clear (`None`) one flag in the configuration layer
+    pub(crate) fn set_config(&self, flag: Flag, enabled: Option<bool>) {
+        let mut layers = self.layers.write();
+        match enabled {
+            Some(enabled) => layers.config.insert(flag, enabled),
+            None => layers.config.remove(&flag),
+        };
+        drop(layers);
+        self.sync();
+    }
+
+    pub(crate) fn set_policy(&self, policy: Option<&ClientPolicy>) {
+        self.layers.write().policy = policy.map(|p| flags::from_names(&p.flags)).unwrap_or_default();
+        self.sync();
+    }
+
+    fn sync(&self) {
+        let states = self.states();
+        for (flag, switch) in self.bound.read().iter() {
+            let enabled = states.iter().any(|state| state.flag == *flag && state.enabled);
+            switch.store(enabled, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Hooks that see outgoing text before it is encrypted
+//!
+//! Applications register hooks to rewrite what the user typed, e.g. render
+//! markdown, expand mentions or mask words. Hooks run in ascending `order`;
+//! hooks with the same order run in the order they were registered. Each
+//! sees the text as left by the ones before it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::error::Result;
+use crate::models::Metadata;
+
+/// The plaintext of a message about to be sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutgoingText {
+    pub recipient_id: String,
+    /// Message text, or the caption of an attachment
+    pub text: String,
+    pub metadata: Metadata,
+}
+
+/// Rewrites an outgoing message in place. An error aborts the send and is
+/// returned to the caller; nothing is stored.
+pub type OutgoingHook = Arc<dyn Fn(&mut OutgoingText) -> Result<()> + Send + Sync>;
+
+/// A registered hook, for `unregister_outgoing_hook`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u64);
+
+#[derive(Default)]
+pub(crate) struct OutgoingHooks {
+    /// Sorted by order, then registration
+    hooks: RwLock<Vec<(i32, HookId, OutgoingHook)>>,
+    next_id: AtomicU64,
+}
+
+impl OutgoingHooks {
+    pub(crate) fn register(&self, order: i32, hook: OutgoingHook) -> HookId {
+        let id = HookId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut hooks = self.hooks.write();
+        let at = hooks.partition_point(|(registered, _, _)| *registered <= order);
+        hooks.insert(at, (order, id, hook));
+        id
+    }
+
+    /// Whether the hook was still registered
+    pub(crate) fn unregister(&self, id: HookId) -> bool {
+        let mut hooks = self.hooks.write();
+        let before = hooks.len();
+        hooks.retain(|(_, registered, _)| *registered != id);
+        hooks.len() != before
+    }
+
+    pub(crate) fn apply(&self, outgoing: &mut OutgoingText) -> Result<()> {
+        // Run on a snapshot so a hook may register or unregister hooks
+        let hooks: Vec<OutgoingHook> = self
+            .hooks
+            .read()
+            .iter()
+            .map(|(_, _, hook)| hook.clone())
+            .collect();
+        for hook in hooks {
+            hook(outgoing)?;
+        }
+        Ok(())
+    }
+}
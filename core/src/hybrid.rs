@@ -0,0 +1,86 @@
+//! ML-KEM-768 half of the hybrid cipher suite
+//!
+//! A hybrid identity holds an ML-KEM-768 key pair next to its X25519 key.
+//! Senders encapsulate a secret to the recipient's encapsulation key once
+//! per session and send the KEM ciphertext with every message; the message
+//! key mixes that secret with the X25519 one, so it stays safe as long as
+//! either exchange does.
+
+use aes_gcm::aead::OsRng;
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// Length of the KEM ciphertext at the start of hybrid messages
+pub(crate) const CIPHERTEXT_LEN: usize = 1088;
+
+/// Our ML-KEM key pair
+pub(crate) struct KemKeys {
+    decapsulation: DecapsulationKey,
+    encapsulation: EncapsulationKey,
+}
+
+impl KemKeys {
+    pub(crate) fn generate() -> Self {
+        let (decapsulation, encapsulation) = MlKem768::generate(&mut OsRng);
+        Self {
+            decapsulation,
+            encapsulation,
+        }
+    }
+
+    pub(crate) fn from_bytes(decapsulation_key: &[u8]) -> Result<Self> {
+        let encoded = Encoded::<DecapsulationKey>::try_from(decapsulation_key)
+            .map_err(|_| Error::Crypto("Invalid ML-KEM key length".into()))?;
+        let decapsulation = DecapsulationKey::from_bytes(&encoded);
+        let encapsulation = decapsulation.encapsulation_key().clone();
+        Ok(Self {
+            decapsulation,
+            encapsulation,
+        })
+    }
+
+    pub(crate) fn secret_bytes(&self) -> Vec<u8> {
+        self.decapsulation.as_bytes().to_vec()
+    }
+
+    pub(crate) fn public_bytes(&self) -> Vec<u8> {
+        self.encapsulation.as_bytes().to_vec()
+    }
+
+    /// The secret a peer encapsulated to us
+    pub(crate) fn decapsulate(&self, ciphertext: &[u8]) -> Result<[u8; 32]> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| Error::Crypto("Invalid ML-KEM ciphertext length".into()))?;
+        let shared = self
+            .decapsulation
+            .decapsulate(&ciphertext)
+            .map_err(|_| Error::Crypto("ML-KEM decapsulation failed".into()))?;
+        Ok(shared.into())
+    }
+}
+
+/// Encapsulate a fresh secret to a peer's key. Returns the ciphertext to
+/// send and the secret.
+pub(crate) fn encapsulate(encapsulation_key: &[u8]) -> Result<(Vec<u8>, [u8; 32])> {
+    let encoded = Encoded::<EncapsulationKey>::try_from(encapsulation_key)
+        .map_err(|_| Error::Crypto("Invalid ML-KEM key length".into()))?;
+    let (ciphertext, shared) = EncapsulationKey::from_bytes(&encoded)
+        .encapsulate(&mut OsRng)
+        .map_err(|_| Error::Crypto("ML-KEM encapsulation failed".into()))?;
+    Ok((ciphertext.to_vec(), shared.into()))
+}
+
+/// Message key of a hybrid session
+pub(crate) fn combine(classical: &[u8; 32], kem_secret: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"privmsg-hybrid-x25519-mlkem768");
+    hasher.update(classical);
+    hasher.update(kem_secret);
+    hasher.finalize().into()
+}
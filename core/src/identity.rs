@@ -0,0 +1,188 @@
+//! Trust in peers' identity keys
+//!
+//! The first key seen for a peer is trusted. When the server later hands
+//! out a different one, the session on the old key is archived and the
+//! conflict is noted in the conversation instead of quietly switching
+//! over: sending to the peer fails with `Error::KeyConflict` until the user
+//! accepts the new key, and messages received in the meantime are marked
+//! `unverified_key`.
+//!
+//! A peer's other devices have keys of their own, which the device holding
+//! the peer's identity key signs. A device key the trusted key didn't sign
+//! goes through the same conflict: it is announced and left out until the
+//! user accepts it.
+
+use std::sync::Arc;
+
+use crate::crypto::CryptoEngine;
+use crate::devices::{self, DeviceLists};
+use crate::error::{Error, Result};
+use crate::events::{ClientEvent, EventSender};
+use crate::models::{DeviceKey, Message, SystemEvent};
+use crate::storage::LocalStorage;
+
+pub(crate) struct PeerKeys {
+    crypto: Arc<CryptoEngine>,
+    storage: Arc<LocalStorage>,
+    events: EventSender,
+    devices: DeviceLists,
+}
+
+impl PeerKeys {
+    pub(crate) fn new(crypto: Arc<CryptoEngine>, storage: Arc<LocalStorage>, events: EventSender) -> Self {
+        Self {
+            crypto,
+            storage,
+            events,
+            devices: DeviceLists::new(),
+        }
+    }
+
+    pub(crate) fn crypto(&self) -> &CryptoEngine {
+        &self.crypto
+    }
+
+    /// Peers' devices, as last fetched
+    pub(crate) fn devices(&self) -> &DeviceLists {
+        &self.devices
+    }
+
+    /// The key first trusted for `peer_id`, or accepted since
+    pub(crate) fn trusted_key(&self, peer_id: &str) -> Result<Option<String>> {
+        self.storage.get_trusted_key(peer_id)
+    }
+
+    /// Compare a key the server gave for `peer_id` with the trusted one,
+    /// trusting it if there is none yet. A new conflict drops the session
+    /// on the old key and is announced with `SystemEvent::KeyChanged`.
+    /// Returns whether the key is unverified.
+    pub(crate) fn observe(&self, peer_id: &str, public_key: &str) -> Result<bool> {
+        let trusted = match self.storage.get_trusted_key(peer_id)? {
+            Some(trusted) => trusted,
+            // Databases from before keys were tracked have the last one
+            // seen cached with the profile
+            None => {
+                let cached = self.storage.get_user(peer_id)?.and_then(|u| u.public_key);
+                let trusted = cached.unwrap_or_else(|| public_key.to_string());
+                self.storage.trust_key(peer_id, &trusted)?;
+                trusted
+            }
+        };
+        if trusted == public_key {
+            return Ok(false);
+        }
+        if self.storage.record_key_conflict(peer_id, public_key)? {
+            log::warn!("Identity key of {} changed; waiting for the user to accept it", peer_id);
+            self.crypto.remove_session(peer_id);
+            self.announce(
+                peer_id,
+                SystemEvent::KeyChanged {
+                    user_id: peer_id.to_string(),
+                },
+            )?;
+        }
+        Ok(true)
+    }
+
+    /// Whether a device key the server listed for `peer_id` may be used:
+    /// certified by the peer's trusted key, or accepted by the user. Any
+    /// other key is announced with `SystemEvent::KeyChanged` the first time
+    /// and left out until [`trust`](Self::trust).
+    pub(crate) fn check_device(&self, peer_id: &str, key: &DeviceKey) -> Result<bool> {
+        if let Some(trusted) = self.storage.get_trusted_key(peer_id)? {
+            if devices::is_certified(&trusted, peer_id, key) {
+                return Ok(true);
+            }
+        }
+        if self.storage.is_device_trusted(peer_id, &key.device_id, &key.public_key)? {
+            return Ok(true);
+        }
+        if self.storage.record_device_conflict(peer_id, &key.device_id, &key.public_key)? {
+            log::warn!(
+                "Device {} of {} isn't certified by their identity key; waiting for the user to accept it",
+                key.device_id,
+                peer_id
+            );
+            self.announce(
+                peer_id,
+                SystemEvent::KeyChanged {
+                    user_id: peer_id.to_string(),
+                },
+            )?;
+        }
+        Ok(false)
+    }
+
+    /// Start a session on a key from the server after checking it with
+    /// [`observe`](Self::observe); an unverified key is only used for
+    /// reading. Returns whether the key is unverified.
+    pub(crate) fn establish(&self, peer_id: &str, public_key: &str) -> Result<bool> {
+        let unverified = self.observe(peer_id, public_key)?;
+        self.crypto.establish_session(peer_id, public_key)?;
+        Ok(unverified)
+    }
+
+    /// Sessions kept before ratchets lack the peer's key. Making them again
+    /// from the trusted key gives the same session with the key.
+    pub(crate) fn fill_peer_key(&self, peer_id: &str) -> Result<()> {
+        if self.crypto.needs_peer_key(peer_id) {
+            if let Some(key) = self.storage.get_trusted_key(peer_id)? {
+                self.crypto.establish_session(peer_id, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The peer reads ratchet ciphertexts; start a ratchet with it
+    pub(crate) fn start_ratchet(&self, peer_id: &str) -> Result<bool> {
+        self.fill_peer_key(peer_id)?;
+        self.crypto.start_ratchet(peer_id)
+    }
+
+    /// Fail with `Error::KeyConflict` while the peer's key is unverified
+    pub(crate) fn check_trusted(&self, peer_id: &str) -> Result<()> {
+        match self.storage.get_key_conflict(peer_id)? {
+            Some(_) => Err(Error::KeyConflict(peer_id.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn is_unverified(&self, peer_id: &str) -> bool {
+        self.storage
+            .get_key_conflict(peer_id)
+            .map(|conflict| conflict.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Accept the key in conflict and the pending device keys, announced
+    /// with `SystemEvent::KeyTrusted`. Returns whether there was anything
+    /// to accept.
+    pub(crate) fn trust(&self, peer_id: &str) -> Result<bool> {
+        let conflict = self.storage.get_key_conflict(peer_id)?;
+        let pending = self.storage.trust_pending_devices(peer_id)?;
+        match conflict {
+            Some(conflict) => {
+                self.storage.trust_key(peer_id, &conflict.new_key)?;
+                self.crypto.remove_session(peer_id);
+                self.crypto.establish_session(peer_id, &conflict.new_key)?;
+            }
+            None if pending == 0 => return Ok(false),
+            None => {}
+        }
+
+        self.announce(
+            peer_id,
+            SystemEvent::KeyTrusted {
+                user_id: peer_id.to_string(),
+            },
+        )?;
+        Ok(true)
+    }
+
+    fn announce(&self, peer_id: &str, event: SystemEvent) -> Result<()> {
+        let message = Message::system(peer_id, event);
+        self.storage.save_message(&message)?;
+        self.events.send(ClientEvent::SystemMessage(Box::new(message)));
+        Ok(())
+    }
+}
@@ -0,0 +1,673 @@
+//! Direct delivery between devices on the same network
+//!
+//! Each device advertises a `_privmsg._tcp.local` service over mDNS that
+//! points at an ephemeral TCP port. A connection starts with a handshake in
+//! which both sides prove they hold the identity key the other already knows
+//! for them; after that the sender hands over envelopes one frame per line.
+//! Messages keep flowing between devices on a LAN without the server, which
+//! remains the fallback whenever a peer can't be reached directly.
+
+use crate::cache::{CacheLimits, CacheStats, LruCache};
+use crate::crypto::CryptoEngine;
+use crate::error::{Error, Result};
+use crate::identity::PeerKeys;
+use crate::models::{LanPeer, MessageEnvelope};
+use crate::transport::ApiTransport;
+use parking_lot::Mutex;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_privmsg._tcp.local";
+
+/// How often a device re-announces itself
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+/// Peers that haven't announced themselves for this long are gone
+const PEER_TTL: Duration = Duration::from_secs(35);
+/// Time allowed to connect, authenticate and hand over one envelope, and
+/// the longest a connection may wait for the next frame
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest frame accepted from a peer
+const MAX_FRAME: u64 = 1024 * 1024;
+
+fn lan_error(e: impl std::fmt::Display) -> Error {
+    Error::Lan(e.to_string())
+}
+
+/// Lines exchanged over a LAN connection
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    Hello {
+        user_id: String,
+        nonce: String,
+        /// The responder's proof, sent with its hello
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proof: Option<String>,
+    },
+    Proof {
+        proof: String,
+    },
+    Envelope {
+        envelope: MessageEnvelope,
+    },
+    Ack {
+        message_id: String,
+    },
+}
+
+/// Messages to and from contacts on the local network
+pub(crate) struct LanDelivery {
+    crypto: Arc<CryptoEngine>,
+    peer_keys: Arc<PeerKeys>,
+    api: Arc<dyn ApiTransport>,
+    /// Envelopes received from peers, waiting to be polled
+    incoming: Arc<Mutex<VecDeque<MessageEnvelope>>>,
+    /// Most envelopes `incoming` holds; more are refused
+    inbox_capacity: Arc<AtomicUsize>,
+    peer_capacity: AtomicUsize,
+    running: Mutex<Option<Running>>,
+}
+
+struct Running {
+    node: Arc<Node>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl LanDelivery {
+    pub(crate) fn new(crypto: Arc<CryptoEngine>, peer_keys: Arc<PeerKeys>, api: Arc<dyn ApiTransport>) -> Self {
+        Self {
+            crypto,
+            peer_keys,
+            api,
+            incoming: Arc::new(Mutex::new(VecDeque::new())),
+            inbox_capacity: Arc::new(AtomicUsize::new(CacheLimits::default().lan_inbox)),
+            peer_capacity: AtomicUsize::new(CacheLimits::default().lan_peers),
+            running: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn set_limits(&self, limits: &CacheLimits) {
+        self.inbox_capacity.store(limits.lan_inbox, Ordering::Relaxed);
+        self.peer_capacity.store(limits.lan_peers, Ordering::Relaxed);
+        if let Some(node) = self.node() {
+            node.peers.lock().set_capacity(limits.lan_peers);
+        }
+    }
+
+    /// Counters of the peer address cache, empty while stopped
+    pub(crate) fn peer_stats(&self) -> CacheStats {
+        match self.node() {
+            Some(node) => node.peers.lock().stats(),
+            None => CacheStats {
+                capacity: self.peer_capacity.load(Ordering::Relaxed),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Number of received envelopes waiting to be polled
+    pub(crate) fn inbox_len(&self) -> usize {
+        self.incoming.lock().len()
+    }
+
+    /// Start listening and advertising as `user_id`. Must be called from
+    /// within the client's runtime.
+    pub(crate) async fn start(&self, user_id: &str) -> Result<()> {
+        self.stop();
+
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let mdns = mdns_socket()?;
+        let node = Arc::new(Node {
+            user_id: user_id.to_string(),
+            instance: uuid::Uuid::new_v4().simple().to_string(),
+            port: listener.local_addr()?.port(),
+            crypto: self.crypto.clone(),
+            peer_keys: self.peer_keys.clone(),
+            api: self.api.clone(),
+            peers: Mutex::new(LruCache::new(self.peer_capacity.load(Ordering::Relaxed))),
+            incoming: self.incoming.clone(),
+            inbox_capacity: self.inbox_capacity.clone(),
+        });
+
+        let tasks = vec![
+            tokio::spawn(node.clone().accept(listener)),
+            tokio::spawn(node.clone().advertise(mdns)),
+        ];
+        *self.running.lock() = Some(Running { node, tasks });
+        Ok(())
+    }
+
+    pub(crate) fn stop(&self) {
+        if let Some(running) = self.running.lock().take() {
+            for task in running.tasks {
+                task.abort();
+            }
+        }
+    }
+
+    fn node(&self) -> Option<Arc<Node>> {
+        self.running.lock().as_ref().map(|running| running.node.clone())
+    }
+
+    pub(crate) fn peers(&self) -> Vec<LanPeer> {
+        let Some(node) = self.node() else {
+            return Vec::new();
+        };
+        let peers = node.peers.lock();
+        peers
+            .iter()
+            .filter(|(_, peer)| peer.seen.elapsed() < PEER_TTL)
+            .map(|(user_id, peer)| LanPeer {
+                user_id: user_id.clone(),
+                address: peer.addr.to_string(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn has_peer(&self, user_id: &str) -> bool {
+        self.node().is_some_and(|node| node.peer_addr(user_id).is_some())
+    }
+
+    /// Hand an envelope to its recipient over the local network
+    pub(crate) async fn send(&self, envelope: &MessageEnvelope) -> Result<()> {
+        let node = self.node().ok_or_else(|| lan_error("LAN delivery is off"))?;
+        let addr = node
+            .peer_addr(&envelope.recipient_id)
+            .ok_or_else(|| lan_error("Recipient is not on this network"))?;
+
+        let result = match tokio::time::timeout(EXCHANGE_TIMEOUT, node.deliver(addr, envelope)).await {
+            Ok(result) => result,
+            Err(_) => Err(lan_error("Timed out")),
+        };
+        if result.is_err() {
+            // Wait for the next announcement before trying this peer again
+            node.peers.lock().remove(&envelope.recipient_id);
+        }
+        result
+    }
+
+    /// Envelopes received from peers since the last call
+    pub(crate) fn receive_messages(&self) -> Vec<MessageEnvelope> {
+        self.incoming.lock().drain(..).collect()
+    }
+}
+
+impl Drop for LanDelivery {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Peer {
+    addr: SocketAddr,
+    seen: Instant,
+}
+
+/// One running LAN endpoint
+struct Node {
+    user_id: String,
+    /// Random per-start name, so a device ignores its own announcements
+    instance: String,
+    port: u16,
+    crypto: Arc<CryptoEngine>,
+    peer_keys: Arc<PeerKeys>,
+    api: Arc<dyn ApiTransport>,
+    /// Latest address announced for each user
+    peers: Mutex<LruCache<String, Peer>>,
+    incoming: Arc<Mutex<VecDeque<MessageEnvelope>>>,
+    inbox_capacity: Arc<AtomicUsize>,
+}
+
+impl Node {
+    fn peer_addr(&self, user_id: &str) -> Option<SocketAddr> {
+        self.peers
+            .lock()
+            .get(&user_id.to_string())
+            .filter(|peer| peer.seen.elapsed() < PEER_TTL)
+            .map(|peer| peer.addr)
+    }
+
+    /// Look up a peer's identity key if we don't have a session yet. Peers
+    /// whose key is unverified aren't talked to directly.
+    async fn ensure_session(&self, peer_id: &str) -> Result<()> {
+        if !self.crypto.has_session(peer_id) {
+            let user = self.api.get_user(peer_id).await?;
+            let key = user
+                .public_key
+                .ok_or_else(|| Error::NoPublicKey(peer_id.to_string()))?;
+            self.peer_keys.establish(peer_id, &key)?;
+        }
+        self.peer_keys.check_trusted(peer_id)
+    }
+
+    // ========================================================================
+    // Discovery
+    // ========================================================================
+
+    async fn advertise(self: Arc<Self>, socket: UdpSocket) {
+        let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+        let announcement = encode_announcement(&Announcement {
+            instance: self.instance.clone(),
+            user_id: self.user_id.clone(),
+            port: self.port,
+        });
+        let _ = socket.send_to(&encode_query(), group).await;
+
+        let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let mut buf = vec![0u8; 9000];
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = socket.send_to(&announcement, group).await {
+                        log::debug!("mDNS announcement failed: {}", e);
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let Ok((len, from)) = received else {
+                        continue;
+                    };
+                    match decode(&buf[..len]) {
+                        Some(Packet::Query) => {
+                            let _ = socket.send_to(&announcement, group).await;
+                        }
+                        Some(Packet::Announcement(peer)) if peer.instance != self.instance => {
+                            self.peers.lock().insert(
+                                peer.user_id,
+                                Peer {
+                                    addr: SocketAddr::new(from.ip(), peer.port),
+                                    seen: Instant::now(),
+                                },
+                            );
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // ========================================================================
+    // Exchange
+    // ========================================================================
+
+    fn transcript(initiator: &str, responder: &str, initiator_nonce: &str, responder_nonce: &str) -> String {
+        format!("privmsg-lan|{}|{}|{}|{}", initiator, responder, initiator_nonce, responder_nonce)
+    }
+
+    async fn deliver(&self, addr: SocketAddr, envelope: &MessageEnvelope) -> Result<()> {
+        let recipient = &envelope.recipient_id;
+        let stream = TcpStream::connect(addr).await?;
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+
+        let nonce = new_nonce();
+        write_frame(
+            &mut write,
+            &Frame::Hello {
+                user_id: self.user_id.clone(),
+                nonce: nonce.clone(),
+                proof: None,
+            },
+        )
+        .await?;
+
+        let Frame::Hello {
+            user_id,
+            nonce: peer_nonce,
+            proof: Some(proof),
+        } = read_frame(&mut read).await?
+        else {
+            return Err(lan_error("Expected hello"));
+        };
+        if user_id != *recipient {
+            return Err(lan_error(format!("Expected {} but reached {}", recipient, user_id)));
+        }
+
+        self.ensure_session(recipient).await?;
+        let transcript = Self::transcript(&self.user_id, recipient, &nonce, &peer_nonce);
+        let expected = format!("responder|{}", transcript);
+        if !self.crypto.verify_session_mac(recipient, expected.as_bytes(), &proof)? {
+            return Err(lan_error(format!("{} failed to authenticate", recipient)));
+        }
+        let proof = self
+            .crypto
+            .session_mac(recipient, format!("initiator|{}", transcript).as_bytes())?;
+        write_frame(&mut write, &Frame::Proof { proof }).await?;
+
+        let mut envelope = envelope.clone();
+        envelope.sender_id = self.user_id.clone();
+        let message_id = envelope.message_id.clone();
+        write_frame(&mut write, &Frame::Envelope { envelope }).await?;
+
+        match read_frame(&mut read).await? {
+            Frame::Ack { message_id: acked } if acked == message_id => Ok(()),
+            _ => Err(lan_error("Envelope was not acknowledged")),
+        }
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let Ok((stream, addr)) = listener.accept().await else {
+                continue;
+            };
+            let node = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = node.handle_incoming(stream).await {
+                    log::debug!("LAN connection from {} closed: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_incoming(&self, stream: TcpStream) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+
+        let handshake = async {
+            let Frame::Hello {
+                user_id: peer_id,
+                nonce: peer_nonce,
+                proof: None,
+            } = read_frame(&mut read).await?
+            else {
+                return Err(lan_error("Expected hello"));
+            };
+
+            // The id is unproven until its MAC checks out, so it must not
+            // trigger a key lookup; unknown peers go through the server
+            if !self.crypto.has_session(&peer_id) {
+                return Err(lan_error(format!("No session with {}", peer_id)));
+            }
+            let nonce = new_nonce();
+            let transcript = Self::transcript(&peer_id, &self.user_id, &peer_nonce, &nonce);
+            let proof = self
+                .crypto
+                .session_mac(&peer_id, format!("responder|{}", transcript).as_bytes())?;
+            write_frame(
+                &mut write,
+                &Frame::Hello {
+                    user_id: self.user_id.clone(),
+                    nonce,
+                    proof: Some(proof),
+                },
+            )
+            .await?;
+
+            let Frame::Proof { proof } = read_frame(&mut read).await? else {
+                return Err(lan_error("Expected proof"));
+            };
+            let expected = format!("initiator|{}", transcript);
+            if !self.crypto.verify_session_mac(&peer_id, expected.as_bytes(), &proof)? {
+                return Err(lan_error(format!("{} failed to authenticate", peer_id)));
+            }
+            self.peer_keys.check_trusted(&peer_id)?;
+            Ok(peer_id)
+        };
+        let peer_id = tokio::time::timeout(EXCHANGE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| lan_error("Handshake timed out"))??;
+
+        while let Ok(Frame::Envelope { mut envelope }) = read_frame(&mut read).await {
+            if envelope.recipient_id != self.user_id {
+                return Err(lan_error("Envelope for someone else"));
+            }
+            // Stamp the authenticated sender like the server does
+            envelope.sender_id = peer_id.clone();
+            let message_id = envelope.message_id.clone();
+            {
+                let mut incoming = self.incoming.lock();
+                // Unacknowledged, so the sender goes through the server
+                if incoming.len() >= self.inbox_capacity.load(Ordering::Relaxed) {
+                    return Err(lan_error("Inbox full"));
+                }
+                incoming.push_back(envelope);
+            }
+            write_frame(&mut write, &Frame::Ack { message_id }).await?;
+        }
+        Ok(())
+    }
+}
+
+fn new_nonce() -> String {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+/// Read one frame; a peer that goes quiet for `EXCHANGE_TIMEOUT` is dropped
+async fn read_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Frame> {
+    let mut line = String::new();
+    let read = tokio::time::timeout(EXCHANGE_TIMEOUT, reader.take(MAX_FRAME).read_line(&mut line))
+        .await
+        .map_err(|_| lan_error("Peer timed out"))??;
+    if read == 0 {
+        return Err(lan_error("Connection closed"));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> Result<()> {
+    let mut line = serde_json::to_string(frame)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// A UDP socket on the mDNS group, shared with other responders on the host
+fn mdns_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    // Devices on the same host should find each other too
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// ============================================================================
+// mDNS Packets
+// ============================================================================
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const RECORD_TTL: u32 = 120;
+
+#[derive(Debug, PartialEq)]
+struct Announcement {
+    instance: String,
+    user_id: String,
+    port: u16,
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    /// Someone is looking for PrivMsg devices
+    Query,
+    Announcement(Announcement),
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+fn write_record(packet: &mut Vec<u8>, name: &str, record_type: u16, data: &[u8]) {
+    write_name(packet, name);
+    packet.extend_from_slice(&record_type.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+fn encode_query() -> Vec<u8> {
+    // id, flags, one question, no records
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    write_name(&mut packet, SERVICE);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+fn encode_announcement(announcement: &Announcement) -> Vec<u8> {
+    let full_name = format!("{}.{}", announcement.instance, SERVICE);
+
+    // id, authoritative response, three answers
+    let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 3, 0, 0, 0, 0];
+
+    let mut ptr = Vec::new();
+    write_name(&mut ptr, &full_name);
+    write_record(&mut packet, SERVICE, TYPE_PTR, &ptr);
+
+    let mut srv = vec![0, 0, 0, 0];
+    srv.extend_from_slice(&announcement.port.to_be_bytes());
+    write_name(&mut srv, &format!("{}.local", announcement.instance));
+    write_record(&mut packet, &full_name, TYPE_SRV, &srv);
+
+    let entry = format!("user={}", announcement.user_id);
+    let mut txt = vec![entry.len() as u8];
+    txt.extend_from_slice(entry.as_bytes());
+    write_record(&mut packet, &full_name, TYPE_TXT, &txt);
+
+    packet
+}
+
+/// Read a possibly compressed name, returning it and the offset after it
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let target = ((len & 0x3F) << 8) | *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            pos = target;
+            continue;
+        }
+        if len == 0 {
+            end.get_or_insert(pos + 1);
+            break;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Some((labels.join("."), end?))
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(pos)?, *packet.get(pos + 1)?]))
+}
+
+/// Pick PrivMsg queries and announcements out of mDNS traffic
+fn decode(packet: &[u8]) -> Option<Packet> {
+    let flags = read_u16(packet, 2)?;
+    let questions = read_u16(packet, 4)?;
+    let records = read_u16(packet, 6)? as usize
+        + read_u16(packet, 8)? as usize
+        + read_u16(packet, 10)? as usize;
+
+    let mut pos = 12;
+    let mut queried = false;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, pos)?;
+        let question_type = read_u16(packet, next)?;
+        queried |= name.eq_ignore_ascii_case(SERVICE) && matches!(question_type, TYPE_PTR | TYPE_ANY);
+        pos = next + 4;
+    }
+    if flags & 0x8000 == 0 {
+        return queried.then_some(Packet::Query);
+    }
+
+    let mut ports: HashMap<String, u16> = HashMap::new();
+    let mut users: HashMap<String, String> = HashMap::new();
+    for _ in 0..records {
+        let (name, next) = read_name(packet, pos)?;
+        let record_type = read_u16(packet, next)?;
+        let len = read_u16(packet, next + 8)? as usize;
+        let data_start = next + 10;
+        let data = packet.get(data_start..data_start + len)?;
+        pos = data_start + len;
+
+        let Some(instance) = name
+            .strip_suffix(SERVICE)
+            .and_then(|prefix| prefix.strip_suffix('.'))
+        else {
+            continue;
+        };
+        match record_type {
+            TYPE_SRV => {
+                ports.insert(instance.to_string(), read_u16(data, 4)?);
+            }
+            TYPE_TXT => {
+                let mut entries = data;
+                while let Some((&len, rest)) = entries.split_first() {
+                    let entry = rest.get(..len as usize)?;
+                    if let Some(user_id) = entry.strip_prefix(b"user=") {
+                        users.insert(
+                            instance.to_string(),
+                            String::from_utf8_lossy(user_id).into_owned(),
+                        );
+                    }
+                    entries = &rest[len as usize..];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let (instance, user_id) = users.into_iter().next()?;
+    let port = *ports.get(&instance)?;
+    Some(Packet::Announcement(Announcement {
+        instance,
+        user_id,
+        port,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mdns_packets() {
+        assert_eq!(decode(&encode_query()), Some(Packet::Query));
+
+        let announcement = Announcement {
+            instance: "0123456789abcdef".into(),
+            user_id: "alice".into(),
+            port: 49152,
+        };
+        let packet = encode_announcement(&announcement);
+        assert_eq!(decode(&packet), Some(Packet::Announcement(announcement)));
+
+        // Truncated packets are ignored rather than misread
+        assert_eq!(decode(&packet[..packet.len() - 3]), None);
+        assert_eq!(decode(&[]), None);
+    }
+}
@@ -2,55 +2,337 @@
 //!
 //! Shared library for E2EE messaging across all platforms.
 //! Provides: cryptography, networking, storage, and models.
+//!
+//! Everything that talks to the network is async at heart: code already
+//! running on a tokio runtime awaits the `_async` methods of
+//! [`PrivMsgClient`], while the blocking methods of the same name drive
+//! them on the client's own runtime for threads that aren't async. The
+//! blocking methods don't panic when called from async code on either
+//! runtime flavor, but they do block the calling thread.
 
+pub mod config;
+pub mod conversation;
+pub mod diagnostics;
+pub mod discovery;
 pub mod crypto;
 pub mod network;
 pub mod storage;
+pub mod transport;
 pub mod models;
 pub mod error;
+pub mod events;
+pub mod notifications;
+pub mod migrate;
+mod archive;
+mod cache;
+mod decrypt;
+mod devices;
+mod drafts;
+mod expiry;
+mod flags;
+mod hooks;
+mod identity;
+#[cfg(feature = "pq-hybrid")]
+mod hybrid;
+mod lan;
+mod p2p;
+mod policy;
+mod power;
+mod presence;
+mod profiles;
+mod ratchet;
+mod rules;
+mod search;
+mod tempfiles;
+mod tls;
+mod transfer;
+
+#[cfg(feature = "test-util")]
+pub mod testing;
 
 #[cfg(target_os = "android")]
 pub mod android;
 
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use futures::future::BoxFuture;
 use parking_lot::RwLock;
 use tokio::runtime::Runtime;
 
+pub use archive::{ArchiveManifest, ArchivedConversation, ARCHIVE_FORMAT, ARCHIVE_VERSION};
+pub use cache::{CacheLimits, CacheStats, ClientStats};
+pub use config::*;
 pub use crypto::*;
 pub use network::*;
 pub use storage::*;
+pub use transport::*;
 pub use models::*;
 pub use error::*;
+pub use conversation::ConversationHandle;
+pub use diagnostics::{CheckStatus, DiagnosticCheck, DiagnosticsReport};
+pub use drafts::DRAFT_SYNC_INTERVAL;
+pub use events::{ClientEvent, Subscription};
+pub use hooks::{HookId, OutgoingHook, OutgoingText};
+pub use notifications::{Notification, NotificationKind, NotificationSink, PreviewPolicy};
+pub use power::PowerSource;
+pub use tempfiles::OpenedAttachment;
+pub use transfer::DOWNLOAD_RANGE_SIZE;
+
+const LOCAL_ANALYTICS_SETTING: &str = "local_analytics";
+
+/// Id of the last announcement the user dismissed
+const DISMISSED_ANNOUNCEMENT_SETTING: &str = "dismissed_announcement";
+
+/// Longest device name the server accepts, in characters
+const MAX_DEVICE_NAME_LEN: usize = 64;
+
+/// Transfer id of a running `export_my_data`
+const DATA_EXPORT_ID: &str = "data-export";
+
+/// Most conversations returned by one `get_conversation_page` or
+/// `get_conversation_changes` call
+pub const MAX_CONVERSATION_PAGE: usize = 500;
+
+/// Called with each received custom message of the type it was
+/// registered for
+pub type CustomHandler = Arc<dyn Fn(&CustomMessage) + Send + Sync>;
 
 /// Main client instance
 pub struct PrivMsgClient {
-    config: ClientConfig,
     crypto: Arc<CryptoEngine>,
-    api: Arc<ApiClient>,
-    ws: Arc<RwLock<Option<WebSocketClient>>>,
+    api: Arc<dyn ApiTransport>,
+    ws_connector: Arc<dyn WsConnector>,
+    ws: Arc<RwLock<Option<Arc<dyn WsTransport>>>>,
     storage: Arc<LocalStorage>,
-    runtime: Runtime,
+    peer_keys: Arc<identity::PeerKeys>,
+    decrypt_pool: decrypt::DecryptPool,
+    events: events::EventQueue,
+    event_sender: events::EventSender,
+    notifier: Arc<notifications::Notifier>,
+    rules: Arc<rules::RuleEngine>,
+    policy: policy::PolicyStore,
+    flags: flags::FeatureFlags,
+    transfers: transfer::TransferControls,
+    direct: Arc<p2p::DirectTransfers>,
+    lan: lan::LanDelivery,
+    /// Retries of a message send before it is stored as failed
+    send_retry: RwLock<RetryPolicy>,
+    /// Attempts at a message queued while offline before it is stored as failed
+    outbox_retry: RwLock<RetryPolicy>,
+    /// Held while the outbox is flushed; sending a queued message can
+    /// reconnect, which would flush it a second time
+    outbox_flush: tokio::sync::Mutex<()>,
+    activity: presence::ActivityThrottle,
+    drafts: drafts::DraftThrottle,
+    profiles: Arc<profiles::ProfileRefresher>,
+    expiry: Arc<expiry::MessageExpiry>,
+    /// The server's service notice, dismissed or not
+    announcement: RwLock<Option<Announcement>>,
+    /// The server's maintenance mode, from its last `maintenance` frame
+    maintenance: RwLock<Option<Maintenance>>,
+    power: power::PowerState,
+    /// Handlers by custom message type or vendor prefix
+    custom_handlers: RwLock<Vec<(String, CustomHandler)>>,
+    /// Whether custom messages without a handler are stored or dropped
+    store_unhandled_custom: AtomicBool,
+    /// Upload attachments peers ask for again without asking the user
+    auto_reupload: AtomicBool,
+    outgoing_hooks: hooks::OutgoingHooks,
+    /// Scratch space for encrypted attachments in transit
+    temp_dir: PathBuf,
+    temp_files: Arc<tempfiles::TempFiles>,
+    /// Runs the blocking API and background tasks; taken only on drop
+    runtime: Option<Runtime>,
+}
+
+/// Conversations decrypted in parallel by the background pool
+const MAX_DECRYPT_WORKERS: usize = 4;
+
+/// Tries at a message queued while offline, unless set with `set_outbox_retry`
+const OUTBOX_RETRY: RetryPolicy = RetryPolicy {
+    max_retries: 5,
+    initial_backoff: Duration::from_secs(2),
+    max_backoff: Duration::from_secs(300),
+};
+
+/// Envelope id of the copy of a message for one group member or device,
+/// so acks and receipts for the copy lead back to the message
+fn copy_id(message_id: &str, member_or_device: &str) -> String {
+    format!("{}/{}", message_id, member_or_device)
+}
+
+/// The message an envelope id belongs to: the id itself, or the message a
+/// member's or device's copy was made from
+pub(crate) fn message_id_of_copy(envelope_id: &str) -> &str {
+    envelope_id.split('/').next().unwrap_or(envelope_id)
+}
+
+/// Drive `future` on `runtime` from blocking code. Blocking on a runtime
+/// from inside another panics whichever flavor it is, so there the future
+/// is driven from a scoped thread outside it while the caller waits;
+/// async code should use the `_async` methods rather than block at all.
+pub(crate) fn block_on<F>(runtime: &Runtime, future: F) -> F::Output
+where
+    F: std::future::Future + Send,
+    F::Output: Send,
+{
+    if tokio::runtime::Handle::try_current().is_err() {
+        return runtime.block_on(future);
+    }
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| runtime.block_on(future))
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
 }
 
 impl PrivMsgClient {
     /// Create new client instance
     pub fn new(config: ClientConfig, data_dir: &str) -> Result<Self> {
+        // Configs from `ClientConfig::new` or `discover` skip the builder's checks
+        config.check_transport_security()?;
+        // Both remember the same last working endpoint
+        let endpoints = Arc::new(EndpointSet::new(&config));
+        let api = Arc::new(ApiClient::with_endpoints(&config, endpoints.clone())?);
+        let ws_connector = Arc::new(DefaultWsConnector::with_endpoints(&config, endpoints));
+        let binary_envelopes = ws_connector.binary_envelopes();
+        let client = Self::with_transport(data_dir, api, ws_connector)?;
+        if let Some(ref key) = config.policy_key {
+            client.policy.pin_key(key)?;
+            client.flags.set_policy(client.policy.current().as_ref());
+        }
+        for (flag, enabled) in &config.flags {
+            client.flags.set_config(*flag, Some(*enabled));
+        }
+        if config.binary_envelopes && !config.flags.contains_key(&Flag::BinaryEnvelopes) {
+            client.flags.set_config(Flag::BinaryEnvelopes, Some(true));
+        }
+        client.flags.bind(Flag::BinaryEnvelopes, binary_envelopes);
+        client.set_cache_limits(config.cache_limits);
+        Ok(client)
+    }
+
+    /// Create a client on top of custom network transports
+    pub fn with_transport(
+        data_dir: &str,
+        api: Arc<dyn ApiTransport>,
+        ws_connector: Arc<dyn WsConnector>,
+    ) -> Result<Self> {
         let runtime = Runtime::new().map_err(|e| Error::Runtime(e.to_string()))?;
 
         let storage = Arc::new(LocalStorage::new(data_dir)?);
+        let temp_dir = Path::new(data_dir).join("tmp");
+        std::fs::create_dir_all(&temp_dir)?;
+        let temp_files = Arc::new(tempfiles::TempFiles::new(temp_dir.join("open"))?);
         let crypto = Arc::new(CryptoEngine::new());
-        let api = Arc::new(ApiClient::new(&config));
+        crypto.set_session_store(storage.clone());
+        let ws = Arc::new(RwLock::new(None));
+
+        let rules = Arc::new(rules::RuleEngine::new(storage.clone())?);
+        let notifier = Arc::new(notifications::Notifier::new(storage.clone(), rules.clone()));
+        let policy = policy::PolicyStore::new(storage.clone());
+        if let Some(preview) = policy.current().and_then(|p| p.notification_preview) {
+            notifier.set_policy(preview);
+        }
+        let flags = flags::FeatureFlags::from_env();
+        flags.set_policy(policy.current().as_ref());
+        flags.bind(Flag::Ratchet, crypto.ratchet_switch());
+        let (event_sender, events) = events::channel(notifier.clone());
+        let peer_keys = Arc::new(identity::PeerKeys::new(
+            crypto.clone(),
+            storage.clone(),
+            event_sender.clone(),
+        ));
+        let workers = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(MAX_DECRYPT_WORKERS);
+        let decrypt_pool = decrypt::DecryptPool::new(
+            runtime.handle().clone(),
+            workers,
+            peer_keys.clone(),
+            api.clone(),
+            storage.clone(),
+            ws.clone(),
+            event_sender.clone(),
+        );
+
+        let direct = Arc::new(p2p::DirectTransfers::new(
+            api.clone(),
+            Path::new(data_dir).join("direct"),
+            event_sender.clone(),
+        )?);
+        direct.spawn_signal_pump(runtime.handle(), ws.clone());
+
+        let lan = lan::LanDelivery::new(crypto.clone(), peer_keys.clone(), api.clone());
+        let activity = presence::ActivityThrottle::new(runtime.handle().clone(), ws.clone());
+        let profiles = Arc::new(profiles::ProfileRefresher::new(
+            api.clone(),
+            storage.clone(),
+            event_sender.clone(),
+        ));
+        profiles.spawn(runtime.handle());
+        let expiry = Arc::new(expiry::MessageExpiry::new(
+            storage.clone(),
+            Arc::downgrade(&temp_files),
+            event_sender.clone(),
+        ));
+        expiry.spawn(runtime.handle());
 
         Ok(Self {
-            config,
             crypto,
             api,
-            ws: Arc::new(RwLock::new(None)),
+            ws_connector,
+            ws,
             storage,
-            runtime,
+            peer_keys,
+            decrypt_pool,
+            events,
+            event_sender,
+            notifier,
+            rules,
+            policy,
+            flags,
+            transfers: transfer::TransferControls::default(),
+            direct,
+            lan,
+            send_retry: RwLock::new(RetryPolicy::none()),
+            outbox_retry: RwLock::new(OUTBOX_RETRY),
+            outbox_flush: tokio::sync::Mutex::new(()),
+            activity,
+            drafts: drafts::DraftThrottle::default(),
+            profiles,
+            expiry,
+            announcement: RwLock::new(None),
+            maintenance: RwLock::new(None),
+            power: power::PowerState::default(),
+            custom_handlers: RwLock::new(Vec::new()),
+            store_unhandled_custom: AtomicBool::new(false),
+            auto_reupload: AtomicBool::new(false),
+            outgoing_hooks: hooks::OutgoingHooks::default(),
+            temp_dir,
+            temp_files,
+            runtime: Some(runtime),
         })
     }
 
+    fn runtime(&self) -> &Runtime {
+        self.runtime.as_ref().expect("runtime is only taken on drop")
+    }
+
+    /// Run an `_async` method to completion for its blocking adapter
+    fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: std::future::Future + Send,
+        F::Output: Send,
+    {
+        block_on(self.runtime(), future)
+    }
+
     /// Initialize crypto keys (load existing or generate new)
     pub fn init_keys(&self, private_key: Option<&str>) -> Result<String> {
         match private_key {
@@ -64,182 +346,3186 @@ impl PrivMsgClient {
         self.crypto.get_public_key()
     }
 
-    /// Login to server
+    /// Load the sessions kept from earlier runs into memory, after
+    /// `init_keys`; returns how many were loaded. Sessions are otherwise
+    /// loaded one at a time as peers are seen. Their secrets are sealed
+    /// with the identity key, so after importing a different identity none
+    /// load and sessions are made again.
+    pub fn restore_sessions(&self) -> Result<usize> {
+        let restored = self.crypto.restore_sessions()?;
+        log::info!("Restored {} sessions", restored);
+        Ok(restored)
+    }
+
+    /// Login to server and fetch the server's client policy
     pub fn login(&self, user_id: &str, access_key: &str, device_name: &str) -> Result<AuthSession> {
+        self.block_on(self.login_async(user_id, access_key, device_name))
+    }
+
+    /// `login`, for async callers
+    pub async fn login_async(
+        &self,
+        user_id: &str,
+        access_key: &str,
+        device_name: &str,
+    ) -> Result<AuthSession> {
         let public_key = self.crypto.get_public_key()?;
 
-        self.runtime.block_on(async {
-            let session = self.api.login(user_id, access_key, device_name, &public_key).await?;
+        let session = match self.login_with_proof(user_id, access_key, device_name, &public_key).await {
+            // The challenge expired while it was being solved
+            Err(Error::ChallengeRequired) => {
+                self.login_with_proof(user_id, access_key, device_name, &public_key)
+                    .await?
+            }
+            result => result?,
+        };
+
+        // Save session
+        self.storage.save_session(&session)?;
 
-            // Save session
-            self.storage.save_session(&session)?;
+        // Connect WebSocket
+        let ws: Arc<dyn WsTransport> = Arc::from(self.ws_connector.connect(&session.token).await?);
+        self.on_connected(ws).await;
+        *self.announcement.write() = session.announcement.clone();
+        // Logins are refused during maintenance, so it is off now
+        *self.maintenance.write() = None;
 
-            // Connect WebSocket
-            let ws = WebSocketClient::connect(&self.config, &session.token).await?;
-            *self.ws.write() = Some(ws);
+        // The last verified policy stays in force if this one can't be had
+        if let Err(e) = self.refresh_policy_async().await {
+            log::warn!("Client policy not updated: {}", e);
+        }
+        Ok(session)
+    }
 
-            Ok(session)
-        })
+    /// Log in, first solving the server's proof-of-work challenge if it
+    /// asks for one
+    async fn login_with_proof(
+        &self,
+        user_id: &str,
+        access_key: &str,
+        device_name: &str,
+        public_key: &str,
+    ) -> Result<AuthSession> {
+        let proof = match self.api.login_challenge().await? {
+            Some(challenge) => Some(
+                tokio::task::spawn_blocking(move || privmsg_proto::pow::solve(&challenge))
+                    .await
+                    .map_err(|e| Error::Runtime(e.to_string()))?,
+            ),
+            None => None,
+        };
+        self.api
+            .login(user_id, access_key, device_name, public_key, proof.as_ref())
+            .await
+    }
+
+    /// Fetch the server's client policy and apply it if it is newer than
+    /// the current one. Returns the policy in force.
+    ///
+    /// Its defaults replace the user's settings once per new version; locked
+    /// settings can't be changed until a later version unlocks them. The
+    /// application applies what core doesn't manage (disappearing timer,
+    /// endpoints, calls) on `ClientEvent::PolicyChanged`.
+    pub fn refresh_policy(&self) -> Result<Option<ClientPolicy>> {
+        self.block_on(self.refresh_policy_async())
+    }
+
+    /// `refresh_policy`, for async callers
+    pub async fn refresh_policy_async(&self) -> Result<Option<ClientPolicy>> {
+        if let Some(signed) = self.api.get_client_policy().await? {
+            if let Some(policy) = self.policy.update(&signed)? {
+                self.apply_policy(&policy).await;
+                self.event_sender.send(ClientEvent::PolicyChanged(Box::new(policy)));
+            }
+        }
+        Ok(self.policy.current())
+    }
+
+    /// The server's service notice to show as a banner: set, not expired
+    /// and not dismissed
+    pub fn current_announcement(&self) -> Option<Announcement> {
+        let dismissed = self.storage.get_setting(DISMISSED_ANNOUNCEMENT_SETTING);
+        self.announcement
+            .read()
+            .clone()
+            .filter(|a| a.is_active(chrono::Utc::now().timestamp()))
+            .filter(|a| dismissed.as_deref() != Some(a.id.as_str()))
+    }
+
+    /// Hide an announcement; the next one the server sets shows again
+    pub fn dismiss_announcement(&self, announcement_id: &str) -> Result<()> {
+        self.storage
+            .save_setting(DISMISSED_ANNOUNCEMENT_SETTING, announcement_id)
+    }
+
+    /// Take announcements the server sent over the WebSocket and tell the
+    /// application when the banner should change
+    fn apply_announcements(&self, updates: Vec<Option<Announcement>>) {
+        let Some(latest) = updates.into_iter().last() else {
+            return;
+        };
+        let before = self.current_announcement();
+        *self.announcement.write() = latest;
+        let after = self.current_announcement();
+        if after != before {
+            self.event_sender.send(ClientEvent::AnnouncementChanged(after));
+        }
+    }
+
+    /// Mark messages a recipient's device received as delivered and tell
+    /// the application which ones changed
+    fn apply_deliveries(&self, receipts: Vec<DeliveryReceipt>) -> Result<()> {
+        for receipt in receipts {
+            let message_ids: Vec<String> = receipt
+                .message_ids
+                .iter()
+                .map(|id| message_id_of_copy(id).to_string())
+                .collect();
+            for (conversation_id, message_id) in self.storage.mark_delivered(&message_ids)? {
+                self.event_sender.send(ClientEvent::MessageStatusChanged {
+                    conversation_id,
+                    message_id,
+                    status: MessageStatus::Delivered,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The server's maintenance mode, if it is on
+    pub fn current_maintenance(&self) -> Option<Maintenance> {
+        self.maintenance.read().clone()
+    }
+
+    fn apply_maintenance(&self, updates: Vec<Option<Maintenance>>) {
+        let Some(latest) = updates.into_iter().last() else {
+            return;
+        };
+        let before = std::mem::replace(&mut *self.maintenance.write(), latest.clone());
+        if latest != before {
+            self.event_sender.send(ClientEvent::MaintenanceChanged(latest));
+        }
+    }
+
+    /// The server's client policy in force, if any
+    pub fn client_policy(&self) -> Option<ClientPolicy> {
+        self.policy.current()
+    }
+
+    /// Whether the client policy keeps the user from changing `setting`
+    pub fn is_locked_by_policy(&self, setting: PolicySetting) -> bool {
+        self.policy.is_locked(setting)
+    }
+
+    /// Every feature flag with its value and where that came from
+    pub fn flags(&self) -> Vec<FlagState> {
+        self.flags.states()
+    }
+
+    pub fn is_flag_enabled(&self, flag: Flag) -> bool {
+        self.flags.is_enabled(flag)
+    }
+
+    /// Turn a flag on or off for this run, or `None` to go back to the
+    /// configured value. `PRIVMSG_FLAGS` and the client policy still win.
+    pub fn set_flag(&self, flag: Flag, enabled: Option<bool>) {
+        self.flags.set_config(flag, enabled);
+    }
+
+    async fn apply_policy(&self, policy: &ClientPolicy) {
+        self.flags.set_policy(Some(policy));
+        if !policy.allows(Feature::DirectTransfer) {
+            self.direct.set_min_size(None);
+        }
+        if !policy.allows(Feature::LanDelivery) {
+            self.lan.stop();
+        }
+        if let Some(preview) = policy.notification_preview {
+            self.notifier.set_policy(preview);
+        }
+        if let Some(visibility) = policy.last_seen_visibility {
+            let update = ProfileUpdate {
+                last_seen_visibility: Some(visibility),
+                ..Default::default()
+            };
+            let applied = self
+                .api
+                .update_profile(&update)
+                .await
+                .and_then(|user| self.storage.save_user(&user));
+            if let Err(e) = applied {
+                log::warn!("Policy last seen visibility not applied: {}", e);
+            }
+        }
     }
 
     /// Send text message
+    ///
+    /// Delivery is retried as set with `set_send_retry`. A message that
+    /// still can't be delivered is stored as failed, so it can be passed to
+    /// `resend_message`, and the delivery error is returned. Without a
+    /// connection the message is kept pending in the outbox instead, see
+    /// `set_outbox_retry`.
     pub fn send_message(&self, recipient_id: &str, text: &str) -> Result<Message> {
-        // Ensure we have session with recipient
-        if !self.crypto.has_session(recipient_id) {
-            // Fetch recipient's public key
-            let user = self.runtime.block_on(self.api.get_user(recipient_id))?;
-            if let Some(pub_key) = user.public_key {
-                self.crypto.establish_session(recipient_id, &pub_key)?;
-            } else {
-                return Err(Error::NoPublicKey(recipient_id.to_string()));
-            }
+        self.block_on(self.send_message_async(recipient_id, text))
+    }
+
+    /// `send_message`, for async callers
+    pub async fn send_message_async(&self, recipient_id: &str, text: &str) -> Result<Message> {
+        self.send_text(recipient_id, text, Metadata::new(), None).await
+    }
+
+    /// Send a text message carrying app-specific `metadata`, which is
+    /// encrypted along with the text
+    pub fn send_message_with_metadata(
+        &self,
+        recipient_id: &str,
+        text: &str,
+        metadata: Metadata,
+    ) -> Result<Message> {
+        self.block_on(self.send_message_with_metadata_async(recipient_id, text, metadata))
+    }
+
+    /// `send_message_with_metadata`, for async callers
+    pub async fn send_message_with_metadata_async(
+        &self,
+        recipient_id: &str,
+        text: &str,
+        metadata: Metadata,
+    ) -> Result<Message> {
+        self.send_text(recipient_id, text, metadata, None).await
+    }
+
+    /// Send a text message replying to `quote`, from `quote_message`. To
+    /// reply privately to someone who wrote in another conversation, send
+    /// to `quote.sender_id`; the quote then keeps where the original was.
+    pub fn send_reply(&self, recipient_id: &str, text: &str, quote: Quote) -> Result<Message> {
+        self.block_on(self.send_reply_async(recipient_id, text, quote))
+    }
+
+    /// `send_reply`, for async callers
+    pub async fn send_reply_async(&self, recipient_id: &str, text: &str, quote: Quote) -> Result<Message> {
+        let quote = quote.sent_in(recipient_id);
+        self.send_text(recipient_id, text, Metadata::new(), Some(quote)).await
+    }
+
+    /// Reply to a stored message in the 1:1 conversation with its sender,
+    /// quoting it. The conversation is created if there is none yet.
+    pub fn reply_privately(&self, message_id: &str, text: &str) -> Result<Message> {
+        let quote = self.quote_message(message_id)?;
+        if quote.sender_id == self.get_current_user_id()? {
+            return Err(Error::Storage(format!("{} is your own message", message_id)));
         }
+        let recipient_id = quote.sender_id.clone();
+        self.send_reply(&recipient_id, text, quote)
+    }
 
-        // Encrypt message
-        let content = serde_json::json!({ "text": text });
-        let encrypted = self.crypto.encrypt_for(recipient_id, &content.to_string())?;
+    /// A quote of a stored message to reply with, in its own conversation
+    /// or privately; see `send_reply`
+    pub fn quote_message(&self, message_id: &str) -> Result<Quote> {
+        let message = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        if message.message_type == MessageType::System {
+            return Err(Error::Storage(format!("Message {} can't be quoted", message_id)));
+        }
+        let text = match message.message_type {
+            MessageType::Text => &message.content,
+            _ => message.caption.as_ref().unwrap_or(&message.content),
+        };
+        Ok(Quote {
+            conversation_id: Some(message.conversation_id.clone()),
+            ..Quote::new(&message.message_id, &message.sender_id, text)
+        })
+    }
 
-        let message_id = uuid::Uuid::new_v4().to_string();
-        let timestamp = chrono::Utc::now().timestamp_millis();
+    async fn send_text(
+        &self,
+        recipient_id: &str,
+        text: &str,
+        metadata: Metadata,
+        quote: Option<Quote>,
+    ) -> Result<Message> {
+        self.ensure_session(recipient_id).await?;
+        let outgoing = self.apply_outgoing_hooks(recipient_id, text, metadata)?;
 
-        let envelope = MessageEnvelope {
-            message_id: message_id.clone(),
+        let message = Message {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            conversation_id: recipient_id.to_string(),
             sender_id: self.get_current_user_id()?,
+            message_type: MessageType::Text,
+            content: outgoing.text,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            status: MessageStatus::Pending,
+            attachment: None,
+            caption: None,
+            is_outgoing: true,
+            metadata: outgoing.metadata,
+            system_event: None,
+            sender_device_id: None,
+            quote,
+            is_starred: false,
+            unverified_key: false,
+        };
+        let message = self.transmit(message).await?;
+        if let Err(e) = self.clear_sent_draft(recipient_id).await {
+            log::warn!("Draft for {} not cleared: {}", recipient_id, e);
+        }
+        Ok(message)
+    }
+
+    /// Run `hook` on the text of every message sent from now on, and on
+    /// attachment captions, before it is encrypted and stored. Hooks run
+    /// in ascending `order`, ties in the order they were registered.
+    /// Forwarded and resent messages don't pass through hooks again.
+    ///
+    /// Hooks run on the sending thread.
+    pub fn register_outgoing_hook(
+        &self,
+        order: i32,
+        hook: impl Fn(&mut OutgoingText) -> Result<()> + Send + Sync + 'static,
+    ) -> HookId {
+        self.outgoing_hooks.register(order, Arc::new(hook))
+    }
+
+    /// Returns whether the hook was still registered
+    pub fn unregister_outgoing_hook(&self, id: HookId) -> bool {
+        self.outgoing_hooks.unregister(id)
+    }
+
+    fn apply_outgoing_hooks(
+        &self,
+        recipient_id: &str,
+        text: &str,
+        metadata: Metadata,
+    ) -> Result<OutgoingText> {
+        let mut outgoing = OutgoingText {
             recipient_id: recipient_id.to_string(),
-            recipient_device_id: None,
-            encrypted_content: encrypted,
-            message_type: "text".to_string(),
-            timestamp,
+            text: emoji::replace_shortcodes(text).into_owned(),
+            metadata,
         };
+        self.outgoing_hooks.apply(&mut outgoing)?;
+        Ok(outgoing)
+    }
+
+    /// Send a copy of a stored message to `recipient_id`, with its
+    /// attachment, caption and metadata. Attachments aren't uploaded again.
+    pub fn forward_message(&self, message_id: &str, recipient_id: &str) -> Result<Message> {
+        self.block_on(self.forward_message_async(message_id, recipient_id))
+    }
+
+    /// `forward_message`, for async callers
+    pub async fn forward_message_async(&self, message_id: &str, recipient_id: &str) -> Result<Message> {
+        let original = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        Self::check_forwardable(&original)?;
+        self.ensure_session(recipient_id).await?;
+        self.forward_copy(original, recipient_id).await
+    }
+
+    /// Forward several messages to `recipient_id`, oldest first. All of them
+    /// are checked before any is sent, so an unknown id or an attachment
+    /// that can't be forwarded sends nothing.
+    pub fn forward_messages(&self, message_ids: &[String], recipient_id: &str) -> Result<Vec<Message>> {
+        self.block_on(self.forward_messages_async(message_ids, recipient_id))
+    }
+
+    /// `forward_messages`, for async callers
+    pub async fn forward_messages_async(
+        &self,
+        message_ids: &[String],
+        recipient_id: &str,
+    ) -> Result<Vec<Message>> {
+        let originals = self.storage.get_messages_by_id(message_ids)?;
+        if let Some(missing) = message_ids
+            .iter()
+            .find(|id| !originals.iter().any(|m| &m.message_id == *id))
+        {
+            return Err(Error::Storage(format!("Message not found: {}", missing)));
+        }
+        for original in &originals {
+            Self::check_forwardable(original)?;
+        }
+        self.ensure_session(recipient_id).await?;
+        let mut forwarded = Vec::with_capacity(originals.len());
+        for original in originals {
+            forwarded.push(self.forward_copy(original, recipient_id).await?);
+        }
+        Ok(forwarded)
+    }
 
-        // Send via WebSocket
-        if let Some(ref ws) = *self.ws.read() {
-            self.runtime.block_on(ws.send_message(&envelope))?;
+    /// Forwarded attachments reuse the uploaded file, so its key is needed
+    fn check_forwardable(message: &Message) -> Result<()> {
+        if message.message_type == MessageType::System
+            || message
+                .attachment
+                .as_ref()
+                .is_some_and(|a| a.encryption_key.is_none())
+        {
+            return Err(Error::Storage(format!("{} can't be forwarded", message.message_id)));
         }
+        Ok(())
+    }
 
-        // Save locally
+    async fn forward_copy(&self, original: Message, recipient_id: &str) -> Result<Message> {
         let message = Message {
-            message_id,
+            message_id: uuid::Uuid::new_v4().to_string(),
             conversation_id: recipient_id.to_string(),
             sender_id: self.get_current_user_id()?,
-            message_type: MessageType::Text,
-            content: text.to_string(),
-            timestamp,
-            status: MessageStatus::Sent,
-            attachment: None,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            status: MessageStatus::Pending,
             is_outgoing: true,
+            is_starred: false,
+            unverified_key: false,
+            ..original
         };
+        self.transmit(message).await
+    }
 
+    /// Store a notice about `event` in a conversation, e.g. a missed call
+    /// the frontend noticed, and announce it as a
+    /// [`ClientEvent::SystemMessage`]. Nothing is sent to the peer.
+    pub fn record_system_event(&self, conversation_id: &str, event: SystemEvent) -> Result<Message> {
+        let message = Message::system(conversation_id, event);
         self.storage.save_message(&message)?;
-
+        self.event_sender
+            .send(ClientEvent::SystemMessage(Box::new(message.clone())));
         Ok(message)
     }
 
-    /// Get conversations list
-    pub fn get_conversations(&self) -> Result<Vec<Conversation>> {
-        self.storage.get_conversations()
+    /// A handle for working within the conversation with `peer_id`
+    pub fn conversation(&self, peer_id: &str) -> ConversationHandle<'_> {
+        ConversationHandle::new(self, peer_id)
     }
 
-    /// Get messages for conversation
-    pub fn get_messages(&self, conversation_id: &str, limit: i64, offset: i64) -> Result<Vec<Message>> {
-        self.storage.get_messages(conversation_id, limit, offset)
+    /// Tell `recipient_id` whether the user is typing. Safe to call on
+    /// every keystroke: updates are throttled as set with
+    /// `set_presence_policy`, and "stopped typing" follows on its own once
+    /// the user goes idle. Dropped while the WebSocket is down.
+    pub fn send_typing(&self, recipient_id: &str, is_typing: bool) -> Result<()> {
+        self.block_on(self.send_typing_async(recipient_id, is_typing))
     }
 
-    /// Get current user ID
-    pub fn get_current_user_id(&self) -> Result<String> {
-        self.storage.get_setting("current_user_id")
-            .ok_or_else(|| Error::NotLoggedIn)
+    /// `send_typing`, for async callers
+    pub async fn send_typing_async(&self, recipient_id: &str, is_typing: bool) -> Result<()> {
+        self.activity.typing(recipient_id, is_typing).await
     }
 
-    /// Export private key for backup
-    pub fn export_private_key(&self) -> Result<String> {
-        self.crypto.export_identity()
+    /// Announce the user's presence to their contacts. Changes in quick
+    /// succession are merged, so only the latest goes out.
+    pub fn set_presence(&self, status: PresenceStatus) -> Result<()> {
+        self.block_on(self.set_presence_async(status))
     }
 
-    /// Logout
-    pub fn logout(&self) -> Result<()> {
-        if let Some(ref ws) = *self.ws.write().take() {
-            self.runtime.block_on(ws.disconnect())?;
+    /// `set_presence`, for async callers
+    pub async fn set_presence_async(&self, status: PresenceStatus) -> Result<()> {
+        self.activity.presence(status).await
+    }
+
+    /// Change how often typing and presence updates may be sent
+    pub fn set_presence_policy(&self, policy: PresencePolicy) {
+        self.activity.set_policy(policy);
+    }
+
+    /// Tell the client whether the device runs on battery, as reported by
+    /// the platform's power monitor. On battery the WebSocket is pinged
+    /// every `ClientConfig::battery_keepalive`, and presence changes and
+    /// read position syncs are held; the latest of each is sent on return
+    /// to mains power.
+    pub fn set_power_source(&self, source: PowerSource) -> Result<()> {
+        self.block_on(self.set_power_source_async(source))
+    }
+
+    /// `set_power_source`, for async callers
+    pub async fn set_power_source_async(&self, source: PowerSource) -> Result<()> {
+        if self.power.set_source(source) == source {
+            return Ok(());
+        }
+        let ws = self.ws.read().clone();
+        if let Some(ws) = ws {
+            ws.set_power_source(source);
+        }
+        self.activity.set_paused(source == PowerSource::Battery).await?;
+
+        for position in self.power.take_deferred_reads() {
+            if let Err(e) = self.sync_read_position(&position).await {
+                log::warn!("Syncing read position of {} failed: {}", position.conversation_id, e);
+            }
         }
-        self.storage.clear_session()?;
         Ok(())
     }
 
-    /// Poll for new messages (call periodically)
-    pub fn poll_messages(&self) -> Result<Vec<Message>> {
-        let ws_guard = self.ws.read();
-        if let Some(ref ws) = *ws_guard {
-            let envelopes = self.runtime.block_on(ws.receive_messages())?;
-            drop(ws_guard);
+    pub fn power_source(&self) -> PowerSource {
+        self.power.source()
+    }
+
+    /// Send a payload to whichever of the recipient's devices are online
+    /// right now, e.g. a live location update. It isn't stored here or on
+    /// the server and isn't retried; the recipient gets a
+    /// [`ClientEvent::Ephemeral`].
+    pub fn send_ephemeral(&self, recipient_id: &str, kind: &str, payload: &str) -> Result<()> {
+        self.block_on(self.send_ephemeral_async(recipient_id, kind, payload))
+    }
+
+    /// `send_ephemeral`, for async callers
+    pub async fn send_ephemeral_async(&self, recipient_id: &str, kind: &str, payload: &str) -> Result<()> {
+        self.ensure_session(recipient_id).await?;
+
+        let body = serde_json::to_string(&EphemeralPayload {
+            kind: kind.to_string(),
+            payload: payload.to_string(),
+        })?;
+        let envelope = MessageEnvelope {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            sender_id: self.get_current_user_id()?,
+            recipient_id: recipient_id.to_string(),
+            recipient_device_id: None,
+            encrypted_content: String::new(),
+            message_type: EPHEMERAL_MESSAGE_TYPE.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            ttl_seconds: Some(0),
+            priority: MessagePriority::for_message_type(EPHEMERAL_MESSAGE_TYPE),
+            delivery_mode: DeliveryMode::OnlineOnly,
+        };
+        // One device failing doesn't keep the copies from the others
+        let mut result = Ok(());
+        for copy in self.fan_out(envelope, &body).await? {
+            result = result.and(self.deliver(&copy).await);
+        }
+        result
+    }
+
+    /// Send a message of an application-defined `x-<vendor>-<name>` type.
+    /// The payload is encrypted like a chat message but kept out of the
+    /// conversation; the recipient's handler for the type receives it.
+    pub fn send_custom(&self, recipient_id: &str, message_type: &str, payload: &str) -> Result<()> {
+        self.block_on(self.send_custom_async(recipient_id, message_type, payload))
+    }
+
+    /// `send_custom`, for async callers
+    pub async fn send_custom_async(&self, recipient_id: &str, message_type: &str, payload: &str) -> Result<()> {
+        if !is_custom_message_type(message_type) {
+            return Err(Error::InvalidConfig(format!(
+                "Custom message types look like x-<vendor>-<name>, got {}",
+                message_type
+            )));
+        }
+        self.ensure_session(recipient_id).await?;
+
+        let envelope = MessageEnvelope {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            sender_id: self.get_current_user_id()?,
+            recipient_id: recipient_id.to_string(),
+            recipient_device_id: None,
+            encrypted_content: String::new(),
+            message_type: message_type.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        };
+        let mut result = Ok(());
+        for copy in self.fan_out(envelope, payload).await? {
+            result = result.and(self.deliver_with_retry(&copy).await);
+        }
+        result
+    }
+
+    /// Call `handler` with each received message of `message_type`. A
+    /// vendor prefix such as `x-acme` handles all of that vendor's types;
+    /// the most specific registration wins. Replaces an earlier handler for
+    /// the same `message_type`.
+    ///
+    /// Handlers run on the thread polling for messages.
+    pub fn register_handler(
+        &self,
+        message_type: &str,
+        handler: impl Fn(&CustomMessage) + Send + Sync + 'static,
+    ) -> Result<()> {
+        let is_vendor = is_custom_message_type(&format!("{}-any", message_type));
+        if !is_custom_message_type(message_type) && !is_vendor {
+            return Err(Error::InvalidConfig(format!(
+                "Not a custom message type or vendor prefix: {}",
+                message_type
+            )));
+        }
+
+        let mut handlers = self.custom_handlers.write();
+        handlers.retain(|(registered, _)| registered != message_type);
+        handlers.push((message_type.to_string(), Arc::new(handler)));
+        Ok(())
+    }
+
+    pub fn unregister_handler(&self, message_type: &str) {
+        self.custom_handlers
+            .write()
+            .retain(|(registered, _)| registered != message_type);
+    }
+
+    /// Keep custom messages nobody registered a handler for, to read later
+    /// with `custom_messages`. Off by default, which drops them.
+    pub fn set_store_unhandled_custom(&self, store: bool) {
+        self.store_unhandled_custom.store(store, Ordering::Relaxed);
+    }
+
+    /// Stored custom messages of `message_type` or vendor prefix, oldest first
+    pub fn custom_messages(&self, message_type: &str) -> Result<Vec<CustomMessage>> {
+        self.storage.get_custom_messages(message_type)
+    }
+
+    fn custom_handler(&self, message_type: &str) -> Option<CustomHandler> {
+        self.custom_handlers
+            .read()
+            .iter()
+            .filter(|(registered, _)| {
+                message_type
+                    .strip_prefix(registered.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+            })
+            .max_by_key(|(registered, _)| registered.len())
+            .map(|(_, handler)| handler.clone())
+    }
+
+    /// Hand custom messages to their handlers, store or drop the rest, and
+    /// ack them
+    async fn dispatch_custom(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+
+        let mut ids = Vec::new();
+        for envelope in envelopes {
+            let content = decrypt::decrypt_content(&self.peer_keys, self.api.as_ref(), &envelope).await;
+            let payload = match content {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::warn!("Skipping unreadable {} {}: {}", envelope.message_type, envelope.message_id, e);
+                    continue;
+                }
+            };
+            let message = CustomMessage {
+                message_id: envelope.message_id,
+                sender_id: envelope.sender_id,
+                message_type: envelope.message_type,
+                payload,
+                timestamp: envelope.timestamp,
+            };
 
-            let mut messages = Vec::new();
-            for envelope in envelopes {
-                if let Ok(msg) = self.process_incoming_message(envelope) {
-                    messages.push(msg);
+            match self.custom_handler(&message.message_type) {
+                Some(handler) => handler(&message),
+                None if self.store_unhandled_custom.load(Ordering::Relaxed) => {
+                    self.storage.save_custom_message(&message)?
                 }
+                None => log::debug!("No handler for {}, dropping it", message.message_type),
             }
-            return Ok(messages);
+            ids.push(message.message_id);
         }
-        Ok(vec![])
+
+        self.ack(&ids).await
     }
 
-    fn process_incoming_message(&self, envelope: MessageEnvelope) -> Result<Message> {
-        // Establish session if needed
-        if !self.crypto.has_session(&envelope.sender_id) {
-            let user = self.runtime.block_on(self.api.get_user(&envelope.sender_id))?;
-            if let Some(pub_key) = user.public_key {
-                self.crypto.establish_session(&envelope.sender_id, &pub_key)?;
-            }
+    /// Send a pending or failed message again. It keeps its message_id, so a copy
+    /// that did reach the server the first time is dropped as a duplicate.
+    pub fn resend_message(&self, message_id: &str) -> Result<Message> {
+        self.block_on(self.resend_message_async(message_id))
+    }
+
+    /// `resend_message`, for async callers
+    pub async fn resend_message_async(&self, message_id: &str) -> Result<Message> {
+        let message = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        if !message.is_outgoing
+            || !matches!(message.status, MessageStatus::Pending | MessageStatus::Failed)
+        {
+            return Err(Error::Storage(format!("Message {} was already sent", message_id)));
         }
 
-        // Decrypt
-        let decrypted = self.crypto.decrypt_from(&envelope.sender_id, &envelope.encrypted_content)?;
-        let content: serde_json::Value = serde_json::from_str(&decrypted)?;
-        let text = content["text"].as_str().unwrap_or("").to_string();
+        self.ensure_session(&message.conversation_id).await?;
+        self.transmit(message).await
+    }
 
-        let message = Message {
-            message_id: envelope.message_id,
-            conversation_id: envelope.sender_id.clone(),
-            sender_id: envelope.sender_id,
-            message_type: MessageType::Text,
-            content: text,
-            timestamp: envelope.timestamp,
-            status: MessageStatus::Delivered,
-            attachment: None,
-            is_outgoing: false,
-        };
+    /// Outgoing messages in a conversation that haven't been sent yet, and
+    /// why: queued while offline, throttled by the server, or failed
+    pub fn pending_outgoing(&self, conversation_id: &str) -> Result<Vec<PendingMessage>> {
+        self.storage.get_pending_outgoing(conversation_id)
+    }
 
-        self.storage.save_message(&message)?;
+    /// A message with the device it was sent from and, for messages sent
+    /// from this device, how it was delivered: transport, attempts and when
+    /// the server acknowledged it
+    pub fn message_info(&self, message_id: &str) -> Result<MessageInfo> {
+        let message = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        let delivery = self.storage.get_delivery(message_id)?;
+        Ok(MessageInfo { message, delivery })
+    }
 
-        Ok(message)
+    /// How often a message send is retried before the message is stored as
+    /// failed. The default, `RetryPolicy::none()`, leaves retrying to the
+    /// application through `resend_message`.
+    pub fn set_send_retry(&self, policy: RetryPolicy) {
+        *self.send_retry.write() = policy;
     }
-}
 
-/// Client configuration
-#[derive(Clone, Debug)]
-pub struct ClientConfig {
-    pub server_host: String,
-    pub server_port: u16,
-    pub use_tls: bool,
-}
+    /// How often a message queued while offline is tried again as messages
+    /// are polled, and how long to wait between tries, before it is stored
+    /// as failed. Every queued message is also tried as soon as the
+    /// WebSocket reconnects.
+    pub fn set_outbox_retry(&self, policy: RetryPolicy) {
+        *self.outbox_retry.write() = policy;
+    }
+
+    /// Try the queued messages that are due again, or with `all` every
+    /// queued message. Each one that is sent or given up on changes status.
+    /// While another flush is running this returns at once without trying
+    /// anything; messages it left due stay queued for the next poll.
+    async fn flush_outbox(&self, all: bool) -> Result<()> {
+        let Ok(_flushing) = self.outbox_flush.try_lock() else {
+            return Ok(());
+        };
+        let due_by = if all {
+            i64::MAX
+        } else {
+            chrono::Utc::now().timestamp_millis()
+        };
+        for message_id in self.storage.get_due_outgoing(due_by)? {
+            let message = match self.storage.get_message(&message_id)? {
+                // Sent through `resend_message` in the meantime
+                Some(message) if message.status == MessageStatus::Pending => message,
+                _ => {
+                    self.storage.remove_outgoing(&message_id)?;
+                    continue;
+                }
+            };
+            let conversation_id = message.conversation_id.clone();
+            let status = match self.transmit(message).await {
+                Ok(message) => message.status,
+                Err(e) => {
+                    log::warn!("Sending queued message {} failed: {}", message_id, e);
+                    self.storage.remove_outgoing(&message_id)?;
+                    self.storage
+                        .update_message_status(&message_id, MessageStatus::Failed)?;
+                    MessageStatus::Failed
+                }
+            };
+            if status != MessageStatus::Pending {
+                self.event_sender.send(ClientEvent::MessageStatusChanged {
+                    conversation_id,
+                    message_id,
+                    status,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a message that found no connection pending in the outbox until
+    /// its next try, or store it as failed once its tries are used up
+    fn queue_offline(&self, message: &mut Message) -> Result<()> {
+        let policy = self.outbox_retry.read().clone();
+        let attempts = self.storage.queue_outgoing(&message.message_id)?;
+        if attempts > policy.max_retries {
+            message.status = MessageStatus::Failed;
+            self.storage.remove_outgoing(&message.message_id)?;
+        } else {
+            let backoff = policy.backoff(attempts - 1).as_millis() as i64;
+            self.storage
+                .schedule_outgoing(&message.message_id, chrono::Utc::now().timestamp_millis() + backoff)?;
+        }
+        self.storage.save_message(message)?;
+        self.storage
+            .set_pending_reason(&message.message_id, PendingReason::Offline)
+    }
+
+    /// Fetch the recipient's public key if there is no session with them
+    /// yet. Fails without asking the server for peers known to be gone,
+    /// and with `Error::KeyConflict` while their key is unverified.
+    async fn ensure_session(&self, recipient_id: &str) -> Result<()> {
+        if let Some(group) = self.storage.get_group(recipient_id)? {
+            // Members' sessions are set up as the message goes out
+            if !group.is_member(&self.get_current_user_id()?) {
+                return Err(Error::RecipientUnavailable);
+            }
+            return Ok(());
+        }
+        if self.storage.is_peer_unavailable(recipient_id)? {
+            return Err(Error::RecipientUnavailable);
+        }
+        if !self.crypto.has_session(recipient_id) {
+            let user = match self.api.get_user(recipient_id).await {
+                Err(Error::RecipientUnavailable) => {
+                    self.set_peer_available(recipient_id, false)?;
+                    return Err(Error::RecipientUnavailable);
+                }
+                result => result?,
+            };
+            if let Some(pub_key) = user.public_key {
+                self.peer_keys.establish(recipient_id, &pub_key)?;
+            } else {
+                return Err(Error::NoPublicKey(recipient_id.to_string()));
+            }
+        }
+        self.peer_keys.check_trusted(recipient_id)
+    }
 
-impl ClientConfig {
-    pub fn new(host: &str, port: u16, use_tls: bool) -> Self {
-        Self {
-            server_host: host.to_string(),
-            server_port: port,
-            use_tls,
+    /// Whether the conversation's peer was deactivated or deleted on the
+    /// server, or the user is no longer in the group. Its history stays
+    /// readable, but sending to it fails with `Error::RecipientUnavailable`.
+    pub fn is_conversation_read_only(&self, conversation_id: &str) -> Result<bool> {
+        if let Some(group) = self.storage.get_group(conversation_id)? {
+            return Ok(!group.is_member(&self.get_current_user_id()?));
         }
+        self.storage.is_peer_unavailable(conversation_id)
+    }
+
+    /// Create a group of the user and `member_ids`. Messages sent to its
+    /// `group_id` go to every member.
+    pub fn create_group(&self, name: &str, member_ids: &[String]) -> Result<Group> {
+        self.block_on(self.create_group_async(name, member_ids))
+    }
+
+    /// `create_group`, for async callers
+    pub async fn create_group_async(&self, name: &str, member_ids: &[String]) -> Result<Group> {
+        let group = self.api.create_group(name, member_ids).await?;
+        self.storage.save_group(&group)?;
+        Ok(group)
+    }
+
+    /// Groups the user is or was in, as last seen
+    pub fn groups(&self) -> Result<Vec<Group>> {
+        self.storage.get_groups()
     }
 
-    pub fn http_url(&self) -> String {
-        let scheme = if self.use_tls { "https" } else { "http" };
-        format!("{}://{}:{}", scheme, self.server_host, self.server_port)
+    pub fn group(&self, group_id: &str) -> Result<Option<Group>> {
+        self.storage.get_group(group_id)
     }
 
-    pub fn ws_url(&self) -> String {
-        let scheme = if self.use_tls { "wss" } else { "ws" };
-        format!("{}://{}:{}/ws", scheme, self.server_host, self.server_port)
+    /// Fetch the groups the user is in from the server, which includes
+    /// groups others added them to before they wrote
+    pub fn refresh_groups(&self) -> Result<Vec<Group>> {
+        self.block_on(self.refresh_groups_async())
+    }
+
+    /// `refresh_groups`, for async callers
+    pub async fn refresh_groups_async(&self) -> Result<Vec<Group>> {
+        let groups = self.api.list_groups().await?;
+        for group in &groups {
+            self.storage.save_group(group)?;
+        }
+        Ok(groups)
+    }
+
+    pub fn add_group_members(&self, group_id: &str, member_ids: &[String]) -> Result<Group> {
+        self.block_on(self.add_group_members_async(group_id, member_ids))
+    }
+
+    /// `add_group_members`, for async callers
+    pub async fn add_group_members_async(&self, group_id: &str, member_ids: &[String]) -> Result<Group> {
+        let group = self.api.add_group_members(group_id, member_ids).await?;
+        self.storage.save_group(&group)?;
+        Ok(group)
+    }
+
+    /// Remove a member; only the group's creator may remove others
+    pub fn remove_group_member(&self, group_id: &str, user_id: &str) -> Result<()> {
+        self.block_on(self.remove_group_member_async(group_id, user_id))
+    }
+
+    /// `remove_group_member`, for async callers
+    pub async fn remove_group_member_async(&self, group_id: &str, user_id: &str) -> Result<()> {
+        self.api.remove_group_member(group_id, user_id).await?;
+        if let Some(mut group) = self.storage.get_group(group_id)? {
+            group.member_ids.retain(|id| id != user_id);
+            self.storage.save_group(&group)?;
+        }
+        Ok(())
+    }
+
+    /// Leave a group. Its conversation stays, read-only.
+    pub fn leave_group(&self, group_id: &str) -> Result<()> {
+        self.block_on(self.leave_group_async(group_id))
+    }
+
+    /// `leave_group`, for async callers
+    pub async fn leave_group_async(&self, group_id: &str) -> Result<()> {
+        self.remove_group_member_async(group_id, &self.get_current_user_id()?).await
+    }
+
+    /// Who a message to `conversation_id` goes to besides the user, or
+    /// `None` if it isn't a group. The members are fetched from the server
+    /// when it can be reached, so joins and removals since are accounted for.
+    async fn group_recipients(&self, conversation_id: &str) -> Result<Option<Vec<String>>> {
+        let Some(mut group) = self.storage.get_group(conversation_id)? else {
+            return Ok(None);
+        };
+        let user_id = self.get_current_user_id()?;
+        match self.api.get_group(conversation_id).await {
+            Ok(current) => group = current,
+            // The server only shows groups to their members
+            Err(Error::Server(e)) if e.code == ErrorCode::NotFound => {
+                group.member_ids.retain(|id| *id != user_id)
+            }
+            Err(e) => log::warn!("Sending to the stored members of {}: {}", conversation_id, e),
+        }
+        self.storage.save_group(&group)?;
+
+        if !group.is_member(&user_id) {
+            return Err(Error::RecipientUnavailable);
+        }
+        Ok(Some(group.member_ids.into_iter().filter(|id| *id != user_id).collect()))
+    }
+
+    /// Record what the server said about the peer, announcing changes
+    fn set_peer_available(&self, peer_id: &str, available: bool) -> Result<()> {
+        if self.storage.set_peer_unavailable(peer_id, !available)? {
+            self.event_sender.send(ClientEvent::PeerAvailabilityChanged {
+                peer_id: peer_id.to_string(),
+                available,
+            });
+        }
+        Ok(())
+    }
+
+    /// Encrypt an outgoing message, deliver it and store it as sent or
+    /// failed. If the server can't be reached it is stored as pending.
+    async fn transmit(&self, mut message: Message) -> Result<Message> {
+        message.sender_device_id = self.storage.get_setting("device_id");
+        let reachable =
+            self.lan.has_peer(&message.conversation_id) || self.reconnect_if_needed().await.is_ok();
+        if !reachable {
+            message.status = MessageStatus::Pending;
+            self.queue_offline(&mut message)?;
+            return Ok(message);
+        }
+
+        let mut content = match message.attachment {
+            Some(ref attachment) => {
+                let mut content = serde_json::json!({
+                    "file_id": attachment.file_id,
+                    "file_name": attachment.file_name,
+                    "file_size": attachment.file_size,
+                    "mime_type": attachment.mime_type,
+                    "encryption_key": attachment.encryption_key,
+                    "sha256": attachment.sha256,
+                    "expires_at": attachment.expires_at
+                });
+                if let Some(ref caption) = message.caption {
+                    content["caption"] = serde_json::json!(caption);
+                }
+                content
+            }
+            None => serde_json::json!({ "text": message.content }),
+        };
+        if !message.metadata.is_empty() {
+            content["metadata"] = serde_json::to_value(&message.metadata)?;
+        }
+        if let Some(ref device_id) = message.sender_device_id {
+            content["device_id"] = serde_json::json!(device_id);
+        }
+        if let Some(ref quote) = message.quote {
+            content["quote"] = serde_json::to_value(quote)?;
+        }
+        // Tells the recipient it can write to us over a ratchet
+        if self.flags.is_enabled(Flag::Ratchet) {
+            content["ratchet"] = serde_json::json!(true);
+        }
+
+        // A group message goes to each member as a copy of its own
+        let (recipients, is_group) = match self.group_recipients(&message.conversation_id).await? {
+            Some(members) => {
+                content["group_id"] = serde_json::json!(message.conversation_id);
+                content["group_message_id"] = serde_json::json!(message.message_id);
+                (members, true)
+            }
+            None => (vec![message.conversation_id.clone()], false),
+        };
+        let content = content.to_string();
+
+        let mut delivered = Ok(None);
+        let mut attempts = 0;
+        for recipient_id in recipients {
+            if is_group {
+                if let Err(e) = self.ensure_session(&recipient_id).await {
+                    log::warn!("Leaving {} out of group message {}: {}", recipient_id, message.message_id, e);
+                    continue;
+                }
+            }
+            let envelope = MessageEnvelope {
+                message_id: if is_group {
+                    copy_id(&message.message_id, &recipient_id)
+                } else {
+                    message.message_id.clone()
+                },
+                sender_id: message.sender_id.clone(),
+                encrypted_content: String::new(),
+                recipient_id,
+                recipient_device_id: None,
+                message_type: message.message_type.as_str().to_string(),
+                timestamp: message.timestamp,
+                ttl_seconds: privmsg_proto::default_ttl_seconds(message.message_type.as_str()),
+                priority: MessagePriority::for_message_type(message.message_type.as_str()),
+                delivery_mode: DeliveryMode::StoreAndForward,
+            };
+
+            for copy in self.fan_out(envelope, &content).await? {
+                let (result, tries) = self.deliver_tracked(&copy).await;
+                delivered = delivered.and(result);
+                attempts += tries;
+            }
+        }
+        message.status = match delivered {
+            Ok(_) => MessageStatus::Sent,
+            Err(_) => MessageStatus::Failed,
+        };
+        self.storage.save_message(&message)?;
+        self.storage.remove_outgoing(&message.message_id)?;
+        let transport = delivered.as_ref().ok().copied().flatten();
+        self.storage
+            .record_delivery(&message.message_id, transport, attempts)?;
+        if let Err(ref e) = delivered {
+            self.storage
+                .set_pending_reason(&message.message_id, PendingReason::from_error(e))?;
+        }
+
+        delivered.map(|_| message)
+    }
+
+    /// Encrypt, upload and send a file, optionally with a caption
+    pub fn send_file(
+        &self,
+        recipient_id: &str,
+        data: Vec<u8>,
+        file_name: &str,
+        mime_type: &str,
+        caption: Option<&str>,
+    ) -> Result<Message> {
+        self.block_on(self.send_file_async(recipient_id, data, file_name, mime_type, caption))
+    }
+
+    /// `send_file`, for async callers
+    pub async fn send_file_async(
+        &self,
+        recipient_id: &str,
+        data: Vec<u8>,
+        file_name: &str,
+        mime_type: &str,
+        caption: Option<&str>,
+    ) -> Result<Message> {
+        self.policy.check(Feature::FileTransfer)?;
+        self.ensure_session(recipient_id).await?;
+
+        // Encrypt and upload file
+        let file_key = self.crypto.generate_file_key()?;
+        let encrypted_data = self.crypto.encrypt_file(&data, &file_key)?;
+        let sha256 = self.crypto.hash(&encrypted_data);
+        let key_hash = self.crypto.hash(file_key.as_bytes());
+        let uploaded = self
+            .api
+            .upload_file(encrypted_data, file_name, mime_type, &key_hash)
+            .await?;
+
+        let attachment = Attachment {
+            file_id: uploaded.file_id,
+            file_name: file_name.to_string(),
+            file_size: data.len() as i64,
+            mime_type: mime_type.to_string(),
+            duration_ms: None,
+            width: None,
+            height: None,
+            encryption_key: Some(file_key),
+            local_path: None,
+            waveform: None,
+            sha256: Some(sha256),
+            expires_at: uploaded.expires_at,
+        };
+
+        self.send_attachment(recipient_id, attachment, caption).await
+    }
+
+    /// Like `send_file`, but encrypts and uploads from disk in chunks so
+    /// large files are never held in memory
+    pub fn send_file_from_path(
+        &self,
+        recipient_id: &str,
+        path: &Path,
+        mime_type: &str,
+        caption: Option<&str>,
+    ) -> Result<Message> {
+        self.block_on(self.send_file_from_path_async(recipient_id, path, mime_type, caption))
+    }
+
+    /// `send_file_from_path`, for async callers
+    pub async fn send_file_from_path_async(
+        &self,
+        recipient_id: &str,
+        path: &Path,
+        mime_type: &str,
+        caption: Option<&str>,
+    ) -> Result<Message> {
+        self.policy.check(Feature::FileTransfer)?;
+        if !self.crypto.has_session(recipient_id) {
+            let user = self.api.get_user(recipient_id).await?;
+            if let Some(pub_key) = user.public_key {
+                self.peer_keys.establish(recipient_id, &pub_key)?;
+            } else {
+                return Err(Error::NoPublicKey(recipient_id.to_string()));
+            }
+        }
+        self.peer_keys.check_trusted(recipient_id)?;
+
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "file".to_string());
+        let file_size = std::fs::metadata(path)?.len() as i64;
+
+        let file_key = self.crypto.generate_file_key()?;
+        let encrypted_path = self.temp_path();
+        let uploaded = async {
+            self.encrypt_to_path(path, &encrypted_path, &file_key)?;
+            let sha256 = transfer::hash_file(&encrypted_path)?;
+            if let Some(file_id) = self.send_direct(recipient_id, &encrypted_path, &sha256).await {
+                return Ok((UploadedFile { file_id, expires_at: None }, sha256));
+            }
+            let key_hash = self.crypto.hash(file_key.as_bytes());
+            let uploaded = self
+                .api
+                .upload_file_from_path(&encrypted_path, &file_name, mime_type, &key_hash)
+                .await?;
+            Ok::<_, Error>((uploaded, sha256))
+        }
+        .await;
+        let _ = std::fs::remove_file(&encrypted_path);
+        let (UploadedFile { file_id, expires_at }, sha256) = uploaded?;
+        // Kept for re-uploads, and after a direct transfer as the only copy
+        let local_path = Some(path.to_string_lossy().into_owned());
+
+        let attachment = Attachment {
+            file_id,
+            file_name,
+            file_size,
+            mime_type: mime_type.to_string(),
+            duration_ms: None,
+            width: None,
+            height: None,
+            encryption_key: Some(file_key),
+            local_path,
+            waveform: None,
+            sha256: Some(sha256),
+            expires_at,
+        };
+
+        self.send_attachment(recipient_id, attachment, caption).await
+    }
+
+    /// Ask the server to keep the file attached to a message at least
+    /// `hours` longer, within the server's maximum lifetime. Returns the new
+    /// expiry in Unix seconds; the peer's copy of the attachment follows.
+    pub fn extend_attachment(&self, message_id: &str, hours: u64) -> Result<i64> {
+        self.block_on(self.extend_attachment_async(message_id, hours))
+    }
+
+    /// `extend_attachment`, for async callers
+    pub async fn extend_attachment_async(&self, message_id: &str, hours: u64) -> Result<i64> {
+        let (conversation_id, file_id) = self.shared_file(message_id)?;
+        let expires_at = self.api.extend_file(&file_id, hours).await?;
+        self.share_file_expiry(&conversation_id, FileExpiry { file_id, expires_at })
+            .await?;
+        Ok(expires_at)
+    }
+
+    /// Delete the file attached to a message from the server before it
+    /// expires. Recipients may do so once they have downloaded it.
+    pub fn delete_attachment(&self, message_id: &str) -> Result<()> {
+        self.block_on(self.delete_attachment_async(message_id))
+    }
+
+    /// `delete_attachment`, for async callers
+    pub async fn delete_attachment_async(&self, message_id: &str) -> Result<()> {
+        let (conversation_id, file_id) = self.shared_file(message_id)?;
+        self.api.delete_file(&file_id).await?;
+        let expires_at = chrono::Utc::now().timestamp();
+        self.share_file_expiry(&conversation_id, FileExpiry { file_id, expires_at })
+            .await
+    }
+
+    /// Conversation and file id of a stored message's attachment
+    fn shared_file(&self, message_id: &str) -> Result<(String, String)> {
+        let message = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        let attachment = message
+            .attachment
+            .ok_or_else(|| Error::Storage(format!("Message {} has no attachment", message_id)))?;
+        Ok((message.conversation_id, attachment.file_id))
+    }
+
+    /// Record a new expiry locally and tell the other side of the conversation
+    async fn share_file_expiry(&self, conversation_id: &str, expiry: FileExpiry) -> Result<()> {
+        self.storage
+            .set_attachment_expiry(conversation_id, &expiry.file_id, expiry.expires_at)?;
+        self.send_to_peer(conversation_id, FILE_EXPIRY_MESSAGE_TYPE, &expiry).await
+    }
+
+    /// Ask the sender of a received attachment to upload it again, e.g.
+    /// after it expired on the server. The sender gets
+    /// `ClientEvent::ReuploadRequested`.
+    pub fn request_reupload(&self, message_id: &str) -> Result<()> {
+        self.block_on(self.request_reupload_async(message_id))
+    }
+
+    /// `request_reupload`, for async callers
+    pub async fn request_reupload_async(&self, message_id: &str) -> Result<()> {
+        let message = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        let attachment = match message.attachment {
+            Some(attachment) if !message.is_outgoing => attachment,
+            _ => {
+                return Err(Error::Storage(format!(
+                    "Message {} has no received attachment",
+                    message_id
+                )))
+            }
+        };
+        let request = ReuploadRequest {
+            message_id: message.message_id,
+            file_id: attachment.file_id,
+        };
+        self.send_to_peer(&message.conversation_id, REUPLOAD_REQUEST_MESSAGE_TYPE, &request)
+            .await
+    }
+
+    /// Upload a sent attachment again from its local copy, e.g. after
+    /// `ClientEvent::ReuploadRequested`. The message keeps its id on both
+    /// sides and points at the new upload.
+    pub fn reupload_attachment(&self, message_id: &str) -> Result<Attachment> {
+        self.block_on(self.reupload_attachment_async(message_id))
+    }
+
+    /// `reupload_attachment`, for async callers
+    pub async fn reupload_attachment_async(&self, message_id: &str) -> Result<Attachment> {
+        self.policy.check(Feature::FileTransfer)?;
+        let message = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        let previous = match message.attachment {
+            Some(attachment) if message.is_outgoing => attachment,
+            _ => {
+                return Err(Error::Storage(format!(
+                    "Message {} has no sent attachment",
+                    message_id
+                )))
+            }
+        };
+        let path = previous
+            .local_path
+            .as_deref()
+            .map(Path::new)
+            .filter(|path| path.is_file())
+            .ok_or_else(|| Error::Storage(format!("No local copy of the attachment of {}", message_id)))?;
+        self.ensure_session(&message.conversation_id).await?;
+
+        let file_key = self.crypto.generate_file_key()?;
+        let encrypted_path = self.temp_path();
+        let uploaded = async {
+            self.encrypt_to_path(path, &encrypted_path, &file_key)?;
+            let sha256 = transfer::hash_file(&encrypted_path)?;
+            let key_hash = self.crypto.hash(file_key.as_bytes());
+            let uploaded = self
+                .api
+                .upload_file_from_path(&encrypted_path, &previous.file_name, &previous.mime_type, &key_hash)
+                .await?;
+            Ok::<_, Error>((uploaded, sha256))
+        }
+        .await;
+        let _ = std::fs::remove_file(&encrypted_path);
+        let (uploaded, sha256) = uploaded?;
+
+        let attachment = Attachment {
+            file_id: uploaded.file_id,
+            encryption_key: Some(file_key),
+            sha256: Some(sha256),
+            expires_at: uploaded.expires_at,
+            ..previous
+        };
+        self.storage.set_message_attachment(message_id, &attachment)?;
+        let update = AttachmentUpdate {
+            message_id: message_id.to_string(),
+            attachment: Attachment {
+                local_path: None,
+                ..attachment.clone()
+            },
+        };
+        self.send_to_peer(&message.conversation_id, ATTACHMENT_UPDATE_MESSAGE_TYPE, &update)
+            .await?;
+        Ok(attachment)
+    }
+
+    /// Upload attachments peers ask for again right away when there is a
+    /// local copy, instead of passing `ClientEvent::ReuploadRequested` on
+    /// for the user to confirm. Off by default.
+    pub fn set_auto_reupload(&self, enabled: bool) {
+        self.auto_reupload.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Encrypt a control payload for a peer and send it outside the
+    /// conversation
+    async fn send_to_peer(
+        &self,
+        peer_id: &str,
+        message_type: &str,
+        payload: &(impl serde::Serialize + Sync),
+    ) -> Result<()> {
+        self.ensure_session(peer_id).await?;
+
+        let envelope = MessageEnvelope {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            sender_id: self.get_current_user_id()?,
+            recipient_id: peer_id.to_string(),
+            recipient_device_id: None,
+            encrypted_content: String::new(),
+            message_type: message_type.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            ttl_seconds: None,
+            priority: MessagePriority::for_message_type(message_type),
+            delivery_mode: DeliveryMode::StoreAndForward,
+        };
+        let mut result = Ok(());
+        for copy in self.fan_out(envelope, &serde_json::to_string(payload)?).await? {
+            result = result.and(self.deliver(&copy).await);
+        }
+        result
+    }
+
+    /// Copies of `envelope` for each device of its recipient, with
+    /// `plaintext` encrypted for each one. A peer on the LAN gets a single
+    /// copy for their own key. A copy that can't be encrypted is left out;
+    /// it is an error only when none can.
+    async fn fan_out(&self, envelope: MessageEnvelope, plaintext: &str) -> Result<Vec<MessageEnvelope>> {
+        let copies = if self.lan.has_peer(&envelope.recipient_id) {
+            vec![devices::DeviceCopy {
+                device_id: None,
+                session_id: envelope.recipient_id.clone(),
+            }]
+        } else {
+            devices::copies_for(&self.peer_keys, self.api.as_ref(), &envelope.recipient_id).await?
+        };
+        let mut envelopes = Vec::with_capacity(copies.len());
+        let mut failure = None;
+        for copy in copies {
+            match self.crypto.encrypt_for(&copy.session_id, plaintext) {
+                Ok(encrypted_content) => envelopes.push(MessageEnvelope {
+                    message_id: match copy.device_id {
+                        Some(ref device_id) => copy_id(&envelope.message_id, device_id),
+                        None => envelope.message_id.clone(),
+                    },
+                    encrypted_content,
+                    recipient_device_id: copy.device_id,
+                    ..envelope.clone()
+                }),
+                Err(e) => {
+                    log::warn!("Leaving out the copy for {}: {}", copy.session_id, e);
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) if envelopes.is_empty() => Err(e),
+            _ => Ok(envelopes),
+        }
+    }
+
+    /// Apply expiry changes from peers to their conversations and ack them
+    async fn apply_file_expiries(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+
+        let mut ids = Vec::new();
+        for envelope in envelopes {
+            let expiry = decrypt::decrypt_content(&self.peer_keys, self.api.as_ref(), &envelope)
+                .await
+                .and_then(|json| Ok(serde_json::from_str::<FileExpiry>(&json)?));
+            match expiry {
+                Ok(expiry) => {
+                    // Only files shared with the sender are touched
+                    if self.storage.set_attachment_expiry(
+                        &envelope.sender_id,
+                        &expiry.file_id,
+                        expiry.expires_at,
+                    )? {
+                        self.event_sender.send(ClientEvent::AttachmentExpiryChanged {
+                            conversation_id: envelope.sender_id,
+                            expiry,
+                        });
+                    }
+                    ids.push(envelope.message_id);
+                }
+                Err(e) => log::warn!("Skipping unreadable file expiry {}: {}", envelope.message_id, e),
+            }
+        }
+
+        self.ack(&ids).await
+    }
+
+    /// Pass re-upload requests for attachments sent to the requester on as
+    /// events and ack them all
+    async fn apply_reupload_requests(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+
+        let mut ids = Vec::new();
+        for envelope in envelopes {
+            let request = decrypt::decrypt_content(&self.peer_keys, self.api.as_ref(), &envelope)
+                .await
+                .and_then(|json| Ok(serde_json::from_str::<ReuploadRequest>(&json)?));
+            match request {
+                Ok(request) => {
+                    // Only attachments the requester was sent are offered
+                    let sent = self.storage.get_message(&request.message_id)?.is_some_and(|m| {
+                        m.is_outgoing
+                            && m.conversation_id == envelope.sender_id
+                            && m.attachment.is_some_and(|a| a.file_id == request.file_id)
+                    });
+                    if sent {
+                        self.answer_reupload_request(envelope.sender_id, request).await;
+                    }
+                    ids.push(envelope.message_id);
+                }
+                Err(e) => log::warn!("Skipping unreadable re-upload request {}: {}", envelope.message_id, e),
+            }
+        }
+
+        self.ack(&ids).await
+    }
+
+    /// Upload the attachment again if allowed to without asking, otherwise
+    /// leave it to the user
+    async fn answer_reupload_request(&self, conversation_id: String, request: ReuploadRequest) {
+        if self.auto_reupload.load(Ordering::Relaxed) {
+            match self.reupload_attachment_async(&request.message_id).await {
+                Ok(_) => {
+                    self.event_sender.send(ClientEvent::AttachmentReuploaded {
+                        conversation_id,
+                        message_id: request.message_id,
+                    });
+                    return;
+                }
+                Err(e) => log::warn!("Could not upload {} again: {}", request.message_id, e),
+            }
+        }
+        self.event_sender.send(ClientEvent::ReuploadRequested {
+            conversation_id,
+            request,
+        });
+    }
+
+    /// Delete messages their senders deleted for everyone and ack the
+    /// deletions. A deletion only opens under the session with its sender,
+    /// which authenticates them, and deletions of someone else's message
+    /// are ignored. A message not received yet is dropped when it arrives.
+    async fn apply_deletions(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+
+        let mut ids = Vec::new();
+        for envelope in envelopes {
+            let deletion = decrypt::decrypt_content(&self.peer_keys, self.api.as_ref(), &envelope)
+                .await
+                .and_then(|json| Ok(serde_json::from_str::<MessageDeletion>(&json)?));
+            match deletion {
+                Ok(deletion) => {
+                    match self.storage.get_message(&deletion.message_id)? {
+                        Some(message) if message.sender_id == envelope.sender_id => {
+                            self.storage.delete_message(&message.message_id)?;
+                            self.event_sender.send(ClientEvent::MessageDeleted {
+                                conversation_id: message.conversation_id,
+                                message_id: message.message_id,
+                            });
+                        }
+                        Some(_) => log::warn!(
+                            "Ignoring deletion of {} by {}, who didn't send it",
+                            deletion.message_id, envelope.sender_id
+                        ),
+                        // Dropped when it arrives, if it is the deleter's
+                        None => self.storage.record_deletion(&deletion.message_id, &envelope.sender_id)?,
+                    }
+                    ids.push(envelope.message_id);
+                }
+                Err(e) => log::warn!("Skipping unreadable deletion {}: {}", envelope.message_id, e),
+            }
+        }
+
+        self.ack(&ids).await
+    }
+
+    /// Apply message lifetimes set by peers or on the user's other devices
+    /// and ack them
+    async fn apply_conversation_ttls(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+
+        let user_id = self.get_current_user_id()?;
+        let mut ids = Vec::new();
+        for envelope in envelopes {
+            let update = decrypt::decrypt_content(&self.peer_keys, self.api.as_ref(), &envelope)
+                .await
+                .and_then(|json| Ok(serde_json::from_str::<ConversationTtl>(&json)?));
+            match update {
+                Ok(update) => {
+                    let group = self.storage.get_group(&update.conversation_id)?;
+                    let conversation_id = if envelope.sender_id == user_id {
+                        Some(update.conversation_id.clone())
+                    } else if let Some(group) = group {
+                        // Only a member may change a group's lifetime
+                        group.is_member(&envelope.sender_id).then_some(group.group_id)
+                    } else {
+                        Some(envelope.sender_id.clone())
+                    };
+                    match conversation_id {
+                        Some(conversation_id) => {
+                            if self.storage.set_conversation_ttl(&conversation_id, update.ttl_seconds)? {
+                                self.event_sender.send(ClientEvent::ConversationTtlChanged {
+                                    conversation_id,
+                                    ttl_seconds: update.ttl_seconds,
+                                });
+                            }
+                        }
+                        None => log::warn!(
+                            "Ignoring message lifetime for {} from non-member {}",
+                            update.conversation_id, envelope.sender_id
+                        ),
+                    }
+                    ids.push(envelope.message_id);
+                }
+                Err(e) => log::warn!("Skipping unreadable message lifetime {}: {}", envelope.message_id, e),
+            }
+        }
+
+        self.ack(&ids).await
+    }
+
+    /// Point received attachments the peer uploaded again at the new
+    /// uploads and ack the updates
+    async fn apply_attachment_updates(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+
+        let mut ids = Vec::new();
+        for envelope in envelopes {
+            let update = decrypt::decrypt_content(&self.peer_keys, self.api.as_ref(), &envelope)
+                .await
+                .and_then(|json| Ok(serde_json::from_str::<AttachmentUpdate>(&json)?));
+            match update {
+                Ok(update) => {
+                    // Only attachments the sender sent are replaced
+                    let received = self.storage.get_message(&update.message_id)?.is_some_and(|m| {
+                        !m.is_outgoing && m.conversation_id == envelope.sender_id && m.attachment.is_some()
+                    });
+                    let attachment = Attachment {
+                        local_path: None,
+                        ..update.attachment
+                    };
+                    if received && self.storage.set_message_attachment(&update.message_id, &attachment)? {
+                        self.event_sender.send(ClientEvent::AttachmentReuploaded {
+                            conversation_id: envelope.sender_id,
+                            message_id: update.message_id,
+                        });
+                    }
+                    ids.push(envelope.message_id);
+                }
+                Err(e) => log::warn!("Skipping unreadable attachment update {}: {}", envelope.message_id, e),
+            }
+        }
+
+        self.ack(&ids).await
+    }
+
+    /// Send files whose encrypted size is at least `min_size` bytes straight
+    /// to the recipient over a WebRTC data channel instead of through the
+    /// server, and accept such files from others. `None` (the default)
+    /// turns direct transfers off. A direct transfer that fails falls back
+    /// to the server relay. Ignored while the client policy disables
+    /// direct transfers; files are relayed while [`Flag::DirectTransfer`]
+    /// is off.
+    pub fn set_direct_transfers(&self, min_size: Option<u64>) {
+        if min_size.is_some() && !self.policy.allows(Feature::DirectTransfer) {
+            log::warn!("Direct transfers are disabled by the client policy");
+            return;
+        }
+        self.direct.set_min_size(min_size);
+    }
+
+    /// Try to hand an encrypted file straight to the recipient. Returns the
+    /// attachment file id, or `None` when the server relay should be used.
+    async fn send_direct(&self, recipient_id: &str, encrypted_path: &Path, sha256: &str) -> Option<String> {
+        if !self.flags.is_enabled(Flag::DirectTransfer) || self.storage.is_group(recipient_id).ok()? {
+            return None;
+        }
+        let min_size = self.direct.min_size()?;
+        if std::fs::metadata(encrypted_path).ok()?.len() < min_size {
+            return None;
+        }
+        let ws = self.ws.read().clone()?;
+        let sender_id = self.get_current_user_id().ok()?;
+
+        let sent = self
+            .direct
+            .send(&*ws, &sender_id, recipient_id, encrypted_path, sha256)
+            .await;
+        match sent {
+            Ok(file_id) => Some(file_id),
+            Err(e) => {
+                log::warn!("Relaying file for {} through the server: {}", recipient_id, e);
+                None
+            }
+        }
+    }
+
+    /// Download an attachment in parallel ranges and decrypt it to `dest`.
+    ///
+    /// An interrupted download resumes from the ranges already fetched,
+    /// including after a restart. The reassembled file is checked against
+    /// the sender's hash before it is decrypted. The transfer id for
+    /// `pause_transfer`/`cancel_transfer` is the attachment's file id.
+    pub fn download_attachment_to(&self, attachment: &Attachment, dest: &Path) -> Result<()> {
+        self.block_on(self.download_attachment_to_async(attachment, dest))
+    }
+
+    /// `download_attachment_to`, for async callers
+    pub async fn download_attachment_to_async(&self, attachment: &Attachment, dest: &Path) -> Result<()> {
+        self.fetch_attachment(attachment, dest, &mut |src, key| self.decrypt_to_path(src, dest, key))
+            .await
+    }
+
+    /// Download and verify an attachment, then hand the encrypted file to
+    /// `decrypt`. `dest` is where an interrupted download resumes to.
+    async fn fetch_attachment(
+        &self,
+        attachment: &Attachment,
+        dest: &Path,
+        decrypt: &mut (dyn FnMut(&Path, &str) -> Result<()> + Send),
+    ) -> Result<()> {
+        let key = attachment
+            .encryption_key
+            .as_deref()
+            .ok_or_else(|| Error::Crypto("Attachment has no encryption key".into()))?;
+
+        if let Some(transfer_id) = p2p::transfer_id(&attachment.file_id) {
+            return self.save_direct_attachment(attachment, transfer_id, dest, key, decrypt);
+        }
+        if attachment.is_expired() {
+            return Err(Error::AttachmentExpired);
+        }
+
+        let id = &attachment.file_id;
+        let signal = self.transfers.begin(id)?;
+        if let Err(e) = self.storage.set_transfer_paused(id, false) {
+            self.transfers.end(id);
+            return Err(e);
+        }
+        let downloaded = transfer::download(&*self.api, &self.storage, attachment, dest, &self.temp_dir, &signal).await;
+        self.transfers.end(id);
+
+        let part_path = match downloaded {
+            Ok(part_path) => part_path,
+            Err(Error::TransferPaused) => {
+                self.storage.set_transfer_paused(id, true)?;
+                return Err(Error::TransferPaused);
+            }
+            Err(Error::TransferCancelled) => {
+                self.discard_transfer(id)?;
+                return Err(Error::TransferCancelled);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let verified = match attachment.sha256 {
+            Some(ref expected) => transfer::hash_file(&part_path).map(|actual| actual == *expected),
+            None => Ok(true),
+        };
+        let result = match verified {
+            Ok(true) => decrypt(&part_path, key),
+            Ok(false) => Err(Error::Crypto("Downloaded file failed integrity check".into())),
+            Err(e) => Err(e),
+        };
+
+        // Only interrupted downloads resume; a complete one is done either way
+        self.storage.delete_transfer(&attachment.file_id)?;
+        let _ = std::fs::remove_file(&part_path);
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(dest);
+        }
+        result
+    }
+
+    /// Decrypt a file that the sender delivered directly. It is kept, since
+    /// there is no server copy to fetch if it needs to be saved again.
+    fn save_direct_attachment(
+        &self,
+        attachment: &Attachment,
+        transfer_id: &str,
+        dest: &Path,
+        key: &str,
+        decrypt: &mut dyn FnMut(&Path, &str) -> Result<()>,
+    ) -> Result<()> {
+        let path = self.direct.received_path(transfer_id)?;
+        if !path.exists() {
+            return Err(Error::TransferNotFound(transfer_id.to_string()));
+        }
+
+        let result = match attachment.sha256 {
+            Some(ref expected) if transfer::hash_file(&path)? != *expected => {
+                Err(Error::Crypto("Received file failed integrity check".into()))
+            }
+            _ => decrypt(&path, key),
+        };
+        if result.is_err() {
+            let _ = std::fs::remove_file(dest);
+        }
+        result
+    }
+
+    /// Decrypt an attachment for preview or playback. One up to the
+    /// in-memory limit is returned as bytes; a larger one is decrypted to
+    /// a temp file that stays until `close_attachment`, and is shredded
+    /// then or when the client is dropped.
+    pub fn open_attachment(&self, attachment: &Attachment) -> Result<OpenedAttachment> {
+        self.block_on(self.open_attachment_async(attachment))
+    }
+
+    /// `open_attachment`, for async callers
+    pub async fn open_attachment_async(&self, attachment: &Attachment) -> Result<OpenedAttachment> {
+        let path = self.temp_files.create(&attachment.file_name, &attachment.file_id);
+        let in_memory = self
+            .temp_files
+            .memory_limit()
+            .is_some_and(|limit| attachment.file_size as u64 <= limit);
+        if in_memory {
+            let mut data = Vec::new();
+            let result = self
+                .fetch_attachment(attachment, &path, &mut |src, key| {
+                    data = self.decrypt_to_memory(src, key)?;
+                    Ok(())
+                })
+                .await;
+            self.temp_files.close(&path)?;
+            return result.map(|()| OpenedAttachment::Memory(data));
+        }
+
+        match self.download_attachment_to_async(attachment, &path).await {
+            Ok(()) => Ok(OpenedAttachment::File(path)),
+            Err(e) => {
+                self.temp_files.close(&path)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Shred a file returned by `open_attachment`. Returns false if `path`
+    /// isn't an open attachment.
+    pub fn close_attachment(&self, path: &Path) -> Result<bool> {
+        self.temp_files.close(path)
+    }
+
+    /// Shred every open attachment, e.g. when the app is locked or about
+    /// to exit. Returns how many there were.
+    pub fn close_all_attachments(&self) -> usize {
+        self.temp_files.close_all()
+    }
+
+    /// Decrypted attachment files currently on disk
+    pub fn open_attachments(&self) -> Vec<PathBuf> {
+        self.temp_files.open_files()
+    }
+
+    /// Keep attachments up to `max_size` bytes in memory only when opened.
+    /// `None` (the default) always decrypts to a temp file.
+    pub fn set_in_memory_attachment_limit(&self, max_size: Option<u64>) {
+        self.temp_files.set_memory_limit(max_size);
+    }
+
+    /// Unfinished downloads, including paused and interrupted ones
+    pub fn list_transfers(&self) -> Result<Vec<Transfer>> {
+        self.storage.list_transfers()
+    }
+
+    /// Pause a download. A running download stops after the ranges in
+    /// flight and returns `Error::TransferPaused`.
+    pub fn pause_transfer(&self, transfer_id: &str) -> Result<()> {
+        if self.transfers.pause(transfer_id) {
+            return Ok(());
+        }
+        if self.storage.get_transfer(transfer_id)?.is_none() {
+            return Err(Error::TransferNotFound(transfer_id.to_string()));
+        }
+        self.storage.set_transfer_paused(transfer_id, true)
+    }
+
+    /// Continue a paused or interrupted download to its original destination.
+    /// Blocks until the download completes, like `download_attachment_to`.
+    pub fn resume_transfer(&self, transfer_id: &str) -> Result<()> {
+        self.block_on(self.resume_transfer_async(transfer_id))
+    }
+
+    /// `resume_transfer`, for async callers
+    pub async fn resume_transfer_async(&self, transfer_id: &str) -> Result<()> {
+        let transfer = self
+            .storage
+            .get_transfer(transfer_id)?
+            .ok_or_else(|| Error::TransferNotFound(transfer_id.to_string()))?;
+
+        self.download_attachment_to_async(&transfer.attachment, Path::new(&transfer.dest_path))
+            .await
+    }
+
+    /// Stop a download and delete everything it has fetched so far
+    pub fn cancel_transfer(&self, transfer_id: &str) -> Result<()> {
+        // A running download cleans up after itself once it sees the signal
+        if self.transfers.cancel(transfer_id) {
+            return Ok(());
+        }
+        if self.storage.get_transfer(transfer_id)?.is_none() {
+            return Err(Error::TransferNotFound(transfer_id.to_string()));
+        }
+        self.discard_transfer(transfer_id)
+    }
+
+    fn discard_transfer(&self, transfer_id: &str) -> Result<()> {
+        if let Some(transfer) = self.storage.get_transfer(transfer_id)? {
+            let _ = std::fs::remove_file(&transfer.part_path);
+        }
+        self.storage.delete_transfer(transfer_id)
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.temp_dir.join(uuid::Uuid::new_v4().to_string())
+    }
+
+    fn encrypt_to_path(&self, src: &Path, dest: &Path, key: &str) -> Result<()> {
+        let mut input = std::io::BufReader::new(std::fs::File::open(src)?);
+        let output = std::io::BufWriter::new(std::fs::File::create(dest)?);
+
+        let mut writer = self.crypto.encrypting_writer(output, key)?;
+        std::io::copy(&mut input, &mut writer)?;
+        writer.finish()?.flush()?;
+        Ok(())
+    }
+
+    fn decrypt_to_path(&self, src: &Path, dest: &Path, key: &str) -> Result<()> {
+        let input = std::io::BufReader::new(std::fs::File::open(src)?);
+        let mut output = std::io::BufWriter::new(std::fs::File::create(dest)?);
+
+        if let Ok(mut reader) = self.crypto.decrypting_reader(input, key) {
+            if std::io::copy(&mut reader, &mut output).is_ok() {
+                output.flush()?;
+                return Ok(());
+            }
+        }
+
+        // Attachments uploaded before chunked encryption are one AES-GCM message
+        let data = self.crypto.decrypt_file(&std::fs::read(src)?, key)?;
+        let mut output = std::fs::File::create(dest)?;
+        output.write_all(&data)?;
+        Ok(())
+    }
+
+    fn decrypt_to_memory(&self, src: &Path, key: &str) -> Result<Vec<u8>> {
+        let input = std::io::BufReader::new(std::fs::File::open(src)?);
+        if let Ok(mut reader) = self.crypto.decrypting_reader(input, key) {
+            let mut data = Vec::new();
+            if reader.read_to_end(&mut data).is_ok() {
+                return Ok(data);
+            }
+        }
+        self.crypto.decrypt_file(&std::fs::read(src)?, key)
+    }
+
+    /// Send the attachment details to the recipient and store them
+    async fn send_attachment(
+        &self,
+        recipient_id: &str,
+        attachment: Attachment,
+        caption: Option<&str>,
+    ) -> Result<Message> {
+        let (caption, metadata) = match caption {
+            Some(caption) => {
+                let outgoing = self.apply_outgoing_hooks(recipient_id, caption, Metadata::new())?;
+                (Some(outgoing.text), outgoing.metadata)
+            }
+            None => (None, Metadata::new()),
+        };
+
+        let message = Message {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            conversation_id: recipient_id.to_string(),
+            sender_id: self.get_current_user_id()?,
+            message_type: MessageType::from_mime(&attachment.mime_type),
+            content: attachment.file_name.clone(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            status: MessageStatus::Pending,
+            attachment: Some(attachment),
+            caption,
+            is_outgoing: true,
+            metadata,
+            system_event: None,
+            sender_device_id: None,
+            quote: None,
+            is_starred: false,
+            unverified_key: false,
+        };
+        self.transmit(message).await
+    }
+
+    /// Hand an envelope straight to the recipient if they are on the same
+    /// network, otherwise to the server
+    async fn deliver(&self, envelope: &MessageEnvelope) -> Result<()> {
+        self.deliver_via(envelope).await.map(|_| ())
+    }
+
+    /// `deliver`, returning the transport that took the envelope, if any
+    async fn deliver_via(&self, envelope: &MessageEnvelope) -> Result<Option<DeliveryTransport>> {
+        if self.lan.has_peer(&envelope.recipient_id) {
+            match self.lan.send(envelope).await {
+                Ok(()) => return Ok(Some(DeliveryTransport::Lan)),
+                Err(e) => log::warn!(
+                    "Sending to {} through the server instead of the LAN: {}",
+                    envelope.recipient_id,
+                    e
+                ),
+            }
+        }
+
+        let ws = self.ws.read().clone();
+        if let Some(ws) = ws {
+            ws.send_message(envelope).await?;
+            return Ok(Some(DeliveryTransport::WebSocket));
+        }
+        Ok(None)
+    }
+
+    /// `deliver`, retried according to the send retry policy. Each retry
+    /// first reconnects the WebSocket if it dropped.
+    async fn deliver_with_retry(&self, envelope: &MessageEnvelope) -> Result<()> {
+        self.deliver_tracked(envelope).await.0.map(|_| ())
+    }
+
+    /// `deliver_with_retry`, also returning the transport and how many
+    /// attempts it took
+    async fn deliver_tracked(
+        &self,
+        envelope: &MessageEnvelope,
+    ) -> (Result<Option<DeliveryTransport>>, u32) {
+        let policy = self.send_retry.read().clone();
+        let mut attempt = 0;
+        loop {
+            let result = self.deliver_via(envelope).await;
+            let retryable = matches!(
+                result,
+                Err(Error::Network(_) | Error::WebSocket(_) | Error::Io(_))
+            );
+            if !retryable || attempt >= policy.max_retries {
+                return (result, attempt + 1);
+            }
+
+            tokio::time::sleep(policy.backoff(attempt)).await;
+            attempt += 1;
+            if let Err(e) = self.reconnect_if_needed().await {
+                log::warn!("Reconnecting before resend failed: {}", e);
+            }
+        }
+    }
+
+    /// Open a new WebSocket with the stored session if the current one dropped
+    async fn reconnect_if_needed(&self) -> Result<()> {
+        if self.ws.read().as_ref().is_some_and(|ws| ws.is_connected()) {
+            return Ok(());
+        }
+        let session = self.storage.get_session().ok_or(Error::NotLoggedIn)?;
+        let ws: Arc<dyn WsTransport> = Arc::from(self.ws_connector.connect(&session.token).await?);
+        self.on_connected(ws).await;
+        Ok(())
+    }
+
+    /// A WebSocket was opened, at login or after the last one dropped:
+    /// throttles start afresh and whatever was queued while offline goes
+    /// out now rather than at its next scheduled try
+    async fn on_connected(&self, ws: Arc<dyn WsTransport>) {
+        ws.set_power_source(self.power.source());
+        *self.ws.write() = Some(ws);
+        self.activity.reset();
+        if let Err(e) = self.flush_whole_outbox().await {
+            log::warn!("Sending queued messages failed: {}", e);
+        }
+    }
+
+    /// Boxed, as sending a queued message may reconnect and land here again
+    fn flush_whole_outbox(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.flush_outbox(true))
+    }
+
+    /// Deliver messages directly to contacts' devices found on the local
+    /// network over mDNS, and accept messages from them. Both sides
+    /// authenticate with the identity keys they know for each other; the
+    /// server is still used for anyone who can't be reached directly.
+    pub fn enable_lan_delivery(&self) -> Result<()> {
+        self.block_on(self.enable_lan_delivery_async())
+    }
+
+    /// `enable_lan_delivery`, for async callers
+    pub async fn enable_lan_delivery_async(&self) -> Result<()> {
+        self.policy.check(Feature::LanDelivery)?;
+        let user_id = self.get_current_user_id()?;
+        self.lan.start(&user_id).await
+    }
+
+    /// Stop advertising on and delivering over the local network
+    pub fn disable_lan_delivery(&self) {
+        self.lan.stop();
+    }
+
+    /// Contacts' devices currently visible on the local network
+    pub fn lan_peers(&self) -> Vec<LanPeer> {
+        self.lan.peers()
+    }
+
+    /// Change the capacities of the in-memory caches, evicting entries
+    /// that no longer fit
+    pub fn set_cache_limits(&self, limits: CacheLimits) {
+        self.crypto.set_session_capacity(limits.sessions);
+        self.profiles.set_capacity(limits.profiles);
+        self.peer_keys.devices().set_capacity(limits.device_lists);
+        self.lan.set_limits(&limits);
+    }
+
+    /// Sizes and hit counters of the in-memory caches
+    pub fn stats(&self) -> ClientStats {
+        ClientStats {
+            sessions: self.crypto.session_stats(),
+            profiles: self.profiles.stats(),
+            device_lists: self.peer_keys.devices().stats(),
+            lan_peers: self.lan.peer_stats(),
+            lan_inbox: self.lan.inbox_len(),
+        }
+    }
+
+    /// Get conversations list. This loads every conversation; lists that
+    /// can grow large should use `get_conversation_page` instead.
+    pub fn get_conversations(&self) -> Result<Vec<Conversation>> {
+        self.storage.get_conversations()
+    }
+
+    /// Up to `limit` conversations of the list, pinned first and then by
+    /// latest message, starting at `offset`. `limit` is capped at
+    /// [`MAX_CONVERSATION_PAGE`].
+    pub fn get_conversation_page(&self, limit: usize, offset: usize) -> Result<Vec<ConversationSummary>> {
+        self.storage
+            .get_conversation_summaries(limit.min(MAX_CONVERSATION_PAGE) as i64, offset as i64)
+    }
+
+    /// How many conversations there are, for sizing a paged list
+    pub fn conversation_count(&self) -> Result<usize> {
+        self.storage.conversation_count()
+    }
+
+    /// Cursor for `get_conversation_changes`. Take it before loading the
+    /// first page, so nothing written meanwhile is missed.
+    pub fn conversation_cursor(&self) -> Result<i64> {
+        self.storage.conversation_cursor()
+    }
+
+    /// Conversations changed since `cursor`: new messages, unread counts,
+    /// mute, pin and archive. At most [`MAX_CONVERSATION_PAGE`] at a time;
+    /// call again with the returned cursor until none come back.
+    pub fn get_conversation_changes(&self, cursor: i64) -> Result<ConversationChanges> {
+        self.storage
+            .get_conversation_changes(cursor, MAX_CONVERSATION_PAGE as i64)
+    }
+
+    /// Get messages for conversation
+    pub fn get_messages(&self, conversation_id: &str, limit: i64, offset: i64) -> Result<Vec<Message>> {
+        self.storage.get_messages(conversation_id, limit, offset)
+    }
+
+    /// Search stored messages for all words of `query`, newest first. With
+    /// the `hashed-search` feature only whole words match.
+    pub fn search_messages(
+        &self,
+        query: &str,
+        conversation_id: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Message>> {
+        self.storage.search_messages(query, conversation_id, limit)
+    }
+
+    /// A user's profile, refreshed from the server when it is reachable and
+    /// from the local cache otherwise. A changed identity key is noted in
+    /// the conversation with a `SystemEvent::KeyChanged` and has to be
+    /// accepted with `trust_new_key`.
+    pub fn get_user_profile(&self, user_id: &str) -> Result<User> {
+        self.block_on(self.get_user_profile_async(user_id))
+    }
+
+    /// `get_user_profile`, for async callers
+    pub async fn get_user_profile_async(&self, user_id: &str) -> Result<User> {
+        match self.api.get_user(user_id).await {
+            Ok(user) => {
+                self.set_peer_available(user_id, true)?;
+                if let Some(ref public_key) = user.public_key {
+                    self.peer_keys.observe(user_id, public_key)?;
+                }
+                self.storage.save_user(&user)?;
+                Ok(user)
+            }
+            Err(e) => {
+                if matches!(e, Error::RecipientUnavailable) {
+                    self.set_peer_available(user_id, false)?;
+                }
+                match self.storage.get_user(user_id)? {
+                    Some(user) => {
+                        log::debug!("Using cached profile of {}: {}", user_id, e);
+                        Ok(user)
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    /// The peer's identity key conflict waiting for the user, if any
+    pub fn key_conflict(&self, peer_id: &str) -> Result<Option<KeyConflict>> {
+        self.storage.get_key_conflict(peer_id)
+    }
+
+    /// All identity key conflicts waiting for the user, newest first
+    pub fn key_conflicts(&self) -> Result<Vec<KeyConflict>> {
+        self.storage.get_key_conflicts()
+    }
+
+    /// Keys of the peer's devices that their identity key didn't certify,
+    /// waiting for the user. Nothing is sent to or accepted from these
+    /// devices until `trust_new_key`.
+    pub fn pending_devices(&self, peer_id: &str) -> Result<Vec<DeviceKey>> {
+        self.storage.get_pending_devices(peer_id)
+    }
+
+    /// Accept a peer's new identity key and pending device keys, once the
+    /// user has checked them with the peer some other way. Sending to them
+    /// works again; messages received before stay marked `unverified_key`.
+    /// Returns whether there was a conflict to resolve.
+    pub fn trust_new_key(&self, peer_id: &str) -> Result<bool> {
+        self.peer_keys.trust(peer_id)
+    }
+
+    /// Identity keys the peer used before, oldest first, each with the time
+    /// of the conversation's last message under it
+    pub fn archived_keys(&self, peer_id: &str) -> Result<Vec<ArchivedPeerKey>> {
+        self.storage.get_archived_keys(peer_id)
+    }
+
+    /// Fetch the profiles of all conversations' peers now and update the
+    /// names and avatars that changed, each announced with
+    /// [`ClientEvent::ConversationProfileChanged`]. Returns how many
+    /// changed. Profiles are also refreshed in the background, see
+    /// `set_profile_refresh_interval`.
+    pub fn refresh_conversation_profiles(&self) -> Result<usize> {
+        self.block_on(self.refresh_conversation_profiles_async())
+    }
+
+    /// `refresh_conversation_profiles`, for async callers
+    pub async fn refresh_conversation_profiles_async(&self) -> Result<usize> {
+        let peer_ids = self.storage.get_peer_conversation_ids()?;
+        Ok(self.profiles.refresh_all(&peer_ids).await)
+    }
+
+    /// How old a conversation's profile may get before the background job
+    /// fetches it again, six hours by default. `None` turns the job off;
+    /// senders of received messages are still refreshed.
+    pub fn set_profile_refresh_interval(&self, interval: Option<Duration>) {
+        self.profiles.set_interval(interval);
+    }
+
+    /// Set or, with `None`, clear the current user's status
+    pub fn set_status(&self, emoji: Option<&str>, text: Option<&str>) -> Result<User> {
+        self.block_on(self.set_status_async(emoji, text))
+    }
+
+    /// `set_status`, for async callers
+    pub async fn set_status_async(&self, emoji: Option<&str>, text: Option<&str>) -> Result<User> {
+        self.update_profile_async(ProfileUpdate {
+            status_emoji: Some(emoji.unwrap_or_default().to_string()),
+            status_text: Some(text.unwrap_or_default().to_string()),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Set the current user's bio; an empty string clears it
+    pub fn set_bio(&self, bio: &str) -> Result<User> {
+        self.block_on(self.set_bio_async(bio))
+    }
+
+    /// `set_bio`, for async callers
+    pub async fn set_bio_async(&self, bio: &str) -> Result<User> {
+        self.update_profile_async(ProfileUpdate {
+            bio: Some(bio.to_string()),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Choose who may see when the current user was last online
+    pub fn set_last_seen_visibility(&self, visibility: LastSeenVisibility) -> Result<User> {
+        self.block_on(self.set_last_seen_visibility_async(visibility))
+    }
+
+    /// `set_last_seen_visibility`, for async callers
+    pub async fn set_last_seen_visibility_async(&self, visibility: LastSeenVisibility) -> Result<User> {
+        self.update_profile_async(ProfileUpdate {
+            last_seen_visibility: Some(visibility),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Change the current user's profile and cache the result
+    pub fn update_profile(&self, update: ProfileUpdate) -> Result<User> {
+        self.block_on(self.update_profile_async(update))
+    }
+
+    /// `update_profile`, for async callers
+    pub async fn update_profile_async(&self, update: ProfileUpdate) -> Result<User> {
+        if update.last_seen_visibility.is_some() {
+            self.policy.check_unlocked(PolicySetting::LastSeenVisibility)?;
+        }
+        let user = self.api.update_profile(&update).await?;
+        self.storage.save_user(&user)?;
+        Ok(user)
+    }
+
+    /// Mark a conversation read up to its latest message and tell the
+    /// user's other devices, so their unread counts clear too
+    pub fn mark_conversation_read(&self, conversation_id: &str) -> Result<()> {
+        self.block_on(self.mark_conversation_read_async(conversation_id))
+    }
+
+    /// `mark_conversation_read`, for async callers
+    pub async fn mark_conversation_read_async(&self, conversation_id: &str) -> Result<()> {
+        let conversation = match self.storage.get_conversation(conversation_id)? {
+            Some(conversation) => conversation,
+            None => return Ok(()),
+        };
+        let position = ReadPosition {
+            conversation_id: conversation_id.to_string(),
+            last_read_timestamp: conversation.last_message_time.unwrap_or(0),
+        };
+        if !self
+            .storage
+            .mark_read_up_to(&position.conversation_id, position.last_read_timestamp)?
+        {
+            return Ok(());
+        }
+        self.sync_read_position(&position).await
+    }
+
+    /// Mark several conversations read in one go, e.g. "mark all read".
+    /// Local storage is updated in a single transaction before the read
+    /// positions are synced to the user's other devices.
+    pub fn mark_conversations_read(&self, conversation_ids: &[String]) -> Result<()> {
+        self.block_on(self.mark_conversations_read_async(conversation_ids))
+    }
+
+    /// `mark_conversations_read`, for async callers
+    pub async fn mark_conversations_read_async(&self, conversation_ids: &[String]) -> Result<()> {
+        for position in self.storage.mark_conversations_read(conversation_ids)? {
+            self.sync_read_position(&position).await?;
+        }
+        Ok(())
+    }
+
+    /// Save the unsent text of a conversation and sync it to the user's
+    /// other devices, where the latest edit wins. Call on every edit;
+    /// syncs are throttled to one per [`DRAFT_SYNC_INTERVAL`]. Empty text
+    /// clears the draft. Sending a text message clears it too.
+    pub fn set_draft(&self, conversation_id: &str, text: &str) -> Result<()> {
+        self.block_on(self.set_draft_async(conversation_id, text))
+    }
+
+    /// `set_draft`, for async callers
+    pub async fn set_draft_async(&self, conversation_id: &str, text: &str) -> Result<()> {
+        let draft = Draft {
+            conversation_id: conversation_id.to_string(),
+            text: text.to_string(),
+            updated_at: self.next_draft_time(conversation_id)?,
+        };
+        if !self.storage.save_draft(&draft)? {
+            return Ok(());
+        }
+        if self.drafts.try_send(conversation_id, std::time::Instant::now()) {
+            self.send_to_own_devices(DRAFT_SYNC_MESSAGE_TYPE, &draft).await?;
+        }
+        Ok(())
+    }
+
+    /// The conversation's draft, if it has any text
+    pub fn draft(&self, conversation_id: &str) -> Result<Option<Draft>> {
+        Ok(self
+            .storage
+            .get_draft(conversation_id)?
+            .filter(|draft| !draft.text.is_empty()))
+    }
+
+    /// Conversations' drafts, most recently edited first
+    pub fn drafts(&self) -> Result<Vec<Draft>> {
+        self.storage.get_drafts()
+    }
+
+    /// Sync drafts held by the throttle now, e.g. when the user leaves a
+    /// conversation or the app goes to the background
+    pub fn flush_drafts(&self) -> Result<()> {
+        self.block_on(self.flush_drafts_async())
+    }
+
+    /// `flush_drafts`, for async callers
+    pub async fn flush_drafts_async(&self) -> Result<()> {
+        self.send_held_drafts(true).await
+    }
+
+    /// Drafts held since their last sync, once their window has ended or
+    /// with `all` right away
+    async fn send_held_drafts(&self, all: bool) -> Result<()> {
+        for conversation_id in self.drafts.due(std::time::Instant::now(), all) {
+            if let Some(draft) = self.storage.get_draft(&conversation_id)? {
+                self.send_to_own_devices(DRAFT_SYNC_MESSAGE_TYPE, &draft).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Now, or just after the stored draft, so an edit here always replaces
+    /// it, even within the same millisecond or after the clock went back
+    fn next_draft_time(&self, conversation_id: &str) -> Result<i64> {
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(match self.storage.get_draft(conversation_id)? {
+            Some(stored) => now.max(stored.updated_at + 1),
+            None => now,
+        })
+    }
+
+    /// The draft became a message; clear it here and, without waiting for
+    /// the throttle, on the other devices
+    async fn clear_sent_draft(&self, conversation_id: &str) -> Result<()> {
+        if self.draft(conversation_id)?.is_none() {
+            return Ok(());
+        }
+        let cleared = Draft {
+            conversation_id: conversation_id.to_string(),
+            text: String::new(),
+            updated_at: self.next_draft_time(conversation_id)?,
+        };
+        if self.storage.save_draft(&cleared)? {
+            self.drafts.forget(conversation_id);
+            self.send_to_own_devices(DRAFT_SYNC_MESSAGE_TYPE, &cleared).await?;
+        }
+        Ok(())
+    }
+
+    /// Delete messages from this device in one transaction; returns how
+    /// many were stored
+    pub fn delete_messages(&self, message_ids: &[String]) -> Result<usize> {
+        self.storage.delete_messages(message_ids)
+    }
+
+    /// Delete a message from this device. With `for_everyone`, only for
+    /// messages sent from this account, the other side of the conversation
+    /// and the user's other devices delete it too, and the server drops any
+    /// copy not received yet.
+    pub fn delete_message(&self, message_id: &str, for_everyone: bool) -> Result<()> {
+        self.block_on(self.delete_message_async(message_id, for_everyone))
+    }
+
+    /// `delete_message`, for async callers
+    pub async fn delete_message_async(&self, message_id: &str, for_everyone: bool) -> Result<()> {
+        let message = self
+            .storage
+            .get_message(message_id)?
+            .ok_or_else(|| Error::Storage(format!("Message not found: {}", message_id)))?;
+        if for_everyone {
+            if !message.is_outgoing {
+                return Err(Error::Storage(format!("Message {} was sent by someone else", message_id)));
+            }
+            if let Err(e) = self.api.retract_message(message_id).await {
+                log::warn!("Server copies of {} not removed: {}", message_id, e);
+            }
+
+            let deletion = MessageDeletion {
+                message_id: message_id.to_string(),
+            };
+            self.send_to_conversation(&message.conversation_id, DELETION_MESSAGE_TYPE, &deletion)
+                .await?;
+        }
+        self.storage.delete_message(message_id)
+    }
+
+    /// Make new messages in a conversation disappear `ttl` after they are
+    /// sent, on every device of both sides; `None` keeps them. Messages
+    /// already there keep the lifetime they had.
+    pub fn set_conversation_ttl(&self, conversation_id: &str, ttl: Option<Duration>) -> Result<()> {
+        self.block_on(self.set_conversation_ttl_async(conversation_id, ttl))
+    }
+
+    /// `set_conversation_ttl`, for async callers
+    pub async fn set_conversation_ttl_async(&self, conversation_id: &str, ttl: Option<Duration>) -> Result<()> {
+        let ttl_seconds = ttl.map(|ttl| ttl.as_secs().max(1));
+        if self.storage.get_conversation_ttl(conversation_id)? == ttl_seconds {
+            return Ok(());
+        }
+        let update = ConversationTtl {
+            conversation_id: conversation_id.to_string(),
+            ttl_seconds,
+        };
+        // Stored once sent, so a failed send can simply be tried again
+        self.send_to_conversation(conversation_id, CONVERSATION_TTL_MESSAGE_TYPE, &update)
+            .await?;
+        self.storage.set_conversation_ttl(conversation_id, ttl_seconds)?;
+        Ok(())
+    }
+
+    /// How long new messages in a conversation are kept; `None` keeps them
+    pub fn get_conversation_ttl(&self, conversation_id: &str) -> Result<Option<Duration>> {
+        Ok(self
+            .storage
+            .get_conversation_ttl(conversation_id)?
+            .map(Duration::from_secs))
+    }
+
+    /// Delete messages whose lifetime has ended now rather than at the
+    /// background job's next pass, e.g. when the app comes to the
+    /// foreground. Returns how many there were.
+    pub fn delete_expired_messages(&self) -> Result<usize> {
+        self.expiry.expire(chrono::Utc::now().timestamp_millis())
+    }
+
+    /// Send a control payload to the other side of a conversation, every
+    /// member of a group, and the user's other devices. A group member who
+    /// can't be reached is left out.
+    async fn send_to_conversation(
+        &self,
+        conversation_id: &str,
+        message_type: &str,
+        payload: &(impl serde::Serialize + Sync),
+    ) -> Result<()> {
+        match self.group_recipients(conversation_id).await? {
+            Some(members) => {
+                for member_id in members {
+                    if let Err(e) = self.send_to_peer(&member_id, message_type, payload).await {
+                        log::warn!("Leaving {} out of {} in {}: {}", member_id, message_type, conversation_id, e);
+                    }
+                }
+            }
+            None => self.send_to_peer(conversation_id, message_type, payload).await?,
+        }
+        self.send_to_own_devices(message_type, payload).await
+    }
+
+    /// Star or unstar messages in one transaction, a mark kept on this
+    /// device to find them again; returns how many were stored
+    pub fn set_messages_starred(&self, message_ids: &[String], starred: bool) -> Result<usize> {
+        self.storage.set_messages_starred(message_ids, starred)
+    }
+
+    /// Starred messages, newest first, in one conversation or all
+    pub fn starred_messages(&self, conversation_id: Option<&str>) -> Result<Vec<Message>> {
+        self.storage.get_starred_messages(conversation_id)
+    }
+
+    /// Delete conversations and their messages from this device
+    pub fn delete_conversations(&self, conversation_ids: &[String]) -> Result<()> {
+        self.storage.delete_conversations(conversation_ids)
+    }
+
+    /// Mute or unmute conversations; muted ones don't show notifications
+    pub fn set_conversations_muted(&self, conversation_ids: &[String], muted: bool) -> Result<()> {
+        self.storage.set_conversations_muted(conversation_ids, muted)
+    }
+
+    /// Move conversations out of the main list or back
+    pub fn set_conversations_archived(&self, conversation_ids: &[String], archived: bool) -> Result<()> {
+        self.storage.set_conversations_archived(conversation_ids, archived)
+    }
+
+    /// Rules for incoming messages, in the order they apply
+    pub fn message_rules(&self) -> Result<Vec<MessageRule>> {
+        self.storage.get_message_rules()
+    }
+
+    /// Add a rule, or replace the one with the same id. Rules act on each
+    /// incoming message once it is stored.
+    pub fn save_message_rule(&self, rule: &MessageRule) -> Result<()> {
+        if !rule.has_conditions() {
+            return Err(Error::InvalidConfig(format!(
+                "Rule {} needs a sender, keyword or message type",
+                rule.name
+            )));
+        }
+        self.storage.save_message_rule(rule)?;
+        self.rules.reload()
+    }
+
+    pub fn delete_message_rule(&self, id: &str) -> Result<()> {
+        self.storage.delete_message_rule(id)?;
+        self.rules.reload()
+    }
+
+    /// Write conversations with all their messages to `dest` as JSON, an
+    /// array of [`ConversationExport`]. Attachments are referenced, not
+    /// included.
+    pub fn export_conversations(&self, conversation_ids: &[String], dest: &Path) -> Result<()> {
+        let exports = self.storage.export_conversations(conversation_ids)?;
+        let file = std::io::BufWriter::new(std::fs::File::create(dest)?);
+        serde_json::to_writer_pretty(file, &exports)?;
+        Ok(())
+    }
+
+    /// Write conversations to a new archive directory at `dest`, in the
+    /// format described by [`ArchiveManifest`], with the files of
+    /// downloaded attachments. All conversations when `conversation_ids`
+    /// is `None`.
+    pub fn export_archive(&self, conversation_ids: Option<&[String]>, dest: &Path) -> Result<ArchiveManifest> {
+        let ids = match conversation_ids {
+            Some(ids) => ids.to_vec(),
+            None => self.storage.get_conversation_ids()?,
+        };
+        archive::export(&self.storage, self.get_current_user_id().ok(), &ids, dest)
+    }
+
+    /// Restore the conversations of an archive written by
+    /// `export_archive`, on this or another device. Attachment files are
+    /// copied into `attachments_dir`. Archived messages replace stored
+    /// ones with the same id.
+    pub fn import_archive(&self, src: &Path, attachments_dir: &Path) -> Result<ArchiveManifest> {
+        archive::import(&self.storage, src, attachments_dir)
+    }
+
+    /// Write everything this device knows about the user to `dest` as JSON,
+    /// a [`DataExport`]: the profile, signed-in devices, and every
+    /// conversation with its messages in plain text. `progress` gets the
+    /// number of conversations written and the total. A cancelled or
+    /// failed export leaves no file behind.
+    pub fn export_my_data(&self, dest: &Path, mut progress: impl FnMut(usize, usize)) -> Result<()> {
+        let signal = self.transfers.begin(DATA_EXPORT_ID)?;
+        let result = self
+            .block_on(self.export_profile())
+            .and_then(|(user, devices)| self.write_data_export(user, devices, dest, &signal, &mut progress));
+        self.transfers.end(DATA_EXPORT_ID);
+        result
+    }
+
+    /// `export_my_data`, for async callers
+    pub async fn export_my_data_async(&self, dest: &Path, mut progress: impl FnMut(usize, usize)) -> Result<()> {
+        let signal = self.transfers.begin(DATA_EXPORT_ID)?;
+        let result = match self.export_profile().await {
+            Ok((user, devices)) => self.write_data_export(user, devices, dest, &signal, &mut progress),
+            Err(e) => Err(e),
+        };
+        self.transfers.end(DATA_EXPORT_ID);
+        result
+    }
+
+    /// Stop a running `export_my_data`, which then returns
+    /// `Error::TransferCancelled`. Returns false if none is running.
+    pub fn cancel_data_export(&self) -> bool {
+        self.transfers.cancel(DATA_EXPORT_ID)
+    }
+
+    /// The profile and devices a data export starts with
+    async fn export_profile(&self) -> Result<(User, Vec<Device>)> {
+        let user = self.get_user_profile_async(&self.get_current_user_id()?).await?;
+        let devices = self.list_devices_async().await?;
+        Ok((user, devices))
+    }
+
+    fn write_data_export(
+        &self,
+        user: User,
+        devices: Vec<Device>,
+        dest: &Path,
+        signal: &transfer::TransferSignal,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        let ids = self.storage.get_conversation_ids()?;
+        let mut conversations = Vec::with_capacity(ids.len());
+        progress(0, ids.len());
+        for (done, id) in ids.iter().enumerate() {
+            signal.check()?;
+            conversations.extend(self.storage.export_conversations(std::slice::from_ref(id))?);
+            progress(done + 1, ids.len());
+        }
+        signal.check()?;
+
+        let export = DataExport {
+            exported_at: chrono::Utc::now().timestamp_millis(),
+            user,
+            devices,
+            conversations,
+        };
+        // Renamed into place once complete, so `dest` is never half written
+        let part = dest.with_extension("part");
+        let written = (|| -> Result<()> {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&part)?);
+            serde_json::to_writer_pretty(&mut file, &export)?;
+            file.flush()?;
+            std::fs::rename(&part, dest)?;
+            Ok(())
+        })();
+        if written.is_err() {
+            let _ = std::fs::remove_file(&part);
+        }
+        written
+    }
+
+    /// Turn per-conversation statistics on or off. They are computed on
+    /// this device from stored message times and are off by default.
+    pub fn set_local_analytics(&self, enabled: bool) -> Result<()> {
+        self.storage
+            .save_setting(LOCAL_ANALYTICS_SETTING, if enabled { "1" } else { "0" })
+    }
+
+    pub fn local_analytics_enabled(&self) -> bool {
+        self.storage.get_setting(LOCAL_ANALYTICS_SETTING).as_deref() == Some("1")
+    }
+
+    /// Message counts, activity by day and hour, and median response times
+    /// for a conversation, in the local time zone. Requires
+    /// [`set_local_analytics`](Self::set_local_analytics).
+    pub fn conversation_stats(&self, conversation_id: &str) -> Result<ConversationStats> {
+        if !self.local_analytics_enabled() {
+            return Err(Error::InvalidConfig("Local analytics are turned off".into()));
+        }
+        let samples = self.storage.message_samples(conversation_id)?;
+        let offset = chrono::Local::now().offset().local_minus_utc();
+        Ok(ConversationStats::compute(&samples, offset as i64))
+    }
+
+    /// Send a read position to the user's other devices, or hold it until
+    /// the device is back on mains power
+    async fn sync_read_position(&self, position: &ReadPosition) -> Result<()> {
+        if self.power.on_battery() {
+            self.power.defer_read(position);
+            return Ok(());
+        }
+        self.send_to_own_devices(READ_SYNC_MESSAGE_TYPE, position).await
+    }
+
+    /// Encrypt `payload` to the user's own key and send it to their other
+    /// devices
+    async fn send_to_own_devices(
+        &self,
+        message_type: &str,
+        payload: &(impl serde::Serialize + Sync),
+    ) -> Result<()> {
+        let user_id = self.get_current_user_id()?;
+        self.ensure_own_session(&user_id)?;
+        let encrypted = self
+            .crypto
+            .encrypt_for(&user_id, &serde_json::to_string(payload)?)?;
+
+        let envelope = MessageEnvelope {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            sender_id: user_id.clone(),
+            recipient_id: user_id,
+            recipient_device_id: None,
+            encrypted_content: encrypted,
+            message_type: message_type.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            ttl_seconds: None,
+            priority: MessagePriority::for_message_type(message_type),
+            delivery_mode: DeliveryMode::StoreAndForward,
+        };
+        self.deliver(&envelope).await
+    }
+
+    /// Syncs between the user's own devices are encrypted to their own key
+    fn ensure_own_session(&self, user_id: &str) -> Result<()> {
+        if !self.crypto.has_session(user_id) {
+            let public_key = self.crypto.get_public_key()?;
+            self.crypto.establish_session(user_id, &public_key)?;
+        }
+        Ok(())
+    }
+
+    /// Apply read positions from the user's other devices and ack them
+    async fn apply_read_syncs(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        self.receive_from_own_devices(envelopes, |position: ReadPosition| {
+            if self
+                .storage
+                .mark_read_up_to(&position.conversation_id, position.last_read_timestamp)?
+            {
+                self.event_sender.send(ClientEvent::ConversationRead(position));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Apply drafts from the user's other devices that are newer than the
+    /// ones stored, and ack them
+    async fn apply_draft_syncs(&self, envelopes: Vec<MessageEnvelope>) -> Result<()> {
+        self.receive_from_own_devices(envelopes, |draft: Draft| {
+            if self.storage.save_draft(&draft)? {
+                // A newer edit elsewhere replaces one held here
+                self.drafts.forget(&draft.conversation_id);
+                self.event_sender.send(ClientEvent::DraftChanged(draft));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Decrypt payloads the user's other devices sent with
+    /// `send_to_own_devices`, `apply` each and ack them
+    async fn receive_from_own_devices<T: serde::de::DeserializeOwned>(
+        &self,
+        envelopes: Vec<MessageEnvelope>,
+        mut apply: impl FnMut(T) -> Result<()> + Send,
+    ) -> Result<()> {
+        if envelopes.is_empty() {
+            return Ok(());
+        }
+        let user_id = self.get_current_user_id()?;
+        self.ensure_own_session(&user_id)?;
+
+        let mut ids = Vec::new();
+        for envelope in envelopes {
+            if envelope.sender_id != user_id {
+                log::warn!("Ignoring {} from {}", envelope.message_type, envelope.sender_id);
+                continue;
+            }
+            let payload = self
+                .crypto
+                .decrypt_from(&user_id, &envelope.encrypted_content)
+                .and_then(|json| Ok(serde_json::from_str::<T>(&json)?));
+            match payload {
+                Ok(payload) => {
+                    apply(payload)?;
+                    ids.push(envelope.message_id);
+                }
+                Err(e) => log::warn!(
+                    "Skipping unreadable {} {}: {}",
+                    envelope.message_type,
+                    envelope.message_id,
+                    e
+                ),
+            }
+        }
+
+        self.ack(&ids).await
+    }
+
+    /// Get current user ID
+    pub fn get_current_user_id(&self) -> Result<String> {
+        self.storage.get_setting("current_user_id")
+            .ok_or_else(|| Error::NotLoggedIn)
+    }
+
+    /// The server's id for this device, which outgoing messages carry as
+    /// their `sender_device_id`
+    pub fn current_device_id(&self) -> Result<String> {
+        self.storage.get_setting("device_id").ok_or(Error::NotLoggedIn)
+    }
+
+    /// The user's signed-in devices, this one included
+    pub fn list_devices(&self) -> Result<Vec<Device>> {
+        self.block_on(self.list_devices_async())
+    }
+
+    /// `list_devices`, for async callers
+    pub async fn list_devices_async(&self) -> Result<Vec<Device>> {
+        self.api.list_devices().await
+    }
+
+    /// Give one of the user's devices a new display name. The server tells
+    /// the other devices, which see a [`ClientEvent::DeviceRenamed`].
+    pub fn rename_device(&self, device_id: &str, device_name: &str) -> Result<()> {
+        self.block_on(self.rename_device_async(device_id, device_name))
+    }
+
+    /// `rename_device`, for async callers
+    pub async fn rename_device_async(&self, device_id: &str, device_name: &str) -> Result<()> {
+        let device_name = device_name.trim();
+        if device_name.is_empty() || device_name.chars().count() > MAX_DEVICE_NAME_LEN {
+            return Err(Error::InvalidConfig(format!(
+                "Device names are 1 to {} characters",
+                MAX_DEVICE_NAME_LEN
+            )));
+        }
+        self.api.rename_device(device_id, device_name).await
+    }
+
+    /// Certify the key of one of the user's other devices, so contacts
+    /// send to it and accept messages from it. Only the device holding the
+    /// user's published identity key can; contacts treat an uncertified
+    /// device like a changed key.
+    pub fn certify_device(&self, device_id: &str) -> Result<()> {
+        self.block_on(self.certify_device_async(device_id))
+    }
+
+    /// `certify_device`, for async callers
+    pub async fn certify_device_async(&self, device_id: &str) -> Result<()> {
+        let user_id = self.get_current_user_id()?;
+        let published = self.api.get_user(&user_id).await?.public_key;
+        if published.as_deref() != Some(self.crypto.get_public_key()?.as_str()) {
+            return Err(Error::InvalidConfig(
+                "Only the device with the published identity key certifies devices".into(),
+            ));
+        }
+        let key = self
+            .api
+            .get_device_keys(&user_id)
+            .await?
+            .into_iter()
+            .find(|key| key.device_id == device_id)
+            .ok_or_else(|| Error::InvalidConfig(format!("No device {}", device_id)))?;
+
+        let data = devices::certificate_data(&user_id, &key.device_id, &key.public_key);
+        let signature = self.crypto.sign(&data)?;
+        self.api.certify_device(device_id, &signature).await
+    }
+
+    /// Check the server, WebSocket, clock skew, TURN reachability, local
+    /// database and identity key. The report holds no secrets, so users can
+    /// share it when filing issues.
+    pub fn run_diagnostics(&self) -> DiagnosticsReport {
+        self.block_on(self.run_diagnostics_async())
+    }
+
+    /// `run_diagnostics`, for async callers
+    pub async fn run_diagnostics_async(&self) -> DiagnosticsReport {
+        diagnostics::run(diagnostics::Targets {
+            api: self.api.as_ref(),
+            connector: self.ws_connector.as_ref(),
+            ws: &self.ws,
+            storage: &self.storage,
+            crypto: &self.crypto,
+        })
+        .await
+    }
+
+    /// Export private key for backup
+    pub fn export_private_key(&self) -> Result<String> {
+        self.crypto.export_identity()
+    }
+
+    /// Logout
+    pub fn logout(&self) -> Result<()> {
+        self.block_on(self.logout_async())
+    }
+
+    /// `logout`, for async callers
+    pub async fn logout_async(&self) -> Result<()> {
+        self.lan.stop();
+        let ws = self.ws.write().take();
+        if let Some(ws) = ws {
+            ws.disconnect().await?;
+        }
+        self.storage.clear_session()?;
+        Ok(())
+    }
+
+    /// Panic button: end every session on the server, then shred open
+    /// attachments, wipe local storage and forget the keys. The identity
+    /// key export is kept only if `keep_identity_key` is set, for signing
+    /// back in later. The local wipe goes ahead even if the server can't be
+    /// reached; the application should exit afterwards.
+    pub fn wipe_and_logout(&self, keep_identity_key: bool) -> Result<WipeOutcome> {
+        self.block_on(self.wipe_and_logout_async(keep_identity_key))
+    }
+
+    /// `wipe_and_logout`, for async callers
+    pub async fn wipe_and_logout_async(&self, keep_identity_key: bool) -> Result<WipeOutcome> {
+        let identity_key = if keep_identity_key {
+            Some(self.crypto.export_identity()?)
+        } else {
+            None
+        };
+
+        let sessions_invalidated = match self.api.logout_all().await {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Ending sessions on the server failed: {}", e);
+                false
+            }
+        };
+
+        self.lan.stop();
+        let ws = self.ws.write().take();
+        if let Some(ws) = ws {
+            if let Err(e) = ws.disconnect().await {
+                log::debug!("Disconnect during wipe failed: {}", e);
+            }
+        }
+        self.temp_files.close_all();
+        self.storage.wipe()?;
+        self.crypto.clear();
+
+        Ok(WipeOutcome {
+            identity_key,
+            sessions_invalidated,
+        })
+    }
+
+    /// Drop the session the server revoked and tell the application
+    fn end_revoked_session(&self, reason: RevocationReason) -> Result<()> {
+        self.lan.stop();
+        self.ws.write().take();
+        self.storage.clear_session()?;
+        self.event_sender.send(ClientEvent::SessionRevoked(reason));
+        Ok(())
+    }
+
+    /// Poll for new messages (call periodically)
+    pub fn poll_messages(&self) -> Result<Vec<Message>> {
+        self.block_on(self.poll_messages_async())
+    }
+
+    /// `poll_messages`, for async callers
+    pub async fn poll_messages_async(&self) -> Result<Vec<Message>> {
+        let envelopes = self.receive_envelopes().await?;
+        if envelopes.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut messages = Vec::with_capacity(envelopes.len());
+        let mut ids = Vec::with_capacity(envelopes.len());
+        for envelope in envelopes {
+            let envelope_id = envelope.message_id.clone();
+            if let Ok(message) = self.process_incoming_message(envelope).await {
+                ids.push(envelope_id);
+                messages.extend(message);
+            }
+        }
+
+        // Store the whole batch in one transaction, then ack it in one frame
+        let deleted = self.storage.save_messages(&messages)?;
+        messages.retain(|message| !deleted.contains(&message.message_id));
+        for message in &messages {
+            let event = ClientEvent::MessageReceived(Box::new(message.clone()));
+            self.notifier.announce(&event);
+            self.event_sender.notify_subscribers(&event);
+        }
+        self.ack(&ids).await?;
+        let senders: Vec<&str> = messages.iter().map(|m| m.sender_id.as_str()).collect();
+        // Someone writing again was reactivated
+        for sender in &senders {
+            self.set_peer_available(sender, true)?;
+        }
+        self.profiles.on_received(self.runtime().handle(), &senders);
+        Ok(messages)
+    }
+
+    /// A message the server refused because its recipient is gone fails
+    /// and makes the conversation read-only; other errors are passed on
+    fn apply_server_error(&self, error: ServerError) -> Result<()> {
+        let rejected = match error.message_id {
+            Some(ref id) if error.code == ErrorCode::RecipientUnavailable => self.storage.get_message(id)?,
+            _ => None,
+        };
+        match rejected {
+            Some(message) => {
+                self.storage
+                    .update_message_status(&message.message_id, MessageStatus::Failed)?;
+                self.storage
+                    .set_pending_reason(&message.message_id, PendingReason::Failed)?;
+                self.set_peer_available(&message.conversation_id, false)
+            }
+            None => {
+                self.event_sender.send(ClientEvent::ServerError(error));
+                Ok(())
+            }
+        }
+    }
+
+    /// Envelopes from peers on the LAN and from the server. Read syncs from
+    /// the user's other devices, file expiry changes, re-upload requests,
+    /// re-uploaded attachments, deletions and message lifetimes are applied,
+    /// ephemeral payloads passed on as events and custom messages dispatched
+    /// here rather than returned.
+    /// Messages waiting in the outbox are tried again once they are due.
+    async fn receive_envelopes(&self) -> Result<Vec<MessageEnvelope>> {
+        let mut envelopes = self.lan.receive_messages();
+        let ws = self.ws.read().clone();
+        if let Some(ws) = ws {
+            if let Some(reason) = ws.revocation() {
+                self.end_revoked_session(reason)?;
+                return Err(Error::SessionRevoked(reason));
+            }
+            // Calls ring before anything in the backlog is processed
+            self.direct.dispatch_signals(&ws).await;
+            envelopes.extend(ws.receive_messages().await?);
+            for error in ws.receive_errors().await? {
+                self.apply_server_error(error)?;
+            }
+            for rename in ws.receive_device_renames().await? {
+                self.event_sender.send(ClientEvent::DeviceRenamed(rename));
+            }
+            self.apply_announcements(ws.receive_announcements().await?);
+            self.apply_maintenance(ws.receive_maintenance().await?);
+            let acks: Vec<_> = ws
+                .receive_acks()
+                .await?
+                .into_iter()
+                .map(|(id, at)| (message_id_of_copy(&id).to_string(), at))
+                .collect();
+            self.storage.set_acked(&acks)?;
+            self.apply_deliveries(ws.receive_deliveries().await?)?;
+        }
+        self.flush_outbox(false).await?;
+
+        let (syncs, envelopes) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == READ_SYNC_MESSAGE_TYPE);
+        self.apply_read_syncs(syncs).await?;
+
+        let (drafts, envelopes) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == DRAFT_SYNC_MESSAGE_TYPE);
+        self.apply_draft_syncs(drafts).await?;
+        self.send_held_drafts(false).await?;
+
+        let (expiries, envelopes) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == FILE_EXPIRY_MESSAGE_TYPE);
+        self.apply_file_expiries(expiries).await?;
+
+        let (reuploads, envelopes) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == REUPLOAD_REQUEST_MESSAGE_TYPE);
+        self.apply_reupload_requests(reuploads).await?;
+
+        let (updates, envelopes) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == ATTACHMENT_UPDATE_MESSAGE_TYPE);
+        self.apply_attachment_updates(updates).await?;
+
+        let (deletions, envelopes) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == DELETION_MESSAGE_TYPE);
+        self.apply_deletions(deletions).await?;
+
+        let (ttls, envelopes) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == CONVERSATION_TTL_MESSAGE_TYPE);
+        self.apply_conversation_ttls(ttls).await?;
+
+        let (ephemeral, envelopes): (Vec<_>, _) = envelopes
+            .into_iter()
+            .partition(|envelope| envelope.message_type == EPHEMERAL_MESSAGE_TYPE);
+        for envelope in ephemeral {
+            let payload = decrypt::decrypt_content(&self.peer_keys, self.api.as_ref(), &envelope)
+                .await
+                .and_then(|json| Ok(serde_json::from_str::<EphemeralPayload>(&json)?));
+            match payload {
+                Ok(payload) => self.event_sender.send(ClientEvent::Ephemeral {
+                    sender_id: envelope.sender_id,
+                    payload,
+                }),
+                Err(e) => log::debug!("Dropping unreadable ephemeral {}: {}", envelope.message_id, e),
+            }
+        }
+
+        let (custom, envelopes): (Vec<_>, _) = envelopes
+            .into_iter()
+            .partition(|envelope| is_custom_message_type(&envelope.message_type));
+        self.dispatch_custom(custom).await?;
+        Ok(envelopes)
+    }
+
+    /// Fetch new envelopes and decrypt them on the background pool
+    ///
+    /// Returns the number of envelopes queued without waiting for them.
+    /// Results arrive through `poll_events` / `next_event`, in order within
+    /// each conversation.
+    pub fn receive_in_background(&self) -> Result<usize> {
+        self.block_on(self.receive_in_background_async())
+    }
+
+    /// `receive_in_background`, for async callers
+    pub async fn receive_in_background_async(&self) -> Result<usize> {
+        let envelopes = self.receive_envelopes().await?;
+        let count = envelopes.len();
+        self.decrypt_pool.submit(envelopes);
+        Ok(count)
+    }
+
+    /// Show notifications for incoming messages and calls through `sink`,
+    /// or stop showing them with `None`. Muted conversations never notify.
+    pub fn set_notification_sink(&self, sink: Option<Arc<dyn NotificationSink>>) {
+        self.notifier.set_sink(sink);
+    }
+
+    /// How much notifications reveal about a message
+    pub fn preview_policy(&self) -> PreviewPolicy {
+        self.notifier.policy()
+    }
+
+    /// Ignored while the client policy locks notification previews
+    pub fn set_preview_policy(&self, policy: PreviewPolicy) {
+        if self.policy.is_locked(PolicySetting::NotificationPreview) {
+            log::warn!("Notification previews are set by the client policy");
+            return;
+        }
+        self.notifier.set_policy(policy);
+    }
+
+    /// Events that are ready, without blocking
+    pub fn poll_events(&self) -> Vec<ClientEvent> {
+        self.events.drain()
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub fn next_event(&self, timeout: Duration) -> Option<ClientEvent> {
+        self.events.next(timeout)
+    }
+
+    async fn process_incoming_message(&self, envelope: MessageEnvelope) -> Result<Option<Message>> {
+        decrypt::open_envelope(&self.peer_keys, self.api.as_ref(), &self.storage, envelope).await
+    }
+
+    /// Ack envelopes on the current WebSocket, if there is one
+    async fn ack(&self, ids: &[String]) -> Result<()> {
+        let ws = self.ws.read().clone();
+        if let Some(ws) = ws {
+            ws.send_ack(ids).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PrivMsgClient {
+    /// Tokio doesn't allow blocking in async code, so a client dropped
+    /// there leaves its runtime to shut down in the background
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            if tokio::runtime::Handle::try_current().is_ok() {
+                runtime.shutdown_background();
+            }
+        }
     }
 }
 
@@ -0,0 +1,486 @@
+//! One-time import of the desktop app's own database
+//!
+//! Before it used `LocalStorage`, the desktop app kept its data in a schema
+//! of its own, in the same `privmsg.db` file name. A legacy file is renamed
+//! out of the way, a fresh core database is created in its place and the
+//! old data is copied over. The renamed file is kept as an archive. If the
+//! import fails, the partial new database is removed and the legacy file is
+//! put back, so the next start tries again.
+//!
+//! Call [`migrate_legacy_database`] before opening the client on a data
+//! directory that may hold a legacy database.
+
+use crate::error::Result;
+use crate::models::*;
+use crate::storage::{LocalStorage, DATABASE_FILE};
+use rusqlite::{params, Connection, OpenFlags};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Settings key the legacy identity private key is stored under, for
+/// passing to `init_keys`
+pub const IDENTITY_KEY_SETTING: &str = "identity_private_key";
+
+/// Messages written per transaction
+const MESSAGE_BATCH: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStage {
+    Messages,
+    Conversations,
+    Contacts,
+    Settings,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationProgress {
+    pub stage: MigrationStage,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MigrationReport {
+    pub conversations: usize,
+    pub messages: usize,
+    /// Users with a cached public key or profile
+    pub contacts: usize,
+    pub settings: usize,
+    /// Where the legacy database now lives
+    pub archived_to: PathBuf,
+}
+
+/// Whether the database at `path` is in the desktop app's legacy schema
+pub fn is_legacy_database(path: &Path) -> Result<bool> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let legacy = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'peer_keys'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(legacy)
+}
+
+/// Convert a legacy database in `data_dir` to the core schema, reporting
+/// progress as it goes. Returns `None` if there was nothing to migrate.
+pub fn migrate_legacy_database(
+    data_dir: &str,
+    mut progress: impl FnMut(MigrationProgress),
+) -> Result<Option<MigrationReport>> {
+    let db_path = Path::new(data_dir).join(DATABASE_FILE);
+    if !db_path.exists() || !is_legacy_database(&db_path)? {
+        return Ok(None);
+    }
+
+    let archive = archive_path(Path::new(data_dir));
+    std::fs::rename(&db_path, &archive)?;
+    log::info!("Migrating legacy database, archived as {}", archive.display());
+
+    match import(data_dir, &archive, &mut progress) {
+        Ok(report) => Ok(Some(MigrationReport {
+            archived_to: archive,
+            ..report
+        })),
+        Err(e) => {
+            let _ = std::fs::remove_file(&db_path);
+            std::fs::rename(&archive, &db_path)?;
+            Err(e)
+        }
+    }
+}
+
+fn archive_path(data_dir: &Path) -> PathBuf {
+    let path = data_dir.join("privmsg.legacy.db");
+    if !path.exists() {
+        return path;
+    }
+    let stamp = chrono::Utc::now().timestamp_millis();
+    data_dir.join(format!("privmsg.legacy-{}.db", stamp))
+}
+
+fn import(
+    data_dir: &str,
+    legacy_path: &Path,
+    progress: &mut impl FnMut(MigrationProgress),
+) -> Result<MigrationReport> {
+    let legacy = Connection::open_with_flags(legacy_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let storage = LocalStorage::new(data_dir)?;
+
+    // Messages first: storing them updates conversation previews and unread
+    // counts, which the legacy conversation rows then overwrite
+    let messages = import_messages(&legacy, &storage, progress)?;
+    let conversations = import_conversations(&legacy, &storage, progress)?;
+    let contacts = import_contacts(&legacy, &storage, progress)?;
+    let settings = import_settings(&legacy, &storage, progress)?;
+
+    Ok(MigrationReport {
+        conversations,
+        messages,
+        contacts,
+        settings,
+        archived_to: PathBuf::new(),
+    })
+}
+
+fn count(legacy: &Connection, table: &str) -> Result<usize> {
+    let n: i64 = legacy.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))?;
+    Ok(n as usize)
+}
+
+fn has_column(legacy: &Connection, table: &str, column: &str) -> Result<bool> {
+    let exists = legacy.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    Ok(exists)
+}
+
+fn import_messages(
+    legacy: &Connection,
+    storage: &LocalStorage,
+    progress: &mut impl FnMut(MigrationProgress),
+) -> Result<usize> {
+    let total = count(legacy, "messages")?;
+    // Both were added to the legacy schema over time
+    let caption = if has_column(legacy, "messages", "caption")? { "caption" } else { "NULL" };
+    let waveform = if has_column(legacy, "messages", "attachment_waveform")? {
+        "attachment_waveform"
+    } else {
+        "NULL"
+    };
+
+    let mut stmt = legacy.prepare(&format!(
+        r#"SELECT message_id, conversation_id, sender_id, message_type, content, timestamp,
+                  status, is_outgoing, attachment_file_id, attachment_file_name,
+                  attachment_file_size, attachment_mime_type, attachment_duration_ms,
+                  attachment_width, attachment_height, attachment_encryption_key,
+                  attachment_local_path, {}, {}
+           FROM messages
+           ORDER BY timestamp, rowid"#,
+        caption, waveform
+    ))?;
+    let mut rows = stmt.query_map([], legacy_message)?;
+
+    let mut done = 0;
+    loop {
+        let batch = rows
+            .by_ref()
+            .take(MESSAGE_BATCH)
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            break;
+        }
+        storage.save_messages(&batch)?;
+        done += batch.len();
+        progress(MigrationProgress {
+            stage: MigrationStage::Messages,
+            done,
+            total,
+        });
+    }
+    Ok(done)
+}
+
+fn legacy_message(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    let attachment = match row.get::<_, Option<String>>(8)? {
+        Some(file_id) => Some(Attachment {
+            file_id,
+            file_name: row.get::<_, Option<String>>(9)?.unwrap_or_default(),
+            file_size: row.get::<_, Option<i64>>(10)?.unwrap_or(0),
+            mime_type: row
+                .get::<_, Option<String>>(11)?
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+            duration_ms: row.get(12)?,
+            width: row.get(13)?,
+            height: row.get(14)?,
+            encryption_key: row.get(15)?,
+            local_path: row.get(16)?,
+            waveform: row
+                .get::<_, Option<String>>(18)?
+                .and_then(|w| serde_json::from_str(&w).ok()),
+            sha256: None,
+            expires_at: None,
+        }),
+        None => None,
+    };
+
+    Ok(Message {
+        message_id: row.get(0)?,
+        conversation_id: row.get(1)?,
+        sender_id: row.get(2)?,
+        message_type: match row.get::<_, String>(3)?.as_str() {
+            "voice" => MessageType::Voice,
+            "video" => MessageType::Video,
+            "image" => MessageType::Image,
+            "file" => MessageType::File,
+            _ => MessageType::Text,
+        },
+        content: row.get(4)?,
+        timestamp: row.get(5)?,
+        status: match row.get::<_, String>(6)?.as_str() {
+            "pending" => MessageStatus::Pending,
+            "delivered" => MessageStatus::Delivered,
+            "read" => MessageStatus::Read,
+            "failed" => MessageStatus::Failed,
+            _ => MessageStatus::Sent,
+        },
+        attachment,
+        caption: row.get(17)?,
+        is_outgoing: row.get::<_, i32>(7)? != 0,
+        metadata: Metadata::new(),
+        system_event: None,
+        sender_device_id: None,
+        quote: None,
+        is_starred: false,
+        unverified_key: false,
+    })
+}
+
+fn import_conversations(
+    legacy: &Connection,
+    storage: &LocalStorage,
+    progress: &mut impl FnMut(MigrationProgress),
+) -> Result<usize> {
+    let total = count(legacy, "conversations")?;
+    let mut stmt = legacy.prepare(
+        r#"SELECT peer_id, peer_name, peer_avatar, last_message, last_message_time,
+                  unread_count, is_muted, is_pinned
+           FROM conversations"#,
+    )?;
+    let conversations = stmt
+        .query_map([], |row| {
+            let peer_id: String = row.get(0)?;
+            Ok(Conversation {
+                // Core keys conversations by peer id
+                id: peer_id.clone(),
+                peer_id,
+                peer_name: row.get(1)?,
+                peer_avatar: row.get(2)?,
+                last_message: row.get(3)?,
+                last_message_time: row.get(4)?,
+                unread_count: row.get::<_, Option<i32>>(5)?.unwrap_or(0),
+                is_muted: row.get::<_, Option<i32>>(6)?.unwrap_or(0) != 0,
+                is_pinned: row.get::<_, Option<i32>>(7)?.unwrap_or(0) != 0,
+                is_archived: false,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (i, conversation) in conversations.iter().enumerate() {
+        storage.save_conversation(conversation)?;
+        progress(MigrationProgress {
+            stage: MigrationStage::Conversations,
+            done: i + 1,
+            total,
+        });
+    }
+    Ok(conversations.len())
+}
+
+/// Cached public keys and profiles, merged into core's users
+fn import_contacts(
+    legacy: &Connection,
+    storage: &LocalStorage,
+    progress: &mut impl FnMut(MigrationProgress),
+) -> Result<usize> {
+    let mut users: BTreeMap<String, User> = BTreeMap::new();
+    let empty = |user_id: &str| User {
+        user_id: user_id.to_string(),
+        display_name: None,
+        avatar_file_id: None,
+        public_key: None,
+        last_seen_at: None,
+        status_emoji: None,
+        status_text: None,
+        bio: None,
+        last_seen_visibility: None,
+    };
+
+    let mut stmt = legacy.prepare("SELECT user_id, public_key FROM peer_keys")?;
+    let keys = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    for key in keys {
+        let (user_id, public_key) = key?;
+        users.entry(user_id.clone()).or_insert_with(|| empty(&user_id)).public_key = Some(public_key);
+    }
+
+    if has_column(legacy, "profiles", "user_id")? {
+        let mut stmt =
+            legacy.prepare("SELECT user_id, display_name, status_emoji, status_text, bio FROM profiles")?;
+        let profiles = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        for profile in profiles {
+            let (user_id, display_name, status_emoji, status_text, bio) = profile?;
+            let user = users.entry(user_id.clone()).or_insert_with(|| empty(&user_id));
+            user.display_name = display_name;
+            user.status_emoji = status_emoji;
+            user.status_text = status_text;
+            user.bio = bio;
+        }
+    }
+
+    let total = users.len();
+    for (i, user) in users.values().enumerate() {
+        storage.save_user(user)?;
+        progress(MigrationProgress {
+            stage: MigrationStage::Contacts,
+            done: i + 1,
+            total,
+        });
+    }
+    Ok(total)
+}
+
+/// Settings, the last session and the identity key
+fn import_settings(
+    legacy: &Connection,
+    storage: &LocalStorage,
+    progress: &mut impl FnMut(MigrationProgress),
+) -> Result<usize> {
+    let mut stmt = legacy.prepare("SELECT key, value FROM settings")?;
+    let settings = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (key, value) in &settings {
+        storage.save_setting(key, value)?;
+    }
+
+    let session = legacy.query_row(
+        "SELECT token, device_id, user_id, expires_at FROM sessions ORDER BY id DESC LIMIT 1",
+        [],
+        |row| {
+            Ok(AuthSession {
+                token: row.get(0)?,
+                device_id: row.get(1)?,
+                user_id: row.get(2)?,
+                expires_at: row.get(3)?,
+                announcement: None,
+            })
+        },
+    );
+    let user_id = match session {
+        Ok(session) => {
+            storage.save_session(&session)?;
+            Some(session.user_id)
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    // The signed-in user's key, or the newest one if nobody is signed in
+    let key = legacy.query_row(
+        "SELECT private_key FROM keys WHERE ?1 IS NULL OR user_id = ?1 ORDER BY id DESC LIMIT 1",
+        params![user_id],
+        |row| row.get::<_, String>(0),
+    );
+    match key {
+        Ok(key) => storage.save_setting(IDENTITY_KEY_SETTING, &key)?,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    progress(MigrationProgress {
+        stage: MigrationStage::Settings,
+        done: settings.len(),
+        total: settings.len(),
+    });
+    Ok(settings.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The desktop schema as of the last release that used it
+    const LEGACY_SCHEMA: &str = r#"
+        CREATE TABLE sessions (id INTEGER PRIMARY KEY, token TEXT NOT NULL, device_id TEXT NOT NULL,
+                               user_id TEXT NOT NULL, expires_at INTEGER NOT NULL);
+        CREATE TABLE keys (id INTEGER PRIMARY KEY, user_id TEXT NOT NULL, private_key TEXT NOT NULL);
+        CREATE TABLE conversations (id TEXT PRIMARY KEY, peer_id TEXT NOT NULL UNIQUE, peer_name TEXT,
+                                    peer_avatar TEXT, last_message TEXT, last_message_time INTEGER,
+                                    unread_count INTEGER DEFAULT 0, is_muted INTEGER DEFAULT 0,
+                                    is_pinned INTEGER DEFAULT 0);
+        CREATE TABLE messages (message_id TEXT PRIMARY KEY, conversation_id TEXT NOT NULL,
+                               sender_id TEXT NOT NULL, message_type TEXT NOT NULL, content TEXT NOT NULL,
+                               timestamp INTEGER NOT NULL, status TEXT NOT NULL, is_outgoing INTEGER NOT NULL,
+                               attachment_file_id TEXT, attachment_file_name TEXT, attachment_file_size INTEGER,
+                               attachment_mime_type TEXT, attachment_duration_ms INTEGER,
+                               attachment_width INTEGER, attachment_height INTEGER,
+                               attachment_encryption_key TEXT, attachment_local_path TEXT, caption TEXT,
+                               attachment_waveform TEXT);
+        CREATE TABLE peer_keys (user_id TEXT PRIMARY KEY, public_key TEXT NOT NULL);
+        CREATE TABLE profiles (user_id TEXT PRIMARY KEY, display_name TEXT, status_emoji TEXT,
+                               status_text TEXT, bio TEXT);
+        CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+
+        INSERT INTO sessions (token, device_id, user_id, expires_at) VALUES ('tok', 'dev', 'alice', 99);
+        INSERT INTO keys (user_id, private_key) VALUES ('alice', 'alice-key');
+        INSERT INTO conversations (id, peer_id, peer_name, last_message, last_message_time, unread_count, is_pinned)
+            VALUES ('bob', 'bob', 'Bob', 'see you', 3, 1, 1);
+        INSERT INTO messages (message_id, conversation_id, sender_id, message_type, content, timestamp,
+                              status, is_outgoing)
+            VALUES ('m1', 'bob', 'alice', 'text', 'hi', 1, 'read', 1),
+                   ('m2', 'bob', 'bob', 'text', 'see you', 3, 'delivered', 0);
+        INSERT INTO messages (message_id, conversation_id, sender_id, message_type, content, timestamp,
+                              status, is_outgoing, attachment_file_id, attachment_file_name,
+                              attachment_file_size, attachment_mime_type, attachment_encryption_key,
+                              caption)
+            VALUES ('m3', 'bob', 'bob', 'image', 'cat.png', 2, 'delivered', 0, 'f1', 'cat.png', 10,
+                    'image/png', 'key', 'look');
+        INSERT INTO peer_keys (user_id, public_key) VALUES ('bob', 'bob-pub');
+        INSERT INTO profiles (user_id, display_name, bio) VALUES ('bob', 'Bob', 'hello');
+        INSERT INTO settings (key, value) VALUES ('theme', 'dark');
+    "#;
+
+    #[test]
+    fn test_migrate_legacy_database() {
+        let dir = std::env::temp_dir().join(format!("privmsg-migrate-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data_dir = dir.to_str().unwrap();
+        Connection::open(dir.join(DATABASE_FILE))
+            .unwrap()
+            .execute_batch(LEGACY_SCHEMA)
+            .unwrap();
+
+        let mut stages = Vec::new();
+        let report = migrate_legacy_database(data_dir, |p| stages.push(p.stage))
+            .unwrap()
+            .unwrap();
+        assert_eq!((report.messages, report.conversations, report.contacts, report.settings), (3, 1, 1, 1));
+        assert!(report.archived_to.exists());
+        assert!(stages.contains(&MigrationStage::Messages) && stages.contains(&MigrationStage::Settings));
+
+        let storage = LocalStorage::new(data_dir).unwrap();
+        let messages = storage.get_messages("bob", 10, 0).unwrap();
+        let ids: Vec<_> = messages.iter().map(|m| m.message_id.as_str()).collect();
+        assert_eq!(ids, ["m1", "m3", "m2"]);
+        let image = &messages[1];
+        assert_eq!(image.message_type, MessageType::Image);
+        assert_eq!(image.caption.as_deref(), Some("look"));
+        assert_eq!(image.attachment.as_ref().unwrap().encryption_key.as_deref(), Some("key"));
+
+        let conversation = storage.get_conversation("bob").unwrap().unwrap();
+        assert_eq!(conversation.peer_name.as_deref(), Some("Bob"));
+        assert_eq!(conversation.unread_count, 1);
+        assert!(conversation.is_pinned);
+
+        let bob = storage.get_user("bob").unwrap().unwrap();
+        assert_eq!(bob.public_key.as_deref(), Some("bob-pub"));
+        assert_eq!(bob.bio.as_deref(), Some("hello"));
+        assert_eq!(storage.get_session().unwrap().user_id, "alice");
+        assert_eq!(storage.get_setting(IDENTITY_KEY_SETTING).as_deref(), Some("alice-key"));
+        assert_eq!(storage.get_setting("theme").as_deref(), Some("dark"));
+        drop(storage);
+
+        // Only once
+        assert!(migrate_legacy_database(data_dir, |_| {}).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,6 +1,23 @@
 //! Data models for PrivMsg
 
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+
+pub use privmsg_proto::announcement::{Announcement, AnnouncementSeverity};
+pub use privmsg_proto::emoji;
+pub use privmsg_proto::envelope::CanonicalEnvelope;
+pub use privmsg_proto::flags::{Flag, FlagSource, FlagState};
+pub use privmsg_proto::maintenance::Maintenance;
+pub use privmsg_proto::pow::{LoginChallenge, LoginProof};
+pub use privmsg_proto::policy::{ClientPolicy, Feature, PolicySetting, SignedPolicy};
+pub use privmsg_proto::quote::Quote;
+pub use privmsg_proto::rules::{MessageRule, RuleAction};
+pub use privmsg_proto::stats::{ConversationStats, DayCount, MessageSample};
+pub use privmsg_proto::{
+    is_custom_message_type, preview_text, truncate_graphemes, DeliveryMode, LastSeenVisibility,
+    MessagePriority,
+};
 
 // ============================================================================
 // User
@@ -12,7 +29,50 @@ pub struct User {
     pub display_name: Option<String>,
     pub avatar_file_id: Option<String>,
     pub public_key: Option<String>,
+    /// Unix millis; `None` if the user hides it from us
+    #[serde(default, deserialize_with = "deserialize_last_seen")]
     pub last_seen_at: Option<i64>,
+    pub status_emoji: Option<String>,
+    pub status_text: Option<String>,
+    pub bio: Option<String>,
+    /// Only known for the current user
+    pub last_seen_visibility: Option<LastSeenVisibility>,
+}
+
+/// The server sends `last_seen_at` as a UTC "YYYY-MM-DD HH:MM:SS" string
+fn deserialize_last_seen<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Millis(i64),
+        Text(String),
+    }
+
+    Ok(match Option::<Raw>::deserialize(d)? {
+        Some(Raw::Millis(millis)) => Some(millis),
+        Some(Raw::Text(text)) => chrono::NaiveDateTime::parse_from_str(&text, "%Y-%m-%d %H:%M:%S")
+            .ok()
+            .map(|dt| dt.and_utc().timestamp_millis()),
+        None => None,
+    })
+}
+
+/// Changes to the current user's profile. `None` leaves a field as it is;
+/// an empty string clears the status fields and bio.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProfileUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_file_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_emoji: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_visibility: Option<LastSeenVisibility>,
 }
 
 // ============================================================================
@@ -25,31 +85,68 @@ pub struct AuthSession {
     pub device_id: String,
     pub user_id: String,
     pub expires_at: i64,
+    /// The server's service notice at login; not kept in storage
+    #[serde(default)]
+    pub announcement: Option<Announcement>,
+}
+
+/// What `wipe_and_logout` left behind
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WipeOutcome {
+    /// The identity key export, if asked to keep it
+    pub identity_key: Option<String>,
+    /// Whether the server confirmed every session was ended. If not, the
+    /// local data is wiped anyway.
+    pub sessions_invalidated: bool,
 }
 
 // ============================================================================
 // Messages
 // ============================================================================
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageType {
+    #[default]
     Text,
     Voice,
     Video,
     Image,
     File,
+    /// A conversation event, see `SystemEvent`
+    System,
 }
 
-impl Default for MessageType {
-    fn default() -> Self {
-        Self::Text
+impl MessageType {
+    /// Message type used for a file with the given MIME type
+    pub fn from_mime(mime_type: &str) -> Self {
+        if mime_type.starts_with("image/") {
+            MessageType::Image
+        } else if mime_type.starts_with("audio/") {
+            MessageType::Voice
+        } else if mime_type.starts_with("video/") {
+            MessageType::Video
+        } else {
+            MessageType::File
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageType::Text => "text",
+            MessageType::Voice => "voice",
+            MessageType::Video => "video",
+            MessageType::Image => "image",
+            MessageType::File => "file",
+            MessageType::System => "system",
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageStatus {
+    #[default]
     Pending,
     Sent,
     Delivered,
@@ -57,10 +154,57 @@ pub enum MessageStatus {
     Failed,
 }
 
-impl Default for MessageStatus {
-    fn default() -> Self {
-        Self::Pending
+/// Why an outgoing message hasn't been sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingReason {
+    /// No connection to the server; waiting to be sent
+    Offline,
+    /// The server is rate limiting this client
+    Throttled,
+    /// Sending failed for another reason
+    Failed,
+}
+
+impl PendingReason {
+    pub fn from_error(error: &crate::error::Error) -> Self {
+        use crate::error::Error;
+        match error {
+            Error::RateLimited => PendingReason::Throttled,
+            Error::Network(_)
+            | Error::WebSocket(_)
+            | Error::Io(_)
+            | Error::NotLoggedIn
+            | Error::Maintenance(_) => {
+                PendingReason::Offline
+            }
+            _ => PendingReason::Failed,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PendingReason::Offline => "offline",
+            PendingReason::Throttled => "throttled",
+            PendingReason::Failed => "failed",
+        }
     }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "offline" => Some(PendingReason::Offline),
+            "throttled" => Some(PendingReason::Throttled),
+            "failed" => Some(PendingReason::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// An outgoing message still waiting to be sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingMessage {
+    pub message: Message,
+    pub reason: PendingReason,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -73,7 +217,133 @@ pub struct Message {
     pub timestamp: i64,
     pub status: MessageStatus,
     pub attachment: Option<Attachment>,
+    /// Text shown beneath a media attachment
+    #[serde(default)]
+    pub caption: Option<String>,
     pub is_outgoing: bool,
+    /// Integrator values, e.g. an order id; encrypted with the message
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+    /// Set on `MessageType::System` messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_event: Option<SystemEvent>,
+    /// The sender's device it was sent from, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_device_id: Option<String>,
+    /// The message this one replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<Quote>,
+    /// Marked by the user to find again; local, never sent
+    #[serde(default)]
+    pub is_starred: bool,
+    /// Received while the sender's identity key was in conflict and not
+    /// yet accepted, see [`KeyConflict`]; local, never sent
+    #[serde(default)]
+    pub unverified_key: bool,
+}
+
+/// App-specific values attached to a message. They travel inside the
+/// encrypted payload, so only the two ends can read them.
+pub type Metadata = BTreeMap<String, serde_json::Value>;
+
+impl Message {
+    /// Metadata under `key`, if present and a `T`
+    pub fn metadata<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.metadata
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    pub fn set_metadata<T: Serialize>(&mut self, key: &str, value: T) -> crate::error::Result<()> {
+        self.metadata.insert(key.to_string(), serde_json::to_value(value)?);
+        Ok(())
+    }
+
+    pub fn remove_metadata(&mut self, key: &str) -> Option<serde_json::Value> {
+        self.metadata.remove(key)
+    }
+
+    /// How many emoji the text is, when it should render large and without
+    /// a bubble; see [`emoji::jumbo_emoji_count`]
+    pub fn jumbo_emoji_count(&self) -> Option<usize> {
+        match self.message_type {
+            MessageType::Text => emoji::jumbo_emoji_count(&self.content),
+            _ => None,
+        }
+    }
+
+    /// A local notice about `event`, never sent to anyone
+    pub fn system(conversation_id: &str, event: SystemEvent) -> Self {
+        Self {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            conversation_id: conversation_id.to_string(),
+            sender_id: String::new(),
+            message_type: MessageType::System,
+            content: event.fallback_text(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            status: MessageStatus::Read,
+            attachment: None,
+            caption: None,
+            is_outgoing: false,
+            metadata: Metadata::new(),
+            system_event: Some(event),
+            sender_device_id: None,
+            quote: None,
+            is_starred: false,
+            unverified_key: false,
+        }
+    }
+}
+
+/// Something that happened in a conversation, shown as a notice instead of
+/// a chat bubble. Frontends word it in the user's language from these
+/// fields; `Message::content` only holds an English fallback.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SystemEvent {
+    /// The user's identity key differs from the one seen before
+    KeyChanged { user_id: String },
+    /// The user accepted the new identity key of `user_id`
+    KeyTrusted { user_id: String },
+    /// `seconds` of `None` turns disappearing messages off
+    DisappearingTimerChanged {
+        changed_by: String,
+        seconds: Option<u64>,
+    },
+    MemberAdded { added_by: String, member_id: String },
+    CallMissed { caller_id: String, call_type: CallType },
+}
+
+impl SystemEvent {
+    pub fn fallback_text(&self) -> String {
+        match self {
+            SystemEvent::KeyChanged { user_id } => {
+                format!("The security key of {} changed", user_id)
+            }
+            SystemEvent::KeyTrusted { user_id } => {
+                format!("You accepted the new security key of {}", user_id)
+            }
+            SystemEvent::DisappearingTimerChanged {
+                changed_by,
+                seconds: Some(seconds),
+            } => format!("{} set disappearing messages to {}s", changed_by, seconds),
+            SystemEvent::DisappearingTimerChanged {
+                changed_by,
+                seconds: None,
+            } => format!("{} turned off disappearing messages", changed_by),
+            SystemEvent::MemberAdded { added_by, member_id } => {
+                format!("{} added {}", added_by, member_id)
+            }
+            SystemEvent::CallMissed {
+                caller_id,
+                call_type: CallType::Audio,
+            } => format!("Missed call from {}", caller_id),
+            SystemEvent::CallMissed {
+                caller_id,
+                call_type: CallType::Video,
+            } => format!("Missed video call from {}", caller_id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -87,6 +357,24 @@ pub struct Attachment {
     pub height: Option<i32>,
     pub encryption_key: Option<String>,
     pub local_path: Option<String>,
+    /// Voice message amplitude buckets (0-255)
+    #[serde(default)]
+    pub waveform: Option<Vec<u8>>,
+    /// Hex SHA-256 of the encrypted file, checked after download
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// When the server drops the file (Unix seconds), once known
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+impl Attachment {
+    /// Whether the server has dropped the file, so it can only be
+    /// downloaded again once the sender re-uploads it
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|at| at <= chrono::Utc::now().timestamp())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -98,6 +386,237 @@ pub struct MessageEnvelope {
     pub encrypted_content: String,
     pub message_type: String,
     pub timestamp: i64,
+    /// How long the server holds the envelope for offline devices; see
+    /// [`privmsg_proto::default_ttl_seconds`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u32>,
+    /// Where the envelope goes in the server's replay order
+    #[serde(default)]
+    pub priority: MessagePriority,
+    #[serde(default)]
+    pub delivery_mode: DeliveryMode,
+}
+
+impl MessageEnvelope {
+    /// The bytes a signature over this envelope covers
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        CanonicalEnvelope::from(self).signing_bytes()
+    }
+}
+
+impl From<&MessageEnvelope> for CanonicalEnvelope {
+    fn from(envelope: &MessageEnvelope) -> Self {
+        Self {
+            message_id: envelope.message_id.clone(),
+            sender_id: envelope.sender_id.clone(),
+            recipient_id: envelope.recipient_id.clone(),
+            recipient_device_id: envelope.recipient_device_id.clone(),
+            encrypted_content: envelope.encrypted_content.clone(),
+            message_type: envelope.message_type.clone(),
+            timestamp: envelope.timestamp,
+            ttl_seconds: envelope.ttl_seconds,
+            priority: envelope.priority,
+            delivery_mode: envelope.delivery_mode,
+        }
+    }
+}
+
+impl From<CanonicalEnvelope> for MessageEnvelope {
+    fn from(envelope: CanonicalEnvelope) -> Self {
+        Self {
+            message_id: envelope.message_id,
+            sender_id: envelope.sender_id,
+            recipient_id: envelope.recipient_id,
+            recipient_device_id: envelope.recipient_device_id,
+            encrypted_content: envelope.encrypted_content,
+            message_type: envelope.message_type,
+            timestamp: envelope.timestamp,
+            ttl_seconds: envelope.ttl_seconds,
+            priority: envelope.priority,
+            delivery_mode: envelope.delivery_mode,
+        }
+    }
+}
+
+/// `message_type` of envelopes that carry a [`ReadPosition`] to the
+/// sender's own devices
+pub const READ_SYNC_MESSAGE_TYPE: &str = "read_sync";
+
+/// `message_type` of envelopes that carry a [`Draft`] to the sender's own
+/// devices
+pub const DRAFT_SYNC_MESSAGE_TYPE: &str = "draft_sync";
+
+/// Unsent text in a conversation, kept per device and synced between the
+/// user's devices. Empty text is a cleared draft.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Draft {
+    pub conversation_id: String,
+    pub text: String,
+    /// Unix milliseconds of the edit; the latest edit wins on every device
+    pub updated_at: i64,
+}
+
+/// A peer's identity key from the server that differs from the one
+/// trusted for them, e.g. after a reinstall or because someone is
+/// impersonating them. Until the user accepts it with
+/// `PrivMsgClient::trust_new_key`, sending to the peer fails with
+/// `Error::KeyConflict` and messages from them are marked
+/// [`unverified_key`](Message::unverified_key).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyConflict {
+    pub peer_id: String,
+    pub trusted_key: String,
+    pub new_key: String,
+    /// Unix milliseconds
+    pub detected_at: i64,
+}
+
+/// An identity key a peer used before, kept when it was replaced so the
+/// conversation's history can be told apart by key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedPeerKey {
+    pub peer_id: String,
+    pub public_key: String,
+    /// Unix milliseconds it was first trusted
+    pub trusted_at: i64,
+    /// Unix milliseconds a different key turned up
+    pub archived_at: i64,
+    /// Latest message in the conversation at that point
+    pub last_message_time: Option<i64>,
+}
+
+/// `message_type` of online-only envelopes carrying an [`EphemeralPayload`]
+pub const EPHEMERAL_MESSAGE_TYPE: &str = "ephemeral";
+
+/// The encrypted body of an ephemeral envelope
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EphemeralPayload {
+    /// What the payload is, e.g. "location"; up to the application
+    pub kind: String,
+    pub payload: String,
+}
+
+/// `message_type` of envelopes carrying a [`FileExpiry`] to the other side
+/// of a conversation
+pub const FILE_EXPIRY_MESSAGE_TYPE: &str = "file_expiry";
+
+/// A shared file's expiry changed: it was extended, or deleted early (an
+/// expiry in the past)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileExpiry {
+    pub file_id: String,
+    /// Unix seconds
+    pub expires_at: i64,
+}
+
+/// `message_type` of envelopes carrying a [`ReuploadRequest`] to the sender
+/// of an attachment
+pub const REUPLOAD_REQUEST_MESSAGE_TYPE: &str = "reupload_request";
+
+/// Asks the sender of an expired attachment to upload the file again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReuploadRequest {
+    /// The message the attachment came with
+    pub message_id: String,
+    pub file_id: String,
+}
+
+/// `message_type` of envelopes carrying an [`AttachmentUpdate`] to the
+/// recipient of an attachment
+pub const ATTACHMENT_UPDATE_MESSAGE_TYPE: &str = "attachment_update";
+
+/// The sender uploaded an attachment again; the message keeps its id and
+/// points at the new upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentUpdate {
+    pub message_id: String,
+    /// Without `local_path`
+    pub attachment: Attachment,
+}
+
+/// `message_type` of envelopes carrying a [`MessageDeletion`] to the other
+/// side of a conversation and the sender's own devices
+pub const DELETION_MESSAGE_TYPE: &str = "delete";
+
+/// The sender deleted a message for everyone. It is honored only from the
+/// message's own sender; the session it is encrypted under shows who that is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageDeletion {
+    pub message_id: String,
+}
+
+/// `message_type` of envelopes carrying a [`ConversationTtl`] to the other
+/// side of a conversation and the sender's own devices
+pub const CONVERSATION_TTL_MESSAGE_TYPE: &str = "conversation_ttl";
+
+/// How long new messages in a conversation are kept before they disappear
+/// on every device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationTtl {
+    /// As the sender sees it; a peer receiving it uses the sender's id
+    /// unless it names a group
+    pub conversation_id: String,
+    /// `None` turns disappearing messages off
+    pub ttl_seconds: Option<u64>,
+}
+
+/// A message of an application-defined `x-<vendor>-<name>` type; see
+/// [`PrivMsgClient::register_handler`](crate::PrivMsgClient::register_handler)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomMessage {
+    pub message_id: String,
+    pub sender_id: String,
+    pub message_type: String,
+    /// Decrypted, in whatever format the type defines
+    pub payload: String,
+    pub timestamp: i64,
+}
+
+/// How far a conversation has been read, synced between a user's devices
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadPosition {
+    pub conversation_id: String,
+    /// Messages up to and including this timestamp are read
+    pub last_read_timestamp: i64,
+}
+
+// ============================================================================
+// LAN
+// ============================================================================
+
+/// A contact's device found on the local network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LanPeer {
+    pub user_id: String,
+    /// Where the device accepts direct deliveries
+    pub address: String,
+}
+
+// ============================================================================
+// Transfers
+// ============================================================================
+
+/// A partially downloaded file, kept so downloads resume across restarts
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    /// Transfer id; the attachment's file id
+    pub file_id: String,
+    pub attachment: Attachment,
+    /// Where the decrypted file is written once complete
+    pub dest_path: String,
+    /// Where the encrypted bytes are assembled
+    pub part_path: String,
+    pub total_size: u64,
+    pub range_size: u64,
+    /// Indices of ranges already written to `part_path`
+    pub completed: Vec<u64>,
+    pub paused: bool,
+}
+
+impl Transfer {
+    pub fn range_count(&self) -> u64 {
+        self.total_size.div_ceil(self.range_size)
+    }
 }
 
 // ============================================================================
@@ -115,6 +634,159 @@ pub struct Conversation {
     pub unread_count: i32,
     pub is_muted: bool,
     pub is_pinned: bool,
+    /// Kept out of the main list, e.g. by a message rule
+    #[serde(default)]
+    pub is_archived: bool,
+}
+
+/// The fields of a conversation its list row needs, as returned a page at
+/// a time by `get_conversation_page`. The id is also the peer's user id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub peer_name: Option<String>,
+    /// Preview of the latest message
+    pub last_message: Option<String>,
+    pub last_message_time: Option<i64>,
+    pub unread_count: i32,
+    pub is_muted: bool,
+    pub is_pinned: bool,
+    pub is_archived: bool,
+}
+
+/// Conversations that changed since a cursor, from
+/// `get_conversation_changes`. Deleted conversations aren't included;
+/// they are only ever deleted by the application itself.
+#[derive(Debug, Clone, Default)]
+pub struct ConversationChanges {
+    /// Oldest change first, each conversation once
+    pub conversations: Vec<ConversationSummary>,
+    /// Pass to the next call. Unchanged when nothing changed.
+    pub cursor: i64,
+}
+
+/// A conversation and its messages, oldest first, as exported by
+/// `export_conversations`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationExport {
+    pub conversation: Conversation,
+    pub messages: Vec<Message>,
+}
+
+/// A group conversation. Its id is also the id of its conversation, and
+/// messages to it are encrypted for each member separately.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub group_id: String,
+    pub name: String,
+    pub created_by: String,
+    /// Server time, e.g. `2024-01-31 09:30:00`
+    pub created_at: String,
+    pub member_ids: Vec<String>,
+}
+
+impl Group {
+    pub fn is_member(&self, user_id: &str) -> bool {
+        self.member_ids.iter().any(|id| id == user_id)
+    }
+}
+
+/// One of the user's signed-in devices, as the server lists them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    pub device_name: String,
+    pub device_type: String,
+    /// Server time, e.g. `2024-01-31 09:30:00`
+    pub created_at: String,
+    pub last_active_at: String,
+}
+
+/// A signed-in device of a user with the key messages to it are
+/// encrypted for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceKey {
+    pub device_id: String,
+    pub public_key: String,
+    /// Certificate of the key: the user's identity key signing the user,
+    /// device id and key. Keys without a valid one aren't used until the
+    /// user accepts them like a changed identity key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// One of the user's devices got a new name, as pushed by the server to
+/// the others
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceRenamed {
+    pub device_id: String,
+    pub device_name: String,
+}
+
+/// Messages this user sent that a device of `recipient_id` received and
+/// acked, as pushed by the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    pub recipient_id: String,
+    pub message_ids: Vec<String>,
+}
+
+/// How an outgoing message left this device. There is no REST fallback for
+/// sending; without a LAN peer it goes over the WebSocket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryTransport {
+    WebSocket,
+    Lan,
+}
+
+impl DeliveryTransport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryTransport::WebSocket => "web_socket",
+            DeliveryTransport::Lan => "lan",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "web_socket" => Some(DeliveryTransport::WebSocket),
+            "lan" => Some(DeliveryTransport::Lan),
+            _ => None,
+        }
+    }
+}
+
+/// Delivery details of a message sent from this device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryInfo {
+    /// None until an attempt succeeds
+    pub transport: Option<DeliveryTransport>,
+    /// Attempts so far, counting retries and later resends
+    pub attempts: u32,
+    /// Unix millis of the last successful attempt
+    pub sent_at: Option<i64>,
+    /// Unix millis the server acknowledged it. LAN deliveries have none.
+    pub acked_at: Option<i64>,
+}
+
+/// What `message_info` shows about a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageInfo {
+    pub message: Message,
+    /// Only for messages sent from this device
+    pub delivery: Option<DeliveryInfo>,
+}
+
+/// Everything this device knows about the user, as written by
+/// `export_my_data`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataExport {
+    /// Unix millis
+    pub exported_at: i64,
+    pub user: User,
+    pub devices: Vec<Device>,
+    pub conversations: Vec<ConversationExport>,
 }
 
 // ============================================================================
@@ -128,6 +800,15 @@ pub enum CallType {
     Video,
 }
 
+/// What the user's contacts see as their current availability
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum CallState {
@@ -169,3 +850,44 @@ pub struct TurnCredentials {
     pub username: String,
     pub credential: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_signing_bytes() {
+        // Field order and spacing in the JSON don't change what is signed
+        let a: MessageEnvelope = serde_json::from_str(
+            r#"{"message_id":"m1","sender_id":"alice","recipient_id":"bob","recipient_device_id":null,
+                "encrypted_content":"c","message_type":"text","timestamp":7}"#,
+        )
+        .unwrap();
+        let b: MessageEnvelope = serde_json::from_str(
+            r#"{ "timestamp": 7, "message_type": "text", "encrypted_content": "c", "priority": "normal",
+                 "recipient_id": "bob", "sender_id": "alice", "message_id": "m1" }"#,
+        )
+        .unwrap();
+        assert_eq!(a.signing_bytes(), b.signing_bytes());
+
+        let decoded = MessageEnvelope::from(CanonicalEnvelope::decode(&CanonicalEnvelope::from(&a).encode()).unwrap());
+        assert_eq!(decoded.signing_bytes(), a.signing_bytes());
+        let changed = MessageEnvelope { timestamp: 8, ..a };
+        assert_ne!(changed.signing_bytes(), b.signing_bytes());
+    }
+
+    #[test]
+    fn test_user_last_seen_formats() {
+        let user: User = serde_json::from_str(
+            r#"{"user_id":"a","display_name":null,"avatar_file_id":null,"public_key":null,
+                "last_seen_at":"2024-05-01 12:00:00"}"#,
+        )
+        .unwrap();
+        assert_eq!(user.last_seen_at, Some(1_714_564_800_000));
+
+        let hidden: User = serde_json::from_str(r#"{"user_id":"a","last_seen_at":null}"#).unwrap();
+        assert_eq!(hidden.last_seen_at, None);
+        let cached: User = serde_json::from_str(r#"{"user_id":"a","last_seen_at":5}"#).unwrap();
+        assert_eq!(cached.last_seen_at, Some(5));
+    }
+}
@@ -1,16 +1,77 @@
 //! Network layer for PrivMsg - HTTP API and WebSocket client
 
-use crate::error::{Error, Result};
+use crate::config::{ClientConfig, Endpoint, RetryPolicy};
+use crate::error::{Error, Result, RevocationReason, ServerError};
 use crate::models::*;
-use crate::ClientConfig;
+use crate::power::PowerSource;
+use crate::tls;
+use crate::transport::{ApiTransport, FileRange, UploadedFile, WsConnector, WsTransport};
+use async_trait::async_trait;
+use base64::Engine;
+use futures::stream::FuturesUnordered;
 use futures::{SinkExt, StreamExt};
 use parking_lot::Mutex;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, Response};
 use serde_json::json;
 use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use tokio_tungstenite::{
+    client_async_tls_with_config,
+    tungstenite::{self, client::IntoClientRequest, Message as WsMessage},
+    MaybeTlsStream, WebSocketStream,
+};
+
+// ============================================================================
+// Endpoints
+// ============================================================================
+
+/// The server's endpoints, remembering which one last answered so that
+/// later connections go there first
+pub struct EndpointSet {
+    endpoints: Vec<Endpoint>,
+    current: AtomicUsize,
+}
+
+impl EndpointSet {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            endpoints: config.endpoints(),
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    /// The endpoint that last answered
+    pub fn current(&self) -> &Endpoint {
+        &self.endpoints[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// The `n`th endpoint to try, counting from the current one
+    fn nth_from_current(&self, n: usize) -> (usize, &Endpoint) {
+        let index = (self.current.load(Ordering::Relaxed) + n) % self.endpoints.len();
+        (index, &self.endpoints[index])
+    }
+
+    fn mark_working(&self, index: usize) {
+        if self.current.swap(index, Ordering::Relaxed) != index {
+            log::info!("Switched to server endpoint {}", self.endpoints[index].http_url());
+        }
+    }
+}
 
 // ============================================================================
 // HTTP API Client
@@ -18,22 +79,52 @@ use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
 
 pub struct ApiClient {
     client: Client,
-    base_url: String,
+    endpoints: Arc<EndpointSet>,
     token: Mutex<Option<String>>,
+    retry: RetryPolicy,
 }
 
 impl ApiClient {
-    pub fn new(config: &ClientConfig) -> Self {
-        let client = Client::builder()
-            .danger_accept_invalid_certs(!config.use_tls) // For development
-            .build()
-            .expect("Failed to create HTTP client");
+    pub fn new(config: &ClientConfig) -> Result<Self> {
+        Self::with_endpoints(config, Arc::new(EndpointSet::new(config)))
+    }
 
-        Self {
-            client,
-            base_url: config.http_url(),
-            token: Mutex::new(None),
+    /// A client that shares endpoint stickiness with other connections
+    pub fn with_endpoints(config: &ClientConfig, endpoints: Arc<EndpointSet>) -> Result<Self> {
+        let mut builder = Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            .user_agent(config.user_agent.clone())
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_keepalive(config.tcp_keepalive)
+            .tcp_nodelay(true);
+
+        // hyper's connector already races IPv6 and IPv4 (happy eyeballs), so
+        // only the HTTP/2 connection itself needs tuning here
+        if config.http2 {
+            builder = builder
+                .http2_adaptive_window(true)
+                .http2_keep_alive_interval(config.tcp_keepalive)
+                .http2_keep_alive_while_idle(true);
+        } else {
+            builder = builder.http1_only();
+        }
+
+        if let Some(ref proxy) = config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+
+        if !config.certificate_pins.is_empty() {
+            builder = builder.use_preconfigured_tls(tls::http_client_config(&config.certificate_pins, config.http2));
         }
+
+        Ok(Self {
+            client: builder.build()?,
+            endpoints,
+            token: Mutex::new(None),
+            retry: config.retry.clone(),
+        })
     }
 
     fn auth_header(&self) -> Option<String> {
@@ -43,28 +134,86 @@ impl ApiClient {
             .map(|t| format!("Bearer {}", t))
     }
 
+    fn authorized(&self, req: RequestBuilder) -> RequestBuilder {
+        match self.auth_header() {
+            Some(auth) => req.header("Authorization", auth),
+            None => req,
+        }
+    }
+
+    /// Send an idempotent request, retrying connection failures and
+    /// gateway errors according to the retry policy
+    async fn send_with_retry(&self, build: impl Fn(&str) -> RequestBuilder) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let result = self.send_with_failover(&build).await;
+            let retryable = match result {
+                Ok(ref resp) => matches!(resp.status().as_u16(), 502..=504),
+                Err(ref e) => e.is_connect() || e.is_timeout(),
+            };
+
+            if !retryable || attempt >= self.retry.max_retries {
+                return Ok(result?);
+            }
+
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Send to the current endpoint, moving on to the next one while the
+    /// connection itself fails. `build` gets the endpoint's base URL.
+    async fn send_with_failover(
+        &self,
+        build: &impl Fn(&str) -> RequestBuilder,
+    ) -> reqwest::Result<Response> {
+        let mut tried = 0;
+        loop {
+            let (index, endpoint) = self.endpoints.nth_from_current(tried);
+            let result = build(&endpoint.http_url()).send().await;
+            tried += 1;
+
+            match result {
+                Err(ref e) if e.is_connect() && tried < self.endpoints.len() => {
+                    log::warn!("Cannot reach {}: {}", endpoint.http_url(), e);
+                }
+                Ok(_) => {
+                    self.endpoints.mark_working(index);
+                    return result;
+                }
+                Err(_) => return result,
+            }
+        }
+    }
+
     pub async fn login(
         &self,
         user_id: &str,
         access_key: &str,
         device_name: &str,
         device_public_key: &str,
+        proof: Option<&LoginProof>,
     ) -> Result<AuthSession> {
+        let body = json!({
+            "user_id": user_id,
+            "access_key": access_key,
+            "device_name": device_name,
+            "device_type": std::env::consts::OS,
+            "device_public_key": device_public_key,
+            "proof": proof,
+        });
+        // Nothing reached the server when the connection failed, so moving
+        // to another endpoint is safe even though login isn't idempotent
         let resp = self
-            .client
-            .post(format!("{}/api/v1/auth/login", self.base_url))
-            .json(&json!({
-                "user_id": user_id,
-                "access_key": access_key,
-                "device_name": device_name,
-                "device_type": std::env::consts::OS,
-                "device_public_key": device_public_key
-            }))
-            .send()
+            .send_with_failover(&|base| {
+                self.client
+                    .post(format!("{}/api/v1/auth/login", base))
+                    .json(&body)
+            })
             .await?;
 
         if !resp.status().is_success() {
-            return Err(Error::InvalidCredentials);
+            return Err(error_from_response(resp).await);
         }
 
         let data: serde_json::Value = resp.json().await?;
@@ -74,6 +223,7 @@ impl ApiClient {
             device_id: data["device_id"].as_str().unwrap_or_default().to_string(),
             user_id: user_id.to_string(),
             expires_at: data["expires_at"].as_i64().unwrap_or(0),
+            announcement: serde_json::from_value(data["announcement"].clone()).unwrap_or(None),
         };
 
         *self.token.lock() = Some(session.token.clone());
@@ -82,19 +232,18 @@ impl ApiClient {
     }
 
     pub async fn get_user(&self, user_id: &str) -> Result<User> {
-        let mut req = self
-            .client
-            .get(format!("{}/api/v1/users/{}", self.base_url, user_id));
-
-        if let Some(auth) = self.auth_header() {
-            req = req.header("Authorization", auth);
-        }
-
-        let resp = req.send().await?;
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/users/{}", base, user_id)))
+            })
+            .await?;
 
         if resp.status().as_u16() == 404 {
             return Err(Error::UserNotFound(user_id.to_string()));
         }
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
 
         let user: User = resp.json().await?;
         Ok(user)
@@ -106,8 +255,37 @@ impl ApiClient {
         file_name: &str,
         mime_type: &str,
         encryption_key_hash: &str,
-    ) -> Result<String> {
-        let part = reqwest::multipart::Part::bytes(data)
+    ) -> Result<UploadedFile> {
+        let part = reqwest::multipart::Part::bytes(data);
+        self.send_upload(part, file_name, mime_type, encryption_key_hash)
+            .await
+    }
+
+    /// Upload a file from disk without reading it into memory
+    pub async fn upload_file_from_path(
+        &self,
+        path: &Path,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key_hash: &str,
+    ) -> Result<UploadedFile> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file));
+
+        let part = reqwest::multipart::Part::stream_with_length(body, len);
+        self.send_upload(part, file_name, mime_type, encryption_key_hash)
+            .await
+    }
+
+    async fn send_upload(
+        &self,
+        part: reqwest::multipart::Part,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key_hash: &str,
+    ) -> Result<UploadedFile> {
+        let part = part
             .file_name(file_name.to_string())
             .mime_str(mime_type)
             .map_err(|e| Error::Network(e.to_string()))?;
@@ -116,9 +294,10 @@ impl ApiClient {
             .part("file", part)
             .text("encryption_key_hash", encryption_key_hash.to_string());
 
+        // A streamed form can't be rebuilt for another endpoint
         let mut req = self
             .client
-            .post(format!("{}/api/v1/files/upload", self.base_url))
+            .post(format!("{}/api/v1/files/upload", self.endpoints.current().http_url()))
             .multipart(form);
 
         if let Some(auth) = self.auth_header() {
@@ -128,44 +307,336 @@ impl ApiClient {
         let resp = req.send().await?;
         let data: serde_json::Value = resp.json().await?;
 
-        Ok(data["file_id"].as_str().unwrap_or_default().to_string())
+        Ok(UploadedFile {
+            file_id: data["file_id"].as_str().unwrap_or_default().to_string(),
+            expires_at: data["expires_at"].as_i64(),
+        })
     }
 
     pub async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
-        let mut req = self
-            .client
-            .get(format!("{}/api/v1/files/{}", self.base_url, file_id));
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/files/{}", base, file_id)))
+            })
+            .await?;
+        let bytes = resp.bytes().await?;
 
-        if let Some(auth) = self.auth_header() {
-            req = req.header("Authorization", auth);
+        Ok(bytes.to_vec())
+    }
+
+    /// Download part of a file with an HTTP range request
+    pub async fn download_range(&self, file_id: &str, offset: u64, len: u64) -> Result<FileRange> {
+        let range = format!("bytes={}-{}", offset, offset + len.max(1) - 1);
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/files/{}", base, file_id)))
+                    .header(reqwest::header::RANGE, range.as_str())
+            })
+            .await?
+            .error_for_status()?;
+
+        if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            // Content-Range: bytes start-end/total
+            let total_size = resp
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit('/').next())
+                .and_then(|total| total.parse().ok())
+                .ok_or_else(|| Error::Http("Missing Content-Range in partial response".into()))?;
+
+            return Ok(FileRange {
+                data: resp.bytes().await?.to_vec(),
+                total_size,
+            });
         }
 
-        let resp = req.send().await?;
-        let bytes = resp.bytes().await?;
+        // The server ignored the range and sent the whole file
+        let data = resp.bytes().await?;
+        let total_size = data.len() as u64;
+        let start = offset.min(total_size) as usize;
+        let end = offset.saturating_add(len).min(total_size) as usize;
 
-        Ok(bytes.to_vec())
+        Ok(FileRange {
+            data: data[start..end].to_vec(),
+            total_size,
+        })
     }
 
-    pub async fn get_turn_credentials(&self) -> Result<TurnCredentials> {
-        let mut req = self
-            .client
-            .get(format!("{}/api/v1/turn/credentials", self.base_url));
+    /// Download a file straight to disk, chunk by chunk
+    pub async fn download_file_to(&self, file_id: &str, path: &Path) -> Result<()> {
+        let mut resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/files/{}", base, file_id)))
+            })
+            .await?
+            .error_for_status()?;
 
-        if let Some(auth) = self.auth_header() {
-            req = req.header("Authorization", auth);
+        let mut file = tokio::fs::File::create(path).await?;
+        let result = async {
+            while let Some(chunk) = resp.chunk().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            Ok(())
         }
+        .await;
 
-        let resp = req.send().await?;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        result
+    }
+
+    pub async fn get_turn_credentials(&self) -> Result<TurnCredentials> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/turn/credentials", base)))
+            })
+            .await?;
         let creds: TurnCredentials = resp.json().await?;
 
         Ok(creds)
     }
 
+    pub async fn update_profile(&self, update: &ProfileUpdate) -> Result<User> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.post(format!("{}/api/v1/users/me/profile", base)))
+                    .json(update)
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+
+        let user: User = resp.json().await?;
+        Ok(user)
+    }
+
+    pub async fn extend_file(&self, file_id: &str, hours: u64) -> Result<i64> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.post(format!("{}/api/v1/files/{}/extend", base, file_id)))
+                    .json(&serde_json::json!({ "hours": hours }))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+
+        let data: serde_json::Value = resp.json().await?;
+        data["expires_at"]
+            .as_i64()
+            .ok_or_else(|| Error::Network("Missing expires_at in response".into()))
+    }
+
+    pub async fn delete_file(&self, file_id: &str) -> Result<()> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.delete(format!("{}/api/v1/files/{}", base, file_id)))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(())
+    }
+
+    /// `None` from servers that don't ask for a proof, including those from
+    /// before login challenges
+    pub async fn login_challenge(&self) -> Result<Option<LoginChallenge>> {
+        let resp = self
+            .send_with_retry(|base| self.client.get(format!("{}/api/v1/auth/challenge", base)))
+            .await?;
+
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(resp.json().await?)
+    }
+
+    pub async fn get_client_policy(&self) -> Result<Option<SignedPolicy>> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/client-policy", base)))
+            })
+            .await?;
+
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(Some(resp.json().await?))
+    }
+
+    pub async fn list_devices(&self) -> Result<Vec<Device>> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/users/me/devices", base)))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(resp.json().await?)
+    }
+
+    /// A user's devices with their keys; empty from servers that don't
+    /// list them
+    pub async fn get_device_keys(&self, user_id: &str) -> Result<Vec<DeviceKey>> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/users/{}/devices", base, user_id)))
+            })
+            .await?;
+
+        if resp.status().as_u16() == 404 {
+            return Ok(Vec::new());
+        }
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(resp.json().await?)
+    }
+
+    pub async fn retract_message(&self, message_id: &str) -> Result<()> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.delete(format!("{}/api/v1/messages/{}", base, message_id)))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(())
+    }
+
+    pub async fn rename_device(&self, device_id: &str, device_name: &str) -> Result<()> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.put(format!("{}/api/v1/users/me/devices/{}", base, device_id)))
+                    .json(&serde_json::json!({ "device_name": device_name }))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(())
+    }
+
+    pub async fn certify_device(&self, device_id: &str, signature: &str) -> Result<()> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.put(format!(
+                    "{}/api/v1/users/me/devices/{}/certificate",
+                    base, device_id
+                )))
+                .json(&serde_json::json!({ "signature": signature }))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(())
+    }
+
+    pub async fn logout_all(&self) -> Result<()> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.post(format!("{}/api/v1/auth/logout-all", base)))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(())
+    }
+
+    pub async fn create_group(&self, name: &str, member_ids: &[String]) -> Result<Group> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.post(format!("{}/api/v1/groups", base)))
+                    .json(&serde_json::json!({ "name": name, "member_ids": member_ids }))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(resp.json().await?)
+    }
+
+    pub async fn list_groups(&self) -> Result<Vec<Group>> {
+        let resp = self
+            .send_with_retry(|base| self.authorized(self.client.get(format!("{}/api/v1/groups", base))))
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(resp.json().await?)
+    }
+
+    pub async fn get_group(&self, group_id: &str) -> Result<Group> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.get(format!("{}/api/v1/groups/{}", base, group_id)))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(resp.json().await?)
+    }
+
+    pub async fn add_group_members(&self, group_id: &str, member_ids: &[String]) -> Result<Group> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.post(format!("{}/api/v1/groups/{}/members", base, group_id)))
+                    .json(&serde_json::json!({ "member_ids": member_ids }))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(resp.json().await?)
+    }
+
+    pub async fn remove_group_member(&self, group_id: &str, user_id: &str) -> Result<()> {
+        let resp = self
+            .send_with_retry(|base| {
+                self.authorized(self.client.delete(format!(
+                    "{}/api/v1/groups/{}/members/{}",
+                    base, group_id, user_id
+                )))
+            })
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+        Ok(())
+    }
+
     pub async fn check_health(&self) -> Result<bool> {
         let resp = self
-            .client
-            .get(format!("{}/health", self.base_url))
-            .send()
+            .send_with_failover(&|base| self.client.get(format!("{}/health", base)))
             .await;
 
         match resp {
@@ -173,32 +644,237 @@ impl ApiClient {
             Err(_) => Ok(false),
         }
     }
+
+    /// The `timestamp` the health endpoint reports
+    pub async fn server_time(&self) -> Result<Option<i64>> {
+        let resp = self
+            .send_with_failover(&|base| self.client.get(format!("{}/health", base)))
+            .await?;
+        if !resp.status().is_success() {
+            return Err(error_from_response(resp).await);
+        }
+
+        let health: serde_json::Value = resp.json().await?;
+        Ok(health.get("timestamp").and_then(|t| t.as_i64()))
+    }
+}
+
+#[async_trait]
+impl ApiTransport for ApiClient {
+    async fn login_challenge(&self) -> Result<Option<LoginChallenge>> {
+        ApiClient::login_challenge(self).await
+    }
+
+    async fn login(
+        &self,
+        user_id: &str,
+        access_key: &str,
+        device_name: &str,
+        device_public_key: &str,
+        proof: Option<&LoginProof>,
+    ) -> Result<AuthSession> {
+        ApiClient::login(self, user_id, access_key, device_name, device_public_key, proof).await
+    }
+
+    async fn get_user(&self, user_id: &str) -> Result<User> {
+        ApiClient::get_user(self, user_id).await
+    }
+
+    async fn upload_file(
+        &self,
+        data: Vec<u8>,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key_hash: &str,
+    ) -> Result<UploadedFile> {
+        ApiClient::upload_file(self, data, file_name, mime_type, encryption_key_hash).await
+    }
+
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        ApiClient::download_file(self, file_id).await
+    }
+
+    async fn upload_file_from_path(
+        &self,
+        path: &Path,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key_hash: &str,
+    ) -> Result<UploadedFile> {
+        ApiClient::upload_file_from_path(self, path, file_name, mime_type, encryption_key_hash)
+            .await
+    }
+
+    async fn download_range(&self, file_id: &str, offset: u64, len: u64) -> Result<FileRange> {
+        ApiClient::download_range(self, file_id, offset, len).await
+    }
+
+    async fn download_file_to(&self, file_id: &str, path: &Path) -> Result<()> {
+        ApiClient::download_file_to(self, file_id, path).await
+    }
+
+    async fn get_turn_credentials(&self) -> Result<TurnCredentials> {
+        ApiClient::get_turn_credentials(self).await
+    }
+
+    async fn check_health(&self) -> Result<bool> {
+        ApiClient::check_health(self).await
+    }
+
+    async fn server_time(&self) -> Result<Option<i64>> {
+        ApiClient::server_time(self).await
+    }
+
+    async fn update_profile(&self, update: &ProfileUpdate) -> Result<User> {
+        ApiClient::update_profile(self, update).await
+    }
+
+    async fn extend_file(&self, file_id: &str, hours: u64) -> Result<i64> {
+        ApiClient::extend_file(self, file_id, hours).await
+    }
+
+    async fn delete_file(&self, file_id: &str) -> Result<()> {
+        ApiClient::delete_file(self, file_id).await
+    }
+
+    async fn get_client_policy(&self) -> Result<Option<SignedPolicy>> {
+        ApiClient::get_client_policy(self).await
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>> {
+        ApiClient::list_devices(self).await
+    }
+
+    async fn get_device_keys(&self, user_id: &str) -> Result<Vec<DeviceKey>> {
+        ApiClient::get_device_keys(self, user_id).await
+    }
+
+    async fn certify_device(&self, device_id: &str, signature: &str) -> Result<()> {
+        ApiClient::certify_device(self, device_id, signature).await
+    }
+
+    async fn retract_message(&self, message_id: &str) -> Result<()> {
+        ApiClient::retract_message(self, message_id).await
+    }
+
+    async fn rename_device(&self, device_id: &str, device_name: &str) -> Result<()> {
+        ApiClient::rename_device(self, device_id, device_name).await
+    }
+
+    async fn logout_all(&self) -> Result<()> {
+        ApiClient::logout_all(self).await
+    }
+
+    async fn create_group(&self, name: &str, member_ids: &[String]) -> Result<Group> {
+        ApiClient::create_group(self, name, member_ids).await
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>> {
+        ApiClient::list_groups(self).await
+    }
+
+    async fn get_group(&self, group_id: &str) -> Result<Group> {
+        ApiClient::get_group(self, group_id).await
+    }
+
+    async fn add_group_members(&self, group_id: &str, member_ids: &[String]) -> Result<Group> {
+        ApiClient::add_group_members(self, group_id, member_ids).await
+    }
+
+    async fn remove_group_member(&self, group_id: &str, user_id: &str) -> Result<()> {
+        ApiClient::remove_group_member(self, group_id, user_id).await
+    }
+}
+
+/// The error a failed response carries in its `{"error": {...}}` body
+async fn error_from_response(resp: Response) -> Error {
+    let status = resp.status();
+    let body: Option<serde_json::Value> = resp.json().await.ok();
+    match body.and_then(|b| serde_json::from_value::<ServerError>(b["error"].clone()).ok()) {
+        Some(e) => e.into(),
+        None if status == reqwest::StatusCode::UNAUTHORIZED => Error::InvalidCredentials,
+        None => Error::Http(format!("Server returned {}", status)),
+    }
 }
 
 // ============================================================================
 // WebSocket Client
 // ============================================================================
 
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
 pub struct WebSocketClient {
-    sender: mpsc::UnboundedSender<String>,
+    sender: mpsc::UnboundedSender<WsMessage>,
+    /// Send envelopes as binary frames in the canonical encoding; shared
+    /// with the connector so the feature flag applies without reconnecting
+    binary_envelopes: Arc<AtomicBool>,
     incoming: Arc<Mutex<VecDeque<MessageEnvelope>>>,
+    signals: Arc<Mutex<VecDeque<CallSignal>>>,
+    errors: Arc<Mutex<VecDeque<ServerError>>>,
+    device_renames: Arc<Mutex<VecDeque<DeviceRenamed>>>,
+    announcements: Arc<Mutex<VecDeque<Option<Announcement>>>>,
+    maintenance: Arc<Mutex<VecDeque<Option<Maintenance>>>>,
+    acks: Arc<Mutex<VecDeque<(String, i64)>>>,
+    deliveries: Arc<Mutex<VecDeque<DeliveryReceipt>>>,
+    revoked: Arc<Mutex<Option<RevocationReason>>>,
     connected: Arc<Mutex<bool>>,
+    /// Ping interval the send task uses
+    keepalive: tokio::sync::watch::Sender<Option<Duration>>,
+    /// Ping intervals on mains power and on battery
+    keepalive_intervals: (Option<Duration>, Option<Duration>),
 }
 
 impl WebSocketClient {
     pub async fn connect(config: &ClientConfig, token: &str) -> Result<Self> {
-        let url = format!("{}", config.ws_url());
-        let (ws_stream, _) = connect_async(&url).await?;
+        Self::connect_to(config, &EndpointSet::new(config), token).await
+    }
+
+    /// Connect to whichever of `endpoints` answers first, starting from the
+    /// one that last worked
+    pub async fn connect_to(config: &ClientConfig, endpoints: &EndpointSet, token: &str) -> Result<Self> {
+        let mut attempt = 0;
+        let ws_stream = loop {
+            match Self::open_any(config, endpoints, token).await {
+                Ok(stream) => break stream,
+                // HTTP errors (e.g. 401) won't go away by retrying
+                Err(e) if matches!(e, tungstenite::Error::Http(_))
+                    || attempt >= config.retry.max_retries =>
+                {
+                    return Err(e.into());
+                }
+                Err(e) => {
+                    log::warn!("WebSocket connect failed, retrying: {}", e);
+                    tokio::time::sleep(config.retry.backoff(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        };
         let (mut write, mut read) = ws_stream.split();
 
-        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let (tx, mut rx) = mpsc::unbounded_channel::<WsMessage>();
         let incoming = Arc::new(Mutex::new(VecDeque::new()));
         let connected = Arc::new(Mutex::new(true));
 
+        let signals = Arc::new(Mutex::new(VecDeque::new()));
+        let errors = Arc::new(Mutex::new(VecDeque::new()));
         let incoming_clone = incoming.clone();
+        let signals_clone = signals.clone();
+        let errors_clone = errors.clone();
+        let device_renames = Arc::new(Mutex::new(VecDeque::new()));
+        let device_renames_clone = device_renames.clone();
+        let announcements = Arc::new(Mutex::new(VecDeque::new()));
+        let announcements_clone = announcements.clone();
+        let maintenance = Arc::new(Mutex::new(VecDeque::new()));
+        let maintenance_clone = maintenance.clone();
+        let acks = Arc::new(Mutex::new(VecDeque::new()));
+        let acks_clone = acks.clone();
+        let deliveries = Arc::new(Mutex::new(VecDeque::new()));
+        let deliveries_clone = deliveries.clone();
+        let revoked = Arc::new(Mutex::new(None));
+        let revoked_clone = revoked.clone();
         let connected_clone = connected.clone();
 
-        // Send authentication
+        // Also authenticate with a frame for servers that predate upgrade auth
         let auth_msg = json!({
             "type": "authenticate",
             "payload": { "token": token }
@@ -211,14 +887,81 @@ impl WebSocketClient {
                 match msg {
                     Ok(WsMessage::Text(text)) => {
                         if let Ok(data) = serde_json::from_str::<serde_json::Value>(&text) {
-                            if data["type"] == "message" {
-                                if let Some(payload) = data.get("payload") {
+                            match data["type"].as_str() {
+                                Some("message") => {
                                     if let Ok(envelope) =
-                                        serde_json::from_value::<MessageEnvelope>(payload.clone())
+                                        serde_json::from_value::<MessageEnvelope>(data["payload"].clone())
                                     {
                                         incoming_clone.lock().push_back(envelope);
                                     }
                                 }
+                                Some("message_batch") => {
+                                    if let Ok(envelopes) = serde_json::from_value::<Vec<MessageEnvelope>>(
+                                        data["payload"]["messages"].clone(),
+                                    ) {
+                                        incoming_clone.lock().extend(envelopes);
+                                    }
+                                }
+                                Some("call_signal") => {
+                                    if let Ok(signal) =
+                                        serde_json::from_value::<CallSignal>(data["payload"].clone())
+                                    {
+                                        signals_clone.lock().push_back(signal);
+                                    }
+                                }
+                                Some("error") => {
+                                    if let Ok(error) =
+                                        serde_json::from_value::<ServerError>(data["payload"].clone())
+                                    {
+                                        log::warn!("Server rejected a frame: {}", error);
+                                        errors_clone.lock().push_back(error);
+                                    }
+                                }
+                                Some("device_renamed") => {
+                                    if let Ok(rename) =
+                                        serde_json::from_value::<DeviceRenamed>(data["payload"].clone())
+                                    {
+                                        device_renames_clone.lock().push_back(rename);
+                                    }
+                                }
+                                Some("announcement") => {
+                                    if let Ok(announcement) = serde_json::from_value::<Option<Announcement>>(
+                                        data["payload"]["announcement"].clone(),
+                                    ) {
+                                        announcements_clone.lock().push_back(announcement);
+                                    }
+                                }
+                                Some("maintenance") => {
+                                    if let Ok(maintenance) = serde_json::from_value::<Option<Maintenance>>(
+                                        data["payload"]["maintenance"].clone(),
+                                    ) {
+                                        maintenance_clone.lock().push_back(maintenance);
+                                    }
+                                }
+                                Some("ack") => {
+                                    if let Ok(ids) = serde_json::from_value::<Vec<String>>(
+                                        data["payload"]["message_ids"].clone(),
+                                    ) {
+                                        let now = chrono::Utc::now().timestamp_millis();
+                                        acks_clone.lock().extend(ids.into_iter().map(|id| (id, now)));
+                                    }
+                                }
+                                Some("delivered") => {
+                                    if let Ok(receipt) =
+                                        serde_json::from_value::<DeliveryReceipt>(data["payload"].clone())
+                                    {
+                                        deliveries_clone.lock().push_back(receipt);
+                                    }
+                                }
+                                Some("session_revoked") => {
+                                    let reason = serde_json::from_value::<RevocationReason>(
+                                        data["payload"]["reason"].clone(),
+                                    )
+                                    .unwrap_or(RevocationReason::Unknown);
+                                    log::warn!("Session revoked: {:?}", reason);
+                                    *revoked_clone.lock() = Some(reason);
+                                }
+                                _ => {}
                             }
                         }
                     }
@@ -235,10 +978,25 @@ impl WebSocketClient {
             }
         });
 
-        // Send task
+        // Send task, also pinging the server to keep idle connections open
+        let (keepalive, mut keepalive_rx) = tokio::sync::watch::channel(config.ws_keepalive);
         tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if write.send(WsMessage::Text(msg)).await.is_err() {
+            let mut ping = ping_interval(*keepalive_rx.borrow_and_update());
+
+            loop {
+                let frame = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    _ = next_ping(&mut ping) => WsMessage::Ping(Vec::new()),
+                    Ok(()) = keepalive_rx.changed() => {
+                        ping = ping_interval(*keepalive_rx.borrow_and_update());
+                        continue;
+                    }
+                };
+
+                if write.send(frame).await.is_err() {
                     break;
                 }
             }
@@ -246,19 +1004,103 @@ impl WebSocketClient {
 
         Ok(Self {
             sender: tx,
+            binary_envelopes: Arc::new(AtomicBool::new(config.binary_envelopes)),
             incoming,
+            signals,
+            errors,
+            device_renames,
+            announcements,
+            maintenance,
+            acks,
+            deliveries,
+            revoked,
             connected,
+            keepalive,
+            keepalive_intervals: (config.ws_keepalive, config.battery_keepalive),
         })
     }
 
+    /// Try each endpoint once, moving on while they can't be reached
+    async fn open_any(
+        config: &ClientConfig,
+        endpoints: &EndpointSet,
+        token: &str,
+    ) -> std::result::Result<WsStream, tungstenite::Error> {
+        let mut tried = 0;
+        loop {
+            let (index, endpoint) = endpoints.nth_from_current(tried);
+            let result = Self::open(config, endpoint, token).await;
+            tried += 1;
+
+            match result {
+                Ok(stream) => {
+                    endpoints.mark_working(index);
+                    return Ok(stream);
+                }
+                // The server answered; another endpoint would say the same
+                Err(e) if !matches!(e, tungstenite::Error::Http(_)) && tried < endpoints.len() => {
+                    log::warn!("Cannot reach {}: {}", endpoint.ws_url(), e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Open the connection, authenticating during the HTTP upgrade
+    async fn open(
+        config: &ClientConfig,
+        endpoint: &Endpoint,
+        token: &str,
+    ) -> std::result::Result<WsStream, tungstenite::Error> {
+        let mut request = endpoint.ws_url().into_client_request()?;
+        let headers = request.headers_mut();
+        headers.insert(
+            "Authorization",
+            format!("Bearer {}", token)
+                .parse()
+                .map_err(|e: tungstenite::http::header::InvalidHeaderValue| {
+                    tungstenite::Error::HttpFormat(e.into())
+                })?,
+        );
+        if let Ok(user_agent) = config.user_agent.parse() {
+            headers.insert("User-Agent", user_agent);
+        }
+
+        let connector = if config.certificate_pins.is_empty() {
+            None
+        } else {
+            Some(tls::ws_connector(&config.certificate_pins))
+        };
+
+        let handshake = async {
+            let stream = open_tcp(config, endpoint).await?;
+            let (ws_stream, _) = client_async_tls_with_config(request, stream, None, connector).await?;
+            Ok(ws_stream)
+        };
+
+        tokio::time::timeout(config.connect_timeout, handshake)
+            .await
+            .map_err(|_| {
+                tungstenite::Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "WebSocket connect timed out",
+                ))
+            })?
+    }
+
     pub async fn send_message(&self, envelope: &MessageEnvelope) -> Result<()> {
-        let msg = json!({
-            "type": "message",
-            "payload": envelope
-        });
+        let frame = if self.binary_envelopes.load(Ordering::Relaxed) {
+            WsMessage::Binary(CanonicalEnvelope::from(envelope).encode())
+        } else {
+            let msg = json!({
+                "type": "message",
+                "payload": envelope
+            });
+            WsMessage::Text(msg.to_string())
+        };
 
         self.sender
-            .send(msg.to_string())
+            .send(frame)
             .map_err(|e| Error::WebSocket(e.to_string()))?;
 
         Ok(())
@@ -274,7 +1116,20 @@ impl WebSocketClient {
         });
 
         self.sender
-            .send(msg.to_string())
+            .send(WsMessage::Text(msg.to_string()))
+            .map_err(|e| Error::WebSocket(e.to_string()))?;
+
+        Ok(())
+    }
+
+    pub async fn send_presence(&self, status: PresenceStatus) -> Result<()> {
+        let msg = json!({
+            "type": "presence",
+            "payload": { "status": status }
+        });
+
+        self.sender
+            .send(WsMessage::Text(msg.to_string()))
             .map_err(|e| Error::WebSocket(e.to_string()))?;
 
         Ok(())
@@ -287,7 +1142,7 @@ impl WebSocketClient {
         });
 
         self.sender
-            .send(msg.to_string())
+            .send(WsMessage::Text(msg.to_string()))
             .map_err(|e| Error::WebSocket(e.to_string()))?;
 
         Ok(())
@@ -304,12 +1159,309 @@ impl WebSocketClient {
         Ok(messages)
     }
 
+    pub async fn receive_call_signals(&self) -> Result<Vec<CallSignal>> {
+        Ok(self.signals.lock().drain(..).collect())
+    }
+
+    pub async fn receive_errors(&self) -> Result<Vec<ServerError>> {
+        Ok(self.errors.lock().drain(..).collect())
+    }
+
+    pub async fn receive_device_renames(&self) -> Result<Vec<DeviceRenamed>> {
+        Ok(self.device_renames.lock().drain(..).collect())
+    }
+
+    pub async fn receive_announcements(&self) -> Result<Vec<Option<Announcement>>> {
+        Ok(self.announcements.lock().drain(..).collect())
+    }
+
+    pub async fn receive_maintenance(&self) -> Result<Vec<Option<Maintenance>>> {
+        Ok(self.maintenance.lock().drain(..).collect())
+    }
+
+    pub async fn receive_acks(&self) -> Result<Vec<(String, i64)>> {
+        Ok(self.acks.lock().drain(..).collect())
+    }
+
+    pub async fn receive_deliveries(&self) -> Result<Vec<DeliveryReceipt>> {
+        Ok(self.deliveries.lock().drain(..).collect())
+    }
+
+    pub async fn send_ack(&self, message_ids: &[String]) -> Result<()> {
+        let msg = json!({
+            "type": "ack",
+            "payload": { "message_ids": message_ids }
+        });
+
+        self.sender
+            .send(WsMessage::Text(msg.to_string()))
+            .map_err(|e| Error::WebSocket(e.to_string()))?;
+
+        Ok(())
+    }
+
     pub fn is_connected(&self) -> bool {
         *self.connected.lock()
     }
 
+    pub fn revocation(&self) -> Option<RevocationReason> {
+        *self.revoked.lock()
+    }
+
+    /// Ping at the interval configured for `source`
+    pub fn set_power_source(&self, source: PowerSource) {
+        let interval = match source {
+            PowerSource::Mains => self.keepalive_intervals.0,
+            PowerSource::Battery => self.keepalive_intervals.1,
+        };
+        self.keepalive.send_if_modified(|current| std::mem::replace(current, interval) != interval);
+    }
+
     pub async fn disconnect(&self) -> Result<()> {
         *self.connected.lock() = false;
         Ok(())
     }
 }
+
+#[async_trait]
+impl WsTransport for WebSocketClient {
+    async fn send_message(&self, envelope: &MessageEnvelope) -> Result<()> {
+        WebSocketClient::send_message(self, envelope).await
+    }
+
+    async fn send_typing(&self, recipient_id: &str, is_typing: bool) -> Result<()> {
+        WebSocketClient::send_typing(self, recipient_id, is_typing).await
+    }
+
+    async fn send_presence(&self, status: PresenceStatus) -> Result<()> {
+        WebSocketClient::send_presence(self, status).await
+    }
+
+    async fn send_call_signal(&self, signal: &CallSignal) -> Result<()> {
+        WebSocketClient::send_call_signal(self, signal).await
+    }
+
+    async fn receive_messages(&self) -> Result<Vec<MessageEnvelope>> {
+        WebSocketClient::receive_messages(self).await
+    }
+
+    async fn receive_call_signals(&self) -> Result<Vec<CallSignal>> {
+        WebSocketClient::receive_call_signals(self).await
+    }
+
+    async fn receive_errors(&self) -> Result<Vec<ServerError>> {
+        WebSocketClient::receive_errors(self).await
+    }
+
+    async fn receive_device_renames(&self) -> Result<Vec<DeviceRenamed>> {
+        WebSocketClient::receive_device_renames(self).await
+    }
+
+    async fn receive_announcements(&self) -> Result<Vec<Option<Announcement>>> {
+        WebSocketClient::receive_announcements(self).await
+    }
+
+    async fn receive_maintenance(&self) -> Result<Vec<Option<Maintenance>>> {
+        WebSocketClient::receive_maintenance(self).await
+    }
+
+    async fn receive_acks(&self) -> Result<Vec<(String, i64)>> {
+        WebSocketClient::receive_acks(self).await
+    }
+
+    async fn receive_deliveries(&self) -> Result<Vec<DeliveryReceipt>> {
+        WebSocketClient::receive_deliveries(self).await
+    }
+
+    async fn send_ack(&self, message_ids: &[String]) -> Result<()> {
+        WebSocketClient::send_ack(self, message_ids).await
+    }
+
+    fn is_connected(&self) -> bool {
+        WebSocketClient::is_connected(self)
+    }
+
+    fn revocation(&self) -> Option<RevocationReason> {
+        WebSocketClient::revocation(self)
+    }
+
+    fn set_power_source(&self, source: PowerSource) {
+        WebSocketClient::set_power_source(self, source)
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        WebSocketClient::disconnect(self).await
+    }
+}
+
+/// Connects `WebSocketClient`s to the configured server
+pub struct DefaultWsConnector {
+    config: ClientConfig,
+    endpoints: Arc<EndpointSet>,
+    binary_envelopes: Arc<AtomicBool>,
+}
+
+impl DefaultWsConnector {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self::with_endpoints(config, Arc::new(EndpointSet::new(config)))
+    }
+
+    /// A connector that shares endpoint stickiness with other connections
+    pub fn with_endpoints(config: &ClientConfig, endpoints: Arc<EndpointSet>) -> Self {
+        Self {
+            config: config.clone(),
+            endpoints,
+            binary_envelopes: Arc::new(AtomicBool::new(config.binary_envelopes)),
+        }
+    }
+
+    /// Whether connections send binary envelopes. Changing it affects
+    /// open connections too.
+    pub fn binary_envelopes(&self) -> Arc<AtomicBool> {
+        self.binary_envelopes.clone()
+    }
+}
+
+#[async_trait]
+impl WsConnector for DefaultWsConnector {
+    async fn connect(&self, token: &str) -> Result<Box<dyn WsTransport>> {
+        let mut ws = WebSocketClient::connect_to(&self.config, &self.endpoints, token).await?;
+        ws.binary_envelopes = self.binary_envelopes.clone();
+        Ok(Box::new(ws))
+    }
+}
+
+/// Pings starting one `interval` from now
+fn ping_interval(interval: Option<Duration>) -> Option<tokio::time::Interval> {
+    interval.map(|interval| tokio::time::interval_at(tokio::time::Instant::now() + interval, interval))
+}
+
+/// Wait for the next keepalive tick, or forever if keepalive is off
+async fn next_ping(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Delay before racing the next address while earlier attempts are pending (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect with happy eyeballs: addresses alternate between IPv6 and IPv4 and
+/// each attempt starts 250ms after the previous one unless that one failed
+/// first. The first socket to connect wins and the rest are dropped.
+async fn connect_dual_stack(
+    host: &str,
+    port: u16,
+    keepalive: Option<Duration>,
+) -> std::io::Result<TcpStream> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        tokio::net::lookup_host((host, port)).await?.partition(|a| a.is_ipv6());
+
+    let mut addrs = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(TcpStream::connect(addr)),
+                None => break,
+            }
+        }
+
+        tokio::select! {
+            result = attempts.next() => match result {
+                Some(Ok(stream)) => {
+                    stream.set_nodelay(true)?;
+                    if let Some(interval) = keepalive {
+                        let keepalive = socket2::TcpKeepalive::new().with_time(interval);
+                        socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+                    }
+                    return Ok(stream);
+                }
+                // Start the next address right away instead of waiting out the delay
+                Some(Err(e)) => {
+                    last_error = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(TcpStream::connect(addr));
+                    }
+                }
+                None => {}
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(TcpStream::connect(addr));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, format!("No addresses for {}", host))
+    }))
+}
+
+/// Connect to `endpoint` directly or through an HTTP CONNECT proxy
+async fn open_tcp(config: &ClientConfig, endpoint: &Endpoint) -> std::io::Result<TcpStream> {
+    let target = format!("{}:{}", endpoint.host, endpoint.port);
+    let Some(ref proxy) = config.proxy else {
+        return connect_dual_stack(&endpoint.host, endpoint.port, config.tcp_keepalive).await;
+    };
+
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg.to_string());
+    let proxy = url::Url::parse(proxy).map_err(|_| invalid("Invalid proxy URL"))?;
+    let proxy_host = proxy.host_str().ok_or_else(|| invalid("Proxy URL has no host"))?;
+    let proxy_port = proxy.port_or_known_default().unwrap_or(8080);
+
+    let mut stream = connect_dual_stack(proxy_host, proxy_port, config.tcp_keepalive).await?;
+
+    let mut request = format!(
+        "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\nUser-Agent: {}\r\n",
+        config.user_agent
+    );
+    if !proxy.username().is_empty() {
+        let credentials = format!("{}:{}", proxy.username(), proxy.password().unwrap_or(""));
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the proxy's response headers; the tunnel starts right after them
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 || response.len() > 8192 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Bad proxy response",
+            ));
+        }
+        response.push(byte[0]);
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("Proxy refused CONNECT: {}", status),
+        ));
+    }
+
+    Ok(stream)
+}
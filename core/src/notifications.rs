@@ -0,0 +1,127 @@
+//! Notifications for incoming messages and calls
+//!
+//! The client decides what is worth a notification and what it may show;
+//! the application supplies a [`NotificationSink`] that puts it on screen.
+//! Without a sink nothing is shown.
+
+use crate::events::ClientEvent;
+use crate::models::{CallSignal, Message, MessageType, RuleAction};
+use crate::rules::RuleEngine;
+use crate::storage::LocalStorage;
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+pub use privmsg_proto::notification::{
+    Notification, NotificationKind, NotificationSink, PreviewPolicy, NOTIFICATION_PREVIEW_LEN,
+};
+
+/// Prints notifications to stdout, for command-line and headless clients
+pub struct PrintSink;
+
+impl NotificationSink for PrintSink {
+    fn notify(&self, notification: &Notification) {
+        println!("[{}] {}", notification.title(), notification.body());
+    }
+}
+
+pub(crate) struct Notifier {
+    storage: Arc<LocalStorage>,
+    rules: Arc<RuleEngine>,
+    sink: RwLock<Option<Arc<dyn NotificationSink>>>,
+    policy: RwLock<PreviewPolicy>,
+}
+
+impl Notifier {
+    pub(crate) fn new(storage: Arc<LocalStorage>, rules: Arc<RuleEngine>) -> Self {
+        Self {
+            storage,
+            rules,
+            sink: RwLock::new(None),
+            policy: RwLock::new(PreviewPolicy::default()),
+        }
+    }
+
+    pub(crate) fn set_sink(&self, sink: Option<Arc<dyn NotificationSink>>) {
+        *self.sink.write() = sink;
+    }
+
+    pub(crate) fn policy(&self) -> PreviewPolicy {
+        *self.policy.read()
+    }
+
+    pub(crate) fn set_policy(&self, policy: PreviewPolicy) {
+        *self.policy.write() = policy;
+    }
+
+    /// Show `event` if the user should hear about it. Every stored
+    /// incoming message passes here once, so message rules act on it first.
+    pub(crate) fn announce(&self, event: &ClientEvent) {
+        let highlight = match event {
+            ClientEvent::MessageReceived(message) => {
+                self.rules.apply(message).contains(&RuleAction::Highlight)
+            }
+            _ => false,
+        };
+        let Some(sink) = self.sink.read().clone() else {
+            return;
+        };
+        let notification = match event {
+            ClientEvent::MessageReceived(message) => self.for_message(message, highlight),
+            ClientEvent::CallSignal(signal) => self.for_call(signal),
+            _ => None,
+        };
+        if let Some(notification) = notification {
+            sink.notify(&notification);
+        }
+    }
+
+    /// A highlighted message notifies even in a muted conversation
+    fn for_message(&self, message: &Message, highlight: bool) -> Option<Notification> {
+        if message.is_outgoing || (!highlight && self.is_muted(&message.conversation_id)) {
+            return None;
+        }
+        let sender_name = self.sender_name(&message.sender_id);
+        let notification = Notification::message(
+            &message.conversation_id,
+            &message.sender_id,
+            sender_name.as_deref(),
+            &readable_text(message),
+            self.policy(),
+        );
+        Some(if highlight { notification.highlighted() } else { notification })
+    }
+
+    fn for_call(&self, signal: &CallSignal) -> Option<Notification> {
+        if signal.signal_type != "offer" || self.is_muted(&signal.sender_id) {
+            return None;
+        }
+        let sender_name = self.sender_name(&signal.sender_id);
+        Some(Notification::incoming_call(&signal.sender_id, sender_name.as_deref(), self.policy()))
+    }
+
+    fn is_muted(&self, conversation_id: &str) -> bool {
+        matches!(self.storage.get_conversation(conversation_id), Ok(Some(c)) if c.is_muted)
+    }
+
+    fn sender_name(&self, user_id: &str) -> Option<String> {
+        self.storage.get_user(user_id).ok().flatten()?.display_name
+    }
+}
+
+/// What a message says, for its preview; attachments without a caption
+/// are named by kind
+fn readable_text(message: &Message) -> String {
+    if message.message_type == MessageType::Text {
+        return message.content.clone();
+    }
+    if let Some(caption) = message.caption.as_ref().filter(|c| !c.is_empty()) {
+        return caption.clone();
+    }
+    match message.message_type {
+        MessageType::Voice => "Voice message",
+        MessageType::Video => "Video",
+        MessageType::Image => "Photo",
+        _ => "File",
+    }
+    .to_string()
+}
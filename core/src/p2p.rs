@@ -0,0 +1,513 @@
+//! Direct attachment delivery over WebRTC data channels
+//!
+//! Relaying a large attachment through the server costs upload time and
+//! server storage. When both sides allow it, the sender instead offers a
+//! data channel through the call-signal relay and streams the already
+//! encrypted file straight to the recipient. The attachment message is only
+//! sent once the recipient has checked the file's hash; anything that goes
+//! wrong before that falls back to the normal upload.
+//!
+//! Negotiation reuses the server's existing signal types (`offer`, `answer`,
+//! `rejected`) and marks the payload with `"kind": "file_transfer"` so file
+//! offers are never mistaken for calls.
+
+use crate::error::{Error, Result};
+use crate::events::{ClientEvent, EventSender};
+use crate::models::CallSignal;
+use crate::transport::{ApiTransport, WsTransport};
+use bytes::Bytes;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot, Notify};
+use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+/// Attachment file ids for directly delivered files start with this
+const FILE_ID_PREFIX: &str = "p2p:";
+
+const SIGNAL_KIND: &str = "file_transfer";
+const CHANNEL_LABEL: &str = "file";
+
+/// Data channel messages are limited to 16 KiB by the receiving side
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Stop queueing chunks while this much is waiting to be sent
+const MAX_BUFFERED: usize = 1024 * 1024;
+const LOW_BUFFERED: usize = 256 * 1024;
+
+/// Time allowed for the recipient to answer and the channel to open
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Time allowed without any progress once the transfer is running
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the signal pump checks the connection for new signals
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const END_OF_FILE: &str = "end";
+const RECEIVED_OK: &str = "ok";
+const RECEIVED_BAD: &str = "bad";
+
+/// Payload of a file transfer signal
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TransferPayload {
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sdp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+impl TransferPayload {
+    fn new() -> Self {
+        Self {
+            kind: SIGNAL_KIND.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Parse a signal payload, or `None` if the signal belongs to a call
+    fn parse(signal: &CallSignal) -> Option<Self> {
+        serde_json::from_str::<Self>(&signal.payload)
+            .ok()
+            .filter(|payload| payload.kind == SIGNAL_KIND)
+    }
+}
+
+/// The transfer id of an attachment that was delivered directly
+pub(crate) fn transfer_id(file_id: &str) -> Option<&str> {
+    file_id.strip_prefix(FILE_ID_PREFIX)
+}
+
+fn direct_error(e: impl std::fmt::Display) -> Error {
+    Error::DirectTransfer(e.to_string())
+}
+
+/// An accepted offer, ready for the answer to be sent
+struct Answered {
+    pc: RTCPeerConnection,
+    /// The sender's data channel, once it opens
+    channels: mpsc::UnboundedReceiver<Arc<RTCDataChannel>>,
+    sdp: String,
+    path: PathBuf,
+    size: u64,
+    sha256: String,
+}
+
+/// Sends and receives attachments over data channels
+pub(crate) struct DirectTransfers {
+    api: Arc<dyn ApiTransport>,
+    /// Smallest encrypted file worth sending directly; `None` disables
+    /// direct transfers in both directions
+    min_size: RwLock<Option<u64>>,
+    /// Where received files are kept. There is no server copy to fetch
+    /// again, so unlike downloads they outlive the first save.
+    dir: PathBuf,
+    /// Outgoing offers waiting for the recipient's answer, by transfer id
+    pending: Mutex<HashMap<String, oneshot::Sender<CallSignal>>>,
+    events: EventSender,
+}
+
+impl DirectTransfers {
+    pub(crate) fn new(
+        api: Arc<dyn ApiTransport>,
+        dir: PathBuf,
+        events: EventSender,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            api,
+            min_size: RwLock::new(None),
+            dir,
+            pending: Mutex::new(HashMap::new()),
+            events,
+        })
+    }
+
+    pub(crate) fn min_size(&self) -> Option<u64> {
+        *self.min_size.read()
+    }
+
+    pub(crate) fn set_min_size(&self, min_size: Option<u64>) {
+        *self.min_size.write() = min_size;
+    }
+
+    /// Where the encrypted file for a direct transfer is (or will be) stored
+    pub(crate) fn received_path(&self, transfer_id: &str) -> Result<PathBuf> {
+        // The id comes from the peer, so never let it name another path
+        let id = uuid::Uuid::parse_str(transfer_id)
+            .map_err(|_| Error::TransferNotFound(transfer_id.to_string()))?;
+        Ok(self.dir.join(id.to_string()))
+    }
+
+    /// Route incoming signals: file transfer signals are handled here and
+    /// everything else is passed on to the application as an event
+    pub(crate) fn spawn_signal_pump(
+        self: &Arc<Self>,
+        handle: &Handle,
+        ws: Arc<RwLock<Option<Arc<dyn WsTransport>>>>,
+    ) {
+        let this = self.clone();
+        handle.spawn(async move {
+            let mut interval = tokio::time::interval(SIGNAL_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let ws = ws.read().clone();
+                if let Some(ws) = ws {
+                    this.dispatch_signals(&ws).await;
+                }
+            }
+        });
+    }
+
+    /// Handle every call signal waiting on the connection. Besides the pump,
+    /// message polling calls this first so a ringing call reaches the call
+    /// UI before the message backlog is worked through.
+    pub(crate) async fn dispatch_signals(self: &Arc<Self>, ws: &Arc<dyn WsTransport>) {
+        let signals = match ws.receive_call_signals().await {
+            Ok(signals) => signals,
+            Err(e) => {
+                log::warn!("Failed to receive call signals: {}", e);
+                return;
+            }
+        };
+        for signal in signals {
+            self.handle_signal(ws, signal);
+        }
+    }
+
+    fn handle_signal(self: &Arc<Self>, ws: &Arc<dyn WsTransport>, signal: CallSignal) {
+        let Some(payload) = TransferPayload::parse(&signal) else {
+            self.events.send(ClientEvent::CallSignal(signal));
+            return;
+        };
+
+        match signal.signal_type.as_str() {
+            "offer" => {
+                let this = self.clone();
+                let ws = ws.clone();
+                tokio::spawn(async move { this.accept(ws, signal, payload).await });
+            }
+            "answer" | "rejected" => {
+                if let Some(waiting) = self.pending.lock().remove(&signal.call_id) {
+                    let _ = waiting.send(signal);
+                }
+            }
+            other => log::debug!("Ignoring file transfer signal {}", other),
+        }
+    }
+
+    // ========================================================================
+    // Sending
+    // ========================================================================
+
+    /// Stream an encrypted file to the recipient and return the attachment
+    /// file id once they have confirmed it arrived intact
+    pub(crate) async fn send(
+        &self,
+        ws: &dyn WsTransport,
+        sender_id: &str,
+        recipient_id: &str,
+        path: &Path,
+        sha256: &str,
+    ) -> Result<String> {
+        let pc = self.peer_connection().await?;
+        let transfer_id = uuid::Uuid::new_v4().to_string();
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.pending.lock().insert(transfer_id.clone(), answer_tx);
+
+        let signal = CallSignal {
+            call_id: transfer_id.clone(),
+            sender_id: sender_id.to_string(),
+            recipient_id: recipient_id.to_string(),
+            signal_type: "offer".to_string(),
+            payload: String::new(),
+        };
+        let result = Self::offer_and_stream(&pc, ws, signal, answer_rx, path, sha256).await;
+
+        self.pending.lock().remove(&transfer_id);
+        let _ = pc.close().await;
+        result.map(|_| format!("{}{}", FILE_ID_PREFIX, transfer_id))
+    }
+
+    async fn offer_and_stream(
+        pc: &RTCPeerConnection,
+        ws: &dyn WsTransport,
+        mut signal: CallSignal,
+        answer_rx: oneshot::Receiver<CallSignal>,
+        path: &Path,
+        sha256: &str,
+    ) -> Result<()> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        let dc = pc
+            .create_data_channel(CHANNEL_LABEL, None)
+            .await
+            .map_err(direct_error)?;
+        let (open_tx, open_rx) = oneshot::channel();
+        dc.on_open(Box::new(move || {
+            let _ = open_tx.send(());
+            Box::pin(async {})
+        }));
+        let mut replies = forward_messages(&dc);
+
+        let drained = Arc::new(Notify::new());
+        dc.set_buffered_amount_low_threshold(LOW_BUFFERED).await;
+        let notify = drained.clone();
+        dc.on_buffered_amount_low(Box::new(move || {
+            notify.notify_one();
+            Box::pin(async {})
+        }))
+        .await;
+
+        let offer = pc.create_offer(None).await.map_err(direct_error)?;
+        let sdp = local_sdp(pc, offer).await?;
+
+        let mut payload = TransferPayload::new();
+        payload.sdp = Some(sdp);
+        payload.size = Some(size);
+        payload.sha256 = Some(sha256.to_string());
+        signal.payload = serde_json::to_string(&payload)?;
+        ws.send_call_signal(&signal).await?;
+
+        let connected = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let answer = answer_rx
+                .await
+                .map_err(|_| direct_error("No answer from recipient"))?;
+            let sdp = match (answer.signal_type.as_str(), TransferPayload::parse(&answer)) {
+                ("answer", Some(TransferPayload { sdp: Some(sdp), .. })) => sdp,
+                _ => return Err(direct_error("Recipient declined")),
+            };
+            let answer = RTCSessionDescription::answer(sdp).map_err(direct_error)?;
+            pc.set_remote_description(answer).await.map_err(direct_error)?;
+            open_rx
+                .await
+                .map_err(|_| direct_error("Data channel closed before opening"))
+        })
+        .await;
+        match connected {
+            Ok(result) => result?,
+            Err(_) => return Err(direct_error("Timed out connecting to recipient")),
+        }
+
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            while dc.buffered_amount().await > MAX_BUFFERED {
+                tokio::time::timeout(STALL_TIMEOUT, drained.notified())
+                    .await
+                    .map_err(|_| direct_error("Transfer stalled"))?;
+            }
+            dc.send(&Bytes::copy_from_slice(&buf[..n]))
+                .await
+                .map_err(direct_error)?;
+        }
+        dc.send_text(END_OF_FILE).await.map_err(direct_error)?;
+
+        // The recipient only confirms after checking the size and hash
+        match tokio::time::timeout(STALL_TIMEOUT, replies.recv()).await {
+            Ok(Some(reply)) if reply.is_string && reply.data == RECEIVED_OK.as_bytes() => Ok(()),
+            Ok(_) => Err(direct_error("Recipient rejected the file")),
+            Err(_) => Err(direct_error("No confirmation from recipient")),
+        }
+    }
+
+    // ========================================================================
+    // Receiving
+    // ========================================================================
+
+    async fn accept(&self, ws: Arc<dyn WsTransport>, offer: CallSignal, payload: TransferPayload) {
+        let mut reply = CallSignal {
+            call_id: offer.call_id.clone(),
+            sender_id: offer.recipient_id.clone(),
+            recipient_id: offer.sender_id.clone(),
+            signal_type: "rejected".to_string(),
+            payload: serde_json::to_string(&TransferPayload::new()).unwrap_or_default(),
+        };
+
+        let mut answered = match self.answer(&offer, payload).await {
+            Ok(answered) => answered,
+            Err(e) => {
+                log::info!("Declining direct transfer from {}: {}", offer.sender_id, e);
+                let _ = ws.send_call_signal(&reply).await;
+                return;
+            }
+        };
+
+        let mut payload = TransferPayload::new();
+        payload.sdp = Some(answered.sdp.clone());
+        reply.signal_type = "answer".to_string();
+        reply.payload = serde_json::to_string(&payload).unwrap_or_default();
+
+        let result = match ws.send_call_signal(&reply).await {
+            Ok(()) => {
+                let Answered { channels, path, size, sha256, .. } = &mut answered;
+                Self::receive(channels, path, *size, sha256).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::warn!("Direct transfer from {} failed: {}", offer.sender_id, e);
+            let _ = tokio::fs::remove_file(&answered.path).await;
+        }
+        let _ = answered.pc.close().await;
+    }
+
+    /// Check the offer and create the answer for it
+    async fn answer(&self, offer: &CallSignal, payload: TransferPayload) -> Result<Answered> {
+        if self.min_size().is_none() {
+            return Err(direct_error("Direct transfers are disabled"));
+        }
+        let (Some(sdp), Some(size), Some(sha256)) = (payload.sdp, payload.size, payload.sha256)
+        else {
+            return Err(direct_error("Incomplete offer"));
+        };
+        let path = self.received_path(&offer.call_id)?;
+
+        let pc = self.peer_connection().await?;
+        let (channel_tx, channel_rx) = mpsc::unbounded_channel();
+        pc.on_data_channel(Box::new(move |dc| {
+            let _ = channel_tx.send(dc);
+            Box::pin(async {})
+        }));
+
+        let offer = RTCSessionDescription::offer(sdp).map_err(direct_error)?;
+        pc.set_remote_description(offer).await.map_err(direct_error)?;
+        let answer = pc.create_answer(None).await.map_err(direct_error)?;
+        let sdp = local_sdp(&pc, answer).await?;
+
+        Ok(Answered {
+            pc,
+            channels: channel_rx,
+            sdp,
+            path,
+            size,
+            sha256,
+        })
+    }
+
+    /// Write the incoming stream to `path` and confirm it once it matches
+    /// the offered size and hash
+    async fn receive(
+        channels: &mut mpsc::UnboundedReceiver<Arc<RTCDataChannel>>,
+        path: &Path,
+        size: u64,
+        sha256: &str,
+    ) -> Result<()> {
+        let dc = tokio::time::timeout(CONNECT_TIMEOUT, channels.recv())
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(|| direct_error("Timed out waiting for data channel"))?;
+        let mut chunks = forward_messages(&dc);
+        let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+        dc.on_close(Box::new(move || {
+            let _ = closed_tx.send(());
+            Box::pin(async {})
+        }));
+
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(path).await?);
+        let mut hasher = Sha256::new();
+        let mut received = 0u64;
+        loop {
+            let chunk = tokio::time::timeout(STALL_TIMEOUT, chunks.recv())
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| direct_error("Transfer stalled"))?;
+            if chunk.is_string {
+                if chunk.data == END_OF_FILE.as_bytes() {
+                    break;
+                }
+                continue;
+            }
+            received += chunk.data.len() as u64;
+            if received > size {
+                break;
+            }
+            hasher.update(&chunk.data);
+            file.write_all(&chunk.data).await?;
+        }
+        file.flush().await?;
+
+        let intact = received == size && hex::encode(hasher.finalize()) == sha256;
+        let reply = if intact { RECEIVED_OK } else { RECEIVED_BAD };
+        dc.send_text(reply).await.map_err(direct_error)?;
+
+        // Give the confirmation time to reach the sender, who hangs up
+        let _ = tokio::time::timeout(STALL_TIMEOUT, closed_rx.recv()).await;
+
+        if intact {
+            Ok(())
+        } else {
+            Err(direct_error("File did not match the offer"))
+        }
+    }
+
+    // ========================================================================
+    // Connection setup
+    // ========================================================================
+
+    async fn peer_connection(&self) -> Result<RTCPeerConnection> {
+        // Without TURN, peers on the same network can still reach each other
+        let ice_servers = match self.api.get_turn_credentials().await {
+            Ok(turn) => vec![RTCIceServer {
+                urls: turn.urls,
+                username: turn.username,
+                credential: turn.credential,
+                ..Default::default()
+            }],
+            Err(e) => {
+                log::debug!("No TURN credentials for direct transfer: {}", e);
+                Vec::new()
+            }
+        };
+
+        APIBuilder::new()
+            .build()
+            .new_peer_connection(RTCConfiguration {
+                ice_servers,
+                ..Default::default()
+            })
+            .await
+            .map_err(direct_error)
+    }
+}
+
+/// Apply a local description and return its SDP once candidate gathering
+/// is complete, so no trickle ICE signals are needed
+async fn local_sdp(pc: &RTCPeerConnection, desc: RTCSessionDescription) -> Result<String> {
+    let mut gathered = pc.gathering_complete_promise().await;
+    pc.set_local_description(desc).await.map_err(direct_error)?;
+    let _ = tokio::time::timeout(CONNECT_TIMEOUT, gathered.recv()).await;
+
+    pc.local_description()
+        .await
+        .map(|desc| desc.sdp)
+        .ok_or_else(|| direct_error("No local description"))
+}
+
+fn forward_messages(dc: &RTCDataChannel) -> mpsc::UnboundedReceiver<DataChannelMessage> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    dc.on_message(Box::new(move |msg| {
+        let _ = tx.send(msg);
+        Box::pin(async {})
+    }));
+    rx
+}
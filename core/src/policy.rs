@@ -0,0 +1,133 @@
+//! Client policy set by the server operator
+//!
+//! The policy arrives signed (see [`privmsg_proto::policy`]). The signing
+//! key comes from `ClientConfig::policy_key` or is pinned the first time a
+//! policy is seen; after that, policies signed by another key and versions
+//! older than the current one are rejected. The verified document is kept in
+//! local storage and checked again on startup.
+
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use parking_lot::RwLock;
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::error::{Error, Result};
+use crate::models::{ClientPolicy, Feature, PolicySetting, SignedPolicy};
+use crate::storage::LocalStorage;
+
+const KEY_SETTING: &str = "policy_public_key";
+const POLICY_SETTING: &str = "client_policy";
+
+pub(crate) struct PolicyStore {
+    storage: Arc<LocalStorage>,
+    current: RwLock<Option<ClientPolicy>>,
+}
+
+impl PolicyStore {
+    pub(crate) fn new(storage: Arc<LocalStorage>) -> Self {
+        let current = load(&storage);
+        Self {
+            storage,
+            current: RwLock::new(current),
+        }
+    }
+
+    /// Trust only policies signed with `public_key` (standard base64). A
+    /// policy pinned earlier under another key is dropped.
+    pub(crate) fn pin_key(&self, public_key: &str) -> Result<()> {
+        if self.storage.get_setting(KEY_SETTING).as_deref() == Some(public_key) {
+            return Ok(());
+        }
+        self.storage.save_setting(KEY_SETTING, public_key)?;
+        self.storage.delete_setting(POLICY_SETTING)?;
+        *self.current.write() = None;
+        Ok(())
+    }
+
+    pub(crate) fn current(&self) -> Option<ClientPolicy> {
+        self.current.read().clone()
+    }
+
+    pub(crate) fn allows(&self, feature: Feature) -> bool {
+        self.current.read().as_ref().is_none_or(|p| p.allows(feature))
+    }
+
+    pub(crate) fn is_locked(&self, setting: PolicySetting) -> bool {
+        self.current.read().as_ref().is_some_and(|p| p.is_locked(setting))
+    }
+
+    /// `Err(Error::Policy)` if the policy switched `feature` off
+    pub(crate) fn check(&self, feature: Feature) -> Result<()> {
+        if self.allows(feature) {
+            Ok(())
+        } else {
+            Err(Error::Policy(format!("{:?} is disabled", feature)))
+        }
+    }
+
+    /// `Err(Error::Policy)` if the policy locked `setting`
+    pub(crate) fn check_unlocked(&self, setting: PolicySetting) -> Result<()> {
+        if self.is_locked(setting) {
+            Err(Error::Policy(format!("{} is set by the server", setting.label())))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Verify and store a policy from the server. Returns it if it is newer
+    /// than the current one, `None` if nothing changed.
+    pub(crate) fn update(&self, signed: &SignedPolicy) -> Result<Option<ClientPolicy>> {
+        let pinned = self.storage.get_setting(KEY_SETTING);
+        let policy = verify(pinned.as_deref().unwrap_or(&signed.public_key), signed)?;
+        if pinned.is_none() {
+            log::info!("Pinning client policy key {}", signed.public_key);
+            self.storage.save_setting(KEY_SETTING, &signed.public_key)?;
+        }
+
+        let mut current = self.current.write();
+        if let Some(ref current) = *current {
+            if policy.version < current.version {
+                return Err(Error::Policy(format!(
+                    "server sent version {} after {}",
+                    policy.version, current.version
+                )));
+            }
+            if policy.version == current.version {
+                return Ok(None);
+            }
+        }
+        self.storage
+            .save_setting(POLICY_SETTING, &serde_json::to_string(signed)?)?;
+        *current = Some(policy.clone());
+        Ok(Some(policy))
+    }
+}
+
+/// The stored policy, if it still verifies against the pinned key
+fn load(storage: &LocalStorage) -> Option<ClientPolicy> {
+    let key = storage.get_setting(KEY_SETTING)?;
+    let json = storage.get_setting(POLICY_SETTING)?;
+    serde_json::from_str::<SignedPolicy>(&json)
+        .map_err(Error::from)
+        .and_then(|signed| verify(&key, &signed))
+        .inspect_err(|e| log::warn!("Dropping stored client policy: {}", e))
+        .ok()
+}
+
+/// Check the signature against `public_key` and parse the policy
+fn verify(public_key: &str, signed: &SignedPolicy) -> Result<ClientPolicy> {
+    if signed.public_key != public_key {
+        return Err(Error::Crypto("Client policy signed by an unknown key".into()));
+    }
+    let public_key = STANDARD
+        .decode(public_key)
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    let signature = STANDARD
+        .decode(&signed.signature)
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    UnparsedPublicKey::new(&ED25519, &public_key)
+        .verify(&signed.signing_bytes(), &signature)
+        .map_err(|_| Error::Crypto("Bad client policy signature".into()))?;
+    Ok(serde_json::from_str(&signed.policy)?)
+}
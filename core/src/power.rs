@@ -0,0 +1,54 @@
+//! Power saving on battery
+//!
+//! Frontends report the power source from the platform's power monitor.
+//! On battery the WebSocket is pinged every `battery_keepalive` instead of
+//! every `ws_keepalive`, presence changes are held back and read positions
+//! wait before going to the user's other devices. The latest of each goes
+//! out once the device is back on mains power.
+
+use std::collections::HashMap;
+
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use crate::models::ReadPosition;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerSource {
+    #[default]
+    Mains,
+    Battery,
+}
+
+#[derive(Default)]
+pub(crate) struct PowerState {
+    source: RwLock<PowerSource>,
+    /// Read positions held while on battery, the latest per conversation
+    deferred_reads: Mutex<HashMap<String, ReadPosition>>,
+}
+
+impl PowerState {
+    pub(crate) fn source(&self) -> PowerSource {
+        *self.source.read()
+    }
+
+    pub(crate) fn on_battery(&self) -> bool {
+        self.source() == PowerSource::Battery
+    }
+
+    /// Returns the previous source
+    pub(crate) fn set_source(&self, source: PowerSource) -> PowerSource {
+        std::mem::replace(&mut *self.source.write(), source)
+    }
+
+    pub(crate) fn defer_read(&self, position: &ReadPosition) {
+        self.deferred_reads
+            .lock()
+            .insert(position.conversation_id.clone(), position.clone());
+    }
+
+    pub(crate) fn take_deferred_reads(&self) -> Vec<ReadPosition> {
+        self.deferred_reads.lock().drain().map(|(_, position)| position).collect()
+    }
+}
@@ -0,0 +1,267 @@
+//! Throttling of typing indicators and presence updates
+//!
+//! Frontends call `send_typing` on every keystroke and `set_presence` on
+//! every focus change. Only what the recipient would notice goes over the
+//! WebSocket: "typing" once per `typing_interval`, "stopped typing" once
+//! the user goes idle, and the latest presence per `presence_interval`.
+//! While paused on battery, presence is held and only the latest goes out
+//! once the device is back on mains.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use parking_lot::{Mutex, RwLock};
+use tokio::runtime::Handle;
+
+use crate::config::PresencePolicy;
+use crate::error::Result;
+use crate::models::PresenceStatus;
+use crate::transport::WsTransport;
+
+type SharedWs = Arc<RwLock<Option<Arc<dyn WsTransport>>>>;
+
+/// Typing towards one recipient that was announced and not yet cleared
+struct Typing {
+    last_sent: Instant,
+    last_keystroke: Instant,
+}
+
+#[derive(Default)]
+struct Presence {
+    last_sent: Option<(PresenceStatus, Instant)>,
+    /// Waiting for the current window to end
+    pending: Option<PresenceStatus>,
+    /// Hold presence instead of sending it
+    paused: bool,
+    /// The latest presence set while paused
+    held: Option<PresenceStatus>,
+}
+
+pub(crate) struct ActivityThrottle {
+    ws: SharedWs,
+    runtime: Handle,
+    policy: Arc<RwLock<PresencePolicy>>,
+    typing: Arc<Mutex<HashMap<String, Typing>>>,
+    presence: Arc<Mutex<Presence>>,
+}
+
+impl ActivityThrottle {
+    pub(crate) fn new(runtime: Handle, ws: SharedWs) -> Self {
+        Self {
+            ws,
+            runtime,
+            policy: Arc::new(RwLock::new(PresencePolicy::default())),
+            typing: Arc::new(Mutex::new(HashMap::new())),
+            presence: Arc::new(Mutex::new(Presence::default())),
+        }
+    }
+
+    pub(crate) fn set_policy(&self, policy: PresencePolicy) {
+        *self.policy.write() = policy;
+    }
+
+    /// Forget what was sent; a new connection starts with a clean slate on
+    /// the server too
+    pub(crate) fn reset(&self) {
+        self.typing.lock().clear();
+        let mut presence = self.presence.lock();
+        presence.last_sent = None;
+        presence.pending = None;
+    }
+
+    /// Hold presence updates while `paused`; resuming sends the latest one
+    pub(crate) async fn set_paused(&self, paused: bool) -> Result<()> {
+        let held = {
+            let mut presence = self.presence.lock();
+            presence.paused = paused;
+            if paused {
+                return Ok(());
+            }
+            presence.held.take()
+        };
+        match held {
+            Some(status) => self.presence(status).await,
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) async fn typing(&self, recipient_id: &str, is_typing: bool) -> Result<()> {
+        let now = Instant::now();
+        let policy = self.policy.read().clone();
+
+        if !is_typing {
+            // Only a recipient that saw "typing" needs to hear it stopped
+            let announced = self.typing.lock().remove(recipient_id).is_some();
+            if announced {
+                return send_typing(&self.ws, recipient_id, false).await;
+            }
+            return Ok(());
+        }
+
+        let (send, start_timer) = {
+            let mut typing = self.typing.lock();
+            match typing.get_mut(recipient_id) {
+                Some(state) => {
+                    state.last_keystroke = now;
+                    let due = now.duration_since(state.last_sent) >= policy.typing_interval;
+                    if due {
+                        state.last_sent = now;
+                    }
+                    (due, false)
+                }
+                None => {
+                    typing.insert(
+                        recipient_id.to_string(),
+                        Typing {
+                            last_sent: now,
+                            last_keystroke: now,
+                        },
+                    );
+                    (true, true)
+                }
+            }
+        };
+
+        if send {
+            if let Err(e) = send_typing(&self.ws, recipient_id, true).await {
+                self.typing.lock().remove(recipient_id);
+                return Err(e);
+            }
+        }
+        if start_timer {
+            self.spawn_idle_timer(recipient_id.to_string());
+        }
+        Ok(())
+    }
+
+    /// Clear "typing" once no keystroke came for `typing_idle_timeout`.
+    /// One timer per recipient runs while they see the user typing.
+    fn spawn_idle_timer(&self, recipient_id: String) {
+        let typing = self.typing.clone();
+        let policy = self.policy.clone();
+        let ws = self.ws.clone();
+
+        self.runtime.spawn(async move {
+            loop {
+                let idle_timeout = policy.read().typing_idle_timeout;
+                let deadline = {
+                    let mut states = typing.lock();
+                    let Some(state) = states.get(&recipient_id) else {
+                        // Cleared explicitly in the meantime
+                        return;
+                    };
+                    let deadline = state.last_keystroke + idle_timeout;
+                    if deadline <= Instant::now() {
+                        states.remove(&recipient_id);
+                        None
+                    } else {
+                        Some(deadline)
+                    }
+                };
+
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => {
+                        let ws = ws.read().clone();
+                        if let Some(ws) = ws {
+                            if let Err(e) = ws.send_typing(&recipient_id, false).await {
+                                log::debug!("Clearing typing indicator failed: {}", e);
+                            }
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    pub(crate) async fn presence(&self, status: PresenceStatus) -> Result<()> {
+        let interval = self.policy.read().presence_interval;
+        let now = Instant::now();
+
+        let flush_at = {
+            let mut presence = self.presence.lock();
+            if presence.paused {
+                presence.held = Some(status);
+                return Ok(());
+            }
+            match presence.last_sent {
+                // A flush is already scheduled; it sends whatever is latest
+                Some(_) if presence.pending.is_some() => {
+                    presence.pending = Some(status);
+                    return Ok(());
+                }
+                Some((sent, _)) if sent == status => return Ok(()),
+                Some((_, at)) if now.duration_since(at) < interval => {
+                    presence.pending = Some(status);
+                    Some(at + interval)
+                }
+                _ => {
+                    presence.last_sent = Some((status, now));
+                    None
+                }
+            }
+        };
+        if let Some(flush_at) = flush_at {
+            self.spawn_presence_flush(flush_at);
+            return Ok(());
+        }
+
+        if let Err(e) = send_presence(&self.ws, status).await {
+            self.presence.lock().last_sent = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Send the latest pending presence at `flush_at`, unless it ended up
+    /// the same as what contacts already see
+    fn spawn_presence_flush(&self, flush_at: Instant) {
+        let presence = self.presence.clone();
+        let ws = self.ws.clone();
+
+        self.runtime.spawn(async move {
+            tokio::time::sleep_until(flush_at.into()).await;
+            let status = {
+                let mut presence = presence.lock();
+                let Some(status) = presence.pending.take() else {
+                    return;
+                };
+                if presence.paused {
+                    presence.held = Some(status);
+                    return;
+                }
+                if presence.last_sent.is_some_and(|(sent, _)| sent == status) {
+                    return;
+                }
+                presence.last_sent = Some((status, Instant::now()));
+                status
+            };
+
+            let ws = ws.read().clone();
+            if let Some(ws) = ws {
+                if let Err(e) = ws.send_presence(status).await {
+                    log::debug!("Sending presence failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Dropped while the WebSocket is down
+async fn send_typing(ws: &SharedWs, recipient_id: &str, is_typing: bool) -> Result<()> {
+    let ws = ws.read().clone();
+    match ws {
+        Some(ws) => ws.send_typing(recipient_id, is_typing).await,
+        None => Ok(()),
+    }
+}
+
+async fn send_presence(ws: &SharedWs, status: PresenceStatus) -> Result<()> {
+    let ws = ws.read().clone();
+    match ws {
+        Some(ws) => ws.send_presence(status).await,
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,183 @@
+//! Conversation names and avatars kept in step with peers' profiles
+//!
+//! A conversation started from a bare user id has no name until the peer
+//! sets one, and keeps a stale one after they change it. A background job
+//! re-fetches a few profiles at a time, those refreshed longest ago first,
+//! and a received message queues its sender too. Changed names and avatars
+//! are written to the conversation and announced with
+//! `ClientEvent::ConversationProfileChanged`. The cached identity key is
+//! left as it was, so `get_user_profile` still notices a key change.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, RwLock};
+use tokio::runtime::Handle;
+
+use crate::cache::{CacheLimits, CacheStats, LruCache};
+use crate::error::Result;
+use crate::events::{ClientEvent, EventSender};
+use crate::storage::LocalStorage;
+use crate::transport::ApiTransport;
+
+/// How often the background job looks for profiles due a refresh
+const REFRESH_TICK: Duration = Duration::from_secs(5 * 60);
+
+/// Profiles fetched per tick, so large accounts spread the requests out
+const REFRESH_BATCH: usize = 50;
+
+/// A sender's profile is fetched again on receipt at most this often
+const RECEIPT_REFRESH_MIN: Duration = Duration::from_secs(10 * 60);
+
+/// Default age at which the background job refreshes a profile
+pub(crate) const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+pub(crate) struct ProfileRefresher {
+    api: Arc<dyn ApiTransport>,
+    storage: Arc<LocalStorage>,
+    event_sender: EventSender,
+    /// When each peer's profile was last fetched; peers evicted from it
+    /// count as never fetched
+    refreshed: Mutex<LruCache<String, Instant>>,
+    /// `None` stops the background job
+    interval: RwLock<Option<Duration>>,
+}
+
+impl ProfileRefresher {
+    pub(crate) fn new(
+        api: Arc<dyn ApiTransport>,
+        storage: Arc<LocalStorage>,
+        event_sender: EventSender,
+    ) -> Self {
+        Self {
+            api,
+            storage,
+            event_sender,
+            refreshed: Mutex::new(LruCache::new(CacheLimits::default().profiles)),
+            interval: RwLock::new(Some(DEFAULT_REFRESH_INTERVAL)),
+        }
+    }
+
+    pub(crate) fn set_interval(&self, interval: Option<Duration>) {
+        *self.interval.write() = interval;
+    }
+
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        self.refreshed.lock().set_capacity(capacity);
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.refreshed.lock().stats()
+    }
+
+    pub(crate) fn spawn(self: &Arc<Self>, handle: &Handle) {
+        let this = self.clone();
+        handle.spawn(async move {
+            let mut tick = tokio::time::interval(REFRESH_TICK);
+            tick.tick().await;
+            loop {
+                tick.tick().await;
+                let Some(interval) = *this.interval.read() else {
+                    continue;
+                };
+                if this.storage.get_session().is_none() {
+                    continue;
+                }
+                match this.due(interval) {
+                    Ok(peer_ids) => {
+                        this.refresh_all(&peer_ids).await;
+                    }
+                    Err(e) => log::warn!("Listing profiles to refresh failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Refresh the senders of received messages not fetched lately, in the
+    /// background
+    pub(crate) fn on_received(self: &Arc<Self>, handle: &Handle, sender_ids: &[&str]) {
+        let now = Instant::now();
+        let peer_ids: Vec<String> = {
+            let mut refreshed = self.refreshed.lock();
+            let mut peer_ids = Vec::new();
+            for &sender_id in sender_ids {
+                let recent = refreshed
+                    .get(&sender_id.to_string())
+                    .is_some_and(|at| now.duration_since(*at) < RECEIPT_REFRESH_MIN);
+                if !recent && !peer_ids.iter().any(|p| p == sender_id) {
+                    // Claimed now so a burst of messages fetches once
+                    refreshed.insert(sender_id.to_string(), now);
+                    peer_ids.push(sender_id.to_string());
+                }
+            }
+            peer_ids
+        };
+        if peer_ids.is_empty() {
+            return;
+        }
+        let this = self.clone();
+        handle.spawn(async move {
+            this.refresh_all(&peer_ids).await;
+        });
+    }
+
+    /// Conversations whose profile is older than `interval`, oldest first,
+    /// at most one batch
+    fn due(&self, interval: Duration) -> Result<Vec<String>> {
+        let now = Instant::now();
+        let refreshed = self.refreshed.lock();
+        let mut due: Vec<(Option<Instant>, String)> = self
+            .storage
+            .get_peer_conversation_ids()?
+            .into_iter()
+            .map(|id| (refreshed.peek(&id).copied(), id))
+            .filter(|(at, _)| at.is_none_or(|at| now.duration_since(at) >= interval))
+            .collect();
+        due.sort_by_key(|(at, _)| *at);
+        due.truncate(REFRESH_BATCH);
+        Ok(due.into_iter().map(|(_, id)| id).collect())
+    }
+
+    /// Refresh each peer, logging failures. Returns how many conversations
+    /// changed.
+    pub(crate) async fn refresh_all(&self, peer_ids: &[String]) -> usize {
+        let mut changed = 0;
+        for peer_id in peer_ids {
+            match self.refresh(peer_id).await {
+                Ok(true) => changed += 1,
+                Ok(false) => {}
+                Err(e) => log::debug!("Refreshing the profile of {} failed: {}", peer_id, e),
+            }
+        }
+        changed
+    }
+
+    /// Fetch a peer's profile and update their conversation. Returns
+    /// whether its name or avatar changed.
+    async fn refresh(&self, peer_id: &str) -> Result<bool> {
+        let mut user = self.api.get_user(peer_id).await?;
+        self.refreshed
+            .lock()
+            .insert(peer_id.to_string(), Instant::now());
+
+        if let Some(cached) = self.storage.get_user(peer_id)? {
+            user.public_key = cached.public_key;
+        }
+        self.storage.save_user(&user)?;
+
+        let changed = self.storage.update_conversation_profile(
+            peer_id,
+            user.display_name.as_deref(),
+            user.avatar_file_id.as_deref(),
+        )?;
+        if changed {
+            self.event_sender
+                .send(ClientEvent::ConversationProfileChanged {
+                    conversation_id: peer_id.to_string(),
+                    peer_name: user.display_name,
+                    peer_avatar: user.avatar_file_id,
+                });
+        }
+        Ok(changed)
+    }
+}
@@ -0,0 +1,74 @@
+//! Message rules applied to incoming messages
+//!
+//! Rules are kept in local storage and cached here. The notifier runs them
+//! on each incoming message once it is stored, so marking read covers the
+//! message, and highlights the notification if a rule asks for it.
+
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use privmsg_proto::rules::matching_actions;
+
+use crate::error::Result;
+use crate::models::{Message, MessageRule, MessageType, RuleAction};
+use crate::storage::LocalStorage;
+
+pub(crate) struct RuleEngine {
+    storage: Arc<LocalStorage>,
+    rules: RwLock<Vec<MessageRule>>,
+}
+
+impl RuleEngine {
+    pub(crate) fn new(storage: Arc<LocalStorage>) -> Result<Self> {
+        let rules = storage.get_message_rules()?;
+        Ok(Self {
+            storage,
+            rules: RwLock::new(rules),
+        })
+    }
+
+    /// Pick up rules changed in storage
+    pub(crate) fn reload(&self) -> Result<()> {
+        *self.rules.write() = self.storage.get_message_rules()?;
+        Ok(())
+    }
+
+    /// What the rules do with `message`; nothing for the user's own
+    /// messages and notices
+    pub(crate) fn actions(&self, message: &Message) -> Vec<RuleAction> {
+        if message.is_outgoing || message.message_type == MessageType::System {
+            return Vec::new();
+        }
+        let text = message.caption.as_deref().unwrap_or(&message.content);
+        matching_actions(
+            &self.rules.read(),
+            &message.sender_id,
+            message.message_type.as_str(),
+            text,
+        )
+    }
+
+    /// Act on the conversation of a stored incoming message and return
+    /// what the rules asked for. A failing action is logged and doesn't stop
+    /// the others.
+    pub(crate) fn apply(&self, message: &Message) -> Vec<RuleAction> {
+        let actions = self.actions(message);
+        let conversation = std::slice::from_ref(&message.conversation_id);
+        for &action in &actions {
+            let result = match action {
+                RuleAction::Mute => self.storage.set_conversations_muted(conversation, true),
+                RuleAction::Archive => self.storage.set_conversations_archived(conversation, true),
+                RuleAction::MarkRead => self
+                    .storage
+                    .mark_read_up_to(&message.conversation_id, message.timestamp)
+                    .map(|_| ()),
+                // Up to the notifier
+                RuleAction::Highlight => Ok(()),
+            };
+            if let Err(e) = result {
+                log::warn!("Rule action {:?} on {} failed: {}", action, message.message_id, e);
+            }
+        }
+        actions
+    }
+}
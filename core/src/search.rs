@@ -0,0 +1,106 @@
+//! Full-text message search
+//!
+//! Messages are indexed in an FTS5 table that triggers keep in step with the
+//! `messages` table. The text reaches the index through the `search_tokens`
+//! SQL function registered on the storage connection.
+//!
+//! With the `hashed-search` feature, every word is replaced by a keyed hash
+//! before it is indexed, so the index holds no readable text. Queries hash
+//! their words the same way. Only whole words match (case-insensitively);
+//! prefix matching is lost.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Settings key of the secret hashed indexes are keyed with
+pub(crate) const SECRET_SETTING: &str = "search_index_secret";
+/// Settings key recording which mode built the index, to rebuild it when
+/// the mode changes
+pub(crate) const MODE_SETTING: &str = "search_index_mode";
+
+/// Bytes of each word hash kept in the index
+const HASH_LEN: usize = 12;
+
+#[derive(Clone)]
+pub(crate) struct SearchIndex {
+    secret: Option<Vec<u8>>,
+}
+
+impl SearchIndex {
+    pub(crate) fn plain() -> Self {
+        Self { secret: None }
+    }
+
+    pub(crate) fn hashed(secret: Vec<u8>) -> Self {
+        Self { secret: Some(secret) }
+    }
+
+    pub(crate) fn mode(&self) -> &'static str {
+        match self.secret {
+            Some(_) => "hashed",
+            None => "plain",
+        }
+    }
+
+    /// What goes into the index for a message
+    pub(crate) fn index_text(&self, content: &str, caption: Option<&str>) -> String {
+        let text = match caption {
+            Some(caption) => format!("{} {}", content, caption),
+            None => content.to_string(),
+        };
+        match self.secret {
+            Some(ref secret) => words(&text).map(|w| hash_word(secret, &w)).collect::<Vec<_>>().join(" "),
+            None => text,
+        }
+    }
+
+    /// FTS5 query matching messages that contain every word of `query`,
+    /// or `None` if it has no words
+    pub(crate) fn match_query(&self, query: &str) -> Option<String> {
+        let terms: Vec<String> = words(query)
+            .map(|w| match self.secret {
+                Some(ref secret) => format!("\"{}\"", hash_word(secret, &w)),
+                // Quoted so FTS5 syntax in the query is taken literally
+                None => format!("\"{}\"*", w),
+            })
+            .collect();
+        (!terms.is_empty()).then(|| terms.join(" "))
+    }
+}
+
+/// Lowercased runs of letters and digits
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn hash_word(secret: &[u8], word: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(word.as_bytes());
+    URL_SAFE_NO_PAD.encode(&mac.finalize().into_bytes()[..HASH_LEN])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashed_index_text() {
+        let index = SearchIndex::hashed(b"secret".to_vec());
+        let indexed = index.index_text("Meet at the Café", Some("tomorrow"));
+        assert!(!indexed.to_lowercase().contains("caf"));
+        assert_eq!(indexed.split(' ').count(), 5);
+
+        // Case-insensitive, whole words only
+        let term = index.match_query("CAFÉ").unwrap();
+        assert!(indexed.contains(term.trim_matches('"')));
+        assert!(!indexed.contains(index.match_query("caf").unwrap().trim_matches('"')));
+
+        // Different secret, different hashes
+        let other = SearchIndex::hashed(b"other".to_vec());
+        assert_ne!(other.index_text("cafe", None), index.index_text("cafe", None));
+        assert_eq!(index.match_query(" ?! "), None);
+    }
+}
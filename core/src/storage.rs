@@ -1,32 +1,52 @@
 //! Local storage using SQLite
 
+use crate::crypto::{CipherSuite, SessionStore, StoredSession};
 use crate::error::{Error, Result};
 use crate::models::*;
+use crate::search::{self, SearchIndex};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use privmsg_proto::sqlite::ensure_column;
+use rusqlite::functions::FunctionFlags;
 use rusqlite::{params, Connection};
 use std::path::Path;
 use std::sync::Mutex;
 
+/// Database file name inside the data directory
+pub(crate) const DATABASE_FILE: &str = "privmsg.db";
+
+/// A message deleted when its lifetime ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredMessage {
+    pub conversation_id: String,
+    pub message_id: String,
+    /// File id of its attachment, if it had one
+    pub file_id: Option<String>,
+}
+
+/// How long a deletion of a message not received yet is kept
+const DELETION_TTL_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+
 pub struct LocalStorage {
     conn: Mutex<Connection>,
+    search: SearchIndex,
 }
 
 impl LocalStorage {
     pub fn new(data_dir: &str) -> Result<Self> {
         std::fs::create_dir_all(data_dir)?;
-        let db_path = Path::new(data_dir).join("privmsg.db");
+        let db_path = Path::new(data_dir).join(DATABASE_FILE);
         let conn = Connection::open(db_path)?;
 
-        let storage = Self {
-            conn: Mutex::new(conn),
-        };
-        storage.init_schema()?;
+        Self::init_schema(&conn)?;
+        let search = Self::init_search(&conn)?;
 
-        Ok(storage)
+        Ok(Self {
+            conn: Mutex::new(conn),
+            search,
+        })
     }
 
-    fn init_schema(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-
+    fn init_schema(conn: &Connection) -> Result<()> {
         conn.execute_batch(
             r#"
             CREATE TABLE IF NOT EXISTS settings (
@@ -73,18 +93,266 @@ impl LocalStorage {
                 created_at INTEGER NOT NULL
             );
 
+            CREATE TABLE IF NOT EXISTS transfers (
+                file_id TEXT PRIMARY KEY,
+                attachment_json TEXT NOT NULL,
+                dest_path TEXT NOT NULL,
+                part_path TEXT NOT NULL,
+                total_size INTEGER NOT NULL,
+                range_size INTEGER NOT NULL,
+                paused INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS transfer_ranges (
+                file_id TEXT NOT NULL,
+                range_index INTEGER NOT NULL,
+                PRIMARY KEY (file_id, range_index)
+            );
+
+            CREATE TABLE IF NOT EXISTS custom_messages (
+                message_id TEXT PRIMARY KEY,
+                sender_id TEXT NOT NULL,
+                message_type TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                timestamp INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS message_rules (
+                id TEXT PRIMARY KEY,
+                rule_json TEXT NOT NULL,
+                position INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS message_delivery (
+                message_id TEXT PRIMARY KEY,
+                transport TEXT,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                sent_at INTEGER,
+                acked_at INTEGER
+            );
+
+            CREATE TABLE IF NOT EXISTS outbox (
+                message_id TEXT PRIMARY KEY,
+                attempts INTEGER NOT NULL,
+                next_attempt_at INTEGER NOT NULL
+            );
+
+            -- Deletions by the sender of messages that hadn't arrived yet
+            CREATE TABLE IF NOT EXISTS deleted_messages (
+                message_id TEXT PRIMARY KEY,
+                sender_id TEXT NOT NULL,
+                deleted_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS unavailable_peers (
+                peer_id TEXT PRIMARY KEY,
+                since INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS drafts (
+                conversation_id TEXT PRIMARY KEY,
+                text TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS trusted_keys (
+                peer_id TEXT PRIMARY KEY,
+                public_key TEXT NOT NULL,
+                trusted_at INTEGER NOT NULL,
+                pending_key TEXT,
+                conflict_at INTEGER
+            );
+
+            CREATE TABLE IF NOT EXISTS archived_keys (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                peer_id TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                trusted_at INTEGER NOT NULL,
+                archived_at INTEGER NOT NULL,
+                last_message_time INTEGER
+            );
+
+            -- Device keys of peers that their identity key didn't sign
+            CREATE TABLE IF NOT EXISTS device_keys (
+                peer_id TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                seen_at INTEGER NOT NULL,
+                trusted INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (peer_id, device_id, public_key)
+            );
+
+            CREATE TABLE IF NOT EXISTS groups (
+                group_id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                member_ids_json TEXT NOT NULL
+            );
+
             CREATE INDEX IF NOT EXISTS idx_messages_conversation ON messages(conversation_id);
             CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp);
             "#,
         )?;
 
+        // Columns added after the initial schema
+        ensure_column(conn, "messages", "caption", "TEXT")?;
+        ensure_column(conn, "messages", "pending_reason", "TEXT")?;
+        ensure_column(conn, "messages", "metadata_json", "TEXT")?;
+        ensure_column(conn, "messages", "system_event_json", "TEXT")?;
+        ensure_column(conn, "messages", "sender_device_id", "TEXT")?;
+        ensure_column(conn, "messages", "quote_json", "TEXT")?;
+        ensure_column(conn, "messages", "is_starred", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(conn, "messages", "unverified_key", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(conn, "messages", "expires_at", "INTEGER")?;
+        ensure_column(conn, "conversations", "last_read_timestamp", "INTEGER")?;
+        ensure_column(conn, "conversations", "is_archived", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(conn, "conversations", "change_seq", "INTEGER")?;
+        ensure_column(conn, "conversations", "message_ttl", "INTEGER")?;
+        ensure_column(conn, "users", "status_emoji", "TEXT")?;
+        ensure_column(conn, "users", "status_text", "TEXT")?;
+        ensure_column(conn, "users", "bio", "TEXT")?;
+        ensure_column(conn, "session_keys", "suite", "TEXT")?;
+        ensure_column(conn, "session_keys", "tagged", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(conn, "session_keys", "peer_key", "TEXT")?;
+        ensure_column(conn, "session_keys", "ratchet", "TEXT")?;
+
+        // Every write to a conversation takes the next change_seq, which
+        // `get_conversation_changes` reads from. The guard stops the
+        // trigger's own update from firing it again.
+        conn.execute_batch(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_conversations_change_seq ON conversations(change_seq);
+            CREATE INDEX IF NOT EXISTS idx_messages_expires_at ON messages(expires_at);
+
+            CREATE TRIGGER IF NOT EXISTS conversations_change_insert AFTER INSERT ON conversations BEGIN
+                UPDATE conversations
+                SET change_seq = (SELECT COALESCE(MAX(change_seq), 0) + 1 FROM conversations)
+                WHERE id = new.id;
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS conversations_change_update AFTER UPDATE ON conversations
+            WHEN new.change_seq IS old.change_seq BEGIN
+                UPDATE conversations
+                SET change_seq = (SELECT COALESCE(MAX(change_seq), 0) + 1 FROM conversations)
+                WHERE id = new.id;
+            END;
+            "#,
+        )?;
+
         Ok(())
     }
 
+    /// Set up the full-text index (see `search`), rebuilding it if it was
+    /// built in the other mode
+    fn init_search(conn: &Connection) -> Result<SearchIndex> {
+        let search = if cfg!(feature = "hashed-search") {
+            SearchIndex::hashed(Self::search_secret(conn)?)
+        } else {
+            SearchIndex::plain()
+        };
+
+        let index = search.clone();
+        conn.create_scalar_function(
+            "search_tokens",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            move |ctx| {
+                let content: Option<String> = ctx.get(0)?;
+                let caption: Option<String> = ctx.get(1)?;
+                Ok(index.index_text(content.as_deref().unwrap_or(""), caption.as_deref()))
+            },
+        )?;
+        // INSERT OR REPLACE only fires the delete trigger with this on
+        conn.pragma_update(None, "recursive_triggers", true)?;
+
+        conn.execute_batch(
+            r#"
+            CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(body);
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, body)
+                VALUES (new.rowid, search_tokens(new.content, new.caption));
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                DELETE FROM messages_fts WHERE rowid = old.rowid;
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content, caption ON messages BEGIN
+                UPDATE messages_fts SET body = search_tokens(new.content, new.caption)
+                WHERE rowid = new.rowid;
+            END;
+            "#,
+        )?;
+
+        let built_as: Option<String> = conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![search::MODE_SETTING],
+                |row| row.get(0),
+            )
+            .ok();
+        if built_as.as_deref() != Some(search.mode()) {
+            log::info!("Building {} search index", search.mode());
+            conn.execute_batch(
+                r#"
+                DELETE FROM messages_fts;
+                INSERT INTO messages_fts (rowid, body)
+                    SELECT rowid, search_tokens(content, caption) FROM messages;
+                "#,
+            )?;
+            conn.execute(
+                "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+                params![search::MODE_SETTING, search.mode()],
+            )?;
+        }
+
+        Ok(search)
+    }
+
+    /// The secret hashed search is keyed with, created on first use
+    fn search_secret(conn: &Connection) -> Result<Vec<u8>> {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+        let stored: Option<String> = conn
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![search::SECRET_SETTING],
+                |row| row.get(0),
+            )
+            .ok();
+        if let Some(secret) = stored.and_then(|s| URL_SAFE_NO_PAD.decode(s).ok()) {
+            return Ok(secret);
+        }
+
+        let mut secret = vec![0u8; 32];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut secret);
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![search::SECRET_SETTING, URL_SAFE_NO_PAD.encode(&secret)],
+        )?;
+        Ok(secret)
+    }
+
     // ========================================================================
     // Settings
     // ========================================================================
 
+    /// Problems SQLite finds in the database file; empty when it is intact
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let problems = rows
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|row| row != "ok")
+            .collect();
+        Ok(problems)
+    }
+
     pub fn save_setting(&self, key: &str, value: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
@@ -133,6 +401,7 @@ impl LocalStorage {
             device_id,
             user_id,
             expires_at,
+            announcement: None,
         })
     }
 
@@ -152,8 +421,8 @@ impl LocalStorage {
         let conn = self.conn.lock().unwrap();
         conn.execute(
             r#"INSERT OR REPLACE INTO conversations
-               (id, peer_id, peer_name, peer_avatar, last_message, last_message_time, unread_count, is_muted, is_pinned)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+               (id, peer_id, peer_name, peer_avatar, last_message, last_message_time, unread_count, is_muted, is_pinned, is_archived)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"#,
             params![
                 conv.id,
                 conv.peer_id,
@@ -164,6 +433,7 @@ impl LocalStorage {
                 conv.unread_count,
                 conv.is_muted as i32,
                 conv.is_pinned as i32,
+                conv.is_archived as i32,
             ],
         )?;
         Ok(())
@@ -173,24 +443,12 @@ impl LocalStorage {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             r#"SELECT id, peer_id, peer_name, peer_avatar, last_message, last_message_time,
-                      unread_count, is_muted, is_pinned
+                      unread_count, is_muted, is_pinned, is_archived
                FROM conversations
                ORDER BY is_pinned DESC, last_message_time DESC"#,
         )?;
 
-        let rows = stmt.query_map([], |row| {
-            Ok(Conversation {
-                id: row.get(0)?,
-                peer_id: row.get(1)?,
-                peer_name: row.get(2)?,
-                peer_avatar: row.get(3)?,
-                last_message: row.get(4)?,
-                last_message_time: row.get(5)?,
-                unread_count: row.get(6)?,
-                is_muted: row.get::<_, i32>(7)? != 0,
-                is_pinned: row.get::<_, i32>(8)? != 0,
-            })
-        })?;
+        let rows = stmt.query_map([], Self::conversation_from_row)?;
 
         let mut conversations = Vec::new();
         for row in rows {
@@ -200,26 +458,113 @@ impl LocalStorage {
         Ok(conversations)
     }
 
+    /// A page of conversations in list order, pinned first and then by
+    /// latest message
+    pub fn get_conversation_summaries(&self, limit: i64, offset: i64) -> Result<Vec<ConversationSummary>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT id, peer_name, last_message, last_message_time, unread_count,
+                      is_muted, is_pinned, is_archived
+               FROM conversations
+               ORDER BY is_pinned DESC, last_message_time DESC, id
+               LIMIT ?1 OFFSET ?2"#,
+        )?;
+        let summaries = stmt
+            .query_map(params![limit, offset], Self::summary_from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(summaries)
+    }
+
+    /// Conversations written after `cursor`, oldest change first, and the
+    /// cursor to pass next time
+    pub fn get_conversation_changes(&self, cursor: i64, limit: i64) -> Result<ConversationChanges> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT id, peer_name, last_message, last_message_time, unread_count,
+                      is_muted, is_pinned, is_archived, change_seq
+               FROM conversations
+               WHERE change_seq > ?1
+               ORDER BY change_seq
+               LIMIT ?2"#,
+        )?;
+        let mut changes = ConversationChanges {
+            conversations: Vec::new(),
+            cursor,
+        };
+        let rows = stmt.query_map(params![cursor, limit], |row| {
+            Ok((Self::summary_from_row(row)?, row.get::<_, i64>(8)?))
+        })?;
+        for row in rows {
+            let (summary, seq) = row?;
+            changes.conversations.push(summary);
+            changes.cursor = seq;
+        }
+        Ok(changes)
+    }
+
+    /// The cursor for `get_conversation_changes` as of now
+    pub fn conversation_cursor(&self) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row(
+            "SELECT COALESCE(MAX(change_seq), 0) FROM conversations",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn conversation_count(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM conversations", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Ids of all conversations, without loading them
+    pub fn get_conversation_ids(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id FROM conversations ORDER BY is_pinned DESC, last_message_time DESC, id",
+        )?;
+        let ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    /// Ids of 1:1 conversations, which are also their peers' user ids
+    pub fn get_peer_conversation_ids(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT id FROM conversations WHERE id NOT IN (SELECT group_id FROM groups)
+               ORDER BY is_pinned DESC, last_message_time DESC, id"#,
+        )?;
+        let ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    /// Columns as selected by `get_conversation_summaries`
+    fn summary_from_row(row: &rusqlite::Row) -> rusqlite::Result<ConversationSummary> {
+        Ok(ConversationSummary {
+            id: row.get(0)?,
+            peer_name: row.get(1)?,
+            last_message: row.get(2)?,
+            last_message_time: row.get(3)?,
+            unread_count: row.get(4)?,
+            is_muted: row.get::<_, i32>(5)? != 0,
+            is_pinned: row.get::<_, i32>(6)? != 0,
+            is_archived: row.get::<_, i32>(7)? != 0,
+        })
+    }
+
     pub fn get_conversation(&self, id: &str) -> Result<Option<Conversation>> {
         let conn = self.conn.lock().unwrap();
         let result = conn.query_row(
             r#"SELECT id, peer_id, peer_name, peer_avatar, last_message, last_message_time,
-                      unread_count, is_muted, is_pinned
+                      unread_count, is_muted, is_pinned, is_archived
                FROM conversations WHERE id = ?1"#,
             params![id],
-            |row| {
-                Ok(Conversation {
-                    id: row.get(0)?,
-                    peer_id: row.get(1)?,
-                    peer_name: row.get(2)?,
-                    peer_avatar: row.get(3)?,
-                    last_message: row.get(4)?,
-                    last_message_time: row.get(5)?,
-                    unread_count: row.get(6)?,
-                    is_muted: row.get::<_, i32>(7)? != 0,
-                    is_pinned: row.get::<_, i32>(8)? != 0,
-                })
-            },
+            Self::conversation_from_row,
         );
 
         match result {
@@ -229,6 +574,39 @@ impl LocalStorage {
         }
     }
 
+    /// Columns as selected by `get_conversations`
+    fn conversation_from_row(row: &rusqlite::Row) -> rusqlite::Result<Conversation> {
+        Ok(Conversation {
+            id: row.get(0)?,
+            peer_id: row.get(1)?,
+            peer_name: row.get(2)?,
+            peer_avatar: row.get(3)?,
+            last_message: row.get(4)?,
+            last_message_time: row.get(5)?,
+            unread_count: row.get(6)?,
+            is_muted: row.get::<_, i32>(7)? != 0,
+            is_pinned: row.get::<_, i32>(8)? != 0,
+            is_archived: row.get::<_, i32>(9)? != 0,
+        })
+    }
+
+    /// Set the name and avatar shown for a conversation. Returns false if
+    /// they were already these, or there is no such conversation.
+    pub fn update_conversation_profile(
+        &self,
+        conversation_id: &str,
+        peer_name: Option<&str>,
+        peer_avatar: Option<&str>,
+    ) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            r#"UPDATE conversations SET peer_name = ?2, peer_avatar = ?3
+               WHERE id = ?1 AND (peer_name IS NOT ?2 OR peer_avatar IS NOT ?3)"#,
+            params![conversation_id, peer_name, peer_avatar],
+        )?;
+        Ok(updated > 0)
+    }
+
     pub fn update_unread_count(&self, conversation_id: &str, count: i32) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
@@ -238,6 +616,45 @@ impl LocalStorage {
         Ok(())
     }
 
+    /// Move the read position forward to `timestamp` and recount the
+    /// incoming messages after it. Returns false if it was already there.
+    pub fn mark_read_up_to(&self, conversation_id: &str, timestamp: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            r#"UPDATE conversations
+               SET last_read_timestamp = ?2,
+                   unread_count = (SELECT COUNT(*) FROM messages
+                                   WHERE conversation_id = ?1 AND is_outgoing = 0 AND timestamp > ?2)
+               WHERE id = ?1 AND COALESCE(last_read_timestamp, 0) < ?2"#,
+            params![conversation_id, timestamp],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Set the expiry of attachments with `file_id` in a conversation.
+    /// Returns whether any message carried it.
+    pub fn set_attachment_expiry(&self, conversation_id: &str, file_id: &str, expires_at: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            r#"UPDATE messages
+               SET attachment_json = json_set(attachment_json, '$.expires_at', ?3)
+               WHERE conversation_id = ?1 AND json_extract(attachment_json, '$.file_id') = ?2"#,
+            params![conversation_id, file_id, expires_at],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Replace the attachment of a message, e.g. after the file was
+    /// uploaded again. Returns whether the message exists.
+    pub fn set_message_attachment(&self, message_id: &str, attachment: &Attachment) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE messages SET attachment_json = ?2 WHERE message_id = ?1",
+            params![message_id, serde_json::to_string(attachment)?],
+        )?;
+        Ok(updated > 0)
+    }
+
     pub fn delete_conversation(&self, id: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute("DELETE FROM messages WHERE conversation_id = ?1", params![id])?;
@@ -245,22 +662,239 @@ impl LocalStorage {
         Ok(())
     }
 
+    // ========================================================================
+    // Bulk conversation operations (each one transaction)
+    // ========================================================================
+
+    /// Mark conversations read up to their latest message. Returns the read
+    /// positions that moved.
+    pub fn mark_conversations_read(&self, ids: &[String]) -> Result<Vec<ReadPosition>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut moved = Vec::new();
+        for id in ids {
+            let updated = tx.execute(
+                r#"UPDATE conversations
+                   SET last_read_timestamp = last_message_time,
+                       unread_count = (SELECT COUNT(*) FROM messages
+                                       WHERE conversation_id = ?1 AND is_outgoing = 0
+                                         AND timestamp > conversations.last_message_time)
+                   WHERE id = ?1 AND COALESCE(last_read_timestamp, 0) < COALESCE(last_message_time, 0)"#,
+                params![id],
+            )?;
+            if updated > 0 {
+                let timestamp: i64 = tx.query_row(
+                    "SELECT last_read_timestamp FROM conversations WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )?;
+                moved.push(ReadPosition {
+                    conversation_id: id.clone(),
+                    last_read_timestamp: timestamp,
+                });
+            }
+        }
+        tx.commit()?;
+        Ok(moved)
+    }
+
+    pub fn delete_conversations(&self, ids: &[String]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for id in ids {
+            tx.execute("DELETE FROM messages WHERE conversation_id = ?1", params![id])?;
+            tx.execute("DELETE FROM conversations WHERE id = ?1", params![id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn set_conversations_muted(&self, ids: &[String], muted: bool) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for id in ids {
+            tx.execute(
+                "UPDATE conversations SET is_muted = ?1 WHERE id = ?2",
+                params![muted as i32, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// How long new messages in a conversation are kept, in seconds;
+    /// `None` keeps them
+    pub fn get_conversation_ttl(&self, id: &str) -> Result<Option<u64>> {
+        let conn = self.conn.lock().unwrap();
+        match conn.query_row(
+            "SELECT message_ttl FROM conversations WHERE id = ?1",
+            params![id],
+            |row| row.get::<_, Option<i64>>(0),
+        ) {
+            Ok(ttl) => Ok(ttl.map(|ttl| ttl as u64)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Set how long new messages in a conversation are kept, creating the
+    /// conversation if needed. Returns whether it changed.
+    pub fn set_conversation_ttl(&self, id: &str, ttl_seconds: Option<u64>) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let ttl = ttl_seconds.map(|ttl| ttl as i64);
+        match tx.query_row(
+            "SELECT message_ttl FROM conversations WHERE id = ?1",
+            params![id],
+            |row| row.get::<_, Option<i64>>(0),
+        ) {
+            Ok(current) if current == ttl => return Ok(false),
+            Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => {}
+            Err(e) => return Err(e.into()),
+        }
+        tx.execute(
+            r#"INSERT INTO conversations (id, peer_id, message_ttl) VALUES (?1, ?1, ?2)
+               ON CONFLICT(id) DO UPDATE SET message_ttl = excluded.message_ttl"#,
+            params![id, ttl],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    pub fn set_conversations_archived(&self, ids: &[String], archived: bool) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for id in ids {
+            tx.execute(
+                "UPDATE conversations SET is_archived = ?1 WHERE id = ?2",
+                params![archived as i32, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Conversations with all their messages, oldest first, read in one
+    /// transaction so the export is consistent. Unknown ids are skipped.
+    pub fn export_conversations(&self, ids: &[String]) -> Result<Vec<ConversationExport>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut exports = Vec::with_capacity(ids.len());
+        for id in ids {
+            let conversation = match tx.query_row(
+                r#"SELECT id, peer_id, peer_name, peer_avatar, last_message, last_message_time,
+                          unread_count, is_muted, is_pinned, is_archived
+                   FROM conversations WHERE id = ?1"#,
+                params![id],
+                Self::conversation_from_row,
+            ) {
+                Ok(conversation) => conversation,
+                Err(rusqlite::Error::QueryReturnedNoRows) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut stmt = tx.prepare(
+                r#"SELECT message_id, conversation_id, sender_id, message_type, content,
+                          timestamp, status, attachment_json, is_outgoing, caption, metadata_json,
+                          system_event_json, sender_device_id, quote_json, is_starred, unverified_key
+                   FROM messages
+                   WHERE conversation_id = ?1
+                   ORDER BY timestamp, rowid"#,
+            )?;
+            let messages = stmt
+                .query_map(params![id], Self::message_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            exports.push(ConversationExport { conversation, messages });
+        }
+        drop(tx);
+        Ok(exports)
+    }
+
     // ========================================================================
     // Messages
     // ========================================================================
 
-    pub fn save_message(&self, msg: &Message) -> Result<()> {
+    /// Save a message. Returns false if its sender deleted it before it
+    /// arrived, see [`record_deletion`](Self::record_deletion); it isn't
+    /// stored then.
+    pub fn save_message(&self, msg: &Message) -> Result<bool> {
         let conn = self.conn.lock().unwrap();
+        Self::insert_message(&conn, msg)
+    }
+
+    /// Save many messages in a single transaction. Returns the ids of those
+    /// their sender deleted before they arrived, which aren't stored.
+    pub fn save_messages(&self, messages: &[Message]) -> Result<Vec<String>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut deleted = Vec::new();
+        for msg in messages {
+            if !Self::insert_message(&tx, msg)? {
+                deleted.push(msg.message_id.clone());
+            }
+        }
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn insert_message(conn: &Connection, msg: &Message) -> Result<bool> {
+        // A deletion that came first applies once the message arrives
+        let deleted = conn.execute(
+            "DELETE FROM deleted_messages WHERE message_id = ?1 AND sender_id = ?2",
+            params![msg.message_id, msg.sender_id],
+        )?;
+        if deleted > 0 {
+            return Ok(false);
+        }
 
         let attachment_json = msg
             .attachment
             .as_ref()
             .map(|a| serde_json::to_string(a).unwrap_or_default());
+        let metadata_json = (!msg.metadata.is_empty())
+            .then(|| serde_json::to_string(&msg.metadata).unwrap_or_default());
+        let system_event_json = msg
+            .system_event
+            .as_ref()
+            .map(|e| serde_json::to_string(e).unwrap_or_default());
+        let quote_json = msg
+            .quote
+            .as_ref()
+            .map(|q| serde_json::to_string(q).unwrap_or_default());
+
+        // Update conversation first so the message's foreign key resolves
+        conn.execute(
+            r#"INSERT OR REPLACE INTO conversations
+               (id, peer_id, peer_name, peer_avatar, last_message, last_message_time, unread_count, is_muted, is_pinned, is_archived, last_read_timestamp, message_ttl)
+               VALUES (?1, ?1,
+                       (SELECT peer_name FROM conversations WHERE id = ?1),
+                       (SELECT peer_avatar FROM conversations WHERE id = ?1),
+                       ?2, ?3,
+                       COALESCE((SELECT unread_count FROM conversations WHERE id = ?1), 0)
+                         + (?4 AND ?3 > COALESCE((SELECT last_read_timestamp FROM conversations WHERE id = ?1), 0)),
+                       COALESCE((SELECT is_muted FROM conversations WHERE id = ?1), 0),
+                       COALESCE((SELECT is_pinned FROM conversations WHERE id = ?1), 0),
+                       COALESCE((SELECT is_archived FROM conversations WHERE id = ?1), 0),
+                       (SELECT last_read_timestamp FROM conversations WHERE id = ?1),
+                       (SELECT message_ttl FROM conversations WHERE id = ?1))"#,
+            params![
+                msg.conversation_id,
+                truncate_graphemes(&msg.content, 50),
+                msg.timestamp,
+                // Notices don't count as unread
+                if msg.is_outgoing || msg.message_type == MessageType::System { 0 } else { 1 },
+            ],
+        )?;
 
         conn.execute(
             r#"INSERT OR REPLACE INTO messages
-               (message_id, conversation_id, sender_id, message_type, content, timestamp, status, attachment_json, is_outgoing)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+               (message_id, conversation_id, sender_id, message_type, content, timestamp, status, attachment_json, is_outgoing, caption, metadata_json, system_event_json, sender_device_id, quote_json, is_starred, unverified_key, expires_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14,
+                       COALESCE((SELECT is_starred FROM messages WHERE message_id = ?1), 0), ?15,
+                       -- Set once, from the lifetime in force when the message arrived,
+                       -- counted from then rather than from the sender's clock
+                       COALESCE((SELECT expires_at FROM messages WHERE message_id = ?1),
+                                ?16 + (SELECT message_ttl FROM conversations WHERE id = ?2) * 1000))"#,
             params![
                 msg.message_id,
                 msg.conversation_id,
@@ -271,67 +905,32 @@ impl LocalStorage {
                 format!("{:?}", msg.status).to_lowercase(),
                 attachment_json,
                 msg.is_outgoing as i32,
+                msg.caption,
+                metadata_json,
+                system_event_json,
+                msg.sender_device_id,
+                quote_json,
+                msg.unverified_key as i32,
+                chrono::Utc::now().timestamp_millis(),
             ],
         )?;
 
-        // Update conversation
-        conn.execute(
-            r#"INSERT OR REPLACE INTO conversations (id, peer_id, last_message, last_message_time, unread_count, is_muted, is_pinned)
-               VALUES (?1, ?1, ?2, ?3,
-                       COALESCE((SELECT unread_count FROM conversations WHERE id = ?1), 0) + ?4,
-                       COALESCE((SELECT is_muted FROM conversations WHERE id = ?1), 0),
-                       COALESCE((SELECT is_pinned FROM conversations WHERE id = ?1), 0))"#,
-            params![
-                msg.conversation_id,
-                if msg.content.len() > 50 { &msg.content[..50] } else { &msg.content },
-                msg.timestamp,
-                if msg.is_outgoing { 0 } else { 1 },
-            ],
-        )?;
-
-        Ok(())
+        Ok(true)
     }
 
     pub fn get_messages(&self, conversation_id: &str, limit: i64, offset: i64) -> Result<Vec<Message>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             r#"SELECT message_id, conversation_id, sender_id, message_type, content,
-                      timestamp, status, attachment_json, is_outgoing
+                      timestamp, status, attachment_json, is_outgoing, caption, metadata_json,
+                      system_event_json, sender_device_id, quote_json, is_starred, unverified_key
                FROM messages
                WHERE conversation_id = ?1
                ORDER BY timestamp DESC
                LIMIT ?2 OFFSET ?3"#,
         )?;
 
-        let rows = stmt.query_map(params![conversation_id, limit, offset], |row| {
-            let type_str: String = row.get(3)?;
-            let status_str: String = row.get(6)?;
-            let attachment_json: Option<String> = row.get(7)?;
-
-            Ok(Message {
-                message_id: row.get(0)?,
-                conversation_id: row.get(1)?,
-                sender_id: row.get(2)?,
-                message_type: match type_str.as_str() {
-                    "voice" => MessageType::Voice,
-                    "video" => MessageType::Video,
-                    "image" => MessageType::Image,
-                    "file" => MessageType::File,
-                    _ => MessageType::Text,
-                },
-                content: row.get(4)?,
-                timestamp: row.get(5)?,
-                status: match status_str.as_str() {
-                    "pending" => MessageStatus::Pending,
-                    "delivered" => MessageStatus::Delivered,
-                    "read" => MessageStatus::Read,
-                    "failed" => MessageStatus::Failed,
-                    _ => MessageStatus::Sent,
-                },
-                attachment: attachment_json.and_then(|j| serde_json::from_str(&j).ok()),
-                is_outgoing: row.get::<_, i32>(8)? != 0,
-            })
-        })?;
+        let rows = stmt.query_map(params![conversation_id, limit, offset], Self::message_from_row)?;
 
         let mut messages = Vec::new();
         for row in rows {
@@ -342,53 +941,394 @@ impl LocalStorage {
         Ok(messages)
     }
 
-    pub fn update_message_status(&self, message_id: &str, status: MessageStatus) -> Result<()> {
+    /// Timestamps and directions of a conversation's messages, oldest
+    /// first, for statistics; system notices are left out
+    pub fn message_samples(&self, conversation_id: &str) -> Result<Vec<MessageSample>> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "UPDATE messages SET status = ?1 WHERE message_id = ?2",
-            params![format!("{:?}", status).to_lowercase(), message_id],
+        let mut stmt = conn.prepare(
+            r#"SELECT timestamp, is_outgoing FROM messages
+               WHERE conversation_id = ?1 AND message_type != 'system'
+               ORDER BY timestamp"#,
         )?;
-        Ok(())
+
+        let rows = stmt.query_map(params![conversation_id], |row| {
+            Ok(MessageSample {
+                timestamp: row.get(0)?,
+                is_outgoing: row.get::<_, i32>(1)? != 0,
+            })
+        })?;
+        Ok(rows.collect::<std::result::Result<_, _>>()?)
     }
 
-    pub fn delete_message(&self, message_id: &str) -> Result<()> {
+    pub fn get_message(&self, message_id: &str) -> Result<Option<Message>> {
         let conn = self.conn.lock().unwrap();
-        conn.execute("DELETE FROM messages WHERE message_id = ?1", params![message_id])?;
-        Ok(())
+        let mut stmt = conn.prepare(
+            r#"SELECT message_id, conversation_id, sender_id, message_type, content,
+                      timestamp, status, attachment_json, is_outgoing, caption, metadata_json,
+                      system_event_json, sender_device_id, quote_json, is_starred, unverified_key
+               FROM messages
+               WHERE message_id = ?1"#,
+        )?;
+
+        let mut rows = stmt.query_map(params![message_id], Self::message_from_row)?;
+        Ok(rows.next().transpose()?)
     }
 
-    // ========================================================================
-    // Users cache
-    // ========================================================================
+    /// Messages containing every word of `query`, newest first, optionally
+    /// within one conversation
+    pub fn search_messages(
+        &self,
+        query: &str,
+        conversation_id: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Message>> {
+        let Some(expression) = self.search.match_query(query) else {
+            return Ok(Vec::new());
+        };
 
-    pub fn save_user(&self, user: &User) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT m.message_id, m.conversation_id, m.sender_id, m.message_type, m.content,
+                      m.timestamp, m.status, m.attachment_json, m.is_outgoing, m.caption, m.metadata_json,
+                      m.system_event_json, m.sender_device_id, m.quote_json, m.is_starred, m.unverified_key
+               FROM messages_fts
+               JOIN messages m ON m.rowid = messages_fts.rowid
+               WHERE messages_fts MATCH ?1 AND (?2 IS NULL OR m.conversation_id = ?2)
+               ORDER BY m.timestamp DESC
+               LIMIT ?3"#,
+        )?;
+        let messages = stmt
+            .query_map(params![expression, conversation_id, limit], Self::message_from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    /// Columns as selected by `get_messages`
+    fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+        let type_str: String = row.get(3)?;
+        let status_str: String = row.get(6)?;
+        let attachment_json: Option<String> = row.get(7)?;
+        let metadata_json: Option<String> = row.get(10)?;
+        let system_event_json: Option<String> = row.get(11)?;
+        let quote_json: Option<String> = row.get(13)?;
+
+        Ok(Message {
+            message_id: row.get(0)?,
+            conversation_id: row.get(1)?,
+            sender_id: row.get(2)?,
+            message_type: match type_str.as_str() {
+                "voice" => MessageType::Voice,
+                "video" => MessageType::Video,
+                "image" => MessageType::Image,
+                "file" => MessageType::File,
+                "system" => MessageType::System,
+                _ => MessageType::Text,
+            },
+            content: row.get(4)?,
+            timestamp: row.get(5)?,
+            status: match status_str.as_str() {
+                "pending" => MessageStatus::Pending,
+                "delivered" => MessageStatus::Delivered,
+                "read" => MessageStatus::Read,
+                "failed" => MessageStatus::Failed,
+                _ => MessageStatus::Sent,
+            },
+            attachment: attachment_json.and_then(|j| serde_json::from_str(&j).ok()),
+            caption: row.get(9)?,
+            is_outgoing: row.get::<_, i32>(8)? != 0,
+            metadata: metadata_json
+                .and_then(|j| serde_json::from_str(&j).ok())
+                .unwrap_or_default(),
+            system_event: system_event_json.and_then(|j| serde_json::from_str(&j).ok()),
+            sender_device_id: row.get(12)?,
+            quote: quote_json.and_then(|j| serde_json::from_str(&j).ok()),
+            is_starred: row.get::<_, i32>(14)? != 0,
+            unverified_key: row.get::<_, i32>(15)? != 0,
+        })
+    }
+
+    /// Record why an outgoing message hasn't been sent. Saving the message
+    /// again clears it.
+    pub fn set_pending_reason(&self, message_id: &str, reason: PendingReason) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            r#"INSERT OR REPLACE INTO users (user_id, display_name, avatar_file_id, public_key, last_seen_at)
-               VALUES (?1, ?2, ?3, ?4, ?5)"#,
-            params![
-                user.user_id,
-                user.display_name,
-                user.avatar_file_id,
-                user.public_key,
-                user.last_seen_at,
-            ],
+            "UPDATE messages SET pending_reason = ?1 WHERE message_id = ?2",
+            params![reason.as_str(), message_id],
         )?;
         Ok(())
     }
 
-    pub fn get_user(&self, user_id: &str) -> Result<Option<User>> {
+    /// Outgoing messages in a conversation that are pending or failed,
+    /// oldest first
+    pub fn get_pending_outgoing(&self, conversation_id: &str) -> Result<Vec<PendingMessage>> {
         let conn = self.conn.lock().unwrap();
-        let result = conn.query_row(
-            "SELECT user_id, display_name, avatar_file_id, public_key, last_seen_at FROM users WHERE user_id = ?1",
-            params![user_id],
-            |row| {
-                Ok(User {
+        let mut stmt = conn.prepare(
+            r#"SELECT message_id, conversation_id, sender_id, message_type, content,
+                      timestamp, status, attachment_json, is_outgoing, caption, metadata_json,
+                      system_event_json, sender_device_id, quote_json, is_starred, unverified_key, pending_reason
+               FROM messages
+               WHERE conversation_id = ?1 AND is_outgoing = 1
+                 AND status IN ('pending', 'failed')
+               ORDER BY timestamp, rowid"#,
+        )?;
+
+        let rows = stmt.query_map(params![conversation_id], |row| {
+            let message = Self::message_from_row(row)?;
+            let reason: Option<String> = row.get(16)?;
+            let reason = reason.as_deref().and_then(PendingReason::parse).unwrap_or(
+                match message.status {
+                    MessageStatus::Failed => PendingReason::Failed,
+                    _ => PendingReason::Offline,
+                },
+            );
+            Ok(PendingMessage { message, reason })
+        })?;
+
+        let mut pending = Vec::new();
+        for row in rows {
+            pending.push(row?);
+        }
+        Ok(pending)
+    }
+
+    pub fn update_message_status(&self, message_id: &str, status: MessageStatus) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE messages SET status = ?1 WHERE message_id = ?2",
+            params![format!("{:?}", status).to_lowercase(), message_id],
+        )?;
+        Ok(())
+    }
+
+    /// Mark outgoing messages delivered in one transaction, unless they
+    /// were already delivered or read. Returns the conversation and id of
+    /// each message that changed.
+    pub fn mark_delivered(&self, message_ids: &[String]) -> Result<Vec<(String, String)>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut changed = Vec::new();
+        for message_id in message_ids {
+            let updated = tx.execute(
+                "UPDATE messages SET status = 'delivered'
+                 WHERE message_id = ?1 AND is_outgoing = 1 AND status IN ('pending', 'sent', 'failed')",
+                params![message_id],
+            )?;
+            if updated > 0 {
+                let conversation_id: String = tx.query_row(
+                    "SELECT conversation_id FROM messages WHERE message_id = ?1",
+                    params![message_id],
+                    |row| row.get(0),
+                )?;
+                changed.push((conversation_id, message_id.clone()));
+            }
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// Messages by id, oldest first, read in one transaction; unknown ids
+    /// are skipped
+    pub fn get_messages_by_id(&self, message_ids: &[String]) -> Result<Vec<Message>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut messages = Vec::new();
+        {
+            let mut stmt = tx.prepare(
+                r#"SELECT message_id, conversation_id, sender_id, message_type, content,
+                          timestamp, status, attachment_json, is_outgoing, caption, metadata_json,
+                          system_event_json, sender_device_id, quote_json, is_starred, unverified_key
+                   FROM messages
+                   WHERE message_id = ?1"#,
+            )?;
+            for id in message_ids {
+                if let Some(message) = stmt.query_map(params![id], Self::message_from_row)?.next() {
+                    messages.push(message?);
+                }
+            }
+        }
+        tx.commit()?;
+        messages.sort_by_key(|m| m.timestamp);
+        Ok(messages)
+    }
+
+    /// Star or unstar messages in one transaction; returns how many exist
+    pub fn set_messages_starred(&self, message_ids: &[String], starred: bool) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut updated = 0;
+        for id in message_ids {
+            updated += tx.execute(
+                "UPDATE messages SET is_starred = ?1 WHERE message_id = ?2",
+                params![starred as i32, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+
+    /// Starred messages, newest first, optionally within one conversation
+    pub fn get_starred_messages(&self, conversation_id: Option<&str>) -> Result<Vec<Message>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT message_id, conversation_id, sender_id, message_type, content,
+                      timestamp, status, attachment_json, is_outgoing, caption, metadata_json,
+                      system_event_json, sender_device_id, quote_json, is_starred, unverified_key
+               FROM messages
+               WHERE is_starred = 1 AND (?1 IS NULL OR conversation_id = ?1)
+               ORDER BY timestamp DESC"#,
+        )?;
+        let messages = stmt
+            .query_map(params![conversation_id], Self::message_from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    /// Delete messages and their delivery details in one transaction;
+    /// returns how many there were
+    pub fn delete_messages(&self, message_ids: &[String]) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut deleted = 0;
+        for id in message_ids {
+            deleted += tx.execute("DELETE FROM messages WHERE message_id = ?1", params![id])?;
+            tx.execute("DELETE FROM message_delivery WHERE message_id = ?1", params![id])?;
+            tx.execute("DELETE FROM outbox WHERE message_id = ?1", params![id])?;
+        }
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Delete messages whose lifetime ended by `now` (Unix milliseconds),
+    /// with their delivery details, in one transaction. The previews of
+    /// their conversations move to the latest message left.
+    pub fn delete_expired_messages(&self, now: i64) -> Result<Vec<ExpiredMessage>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let expired = {
+            let mut stmt = tx.prepare(
+                r#"SELECT conversation_id, message_id, attachment_json FROM messages
+                   WHERE expires_at <= ?1 ORDER BY expires_at"#,
+            )?;
+            let rows = stmt.query_map(params![now], |row| {
+                let attachment: Option<String> = row.get(2)?;
+                Ok(ExpiredMessage {
+                    conversation_id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    file_id: attachment
+                        .and_then(|json| serde_json::from_str::<Attachment>(&json).ok())
+                        .map(|attachment| attachment.file_id),
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for message in &expired {
+            let id = &message.message_id;
+            tx.execute("DELETE FROM messages WHERE message_id = ?1", params![id])?;
+            tx.execute("DELETE FROM message_delivery WHERE message_id = ?1", params![id])?;
+            tx.execute("DELETE FROM outbox WHERE message_id = ?1", params![id])?;
+        }
+
+        let mut conversations: Vec<&str> = expired.iter().map(|m| m.conversation_id.as_str()).collect();
+        conversations.sort_unstable();
+        conversations.dedup();
+        for conversation_id in conversations {
+            let latest = tx.query_row(
+                "SELECT content, timestamp FROM messages WHERE conversation_id = ?1 ORDER BY timestamp DESC LIMIT 1",
+                params![conversation_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+            );
+            let (preview, time) = match latest {
+                Ok((content, timestamp)) => (Some(truncate_graphemes(&content, 50).to_string()), Some(timestamp)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => (None, None),
+                Err(e) => return Err(e.into()),
+            };
+            // An emptied conversation keeps its place in the list
+            tx.execute(
+                r#"UPDATE conversations
+                   SET last_message = ?2, last_message_time = COALESCE(?3, last_message_time)
+                   WHERE id = ?1"#,
+                params![conversation_id, preview, time],
+            )?;
+        }
+        tx.commit()?;
+        Ok(expired)
+    }
+
+    pub fn delete_message(&self, message_id: &str) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM messages WHERE message_id = ?1", params![message_id])?;
+        tx.execute(
+            "DELETE FROM message_delivery WHERE message_id = ?1",
+            params![message_id],
+        )?;
+        tx.execute("DELETE FROM outbox WHERE message_id = ?1", params![message_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Remember that `sender_id` deleted a message not received yet, so
+    /// [`save_message`](Self::save_message) drops it when it arrives.
+    /// Deletions are forgotten after `DELETION_TTL_MS`.
+    pub fn record_deletion(&self, message_id: &str, sender_id: &str) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = chrono::Utc::now().timestamp_millis();
+        tx.execute(
+            "DELETE FROM deleted_messages WHERE deleted_at < ?1",
+            params![now - DELETION_TTL_MS],
+        )?;
+        tx.execute(
+            r#"INSERT OR REPLACE INTO deleted_messages (message_id, sender_id, deleted_at)
+               VALUES (?1, ?2, ?3)"#,
+            params![message_id, sender_id, now],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // ========================================================================
+    // Users cache
+    // ========================================================================
+
+    pub fn save_user(&self, user: &User) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT OR REPLACE INTO users
+               (user_id, display_name, avatar_file_id, public_key, last_seen_at, status_emoji, status_text, bio)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"#,
+            params![
+                user.user_id,
+                user.display_name,
+                user.avatar_file_id,
+                user.public_key,
+                user.last_seen_at,
+                user.status_emoji,
+                user.status_text,
+                user.bio,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_user(&self, user_id: &str) -> Result<Option<User>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            r#"SELECT user_id, display_name, avatar_file_id, public_key, last_seen_at,
+                      status_emoji, status_text, bio
+               FROM users WHERE user_id = ?1"#,
+            params![user_id],
+            |row| {
+                Ok(User {
                     user_id: row.get(0)?,
                     display_name: row.get(1)?,
                     avatar_file_id: row.get(2)?,
                     public_key: row.get(3)?,
                     last_seen_at: row.get(4)?,
+                    status_emoji: row.get(5)?,
+                    status_text: row.get(6)?,
+                    bio: row.get(7)?,
+                    last_seen_visibility: None,
                 })
             },
         );
@@ -400,6 +1340,666 @@ impl LocalStorage {
         }
     }
 
+    // ========================================================================
+    // Groups
+    // ========================================================================
+
+    /// Store a group as the server sent it, and name its conversation
+    /// after it, creating the conversation if there is none yet
+    pub fn save_group(&self, group: &Group) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            r#"INSERT OR REPLACE INTO groups (group_id, name, created_by, created_at, member_ids_json)
+               VALUES (?1, ?2, ?3, ?4, ?5)"#,
+            params![
+                group.group_id,
+                group.name,
+                group.created_by,
+                group.created_at,
+                serde_json::to_string(&group.member_ids)?,
+            ],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO conversations (id, peer_id, peer_name) VALUES (?1, ?1, ?2)",
+            params![group.group_id, group.name],
+        )?;
+        tx.execute(
+            "UPDATE conversations SET peer_name = ?2 WHERE id = ?1",
+            params![group.group_id, group.name],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_group(&self, group_id: &str) -> Result<Option<Group>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            r#"SELECT group_id, name, created_by, created_at, member_ids_json
+               FROM groups WHERE group_id = ?1"#,
+            params![group_id],
+            Self::group_from_row,
+        );
+
+        match result {
+            Ok(group) => Ok(Some(group)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn get_groups(&self) -> Result<Vec<Group>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT group_id, name, created_by, created_at, member_ids_json FROM groups ORDER BY name",
+        )?;
+        let groups = stmt
+            .query_map([], Self::group_from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(groups)
+    }
+
+    pub fn is_group(&self, conversation_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM groups WHERE group_id = ?1",
+            params![conversation_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    fn group_from_row(row: &rusqlite::Row) -> rusqlite::Result<Group> {
+        let member_ids: String = row.get(4)?;
+        Ok(Group {
+            group_id: row.get(0)?,
+            name: row.get(1)?,
+            created_by: row.get(2)?,
+            created_at: row.get(3)?,
+            member_ids: serde_json::from_str(&member_ids).unwrap_or_default(),
+        })
+    }
+
+    /// Note that the server said a peer's account was deactivated or
+    /// deleted, or that it is back. Returns whether anything changed.
+    pub fn set_peer_unavailable(&self, peer_id: &str, unavailable: bool) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let changed = if unavailable {
+            conn.execute(
+                "INSERT OR IGNORE INTO unavailable_peers (peer_id, since) VALUES (?1, ?2)",
+                params![peer_id, chrono::Utc::now().timestamp()],
+            )?
+        } else {
+            conn.execute("DELETE FROM unavailable_peers WHERE peer_id = ?1", params![peer_id])?
+        };
+        Ok(changed > 0)
+    }
+
+    pub fn is_peer_unavailable(&self, peer_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM unavailable_peers WHERE peer_id = ?1",
+            params![peer_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    // ========================================================================
+    // Drafts
+    // ========================================================================
+
+    /// Store a draft unless the one stored is as new or newer. Returns
+    /// whether it was stored.
+    pub fn save_draft(&self, draft: &Draft) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            r#"INSERT INTO drafts (conversation_id, text, updated_at) VALUES (?1, ?2, ?3)
+               ON CONFLICT(conversation_id) DO UPDATE SET text = excluded.text, updated_at = excluded.updated_at
+               WHERE excluded.updated_at > drafts.updated_at"#,
+            params![draft.conversation_id, draft.text, draft.updated_at],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// The stored draft, cleared ones included
+    pub fn get_draft(&self, conversation_id: &str) -> Result<Option<Draft>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT conversation_id, text, updated_at FROM drafts WHERE conversation_id = ?1",
+            params![conversation_id],
+            Self::draft_from_row,
+        );
+        match result {
+            Ok(draft) => Ok(Some(draft)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn draft_from_row(row: &rusqlite::Row) -> rusqlite::Result<Draft> {
+        Ok(Draft {
+            conversation_id: row.get(0)?,
+            text: row.get(1)?,
+            updated_at: row.get(2)?,
+        })
+    }
+
+    /// Drafts with text, most recently edited first
+    pub fn get_drafts(&self) -> Result<Vec<Draft>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT conversation_id, text, updated_at FROM drafts WHERE text != '' ORDER BY updated_at DESC",
+        )?;
+        let drafts = stmt
+            .query_map([], Self::draft_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(drafts)
+    }
+
+    // ========================================================================
+    // Peer Keys
+    // ========================================================================
+
+    /// The identity key trusted for a peer
+    pub fn get_trusted_key(&self, peer_id: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT public_key FROM trusted_keys WHERE peer_id = ?1",
+            params![peer_id],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(key) => Ok(Some(key)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Trust `public_key` for a peer, replacing any key and conflict before
+    pub fn trust_key(&self, peer_id: &str, public_key: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT OR REPLACE INTO trusted_keys (peer_id, public_key, trusted_at, pending_key, conflict_at)
+               VALUES (?1, ?2, ?3, NULL, NULL)"#,
+            params![peer_id, public_key, chrono::Utc::now().timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// Note that the server gave a key other than the trusted one. The
+    /// first time, the trusted key is archived along with the latest
+    /// message time of the conversation; a key turning up again keeps the
+    /// archive as is. Returns whether this is a new conflict.
+    pub fn record_key_conflict(&self, peer_id: &str, new_key: &str) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = chrono::Utc::now().timestamp_millis();
+        let pending: Option<String> = tx.query_row(
+            "SELECT pending_key FROM trusted_keys WHERE peer_id = ?1",
+            params![peer_id],
+            |row| row.get(0),
+        )?;
+        if pending.as_deref() == Some(new_key) {
+            return Ok(false);
+        }
+        if pending.is_none() {
+            tx.execute(
+                r#"INSERT INTO archived_keys (peer_id, public_key, trusted_at, archived_at, last_message_time)
+                   SELECT peer_id, public_key, trusted_at, ?2,
+                          (SELECT MAX(timestamp) FROM messages WHERE conversation_id = ?1)
+                   FROM trusted_keys WHERE peer_id = ?1"#,
+                params![peer_id, now],
+            )?;
+        }
+        tx.execute(
+            "UPDATE trusted_keys SET pending_key = ?2, conflict_at = ?3 WHERE peer_id = ?1",
+            params![peer_id, new_key, now],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    pub fn get_key_conflict(&self, peer_id: &str) -> Result<Option<KeyConflict>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            r#"SELECT peer_id, public_key, pending_key, conflict_at FROM trusted_keys
+               WHERE peer_id = ?1 AND pending_key IS NOT NULL"#,
+            params![peer_id],
+            Self::key_conflict_from_row,
+        );
+        match result {
+            Ok(conflict) => Ok(Some(conflict)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Conflicts waiting for the user, newest first
+    pub fn get_key_conflicts(&self) -> Result<Vec<KeyConflict>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT peer_id, public_key, pending_key, conflict_at FROM trusted_keys
+               WHERE pending_key IS NOT NULL
+               ORDER BY conflict_at DESC"#,
+        )?;
+        let conflicts = stmt
+            .query_map([], Self::key_conflict_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(conflicts)
+    }
+
+    fn key_conflict_from_row(row: &rusqlite::Row) -> rusqlite::Result<KeyConflict> {
+        Ok(KeyConflict {
+            peer_id: row.get(0)?,
+            trusted_key: row.get(1)?,
+            new_key: row.get(2)?,
+            detected_at: row.get(3)?,
+        })
+    }
+
+    /// Whether the user accepted an uncertified key of a peer's device
+    pub fn is_device_trusted(&self, peer_id: &str, device_id: &str, public_key: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT trusted FROM device_keys WHERE peer_id = ?1 AND device_id = ?2 AND public_key = ?3",
+            params![peer_id, device_id, public_key],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(trusted) => Ok(trusted),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Note an uncertified device key, waiting for the user. Returns
+    /// whether it wasn't noted before.
+    pub fn record_device_conflict(&self, peer_id: &str, device_id: &str, public_key: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let inserted = conn.execute(
+            r#"INSERT OR IGNORE INTO device_keys (peer_id, device_id, public_key, seen_at, trusted)
+               VALUES (?1, ?2, ?3, ?4, 0)"#,
+            params![peer_id, device_id, public_key, chrono::Utc::now().timestamp_millis()],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// A peer's uncertified device keys waiting for the user, oldest first
+    pub fn get_pending_devices(&self, peer_id: &str) -> Result<Vec<DeviceKey>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT device_id, public_key FROM device_keys
+               WHERE peer_id = ?1 AND trusted = 0
+               ORDER BY seen_at"#,
+        )?;
+        let keys = stmt
+            .query_map(params![peer_id], |row| {
+                Ok(DeviceKey {
+                    device_id: row.get(0)?,
+                    public_key: row.get(1)?,
+                    signature: None,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(keys)
+    }
+
+    /// Accept all of a peer's pending device keys; returns how many
+    pub fn trust_pending_devices(&self, peer_id: &str) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let trusted = conn.execute(
+            "UPDATE device_keys SET trusted = 1 WHERE peer_id = ?1 AND trusted = 0",
+            params![peer_id],
+        )?;
+        Ok(trusted)
+    }
+
+    /// Keys a peer used before, oldest first
+    pub fn get_archived_keys(&self, peer_id: &str) -> Result<Vec<ArchivedPeerKey>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT peer_id, public_key, trusted_at, archived_at, last_message_time
+               FROM archived_keys
+               WHERE peer_id = ?1
+               ORDER BY archived_at, id"#,
+        )?;
+        let keys = stmt
+            .query_map(params![peer_id], |row| {
+                Ok(ArchivedPeerKey {
+                    peer_id: row.get(0)?,
+                    public_key: row.get(1)?,
+                    trusted_at: row.get(2)?,
+                    archived_at: row.get(3)?,
+                    last_message_time: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(keys)
+    }
+
+    // ========================================================================
+    // Transfers
+    // ========================================================================
+
+    pub fn start_transfer(&self, transfer: &Transfer) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM transfer_ranges WHERE file_id = ?1",
+            params![transfer.file_id],
+        )?;
+        conn.execute(
+            r#"INSERT OR REPLACE INTO transfers (file_id, attachment_json, dest_path, part_path, total_size, range_size, paused, created_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"#,
+            params![
+                transfer.file_id,
+                serde_json::to_string(&transfer.attachment)?,
+                transfer.dest_path,
+                transfer.part_path,
+                transfer.total_size as i64,
+                transfer.range_size as i64,
+                transfer.paused,
+                chrono::Utc::now().timestamp_millis(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_transfer(&self, file_id: &str) -> Result<Option<Transfer>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            r#"SELECT file_id, attachment_json, dest_path, part_path, total_size, range_size, paused
+               FROM transfers WHERE file_id = ?1"#,
+            params![file_id],
+            Self::transfer_from_row,
+        );
+
+        let mut transfer = match result {
+            Ok(transfer) => transfer,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        transfer.completed = Self::completed_ranges(&conn, file_id)?;
+
+        Ok(Some(transfer))
+    }
+
+    /// Unfinished transfers, oldest first
+    pub fn list_transfers(&self) -> Result<Vec<Transfer>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT file_id, attachment_json, dest_path, part_path, total_size, range_size, paused
+               FROM transfers ORDER BY created_at"#,
+        )?;
+
+        let rows = stmt.query_map([], Self::transfer_from_row)?;
+        let mut transfers = Vec::new();
+        for row in rows {
+            let mut transfer = row?;
+            transfer.completed = Self::completed_ranges(&conn, &transfer.file_id)?;
+            transfers.push(transfer);
+        }
+
+        Ok(transfers)
+    }
+
+    pub fn set_transfer_paused(&self, file_id: &str, paused: bool) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE transfers SET paused = ?2 WHERE file_id = ?1",
+            params![file_id, paused],
+        )?;
+        Ok(())
+    }
+
+    fn transfer_from_row(row: &rusqlite::Row) -> rusqlite::Result<Transfer> {
+        let attachment_json: String = row.get(1)?;
+        let attachment = serde_json::from_str(&attachment_json).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+
+        Ok(Transfer {
+            file_id: row.get(0)?,
+            attachment,
+            dest_path: row.get(2)?,
+            part_path: row.get(3)?,
+            total_size: row.get::<_, i64>(4)? as u64,
+            range_size: row.get::<_, i64>(5)? as u64,
+            completed: Vec::new(),
+            paused: row.get(6)?,
+        })
+    }
+
+    fn completed_ranges(conn: &Connection, file_id: &str) -> Result<Vec<u64>> {
+        let mut stmt = conn.prepare(
+            "SELECT range_index FROM transfer_ranges WHERE file_id = ?1 ORDER BY range_index",
+        )?;
+        let completed = stmt
+            .query_map(params![file_id], |row| row.get::<_, i64>(0))?
+            .map(|index| index.map(|i| i as u64))
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(completed)
+    }
+
+    pub fn complete_transfer_range(&self, file_id: &str, range_index: u64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO transfer_ranges (file_id, range_index) VALUES (?1, ?2)",
+            params![file_id, range_index as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_transfer(&self, file_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM transfer_ranges WHERE file_id = ?1", params![file_id])?;
+        conn.execute("DELETE FROM transfers WHERE file_id = ?1", params![file_id])?;
+        Ok(())
+    }
+
+    // ========================================================================
+    // Custom messages
+    // ========================================================================
+
+    pub fn save_custom_message(&self, msg: &CustomMessage) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT OR IGNORE INTO custom_messages (message_id, sender_id, message_type, payload, timestamp)
+               VALUES (?1, ?2, ?3, ?4, ?5)"#,
+            params![msg.message_id, msg.sender_id, msg.message_type, msg.payload, msg.timestamp],
+        )?;
+        Ok(())
+    }
+
+    /// Custom messages of `message_type`, or of every type under it when it
+    /// is a vendor prefix like `x-acme`, oldest first
+    pub fn get_custom_messages(&self, message_type: &str) -> Result<Vec<CustomMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r#"SELECT message_id, sender_id, message_type, payload, timestamp
+               FROM custom_messages
+               WHERE message_type = ?1 OR substr(message_type, 1, length(?1) + 1) = ?1 || '-'
+               ORDER BY timestamp, rowid"#,
+        )?;
+        let messages = stmt
+            .query_map(params![message_type], |row| {
+                Ok(CustomMessage {
+                    message_id: row.get(0)?,
+                    sender_id: row.get(1)?,
+                    message_type: row.get(2)?,
+                    payload: row.get(3)?,
+                    timestamp: row.get(4)?,
+                })
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    // ========================================================================
+    // Message rules
+    // ========================================================================
+
+    /// Add a rule at the end, or replace the one with its id in place
+    pub fn save_message_rule(&self, rule: &MessageRule) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT INTO message_rules (id, rule_json, position)
+               VALUES (?1, ?2, (SELECT COALESCE(MAX(position), 0) + 1 FROM message_rules))
+               ON CONFLICT(id) DO UPDATE SET rule_json = excluded.rule_json"#,
+            params![rule.id, serde_json::to_string(rule)?],
+        )?;
+        Ok(())
+    }
+
+    /// Rules in the order they were added
+    pub fn get_message_rules(&self) -> Result<Vec<MessageRule>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT rule_json FROM message_rules ORDER BY position")?;
+        let rules = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|json| match json {
+                Ok(json) => serde_json::from_str(&json).ok().map(Ok),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(rules)
+    }
+
+    pub fn delete_message_rule(&self, id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM message_rules WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    // ========================================================================
+    // Delivery details
+    // ========================================================================
+
+    /// Record a send of `message_id`: `attempts` are added to earlier ones,
+    /// and a transport, set when an attempt succeeded, replaces the last
+    pub fn record_delivery(
+        &self,
+        message_id: &str,
+        transport: Option<DeliveryTransport>,
+        attempts: u32,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let sent_at = transport.map(|_| chrono::Utc::now().timestamp_millis());
+        conn.execute(
+            r#"INSERT INTO message_delivery (message_id, transport, attempts, sent_at)
+               VALUES (?1, ?2, ?3, ?4)
+               ON CONFLICT(message_id) DO UPDATE SET
+                   transport = COALESCE(excluded.transport, transport),
+                   attempts = attempts + excluded.attempts,
+                   sent_at = COALESCE(excluded.sent_at, sent_at)"#,
+            params![message_id, transport.map(|t| t.as_str()), attempts, sent_at],
+        )?;
+        Ok(())
+    }
+
+    /// Store when the server acknowledged messages sent from this device.
+    /// Ids without a delivery record, like acks of received messages, are
+    /// ignored.
+    pub fn set_acked(&self, acks: &[(String, i64)]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for (message_id, acked_at) in acks {
+            tx.execute(
+                "UPDATE message_delivery SET acked_at = ?2 WHERE message_id = ?1 AND acked_at IS NULL",
+                params![message_id, acked_at],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_delivery(&self, message_id: &str) -> Result<Option<DeliveryInfo>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT transport, attempts, sent_at, acked_at FROM message_delivery WHERE message_id = ?1",
+            params![message_id],
+            |row| {
+                Ok(DeliveryInfo {
+                    transport: row
+                        .get::<_, Option<String>>(0)?
+                        .and_then(|t| DeliveryTransport::parse(&t)),
+                    attempts: row.get(1)?,
+                    sent_at: row.get(2)?,
+                    acked_at: row.get(3)?,
+                })
+            },
+        );
+        match result {
+            Ok(delivery) => Ok(Some(delivery)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // ========================================================================
+    // Outbox
+    // ========================================================================
+
+    /// Put a message that couldn't be sent in the outbox, or count another
+    /// attempt for one already there; returns the attempts so far
+    pub fn queue_outgoing(&self, message_id: &str) -> Result<u32> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            r#"INSERT INTO outbox (message_id, attempts, next_attempt_at)
+               VALUES (?1, 1, 0)
+               ON CONFLICT(message_id) DO UPDATE SET attempts = attempts + 1"#,
+            params![message_id],
+        )?;
+        let attempts = tx.query_row(
+            "SELECT attempts FROM outbox WHERE message_id = ?1",
+            params![message_id],
+            |row| row.get(0),
+        )?;
+        tx.commit()?;
+        Ok(attempts)
+    }
+
+    /// Set when a queued message is next tried, in Unix millis
+    pub fn schedule_outgoing(&self, message_id: &str, next_attempt_at: i64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE outbox SET next_attempt_at = ?2 WHERE message_id = ?1",
+            params![message_id, next_attempt_at],
+        )?;
+        Ok(())
+    }
+
+    /// Ids of queued messages due for another attempt at `now`, oldest first
+    pub fn get_due_outgoing(&self, now: i64) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT message_id FROM outbox WHERE next_attempt_at <= ?1 ORDER BY rowid",
+        )?;
+        let ids = stmt
+            .query_map(params![now], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<String>, _>>()?;
+        Ok(ids)
+    }
+
+    /// Take a message out of the outbox once it was sent or gave up on
+    pub fn remove_outgoing(&self, message_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM outbox WHERE message_id = ?1", params![message_id])?;
+        Ok(())
+    }
+
+    // ========================================================================
+    // Session keys
+    // ========================================================================
+
+    /// Number of sessions kept for after a restart or eviction from memory
+    pub fn get_session_count(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM session_keys", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
     // ========================================================================
     // Storage management
     // ========================================================================
@@ -413,11 +2013,33 @@ impl LocalStorage {
             DELETE FROM users;
             DELETE FROM settings;
             DELETE FROM session_keys;
+            DELETE FROM transfers;
+            DELETE FROM transfer_ranges;
+            DELETE FROM custom_messages;
+            DELETE FROM message_rules;
+            DELETE FROM message_delivery;
+            DELETE FROM outbox;
+            DELETE FROM deleted_messages;
+            DELETE FROM unavailable_peers;
+            DELETE FROM drafts;
+            DELETE FROM trusted_keys;
+            DELETE FROM archived_keys;
+            DELETE FROM device_keys;
+            DELETE FROM groups;
             "#,
         )?;
         Ok(())
     }
 
+    /// Clear everything and compact the database, so deleted rows don't
+    /// linger in free pages
+    pub fn wipe(&self) -> Result<()> {
+        self.clear_all()?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
     pub fn get_storage_size(&self) -> Result<u64> {
         // Approximate based on page count
         let conn = self.conn.lock().unwrap();
@@ -426,3 +2048,94 @@ impl LocalStorage {
         Ok((page_count * page_size) as u64)
     }
 }
+
+/// The crypto engine's sessions, kept across restarts
+impl SessionStore for LocalStorage {
+    fn save_session(&self, peer_id: &str, session: &StoredSession) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r#"INSERT OR REPLACE INTO session_keys (peer_id, shared_secret, created_at, suite, tagged, peer_key, ratchet)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+            params![
+                peer_id,
+                URL_SAFE_NO_PAD.encode(&session.sealed_secret),
+                session.created_at,
+                session.suite.id(),
+                session.tagged,
+                session.peer_key.map(|key| URL_SAFE_NO_PAD.encode(key)),
+                session.sealed_ratchet.as_ref().map(|sealed| URL_SAFE_NO_PAD.encode(sealed)),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_session(&self, peer_id: &str) -> Result<Option<StoredSession>> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.query_row(
+            "SELECT shared_secret, created_at, suite, tagged, peer_key, ratchet FROM session_keys WHERE peer_id = ?1",
+            params![peer_id],
+            Self::stored_session_columns,
+        );
+        match result {
+            Ok(row) => Ok(Some(Self::stored_session_from_columns(row)?)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn load_sessions(&self) -> Result<Vec<(String, StoredSession)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT shared_secret, created_at, suite, tagged, peer_key, ratchet, peer_id FROM session_keys
+             ORDER BY created_at DESC",
+        )?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(6)?, Self::stored_session_columns(row)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(peer_id, columns)| Ok((peer_id, Self::stored_session_from_columns(columns)?)))
+            .collect()
+    }
+
+    fn delete_session(&self, peer_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM session_keys WHERE peer_id = ?1", params![peer_id])?;
+        Ok(())
+    }
+}
+
+type StoredSessionColumns = (String, i64, Option<String>, bool, Option<String>, Option<String>);
+
+impl LocalStorage {
+    fn stored_session_columns(row: &rusqlite::Row) -> rusqlite::Result<StoredSessionColumns> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+    }
+
+    fn stored_session_from_columns(
+        (secret, created_at, suite, tagged, peer_key, ratchet): StoredSessionColumns,
+    ) -> Result<StoredSession> {
+        let suite = match suite {
+            Some(id) => CipherSuite::from_id(&id).ok_or_else(|| Error::Crypto(format!("Unknown cipher suite: {}", id)))?,
+            None => CipherSuite::X25519Aes256Gcm,
+        };
+        let decode = |value: String| {
+            URL_SAFE_NO_PAD
+                .decode(value)
+                .map_err(|_| Error::Crypto("Invalid stored session".into()))
+        };
+        let peer_key = match peer_key {
+            Some(key) => Some(
+                <[u8; 32]>::try_from(decode(key)?).map_err(|_| Error::Crypto("Invalid stored session".into()))?,
+            ),
+            None => None,
+        };
+        Ok(StoredSession {
+            suite,
+            tagged,
+            sealed_secret: decode(secret)?,
+            peer_key,
+            sealed_ratchet: ratchet.map(decode).transpose()?,
+            created_at,
+        })
+    }
+}
@@ -0,0 +1,148 @@
+//! Decrypted attachments opened for preview or playback
+//!
+//! Each one is decrypted into `tmp/open` and tracked until it is closed.
+//! Closing shreds the file: its contents are overwritten with zeros before
+//! it is deleted, so the plain text doesn't linger in free disk blocks.
+//! Whatever is still open when the client is dropped or when its message
+//! disappears is shredded then, and leftovers of a run that crashed are
+//! shredded on the next start.
+//! Attachments up to the in-memory limit never touch the disk decrypted.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::error::Result;
+
+/// Zeros written per call while shredding
+const SHRED_CHUNK: usize = 64 * 1024;
+
+/// A decrypted attachment returned by `open_attachment`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenedAttachment {
+    /// Small enough to be kept in memory only
+    Memory(Vec<u8>),
+    /// A temp file, shredded by `close_attachment` or when the client is
+    /// dropped
+    File(PathBuf),
+}
+
+pub(crate) struct TempFiles {
+    dir: PathBuf,
+    /// Open files with the file id of their attachment
+    open: Mutex<HashMap<PathBuf, String>>,
+    /// Largest attachment decrypted in memory instead of to a file
+    memory_limit: RwLock<Option<u64>>,
+}
+
+impl TempFiles {
+    pub(crate) fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        for entry in std::fs::read_dir(&dir)?.flatten() {
+            if let Err(e) = shred(&entry.path()) {
+                log::warn!("Shredding leftover {}: {}", entry.path().display(), e);
+            }
+        }
+        Ok(Self {
+            dir,
+            open: Mutex::new(HashMap::new()),
+            memory_limit: RwLock::new(None),
+        })
+    }
+
+    pub(crate) fn memory_limit(&self) -> Option<u64> {
+        *self.memory_limit.read()
+    }
+
+    pub(crate) fn set_memory_limit(&self, max_size: Option<u64>) {
+        *self.memory_limit.write() = max_size;
+    }
+
+    /// A new tracked path for a decrypted `file_name` of the attachment
+    /// `file_id`, keeping its extension for players that go by it
+    pub(crate) fn create(&self, file_name: &str, file_id: &str) -> PathBuf {
+        let mut name = uuid::Uuid::new_v4().to_string();
+        if let Some(ext) = Path::new(file_name).extension().and_then(|e| e.to_str()) {
+            if ext.chars().all(|c| c.is_ascii_alphanumeric()) {
+                name = format!("{}.{}", name, ext);
+            }
+        }
+        let path = self.dir.join(name);
+        self.open.lock().insert(path.clone(), file_id.to_string());
+        path
+    }
+
+    /// Shred a tracked file. Returns false if `path` isn't one.
+    pub(crate) fn close(&self, path: &Path) -> Result<bool> {
+        if self.open.lock().remove(path).is_none() {
+            return Ok(false);
+        }
+        shred(path)?;
+        Ok(true)
+    }
+
+    /// Shred every tracked file; returns how many there were
+    pub(crate) fn close_all(&self) -> usize {
+        let paths: Vec<PathBuf> = self.open.lock().drain().map(|(path, _)| path).collect();
+        shred_all(&paths);
+        paths.len()
+    }
+
+    /// Shred the tracked files of the attachment `file_id`; returns how
+    /// many there were
+    pub(crate) fn close_file(&self, file_id: &str) -> usize {
+        let mut open = self.open.lock();
+        let paths: Vec<PathBuf> = open
+            .iter()
+            .filter(|(_, id)| id.as_str() == file_id)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &paths {
+            open.remove(path);
+        }
+        drop(open);
+        shred_all(&paths);
+        paths.len()
+    }
+
+    pub(crate) fn open_files(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.open.lock().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
+
+fn shred_all(paths: &[PathBuf]) {
+    for path in paths {
+        if let Err(e) = shred(path) {
+            log::warn!("Shredding {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Overwrite a file with zeros, then delete it. A missing file is fine.
+pub(crate) fn shred(path: &Path) -> std::io::Result<()> {
+    let mut file = match std::fs::OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let zeros = [0u8; SHRED_CHUNK];
+    let mut remaining = file.metadata()?.len();
+    while remaining > 0 {
+        let n = remaining.min(SHRED_CHUNK as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        remaining -= n as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(path)
+}
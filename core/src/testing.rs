@@ -0,0 +1,4151 @@
+//! In-process mock server for tests (`test-util` feature)
+//!
+//! `MockServer` implements the network transports in memory so code built
+//! on `PrivMsgClient` can be tested without a live server:
+//!
+//! ```no_run
+//! use privmsg_core::testing::{Fault, MockServer};
+//!
+//! let server = MockServer::new();
+//! let client = server.client("/tmp/privmsg-test").unwrap();
+//! client.init_keys(None).unwrap();
+//! client.login("alice", "key", "test").unwrap();
+//!
+//! server.inject_fault(Fault::Unauthorized, 1);
+//! assert!(client.send_message("bob", "hi").is_err());
+//! ```
+
+use crate::error::{Error, ErrorCode, Result, RevocationReason, ServerError};
+use crate::models::*;
+use crate::transport::{ApiTransport, UploadedFile, WsConnector, WsTransport};
+use crate::{PowerSource, PrivMsgClient};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long uploaded files live, like the server's default
+/// `max_file_age_hours`
+const FILE_AGE_HOURS: i64 = 72;
+
+/// A failure applied to the next API call or WebSocket send
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Reject with 401 (login fails with invalid credentials)
+    Unauthorized,
+    /// Fail as if the connection broke
+    Network(String),
+    /// Drop the request: WebSocket sends vanish silently, API calls time out
+    Drop,
+    /// Refuse the request: API calls fail with this code, WebSocket sends
+    /// are answered with an `error` frame
+    Reject(ErrorCode),
+}
+
+#[derive(Default)]
+struct MockState {
+    users: HashMap<String, User>,
+    /// Accounts an admin deactivated
+    deactivated: HashSet<String>,
+    credentials: HashMap<String, String>,
+    files: HashMap<String, Vec<u8>>,
+    file_expiry: HashMap<String, i64>,
+    client_policy: Option<SignedPolicy>,
+    /// Signed-in devices with their user ids
+    devices: Vec<(String, Device)>,
+    /// Device keys by user id, in the order the devices signed in
+    device_keys: HashMap<String, Vec<DeviceKey>>,
+    groups: HashMap<String, Group>,
+    device_renames: VecDeque<DeviceRenamed>,
+    /// Receipts for the client's messages, not yet received
+    deliveries: VecDeque<DeliveryReceipt>,
+    /// The current announcement, returned at login
+    announcement: Option<Announcement>,
+    /// Announcement frames not yet received
+    announcement_frames: VecDeque<Option<Announcement>>,
+    /// Proof of work asked of logins, when set
+    login_difficulty: Option<u8>,
+    /// Challenges issued and not yet used
+    login_challenges: HashSet<String>,
+    /// Refuses logins while set
+    maintenance: Option<Maintenance>,
+    maintenance_frames: VecDeque<Option<Maintenance>>,
+    turn: Option<TurnCredentials>,
+    sent: Vec<MessageEnvelope>,
+    typing: Vec<(String, bool)>,
+    presence: Vec<PresenceStatus>,
+    power_source: PowerSource,
+    call_signals: Vec<CallSignal>,
+    acked: Vec<String>,
+    /// Messages whose undelivered copies the client had dropped
+    retracted: Vec<String>,
+    /// Acks the server sent back for envelopes it took
+    server_acks: VecDeque<String>,
+    inbox: VecDeque<MessageEnvelope>,
+    incoming_signals: VecDeque<CallSignal>,
+    rejections: VecDeque<ServerError>,
+    revoked: Option<RevocationReason>,
+    /// Users who ended all their sessions
+    logged_out_everywhere: Vec<String>,
+    faults: VecDeque<Fault>,
+    latency: Duration,
+    clock_skew: i64,
+    unauthorized: bool,
+    connected: bool,
+    next_id: u64,
+}
+
+impl MockState {
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}-{}", prefix, self.next_id)
+    }
+}
+
+/// Scriptable in-memory server shared by a mock API and WebSocket
+#[derive(Clone, Default)]
+pub struct MockServer {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build a `PrivMsgClient` wired to this server
+    pub fn client(&self, data_dir: &str) -> Result<PrivMsgClient> {
+        PrivMsgClient::with_transport(data_dir, self.api(), self.connector())
+    }
+
+    pub fn api(&self) -> Arc<dyn ApiTransport> {
+        Arc::new(MockApi {
+            server: self.clone(),
+            user_id: Mutex::new(None),
+        })
+    }
+
+    pub fn connector(&self) -> Arc<dyn WsConnector> {
+        Arc::new(MockConnector {
+            server: self.clone(),
+        })
+    }
+
+    // ========================================================================
+    // Scripting
+    // ========================================================================
+
+    /// Register a user returned by `get_user`
+    pub fn add_user(&self, user: User) {
+        self.state.lock().users.insert(user.user_id.clone(), user);
+    }
+
+    /// Only accept this access key for `user_id`. Users without
+    /// credentials can log in with any key.
+    pub fn set_credentials(&self, user_id: &str, access_key: &str) {
+        self.state
+            .lock()
+            .credentials
+            .insert(user_id.to_string(), access_key.to_string());
+    }
+
+    pub fn set_turn_credentials(&self, credentials: TurnCredentials) {
+        self.state.lock().turn = Some(credentials);
+    }
+
+    /// Serve `policy` from `get_client_policy`
+    pub fn set_client_policy(&self, policy: SignedPolicy) {
+        self.state.lock().client_policy = Some(policy);
+    }
+
+    /// Create or replace a group, as if another client had changed it
+    pub fn set_group(&self, group: Group) {
+        self.state.lock().groups.insert(group.group_id.clone(), group);
+    }
+
+    /// Queue an envelope for delivery to the client
+    pub fn push_incoming(&self, envelope: MessageEnvelope) {
+        self.state.lock().inbox.push_back(envelope);
+    }
+
+    /// Ask for a proof of work of `difficulty` bits with each login, or
+    /// stop asking with `None`
+    pub fn set_login_difficulty(&self, difficulty: Option<u8>) {
+        let mut state = self.state.lock();
+        state.login_difficulty = difficulty;
+        state.login_challenges.clear();
+    }
+
+    /// Deactivate or reactivate an account; messages to a deactivated one
+    /// are rejected
+    pub fn set_deactivated(&self, user_id: &str, deactivated: bool) {
+        let mut state = self.state.lock();
+        if deactivated {
+            state.deactivated.insert(user_id.to_string());
+        } else {
+            state.deactivated.remove(user_id);
+        }
+    }
+
+    /// Set or clear the announcement, sending it to connected clients
+    pub fn set_announcement(&self, announcement: Option<Announcement>) {
+        let mut state = self.state.lock();
+        state.announcement = announcement.clone();
+        state.announcement_frames.push_back(announcement);
+    }
+
+    /// Turn maintenance mode on or off, telling connected clients
+    pub fn set_maintenance(&self, maintenance: Option<Maintenance>) {
+        let mut state = self.state.lock();
+        state.maintenance = maintenance.clone();
+        state.maintenance_frames.push_back(maintenance);
+    }
+
+    /// Sign in a device of `user_id` with its own key, as listed to senders
+    pub fn add_device_key(&self, user_id: &str, key: DeviceKey) {
+        self.state
+            .lock()
+            .device_keys
+            .entry(user_id.to_string())
+            .or_default()
+            .push(key);
+    }
+
+    /// The devices listed for `user_id`, with their certificates
+    pub fn device_keys(&self, user_id: &str) -> Vec<DeviceKey> {
+        self.state.lock().device_keys.get(user_id).cloned().unwrap_or_default()
+    }
+
+    /// Tell the client one of its user's other devices was renamed
+    pub fn push_device_rename(&self, rename: DeviceRenamed) {
+        self.state.lock().device_renames.push_back(rename);
+    }
+
+    /// Tell the client a device of `receipt.recipient_id` received its messages
+    pub fn push_delivery(&self, receipt: DeliveryReceipt) {
+        self.state.lock().deliveries.push_back(receipt);
+    }
+
+    /// Queue a call signal for delivery to the client
+    pub fn push_call_signal(&self, signal: CallSignal) {
+        self.state.lock().incoming_signals.push_back(signal);
+    }
+
+    /// Envelopes the client sent, oldest first
+    pub fn sent_messages(&self) -> Vec<MessageEnvelope> {
+        self.state.lock().sent.clone()
+    }
+
+    /// Typing notifications the client sent as (recipient_id, is_typing)
+    pub fn sent_typing(&self) -> Vec<(String, bool)> {
+        self.state.lock().typing.clone()
+    }
+
+    /// Presence updates the client sent, oldest first
+    pub fn sent_presence(&self) -> Vec<PresenceStatus> {
+        self.state.lock().presence.clone()
+    }
+
+    /// What the client last told its WebSocket about the power source
+    pub fn power_source(&self) -> PowerSource {
+        self.state.lock().power_source
+    }
+
+    pub fn sent_call_signals(&self) -> Vec<CallSignal> {
+        self.state.lock().call_signals.clone()
+    }
+
+    /// Message ids the client acknowledged, in order
+    pub fn acked_messages(&self) -> Vec<String> {
+        self.state.lock().acked.clone()
+    }
+
+    /// Message ids the client retracted, in order
+    pub fn retracted_messages(&self) -> Vec<String> {
+        self.state.lock().retracted.clone()
+    }
+
+    /// Encrypted bytes uploaded under `file_id`
+    pub fn uploaded_file(&self, file_id: &str) -> Option<Vec<u8>> {
+        self.state.lock().files.get(file_id).cloned()
+    }
+
+    /// When `file_id` expires, as set at upload or extended since
+    pub fn file_expiry(&self, file_id: &str) -> Option<i64> {
+        self.state.lock().file_expiry.get(file_id).copied()
+    }
+
+    // ========================================================================
+    // Fault Injection
+    // ========================================================================
+
+    /// Apply `fault` to the next `count` API calls or WebSocket sends
+    pub fn inject_fault(&self, fault: Fault, count: usize) {
+        let mut state = self.state.lock();
+        state.faults.extend(std::iter::repeat_n(fault, count));
+    }
+
+    /// Delay every API call and WebSocket send
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().latency = latency;
+    }
+
+    /// Run the server's clock this many seconds ahead of the client's
+    pub fn set_clock_skew(&self, seconds: i64) {
+        self.state.lock().clock_skew = seconds;
+    }
+
+    /// Reject every request with 401 until cleared, e.g. a revoked session
+    pub fn set_unauthorized(&self, unauthorized: bool) {
+        self.state.lock().unauthorized = unauthorized;
+    }
+
+    /// End the session the way the server does when a device is removed
+    /// or the account deactivated
+    pub fn revoke_session(&self, reason: RevocationReason) {
+        let mut state = self.state.lock();
+        state.revoked = Some(reason);
+        state.connected = false;
+    }
+
+    /// Whether `user_id` ended all their sessions
+    pub fn logged_out_everywhere(&self, user_id: &str) -> bool {
+        self.state
+            .lock()
+            .logged_out_everywhere
+            .iter()
+            .any(|u| u == user_id)
+    }
+
+    /// Drop the WebSocket connection
+    pub fn disconnect(&self) {
+        self.state.lock().connected = false;
+    }
+
+    /// Wait out the latency, then take the next fault if any
+    async fn before_request(&self) -> Option<Fault> {
+        let latency = self.state.lock().latency;
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let mut state = self.state.lock();
+        if state.unauthorized {
+            return Some(Fault::Unauthorized);
+        }
+        state.faults.pop_front()
+    }
+
+    /// Turn a fault on an API call into its error
+    async fn api_fault(&self) -> Result<()> {
+        match self.before_request().await {
+            None => Ok(()),
+            Some(Fault::Unauthorized) => Err(Error::Http("401 Unauthorized".into())),
+            Some(Fault::Network(msg)) => Err(Error::Network(msg)),
+            Some(Fault::Drop) => Err(Error::Http("operation timed out".into())),
+            Some(Fault::Reject(code)) => Err(ServerError::new(code, "Rejected by mock").into()),
+        }
+    }
+}
+
+// ============================================================================
+// Mock API
+// ============================================================================
+
+struct MockApi {
+    server: MockServer,
+    /// Who logged in through this client
+    user_id: Mutex<Option<String>>,
+}
+
+#[async_trait]
+impl ApiTransport for MockApi {
+    async fn login_challenge(&self) -> Result<Option<LoginChallenge>> {
+        let mut state = self.server.state.lock();
+        let Some(difficulty) = state.login_difficulty else {
+            return Ok(None);
+        };
+        let challenge = state.next_id("challenge");
+        state.login_challenges.insert(challenge.clone());
+        Ok(Some(LoginChallenge {
+            challenge,
+            difficulty,
+            expires_at: chrono::Utc::now().timestamp() + 120,
+        }))
+    }
+
+    async fn login(
+        &self,
+        user_id: &str,
+        access_key: &str,
+        device_name: &str,
+        device_public_key: &str,
+        proof: Option<&LoginProof>,
+    ) -> Result<AuthSession> {
+        match self.server.before_request().await {
+            Some(Fault::Unauthorized) => return Err(Error::InvalidCredentials),
+            Some(Fault::Network(msg)) => return Err(Error::Network(msg)),
+            Some(Fault::Drop) => return Err(Error::Http("operation timed out".into())),
+            Some(Fault::Reject(code)) => {
+                return Err(ServerError::new(code, "Rejected by mock").into())
+            }
+            None => {}
+        }
+
+        let mut state = self.server.state.lock();
+        if state.maintenance.is_some() {
+            return Err(ServerError::new(ErrorCode::Maintenance, "Server is down for maintenance").into());
+        }
+        if let Some(difficulty) = state.login_difficulty {
+            let valid = proof.is_some_and(|proof| {
+                state.login_challenges.remove(&proof.challenge)
+                    && privmsg_proto::pow::verify(&proof.challenge, proof.nonce, difficulty)
+            });
+            if !valid {
+                return Err(Error::ChallengeRequired);
+            }
+        }
+        if let Some(expected) = state.credentials.get(user_id) {
+            if expected != access_key {
+                return Err(Error::InvalidCredentials);
+            }
+        }
+
+        // Publish the key so other clients can reach this user
+        state
+            .users
+            .entry(user_id.to_string())
+            .or_insert_with(|| User {
+                user_id: user_id.to_string(),
+                display_name: None,
+                avatar_file_id: None,
+                public_key: None,
+                last_seen_at: None,
+                status_emoji: None,
+                status_text: None,
+                bio: None,
+                last_seen_visibility: None,
+            })
+            .public_key = Some(device_public_key.to_string());
+
+        let device_id = state.next_id("device");
+        let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        state.devices.push((
+            user_id.to_string(),
+            Device {
+                device_id: device_id.clone(),
+                device_name: device_name.to_string(),
+                device_type: "test".into(),
+                created_at: now.clone(),
+                last_active_at: now,
+            },
+        ));
+        state
+            .device_keys
+            .entry(user_id.to_string())
+            .or_default()
+            .push(DeviceKey {
+                device_id: device_id.clone(),
+                public_key: device_public_key.to_string(),
+                signature: None,
+            });
+        *self.user_id.lock() = Some(user_id.to_string());
+
+        Ok(AuthSession {
+            token: state.next_id("token"),
+            device_id,
+            user_id: user_id.to_string(),
+            expires_at: chrono::Utc::now().timestamp() + 86400,
+            announcement: state.announcement.clone(),
+        })
+    }
+
+    async fn get_user(&self, user_id: &str) -> Result<User> {
+        self.server.api_fault().await?;
+        let state = self.server.state.lock();
+        if state.deactivated.contains(user_id) {
+            return Err(ServerError::new(ErrorCode::RecipientUnavailable, "No longer available").into());
+        }
+        state
+            .users
+            .get(user_id)
+            .cloned()
+            .ok_or_else(|| Error::UserNotFound(user_id.to_string()))
+    }
+
+    async fn upload_file(
+        &self,
+        data: Vec<u8>,
+        _file_name: &str,
+        _mime_type: &str,
+        _encryption_key_hash: &str,
+    ) -> Result<UploadedFile> {
+        self.server.api_fault().await?;
+        let mut state = self.server.state.lock();
+        let file_id = state.next_id("file");
+        let expires_at = chrono::Utc::now().timestamp() + FILE_AGE_HOURS * 3600;
+        state.files.insert(file_id.clone(), data);
+        state.file_expiry.insert(file_id.clone(), expires_at);
+        Ok(UploadedFile { file_id, expires_at: Some(expires_at) })
+    }
+
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        self.server.api_fault().await?;
+        self.server
+            .state
+            .lock()
+            .files
+            .get(file_id)
+            .cloned()
+            .ok_or_else(|| Error::Http("404 Not Found".into()))
+    }
+
+    async fn get_turn_credentials(&self) -> Result<TurnCredentials> {
+        self.server.api_fault().await?;
+        self.server
+            .state
+            .lock()
+            .turn
+            .clone()
+            .ok_or_else(|| Error::Http("404 Not Found".into()))
+    }
+
+    async fn check_health(&self) -> Result<bool> {
+        Ok(self.server.api_fault().await.is_ok())
+    }
+
+    async fn server_time(&self) -> Result<Option<i64>> {
+        self.server.api_fault().await?;
+        Ok(Some(chrono::Utc::now().timestamp() + self.server.state.lock().clock_skew))
+    }
+
+    async fn extend_file(&self, file_id: &str, hours: u64) -> Result<i64> {
+        self.server.api_fault().await?;
+        let mut state = self.server.state.lock();
+        if !state.files.contains_key(file_id) {
+            return Err(Error::Http("404 Not Found".into()));
+        }
+        let requested = chrono::Utc::now().timestamp() + hours as i64 * 3600;
+        let expires_at = state.file_expiry.get(file_id).map_or(requested, |&e| e.max(requested));
+        state.file_expiry.insert(file_id.to_string(), expires_at);
+        Ok(expires_at)
+    }
+
+    async fn delete_file(&self, file_id: &str) -> Result<()> {
+        self.server.api_fault().await?;
+        let mut state = self.server.state.lock();
+        state.file_expiry.remove(file_id);
+        state
+            .files
+            .remove(file_id)
+            .map(|_| ())
+            .ok_or_else(|| Error::Http("404 Not Found".into()))
+    }
+
+    async fn get_client_policy(&self) -> Result<Option<SignedPolicy>> {
+        self.server.api_fault().await?;
+        Ok(self.server.state.lock().client_policy.clone())
+    }
+
+    // Not subject to injected faults, which are meant for the send or
+    // request that follows
+    async fn get_device_keys(&self, user_id: &str) -> Result<Vec<DeviceKey>> {
+        let state = self.server.state.lock();
+        Ok(state.device_keys.get(user_id).cloned().unwrap_or_default())
+    }
+
+    async fn certify_device(&self, device_id: &str, signature: &str) -> Result<()> {
+        self.server.api_fault().await?;
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        let mut state = self.server.state.lock();
+        let key = state
+            .device_keys
+            .get_mut(&user_id)
+            .and_then(|keys| keys.iter_mut().find(|key| key.device_id == device_id))
+            .ok_or_else(|| Error::Http("404 Not Found".into()))?;
+        key.signature = Some(signature.to_string());
+        Ok(())
+    }
+
+    async fn retract_message(&self, message_id: &str) -> Result<()> {
+        self.server.api_fault().await?;
+        self.server.state.lock().retracted.push(message_id.to_string());
+        Ok(())
+    }
+
+    async fn list_devices(&self) -> Result<Vec<Device>> {
+        self.server.api_fault().await?;
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        Ok(self
+            .server
+            .state
+            .lock()
+            .devices
+            .iter()
+            .filter(|(owner, _)| *owner == user_id)
+            .map(|(_, device)| device.clone())
+            .collect())
+    }
+
+    async fn rename_device(&self, device_id: &str, device_name: &str) -> Result<()> {
+        self.server.api_fault().await?;
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        let mut state = self.server.state.lock();
+        let (_, device) = state
+            .devices
+            .iter_mut()
+            .find(|(owner, device)| *owner == user_id && device.device_id == device_id)
+            .ok_or_else(|| Error::Http("404 Not Found".into()))?;
+        device.device_name = device_name.to_string();
+        Ok(())
+    }
+
+    async fn logout_all(&self) -> Result<()> {
+        self.server.api_fault().await?;
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        let mut state = self.server.state.lock();
+        state.logged_out_everywhere.push(user_id);
+        state.revoked = Some(RevocationReason::LoggedOut);
+        state.connected = false;
+        Ok(())
+    }
+
+    async fn create_group(&self, name: &str, member_ids: &[String]) -> Result<Group> {
+        self.server.api_fault().await?;
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        let mut state = self.server.state.lock();
+        let mut members = vec![user_id.clone()];
+        members.extend(member_ids.iter().filter(|id| **id != user_id).cloned());
+        let group = Group {
+            group_id: state.next_id("group"),
+            name: name.to_string(),
+            created_by: user_id,
+            created_at: chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            member_ids: members,
+        };
+        state.groups.insert(group.group_id.clone(), group.clone());
+        Ok(group)
+    }
+
+    async fn list_groups(&self) -> Result<Vec<Group>> {
+        self.server.api_fault().await?;
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        let state = self.server.state.lock();
+        Ok(state.groups.values().filter(|g| g.is_member(&user_id)).cloned().collect())
+    }
+
+    async fn get_group(&self, group_id: &str) -> Result<Group> {
+        self.server.api_fault().await?;
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        let state = self.server.state.lock();
+        state
+            .groups
+            .get(group_id)
+            .filter(|g| g.is_member(&user_id))
+            .cloned()
+            .ok_or_else(|| ServerError::new(ErrorCode::NotFound, "Group not found").into())
+    }
+
+    async fn add_group_members(&self, group_id: &str, member_ids: &[String]) -> Result<Group> {
+        let mut group = self.get_group(group_id).await?;
+        for member_id in member_ids {
+            if !group.is_member(member_id) {
+                group.member_ids.push(member_id.clone());
+            }
+        }
+        self.server.set_group(group.clone());
+        Ok(group)
+    }
+
+    async fn remove_group_member(&self, group_id: &str, user_id: &str) -> Result<()> {
+        let mut group = self.get_group(group_id).await?;
+        group.member_ids.retain(|id| id != user_id);
+        self.server.set_group(group);
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Mock WebSocket
+// ============================================================================
+
+struct MockConnector {
+    server: MockServer,
+}
+
+#[async_trait]
+impl WsConnector for MockConnector {
+    async fn connect(&self, _token: &str) -> Result<Box<dyn WsTransport>> {
+        match self.server.before_request().await {
+            Some(Fault::Unauthorized) => return Err(Error::WebSocket("HTTP error: 401 Unauthorized".into())),
+            Some(Fault::Network(msg)) => return Err(Error::WebSocket(msg)),
+            Some(Fault::Drop) => return Err(Error::WebSocket("WebSocket connect timed out".into())),
+            Some(Fault::Reject(code)) => {
+                return Err(ServerError::new(code, "Rejected by mock").into())
+            }
+            None => {}
+        }
+
+        let mut state = self.server.state.lock();
+        state.connected = true;
+        state.revoked = None;
+        drop(state);
+        Ok(Box::new(MockWs {
+            server: self.server.clone(),
+        }))
+    }
+}
+
+struct MockWs {
+    server: MockServer,
+}
+
+impl MockWs {
+    /// Apply faults to an outgoing frame; `Ok(false)` means it was dropped
+    async fn before_send(&self) -> Result<bool> {
+        if !self.is_connected() {
+            return Err(Error::WebSocket("Not connected".into()));
+        }
+        match self.server.before_request().await {
+            None => Ok(true),
+            Some(Fault::Drop) => Ok(false),
+            Some(Fault::Reject(code)) => {
+                let error = ServerError::new(code, "Rejected by mock");
+                self.server.state.lock().rejections.push_back(error);
+                Ok(false)
+            }
+            Some(Fault::Unauthorized) => {
+                self.server.disconnect();
+                Err(Error::WebSocket("Session revoked".into()))
+            }
+            Some(Fault::Network(msg)) => {
+                self.server.disconnect();
+                Err(Error::WebSocket(msg))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl WsTransport for MockWs {
+    async fn send_message(&self, envelope: &MessageEnvelope) -> Result<()> {
+        if self.before_send().await? {
+            let mut state = self.server.state.lock();
+            if state.deactivated.contains(&envelope.recipient_id) {
+                let error = ServerError::new(ErrorCode::RecipientUnavailable, "No longer available")
+                    .for_message(&envelope.message_id);
+                state.rejections.push_back(error);
+                return Ok(());
+            }
+            state.sent.push(envelope.clone());
+            state.server_acks.push_back(envelope.message_id.clone());
+        }
+        Ok(())
+    }
+
+    async fn send_typing(&self, recipient_id: &str, is_typing: bool) -> Result<()> {
+        if self.before_send().await? {
+            self.server
+                .state
+                .lock()
+                .typing
+                .push((recipient_id.to_string(), is_typing));
+        }
+        Ok(())
+    }
+
+    async fn send_presence(&self, status: PresenceStatus) -> Result<()> {
+        if self.before_send().await? {
+            self.server.state.lock().presence.push(status);
+        }
+        Ok(())
+    }
+
+    async fn send_call_signal(&self, signal: &CallSignal) -> Result<()> {
+        if self.before_send().await? {
+            self.server.state.lock().call_signals.push(signal.clone());
+        }
+        Ok(())
+    }
+
+    async fn receive_messages(&self) -> Result<Vec<MessageEnvelope>> {
+        let mut state = self.server.state.lock();
+        if !state.connected {
+            return Ok(Vec::new());
+        }
+        Ok(state.inbox.drain(..).collect())
+    }
+
+    async fn receive_call_signals(&self) -> Result<Vec<CallSignal>> {
+        let mut state = self.server.state.lock();
+        if !state.connected {
+            return Ok(Vec::new());
+        }
+        Ok(state.incoming_signals.drain(..).collect())
+    }
+
+    async fn receive_errors(&self) -> Result<Vec<ServerError>> {
+        Ok(self.server.state.lock().rejections.drain(..).collect())
+    }
+
+    async fn receive_device_renames(&self) -> Result<Vec<DeviceRenamed>> {
+        Ok(self.server.state.lock().device_renames.drain(..).collect())
+    }
+
+    async fn receive_announcements(&self) -> Result<Vec<Option<Announcement>>> {
+        Ok(self.server.state.lock().announcement_frames.drain(..).collect())
+    }
+
+    async fn receive_maintenance(&self) -> Result<Vec<Option<Maintenance>>> {
+        Ok(self.server.state.lock().maintenance_frames.drain(..).collect())
+    }
+
+    async fn receive_acks(&self) -> Result<Vec<(String, i64)>> {
+        // Timed as they arrive, like the WebSocket client does
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(self.server.state.lock().server_acks.drain(..).map(|id| (id, now)).collect())
+    }
+
+    async fn receive_deliveries(&self) -> Result<Vec<DeliveryReceipt>> {
+        Ok(self.server.state.lock().deliveries.drain(..).collect())
+    }
+
+    async fn send_ack(&self, message_ids: &[String]) -> Result<()> {
+        if self.before_send().await? {
+            self.server.state.lock().acked.extend_from_slice(message_ids);
+        }
+        Ok(())
+    }
+
+    fn set_power_source(&self, source: PowerSource) {
+        self.server.state.lock().power_source = source;
+    }
+
+    fn is_connected(&self) -> bool {
+        self.server.state.lock().connected
+    }
+
+    fn revocation(&self) -> Option<RevocationReason> {
+        self.server.state.lock().revoked
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.server.disconnect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheLimits;
+    use crate::config::{PresencePolicy, RetryPolicy};
+    use crate::crypto::CryptoEngine;
+    use crate::events::ClientEvent;
+    use crate::notifications::{Notification, NotificationSink, PreviewPolicy};
+    use crate::transfer::DOWNLOAD_RANGE_SIZE;
+    use crate::transport::FileRange;
+    use crate::OpenedAttachment;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    fn temp_dir() -> String {
+        std::env::temp_dir()
+            .join(format!("privmsg-mock-{}", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn add_peer(server: &MockServer, user_id: &str) -> CryptoEngine {
+        let peer = CryptoEngine::new();
+        peer.generate_identity().unwrap();
+        server.add_user(User {
+            user_id: user_id.to_string(),
+            display_name: None,
+            avatar_file_id: None,
+            public_key: Some(peer.get_public_key().unwrap()),
+            last_seen_at: None,
+            status_emoji: None,
+            status_text: None,
+            bio: None,
+            last_seen_visibility: None,
+        });
+        peer
+    }
+
+    #[test]
+    fn test_send_and_receive() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        client.send_message("bob", "hello").unwrap();
+        let sent = server.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].recipient_id, "bob");
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        let reply = bob.encrypt_for("alice", r#"{"text":"hi alice"}"#).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: reply,
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].content, "hi alice");
+        assert_eq!(server.acked_messages(), vec!["m1".to_string()]);
+    }
+
+    #[test]
+    fn test_async_api_within_runtime() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        bob.establish_session("alice", &alice_key).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            client.login_async("alice", "key", "test").await.unwrap();
+            client.send_message_async("bob", "hello").await.unwrap();
+            // The blocking API drives its runtime from another thread here
+            client.send_message("bob", "again").unwrap();
+            assert_eq!(server.sent_messages().len(), 2);
+
+            server.push_incoming(MessageEnvelope {
+                message_id: "m1".into(),
+                sender_id: "bob".into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: bob.encrypt_for("alice", r#"{"text":"hi alice"}"#).unwrap(),
+                message_type: "text".into(),
+                timestamp: 1,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+            let received = client.poll_messages_async().await.unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].content, "hi alice");
+            client.logout_async().await.unwrap();
+        });
+        // Dropping the client in async code doesn't block on its runtime
+        runtime.block_on(async move { drop(client) });
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_blocking_api_on_current_thread_runtime() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        bob.establish_session("alice", &alice_key).unwrap();
+
+        // Neither API panics on the only thread of the caller's runtime
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hello").unwrap();
+        client.send_message_async("bob", "again").await.unwrap();
+        assert_eq!(server.sent_messages().len(), 2);
+
+        client.set_presence_policy(PresencePolicy {
+            typing_interval: Duration::ZERO,
+            typing_idle_timeout: Duration::from_secs(60),
+            presence_interval: Duration::ZERO,
+        });
+        client.send_typing("bob", true).unwrap();
+        client.send_typing_async("bob", false).await.unwrap();
+        assert_eq!(
+            server.sent_typing(),
+            vec![("bob".to_string(), true), ("bob".to_string(), false)]
+        );
+        client.set_presence(PresenceStatus::Away).unwrap();
+        client.set_power_source(PowerSource::Battery).unwrap();
+        client.set_presence_async(PresenceStatus::Online).await.unwrap();
+        client.set_power_source_async(PowerSource::Mains).await.unwrap();
+        assert_eq!(server.sent_presence(), vec![PresenceStatus::Away, PresenceStatus::Online]);
+
+        client.logout().unwrap();
+        drop(client);
+    }
+
+    #[test]
+    fn test_emoji_only_messages() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        // Shortcodes become emoji before hooks and encryption see the text
+        let sent = client.send_message("bob", ":tada: :rocket:").unwrap();
+        assert_eq!(sent.content, "\u{1F389} \u{1F680}");
+        assert_eq!(sent.jumbo_emoji_count(), Some(2));
+
+        let sent = client.send_message("bob", "Shipped :tada:").unwrap();
+        assert_eq!(sent.content, "Shipped \u{1F389}");
+        assert_eq!(sent.jumbo_emoji_count(), None);
+    }
+
+    #[test]
+    fn test_reply_privately() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        // What bob wrote somewhere other than his chat with alice
+        client
+            .storage
+            .save_message(&Message {
+                message_id: "g1".into(),
+                sender_id: "bob".into(),
+                message_type: MessageType::Text,
+                content: "Anyone up for lunch?".into(),
+                system_event: None,
+                ..Message::system("team", SystemEvent::KeyChanged { user_id: "bob".into() })
+            })
+            .unwrap();
+
+        let reply = client.reply_privately("g1", "Me!").unwrap();
+        assert_eq!(reply.conversation_id, "bob");
+        let quote = reply.quote.clone().unwrap();
+        assert_eq!((quote.message_id.as_str(), quote.conversation_id.as_deref()), ("g1", Some("team")));
+        assert_eq!(quote.text, "Anyone up for lunch?");
+        assert_eq!(client.get_messages("bob", 1, 0).unwrap()[0].quote, reply.quote);
+
+        // The quote travels inside the encrypted payload
+        let envelope = server.sent_messages().pop().unwrap();
+        bob.establish_session("alice", &alice_key).unwrap();
+        let payload: serde_json::Value =
+            serde_json::from_str(&bob.decrypt_from("alice", &envelope.encrypted_content).unwrap()).unwrap();
+        assert_eq!(payload["quote"]["conversation_id"], "team");
+
+        // Within the same conversation the quote doesn't name it
+        let quote = client.quote_message(&reply.message_id).unwrap();
+        let answer = client.conversation("bob").reply("see you there", quote).unwrap();
+        assert!(!answer.quote.unwrap().is_private_reply());
+        assert!(client.reply_privately(&reply.message_id, "hm").is_err());
+        assert!(client.quote_message("missing").is_err());
+    }
+
+    #[test]
+    fn test_call_offer_before_backlog() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        let events = client.conversation("bob").subscribe();
+
+        // What the server replays on connect: the held offer, then the backlog
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", r#"{"text":"call me"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        server.push_call_signal(CallSignal {
+            call_id: "call-1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            signal_type: "offer".into(),
+            payload: r#"{"sdp":"v=0"}"#.into(),
+        });
+
+        assert_eq!(client.poll_messages().unwrap().len(), 1);
+        let events = events.drain();
+        let call = events.iter().position(|e| matches!(e, ClientEvent::CallSignal(s) if s.call_id == "call-1"));
+        let message = events.iter().position(|e| matches!(e, ClientEvent::MessageReceived(_)));
+        assert!(call.unwrap() < message.unwrap());
+        assert_eq!(events.iter().filter(|e| matches!(e, ClientEvent::CallSignal(_))).count(), 1);
+    }
+
+    #[test]
+    fn test_message_metadata() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let carol = add_peer(&server, "carol");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let mut metadata = Metadata::new();
+        metadata.insert("order_id".into(), serde_json::json!(1042));
+        let sent = client.send_message_with_metadata("bob", "shipped", metadata).unwrap();
+        assert_eq!(sent.metadata::<u32>("order_id"), Some(1042));
+
+        // Only inside the encrypted payload
+        let envelope = server.sent_messages().pop().unwrap();
+        assert!(!serde_json::to_string(&envelope).unwrap().contains("order_id"));
+        bob.establish_session("alice", &alice_key).unwrap();
+        let payload = bob.decrypt_from("alice", &envelope.encrypted_content).unwrap();
+        assert!(payload.contains(r#""order_id":1042"#));
+
+        let reply = bob
+            .encrypt_for("alice", r#"{"text":"thanks","metadata":{"thread":"support"}}"#)
+            .unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: reply,
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received[0].metadata::<String>("thread").as_deref(), Some("support"));
+        assert_eq!(received[0].metadata::<u32>("thread"), None);
+
+        let stored = client.storage.get_message("m1").unwrap().unwrap();
+        assert_eq!(stored.metadata, received[0].metadata);
+
+        let forwarded = client.forward_message("m1", "carol").unwrap();
+        assert_eq!(forwarded.content, "thanks");
+        assert_eq!(forwarded.metadata, received[0].metadata);
+        let envelope = server.sent_messages().pop().unwrap();
+        carol.establish_session("alice", &alice_key).unwrap();
+        let payload = carol.decrypt_from("alice", &envelope.encrypted_content).unwrap();
+        assert!(payload.contains(r#""thread":"support""#));
+    }
+
+    #[test]
+    fn test_wrapped_file_key() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let carol = add_peer(&server, "carol");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        // Bob shares one upload with Alice and Carol
+        bob.establish_session("alice", &alice_key).unwrap();
+        bob.establish_session("carol", &carol.get_public_key().unwrap()).unwrap();
+        let file_key = bob.generate_file_key().unwrap();
+        let wrapped = bob.wrap_key_for_members(&file_key, &["alice", "carol"]).unwrap();
+        let payload = serde_json::json!({
+            "file_id": "f1",
+            "file_name": "plan.pdf",
+            "file_size": 10,
+            "mime_type": "application/pdf",
+            "wrapped_keys": wrapped
+        });
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", &payload.to_string()).unwrap(),
+            message_type: "file".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+
+        let received = client.poll_messages().unwrap();
+        let attachment = received[0].attachment.as_ref().unwrap();
+        assert_eq!(attachment.encryption_key.as_deref(), Some(file_key.as_str()));
+    }
+
+    #[test]
+    fn test_search_messages() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        add_peer(&server, "carol");
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        client.send_message("bob", "Dinner at the Café tonight?").unwrap();
+        client.send_message("bob", "running late").unwrap();
+        client.send_message("carol", "café was closed").unwrap();
+
+        let found = |query: &str, conversation: Option<&str>| -> Vec<String> {
+            client
+                .search_messages(query, conversation, 10)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.content)
+                .collect()
+        };
+        assert_eq!(found("CAFÉ", None).len(), 2);
+        assert_eq!(found("café", Some("bob")), vec!["Dinner at the Café tonight?"]);
+        assert_eq!(found("dinner tonight", None).len(), 1);
+        assert!(found("dinner closed", None).is_empty());
+        assert!(found("\"*", None).is_empty());
+
+        // Plain indexes match prefixes, hashed ones whole words only
+        assert_eq!(found("run", None).len(), usize::from(!cfg!(feature = "hashed-search")));
+
+        let message_id = client.search_messages("late", None, 1).unwrap()[0].message_id.clone();
+        client.storage.delete_message(&message_id).unwrap();
+        assert!(found("late", None).is_empty());
+    }
+
+    #[test]
+    fn test_multibyte_preview() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        // 50 bytes in would land inside a three-byte char
+        let text = format!("a{}", "\u{4f60}\u{597d}".repeat(30));
+        client.send_message("bob", &text).unwrap();
+
+        let conversations = client.storage.get_conversations().unwrap();
+        let preview = conversations[0].last_message.as_deref().unwrap();
+        assert_eq!(preview.chars().count(), 50);
+        assert!(text.starts_with(preview));
+    }
+
+    #[test]
+    fn test_streamed_attachment_round_trip() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+        let source = std::path::Path::new(&dir).join("photo.bin");
+        std::fs::write(&source, &data).unwrap();
+
+        let message = client
+            .send_file_from_path("bob", &source, "application/octet-stream", None)
+            .unwrap();
+        let attachment = message.attachment.unwrap();
+        assert_eq!(attachment.file_name, "photo.bin");
+        assert_eq!(attachment.file_size, data.len() as i64);
+
+        let dest = std::path::Path::new(&dir).join("download.bin");
+        client.download_attachment_to(&attachment, &dest).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+    }
+
+    #[test]
+    fn test_opened_attachments_are_shredded() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let source = std::path::Path::new(&dir).join("clip.ogg");
+        std::fs::write(&source, b"voice").unwrap();
+        let attachment = client
+            .send_file_from_path("bob", &source, "audio/ogg", None)
+            .unwrap()
+            .attachment
+            .unwrap();
+
+        let OpenedAttachment::File(path) = client.open_attachment(&attachment).unwrap() else {
+            panic!("expected a temp file");
+        };
+        assert_eq!(path.extension().unwrap(), "ogg");
+        assert_eq!(std::fs::read(&path).unwrap(), b"voice");
+        assert_eq!(client.open_attachments(), vec![path.clone()]);
+        assert!(client.close_attachment(&path).unwrap());
+        assert!(!path.exists());
+        assert!(!client.close_attachment(&path).unwrap());
+
+        // Small ones stay in memory
+        client.set_in_memory_attachment_limit(Some(1024));
+        assert_eq!(
+            client.open_attachment(&attachment).unwrap(),
+            OpenedAttachment::Memory(b"voice".to_vec())
+        );
+        assert!(client.open_attachments().is_empty());
+
+        // Whatever is still open goes with the client
+        client.set_in_memory_attachment_limit(None);
+        let OpenedAttachment::File(path) = client.open_attachment(&attachment).unwrap() else {
+            panic!("expected a temp file");
+        };
+        drop(client);
+        assert!(!path.exists());
+
+        // And leftovers of a crash on the next start
+        std::fs::write(&path, b"voice").unwrap();
+        let _client = server.client(&dir).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_wipe_and_logout() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        let identity = client.export_private_key().unwrap();
+
+        client.send_message("bob", "hi").unwrap();
+        let source = std::path::Path::new(&dir).join("note.txt");
+        std::fs::write(&source, b"secret").unwrap();
+        let attachment = client
+            .send_file_from_path("bob", &source, "text/plain", None)
+            .unwrap()
+            .attachment
+            .unwrap();
+        let OpenedAttachment::File(path) = client.open_attachment(&attachment).unwrap() else {
+            panic!("expected a temp file");
+        };
+
+        let outcome = client.wipe_and_logout(true).unwrap();
+        assert_eq!(outcome.identity_key, Some(identity));
+        assert!(outcome.sessions_invalidated);
+        assert!(server.logged_out_everywhere("alice"));
+        assert!(!path.exists());
+        assert_eq!(client.conversation_count().unwrap(), 0);
+        assert!(client.export_private_key().is_err());
+
+        // Wiped even when the server can't be reached
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hi").unwrap();
+        server.inject_fault(Fault::Network("offline".into()), 1);
+        let outcome = client.wipe_and_logout(false).unwrap();
+        assert_eq!(outcome.identity_key, None);
+        assert!(!outcome.sessions_invalidated);
+        assert_eq!(client.conversation_count().unwrap(), 0);
+    }
+
+    /// Fails range requests past `fail_from` to interrupt downloads
+    struct FlakyApi {
+        inner: Arc<dyn ApiTransport>,
+        fail_from: AtomicU64,
+        range_requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ApiTransport for FlakyApi {
+        async fn login(
+            &self,
+            user_id: &str,
+            access_key: &str,
+            device_name: &str,
+            device_public_key: &str,
+            proof: Option<&LoginProof>,
+        ) -> Result<AuthSession> {
+            self.inner
+                .login(user_id, access_key, device_name, device_public_key, proof)
+                .await
+        }
+
+        async fn get_user(&self, user_id: &str) -> Result<User> {
+            self.inner.get_user(user_id).await
+        }
+
+        async fn upload_file(
+            &self,
+            data: Vec<u8>,
+            file_name: &str,
+            mime_type: &str,
+            encryption_key_hash: &str,
+        ) -> Result<UploadedFile> {
+            self.inner
+                .upload_file(data, file_name, mime_type, encryption_key_hash)
+                .await
+        }
+
+        async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+            self.inner.download_file(file_id).await
+        }
+
+        async fn download_range(&self, file_id: &str, offset: u64, len: u64) -> Result<FileRange> {
+            self.range_requests.fetch_add(1, Ordering::SeqCst);
+            if offset >= self.fail_from.load(Ordering::SeqCst) {
+                return Err(Error::Network("connection reset".into()));
+            }
+            self.inner.download_range(file_id, offset, len).await
+        }
+
+        async fn get_turn_credentials(&self) -> Result<TurnCredentials> {
+            self.inner.get_turn_credentials().await
+        }
+
+        async fn check_health(&self) -> Result<bool> {
+            self.inner.check_health().await
+        }
+    }
+
+    #[test]
+    fn test_interrupted_download_resumes() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let api = Arc::new(FlakyApi {
+            inner: server.api(),
+            fail_from: AtomicU64::new(2 * DOWNLOAD_RANGE_SIZE),
+            range_requests: AtomicUsize::new(0),
+        });
+
+        let dir = temp_dir();
+        let client = PrivMsgClient::with_transport(&dir, api.clone(), server.connector()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let data: Vec<u8> = (0..3 * DOWNLOAD_RANGE_SIZE + 1000).map(|i| (i % 241) as u8).collect();
+        let source = std::path::Path::new(&dir).join("video.bin");
+        std::fs::write(&source, &data).unwrap();
+        let attachment = client
+            .send_file_from_path("bob", &source, "video/mp4", None)
+            .unwrap()
+            .attachment
+            .unwrap();
+        assert!(attachment.sha256.is_some());
+
+        let dest = std::path::Path::new(&dir).join("download.bin");
+        assert!(client.download_attachment_to(&attachment, &dest).is_err());
+        assert!(!dest.exists());
+
+        // Pick the transfer up again from a fresh client, as after a restart
+        drop(client);
+        let client = PrivMsgClient::with_transport(&dir, api.clone(), server.connector()).unwrap();
+        let transfer = client.storage.get_transfer(&attachment.file_id).unwrap().unwrap();
+        assert!(transfer.completed.contains(&0));
+        let remaining = transfer.range_count() as usize - transfer.completed.len();
+
+        api.fail_from.store(u64::MAX, Ordering::SeqCst);
+        api.range_requests.store(0, Ordering::SeqCst);
+        client.download_attachment_to(&attachment, &dest).unwrap();
+
+        assert_eq!(api.range_requests.load(Ordering::SeqCst), remaining);
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        assert!(client.storage.get_transfer(&attachment.file_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pause_resume_and_cancel_transfer() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let dir = temp_dir();
+        let client = Arc::new(server.client(&dir).unwrap());
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let data: Vec<u8> = (0..5 * DOWNLOAD_RANGE_SIZE).map(|i| (i % 239) as u8).collect();
+        let source = std::path::Path::new(&dir).join("archive.bin");
+        std::fs::write(&source, &data).unwrap();
+        let attachment = client
+            .send_file_from_path("bob", &source, "application/zip", None)
+            .unwrap()
+            .attachment
+            .unwrap();
+        let id = attachment.file_id.clone();
+        let dest = std::path::Path::new(&dir).join("download.bin");
+
+        // Slow ranges down so the signal lands while later ones are pending
+        server.set_latency(Duration::from_millis(100));
+        let start_download = || {
+            let (worker, attachment, dest) = (client.clone(), attachment.clone(), dest.clone());
+            let handle = std::thread::spawn(move || worker.download_attachment_to(&attachment, &dest));
+            while client.list_transfers().unwrap().is_empty() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            handle
+        };
+
+        let download = start_download();
+        client.pause_transfer(&id).unwrap();
+        assert!(matches!(download.join().unwrap(), Err(Error::TransferPaused)));
+
+        let transfer = client.list_transfers().unwrap().remove(0);
+        assert!(transfer.paused);
+        assert!((transfer.completed.len() as u64) < transfer.range_count());
+
+        client.resume_transfer(&id).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), data);
+        assert!(client.list_transfers().unwrap().is_empty());
+
+        // Cancelling a running download removes its partial data
+        std::fs::remove_file(&dest).unwrap();
+        let download = start_download();
+        let part_path = client.list_transfers().unwrap()[0].part_path.clone();
+        client.cancel_transfer(&id).unwrap();
+        assert!(matches!(download.join().unwrap(), Err(Error::TransferCancelled)));
+        assert!(client.list_transfers().unwrap().is_empty());
+        assert!(!std::path::Path::new(&part_path).exists());
+        assert!(!dest.exists());
+
+        assert!(matches!(client.resume_transfer(&id), Err(Error::TransferNotFound(_))));
+    }
+
+    #[test]
+    fn test_tampered_download_is_rejected() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let message = client
+            .send_file("bob", vec![7u8; 5000], "notes.txt", "text/plain", None)
+            .unwrap();
+        let mut attachment = message.attachment.unwrap();
+        attachment.sha256 = Some("00".repeat(32));
+
+        let dest = std::path::Path::new(&dir).join("notes.txt");
+        assert!(client.download_attachment_to(&attachment, &dest).is_err());
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn test_background_decryption_keeps_order() {
+        let server = MockServer::new();
+        let peers = [add_peer(&server, "bob"), add_peer(&server, "carol")];
+
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        for peer in &peers {
+            peer.establish_session("alice", &alice_key).unwrap();
+        }
+
+        // Interleave two conversations, with one undecryptable envelope
+        for i in 0..20 {
+            for (peer, sender) in peers.iter().zip(["bob", "carol"]) {
+                let text = format!(r#"{{"text":"{}"}}"#, i);
+                server.push_incoming(MessageEnvelope {
+                    message_id: format!("{}-{}", sender, i),
+                    sender_id: sender.into(),
+                    recipient_id: "alice".into(),
+                    recipient_device_id: None,
+                    encrypted_content: peer.encrypt_for("alice", &text).unwrap(),
+                    message_type: "text".into(),
+                    timestamp: i,
+                    ttl_seconds: None,
+                    priority: MessagePriority::Normal,
+                    delivery_mode: DeliveryMode::StoreAndForward,
+                });
+            }
+        }
+        server.push_incoming(MessageEnvelope {
+            message_id: "garbage".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: "not-ciphertext".into(),
+            message_type: "text".into(),
+            timestamp: 99,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+
+        assert_eq!(client.receive_in_background().unwrap(), 41);
+
+        let mut received: HashMap<String, Vec<String>> = HashMap::new();
+        let mut failed = Vec::new();
+        while received.values().map(Vec::len).sum::<usize>() + failed.len() < 41 {
+            match client.next_event(Duration::from_secs(5)).expect("timed out") {
+                ClientEvent::MessageReceived(msg) => {
+                    received.entry(msg.sender_id).or_default().push(msg.content)
+                }
+                ClientEvent::DecryptionFailed { message_id, .. } => failed.push(message_id),
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+
+        let expected: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        assert_eq!(received["bob"], expected);
+        assert_eq!(received["carol"], expected);
+        assert_eq!(failed, vec!["garbage".to_string()]);
+        assert_eq!(server.acked_messages().len(), 40);
+    }
+
+    #[test]
+    fn test_faults() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        server.set_credentials("alice", "right");
+
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        assert!(matches!(
+            client.login("alice", "wrong", "test"),
+            Err(Error::InvalidCredentials)
+        ));
+        client.login("alice", "right", "test").unwrap();
+        client.send_message("bob", "first").unwrap();
+
+        // A dropped frame is lost without an error
+        server.inject_fault(Fault::Drop, 1);
+        client.send_message("bob", "lost").unwrap();
+        assert_eq!(server.sent_messages().len(), 1);
+
+        server.inject_fault(Fault::Network("reset".into()), 1);
+        assert!(client.send_message("bob", "fails").is_err());
+    }
+
+    #[test]
+    fn test_failed_message_resend() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hello").unwrap();
+
+        // Without retries the first failure sticks
+        server.inject_fault(Fault::Network("reset".into()), 1);
+        assert!(client.send_message("bob", "again").is_err());
+        let messages = client.get_messages("bob", 10, 0).unwrap();
+        let failed = messages.into_iter().find(|m| m.content == "again").unwrap();
+        assert_eq!(failed.status, MessageStatus::Failed);
+
+        // Resending reconnects and keeps the message_id
+        let resent = client.resend_message(&failed.message_id).unwrap();
+        assert_eq!(resent.status, MessageStatus::Sent);
+        assert_eq!(server.sent_messages()[1].message_id, failed.message_id);
+        assert!(client.resend_message(&failed.message_id).is_err());
+
+        client.set_send_retry(RetryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        });
+        server.inject_fault(Fault::Network("reset".into()), 1);
+        let sent = client.send_message("bob", "retried").unwrap();
+        assert_eq!(sent.status, MessageStatus::Sent);
+        assert_eq!(server.sent_messages().len(), 3);
+    }
+
+    #[test]
+    fn test_pending_outgoing() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hello").unwrap();
+        assert!(client.pending_outgoing("bob").unwrap().is_empty());
+
+        server.inject_fault(Fault::Network("reset".into()), 1);
+        assert!(client.send_message("bob", "broken").is_err());
+
+        // While the server can't be reached, messages wait for a connection
+        server.inject_fault(Fault::Network("unreachable".into()), 1);
+        let queued = client.send_message("bob", "later").unwrap();
+        assert_eq!(queued.status, MessageStatus::Pending);
+
+        let pending = client.pending_outgoing("bob").unwrap();
+        let summary: Vec<_> = pending
+            .iter()
+            .map(|p| (p.message.content.as_str(), p.reason))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("broken", PendingReason::Offline), ("later", PendingReason::Offline)]
+        );
+        assert_eq!(pending[0].message.status, MessageStatus::Failed);
+
+        // Resending reconnects, which sends the queued message first
+        client.resend_message(&pending[0].message.message_id).unwrap();
+        let sent = server.sent_messages();
+        assert_eq!(sent[1].message_id, pending[1].message.message_id);
+        assert_eq!(sent[2].message_id, pending[0].message.message_id);
+        assert!(client.pending_outgoing("bob").unwrap().is_empty());
+        assert_eq!(server.sent_messages().len(), 3);
+    }
+
+    #[test]
+    fn test_outbox_retry() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hello").unwrap();
+        client.set_outbox_retry(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        });
+
+        // Queued while offline, then sent on a poll once the server is back
+        server.disconnect();
+        server.inject_fault(Fault::Network("unreachable".into()), 2);
+        let queued = client.send_message("bob", "later").unwrap();
+        assert_eq!(queued.status, MessageStatus::Pending);
+        std::thread::sleep(Duration::from_millis(5));
+
+        // A poll overlapping a flush leaves what is due to a later poll
+        {
+            let _flushing = client.outbox_flush.try_lock().unwrap();
+            client.poll_messages().unwrap();
+        }
+        assert_eq!(server.sent_messages().len(), 1);
+        assert_eq!(client.message_info(&queued.message_id).unwrap().message.status, MessageStatus::Pending);
+
+        client.poll_messages().unwrap();
+        assert_eq!(client.message_info(&queued.message_id).unwrap().message.status, MessageStatus::Pending);
+        assert!(client.poll_events().is_empty());
+
+        std::thread::sleep(Duration::from_millis(5));
+        client.poll_messages().unwrap();
+        assert_eq!(server.sent_messages().last().unwrap().message_id, queued.message_id);
+        assert_eq!(client.message_info(&queued.message_id).unwrap().message.status, MessageStatus::Sent);
+        assert!(matches!(
+            client.poll_events().as_slice(),
+            [ClientEvent::MessageStatusChanged { status: MessageStatus::Sent, .. }]
+        ));
+        assert!(client.pending_outgoing("bob").unwrap().is_empty());
+
+        // Once its tries are used up the message fails
+        server.disconnect();
+        server.inject_fault(Fault::Network("unreachable".into()), 3);
+        let queued = client.send_message("bob", "never").unwrap();
+        for _ in 0..2 {
+            std::thread::sleep(Duration::from_millis(5));
+            client.poll_messages().unwrap();
+        }
+        assert_eq!(client.message_info(&queued.message_id).unwrap().message.status, MessageStatus::Failed);
+        assert!(matches!(
+            client.poll_events().as_slice(),
+            [ClientEvent::MessageStatusChanged { status: MessageStatus::Failed, .. }]
+        ));
+        let pending = client.pending_outgoing("bob").unwrap();
+        assert_eq!(pending[0].reason, PendingReason::Offline);
+
+        // Nothing is left to retry
+        std::thread::sleep(Duration::from_millis(5));
+        client.poll_messages().unwrap();
+        assert_eq!(server.sent_messages().len(), 2);
+
+        // A reconnect sends what is queued right away, ahead of new messages
+        client.set_outbox_retry(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_secs(3600),
+            max_backoff: Duration::from_secs(3600),
+        });
+        server.disconnect();
+        server.inject_fault(Fault::Network("unreachable".into()), 1);
+        let queued = client.send_message("bob", "queued").unwrap();
+        assert_eq!(queued.status, MessageStatus::Pending);
+        let next = client.send_message("bob", "next").unwrap();
+        assert_eq!(next.status, MessageStatus::Sent);
+        let sent: Vec<String> = server.sent_messages().into_iter().map(|e| e.message_id).collect();
+        assert_eq!(sent[2..], [queued.message_id.clone(), next.message_id]);
+        assert_eq!(client.message_info(&queued.message_id).unwrap().message.status, MessageStatus::Sent);
+        assert!(client.pending_outgoing("bob").unwrap().iter().all(|p| p.message.message_id != queued.message_id));
+    }
+
+    #[test]
+    fn test_read_position_syncs_to_other_devices() {
+        let (laptop_server, phone_server) = (MockServer::new(), MockServer::new());
+        let bob = add_peer(&laptop_server, "bob");
+        phone_server.add_user(laptop_server.state.lock().users["bob"].clone());
+
+        let laptop = laptop_server.client(&temp_dir()).unwrap();
+        let alice_key = laptop.init_keys(None).unwrap();
+        laptop.login("alice", "key", "laptop").unwrap();
+        let phone = phone_server.client(&temp_dir()).unwrap();
+        phone.init_keys(Some(&laptop.export_private_key().unwrap())).unwrap();
+        phone.login("alice", "key", "phone").unwrap();
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        for (i, server) in [(1, &laptop_server), (1, &phone_server), (2, &laptop_server), (2, &phone_server)] {
+            server.push_incoming(MessageEnvelope {
+                message_id: format!("m{}", i),
+                sender_id: "bob".into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: bob.encrypt_for("alice", r#"{"text":"hi"}"#).unwrap(),
+                message_type: "text".into(),
+                timestamp: i,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        }
+        let unread = |client: &PrivMsgClient| {
+            client.poll_messages().unwrap();
+            client.get_conversations().unwrap()[0].unread_count
+        };
+        assert_eq!((unread(&laptop), unread(&phone)), (2, 2));
+
+        laptop.mark_conversation_read("bob").unwrap();
+        assert_eq!(unread(&laptop), 0);
+        let sync = laptop_server.sent_messages().pop().unwrap();
+        assert_eq!(sync.message_type, READ_SYNC_MESSAGE_TYPE);
+        assert_eq!(sync.recipient_id, "alice");
+
+        // Already read, nothing more to sync
+        laptop.mark_conversation_read("bob").unwrap();
+        assert_eq!(laptop_server.sent_messages().len(), 1);
+
+        phone_server.push_incoming(sync.clone());
+        assert!(phone.poll_messages().unwrap().is_empty());
+        assert_eq!(unread(&phone), 0);
+        assert!(phone_server.acked_messages().contains(&sync.message_id));
+        match phone.poll_events().as_slice() {
+            [ClientEvent::ConversationRead(position)] => {
+                assert_eq!(position.conversation_id, "bob");
+                assert_eq!(position.last_read_timestamp, 2);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_drafts_sync_to_other_devices() {
+        let (laptop_server, phone_server) = (MockServer::new(), MockServer::new());
+        add_peer(&laptop_server, "bob");
+        phone_server.add_user(laptop_server.state.lock().users["bob"].clone());
+        let laptop = laptop_server.client(&temp_dir()).unwrap();
+        laptop.init_keys(None).unwrap();
+        laptop.login("alice", "key", "laptop").unwrap();
+        let phone = phone_server.client(&temp_dir()).unwrap();
+        phone.init_keys(Some(&laptop.export_private_key().unwrap())).unwrap();
+        phone.login("alice", "key", "phone").unwrap();
+        let drafts_sent = |server: &MockServer| {
+            server
+                .sent_messages()
+                .into_iter()
+                .filter(|m| m.message_type == DRAFT_SYNC_MESSAGE_TYPE)
+                .collect::<Vec<_>>()
+        };
+
+        // The first keystroke goes out, the rest wait for the window to end
+        laptop.set_draft("bob", "Lunch").unwrap();
+        laptop.set_draft("bob", "Lunch tomo").unwrap();
+        laptop.set_draft("bob", "Lunch tomorrow?").unwrap();
+        assert_eq!(drafts_sent(&laptop_server).len(), 1);
+        laptop.flush_drafts().unwrap();
+        let sent = drafts_sent(&laptop_server);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].recipient_id, "alice");
+        laptop.flush_drafts().unwrap();
+        assert_eq!(drafts_sent(&laptop_server).len(), 2);
+
+        for envelope in &sent {
+            phone_server.push_incoming(envelope.clone());
+        }
+        assert!(phone.poll_messages().unwrap().is_empty());
+        assert_eq!(phone.draft("bob").unwrap().unwrap().text, "Lunch tomorrow?");
+        assert!(phone_server.acked_messages().contains(&sent[1].message_id));
+        assert!(matches!(
+            phone.poll_events().last(),
+            Some(ClientEvent::DraftChanged(draft)) if draft.text == "Lunch tomorrow?"
+        ));
+
+        // Latest edit wins: an older sync arriving late changes nothing
+        phone_server.push_incoming(sent[0].clone());
+        phone.poll_messages().unwrap();
+        assert_eq!(phone.draft("bob").unwrap().unwrap().text, "Lunch tomorrow?");
+        assert!(phone.poll_events().is_empty());
+
+        // Finished on the phone: sending clears the draft on the laptop too
+        phone.send_message("bob", "Lunch tomorrow? 12:30").unwrap();
+        assert!(phone.draft("bob").unwrap().is_none());
+        let cleared = drafts_sent(&phone_server).pop().unwrap();
+        laptop_server.push_incoming(cleared);
+        laptop.poll_messages().unwrap();
+        assert!(laptop.draft("bob").unwrap().is_none());
+        assert!(laptop.drafts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bulk_conversation_operations() {
+        let server = MockServer::new();
+        let peers: Vec<_> = ["bob", "carol", "dave"]
+            .iter()
+            .map(|id| (*id, add_peer(&server, id)))
+            .collect();
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        for (i, (id, peer)) in peers.iter().enumerate() {
+            peer.establish_session("alice", &alice_key).unwrap();
+            server.push_incoming(MessageEnvelope {
+                message_id: format!("m{}", i),
+                sender_id: id.to_string(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: peer.encrypt_for("alice", r#"{"text":"hi"}"#).unwrap(),
+                message_type: "text".into(),
+                timestamp: i as i64 + 1,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        }
+        client.poll_messages().unwrap();
+        let selection = vec!["bob".to_string(), "carol".to_string()];
+
+        client.mark_conversations_read(&selection).unwrap();
+        let unread = |id: &str| client.storage.get_conversation(id).unwrap().unwrap().unread_count;
+        assert_eq!((unread("bob"), unread("carol"), unread("dave")), (0, 0, 1));
+        // One read sync per conversation that moved
+        let syncs = server
+            .sent_messages()
+            .iter()
+            .filter(|m| m.message_type == READ_SYNC_MESSAGE_TYPE)
+            .count();
+        assert_eq!(syncs, 2);
+
+        client.set_conversations_muted(&selection, true).unwrap();
+        let muted: Vec<_> = client
+            .get_conversations()
+            .unwrap()
+            .into_iter()
+            .filter(|c| c.is_muted)
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(muted.len(), 2);
+
+        let dest = std::path::Path::new(&temp_dir()).with_extension("json");
+        client.export_conversations(&selection, &dest).unwrap();
+        let exported: Vec<ConversationExport> =
+            serde_json::from_slice(&std::fs::read(&dest).unwrap()).unwrap();
+        assert_eq!(exported.len(), 2);
+        assert_eq!(exported[0].messages[0].content, "hi");
+
+        client.delete_conversations(&selection).unwrap();
+        let left = client.get_conversations().unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, "dave");
+        assert!(client.storage.get_message("m0").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_bulk_message_actions() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        add_peer(&server, "carol");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        for (i, text) in ["one", "two", "three"].iter().enumerate() {
+            server.push_incoming(MessageEnvelope {
+                message_id: format!("m{}", i),
+                sender_id: "bob".into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: bob.encrypt_for("alice", &format!(r#"{{"text":"{}"}}"#, text)).unwrap(),
+                message_type: "text".into(),
+                timestamp: 10 - i as i64,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        }
+        client.poll_messages().unwrap();
+        let selection = vec!["m0".to_string(), "m2".to_string()];
+
+        assert_eq!(client.set_messages_starred(&selection, true).unwrap(), 2);
+        let starred = client.starred_messages(Some("bob")).unwrap();
+        assert_eq!(starred.len(), 2);
+        assert!(client.storage.get_message("m0").unwrap().unwrap().is_starred);
+
+        // Nothing is sent when one of the selection is missing
+        let before = server.sent_messages().len();
+        let missing = vec!["m0".to_string(), "gone".to_string()];
+        assert!(client.forward_messages(&missing, "carol").is_err());
+        assert_eq!(server.sent_messages().len(), before);
+
+        // Oldest first, as unstarred copies
+        let forwarded = client.forward_messages(&selection, "carol").unwrap();
+        let texts: Vec<_> = forwarded.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(texts, ["three", "one"]);
+        assert!(forwarded.iter().all(|m| !m.is_starred && m.is_outgoing));
+        assert_eq!(server.sent_messages().len(), before + 2);
+
+        assert_eq!(client.delete_messages(&selection).unwrap(), 2);
+        assert!(client.storage.get_message("m0").unwrap().is_none());
+        assert!(client.storage.get_message("m1").unwrap().is_some());
+        assert!(client.starred_messages(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ephemeral_payloads() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        client.send_ephemeral("bob", "location", "52.52,13.40").unwrap();
+        let sent = server.sent_messages().pop().unwrap();
+        assert_eq!(sent.delivery_mode, DeliveryMode::OnlineOnly);
+        assert_eq!(sent.message_type, EPHEMERAL_MESSAGE_TYPE);
+        assert!(client.get_conversations().unwrap().is_empty());
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "e1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob
+                .encrypt_for("alice", r#"{"kind":"location","payload":"48.85,2.35"}"#)
+                .unwrap(),
+            message_type: EPHEMERAL_MESSAGE_TYPE.into(),
+            timestamp: 1,
+            ttl_seconds: Some(0),
+            priority: MessagePriority::Low,
+            delivery_mode: DeliveryMode::OnlineOnly,
+        });
+
+        // Passed on as an event, never stored or acked
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert!(client.get_conversations().unwrap().is_empty());
+        assert!(server.acked_messages().is_empty());
+        match client.poll_events().as_slice() {
+            [ClientEvent::Ephemeral { sender_id, payload }] => {
+                assert_eq!(sender_id, "bob");
+                assert_eq!(payload.kind, "location");
+                assert_eq!(payload.payload, "48.85,2.35");
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_notification_sink() {
+        #[derive(Default)]
+        struct Collect(Mutex<Vec<Notification>>);
+        impl NotificationSink for Collect {
+            fn notify(&self, notification: &Notification) {
+                self.0.lock().push(notification.clone());
+            }
+        }
+
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        let sink = Arc::new(Collect::default());
+        client.set_notification_sink(Some(sink.clone()));
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        let push = |id: &str, text: &str| {
+            server.push_incoming(MessageEnvelope {
+                message_id: id.into(),
+                sender_id: "bob".into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: bob.encrypt_for("alice", &format!(r#"{{"text":"{}"}}"#, text)).unwrap(),
+                message_type: "text".into(),
+                timestamp: 1,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        };
+
+        push("m1", "lunch?");
+        client.poll_messages().unwrap();
+        client.set_preview_policy(PreviewPolicy::SenderOnly);
+        push("m2", "at noon");
+        client.poll_messages().unwrap();
+
+        // Our own messages don't notify
+        client.send_message("bob", "sure").unwrap();
+
+        let shown = sink.0.lock().clone();
+        assert_eq!(shown.len(), 2);
+        assert_eq!(shown[0].conversation_id, "bob");
+        assert_eq!(shown[0].title(), "Message from bob");
+        assert_eq!(shown[0].body(), "lunch?");
+        assert_eq!(shown[1].body(), "New message");
+    }
+
+    #[test]
+    fn test_message_rules() {
+        #[derive(Default)]
+        struct Collect(Mutex<Vec<Notification>>);
+        impl NotificationSink for Collect {
+            fn notify(&self, notification: &Notification) {
+                self.0.lock().push(notification.clone());
+            }
+        }
+
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        let sink = Arc::new(Collect::default());
+        client.set_notification_sink(Some(sink.clone()));
+
+        let rule = |id: &str, sender: Option<&str>, keyword: Option<&str>, actions: &[RuleAction]| MessageRule {
+            id: id.into(),
+            name: id.into(),
+            enabled: true,
+            sender_id: sender.map(str::to_string),
+            keyword: keyword.map(str::to_string),
+            message_type: None,
+            actions: actions.to_vec(),
+        };
+        client
+            .save_message_rule(&rule("quiet", Some("bob"), None, &[RuleAction::Mute, RuleAction::MarkRead]))
+            .unwrap();
+        client
+            .save_message_rule(&rule("urgent", None, Some("urgent"), &[RuleAction::Highlight]))
+            .unwrap();
+        assert!(client.save_message_rule(&rule("all", None, None, &[RuleAction::Archive])).is_err());
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        let push = |id: &str, text: &str, timestamp: i64| {
+            server.push_incoming(MessageEnvelope {
+                message_id: id.into(),
+                sender_id: "bob".into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: bob.encrypt_for("alice", &format!(r#"{{"text":"{}"}}"#, text)).unwrap(),
+                message_type: "text".into(),
+                timestamp,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        };
+
+        push("m1", "newsletter", 1);
+        client.poll_messages().unwrap();
+        let conversation = client.get_conversations().unwrap().remove(0);
+        assert!(conversation.is_muted);
+        assert_eq!(conversation.unread_count, 0);
+        assert!(sink.0.lock().is_empty());
+
+        // Highlighted messages get through the mute
+        push("m2", "URGENT: call me", 2);
+        client.poll_messages().unwrap();
+        let shown = sink.0.lock().clone();
+        assert_eq!(shown.len(), 1);
+        assert!(shown[0].highlighted);
+
+        // Rules keep their place when edited and stop acting once deleted
+        client
+            .save_message_rule(&rule("quiet", Some("bob"), None, &[RuleAction::Archive]))
+            .unwrap();
+        let ids: Vec<String> = client.message_rules().unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, ["quiet", "urgent"]);
+        push("m3", "hi", 3);
+        client.poll_messages().unwrap();
+        assert!(client.get_conversations().unwrap()[0].is_archived);
+
+        client.delete_message_rule("quiet").unwrap();
+        client.set_conversations_archived(&["bob".to_string()], false).unwrap();
+        push("m4", "hi again", 4);
+        client.poll_messages().unwrap();
+        let conversation = client.get_conversations().unwrap().remove(0);
+        assert!(!conversation.is_archived);
+        assert_eq!(conversation.unread_count, 2);
+    }
+
+    #[test]
+    fn test_conversation_stats() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let sent = client.send_message("bob", "lunch?").unwrap();
+        assert!(client.conversation_stats("bob").is_err());
+        client.set_local_analytics(true).unwrap();
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "r1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", r#"{"text":"sure"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: sent.timestamp + 90_000,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        client.poll_messages().unwrap();
+
+        let stats = client.conversation_stats("bob").unwrap();
+        assert_eq!((stats.sent, stats.received), (1, 1));
+        assert_eq!(stats.their_response_seconds, Some(90));
+        assert_eq!(stats.my_response_seconds, None);
+        assert_eq!(stats.by_hour.iter().sum::<u64>(), 2);
+
+        client.set_local_analytics(false).unwrap();
+        assert!(!client.local_analytics_enabled());
+        assert!(client.conversation_stats("bob").is_err());
+    }
+
+    #[test]
+    fn test_export_my_data() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        add_peer(&server, "carol");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "laptop").unwrap();
+        client.send_message("bob", "hi bob").unwrap();
+        client.send_message("carol", "hi carol").unwrap();
+
+        let dest = std::path::Path::new(&dir).join("my-data.json");
+        let mut steps = Vec::new();
+        client.export_my_data(&dest, |done, total| steps.push((done, total))).unwrap();
+        assert_eq!(steps, [(0, 2), (1, 2), (2, 2)]);
+
+        let export: DataExport = serde_json::from_slice(&std::fs::read(&dest).unwrap()).unwrap();
+        assert_eq!(export.user.user_id, "alice");
+        assert_eq!(export.devices.len(), 1);
+        assert_eq!(export.devices[0].device_name, "laptop");
+        let mut texts: Vec<_> = export
+            .conversations
+            .iter()
+            .flat_map(|c| c.messages.iter().map(|m| m.content.clone()))
+            .collect();
+        texts.sort();
+        assert_eq!(texts, ["hi bob", "hi carol"]);
+
+        // Cancelling leaves nothing behind
+        std::fs::remove_file(&dest).unwrap();
+        let result = client.export_my_data(&dest, |_, _| {
+            client.cancel_data_export();
+        });
+        assert!(matches!(result, Err(Error::TransferCancelled)));
+        assert!(!dest.exists());
+        assert!(!client.cancel_data_export());
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "laptop").unwrap();
+        let text = client.send_message("bob", "hi bob").unwrap();
+
+        let photo = std::path::Path::new(&dir).join("photo.jpg");
+        std::fs::write(&photo, b"jpeg").unwrap();
+        let mut with_photo = text.clone();
+        with_photo.message_id = "m-photo".into();
+        with_photo.timestamp += 1;
+        with_photo.status = MessageStatus::Read;
+        with_photo.set_metadata("order", 42).unwrap();
+        with_photo.attachment = Some(Attachment {
+            file_id: "f1".into(),
+            file_name: "photo.jpg".into(),
+            file_size: 4,
+            mime_type: "image/jpeg".into(),
+            duration_ms: None,
+            width: None,
+            height: None,
+            encryption_key: Some("key".into()),
+            local_path: Some(photo.to_string_lossy().into_owned()),
+            waveform: None,
+            sha256: None,
+            expires_at: None,
+        });
+        client.storage.save_message(&with_photo).unwrap();
+        let mut conversation = client.get_conversations().unwrap().remove(0);
+        conversation.is_pinned = true;
+        client.storage.save_conversation(&conversation).unwrap();
+
+        let archive = std::path::Path::new(&dir).join("archive");
+        let manifest = client.export_archive(None, &archive).unwrap();
+        assert_eq!(manifest.version, crate::ARCHIVE_VERSION);
+        assert_eq!(manifest.user_id.as_deref(), Some("alice"));
+        assert_eq!(manifest.conversations[0].message_count, 2);
+        assert!(matches!(
+            client.export_archive(None, &archive),
+            Err(Error::InvalidArchive(_))
+        ));
+
+        // Another device ends up with the same history
+        let other_dir = temp_dir();
+        let other = MockServer::new().client(&other_dir).unwrap();
+        let attachments = std::path::Path::new(&other_dir).join("attachments");
+        other.import_archive(&archive, &attachments).unwrap();
+
+        let imported = other.get_conversations().unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].id, conversation.id);
+        assert!(imported[0].is_pinned);
+        assert_eq!(imported[0].unread_count, conversation.unread_count);
+
+        let original = client.get_messages("bob", 10, 0).unwrap();
+        let messages = other.get_messages("bob", 10, 0).unwrap();
+        assert_eq!(messages.len(), 2);
+        for (imported, original) in messages.iter().zip(&original) {
+            assert_eq!(imported.message_id, original.message_id);
+            assert_eq!(imported.status, original.status);
+            assert_eq!(imported.timestamp, original.timestamp);
+        }
+        let photo = messages.iter().find(|m| m.message_id == "m-photo").unwrap();
+        assert_eq!(photo.status, MessageStatus::Read);
+        assert_eq!(photo.metadata::<i64>("order"), Some(42));
+        let copied = photo.attachment.as_ref().unwrap().local_path.clone().unwrap();
+        assert!(copied.starts_with(&*attachments.to_string_lossy()));
+        assert_eq!(std::fs::read(copied).unwrap(), b"jpeg");
+
+        // Importing again replaces rather than duplicates
+        other.import_archive(&archive, &attachments).unwrap();
+        assert_eq!(other.get_messages("bob", 10, 0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_announcements() {
+        let server = MockServer::new();
+        let maintenance = Announcement {
+            id: "a1".into(),
+            text: "Maintenance at 22:00".into(),
+            severity: AnnouncementSeverity::Warning,
+            expires_at: None,
+        };
+        server.set_announcement(Some(maintenance.clone()));
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        let session = client.login("alice", "key", "laptop").unwrap();
+        assert_eq!(session.announcement.as_ref(), Some(&maintenance));
+        assert_eq!(client.current_announcement(), Some(maintenance.clone()));
+
+        // The frame repeating the one from login changes nothing
+        client.poll_messages().unwrap();
+        assert!(!client
+            .poll_events()
+            .iter()
+            .any(|e| matches!(e, ClientEvent::AnnouncementChanged(_))));
+
+        // Dismissed ones stay hidden, the next one shows
+        client.dismiss_announcement("a1").unwrap();
+        assert_eq!(client.current_announcement(), None);
+        let outage = Announcement {
+            id: "a2".into(),
+            text: "Degraded service".into(),
+            severity: AnnouncementSeverity::Critical,
+            expires_at: Some(chrono::Utc::now().timestamp() + 3600),
+        };
+        server.set_announcement(Some(outage.clone()));
+        client.poll_messages().unwrap();
+        assert!(client
+            .poll_events()
+            .iter()
+            .any(|e| matches!(e, ClientEvent::AnnouncementChanged(Some(a)) if *a == outage)));
+
+        server.set_announcement(None);
+        client.poll_messages().unwrap();
+        assert!(client
+            .poll_events()
+            .iter()
+            .any(|e| matches!(e, ClientEvent::AnnouncementChanged(None))));
+        assert_eq!(client.current_announcement(), None);
+
+        // Expired ones aren't shown
+        server.set_announcement(Some(Announcement {
+            id: "a3".into(),
+            expires_at: Some(1),
+            ..outage
+        }));
+        client.poll_messages().unwrap();
+        assert_eq!(client.current_announcement(), None);
+    }
+
+    #[test]
+    fn test_login_proof_of_work() {
+        let server = MockServer::new();
+        server.set_login_difficulty(Some(8));
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "laptop").unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let api = server.api();
+        runtime.block_on(async {
+            assert!(matches!(
+                api.login("mallory", "guess", "bot", "pk", None).await,
+                Err(Error::ChallengeRequired)
+            ));
+            let challenge = api.login_challenge().await.unwrap().unwrap();
+            let proof = privmsg_proto::pow::solve(&challenge);
+            api.login("mallory", "guess", "bot", "pk", Some(&proof)).await.unwrap();
+            // Each challenge is good for one login
+            assert!(matches!(
+                api.login("mallory", "guess", "bot", "pk", Some(&proof)).await,
+                Err(Error::ChallengeRequired)
+            ));
+        });
+
+        server.set_login_difficulty(None);
+        assert!(runtime.block_on(api.login_challenge()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let server = MockServer::new();
+        let maintenance = Maintenance {
+            ends_at: Some(chrono::Utc::now().timestamp() + 3600),
+            relay_messages: true,
+            message: Some("Database upgrade".into()),
+        };
+        server.set_maintenance(Some(maintenance.clone()));
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        assert!(matches!(client.login("alice", "key", "laptop"), Err(Error::Maintenance(_))));
+
+        server.set_maintenance(None);
+        client.login("alice", "key", "laptop").unwrap();
+        client.poll_messages().unwrap();
+        assert_eq!(client.current_maintenance(), None);
+
+        server.set_maintenance(Some(maintenance.clone()));
+        client.poll_messages().unwrap();
+        assert_eq!(client.current_maintenance(), Some(maintenance.clone()));
+        assert!(client
+            .poll_events()
+            .iter()
+            .any(|e| matches!(e, ClientEvent::MaintenanceChanged(Some(m)) if *m == maintenance)));
+
+        server.set_maintenance(None);
+        client.poll_messages().unwrap();
+        assert_eq!(client.current_maintenance(), None);
+        assert!(client
+            .poll_events()
+            .iter()
+            .any(|e| matches!(e, ClientEvent::MaintenanceChanged(None))));
+    }
+
+    #[test]
+    fn test_device_names() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "laptop").unwrap();
+
+        let device_id = client.current_device_id().unwrap();
+        client.rename_device(&device_id, "  Work laptop ").unwrap();
+        assert_eq!(client.list_devices().unwrap()[0].device_name, "Work laptop");
+        assert!(matches!(client.rename_device(&device_id, " "), Err(Error::InvalidConfig(_))));
+        assert!(client.rename_device(&device_id, &"x".repeat(65)).is_err());
+        assert!(client.rename_device("someone-else", "Mine").is_err());
+
+        // Renames from the user's other devices arrive as events
+        let rename = DeviceRenamed {
+            device_id: "device-phone".into(),
+            device_name: "Old phone".into(),
+        };
+        server.push_device_rename(rename.clone());
+        client.poll_messages().unwrap();
+        assert!(client
+            .poll_events()
+            .iter()
+            .any(|e| matches!(e, ClientEvent::DeviceRenamed(r) if *r == rename)));
+
+        // Messages say which device they came from
+        let sent = client.send_message("bob", "hi").unwrap();
+        assert_eq!(sent.sender_device_id.as_deref(), Some(device_id.as_str()));
+        assert_eq!(
+            client.get_messages("bob", 1, 0).unwrap()[0].sender_device_id,
+            sent.sender_device_id
+        );
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob
+                .encrypt_for("alice", r#"{"text":"hey","device_id":"bob-tablet"}"#)
+                .unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received[0].sender_device_id.as_deref(), Some("bob-tablet"));
+    }
+
+    #[test]
+    fn test_message_info() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "laptop").unwrap();
+
+        let sent = client.send_message("bob", "hi").unwrap();
+        let info = client.message_info(&sent.message_id).unwrap();
+        assert_eq!(info.message.sender_device_id, Some(client.current_device_id().unwrap()));
+        let delivery = info.delivery.unwrap();
+        assert_eq!(delivery.transport, Some(DeliveryTransport::WebSocket));
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.sent_at.is_some());
+        assert_eq!(delivery.acked_at, None);
+
+        // The server's ack is picked up with the next poll
+        client.poll_messages().unwrap();
+        let delivery = client.message_info(&sent.message_id).unwrap().delivery.unwrap();
+        assert!(delivery.acked_at.unwrap() >= delivery.sent_at.unwrap());
+
+        // A failed send and its resend count as two attempts
+        server.inject_fault(Fault::Network("reset".into()), 1);
+        assert!(client.send_message("bob", "again").is_err());
+        let failed = client
+            .get_messages("bob", 10, 0)
+            .unwrap()
+            .into_iter()
+            .find(|m| m.content == "again")
+            .unwrap();
+        let delivery = client.message_info(&failed.message_id).unwrap().delivery.unwrap();
+        assert_eq!((delivery.transport, delivery.attempts), (None, 1));
+        client.resend_message(&failed.message_id).unwrap();
+        let delivery = client.message_info(&failed.message_id).unwrap().delivery.unwrap();
+        assert_eq!(delivery.transport, Some(DeliveryTransport::WebSocket));
+        assert_eq!(delivery.attempts, 2);
+
+        assert!(client.message_info("missing").is_err());
+    }
+
+    #[test]
+    fn test_conversation_pages_and_changes() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        for i in 0..1200 {
+            client
+                .storage
+                .save_conversation(&Conversation {
+                    id: format!("peer-{:04}", i),
+                    peer_id: format!("peer-{:04}", i),
+                    peer_name: None,
+                    peer_avatar: None,
+                    last_message: Some("hi".into()),
+                    last_message_time: Some(i),
+                    unread_count: 0,
+                    is_muted: false,
+                    is_pinned: i == 7,
+                    is_archived: false,
+                })
+                .unwrap();
+        }
+        assert_eq!(client.conversation_count().unwrap(), 1200);
+
+        let first = client.get_conversation_page(10_000, 0).unwrap();
+        assert_eq!(first.len(), crate::MAX_CONVERSATION_PAGE);
+        assert_eq!(first[0].id, "peer-0007");
+        assert_eq!(first[1].id, "peer-1199");
+        let last = client.get_conversation_page(500, 1000).unwrap();
+        assert_eq!(last.len(), 200);
+        assert_eq!(last.last().unwrap().id, "peer-0000");
+
+        // Only what changed after the cursor comes back
+        let cursor = client.conversation_cursor().unwrap();
+        assert!(client.get_conversation_changes(cursor).unwrap().conversations.is_empty());
+        client
+            .set_conversations_muted(&["peer-0500".to_string()], true)
+            .unwrap();
+        let sent = client.send_message("bob", "hello").unwrap();
+        let changes = client.get_conversation_changes(cursor).unwrap();
+        let ids: Vec<&str> = changes.conversations.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["peer-0500", "bob"]);
+        assert!(changes.conversations[0].is_muted);
+        assert_eq!(changes.conversations[1].last_message_time, Some(sent.timestamp));
+        assert!(changes.cursor > cursor);
+
+        let again = client.get_conversation_changes(changes.cursor).unwrap();
+        assert!(again.conversations.is_empty());
+        assert_eq!(again.cursor, changes.cursor);
+    }
+
+    #[test]
+    fn test_conversation_profile_refresh() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hi").unwrap();
+        let mut profile = client.get_user_profile("bob").unwrap();
+        client.poll_events();
+
+        // A received message refreshes its sender in the background
+        profile.display_name = Some("Bob".into());
+        server.add_user(profile.clone());
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", r#"{"text":"hey"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        client.poll_messages().unwrap();
+        let renamed = std::iter::from_fn(|| client.next_event(Duration::from_secs(5))).any(|e| {
+            matches!(
+                e,
+                ClientEvent::ConversationProfileChanged { conversation_id, peer_name, .. }
+                    if conversation_id == "bob" && peer_name.as_deref() == Some("Bob")
+            )
+        });
+        assert!(renamed);
+        assert_eq!(
+            client.get_conversations().unwrap()[0].peer_name.as_deref(),
+            Some("Bob")
+        );
+
+        // New messages keep the name
+        client.send_message("bob", "again").unwrap();
+        assert_eq!(
+            client.get_conversations().unwrap()[0].peer_name.as_deref(),
+            Some("Bob")
+        );
+
+        profile.display_name = Some("Robert".into());
+        profile.avatar_file_id = Some("avatar-1".into());
+        profile.public_key = Some("new-key".into());
+        server.add_user(profile);
+        assert_eq!(client.refresh_conversation_profiles().unwrap(), 1);
+        let conversation = client.get_conversations().unwrap().remove(0);
+        assert_eq!(conversation.peer_name.as_deref(), Some("Robert"));
+        assert_eq!(conversation.peer_avatar.as_deref(), Some("avatar-1"));
+        assert_eq!(client.refresh_conversation_profiles().unwrap(), 0);
+
+        // The cached key only changes through get_user_profile, which
+        // notes the change
+        assert_ne!(
+            client.storage.get_user("bob").unwrap().unwrap().public_key.as_deref(),
+            Some("new-key")
+        );
+    }
+
+    #[test]
+    fn test_custom_message_handlers() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        client.send_custom("bob", "x-acme-poll", r#"{"question":"lunch?"}"#).unwrap();
+        assert_eq!(server.sent_messages().pop().unwrap().message_type, "x-acme-poll");
+        assert!(client.send_custom("bob", "poll", "{}").is_err());
+        assert!(client.register_handler("acme", |_| {}).is_err());
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        for registered in ["x-acme", "x-acme-vote"] {
+            let received = received.clone();
+            client
+                .register_handler(registered, move |msg| {
+                    received.lock().push((registered, msg.payload.clone()))
+                })
+                .unwrap();
+        }
+        client.set_store_unhandled_custom(true);
+
+        bob.establish_session("alice", &alice_key).unwrap();
+        for (i, message_type) in ["x-acme-poll", "x-acme-vote", "x-other-ping"].into_iter().enumerate() {
+            server.push_incoming(MessageEnvelope {
+                message_id: format!("c{}", i),
+                sender_id: "bob".into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: bob.encrypt_for("alice", message_type).unwrap(),
+                message_type: message_type.into(),
+                timestamp: i as i64,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        }
+
+        // None of them end up in a conversation
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert!(client.get_conversations().unwrap().is_empty());
+        assert_eq!(
+            *received.lock(),
+            vec![
+                ("x-acme", "x-acme-poll".to_string()),
+                ("x-acme-vote", "x-acme-vote".to_string()),
+            ]
+        );
+        let stored = client.custom_messages("x-other").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].payload, "x-other-ping");
+        assert_eq!(server.acked_messages(), vec!["c0", "c1", "c2"]);
+    }
+
+    #[test]
+    fn test_conversation_handle() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let carol = add_peer(&server, "carol");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let chat = client.conversation("bob");
+        let events = chat.subscribe();
+        chat.send_text("first").unwrap();
+        chat.typing(true).unwrap();
+        assert_eq!(server.sent_typing(), vec![("bob".to_string(), true)]);
+
+        for (i, (sender, peer)) in [("bob", &bob), ("carol", &carol)].into_iter().enumerate() {
+            peer.establish_session("alice", &alice_key).unwrap();
+            server.push_incoming(MessageEnvelope {
+                message_id: format!("m{}", i),
+                sender_id: sender.into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: peer.encrypt_for("alice", r#"{"text":"second"}"#).unwrap(),
+                message_type: "text".into(),
+                timestamp: chrono::Utc::now().timestamp_millis() + 1000,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        }
+        assert_eq!(client.poll_messages().unwrap().len(), 2);
+
+        // Only bob's message reaches this conversation's subscription
+        match events.drain().as_slice() {
+            [ClientEvent::MessageReceived(message)] => assert_eq!(message.sender_id, "bob"),
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        let contents = |range| -> Vec<String> {
+            chat.history(range).unwrap().into_iter().map(|m| m.content).collect()
+        };
+        assert_eq!(contents(0..10), vec!["first", "second"]);
+        assert_eq!(contents(0..1), vec!["second"]);
+        assert_eq!(contents(1..2), vec!["first"]);
+
+        assert_eq!(chat.summary().unwrap().unwrap().unread_count, 1);
+        chat.mark_read().unwrap();
+        assert_eq!(chat.summary().unwrap().unwrap().unread_count, 0);
+        assert!(client.conversation("dave").summary().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_system_messages() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        client.get_user_profile("bob").unwrap();
+        let events = client.conversation("bob").subscribe();
+
+        // Bob reinstalls and comes back with a new identity key
+        add_peer(&server, "bob");
+        client.get_user_profile("bob").unwrap();
+        client
+            .record_system_event(
+                "bob",
+                SystemEvent::CallMissed {
+                    caller_id: "bob".into(),
+                    call_type: CallType::Video,
+                },
+            )
+            .unwrap();
+
+        let messages = client.get_messages("bob", 10, 0).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.message_type == MessageType::System));
+        assert!(messages
+            .iter()
+            .any(|m| m.system_event == Some(SystemEvent::KeyChanged { user_id: "bob".into() })));
+        assert!(messages.iter().any(|m| m.content == "Missed video call from bob"));
+        assert_eq!(client.get_conversations().unwrap()[0].unread_count, 0);
+        assert!(matches!(events.drain()[..], [ClientEvent::SystemMessage(_), ClientEvent::SystemMessage(_)]));
+
+        // An unchanged key adds nothing
+        client.get_user_profile("bob").unwrap();
+        assert_eq!(client.get_messages("bob", 10, 0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_key_conflict() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hello").unwrap();
+        assert!(client.key_conflicts().unwrap().is_empty());
+
+        // The server now hands out another key for bob; his message under
+        // it can't be read with the old session
+        let impostor = add_peer(&server, "bob");
+        impostor.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: impostor.encrypt_for("alice", r#"{"text":"it's me"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].unverified_key);
+        assert!(client.storage.get_message("m1").unwrap().unwrap().unverified_key);
+
+        let conflict = client.key_conflict("bob").unwrap().unwrap();
+        assert_eq!(conflict.new_key, impostor.get_public_key().unwrap());
+        assert!(matches!(client.send_message("bob", "who is this?"), Err(Error::KeyConflict(_))));
+        assert_eq!(server.sent_messages().len(), 1);
+        // Fetching the same key again doesn't announce it twice
+        client.get_user_profile("bob").unwrap();
+        let notices = |event: SystemEvent| {
+            client
+                .get_messages("bob", 10, 0)
+                .unwrap()
+                .iter()
+                .filter(|m| m.system_event.as_ref() == Some(&event))
+                .count()
+        };
+        assert_eq!(notices(SystemEvent::KeyChanged { user_id: "bob".into() }), 1);
+
+        let archived = client.archived_keys("bob").unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].public_key, conflict.trusted_key);
+        assert!(archived[0].last_message_time.is_some());
+
+        assert!(client.trust_new_key("bob").unwrap());
+        assert!(!client.trust_new_key("bob").unwrap());
+        assert!(client.key_conflicts().unwrap().is_empty());
+        assert_eq!(notices(SystemEvent::KeyTrusted { user_id: "bob".into() }), 1);
+        client.send_message("bob", "hi again").unwrap();
+        assert_eq!(server.sent_messages().len(), 2);
+        // What came in before stays marked
+        assert!(client.storage.get_message("m1").unwrap().unwrap().unverified_key);
+    }
+
+    #[test]
+    fn test_session_cache_limits() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        add_peer(&server, "carol");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.set_cache_limits(CacheLimits {
+            sessions: 1,
+            ..Default::default()
+        });
+
+        client.send_message("bob", "hello").unwrap();
+        client.send_message("carol", "hello").unwrap();
+        let stats = client.stats().sessions;
+        assert_eq!((stats.len, stats.capacity, stats.evictions), (1, 1, 1));
+        // Classical sessions are kept in the database as they are made
+        #[cfg(not(feature = "pq-hybrid"))]
+        assert_eq!(client.storage.get_session_count().unwrap(), 2);
+
+        // Bob's session comes back for his reply
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", r#"{"text":"hi alice"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].content, "hi alice");
+        assert_eq!(client.stats().sessions.evictions, 2);
+        assert!(client.stats().sessions.hit_rate() > 0.0);
+    }
+
+    // Hybrid sessions aren't kept
+    #[cfg(not(feature = "pq-hybrid"))]
+    #[test]
+    fn test_restore_sessions() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        add_peer(&server, "carol");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hello").unwrap();
+        client.send_message("carol", "hello").unwrap();
+        let private_key = client.export_private_key().unwrap();
+        // Sealed, not the bare secret
+        let stored = client.storage.load_session("bob").unwrap().unwrap();
+        assert_eq!(stored.sealed_secret.len(), 12 + 32 + 16);
+        drop(client);
+
+        let client = server.client(&dir).unwrap();
+        assert!(client.restore_sessions().is_err());
+        client.init_keys(Some(&private_key)).unwrap();
+        client.set_cache_limits(CacheLimits {
+            sessions: 1,
+            ..Default::default()
+        });
+        assert_eq!(client.restore_sessions().unwrap(), 1);
+        assert_eq!(client.stats().sessions.len, 1);
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("carol", "hello again").unwrap();
+        assert_eq!(server.sent_messages().len(), 3);
+        drop(client);
+
+        // Another identity can't open them
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+        assert_eq!(client.restore_sessions().unwrap(), 0);
+    }
+
+    // Hybrid sessions don't ratchet
+    #[cfg(not(feature = "pq-hybrid"))]
+    #[test]
+    fn test_ratchet_messages() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.set_flag(Flag::Ratchet, Some(true));
+        bob.establish_session("alice", &alice_key).unwrap();
+        bob.set_ratchet_enabled(true);
+
+        // The first message is classical and says Alice reads ratchets
+        client.send_message("bob", "hello").unwrap();
+        let sent = server.sent_messages().pop().unwrap();
+        assert!(!sent.encrypted_content.starts_with("ratchet:"));
+        let content: serde_json::Value =
+            serde_json::from_str(&bob.decrypt_from("alice", &sent.encrypted_content).unwrap()).unwrap();
+        assert_eq!(content["ratchet"], true);
+        assert!(bob.start_ratchet("alice").unwrap());
+
+        let reply = |id: &str, text: &str| {
+            let encrypted_content = bob.encrypt_for("alice", &serde_json::json!({ "text": text }).to_string()).unwrap();
+            assert!(encrypted_content.starts_with("ratchet:"));
+            server.push_incoming(MessageEnvelope {
+                message_id: id.into(),
+                sender_id: "bob".into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content,
+                message_type: "text".into(),
+                timestamp: 1,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            });
+        };
+        reply("m1", "hi alice");
+        assert_eq!(client.poll_messages().unwrap()[0].content, "hi alice");
+
+        // Alice answers over the ratchet now
+        client.send_message("bob", "ratcheted").unwrap();
+        let sent = server.sent_messages().pop().unwrap();
+        assert!(sent.encrypted_content.starts_with("ratchet:"));
+        assert!(bob.decrypt_from("alice", &sent.encrypted_content).unwrap().contains("ratcheted"));
+
+        // The ratchet survives a restart
+        let private_key = client.export_private_key().unwrap();
+        drop(client);
+        let client = server.client(&dir).unwrap();
+        client.init_keys(Some(&private_key)).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        reply("m2", "still here");
+        assert_eq!(client.poll_messages().unwrap()[0].content, "still here");
+
+        // Switched off, Alice writes classical ciphertexts again
+        client.send_message("bob", "plain").unwrap();
+        let sent = server.sent_messages().pop().unwrap();
+        assert!(!sent.encrypted_content.starts_with("ratchet:"));
+        assert!(bob.decrypt_from("alice", &sent.encrypted_content).unwrap().contains("plain"));
+    }
+
+    #[test]
+    fn test_attachment_expiry_sync() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let message = client
+            .send_file("bob", vec![7u8; 100], "notes.txt", "text/plain", None)
+            .unwrap();
+        let file_id = message.attachment.unwrap().file_id;
+        let stored_expiry = |client: &PrivMsgClient| {
+            client.get_messages("bob", 10, 0).unwrap()[0].attachment.as_ref().unwrap().expires_at
+        };
+
+        let expires_at = client.extend_attachment(&message.message_id, 48).unwrap();
+        assert_eq!(server.file_expiry(&file_id), Some(expires_at));
+        assert_eq!(stored_expiry(&client), Some(expires_at));
+        let sent = server.sent_messages().pop().unwrap();
+        assert_eq!(sent.message_type, FILE_EXPIRY_MESSAGE_TYPE);
+        assert_eq!(sent.recipient_id, "bob");
+
+        // Bob deleted the file after downloading it
+        bob.establish_session("alice", &alice_key).unwrap();
+        let expiry = FileExpiry { file_id: file_id.clone(), expires_at: 1 };
+        server.push_incoming(MessageEnvelope {
+            message_id: "x1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob
+                .encrypt_for("alice", &serde_json::to_string(&expiry).unwrap())
+                .unwrap(),
+            message_type: FILE_EXPIRY_MESSAGE_TYPE.into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert_eq!(stored_expiry(&client), Some(1));
+        assert_eq!(server.acked_messages(), vec!["x1".to_string()]);
+        match client.poll_events().as_slice() {
+            [ClientEvent::AttachmentExpiryChanged { conversation_id, expiry: changed }] => {
+                assert_eq!(conversation_id, "bob");
+                assert_eq!(changed, &expiry);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        client.delete_attachment(&message.message_id).unwrap();
+        assert!(server.uploaded_file(&file_id).is_none());
+        assert!(client.delete_attachment(&message.message_id).is_err());
+    }
+
+    #[test]
+    fn test_attachment_reupload_request() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        bob.establish_session("alice", &alice_key).unwrap();
+
+        // The expiry the server gave the upload travels with the attachment
+        let sent = client
+            .send_file("bob", vec![7u8; 100], "notes.txt", "text/plain", None)
+            .unwrap();
+        let sent_attachment = sent.attachment.unwrap();
+        assert_eq!(sent_attachment.expires_at, server.file_expiry(&sent_attachment.file_id));
+        let payload = bob
+            .decrypt_from("alice", &server.sent_messages().pop().unwrap().encrypted_content)
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["expires_at"].as_i64(), sent_attachment.expires_at);
+
+        let file_key = bob.generate_file_key().unwrap();
+        let payload = serde_json::json!({
+            "file_id": "f1",
+            "file_name": "plan.pdf",
+            "file_size": 10,
+            "mime_type": "application/pdf",
+            "encryption_key": file_key,
+            "expires_at": 1
+        });
+        let envelope = |message_id: &str, message_type: &str, content: &str| MessageEnvelope {
+            message_id: message_id.into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", content).unwrap(),
+            message_type: message_type.into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        };
+        server.push_incoming(envelope("m1", "file", &payload.to_string()));
+        let received = client.poll_messages().unwrap();
+        let attachment = received[0].attachment.as_ref().unwrap();
+        assert!(attachment.is_expired());
+        let dest = std::path::Path::new(&temp_dir()).join("plan.pdf");
+        assert!(matches!(
+            client.download_attachment_to(attachment, &dest),
+            Err(Error::AttachmentExpired)
+        ));
+
+        client.request_reupload("m1").unwrap();
+        let request = server.sent_messages().pop().unwrap();
+        assert_eq!(request.message_type, REUPLOAD_REQUEST_MESSAGE_TYPE);
+        let request: ReuploadRequest =
+            serde_json::from_str(&bob.decrypt_from("alice", &request.encrypted_content).unwrap()).unwrap();
+        assert_eq!(request, ReuploadRequest { message_id: "m1".into(), file_id: "f1".into() });
+        // Only received attachments can be asked for
+        assert!(client.request_reupload(&sent.message_id).is_err());
+
+        // Bob asks for Alice's file back, and for one she never sent him
+        let asked = ReuploadRequest {
+            message_id: sent.message_id.clone(),
+            file_id: sent_attachment.file_id.clone(),
+        };
+        let unknown = ReuploadRequest { message_id: "m1".into(), file_id: "f1".into() };
+        for (id, request) in [("r1", &asked), ("r2", &unknown)] {
+            let json = serde_json::to_string(request).unwrap();
+            server.push_incoming(envelope(id, REUPLOAD_REQUEST_MESSAGE_TYPE, &json));
+        }
+        client.poll_events();
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert_eq!(server.acked_messages(), vec!["m1".to_string(), "r1".into(), "r2".into()]);
+        match client.poll_events().as_slice() {
+            [ClientEvent::ReuploadRequested { conversation_id, request }] => {
+                assert_eq!(conversation_id, "bob");
+                assert_eq!(request, &asked);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reupload_attachment() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        bob.establish_session("alice", &alice_key).unwrap();
+
+        let source = std::path::Path::new(&dir).join("notes.txt");
+        std::fs::write(&source, b"meeting notes").unwrap();
+        let sent = client
+            .send_file_from_path("bob", &source, "text/plain", None)
+            .unwrap();
+        let first = sent.attachment.clone().unwrap();
+        let envelope = |message_id: &str, message_type: &str, content: &str| MessageEnvelope {
+            message_id: message_id.into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", content).unwrap(),
+            message_type: message_type.into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        };
+        let request = |file_id: &str| {
+            serde_json::to_string(&ReuploadRequest {
+                message_id: sent.message_id.clone(),
+                file_id: file_id.into(),
+            })
+            .unwrap()
+        };
+
+        // Without auto re-upload the user confirms
+        server.push_incoming(envelope("r1", REUPLOAD_REQUEST_MESSAGE_TYPE, &request(&first.file_id)));
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert!(matches!(
+            client.poll_events().as_slice(),
+            [ClientEvent::ReuploadRequested { .. }]
+        ));
+        let second = client.reupload_attachment(&sent.message_id).unwrap();
+        assert_ne!(second.file_id, first.file_id);
+        assert_ne!(second.encryption_key, first.encryption_key);
+        assert_eq!(second.expires_at, server.file_expiry(&second.file_id));
+        let stored = client.storage.get_message(&sent.message_id).unwrap().unwrap();
+        assert_eq!(stored.attachment.unwrap().file_id, second.file_id);
+
+        let update = server.sent_messages().pop().unwrap();
+        assert_eq!(update.message_type, ATTACHMENT_UPDATE_MESSAGE_TYPE);
+        let update: AttachmentUpdate =
+            serde_json::from_str(&bob.decrypt_from("alice", &update.encrypted_content).unwrap()).unwrap();
+        assert_eq!(update.message_id, sent.message_id);
+        assert_eq!(update.attachment.file_id, second.file_id);
+        assert!(update.attachment.local_path.is_none());
+        let dest = std::path::Path::new(&dir).join("download.txt");
+        client.download_attachment_to(&second, &dest).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"meeting notes");
+
+        // A request for a file that was replaced since is ignored
+        client.set_auto_reupload(true);
+        let sent_before = server.sent_messages().len();
+        server.push_incoming(envelope("r2", REUPLOAD_REQUEST_MESSAGE_TYPE, &request(&first.file_id)));
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert!(client.poll_events().is_empty());
+        assert_eq!(server.sent_messages().len(), sent_before);
+
+        // With auto re-upload on, the request is answered right away
+        server.push_incoming(envelope("r3", REUPLOAD_REQUEST_MESSAGE_TYPE, &request(&second.file_id)));
+        assert!(client.poll_messages().unwrap().is_empty());
+        match client.poll_events().as_slice() {
+            [ClientEvent::AttachmentReuploaded { conversation_id, message_id }] => {
+                assert_eq!(conversation_id, "bob");
+                assert_eq!(message_id, &sent.message_id);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+        assert_eq!(server.sent_messages().pop().unwrap().message_type, ATTACHMENT_UPDATE_MESSAGE_TYPE);
+        let third = client.storage.get_message(&sent.message_id).unwrap().unwrap().attachment.unwrap();
+        assert_ne!(third.file_id, second.file_id);
+
+        // Without a local copy the user has to send the file again
+        std::fs::remove_file(&source).unwrap();
+        server.push_incoming(envelope("r4", REUPLOAD_REQUEST_MESSAGE_TYPE, &request(&third.file_id)));
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert!(matches!(
+            client.poll_events().as_slice(),
+            [ClientEvent::ReuploadRequested { .. }]
+        ));
+        assert!(client.reupload_attachment(&sent.message_id).is_err());
+
+        // Bob uploads a file he sent again
+        let payload = serde_json::json!({
+            "file_id": "f1",
+            "file_name": "plan.pdf",
+            "file_size": 10,
+            "mime_type": "application/pdf",
+            "encryption_key": bob.generate_file_key().unwrap(),
+            "expires_at": 1
+        });
+        server.push_incoming(envelope("m1", "file", &payload.to_string()));
+        client.poll_messages().unwrap();
+        let mut attachment = client.storage.get_message("m1").unwrap().unwrap().attachment.unwrap();
+        attachment.file_id = "f2".into();
+        attachment.expires_at = None;
+        attachment.local_path = Some("/elsewhere/plan.pdf".into());
+        let update = serde_json::to_string(&AttachmentUpdate {
+            message_id: "m1".into(),
+            attachment,
+        })
+        .unwrap();
+        server.push_incoming(envelope("u1", ATTACHMENT_UPDATE_MESSAGE_TYPE, &update));
+        assert!(client.poll_messages().unwrap().is_empty());
+        let updated = client.storage.get_message("m1").unwrap().unwrap().attachment.unwrap();
+        assert_eq!(updated.file_id, "f2");
+        assert!(!updated.is_expired());
+        assert!(updated.local_path.is_none());
+        assert!(client.poll_events().iter().any(|event| matches!(
+            event,
+            ClientEvent::AttachmentReuploaded { message_id, .. } if message_id == "m1"
+        )));
+    }
+
+    #[test]
+    fn test_per_device_encryption() {
+        let server = MockServer::new();
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        // Bob's phone holds his published key and certifies his laptop's
+        let phone = add_peer(&server, "bob");
+        let laptop = CryptoEngine::new();
+        laptop.generate_identity().unwrap();
+        for (device_id, engine) in [("phone", &phone), ("laptop", &laptop)] {
+            engine.establish_session("alice", &alice_key).unwrap();
+            let public_key = engine.get_public_key().unwrap();
+            let signature = phone
+                .sign(&crate::devices::certificate_data("bob", device_id, &public_key))
+                .unwrap();
+            server.add_device_key(
+                "bob",
+                DeviceKey {
+                    device_id: device_id.into(),
+                    public_key,
+                    signature: Some(signature),
+                },
+            );
+        }
+        // A device the server lists without a certificate
+        let tablet = CryptoEngine::new();
+        tablet.generate_identity().unwrap();
+        tablet.establish_session("alice", &alice_key).unwrap();
+        let tablet_key = DeviceKey {
+            device_id: "tablet".into(),
+            public_key: tablet.get_public_key().unwrap(),
+            signature: None,
+        };
+        server.add_device_key("bob", tablet_key.clone());
+
+        // One copy per certified device, each readable by that device alone
+        let sent = client.send_message("bob", "hi").unwrap();
+        let copies = server.sent_messages();
+        assert_eq!(copies.len(), 2);
+        for (copy, (device_id, engine)) in copies.iter().zip([("phone", &phone), ("laptop", &laptop)]) {
+            assert_eq!(copy.recipient_device_id.as_deref(), Some(device_id));
+            assert_eq!(copy.message_id, format!("{}/{}", sent.message_id, device_id));
+            let payload = engine.decrypt_from("alice", &copy.encrypted_content).unwrap();
+            let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+            assert_eq!(payload["text"], "hi");
+        }
+        assert!(phone.decrypt_from("alice", &copies[1].encrypted_content).is_err());
+
+        // A copy from the laptop is read under the session with it
+        let content = serde_json::json!({ "text": "from my laptop" }).to_string();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1/device-1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: Some("device-1".into()),
+            encrypted_content: laptop.encrypt_for("alice", &content).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].message_id, "m1");
+        assert_eq!(received[0].content, "from my laptop");
+        assert_eq!(server.acked_messages(), vec!["m1/device-1".to_string()]);
+
+        // The tablet waits for the user like a changed key, announced once
+        assert_eq!(client.pending_devices("bob").unwrap(), vec![tablet_key]);
+        let notices = client
+            .get_messages("bob", 10, 0)
+            .unwrap()
+            .iter()
+            .filter(|m| m.system_event == Some(SystemEvent::KeyChanged { user_id: "bob".into() }))
+            .count();
+        assert_eq!(notices, 1);
+        assert!(client.trust_new_key("bob").unwrap());
+        assert!(client.pending_devices("bob").unwrap().is_empty());
+        let sent = client.send_message("bob", "welcome").unwrap();
+        let copy = server.sent_messages().pop().unwrap();
+        assert_eq!(copy.message_id, format!("{}/tablet", sent.message_id));
+        assert!(tablet.decrypt_from("alice", &copy.encrypted_content).is_ok());
+    }
+
+    #[test]
+    fn test_certify_device() {
+        let server = MockServer::new();
+        let phone = server.client(&temp_dir()).unwrap();
+        let phone_key = phone.init_keys(None).unwrap();
+        phone.login("bob", "key", "phone").unwrap();
+        // Logging in publishes the device's key, so the laptop's is bob's
+        let laptop = server.client(&temp_dir()).unwrap();
+        let laptop_key = laptop.init_keys(None).unwrap();
+        laptop.login("bob", "key", "laptop").unwrap();
+
+        let phone_device = server
+            .device_keys("bob")
+            .into_iter()
+            .find(|key| key.public_key == phone_key)
+            .unwrap();
+        assert!(matches!(
+            phone.certify_device(&phone_device.device_id),
+            Err(Error::InvalidConfig(_))
+        ));
+        laptop.certify_device(&phone_device.device_id).unwrap();
+        let certified = server
+            .device_keys("bob")
+            .into_iter()
+            .find(|key| key.public_key == phone_key)
+            .unwrap();
+        assert!(crate::devices::is_certified(&laptop_key, "bob", &certified));
+        assert!(!crate::devices::is_certified(&phone_key, "bob", &certified));
+    }
+
+    #[test]
+    fn test_delete_for_everyone() {
+        let server = MockServer::new();
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        let bob = add_peer(&server, "bob");
+        bob.establish_session("alice", &alice_key).unwrap();
+        let mallory = add_peer(&server, "mallory");
+        mallory.establish_session("alice", &alice_key).unwrap();
+
+        // Gone here, dropped on the server and deleted on bob's side
+        let sent = client.send_message("bob", "oops").unwrap();
+        client.delete_message(&sent.message_id, true).unwrap();
+        assert!(client.get_messages("bob", 10, 0).unwrap().is_empty());
+        assert_eq!(server.retracted_messages(), vec![sent.message_id.clone()]);
+        let deletions: Vec<_> = server
+            .sent_messages()
+            .into_iter()
+            .filter(|m| m.message_type == DELETION_MESSAGE_TYPE)
+            .collect();
+        // A copy also goes to alice's own devices
+        assert!(deletions.iter().any(|m| m.recipient_id == "alice"));
+        let deletion = deletions.iter().find(|m| m.recipient_id == "bob").unwrap();
+        let payload = bob.decrypt_from("alice", &deletion.encrypted_content).unwrap();
+        let payload: MessageDeletion = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload.message_id, sent.message_id);
+
+        let envelope = |id: &str, sender: &str, engine: &CryptoEngine, message_type: &str, content: String| {
+            MessageEnvelope {
+                message_id: id.into(),
+                sender_id: sender.into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: engine.encrypt_for("alice", &content).unwrap(),
+                message_type: message_type.into(),
+                timestamp: 1,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            }
+        };
+        let text = serde_json::json!({ "text": "hi" }).to_string();
+        server.push_incoming(envelope("m1", "bob", &bob, "text", text));
+        client.poll_messages().unwrap();
+        client.poll_events();
+
+        // Only bob can delete his message
+        let deletion = serde_json::to_string(&MessageDeletion { message_id: "m1".into() }).unwrap();
+        server.push_incoming(envelope("d1", "mallory", &mallory, DELETION_MESSAGE_TYPE, deletion.clone()));
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert_eq!(client.get_messages("bob", 10, 0).unwrap().len(), 1);
+        assert!(client.delete_message("m1", true).is_err());
+
+        server.push_incoming(envelope("d2", "bob", &bob, DELETION_MESSAGE_TYPE, deletion));
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert!(client.get_messages("bob", 10, 0).unwrap().is_empty());
+        assert!(server.acked_messages().ends_with(&["d1".to_string(), "d2".to_string()]));
+        match client.poll_events().as_slice() {
+            [ClientEvent::MessageDeleted { conversation_id, message_id }] => {
+                assert_eq!(conversation_id, "bob");
+                assert_eq!(message_id, "m1");
+            }
+            other => panic!("unexpected events: {:?}", other),
+        }
+
+        // A deletion that overtakes its message drops it on arrival, but
+        // only if it came from the message's sender
+        for (id, deleter, engine) in [("m2", "bob", &bob), ("m3", "mallory", &mallory)] {
+            let deletion = serde_json::to_string(&MessageDeletion { message_id: id.into() }).unwrap();
+            let deletion_id = format!("d-{}", id);
+            server.push_incoming(envelope(&deletion_id, deleter, engine, DELETION_MESSAGE_TYPE, deletion));
+            assert!(client.poll_messages().unwrap().is_empty());
+        }
+        for id in ["m2", "m3"] {
+            let text = serde_json::json!({ "text": id }).to_string();
+            server.push_incoming(envelope(id, "bob", &bob, "text", text));
+        }
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].message_id, "m3");
+        assert!(server.acked_messages().ends_with(&["m2".to_string(), "m3".to_string()]));
+        let kept: Vec<_> = client
+            .get_messages("bob", 10, 0)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.message_id)
+            .collect();
+        assert_eq!(kept, vec!["m3".to_string()]);
+    }
+
+    #[test]
+    fn test_disappearing_messages() {
+        let server = MockServer::new();
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        let bob = add_peer(&server, "bob");
+        bob.establish_session("alice", &alice_key).unwrap();
+
+        let kept = client.send_message("bob", "before").unwrap();
+        client
+            .set_conversation_ttl("bob", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(client.get_conversation_ttl("bob").unwrap(), Some(Duration::from_secs(60)));
+        let to_bob: Vec<MessageEnvelope> = server
+            .sent_messages()
+            .into_iter()
+            .filter(|e| e.message_type == CONVERSATION_TTL_MESSAGE_TYPE && e.recipient_id == "bob")
+            .collect();
+        assert_eq!(to_bob.len(), 1);
+        let payload = bob.decrypt_from("alice", &to_bob[0].encrypted_content).unwrap();
+        let payload: ConversationTtl = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload.ttl_seconds, Some(60));
+
+        // Setting it again sends nothing
+        let sent = server.sent_messages().len();
+        client
+            .set_conversation_ttl("bob", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(server.sent_messages().len(), sent);
+
+        // Only messages sent since disappear, once their lifetime is over
+        let vanishing = client.send_message("bob", "after").unwrap();
+        let source = std::path::Path::new(&dir).join("clip.ogg");
+        std::fs::write(&source, b"voice").unwrap();
+        let attachment = client
+            .send_file_from_path("bob", &source, "audio/ogg", None)
+            .unwrap()
+            .attachment
+            .unwrap();
+        let OpenedAttachment::File(opened) = client.open_attachment(&attachment).unwrap() else {
+            panic!("expected a temp file");
+        };
+        // The lifetime runs from receipt, whatever the sender's clock says
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", r#"{"text":"from the past"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        assert_eq!(client.poll_messages().unwrap().len(), 1);
+        let saved_by = chrono::Utc::now().timestamp_millis();
+        client.poll_events();
+        assert_eq!(client.expiry.expire(vanishing.timestamp + 59_000).unwrap(), 0);
+        assert_eq!(client.expiry.expire(saved_by + 60_000).unwrap(), 3);
+        let remaining = client.get_messages("bob", 10, 0).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].message_id, kept.message_id);
+        let deleted: Vec<String> = client
+            .poll_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                ClientEvent::MessageDeleted { conversation_id, message_id } if conversation_id == "bob" => {
+                    Some(message_id)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deleted.len(), 3);
+        assert!(deleted.contains(&vanishing.message_id));
+        // The preview goes back to what is left, and the opened copy goes
+        let conversation = client.storage.get_conversation("bob").unwrap().unwrap();
+        assert_eq!(conversation.last_message.as_deref(), Some("before"));
+        assert!(!opened.exists());
+        assert!(client.open_attachments().is_empty());
+
+        // Bob turns it off again
+        let update = serde_json::to_string(&ConversationTtl {
+            conversation_id: "alice".into(),
+            ttl_seconds: None,
+        })
+        .unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "t1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", &update).unwrap(),
+            message_type: CONVERSATION_TTL_MESSAGE_TYPE.into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        assert!(client.poll_messages().unwrap().is_empty());
+        assert_eq!(client.get_conversation_ttl("bob").unwrap(), None);
+        assert!(server.acked_messages().contains(&"t1".to_string()));
+        match client.poll_events().as_slice() {
+            [ClientEvent::ConversationTtlChanged { conversation_id, ttl_seconds }] => {
+                assert_eq!(conversation_id, "bob");
+                assert_eq!(*ttl_seconds, None);
+            }
+            other => panic!("unexpected events: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_group_messages() {
+        let server = MockServer::new();
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        let peers: HashMap<&str, CryptoEngine> = ["bob", "carol", "dave", "mallory"]
+            .into_iter()
+            .map(|id| (id, add_peer(&server, id)))
+            .collect();
+        for peer in peers.values() {
+            peer.establish_session("alice", &alice_key).unwrap();
+        }
+
+        let group = client
+            .create_group("Team", &["bob".to_string(), "carol".to_string()])
+            .unwrap();
+        assert_eq!(group.member_ids, vec!["alice", "bob", "carol"]);
+        let conversation = client.get_conversations().unwrap().pop().unwrap();
+        assert_eq!(conversation.id, group.group_id);
+        assert_eq!(conversation.peer_name.as_deref(), Some("Team"));
+
+        // One copy per member, each under its own envelope id
+        let sent = client.send_message(&group.group_id, "hi all").unwrap();
+        assert_eq!(sent.status, MessageStatus::Sent);
+        assert_eq!(sent.conversation_id, group.group_id);
+        let copies = server.sent_messages();
+        let recipients: Vec<&str> = copies.iter().map(|e| e.recipient_id.as_str()).collect();
+        assert_eq!(recipients, vec!["bob", "carol"]);
+        assert_ne!(copies[0].message_id, copies[1].message_id);
+        for copy in &copies {
+            let payload = peers[copy.recipient_id.as_str()]
+                .decrypt_from("alice", &copy.encrypted_content)
+                .unwrap();
+            let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+            assert_eq!(payload["text"], "hi all");
+            assert_eq!(payload["group_id"], group.group_id.as_str());
+            assert_eq!(payload["group_message_id"], sent.message_id.as_str());
+        }
+
+        // Members' messages land in the group; others' are acked and dropped
+        let incoming = |id: &str, sender: &str| {
+            let content = serde_json::json!({
+                "text": "hey",
+                "group_id": group.group_id,
+                "group_message_id": format!("g-{}", id)
+            });
+            MessageEnvelope {
+                message_id: id.into(),
+                sender_id: sender.into(),
+                recipient_id: "alice".into(),
+                recipient_device_id: None,
+                encrypted_content: peers[sender].encrypt_for("alice", &content.to_string()).unwrap(),
+                message_type: "text".into(),
+                timestamp: 1,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            }
+        };
+        server.push_incoming(incoming("e1", "bob"));
+        server.push_incoming(incoming("e2", "mallory"));
+        // A group id the server doesn't know is dropped too, not retried
+        let mut forged = incoming("e3", "mallory");
+        let content = serde_json::json!({
+            "text": "hey",
+            "group_id": "no-such-group",
+            "group_message_id": "g-e3"
+        });
+        forged.encrypted_content = peers["mallory"].encrypt_for("alice", &content.to_string()).unwrap();
+        server.push_incoming(forged);
+        let received = client.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].message_id, "g-e1");
+        assert_eq!(received[0].conversation_id, group.group_id);
+        assert_eq!(
+            server.acked_messages(),
+            vec!["e1".to_string(), "e2".into(), "e3".into()]
+        );
+
+        // Members added elsewhere get the next message
+        let mut changed = group.clone();
+        changed.member_ids.push("dave".into());
+        server.set_group(changed);
+        client.send_message(&group.group_id, "welcome").unwrap();
+        let copies = server.sent_messages();
+        assert_eq!(copies.last().unwrap().recipient_id, "dave");
+        assert_eq!(client.group(&group.group_id).unwrap().unwrap().member_ids.len(), 4);
+
+        // After leaving, the history stays but can't be written to
+        client.leave_group(&group.group_id).unwrap();
+        assert!(client.is_conversation_read_only(&group.group_id).unwrap());
+        assert!(matches!(
+            client.send_message(&group.group_id, "bye"),
+            Err(Error::RecipientUnavailable)
+        ));
+        assert_eq!(client.get_messages(&group.group_id, 10, 0).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_delivery_receipts() {
+        let server = MockServer::new();
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        for id in ["bob", "carol"] {
+            add_peer(&server, id).establish_session("alice", &alice_key).unwrap();
+        }
+
+        let sent = client.send_message("bob", "hello").unwrap();
+        assert_eq!(sent.status, MessageStatus::Sent);
+        client.poll_events();
+
+        server.push_delivery(DeliveryReceipt {
+            recipient_id: "bob".into(),
+            message_ids: vec![sent.message_id.clone(), "unknown".into()],
+        });
+        client.poll_messages().unwrap();
+        assert_eq!(client.get_messages("bob", 1, 0).unwrap()[0].status, MessageStatus::Delivered);
+        match client.poll_events().as_slice() {
+            [ClientEvent::MessageStatusChanged { conversation_id, message_id, status }] => {
+                assert_eq!(conversation_id, "bob");
+                assert_eq!(message_id, &sent.message_id);
+                assert_eq!(*status, MessageStatus::Delivered);
+            }
+            other => panic!("unexpected events: {:?}", other),
+        }
+
+        // A second receipt for the same message changes nothing
+        server.push_delivery(DeliveryReceipt {
+            recipient_id: "bob".into(),
+            message_ids: vec![sent.message_id.clone()],
+        });
+        client.poll_messages().unwrap();
+        assert!(client.poll_events().is_empty());
+
+        // A receipt for a member's copy marks the group message
+        let group = client
+            .create_group("Team", &["bob".to_string(), "carol".to_string()])
+            .unwrap();
+        let sent = client.send_message(&group.group_id, "hi all").unwrap();
+        client.poll_events();
+        let copy = server.sent_messages().pop().unwrap();
+        server.push_delivery(DeliveryReceipt {
+            recipient_id: copy.recipient_id.clone(),
+            message_ids: vec![copy.message_id],
+        });
+        client.poll_messages().unwrap();
+        let stored = client.get_messages(&group.group_id, 1, 0).unwrap().pop().unwrap();
+        assert_eq!(stored.message_id, sent.message_id);
+        assert_eq!(stored.status, MessageStatus::Delivered);
+    }
+
+    #[test]
+    fn test_outgoing_hooks() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        let shout = client.register_outgoing_hook(10, |out| {
+            out.text = out.text.to_uppercase();
+            Ok(())
+        });
+        client.register_outgoing_hook(-5, |out| {
+            out.text = out.text.replace("@bob", "Bob");
+            out.metadata.insert("mentions".into(), serde_json::json!([out.recipient_id]));
+            Ok(())
+        });
+        // Same order as the first hook, so it runs after it
+        client.register_outgoing_hook(10, |out| {
+            out.text.push('!');
+            Ok(())
+        });
+
+        let sent = client.send_message("bob", "hi @bob").unwrap();
+        assert_eq!(sent.content, "HI BOB!");
+        assert_eq!(sent.metadata["mentions"], serde_json::json!(["bob"]));
+        let stored = client.get_messages("bob", 10, 0).unwrap();
+        assert_eq!(stored[0].content, "HI BOB!");
+
+        let file = client
+            .send_file("bob", vec![1u8; 10], "a.txt", "text/plain", Some("see @bob"))
+            .unwrap();
+        assert_eq!(file.caption.as_deref(), Some("SEE BOB!"));
+
+        assert!(client.unregister_outgoing_hook(shout));
+        assert!(!client.unregister_outgoing_hook(shout));
+        assert_eq!(client.send_message("bob", "ok").unwrap().content, "ok!");
+
+        // A failing hook stops the send before anything is stored
+        client.register_outgoing_hook(0, |_| Err(Error::InvalidConfig("blocked".into())));
+        let before = server.sent_messages().len();
+        assert!(client.send_message("bob", "nope").is_err());
+        assert_eq!(server.sent_messages().len(), before);
+        assert_eq!(client.get_messages("bob", 10, 0).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_client_policy() {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let rng = ring::rand::SystemRandom::new();
+        let new_key = || {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+        };
+        let sign = |key: &Ed25519KeyPair, policy: &ClientPolicy| {
+            let policy = serde_json::to_string(policy).unwrap();
+            let signature = key.sign(&privmsg_proto::policy::signing_bytes(&policy));
+            SignedPolicy {
+                policy,
+                signature: STANDARD.encode(signature.as_ref()),
+                public_key: STANDARD.encode(key.public_key().as_ref()),
+            }
+        };
+
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+        let dir = temp_dir();
+        let client = server.client(&dir).unwrap();
+        client.init_keys(None).unwrap();
+
+        let operator = new_key();
+        let mut policy = ClientPolicy {
+            version: 1,
+            notification_preview: Some(PreviewPolicy::Hidden),
+            disabled_features: vec![Feature::FileTransfer],
+            locked: vec![PolicySetting::NotificationPreview, PolicySetting::LastSeenVisibility],
+            ..Default::default()
+        };
+        server.set_client_policy(sign(&operator, &policy));
+        client.login("alice", "key", "test").unwrap();
+
+        assert_eq!(client.client_policy(), Some(policy.clone()));
+        assert!(matches!(
+            client.poll_events().as_slice(),
+            [ClientEvent::PolicyChanged(p)] if **p == policy
+        ));
+        assert!(client.is_locked_by_policy(PolicySetting::NotificationPreview));
+        assert_eq!(client.preview_policy(), PreviewPolicy::Hidden);
+        client.set_preview_policy(PreviewPolicy::Full);
+        assert_eq!(client.preview_policy(), PreviewPolicy::Hidden);
+        assert!(matches!(
+            client.set_last_seen_visibility(LastSeenVisibility::Everyone),
+            Err(Error::Policy(_))
+        ));
+        assert!(matches!(
+            client.send_file("bob", b"hi".to_vec(), "a.txt", "text/plain", None),
+            Err(Error::Policy(_))
+        ));
+
+        // The same version again changes nothing
+        assert_eq!(client.refresh_policy().unwrap(), Some(policy.clone()));
+        assert!(client.poll_events().is_empty());
+
+        // Other keys, tampering and rollbacks are rejected
+        policy.version = 2;
+        policy.disabled_features.clear();
+        policy.locked.clear();
+        policy.flags.insert("direct_transfer".to_string(), false);
+        server.set_client_policy(sign(&new_key(), &policy));
+        assert!(matches!(client.refresh_policy(), Err(Error::Crypto(_))));
+        let mut tampered = sign(&operator, &policy);
+        tampered.policy = tampered.policy.replace(r#""version":2"#, r#""version":3"#);
+        server.set_client_policy(tampered);
+        assert!(matches!(client.refresh_policy(), Err(Error::Crypto(_))));
+        let old = ClientPolicy { version: 0, ..policy.clone() };
+        server.set_client_policy(sign(&operator, &old));
+        assert!(matches!(client.refresh_policy(), Err(Error::Policy(_))));
+        assert_eq!(client.client_policy().unwrap().version, 1);
+
+        // A new version lifts the restrictions
+        server.set_client_policy(sign(&operator, &policy));
+        client.refresh_policy().unwrap();
+        client.set_preview_policy(PreviewPolicy::Full);
+        assert_eq!(client.preview_policy(), PreviewPolicy::Full);
+        client.send_file("bob", b"hi".to_vec(), "a.txt", "text/plain", None).unwrap();
+
+        // Its flags win over the client's own
+        let direct = |client: &PrivMsgClient| {
+            client.flags().into_iter().find(|s| s.flag == Flag::DirectTransfer).unwrap()
+        };
+        client.set_flag(Flag::DirectTransfer, Some(true));
+        client.set_flag(Flag::Ratchet, Some(true));
+        assert_eq!(direct(&client).source, FlagSource::Policy);
+        assert!(!client.is_flag_enabled(Flag::DirectTransfer));
+        assert!(client.is_flag_enabled(Flag::Ratchet));
+        client.set_flag(Flag::Ratchet, None);
+        assert!(!client.is_flag_enabled(Flag::Ratchet));
+
+        // The verified policy survives a restart
+        drop(client);
+        let client = server.client(&dir).unwrap();
+        assert_eq!(client.client_policy(), Some(policy));
+        assert!(!direct(&client).enabled);
+    }
+
+    #[test]
+    fn test_typing_and_presence_throttling() {
+        let server = MockServer::new();
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.set_presence_policy(PresencePolicy {
+            typing_interval: Duration::from_millis(200),
+            typing_idle_timeout: Duration::from_millis(300),
+            presence_interval: Duration::from_millis(200),
+        });
+
+        // A burst of keystrokes is one "typing", cleared once idle
+        for _ in 0..10 {
+            client.send_typing("bob", true).unwrap();
+        }
+        assert_eq!(server.sent_typing(), vec![("bob".to_string(), true)]);
+        std::thread::sleep(Duration::from_millis(600));
+        assert_eq!(
+            server.sent_typing(),
+            vec![("bob".to_string(), true), ("bob".to_string(), false)]
+        );
+
+        // Stopping twice only sends one "stopped"
+        client.send_typing("bob", true).unwrap();
+        client.send_typing("bob", false).unwrap();
+        client.send_typing("bob", false).unwrap();
+        assert_eq!(server.sent_typing().len(), 4);
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(server.sent_typing().len(), 4);
+
+        // Away and back within the window nets out to nothing more
+        client.set_presence(PresenceStatus::Away).unwrap();
+        client.set_presence(PresenceStatus::Online).unwrap();
+        client.set_presence(PresenceStatus::Away).unwrap();
+        client.set_presence(PresenceStatus::Away).unwrap();
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(server.sent_presence(), vec![PresenceStatus::Away]);
+
+        client.set_presence(PresenceStatus::Online).unwrap();
+        client.set_presence(PresenceStatus::Offline).unwrap();
+        assert_eq!(server.sent_presence(), vec![PresenceStatus::Away, PresenceStatus::Online]);
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(
+            server.sent_presence(),
+            vec![PresenceStatus::Away, PresenceStatus::Online, PresenceStatus::Offline]
+        );
+    }
+
+    #[test]
+    fn test_power_source() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "m1".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", r#"{"text":"hi"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        client.poll_messages().unwrap();
+
+        // On battery presence is held and read positions wait
+        client.set_power_source(PowerSource::Battery).unwrap();
+        assert_eq!(server.power_source(), PowerSource::Battery);
+        client.set_presence(PresenceStatus::Away).unwrap();
+        client.mark_conversation_read("bob").unwrap();
+        assert_eq!(client.get_conversations().unwrap()[0].unread_count, 0);
+        assert!(server.sent_presence().is_empty());
+        assert!(server.sent_messages().is_empty());
+
+        // Back on mains both go out
+        client.set_power_source(PowerSource::Mains).unwrap();
+        assert_eq!(server.power_source(), PowerSource::Mains);
+        assert_eq!(server.sent_presence(), vec![PresenceStatus::Away]);
+        let sent = server.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].message_type, READ_SYNC_MESSAGE_TYPE);
+    }
+
+    #[test]
+    fn test_session_revoked() {
+        let server = MockServer::new();
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+
+        server.revoke_session(RevocationReason::DeviceRemoved);
+        assert!(matches!(
+            client.poll_messages(),
+            Err(Error::SessionRevoked(RevocationReason::DeviceRemoved))
+        ));
+        assert!(matches!(
+            client.poll_events().as_slice(),
+            [ClientEvent::SessionRevoked(RevocationReason::DeviceRemoved)]
+        ));
+        assert!(client.get_current_user_id().is_err());
+
+        // Logging in again starts a fresh session
+        client.login("alice", "key", "test").unwrap();
+        assert!(client.poll_messages().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rejected_frames_become_events() {
+        let server = MockServer::new();
+        add_peer(&server, "bob");
+
+        let client = server.client(&temp_dir()).unwrap();
+        client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "first").unwrap();
+
+        server.inject_fault(Fault::Reject(ErrorCode::RecipientBlocked), 1);
+        client.send_message("bob", "blocked").unwrap();
+        assert_eq!(server.sent_messages().len(), 1);
+
+        client.poll_messages().unwrap();
+        match client.poll_events().as_slice() {
+            [ClientEvent::ServerError(error)] => {
+                assert_eq!(error.code, ErrorCode::RecipientBlocked);
+                assert!(matches!(Error::from(error.clone()), Error::RecipientBlocked));
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        // Looking up a new peer's key is an API call
+        add_peer(&server, "carol");
+        server.inject_fault(Fault::Reject(ErrorCode::RateLimited), 1);
+        assert!(matches!(client.send_message("carol", "hi"), Err(Error::RateLimited)));
+    }
+
+    #[test]
+    fn test_deactivated_peer_is_read_only() {
+        let server = MockServer::new();
+        let bob = add_peer(&server, "bob");
+        add_peer(&server, "carol");
+        let client = server.client(&temp_dir()).unwrap();
+        let alice_key = client.init_keys(None).unwrap();
+        client.login("alice", "key", "test").unwrap();
+        client.send_message("bob", "hi").unwrap();
+
+        // With a session the server is the one to notice
+        server.set_deactivated("bob", true);
+        let rejected = client.send_message("bob", "still there?").unwrap();
+        client.poll_messages().unwrap();
+        match client.poll_events().as_slice() {
+            [ClientEvent::PeerAvailabilityChanged { peer_id, available: false }] => assert_eq!(peer_id, "bob"),
+            other => panic!("unexpected events {:?}", other),
+        }
+        let stored = client.storage.get_message(&rejected.message_id).unwrap().unwrap();
+        assert_eq!(stored.status, MessageStatus::Failed);
+        assert!(client.is_conversation_read_only("bob").unwrap());
+
+        // Nothing more is sent, and history stays
+        let sent = server.sent_messages().len();
+        assert!(matches!(client.send_message("bob", "hello?"), Err(Error::RecipientUnavailable)));
+        assert_eq!(server.sent_messages().len(), sent);
+        assert_eq!(client.get_messages("bob", 10, 0).unwrap().len(), 2);
+
+        // Without one, fetching the key tells
+        server.set_deactivated("carol", true);
+        assert!(matches!(client.send_message("carol", "hi"), Err(Error::RecipientUnavailable)));
+        assert!(client.is_conversation_read_only("carol").unwrap());
+
+        // Writing again means the account is back
+        server.set_deactivated("bob", false);
+        bob.establish_session("alice", &alice_key).unwrap();
+        server.push_incoming(MessageEnvelope {
+            message_id: "back".into(),
+            sender_id: "bob".into(),
+            recipient_id: "alice".into(),
+            recipient_device_id: None,
+            encrypted_content: bob.encrypt_for("alice", r#"{"text":"back"}"#).unwrap(),
+            message_type: "text".into(),
+            timestamp: 1,
+            ttl_seconds: None,
+            priority: MessagePriority::Normal,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        });
+        client.poll_messages().unwrap();
+        assert!(!client.is_conversation_read_only("bob").unwrap());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        use crate::diagnostics::CheckStatus;
+
+        let server = MockServer::new();
+        let client = server.client(&temp_dir()).unwrap();
+        let status = |report: &crate::DiagnosticsReport, name: &str| report.check(name).unwrap().status;
+
+        // No keys and no session yet
+        let report = client.run_diagnostics();
+        assert_eq!(status(&report, "server"), CheckStatus::Ok);
+        assert_eq!(status(&report, "websocket"), CheckStatus::Skipped);
+        assert_eq!(status(&report, "clock"), CheckStatus::Ok);
+        assert_eq!(status(&report, "turn"), CheckStatus::Skipped);
+        assert_eq!(status(&report, "database"), CheckStatus::Ok);
+        assert_eq!(status(&report, "keys"), CheckStatus::Failed);
+        assert!(!report.is_healthy());
+
+        client.init_keys(None).unwrap();
+        let session = client.login("alice", "key", "test").unwrap();
+        let turn = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        server.set_turn_credentials(TurnCredentials {
+            urls: vec![format!("turn:{}", turn.local_addr().unwrap())],
+            username: "user".into(),
+            credential: "secret".into(),
+        });
+        let report = client.run_diagnostics();
+        assert!(report.is_healthy(), "{}", report.to_text());
+        assert_eq!(status(&report, "websocket"), CheckStatus::Ok);
+        assert_eq!(status(&report, "turn"), CheckStatus::Ok);
+        assert_eq!(status(&report, "keys"), CheckStatus::Ok);
+
+        server.set_clock_skew(-600);
+        let report = client.run_diagnostics();
+        assert_eq!(status(&report, "clock"), CheckStatus::Failed);
+        assert!(report.check("clock").unwrap().detail.contains("ahead of the server"));
+        assert!(report.to_text().contains("[failed] clock"));
+        server.set_clock_skew(60);
+        assert_eq!(status(&client.run_diagnostics(), "clock"), CheckStatus::Warning);
+
+        // Safe to share
+        assert!(!report.to_json().contains(&session.token));
+        assert!(!report.to_text().contains(&client.export_private_key().unwrap()));
+    }
+}
@@ -0,0 +1,120 @@
+//! Certificate pinning for HTTP and WebSocket TLS
+//!
+//! reqwest 0.11 and tokio-tungstenite 0.21 are built on different rustls
+//! versions, so each gets its own verifier. Both accept a server certificate
+//! only if the SHA-256 of its DER encoding is in the pin set; the handshake
+//! signature is still checked against that certificate.
+
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+fn is_pinned(pins: &[String], cert_der: &[u8]) -> bool {
+    let fingerprint = hex::encode(Sha256::digest(cert_der));
+    pins.contains(&fingerprint)
+}
+
+// ============================================================================
+// HTTP (rustls 0.21)
+// ============================================================================
+
+struct HttpPinVerifier {
+    pins: Vec<String>,
+}
+
+impl rustls_021::client::ServerCertVerifier for HttpPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls_021::Certificate,
+        _intermediates: &[rustls_021::Certificate],
+        _server_name: &rustls_021::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls_021::client::ServerCertVerified, rustls_021::Error> {
+        if is_pinned(&self.pins, &end_entity.0) {
+            Ok(rustls_021::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls_021::Error::General("Certificate does not match any pin".into()))
+        }
+    }
+}
+
+pub(crate) fn http_client_config(pins: &[String], http2: bool) -> rustls_021::ClientConfig {
+    let mut config = rustls_021::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(HttpPinVerifier {
+            pins: pins.to_vec(),
+        }))
+        .with_no_client_auth();
+
+    // A preconfigured TLS config bypasses reqwest's own ALPN setup
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    config
+}
+
+// ============================================================================
+// WebSocket (rustls 0.22)
+// ============================================================================
+
+#[derive(Debug)]
+struct WsPinVerifier {
+    pins: Vec<String>,
+    algorithms: rustls::crypto::WebPkiSupportedAlgorithms,
+}
+
+impl rustls::client::danger::ServerCertVerifier for WsPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if is_pinned(&self.pins, end_entity.as_ref()) {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("Certificate does not match any pin".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+pub(crate) fn ws_connector(pins: &[String]) -> tokio_tungstenite::Connector {
+    let verifier = WsPinVerifier {
+        pins: pins.to_vec(),
+        algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+    };
+
+    let config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    tokio_tungstenite::Connector::Rustls(Arc::new(config))
+}
@@ -0,0 +1,219 @@
+//! Parallel, resumable attachment downloads
+//!
+//! Encrypted files are fetched in fixed-size ranges, several at a time, and
+//! written in place into a preallocated part file. Finished ranges are
+//! recorded in the transfers tables, so an interrupted download continues
+//! where it stopped, even after the app restarts. Running downloads check
+//! their `TransferSignal` between ranges to pause or cancel.
+
+use crate::error::{Error, Result};
+use crate::models::{Attachment, Transfer};
+use crate::storage::LocalStorage;
+use crate::transport::ApiTransport;
+use futures::{StreamExt, TryStreamExt};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+/// Bytes fetched per range request
+pub const DOWNLOAD_RANGE_SIZE: u64 = 1024 * 1024;
+
+/// Range requests in flight per download
+const MAX_PARALLEL_RANGES: usize = 4;
+
+// ============================================================================
+// Transfer Control
+// ============================================================================
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Shared flag a running download polls between ranges
+#[derive(Clone, Default)]
+pub(crate) struct TransferSignal(Arc<AtomicU8>);
+
+impl TransferSignal {
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.0.load(Ordering::SeqCst) {
+            RUNNING => Ok(()),
+            PAUSED => Err(Error::TransferPaused),
+            _ => Err(Error::TransferCancelled),
+        }
+    }
+}
+
+/// Signals of the downloads currently running, by transfer id
+#[derive(Default)]
+pub(crate) struct TransferControls {
+    running: Mutex<HashMap<String, TransferSignal>>,
+}
+
+impl TransferControls {
+    /// Register a download; fails if one for `id` is already running
+    pub(crate) fn begin(&self, id: &str) -> Result<TransferSignal> {
+        let mut running = self.running.lock();
+        if running.contains_key(id) {
+            return Err(Error::Storage(format!("Transfer already running: {}", id)));
+        }
+        let signal = TransferSignal::default();
+        running.insert(id.to_string(), signal.clone());
+        Ok(signal)
+    }
+
+    pub(crate) fn end(&self, id: &str) {
+        self.running.lock().remove(id);
+    }
+
+    /// Ask a running download to pause. Returns false if none is running.
+    pub(crate) fn pause(&self, id: &str) -> bool {
+        self.set(id, PAUSED)
+    }
+
+    /// Ask a running download to stop and discard its progress.
+    /// Returns false if none is running.
+    pub(crate) fn cancel(&self, id: &str) -> bool {
+        self.set(id, CANCELLED)
+    }
+
+    fn set(&self, id: &str, state: u8) -> bool {
+        match self.running.lock().get(id) {
+            Some(signal) => {
+                signal.0.store(state, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// ============================================================================
+// Download
+// ============================================================================
+
+/// Download an attachment into a part file under `temp_dir`, resuming an
+/// earlier attempt if there is one, and return the part file's path
+pub(crate) async fn download(
+    api: &dyn ApiTransport,
+    storage: &LocalStorage,
+    attachment: &Attachment,
+    dest: &Path,
+    temp_dir: &Path,
+    signal: &TransferSignal,
+) -> Result<PathBuf> {
+    signal.check()?;
+    let transfer = match storage.get_transfer(&attachment.file_id)? {
+        Some(transfer) if part_file_intact(&transfer).await => transfer,
+        _ => start(api, storage, attachment, dest, temp_dir).await?,
+    };
+
+    let remaining = (0..transfer.range_count()).filter(|i| !transfer.completed.contains(i));
+    futures::stream::iter(remaining)
+        .map(|index| fetch_range(api, storage, &transfer, index, signal))
+        .buffer_unordered(MAX_PARALLEL_RANGES)
+        .try_collect::<()>()
+        .await?;
+
+    Ok(PathBuf::from(transfer.part_path))
+}
+
+/// Hex SHA-256 of a file, read in blocks
+pub(crate) fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+async fn part_file_intact(transfer: &Transfer) -> bool {
+    match tokio::fs::metadata(&transfer.part_path).await {
+        Ok(meta) => meta.len() == transfer.total_size,
+        Err(_) => false,
+    }
+}
+
+/// Fetch the first range, which also tells us the file size, and record
+/// the new transfer
+async fn start(
+    api: &dyn ApiTransport,
+    storage: &LocalStorage,
+    attachment: &Attachment,
+    dest: &Path,
+    temp_dir: &Path,
+) -> Result<Transfer> {
+    let file_id = &attachment.file_id;
+    if let Some(stale) = storage.get_transfer(file_id)? {
+        let _ = tokio::fs::remove_file(&stale.part_path).await;
+    }
+
+    let first = api.download_range(file_id, 0, DOWNLOAD_RANGE_SIZE).await?;
+    let part_path = temp_dir.join(format!("{}.part", uuid::Uuid::new_v4()));
+
+    let mut file = tokio::fs::File::create(&part_path).await?;
+    file.set_len(first.total_size).await?;
+    file.write_all(&first.data).await?;
+    file.flush().await?;
+
+    let mut transfer = Transfer {
+        file_id: file_id.to_string(),
+        attachment: attachment.clone(),
+        dest_path: dest.to_string_lossy().into_owned(),
+        part_path: part_path.to_string_lossy().into_owned(),
+        total_size: first.total_size,
+        range_size: DOWNLOAD_RANGE_SIZE,
+        completed: Vec::new(),
+        paused: false,
+    };
+    check_range_len(&transfer, 0, first.data.len())?;
+
+    storage.start_transfer(&transfer)?;
+    storage.complete_transfer_range(file_id, 0)?;
+    transfer.completed.push(0);
+
+    Ok(transfer)
+}
+
+async fn fetch_range(
+    api: &dyn ApiTransport,
+    storage: &LocalStorage,
+    transfer: &Transfer,
+    index: u64,
+    signal: &TransferSignal,
+) -> Result<()> {
+    signal.check()?;
+    let offset = index * transfer.range_size;
+    let range = api
+        .download_range(&transfer.file_id, offset, transfer.range_size)
+        .await?;
+
+    if range.total_size != transfer.total_size {
+        return Err(Error::Network("File changed during download".into()));
+    }
+    check_range_len(transfer, index, range.data.len())?;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&transfer.part_path)
+        .await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(&range.data).await?;
+    file.flush().await?;
+
+    storage.complete_transfer_range(&transfer.file_id, index)
+}
+
+fn check_range_len(transfer: &Transfer, index: u64, len: usize) -> Result<()> {
+    let offset = index * transfer.range_size;
+    let expected = transfer.range_size.min(transfer.total_size.saturating_sub(offset));
+    if len as u64 != expected {
+        return Err(Error::Network(format!(
+            "Range {} returned {} bytes, expected {}",
+            index, len, expected
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,674 @@
+//! Network transport abstraction for PrivMsg
+//!
+//! `PrivMsgClient` only talks to the network through these traits, so other
+//! stacks (HTTP/3, Tor, in-memory) plug in via `PrivMsgClient::with_transport`
+//! without touching client logic. `ApiClient` and `WebSocketClient` are the
+//! default implementations; `LoopbackHub` connects clients in-process.
+
+use crate::error::{Error, Result, RevocationReason, ServerError};
+use crate::models::*;
+use crate::power::PowerSource;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::Arc;
+
+// ============================================================================
+// Traits
+// ============================================================================
+
+/// Part of a file returned by `ApiTransport::download_range`
+#[derive(Debug, Clone)]
+pub struct FileRange {
+    pub data: Vec<u8>,
+    /// Size of the whole file
+    pub total_size: u64,
+}
+
+/// A file stored by `ApiTransport::upload_file`
+#[derive(Debug, Clone)]
+pub struct UploadedFile {
+    pub file_id: String,
+    /// When the server drops the file (Unix seconds), if it said
+    pub expires_at: Option<i64>,
+}
+
+/// HTTP API used by `PrivMsgClient`
+#[async_trait]
+pub trait ApiTransport: Send + Sync {
+    /// A proof-of-work challenge to solve before logging in, or `None` if
+    /// the server doesn't ask for one
+    async fn login_challenge(&self) -> Result<Option<LoginChallenge>> {
+        Ok(None)
+    }
+
+    async fn login(
+        &self,
+        user_id: &str,
+        access_key: &str,
+        device_name: &str,
+        device_public_key: &str,
+        proof: Option<&LoginProof>,
+    ) -> Result<AuthSession>;
+
+    async fn get_user(&self, user_id: &str) -> Result<User>;
+
+    async fn upload_file(
+        &self,
+        data: Vec<u8>,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key_hash: &str,
+    ) -> Result<UploadedFile>;
+
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>>;
+
+    /// Upload a file from disk. The default reads it into memory; transports
+    /// that can stream request bodies should override this.
+    async fn upload_file_from_path(
+        &self,
+        path: &Path,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key_hash: &str,
+    ) -> Result<UploadedFile> {
+        let data = tokio::fs::read(path).await?;
+        self.upload_file(data, file_name, mime_type, encryption_key_hash)
+            .await
+    }
+
+    /// Download `len` bytes starting at `offset`. The default fetches the
+    /// whole file; transports that support HTTP ranges should override this.
+    async fn download_range(&self, file_id: &str, offset: u64, len: u64) -> Result<FileRange> {
+        let data = self.download_file(file_id).await?;
+        let total_size = data.len() as u64;
+        let start = offset.min(total_size) as usize;
+        let end = offset.saturating_add(len).min(total_size) as usize;
+
+        Ok(FileRange {
+            data: data[start..end].to_vec(),
+            total_size,
+        })
+    }
+
+    /// Download a file to disk. The default buffers it in memory first.
+    async fn download_file_to(&self, file_id: &str, path: &Path) -> Result<()> {
+        let data = self.download_file(file_id).await?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn get_turn_credentials(&self) -> Result<TurnCredentials>;
+
+    async fn check_health(&self) -> Result<bool>;
+
+    /// The server's clock in Unix seconds, for clock skew checks. `None`
+    /// if the transport can't tell.
+    async fn server_time(&self) -> Result<Option<i64>> {
+        Ok(None)
+    }
+
+    /// Change the logged-in user's profile and return it as updated
+    async fn update_profile(&self, _update: &ProfileUpdate) -> Result<User> {
+        Err(Error::Network("Profile updates are not supported by this transport".into()))
+    }
+
+    /// Keep an uploaded file at least `hours` longer and return its new
+    /// expiry in Unix seconds. The server caps how long a file may live.
+    async fn extend_file(&self, _file_id: &str, _hours: u64) -> Result<i64> {
+        Err(Error::Network("Extending files is not supported by this transport".into()))
+    }
+
+    /// Delete an uploaded file before it expires
+    async fn delete_file(&self, _file_id: &str) -> Result<()> {
+        Err(Error::Network("Deleting files is not supported by this transport".into()))
+    }
+
+    /// The operator's signed client policy, or `None` if the server has none
+    async fn get_client_policy(&self) -> Result<Option<SignedPolicy>> {
+        Ok(None)
+    }
+
+    /// The current user's signed-in devices
+    async fn list_devices(&self) -> Result<Vec<Device>> {
+        Ok(Vec::new())
+    }
+
+    /// Give one of the current user's devices a new display name
+    async fn rename_device(&self, _device_id: &str, _device_name: &str) -> Result<()> {
+        Err(Error::Network("Renaming devices is not supported by this transport".into()))
+    }
+
+    /// The signed-in devices of `user_id` with their keys. Empty when the
+    /// transport can't tell, and messages go to the user's key alone.
+    async fn get_device_keys(&self, _user_id: &str) -> Result<Vec<DeviceKey>> {
+        Ok(Vec::new())
+    }
+
+    /// Attach a certificate to one of the current user's devices, see
+    /// `PrivMsgClient::certify_device`
+    async fn certify_device(&self, _device_id: &str, _signature: &str) -> Result<()> {
+        Err(Error::Network("Certifying devices is not supported by this transport".into()))
+    }
+
+    /// Drop whatever the server still holds of a message the current user
+    /// sent, so recipients who haven't received it never will
+    async fn retract_message(&self, _message_id: &str) -> Result<()> {
+        Err(Error::Network("Retracting messages is not supported by this transport".into()))
+    }
+
+    /// End every session of the current user, on all their devices
+    async fn logout_all(&self) -> Result<()> {
+        Err(Error::Network("Logging out everywhere is not supported by this transport".into()))
+    }
+
+    /// Create a group of the current user and `member_ids`
+    async fn create_group(&self, _name: &str, _member_ids: &[String]) -> Result<Group> {
+        Err(Error::Network("Groups are not supported by this transport".into()))
+    }
+
+    /// The groups the current user is a member of
+    async fn list_groups(&self) -> Result<Vec<Group>> {
+        Ok(Vec::new())
+    }
+
+    /// A group the current user is a member of
+    async fn get_group(&self, _group_id: &str) -> Result<Group> {
+        Err(Error::Network("Groups are not supported by this transport".into()))
+    }
+
+    /// Add members to a group and return it as updated
+    async fn add_group_members(&self, _group_id: &str, _member_ids: &[String]) -> Result<Group> {
+        Err(Error::Network("Groups are not supported by this transport".into()))
+    }
+
+    /// Leave a group, or as its creator remove `user_id` from it
+    async fn remove_group_member(&self, _group_id: &str, _user_id: &str) -> Result<()> {
+        Err(Error::Network("Groups are not supported by this transport".into()))
+    }
+}
+
+/// An established real-time connection
+#[async_trait]
+pub trait WsTransport: Send + Sync {
+    async fn send_message(&self, envelope: &MessageEnvelope) -> Result<()>;
+
+    async fn send_typing(&self, recipient_id: &str, is_typing: bool) -> Result<()>;
+
+    /// Announce the user's presence to their contacts
+    async fn send_presence(&self, _status: PresenceStatus) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send_call_signal(&self, signal: &CallSignal) -> Result<()>;
+
+    async fn receive_messages(&self) -> Result<Vec<MessageEnvelope>>;
+
+    /// Call signals relayed from peers since the last call
+    async fn receive_call_signals(&self) -> Result<Vec<CallSignal>> {
+        Ok(Vec::new())
+    }
+
+    /// Frames the server rejected since the last call
+    async fn receive_errors(&self) -> Result<Vec<ServerError>> {
+        Ok(Vec::new())
+    }
+
+    /// Renames of the user's devices since the last call
+    async fn receive_device_renames(&self) -> Result<Vec<DeviceRenamed>> {
+        Ok(Vec::new())
+    }
+
+    /// Announcements the server set (`Some`) or cleared (`None`) since the
+    /// last call
+    async fn receive_announcements(&self) -> Result<Vec<Option<Announcement>>> {
+        Ok(Vec::new())
+    }
+
+    /// Maintenance mode turned on (`Some`) or off (`None`) since the last
+    /// call
+    async fn receive_maintenance(&self) -> Result<Vec<Option<Maintenance>>> {
+        Ok(Vec::new())
+    }
+
+    /// Ids the server acknowledged since the last call, each with when the
+    /// ack arrived in Unix millis
+    async fn receive_acks(&self) -> Result<Vec<(String, i64)>> {
+        Ok(Vec::new())
+    }
+
+    /// Receipts for sent messages that reached a recipient's device since
+    /// the last call
+    async fn receive_deliveries(&self) -> Result<Vec<DeliveryReceipt>> {
+        Ok(Vec::new())
+    }
+
+    /// Tell the server these messages are stored so it stops replaying them
+    async fn send_ack(&self, message_ids: &[String]) -> Result<()>;
+
+    fn is_connected(&self) -> bool;
+
+    /// Set once the server has revoked this session
+    fn revocation(&self) -> Option<RevocationReason> {
+        None
+    }
+
+    /// Adjust keepalive to the device's power source
+    fn set_power_source(&self, _source: PowerSource) {}
+
+    async fn disconnect(&self) -> Result<()>;
+}
+
+/// Opens real-time connections after login
+#[async_trait]
+pub trait WsConnector: Send + Sync {
+    async fn connect(&self, token: &str) -> Result<Box<dyn WsTransport>>;
+}
+
+// ============================================================================
+// Loopback
+// ============================================================================
+
+#[derive(Default)]
+struct HubState {
+    /// Registered users with their published keys
+    users: HashMap<String, User>,
+    /// Session token -> user_id
+    tokens: HashMap<String, String>,
+    inboxes: HashMap<String, VecDeque<MessageEnvelope>>,
+    signals: HashMap<String, VecDeque<CallSignal>>,
+    files: HashMap<String, Vec<u8>>,
+    next_id: u64,
+}
+
+/// In-memory relay that lets several clients exchange messages without a
+/// server. Every client built from the same hub shares users, files and
+/// message queues.
+#[derive(Clone, Default)]
+pub struct LoopbackHub {
+    state: Arc<Mutex<HubState>>,
+}
+
+impl LoopbackHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn api(&self) -> Arc<dyn ApiTransport> {
+        Arc::new(LoopbackApi {
+            hub: self.clone(),
+            user_id: Mutex::new(None),
+        })
+    }
+
+    pub fn connector(&self) -> Arc<dyn WsConnector> {
+        Arc::new(LoopbackConnector { hub: self.clone() })
+    }
+}
+
+struct LoopbackApi {
+    hub: LoopbackHub,
+    /// User logged in through this API
+    user_id: Mutex<Option<String>>,
+}
+
+#[async_trait]
+impl ApiTransport for LoopbackApi {
+    async fn login(
+        &self,
+        user_id: &str,
+        _access_key: &str,
+        _device_name: &str,
+        device_public_key: &str,
+        _proof: Option<&LoginProof>,
+    ) -> Result<AuthSession> {
+        let mut state = self.hub.state.lock();
+        state.next_id += 1;
+        let token = format!("loopback-{}", state.next_id);
+
+        state
+            .users
+            .entry(user_id.to_string())
+            .or_insert_with(|| User {
+                user_id: user_id.to_string(),
+                display_name: None,
+                avatar_file_id: None,
+                public_key: None,
+                last_seen_at: None,
+                status_emoji: None,
+                status_text: None,
+                bio: None,
+                last_seen_visibility: None,
+            })
+            .public_key = Some(device_public_key.to_string());
+        state.tokens.insert(token.clone(), user_id.to_string());
+        *self.user_id.lock() = Some(user_id.to_string());
+
+        Ok(AuthSession {
+            device_id: token.clone(),
+            token,
+            user_id: user_id.to_string(),
+            expires_at: i64::MAX,
+            announcement: None,
+        })
+    }
+
+    async fn get_user(&self, user_id: &str) -> Result<User> {
+        self.hub
+            .state
+            .lock()
+            .users
+            .get(user_id)
+            .cloned()
+            .ok_or_else(|| Error::UserNotFound(user_id.to_string()))
+    }
+
+    async fn upload_file(
+        &self,
+        data: Vec<u8>,
+        _file_name: &str,
+        _mime_type: &str,
+        _encryption_key_hash: &str,
+    ) -> Result<UploadedFile> {
+        let mut state = self.hub.state.lock();
+        state.next_id += 1;
+        let file_id = format!("file-{}", state.next_id);
+        state.files.insert(file_id.clone(), data);
+        Ok(UploadedFile { file_id, expires_at: None })
+    }
+
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        self.hub
+            .state
+            .lock()
+            .files
+            .get(file_id)
+            .cloned()
+            .ok_or_else(|| Error::Http("404 Not Found".into()))
+    }
+
+    async fn get_turn_credentials(&self) -> Result<TurnCredentials> {
+        Err(Error::Network("No TURN server on loopback".into()))
+    }
+
+    async fn check_health(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn server_time(&self) -> Result<Option<i64>> {
+        Ok(Some(chrono::Utc::now().timestamp()))
+    }
+
+    async fn update_profile(&self, update: &ProfileUpdate) -> Result<User> {
+        let user_id = self.user_id.lock().clone().ok_or(Error::NotLoggedIn)?;
+        let mut state = self.hub.state.lock();
+        let user = state
+            .users
+            .get_mut(&user_id)
+            .ok_or_else(|| Error::UserNotFound(user_id.clone()))?;
+
+        let set = |field: &mut Option<String>, value: &Option<String>| {
+            if let Some(value) = value {
+                *field = Some(value.clone()).filter(|v| !v.is_empty());
+            }
+        };
+        set(&mut user.display_name, &update.display_name);
+        set(&mut user.avatar_file_id, &update.avatar_file_id);
+        set(&mut user.status_emoji, &update.status_emoji);
+        set(&mut user.status_text, &update.status_text);
+        set(&mut user.bio, &update.bio);
+        if let Some(visibility) = update.last_seen_visibility {
+            user.last_seen_visibility = Some(visibility);
+        }
+        Ok(user.clone())
+    }
+}
+
+struct LoopbackConnector {
+    hub: LoopbackHub,
+}
+
+#[async_trait]
+impl WsConnector for LoopbackConnector {
+    async fn connect(&self, token: &str) -> Result<Box<dyn WsTransport>> {
+        let user_id = self
+            .hub
+            .state
+            .lock()
+            .tokens
+            .get(token)
+            .cloned()
+            .ok_or_else(|| Error::WebSocket("Invalid token".into()))?;
+
+        Ok(Box::new(LoopbackWs {
+            hub: self.hub.clone(),
+            user_id,
+            connected: Mutex::new(true),
+        }))
+    }
+}
+
+struct LoopbackWs {
+    hub: LoopbackHub,
+    user_id: String,
+    connected: Mutex<bool>,
+}
+
+#[async_trait]
+impl WsTransport for LoopbackWs {
+    async fn send_message(&self, envelope: &MessageEnvelope) -> Result<()> {
+        if !self.is_connected() {
+            return Err(Error::WebSocket("Not connected".into()));
+        }
+
+        // Stamp the sender like the server does
+        let mut envelope = envelope.clone();
+        envelope.sender_id = self.user_id.clone();
+
+        self.hub
+            .state
+            .lock()
+            .inboxes
+            .entry(envelope.recipient_id.clone())
+            .or_default()
+            .push_back(envelope);
+        Ok(())
+    }
+
+    async fn send_typing(&self, _recipient_id: &str, _is_typing: bool) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send_call_signal(&self, signal: &CallSignal) -> Result<()> {
+        if !self.is_connected() {
+            return Err(Error::WebSocket("Not connected".into()));
+        }
+
+        let mut signal = signal.clone();
+        signal.sender_id = self.user_id.clone();
+
+        self.hub
+            .state
+            .lock()
+            .signals
+            .entry(signal.recipient_id.clone())
+            .or_default()
+            .push_back(signal);
+        Ok(())
+    }
+
+    async fn receive_messages(&self) -> Result<Vec<MessageEnvelope>> {
+        if !self.is_connected() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .hub
+            .state
+            .lock()
+            .inboxes
+            .get_mut(&self.user_id)
+            .map(|inbox| inbox.drain(..).collect())
+            .unwrap_or_default())
+    }
+
+    async fn receive_call_signals(&self) -> Result<Vec<CallSignal>> {
+        if !self.is_connected() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .hub
+            .state
+            .lock()
+            .signals
+            .get_mut(&self.user_id)
+            .map(|signals| signals.drain(..).collect())
+            .unwrap_or_default())
+    }
+
+    async fn send_ack(&self, _message_ids: &[String]) -> Result<()> {
+        // Inboxes are drained on receive, so there is nothing to replay
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        *self.connected.lock()
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        *self.connected.lock() = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PrivMsgClient;
+
+    fn client(hub: &LoopbackHub, user_id: &str) -> PrivMsgClient {
+        let dir = std::env::temp_dir().join(format!("privmsg-loopback-{}", uuid::Uuid::new_v4()));
+        let client =
+            PrivMsgClient::with_transport(&dir.to_string_lossy(), hub.api(), hub.connector())
+                .unwrap();
+        client.init_keys(None).unwrap();
+        client.login(user_id, "key", "test").unwrap();
+        client
+    }
+
+    #[test]
+    fn test_loopback_round_trip() {
+        let hub = LoopbackHub::new();
+        let alice = client(&hub, "alice");
+        let bob = client(&hub, "bob");
+
+        alice.send_message("bob", "hello bob").unwrap();
+
+        let received = bob.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].sender_id, "alice");
+        assert_eq!(received[0].content, "hello bob");
+        assert!(alice.poll_messages().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_profile_status_and_bio() {
+        let hub = LoopbackHub::new();
+        let alice = client(&hub, "alice");
+        let bob = client(&hub, "bob");
+
+        let me = alice.set_status(Some("🌴"), Some("On holiday")).unwrap();
+        assert_eq!(me.status_emoji.as_deref(), Some("🌴"));
+        alice.set_bio("Writes Rust").unwrap();
+
+        let seen = bob.get_user_profile("alice").unwrap();
+        assert_eq!(seen.status_text.as_deref(), Some("On holiday"));
+        assert_eq!(seen.bio.as_deref(), Some("Writes Rust"));
+
+        // Clearing the status keeps the bio
+        alice.set_status(None, None).unwrap();
+        let seen = bob.get_user_profile("alice").unwrap();
+        assert_eq!((seen.status_emoji, seen.status_text), (None, None));
+        assert_eq!(seen.bio.as_deref(), Some("Writes Rust"));
+    }
+
+    fn write_file(len: usize) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("privmsg-direct-{}.bin", uuid::Uuid::new_v4()));
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn save_received(bob: &PrivMsgClient) -> Vec<u8> {
+        let received = bob.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        let dest = std::env::temp_dir().join(format!("privmsg-saved-{}", uuid::Uuid::new_v4()));
+        bob.download_attachment_to(received[0].attachment.as_ref().unwrap(), &dest).unwrap();
+        let data = std::fs::read(&dest).unwrap();
+        let _ = std::fs::remove_file(&dest);
+        data
+    }
+
+    #[test]
+    fn test_direct_file_transfer() {
+        let hub = LoopbackHub::new();
+        let alice = client(&hub, "alice");
+        let bob = client(&hub, "bob");
+        alice.set_direct_transfers(Some(0));
+        bob.set_direct_transfers(Some(0));
+
+        let path = write_file(300 * 1024);
+        let sent = alice.send_file_from_path("bob", &path, "application/octet-stream", None).unwrap();
+
+        let attachment = sent.attachment.unwrap();
+        assert!(attachment.file_id.starts_with("p2p:"));
+        assert!(hub.state.lock().files.is_empty());
+        assert_eq!(save_received(&bob), std::fs::read(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_declined_direct_transfer_falls_back_to_relay() {
+        let hub = LoopbackHub::new();
+        let alice = client(&hub, "alice");
+        let bob = client(&hub, "bob");
+        alice.set_direct_transfers(Some(0));
+
+        let path = write_file(64 * 1024);
+        let sent = alice.send_file_from_path("bob", &path, "application/octet-stream", None).unwrap();
+
+        assert!(!sent.attachment.unwrap().file_id.starts_with("p2p:"));
+        assert_eq!(hub.state.lock().files.len(), 1);
+        assert_eq!(save_received(&bob), std::fs::read(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_lan_delivery() {
+        let hub = LoopbackHub::new();
+        let alice = client(&hub, "alice");
+        let bob = client(&hub, "bob");
+        // Only peers with a session are accepted, so start through the server
+        alice.send_message("bob", "hello").unwrap();
+        assert_eq!(bob.poll_messages().unwrap().len(), 1);
+        alice.enable_lan_delivery().unwrap();
+        bob.enable_lan_delivery().unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !alice.lan_peers().iter().any(|peer| peer.user_id == "bob") {
+            assert!(std::time::Instant::now() < deadline, "bob was not discovered");
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+
+        alice.send_message("bob", "over the LAN").unwrap();
+
+        // Nothing went through the relay
+        assert!(hub.state.lock().inboxes.values().all(|inbox| inbox.is_empty()));
+        let received = bob.poll_messages().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].sender_id, "alice");
+        assert_eq!(received[0].content, "over the LAN");
+    }
+}
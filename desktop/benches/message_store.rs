@@ -0,0 +1,92 @@
+//! Chat state benchmarks: indexed `MessageStore` vs the plain `Vec` it replaced
+//!
+//! Run with `cargo bench --bench message_store`.
+
+#[path = "../src/message_store.rs"]
+#[allow(dead_code)]
+mod message_store;
+
+use message_store::{MessageStore, StoredMessage};
+use std::hint::black_box;
+use std::time::Instant;
+
+#[derive(Clone)]
+struct Msg {
+    id: String,
+    timestamp: i64,
+    status: u8,
+}
+
+impl StoredMessage for Msg {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+fn messages(count: usize) -> Vec<Msg> {
+    (0..count)
+        .map(|i| Msg {
+            id: format!("message-{}", i),
+            timestamp: i as i64,
+            status: 0,
+        })
+        .collect()
+}
+
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iter = start.elapsed() / iterations;
+    println!("{:<44} {:>12?}", name, per_iter);
+}
+
+fn main() {
+    for count in [1_000, 10_000, 50_000] {
+        let history = messages(count);
+        let target = format!("message-{}", count / 2);
+        println!("-- {} messages", count);
+
+        // The old update path cloned the list on every received message
+        let vec = history.clone();
+        bench("vec: clone + push", 50, || {
+            let mut copy = vec.clone();
+            copy.push(Msg {
+                id: "new".into(),
+                timestamp: count as i64,
+                status: 0,
+            });
+            black_box(copy);
+        });
+
+        let mut store = MessageStore::new();
+        store.replace_all(history.clone());
+        bench("store: append", 10_000, || {
+            store.append(Msg {
+                id: "new".into(),
+                timestamp: count as i64,
+                status: 0,
+            });
+        });
+
+        let mut vec = history.clone();
+        bench("vec: find + patch status", 1_000, || {
+            if let Some(m) = vec.iter_mut().find(|m| m.id == target) {
+                m.status = black_box(1);
+            }
+        });
+
+        bench("store: patch status", 100_000, || {
+            store.patch(&target, |m| m.status = black_box(1));
+        });
+
+        bench("store: tail(100) for rendering", 10_000, || {
+            black_box(store.tail(100).map(|m| m.timestamp).sum::<i64>());
+        });
+    }
+}
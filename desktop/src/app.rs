@@ -1,26 +1,74 @@
 //! Main application module for PrivMsg Desktop
 
+use crate::applock;
+use crate::audio::{VoicePlayer, VoiceRecorder};
+use crate::backup;
+use crate::clipboard::{self, ClipboardClear};
 use crate::config::AppConfig;
+use crate::data_export::ExportProgress;
 use crate::database::Database;
+use crate::deeplink::DeepLink;
+use crate::instance::Instance;
 use crate::messages::Message;
-use crate::network::NetworkClient;
+use crate::power::{self, PowerSource};
+use crate::network::{download_part_path, KeyConflict, NetworkClient, PeerUnavailable, TransferControl, TransferOutcome};
+use crate::notifications::{self, DesktopSink};
 use crate::screens::{
-    call::CallScreen, chat::ChatScreen, home::HomeScreen, login::LoginScreen,
-    settings::SettingsScreen,
+    call::CallScreen, chat::ChatScreen, diagnostics::DiagnosticsScreen, home::HomeScreen,
+    lock::LockScreen, login::LoginScreen, rules::RulesScreen, settings::SettingsScreen,
+};
+use crate::state::{
+    AppState, Attachment, AttachmentUpload, ChatMessage, Conversation, FileDownload, MessageStatus,
+    MessageType, PanicConfirm, PhotoCapture, RuleDraft, Screen, StagedAttachment, SystemEvent,
+    VoicePlayback,
 };
-use crate::state::{AppState, Screen};
 use crate::theme::Theme;
+use crate::wallpaper::Wallpaper;
 
 use iced::widget::{column, container, row, text};
 use iced::{executor, Application, Command, Element, Length, Subscription};
+use privmsg_proto::announcement::{Announcement, AnnouncementSeverity};
+use privmsg_proto::emoji;
+use privmsg_proto::maintenance::Maintenance;
+use privmsg_proto::notification::{Notification, NotificationSink, PreviewPolicy};
+use privmsg_proto::policy::{Feature, PolicySetting};
+use privmsg_proto::{ErrorCode, LastSeenVisibility};
+use privmsg_proto::rules::{matching_actions, RuleAction};
+use privmsg_proto::stats::ConversationStats;
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+/// Delay before the first automatic resend; doubles with each retry
+const SEND_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Conversation rows read per query when loading the list
+const CONVERSATION_PAGE: usize = 500;
+
+/// Age in seconds at which the background job refetches a peer's profile
+const PROFILE_REFRESH_INTERVAL: i64 = 6 * 60 * 60;
+
+/// A sender's profile is refetched on receipt at most this often, in seconds
+const PROFILE_RECEIPT_REFRESH: i64 = 10 * 60;
+
+/// Profiles fetched per refresh tick
+const PROFILE_REFRESH_BATCH: usize = 50;
+
+/// Settings key holding the id of the last announcement the user dismissed
+const DISMISSED_ANNOUNCEMENT_SETTING: &str = "dismissed_announcement";
+
+/// Under the data directory; imported wallpaper images
+const WALLPAPER_DIR: &str = "wallpapers";
+
 #[derive(Default)]
 pub struct Flags {
     pub data_dir: PathBuf,
     pub config: AppConfig,
+    /// Held for the lifetime of the app; receives later launches
+    pub instance: Option<Arc<Instance>>,
+    /// Links this process was started with
+    pub links: Vec<DeepLink>,
 }
 
 pub struct PrivMsg {
@@ -28,6 +76,12 @@ pub struct PrivMsg {
     db: Arc<Database>,
     network: Arc<RwLock<Option<NetworkClient>>>,
     theme: Theme,
+    recorder: Option<VoiceRecorder>,
+    voice_player: Option<VoicePlayer>,
+    /// Decrypted audio of the voice message being played, by message_id
+    voice_audio: Option<(String, Vec<u8>)>,
+    instance: Option<Arc<Instance>>,
+    notifier: Box<dyn NotificationSink>,
 }
 
 impl Application for PrivMsg {
@@ -52,28 +106,40 @@ impl Application for PrivMsg {
             Screen::Login
         };
 
-        let theme = if flags.config.ui.theme == "dark" {
-            Theme::dark()
-        } else {
-            Theme::light()
-        };
+        let theme = Theme::named(&flags.config.ui.theme);
 
-        let state = AppState::new(flags.data_dir, flags.config, initial_screen);
+        let mut state = AppState::new(flags.data_dir, flags.config, initial_screen);
+        state.message_rules = db.get_message_rules().unwrap_or_default();
+        state.conversation_wallpapers = db.get_conversation_wallpapers().unwrap_or_default();
+        state.unavailable_peers = db.get_unavailable_peers().unwrap_or_default();
+        state.key_conflicts = db.get_key_conflicts().unwrap_or_default();
+        state.client_policy = crate::policy::load(&db);
 
         let app = Self {
             state,
             db,
             network: Arc::new(RwLock::new(None)),
             theme,
+            recorder: None,
+            voice_player: None,
+            voice_audio: None,
+            instance: flags.instance,
+            notifier: Box::new(DesktopSink),
         };
 
-        let command = if has_session && has_server {
-            Command::perform(async {}, |_| Message::TryRestoreSession)
-        } else {
-            Command::none()
-        };
+        let mut commands = Vec::new();
+        if has_session && has_server {
+            commands.push(Command::perform(async {}, |_| Message::TryRestoreSession));
+        }
+        if app.state.config.backup.enabled {
+            commands.push(Command::perform(async {}, |_| Message::BackupTick));
+        }
+        // Not signed in yet, so these are held until LoginSuccess
+        for link in flags.links {
+            commands.push(Command::perform(async {}, move |_| Message::DeepLinkOpened(link)));
+        }
 
-        (app, command)
+        (app, Command::batch(commands))
     }
 
     fn title(&self) -> String {
@@ -95,6 +161,8 @@ impl Application for PrivMsg {
                 }
             }
             Screen::Settings => "PrivMsg - Settings".to_string(),
+            Screen::Diagnostics => "PrivMsg - Diagnostics".to_string(),
+            Screen::Rules => "PrivMsg - Message Rules".to_string(),
             Screen::Call(_) => "PrivMsg - Call".to_string(),
         }
     }
@@ -109,6 +177,7 @@ impl Application for PrivMsg {
 
             Message::GoBack => {
                 self.state.current_screen = match &self.state.current_screen {
+                    Screen::Diagnostics | Screen::Rules => Screen::Settings,
                     Screen::Chat(_) | Screen::Settings | Screen::Call(_) => Screen::Home,
                     _ => Screen::Login,
                 };
@@ -133,6 +202,11 @@ impl Application for PrivMsg {
                 Command::none()
             }
 
+            Message::AllowInsecureLocalhostChanged(allow) => {
+                self.state.config.security.allow_insecure_localhost = allow;
+                Command::none()
+            }
+
             Message::UserIdChanged(user_id) => {
                 self.state.login_user_id = user_id;
                 Command::none()
@@ -160,7 +234,7 @@ impl Application for PrivMsg {
                         config.save(&data_dir).ok();
 
                         // Create network client
-                        let client = NetworkClient::new(&config).await?;
+                        let client = NetworkClient::new(&config, db.clone()).await?;
 
                         // Login
                         let session = client.login(&user_id, &access_key, "Desktop").await?;
@@ -182,12 +256,48 @@ impl Application for PrivMsg {
 
             Message::LoginSuccess(session) => {
                 self.state.is_loading = false;
+                self.show_announcement(session.announcement.clone());
                 self.state.session = Some(session);
                 self.state.current_screen = Screen::Home;
                 self.state.login_access_key.clear();
+                match self.db.get_profiles() {
+                    Ok(profiles) => {
+                        self.state.profiles = profiles
+                            .into_iter()
+                            .map(|user| (user.user_id.clone(), user))
+                            .collect();
+                    }
+                    Err(e) => tracing::warn!("Failed to load cached profiles: {}", e),
+                }
 
                 // Load conversations
-                Command::perform(async {}, |_| Message::LoadConversations)
+                let network = self.network.clone();
+                let capabilities_network = self.network.clone();
+                let mut commands = vec![
+                    Command::perform(async {}, |_| Message::LoadConversations),
+                    Command::perform(
+                        async move {
+                            match *network.read().await {
+                                Some(ref client) => client.get_client_policy().await,
+                                None => Err(anyhow::anyhow!("Not connected")),
+                            }
+                        },
+                        |result| Message::ClientPolicyFetched(result.map_err(|e| e.to_string())),
+                    ),
+                    Command::perform(
+                        async move {
+                            match *capabilities_network.read().await {
+                                Some(ref client) => client.get_capabilities().await,
+                                None => Err(anyhow::anyhow!("Not connected")),
+                            }
+                        },
+                        |result| Message::CapabilitiesFetched(result.map_err(|e| e.to_string())),
+                    ),
+                ];
+                if let Some(link) = self.state.pending_link.take() {
+                    commands.push(self.update(Message::DeepLinkOpened(link)));
+                }
+                Command::batch(commands)
             }
 
             Message::LoginError(error) => {
@@ -200,10 +310,11 @@ impl Application for PrivMsg {
                 if let Some(session) = self.db.get_session() {
                     let config = self.state.config.clone();
                     let network = self.network.clone();
+                    let db = self.db.clone();
 
                     return Command::perform(
                         async move {
-                            let client = NetworkClient::new(&config).await?;
+                            let client = NetworkClient::new(&config, db).await?;
                             if client.validate_token(&session.token).await? {
                                 *network.write().await = Some(client);
                                 Ok(session)
@@ -220,39 +331,268 @@ impl Application for PrivMsg {
                 Command::none()
             }
 
+            // ============= Links =============
+            Message::Activated(activation) => {
+                let window = iced::window::Id::MAIN;
+                let mut commands = vec![
+                    iced::window::minimize(window, false),
+                    iced::window::gain_focus(window),
+                ];
+                for link in activation.links {
+                    commands.push(self.update(Message::DeepLinkOpened(link)));
+                }
+                Command::batch(commands)
+            }
+
+            Message::DeepLinkOpened(link) => {
+                tracing::info!("Opening link: {:?}", link);
+                let link = match link {
+                    DeepLink::Invite(invite) => {
+                        let server = &mut self.state.config.server;
+                        if self.state.session.is_none() {
+                            server.host = invite.host;
+                            server.port = invite.port;
+                            server.use_tls = invite.use_tls;
+                        } else if server.host != invite.host || server.port != invite.port {
+                            self.state.error =
+                                Some(format!("This invite is for {}:{}", invite.host, invite.port));
+                            return Command::none();
+                        }
+                        DeepLink::Chat(invite.user_id)
+                    }
+                    other => other,
+                };
+
+                if self.state.session.is_none() {
+                    self.state.pending_link = Some(link);
+                    return Command::none();
+                }
+
+                match link {
+                    DeepLink::Chat(user_id) => self.update(Message::StartChatWithUser(user_id)),
+                    DeepLink::Call(user_id) => self.update(Message::StartCall(user_id, false)),
+                    DeepLink::Invite(_) => Command::none(),
+                }
+            }
+
             // ============= Conversations =============
             Message::LoadConversations => {
+                self.state.conversation_load += 1;
+                self.load_conversation_page(0)
+            }
+
+            Message::ConversationPageLoaded(load, offset, conversations) => {
+                if load != self.state.conversation_load {
+                    return Command::none();
+                }
+                let more = conversations.len() == CONVERSATION_PAGE;
+                if offset == 0 {
+                    self.state.conversations = conversations;
+                } else {
+                    // Rows placed since the load started are newer than the page's
+                    let known: std::collections::HashSet<String> =
+                        self.state.conversations.iter().map(|c| c.peer_id.clone()).collect();
+                    self.state
+                        .conversations
+                        .extend(conversations.into_iter().filter(|c| !known.contains(&c.peer_id)));
+                }
+                if more {
+                    self.load_conversation_page(offset + CONVERSATION_PAGE)
+                } else {
+                    Command::none()
+                }
+            }
+
+            Message::ConversationsChanged(conversations) => {
+                for conv in conversations {
+                    self.state.place_conversation(conv);
+                }
+                Command::none()
+            }
+
+            Message::ConversationsRemoved(peer_ids) => {
+                self.state.conversations.retain(|c| !peer_ids.contains(&c.peer_id));
+                Command::none()
+            }
+
+            Message::ConversationListScrolled(viewport) => {
+                self.state.conversation_viewport = viewport;
+                Command::none()
+            }
+
+            Message::ToggleSelectionMode => {
+                self.state.selecting_conversations = !self.state.selecting_conversations;
+                self.state.selected_conversations.clear();
+                Command::none()
+            }
+
+            Message::ToggleConversationSelected(peer_id) => {
+                if !self.state.selected_conversations.remove(&peer_id) {
+                    self.state.selected_conversations.insert(peer_id);
+                }
+                Command::none()
+            }
+
+            Message::SelectAllConversations => {
+                let selected = &mut self.state.selected_conversations;
+                if selected.len() == self.state.conversations.len() {
+                    selected.clear();
+                } else {
+                    selected.extend(self.state.conversations.iter().map(|c| c.peer_id.clone()));
+                }
+                Command::none()
+            }
+
+            Message::MarkSelectedRead => {
+                let peer_ids = self.take_selection();
                 let db = self.db.clone();
                 Command::perform(
-                    async move { db.get_conversations() },
+                    async move {
+                        db.mark_conversations_read(&peer_ids)?;
+                        db.get_conversations_by_peer(&peer_ids)
+                    },
                     |result| match result {
-                        Ok(convs) => Message::ConversationsLoaded(convs),
+                        Ok(conversations) => Message::ConversationsChanged(conversations),
                         Err(e) => Message::Error(e.to_string()),
                     },
                 )
             }
 
-            Message::ConversationsLoaded(conversations) => {
-                self.state.conversations = conversations;
-                Command::none()
+            Message::MuteSelected(muted) => {
+                let peer_ids = self.take_selection();
+                let db = self.db.clone();
+                Command::perform(
+                    async move {
+                        db.set_conversations_muted(&peer_ids, muted)?;
+                        db.get_conversations_by_peer(&peer_ids)
+                    },
+                    |result| match result {
+                        Ok(conversations) => Message::ConversationsChanged(conversations),
+                        Err(e) => Message::Error(e.to_string()),
+                    },
+                )
+            }
+
+            Message::ArchiveSelected(archived) => {
+                let peer_ids = self.take_selection();
+                let db = self.db.clone();
+                Command::perform(
+                    async move {
+                        db.set_conversations_archived(&peer_ids, archived)?;
+                        db.get_conversations_by_peer(&peer_ids)
+                    },
+                    |result| match result {
+                        Ok(conversations) => Message::ConversationsChanged(conversations),
+                        Err(e) => Message::Error(e.to_string()),
+                    },
+                )
+            }
+
+            Message::DeleteSelected => {
+                let peer_ids = self.take_selection();
+                if let Some(ref open) = self.state.current_chat_peer {
+                    if peer_ids.contains(open) {
+                        self.state.current_chat_peer = None;
+                        self.state.current_messages.clear();
+                    }
+                }
+                let db = self.db.clone();
+                Command::perform(
+                    async move { db.delete_conversations(&peer_ids).map(|()| peer_ids) },
+                    |result| match result {
+                        Ok(peer_ids) => Message::ConversationsRemoved(peer_ids),
+                        Err(e) => Message::Error(e.to_string()),
+                    },
+                )
+            }
+
+            Message::ExportSelected => {
+                let peer_ids = self.take_selection();
+                if peer_ids.is_empty() {
+                    return Command::none();
+                }
+                Command::perform(
+                    async {
+                        rfd::AsyncFileDialog::new()
+                            .set_title("Export chats")
+                            .set_file_name("privmsg-chats.json")
+                            .save_file()
+                            .await
+                            .map(|f| f.path().to_path_buf())
+                    },
+                    move |path| match path {
+                        Some(path) => Message::ExportSelectedTo(peer_ids.clone(), path),
+                        None => Message::Noop,
+                    },
+                )
+            }
+
+            Message::ExportSelectedTo(peer_ids, path) => {
+                let db = self.db.clone();
+                Command::perform(
+                    async move {
+                        let exports = db.export_conversations(&peer_ids)?;
+                        let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+                        serde_json::to_writer_pretty(file, &exports)?;
+                        anyhow::Ok(())
+                    },
+                    |result| match result {
+                        Ok(()) => Message::Noop,
+                        Err(e) => Message::Error(format!("Export failed: {}", e)),
+                    },
+                )
             }
 
             Message::OpenChat(peer_id) => {
+                // Staged files belong to the composer of the chat they were picked in
+                if self.state.attachment_upload.is_none()
+                    && self.state.current_chat_peer.as_deref() != Some(peer_id.as_str())
+                {
+                    self.state.staged_files.clear();
+                    self.state.attachment_caption.clear();
+                }
+                if self
+                    .state
+                    .photo_capture
+                    .as_ref()
+                    .is_some_and(|c| !c.sending && c.peer_id != peer_id)
+                {
+                    self.state.photo_capture = None;
+                }
+
+                if self.state.current_chat_peer.as_deref() != Some(peer_id.as_str()) {
+                    self.state.reply_quote = None;
+                    self.state.selecting_messages = false;
+                    self.state.selected_messages.clear();
+                }
+
                 self.state.current_screen = Screen::Chat(peer_id.clone());
                 self.state.current_chat_peer = Some(peer_id.clone());
+                self.state.message_info = None;
+                self.refresh_conversation_stats();
 
                 let db = self.db.clone();
-                Command::perform(
-                    async move { db.get_messages(&peer_id, 50, 0) },
+                let load = Command::perform(
+                    {
+                        let peer_id = peer_id.clone();
+                        async move { db.get_messages(&peer_id, 50, 0) }
+                    },
                     |result| match result {
                         Ok(msgs) => Message::MessagesLoaded(msgs),
                         Err(e) => Message::Error(e.to_string()),
                     },
-                )
+                );
+                Command::batch([load, self.refresh_profile(peer_id)])
             }
 
             Message::MessagesLoaded(messages) => {
-                self.state.current_messages = messages;
+                self.state.current_messages.replace_all(messages);
+                self.state.chat_window = crate::state::CHAT_PAGE_SIZE;
+                Command::none()
+            }
+
+            Message::ShowEarlierMessages => {
+                self.state.chat_window += crate::state::CHAT_PAGE_SIZE;
                 Command::none()
             }
 
@@ -266,142 +606,566 @@ impl Application for PrivMsg {
                 if self.state.message_input.trim().is_empty() {
                     return Command::none();
                 }
+                if self
+                    .state
+                    .current_chat_peer
+                    .as_ref()
+                    .is_some_and(|peer_id| self.state.is_read_only(peer_id))
+                {
+                    return Command::none();
+                }
 
-                let text = self.state.message_input.clone();
+                let (Some(peer_id), Some(session)) =
+                    (self.state.current_chat_peer.as_ref(), self.state.session.as_ref())
+                else {
+                    return Command::none();
+                };
+                let text = emoji::replace_shortcodes(&self.state.message_input);
+                let mut msg = ChatMessage::outgoing_text(peer_id, &session.user_id, &text);
+                msg.sender_device_id = Some(session.device_id.clone());
+                msg.quote = self.state.reply_quote.take();
                 self.state.message_input.clear();
 
-                if let Some(ref peer_id) = self.state.current_chat_peer {
-                    let peer_id = peer_id.clone();
-                    let network = self.network.clone();
-                    let db = self.db.clone();
-                    let session = self.state.session.clone();
-
-                    return Command::perform(
-                        async move {
-                            if session.is_some() {
-                                if let Some(ref client) = *network.read().await {
-                                    let msg = client.send_text_message(&peer_id, &text).await?;
-                                    db.save_message(&msg)?;
-                                    return Ok(msg);
-                                }
-                            }
-                            Err(anyhow::anyhow!("Not connected"))
-                        },
-                        |result| match result {
-                            Ok(msg) => Message::MessageSent(msg),
-                            Err(e) => Message::Error(e.to_string()),
-                        },
-                    );
-                }
-                Command::none()
+                // Shown as pending until delivery settles
+                self.state.current_messages.append(msg.clone());
+                self.deliver_message(msg)
             }
 
             Message::MessageSent(msg) => {
-                self.state.current_messages.push(msg);
+                // Forwarded messages may belong to another chat
+                if self.state.current_chat_peer.as_deref() == Some(msg.conversation_id.as_str()) {
+                    self.state.current_messages.append(msg);
+                }
                 Command::none()
             }
 
-            Message::MessageReceived(msg) => {
-                // Check if this message belongs to current chat
-                if let Some(ref peer_id) = self.state.current_chat_peer {
-                    if msg.conversation_id == *peer_id {
-                        self.state.current_messages.push(msg.clone());
-                    }
-                }
-
-                // Update conversation
-                if let Some(conv) = self
-                    .state
-                    .conversations
-                    .iter_mut()
-                    .find(|c| c.peer_id == msg.conversation_id)
-                {
-                    conv.last_message = Some(msg.content.clone());
-                    conv.last_message_time = Some(msg.timestamp);
-                    if !msg.is_outgoing {
-                        conv.unread_count += 1;
-                    }
+            Message::MessageFailed(msg, error) => {
+                tracing::warn!("Message {} not sent: {}", msg.message_id, error);
+                self.state.error = Some(format!("Message not sent: {}", error));
+                if self.state.current_chat_peer.as_deref() == Some(msg.conversation_id.as_str()) {
+                    self.state.current_messages.append(msg);
                 }
+                Command::none()
+            }
 
-                // Show notification
-                if self.state.config.notifications.enabled && !msg.is_outgoing {
-                    self.show_notification(&msg);
+            Message::RecipientUnavailable(msg) => {
+                self.set_peer_available(&msg.conversation_id, false);
+                if self.state.current_chat_peer.as_deref() == Some(msg.conversation_id.as_str()) {
+                    self.state.current_messages.append(msg);
                 }
-
                 Command::none()
             }
 
-            // ============= Search =============
-            Message::SearchQueryChanged(query) => {
-                self.state.search_query = query;
+            Message::PeerKeyChanged(msg) => {
+                self.state.key_conflicts.insert(msg.conversation_id.clone());
+                if self.state.current_chat_peer.as_deref() == Some(msg.conversation_id.as_str()) {
+                    self.state.current_messages.append(msg);
+                }
                 Command::none()
             }
 
-            Message::SearchUser => {
-                let query = self.state.search_query.clone();
+            Message::AcceptPeerKey(peer_id) => {
                 let network = self.network.clone();
-
                 Command::perform(
                     async move {
-                        if let Some(ref client) = *network.read().await {
-                            client.find_user(&query).await
-                        } else {
-                            Err(anyhow::anyhow!("Not connected"))
+                        match *network.read().await {
+                            Some(ref client) => client.accept_peer_key(&peer_id).map(|_| peer_id),
+                            None => Err(anyhow::anyhow!("Not connected")),
                         }
                     },
                     |result| match result {
-                        Ok(user) => Message::UserFound(user),
-                        Err(e) => Message::Error(e.to_string()),
+                        Ok(peer_id) => Message::PeerKeyAccepted(peer_id),
+                        Err(e) => Message::Error(format!("Could not accept the new key: {}", e)),
                     },
                 )
             }
 
-            Message::UserFound(user) => {
-                self.state.found_user = Some(user);
+            Message::PeerKeyAccepted(peer_id) => {
+                self.state.key_conflicts.remove(&peer_id);
                 Command::none()
             }
 
-            Message::StartChatWithUser(user_id) => {
-                // Create or find conversation
-                if !self.state.conversations.iter().any(|c| c.peer_id == user_id) {
-                    let conv = crate::state::Conversation {
-                        id: user_id.clone(),
-                        peer_id: user_id.clone(),
-                        peer_name: self.state.found_user.as_ref().and_then(|u| u.display_name.clone()),
-                        peer_avatar: None,
-                        last_message: None,
-                        last_message_time: None,
-                        unread_count: 0,
-                        is_muted: false,
-                        is_pinned: false,
-                    };
-                    self.state.conversations.push(conv);
-                    self.db.save_conversation(&self.state.conversations.last().unwrap()).ok();
+            Message::ToggleFailedActions(message_id) => {
+                self.state.failed_actions = match self.state.failed_actions {
+                    Some(ref open) if *open == message_id => None,
+                    _ => Some(message_id),
+                };
+                Command::none()
+            }
+
+            Message::ResendMessage(message_id) => {
+                self.state.failed_actions = None;
+                let Some(msg) = self.state.current_messages.get(&message_id).cloned() else {
+                    return Command::none();
+                };
+                if msg.status != MessageStatus::Failed {
+                    return Command::none();
                 }
+                self.state
+                    .current_messages
+                    .patch(&message_id, |m| m.status = MessageStatus::Pending);
+                self.deliver_message(msg)
+            }
 
-                self.state.found_user = None;
-                self.state.search_query.clear();
-                self.state.show_search = false;
+            Message::ShowMessageInfo(message_id) => {
+                let Some(msg) = self.state.current_messages.get(&message_id).cloned() else {
+                    return Command::none();
+                };
+                let delivery = if msg.is_outgoing {
+                    self.db.get_delivery(&message_id).unwrap_or_else(|e| {
+                        tracing::warn!("Failed to load delivery of {}: {}", message_id, e);
+                        None
+                    })
+                } else {
+                    None
+                };
+                self.state.message_info = Some((msg, delivery));
+                Command::none()
+            }
 
-                self.update(Message::OpenChat(user_id))
+            Message::CloseMessageInfo => {
+                self.state.message_info = None;
+                Command::none()
             }
 
-            // ============= Calls =============
-            Message::StartCall(peer_id, is_video) => {
-                self.state.current_screen = Screen::Call(peer_id.clone());
-                self.state.call_state = Some(crate::state::CallState::Outgoing);
-                self.state.call_peer_id = Some(peer_id.clone());
-                self.state.call_is_video = is_video;
+            Message::ReplyPrivately(message_id) => {
+                let Some(msg) = self.state.current_messages.get(&message_id).cloned() else {
+                    return Command::none();
+                };
+                if msg.is_outgoing || msg.message_type == MessageType::System {
+                    return Command::none();
+                }
+                // Opening the chat drops a quote meant for another one
+                let open = self.update(Message::StartChatWithUser(msg.sender_id.clone()));
+                self.state.reply_quote = Some(msg.to_quote().sent_in(&msg.sender_id));
+                open
+            }
 
-                let network = self.network.clone();
-                Command::perform(
-                    async move {
-                        if let Some(ref client) = *network.read().await {
-                            client.initiate_call(&peer_id, is_video).await
-                        } else {
-                            Err(anyhow::anyhow!("Not connected"))
-                        }
-                    },
+            Message::ReplyToMessage(message_id) => {
+                if let Some(msg) = self.state.current_messages.get(&message_id) {
+                    if msg.message_type != MessageType::System {
+                        self.state.reply_quote = Some(msg.to_quote().sent_in(&msg.conversation_id));
+                    }
+                }
+                Command::none()
+            }
+
+            Message::ForwardMessage(message_id) => {
+                self.state.forwarding = self
+                    .state
+                    .current_messages
+                    .get(&message_id)
+                    .filter(|m| m.message_type == MessageType::Text)
+                    .cloned()
+                    .into_iter()
+                    .collect();
+                Command::none()
+            }
+
+            Message::ForwardTo(peer_id) => {
+                let Some(session) = self.state.session.as_ref() else {
+                    return Command::none();
+                };
+                let copies: Vec<_> = self
+                    .state
+                    .forwarding
+                    .drain(..)
+                    .map(|original| {
+                        let mut msg = ChatMessage::outgoing_text(&peer_id, &session.user_id, &original.content);
+                        msg.sender_device_id = Some(session.device_id.clone());
+                        msg
+                    })
+                    .collect();
+                if self.state.current_chat_peer.as_deref() == Some(peer_id.as_str()) {
+                    for msg in &copies {
+                        self.state.current_messages.append(msg.clone());
+                    }
+                }
+                Command::batch(copies.into_iter().map(|msg| self.deliver_message(msg)))
+            }
+
+            Message::ToggleMessageSelected(message_id) => {
+                self.state.selecting_messages = true;
+                if !self.state.selected_messages.remove(&message_id) {
+                    self.state.selected_messages.insert(message_id);
+                }
+                Command::none()
+            }
+
+            Message::ClearMessageSelection => {
+                self.state.selecting_messages = false;
+                self.state.selected_messages.clear();
+                Command::none()
+            }
+
+            Message::ForwardSelected => {
+                // Only text is forwarded from here, as with a single message
+                self.state.forwarding = self
+                    .take_message_selection()
+                    .into_iter()
+                    .filter(|m| m.message_type == MessageType::Text)
+                    .collect();
+                Command::none()
+            }
+
+            Message::DeleteSelectedMessages => {
+                let ids: Vec<_> = self
+                    .take_message_selection()
+                    .into_iter()
+                    .map(|m| m.message_id)
+                    .collect();
+                if let Err(e) = self.db.delete_messages(&ids) {
+                    self.state.error = Some(format!("Failed to delete messages: {}", e));
+                    return Command::none();
+                }
+                for id in &ids {
+                    self.state.current_messages.remove(id);
+                }
+                Command::none()
+            }
+
+            Message::StarSelected(starred) => {
+                let ids: Vec<_> = self
+                    .take_message_selection()
+                    .into_iter()
+                    .map(|m| m.message_id)
+                    .collect();
+                if let Err(e) = self.db.set_messages_starred(&ids, starred) {
+                    self.state.error = Some(format!("Failed to star messages: {}", e));
+                    return Command::none();
+                }
+                for id in &ids {
+                    self.state.current_messages.patch(id, |m| m.is_starred = starred);
+                }
+                Command::none()
+            }
+
+            Message::CancelForward => {
+                self.state.forwarding.clear();
+                Command::none()
+            }
+
+            Message::ToggleStarred(message_id) => {
+                let Some(starred) = self.state.current_messages.get(&message_id).map(|m| !m.is_starred) else {
+                    return Command::none();
+                };
+                if let Err(e) = self.db.set_message_starred(&message_id, starred) {
+                    self.state.error = Some(format!("Failed to star message: {}", e));
+                    return Command::none();
+                }
+                self.state
+                    .current_messages
+                    .patch(&message_id, |m| m.is_starred = starred);
+                Command::none()
+            }
+
+            Message::CancelReply => {
+                self.state.reply_quote = None;
+                Command::none()
+            }
+
+            Message::DeleteMessage(message_id) => {
+                self.state.failed_actions = None;
+                self.state.current_messages.remove(&message_id);
+                if let Err(e) = self.db.delete_message(&message_id) {
+                    self.state.error = Some(format!("Failed to delete message: {}", e));
+                }
+                Command::none()
+            }
+
+            Message::AutoRetriesChanged(retries) => {
+                self.state.config.delivery.auto_retries = retries;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::MessageReceived(msg) => {
+                let actions = self.rule_actions(&msg);
+                // Someone writing again was reactivated
+                if !msg.is_outgoing && self.state.is_read_only(&msg.conversation_id) {
+                    self.set_peer_available(&msg.conversation_id, true);
+                }
+
+                // Update conversation and move it into place
+                if let Some(conv) = self
+                    .state
+                    .conversations
+                    .iter()
+                    .find(|c| c.peer_id == msg.conversation_id)
+                {
+                    let mut conv = conv.clone();
+                    conv.last_message = Some(msg.content.clone());
+                    conv.last_message_time = Some(msg.timestamp);
+                    if !msg.is_outgoing {
+                        conv.unread_count += 1;
+                    }
+                    self.apply_rule_actions(&mut conv, &actions);
+                    self.state.place_conversation(conv);
+                }
+
+                // Show notification
+                if self.state.config.notifications.enabled && !msg.is_outgoing {
+                    self.show_notification(&msg, actions.contains(&RuleAction::Highlight));
+                }
+
+                // Add to the open chat if it belongs there
+                if self.state.current_chat_peer.as_deref() == Some(msg.conversation_id.as_str()) {
+                    self.state.current_messages.append(msg);
+                }
+
+                Command::none()
+            }
+
+            // ============= Search =============
+            Message::SearchQueryChanged(query) => {
+                self.state.search_query = query;
+                Command::none()
+            }
+
+            Message::SearchUser => {
+                let query = self.state.search_query.clone();
+                let network = self.network.clone();
+
+                Command::perform(
+                    async move {
+                        if let Some(ref client) = *network.read().await {
+                            client.find_user(&query).await
+                        } else {
+                            Err(anyhow::anyhow!("Not connected"))
+                        }
+                    },
+                    |result| match result {
+                        Ok(user) => Message::UserFound(user),
+                        Err(e) => Message::Error(e.to_string()),
+                    },
+                )
+            }
+
+            Message::UserFound(user) => {
+                self.state.found_user = Some(user);
+                Command::none()
+            }
+
+            Message::StartChatWithUser(user_id) => {
+                // Create or find conversation
+                if !self.state.conversations.iter().any(|c| c.peer_id == user_id) {
+                    let conv = crate::state::Conversation {
+                        id: user_id.clone(),
+                        peer_id: user_id.clone(),
+                        peer_name: self.state.found_user.as_ref().and_then(|u| u.display_name.clone()),
+                        peer_avatar: None,
+                        last_message: None,
+                        last_message_time: None,
+                        unread_count: 0,
+                        is_muted: false,
+                        is_pinned: false,
+                        is_archived: false,
+                    };
+                    self.db.save_conversation(&conv).ok();
+                    self.state.place_conversation(conv);
+                }
+
+                self.state.found_user = None;
+                self.state.search_query.clear();
+                self.state.show_search = false;
+
+                self.update(Message::OpenChat(user_id))
+            }
+
+            // ============= Profiles =============
+            Message::ProfileLoaded(user) => {
+                if let Err(e) = self.db.save_profile(&user) {
+                    tracing::warn!("Failed to cache profile of {}: {}", user.user_id, e);
+                }
+                let own = self.state.session.as_ref().map(|s| s.user_id.as_str());
+                if own == Some(user.user_id.as_str()) {
+                    self.state.status_emoji_input = user.status_emoji.clone().unwrap_or_default();
+                    self.state.status_text_input = user.status_text.clone().unwrap_or_default();
+                    self.state.bio_input = user.bio.clone().unwrap_or_default();
+                    if let Some(visibility) = user.last_seen_visibility {
+                        self.state.last_seen_visibility = visibility;
+                    }
+                }
+                match self.db.update_conversation_profile(
+                    &user.user_id,
+                    user.display_name.as_deref(),
+                    user.avatar_file_id.as_deref(),
+                ) {
+                    Ok(true) => {
+                        if let Some(conversation) = self
+                            .state
+                            .conversations
+                            .iter_mut()
+                            .find(|c| c.peer_id == user.user_id)
+                        {
+                            conversation.peer_name = user.display_name.clone();
+                            conversation.peer_avatar = user.avatar_file_id.clone();
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        tracing::warn!("Failed to update conversation of {}: {}", user.user_id, e)
+                    }
+                }
+                self.state.profiles.insert(user.user_id.clone(), user);
+                Command::none()
+            }
+
+            Message::ProfileRefreshTick => {
+                let now = chrono::Utc::now().timestamp();
+                let refreshed = &self.state.profiles_refreshed;
+                let mut due: Vec<(Option<i64>, String)> = self
+                    .state
+                    .conversations
+                    .iter()
+                    .map(|c| (refreshed.get(&c.peer_id).copied(), c.peer_id.clone()))
+                    .filter(|(at, _)| at.is_none_or(|at| now - at >= PROFILE_REFRESH_INTERVAL))
+                    .collect();
+                due.sort_by_key(|(at, _)| *at);
+                due.truncate(PROFILE_REFRESH_BATCH);
+                let peer_ids = due.into_iter().map(|(_, id)| id).collect();
+                self.refresh_profiles(peer_ids, PROFILE_REFRESH_INTERVAL)
+            }
+
+            Message::ToggleChatDetails => {
+                self.state.show_chat_details = !self.state.show_chat_details;
+                self.refresh_conversation_stats();
+                Command::none()
+            }
+
+            Message::StatusEmojiChanged(emoji) => {
+                self.state.status_emoji_input = emoji;
+                Command::none()
+            }
+
+            Message::StatusTextChanged(text) => {
+                self.state.status_text_input = text;
+                Command::none()
+            }
+
+            Message::BioChanged(bio) => {
+                self.state.bio_input = bio;
+                Command::none()
+            }
+
+            Message::SaveProfile => {
+                let emoji = self.state.status_emoji_input.trim().to_string();
+                let status = self.state.status_text_input.trim().to_string();
+                let bio = self.state.bio_input.trim().to_string();
+                let network = self.network.clone();
+
+                Command::perform(
+                    async move {
+                        if let Some(ref client) = *network.read().await {
+                            client.update_profile(&emoji, &status, &bio).await
+                        } else {
+                            Err(anyhow::anyhow!("Not connected"))
+                        }
+                    },
+                    |result| match result {
+                        Ok(user) => Message::ProfileSaved(user),
+                        Err(e) => Message::Error(e.to_string()),
+                    },
+                )
+            }
+
+            Message::ProfileSaved(user) => self.update(Message::ProfileLoaded(user)),
+
+            Message::LastSeenVisibilityChanged(visibility) => {
+                if self.state.is_locked(PolicySetting::LastSeenVisibility) {
+                    return Command::none();
+                }
+                self.set_last_seen_visibility(visibility)
+            }
+
+            // ============= Devices =============
+            Message::DevicesLoaded(devices) => {
+                self.state.devices = devices;
+                Command::none()
+            }
+
+            Message::StartRenameDevice(device_id) => {
+                let name = self
+                    .state
+                    .devices
+                    .iter()
+                    .find(|d| d.device_id == device_id)
+                    .map(|d| d.device_name.clone())
+                    .unwrap_or_default();
+                self.state.device_rename = Some((device_id, name));
+                Command::none()
+            }
+
+            Message::DeviceNameChanged(name) => {
+                if let Some((_, ref mut input)) = self.state.device_rename {
+                    *input = name;
+                }
+                Command::none()
+            }
+
+            Message::CancelRenameDevice => {
+                self.state.device_rename = None;
+                Command::none()
+            }
+
+            Message::SaveDeviceName => {
+                let Some((device_id, name)) = self.state.device_rename.clone() else {
+                    return Command::none();
+                };
+                let name = name.trim().to_string();
+                if name.is_empty() {
+                    return Command::none();
+                }
+                let network = self.network.clone();
+
+                Command::perform(
+                    async move {
+                        if let Some(ref client) = *network.read().await {
+                            client.rename_device(&device_id, &name).await
+                        } else {
+                            Err(anyhow::anyhow!("Not connected"))
+                        }
+                    },
+                    |result| match result {
+                        Ok(device) => Message::DeviceRenamed(device),
+                        Err(e) => Message::Error(e.to_string()),
+                    },
+                )
+            }
+
+            Message::DeviceRenamed(device) => {
+                self.state.device_rename = None;
+                if let Some(existing) = self
+                    .state
+                    .devices
+                    .iter_mut()
+                    .find(|d| d.device_id == device.device_id)
+                {
+                    existing.device_name = device.device_name;
+                }
+                Command::none()
+            }
+
+            // ============= Calls =============
+            Message::StartCall(peer_id, is_video) => {
+                if let Some(reason) = self.state.unavailable(Feature::Calls) {
+                    self.state.error = Some(format!("Calls are {}", reason));
+                    return Command::none();
+                }
+                self.state.current_screen = Screen::Call(peer_id.clone());
+                self.state.call_state = Some(crate::state::CallState::Outgoing);
+                self.state.call_peer_id = Some(peer_id.clone());
+                self.state.call_is_video = is_video;
+
+                let network = self.network.clone();
+                Command::perform(
+                    async move {
+                        if let Some(ref client) = *network.read().await {
+                            client.initiate_call(&peer_id, is_video).await
+                        } else {
+                            Err(anyhow::anyhow!("Not connected"))
+                        }
+                    },
                     |result| match result {
                         Ok(call_id) => Message::CallInitiated(call_id),
                         Err(e) => Message::CallError(e.to_string()),
@@ -415,6 +1179,18 @@ impl Application for PrivMsg {
             }
 
             Message::IncomingCall(call_id, peer_id, is_video) => {
+                // Turned down without ringing while calls are off
+                if !self.state.can_use(Feature::Calls) {
+                    let network = self.network.clone();
+                    return Command::perform(
+                        async move {
+                            if let Some(ref client) = *network.read().await {
+                                client.end_call(&call_id).await.ok();
+                            }
+                        },
+                        |_| Message::Noop,
+                    );
+                }
                 self.state.call_id = Some(call_id);
                 self.state.call_peer_id = Some(peer_id.clone());
                 self.state.call_is_video = is_video;
@@ -423,11 +1199,13 @@ impl Application for PrivMsg {
 
                 // Show notification
                 if self.state.config.notifications.enabled {
-                    notify_rust::Notification::new()
-                        .summary("Incoming Call")
-                        .body(&format!("Call from {}", self.state.call_peer_id.as_deref().unwrap_or("Unknown")))
-                        .show()
-                        .ok();
+                    let caller = self.state.call_peer_id.as_deref().unwrap_or_default();
+                    let notification = Notification::incoming_call(
+                        caller,
+                        self.peer_name(caller),
+                        self.state.preview_policy(),
+                    );
+                    self.notifier.notify(&notification);
                 }
 
                 Command::none()
@@ -481,6 +1259,24 @@ impl Application for PrivMsg {
             }
 
             Message::CallEnded => {
+                // Hung up before anyone answered
+                if self.state.call_state == Some(crate::state::CallState::Incoming) {
+                    if let Some(caller_id) = self.state.call_peer_id.clone() {
+                        let notice = ChatMessage::system(
+                            &caller_id,
+                            SystemEvent::CallMissed {
+                                caller_id: caller_id.clone(),
+                                is_video: self.state.call_is_video,
+                            },
+                        );
+                        if let Err(e) = self.db.save_message(&notice) {
+                            tracing::error!("Failed to store missed call: {}", e);
+                        }
+                        if self.state.current_chat_peer.as_deref() == Some(caller_id.as_str()) {
+                            self.state.current_messages.append(notice);
+                        }
+                    }
+                }
                 self.state.call_state = None;
                 self.state.call_id = None;
                 self.state.call_start_time = None;
@@ -507,169 +1303,1352 @@ impl Application for PrivMsg {
 
             // ============= Voice Messages =============
             Message::StartRecordingVoice => {
-                self.state.is_recording_voice = true;
-                self.state.recording_start_time = Some(chrono::Utc::now().timestamp());
-                // TODO: Start actual recording
-                Command::none()
-            }
+                match VoiceRecorder::start() {
+                    Ok(recorder) => {
+                        self.recorder = Some(recorder);
+                        self.state.is_recording_voice = true;
+                        self.state.recording_start_time = Some(chrono::Utc::now().timestamp());
+                    }
+                    Err(e) => {
+                        self.state.error = Some(format!("Cannot start recording: {}", e));
+                    }
+                }
+                Command::none()
+            }
 
             Message::StopRecordingVoice => {
                 self.state.is_recording_voice = false;
-                let duration = self.state.recording_start_time.map(|start| {
-                    chrono::Utc::now().timestamp() - start
-                });
                 self.state.recording_start_time = None;
 
-                // TODO: Get recorded audio data and send
-                if let Some(_duration) = duration {
-                    // Send voice message
+                let Some(recorder) = self.recorder.take() else {
+                    return Command::none();
+                };
+                let recording = recorder.finish();
+                if recording.samples.is_empty() {
+                    return Command::none();
+                }
+
+                if let Some(ref peer_id) = self.state.current_chat_peer {
+                    let peer_id = peer_id.clone();
+                    let network = self.network.clone();
+                    let db = self.db.clone();
+
+                    // Sample the waveform now, while we still have raw PCM
+                    let duration_ms = recording.duration_ms();
+                    let waveform = recording.waveform();
+                    let audio = recording.to_wav();
+
+                    return Command::perform(
+                        async move {
+                            if let Some(ref client) = *network.read().await {
+                                let msg = client
+                                    .send_voice_message(&peer_id, audio, duration_ms, waveform)
+                                    .await?;
+                                db.save_message(&msg)?;
+                                return Ok(msg);
+                            }
+                            Err(anyhow::anyhow!("Not connected"))
+                        },
+                        |result| match result {
+                            Ok(msg) => Message::MessageSent(msg),
+                            Err(e) => Message::Error(e.to_string()),
+                        },
+                    );
                 }
                 Command::none()
             }
 
             Message::CancelRecordingVoice => {
+                self.recorder = None;
                 self.state.is_recording_voice = false;
                 self.state.recording_start_time = None;
                 Command::none()
             }
 
+            // ============= Voice Playback =============
+            Message::PlayVoice(message_id) => {
+                // Pause if this message is already playing
+                if let Some(ref mut playback) = self.state.voice_playback {
+                    if playback.message_id == message_id && playback.is_playing() {
+                        playback.advance(chrono::Utc::now().timestamp_millis());
+                        playback.playing_since = None;
+                        self.voice_player = None;
+                        return Command::none();
+                    }
+                }
+
+                if matches!(self.voice_audio, Some((ref id, _)) if *id == message_id) {
+                    self.start_voice_playback(&message_id)
+                } else {
+                    self.load_voice(message_id)
+                }
+            }
+
+            Message::SeekVoice(message_id, position) => {
+                let was_playing = self
+                    .state
+                    .voice_playback
+                    .as_ref()
+                    .is_some_and(|p| p.message_id == message_id && p.is_playing());
+
+                self.voice_player = None;
+                self.state.voice_playback = Some(VoicePlayback {
+                    duration_ms: self.voice_duration_ms(&message_id),
+                    message_id: message_id.clone(),
+                    position,
+                    playing_since: None,
+                });
+
+                if was_playing {
+                    return self.start_voice_playback(&message_id);
+                }
+                Command::none()
+            }
+
+            Message::VoiceLoaded(message_id, audio) => {
+                self.voice_audio = Some((message_id.clone(), audio));
+                self.start_voice_playback(&message_id)
+            }
+
+            Message::VoicePlaybackFinished(message_id) => {
+                if let Some(ref mut playback) = self.state.voice_playback {
+                    if playback.message_id == message_id && playback.is_playing() {
+                        playback.position = 0.0;
+                        playback.playing_since = None;
+                        self.voice_player = None;
+                    }
+                }
+                Command::none()
+            }
+
+            Message::VoicePlaybackTick => {
+                if let Some(ref mut playback) = self.state.voice_playback {
+                    playback.advance(chrono::Utc::now().timestamp_millis());
+                }
+                Command::none()
+            }
+
             // ============= File Attachments =============
             Message::AttachFile => {
                 Command::perform(
                     async {
                         rfd::AsyncFileDialog::new()
-                            .set_title("Select file to send")
-                            .pick_file()
+                            .set_title("Select files to send")
+                            .pick_files()
                             .await
-                            .map(|f| f.path().to_path_buf())
+                            .map(|files| files.iter().map(|f| f.path().to_path_buf()).collect())
                     },
-                    |path| match path {
-                        Some(p) => Message::FileSelected(p),
+                    |paths| match paths {
+                        Some(p) => Message::FilesSelected(p),
                         None => Message::Noop,
                     },
                 )
             }
 
-            Message::FileSelected(path) => {
-                self.state.selected_file = Some(path.clone());
+            Message::FilesSelected(paths) => {
+                if let Some(reason) = self.state.unavailable(Feature::FileTransfer) {
+                    self.state.error = Some(format!("Sending files is {}", reason));
+                    return Command::none();
+                }
+                // Don't let the batch change underneath an upload
+                if self.state.attachment_upload.is_some() {
+                    return Command::none();
+                }
+
+                for path in paths {
+                    if self.state.staged_files.iter().any(|f| f.path == path) {
+                        continue;
+                    }
+                    match StagedAttachment::from_path(path) {
+                        Ok(file) if !self.state.capabilities.accepts_file(file.file_size as u64) => {
+                            self.state.error = Some(format!(
+                                "{} is larger than the server accepts ({})",
+                                file.file_name,
+                                AppState::format_file_size(self.state.capabilities.max_file_size as i64)
+                            ));
+                        }
+                        Ok(file) => self.state.staged_files.push(file),
+                        Err(e) => self.state.error = Some(format!("Cannot attach file: {}", e)),
+                    }
+                }
+                Command::none()
+            }
+
+            Message::RemoveStagedFile(index) => {
+                if self.state.attachment_upload.is_none() && index < self.state.staged_files.len() {
+                    self.state.staged_files.remove(index);
+                    if self.state.staged_files.is_empty() {
+                        self.state.attachment_caption.clear();
+                    }
+                }
+                Command::none()
+            }
 
-                if let Some(ref peer_id) = self.state.current_chat_peer {
-                    let peer_id = peer_id.clone();
+            Message::ClearStagedFiles => {
+                if self.state.attachment_upload.is_none() {
+                    self.state.staged_files.clear();
+                    self.state.attachment_caption.clear();
+                }
+                Command::none()
+            }
+
+            Message::AttachmentCaptionChanged(caption) => {
+                self.state.attachment_caption = caption;
+                Command::none()
+            }
+
+            Message::SendAttachments => {
+                if self.state.staged_files.is_empty() || self.state.attachment_upload.is_some() {
+                    return Command::none();
+                }
+                let Some(peer_id) = self.state.current_chat_peer.clone() else {
+                    return Command::none();
+                };
+
+                let batch_id = uuid::Uuid::new_v4().to_string();
+                self.state.attachment_upload = Some(AttachmentUpload {
+                    batch_id: batch_id.clone(),
+                    peer_id,
+                    total_bytes: self.state.staged_files.iter().map(|f| f.file_size).sum(),
+                    uploaded_bytes: 0,
+                    uploaded: vec![None; self.state.staged_files.len()],
+                });
+
+                // Upload every file first; messages only go out once all succeed
+                let uploads = self.state.staged_files.iter().enumerate().map(|(index, file)| {
                     let network = self.network.clone();
-                    let db = self.db.clone();
+                    let file = file.clone();
+                    let batch_id = batch_id.clone();
 
-                    return Command::perform(
+                    Command::perform(
                         async move {
-                            let data = tokio::fs::read(&path).await?;
-                            let file_name = path
-                                .file_name()
-                                .and_then(|n| n.to_str())
-                                .unwrap_or("file")
-                                .to_string();
-                            let mime = mime_guess::from_path(&path)
-                                .first_or_octet_stream()
-                                .to_string();
-
                             if let Some(ref client) = *network.read().await {
-                                let msg = client
-                                    .send_file_message(&peer_id, data, &file_name, &mime)
-                                    .await?;
-                                db.save_message(&msg)?;
-                                return Ok(msg);
+                                return client
+                                    .upload_attachment_from_path(&file.path, &file.file_name, &file.mime_type)
+                                    .await;
                             }
                             Err(anyhow::anyhow!("Not connected"))
                         },
-                        |result| match result {
-                            Ok(msg) => Message::MessageSent(msg),
-                            Err(e) => Message::Error(e.to_string()),
+                        move |result| match result {
+                            Ok(attachment) => Message::AttachmentUploaded(batch_id, index, attachment),
+                            Err(e) => Message::AttachmentUploadFailed(batch_id, e.to_string()),
                         },
-                    );
+                    )
+                });
+
+                Command::batch(uploads.collect::<Vec<_>>())
+            }
+
+            Message::AttachmentUploaded(batch_id, index, attachment) => {
+                // Finished after its batch failed, so nothing will send it
+                let Some(upload) = self
+                    .state
+                    .attachment_upload
+                    .as_mut()
+                    .filter(|u| u.batch_id == batch_id && index < u.uploaded.len())
+                else {
+                    return self.discard_uploads(vec![attachment]);
+                };
+
+                upload.uploaded_bytes += attachment.file_size;
+                upload.uploaded[index] = Some(attachment);
+                if !upload.is_complete() {
+                    return Command::none();
+                }
+
+                let peer_id = upload.peer_id.clone();
+                let attachments: Vec<Attachment> = upload.uploaded.iter().flatten().cloned().collect();
+                let caption = Some(self.state.attachment_caption.trim().to_string())
+                    .filter(|c| !c.is_empty());
+                let network = self.network.clone();
+                let db = self.db.clone();
+
+                // Sent in order; on a failure the uploads not sent yet are
+                // removed and the rest of the batch stays staged
+                Command::perform(
+                    async move {
+                        let network = network.read().await;
+                        let Some(client) = network.as_ref() else {
+                            return (Vec::new(), Some("Not connected".to_string()));
+                        };
+                        let mut sent = Vec::with_capacity(attachments.len());
+                        for attachment in &attachments {
+                            let msg = match client
+                                .send_attachment_message(&peer_id, attachment, caption.as_deref())
+                                .await
+                            {
+                                Ok(msg) => msg,
+                                Err(error) => {
+                                    for unsent in &attachments[sent.len()..] {
+                                        if let Err(e) = client.delete_file(&unsent.file_id).await {
+                                            tracing::warn!("Failed to delete upload {}: {}", unsent.file_id, e);
+                                        }
+                                    }
+                                    return (sent, Some(error.to_string()));
+                                }
+                            };
+                            if let Err(e) = db.save_message(&msg) {
+                                tracing::warn!("Failed to store message {}: {}", msg.message_id, e);
+                            }
+                            sent.push(msg);
+                        }
+                        (sent, None)
+                    },
+                    move |(sent, error)| Message::AttachmentsSent(batch_id, sent, error),
+                )
+            }
+
+            Message::AttachmentUploadFailed(batch_id, error) => {
+                // Keep the staged files so the user can retry
+                if !self
+                    .state
+                    .attachment_upload
+                    .as_ref()
+                    .is_some_and(|u| u.batch_id == batch_id)
+                {
+                    return Command::none();
+                }
+                let uploaded = self.state.attachment_upload.take().map(|u| u.uploaded).unwrap_or_default();
+                self.state.error = Some(format!("Failed to send attachments: {}", error));
+                self.discard_uploads(uploaded.into_iter().flatten().collect())
+            }
+
+            Message::AttachmentsSent(batch_id, msgs, error) => {
+                if self
+                    .state
+                    .attachment_upload
+                    .as_ref()
+                    .is_some_and(|u| u.batch_id == batch_id)
+                {
+                    self.state.attachment_upload = None;
+                }
+                // What went out leaves the staging area, so a retry sends
+                // only the rest
+                let sent = msgs.len().min(self.state.staged_files.len());
+                self.state.staged_files.drain(..sent);
+                match error {
+                    Some(error) => self.state.error = Some(format!("Failed to send attachments: {}", error)),
+                    None => self.state.attachment_caption.clear(),
+                }
+                for msg in msgs {
+                    if self.state.current_chat_peer.as_deref() == Some(msg.conversation_id.as_str()) {
+                        self.state.current_messages.append(msg);
+                    }
+                }
+                Command::none()
+            }
+
+            // ============= Webcam Photos =============
+            Message::CapturePhoto => {
+                let Some(peer_id) = self.state.current_chat_peer.clone() else {
+                    return Command::none();
+                };
+                if let Some(reason) = self.state.unavailable(Feature::FileTransfer) {
+                    self.state.error = Some(format!("Sending files is {}", reason));
+                    return Command::none();
+                }
+                if self.state.photo_capture.as_ref().is_some_and(|c| c.sending) {
+                    return Command::none();
+                }
+                let view_once = self.state.photo_capture.as_ref().is_some_and(|c| c.view_once);
+                self.state.photo_capture = Some(PhotoCapture {
+                    peer_id,
+                    photo: None,
+                    view_once,
+                    sending: false,
+                });
+
+                Command::perform(
+                    async {
+                        tokio::task::spawn_blocking(crate::camera::capture)
+                            .await
+                            .map_err(anyhow::Error::from)
+                            .and_then(|r| r)
+                            .map_err(|e| e.to_string())
+                    },
+                    Message::PhotoCaptured,
+                )
+            }
+
+            Message::PhotoCaptured(result) => {
+                let Some(capture) = self.state.photo_capture.as_mut() else {
+                    return Command::none();
+                };
+                match result {
+                    Ok(photo) => capture.photo = Some(photo),
+                    Err(e) => {
+                        self.state.photo_capture = None;
+                        self.state.error = Some(format!("Camera failed: {}", e));
+                    }
+                }
+                Command::none()
+            }
+
+            Message::PhotoViewOnceToggled(view_once) => {
+                if let Some(capture) = self.state.photo_capture.as_mut() {
+                    capture.view_once = view_once;
+                }
+                Command::none()
+            }
+
+            Message::DiscardPhoto => {
+                if !self.state.photo_capture.as_ref().is_some_and(|c| c.sending) {
+                    self.state.photo_capture = None;
+                }
+                Command::none()
+            }
+
+            Message::SendPhoto => {
+                let Some(capture) = self.state.photo_capture.as_mut() else {
+                    return Command::none();
+                };
+                let Some(photo) = capture.photo.clone() else {
+                    return Command::none();
+                };
+                if capture.sending {
+                    return Command::none();
+                }
+                capture.sending = true;
+
+                let peer_id = capture.peer_id.clone();
+                let view_once = capture.view_once;
+                let file_name = format!("photo-{}.jpg", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+                let network = self.network.clone();
+                let db = self.db.clone();
+
+                Command::perform(
+                    async move {
+                        if let Some(ref client) = *network.read().await {
+                            // Encrypted straight from memory, so no plaintext copy lands on disk
+                            let mut attachment = client.upload_attachment(photo.jpeg, &file_name, "image/jpeg").await?;
+                            attachment.width = Some(photo.width as i32);
+                            attachment.height = Some(photo.height as i32);
+                            attachment.view_once = view_once;
+
+                            let mut msg = client.send_attachment_message(&peer_id, &attachment, None).await?;
+                            if let Some(sent) = msg.attachment.as_mut().filter(|a| a.view_once) {
+                                // The sender can't reopen a view-once photo either
+                                sent.encryption_key = None;
+                            }
+                            db.save_message(&msg)?;
+                            return Ok(msg);
+                        }
+                        Err(anyhow::anyhow!("Not connected"))
+                    },
+                    |result| Message::PhotoSent(result.map_err(|e| e.to_string())),
+                )
+            }
+
+            Message::PhotoSent(result) => {
+                match result {
+                    Ok(msg) => {
+                        self.state.photo_capture = None;
+                        if self.state.current_chat_peer.as_deref() == Some(msg.conversation_id.as_str()) {
+                            self.state.current_messages.append(msg);
+                        }
+                    }
+                    Err(e) => {
+                        // Keep the photo so the user can retry
+                        if let Some(capture) = self.state.photo_capture.as_mut() {
+                            capture.sending = false;
+                        }
+                        self.state.error = Some(format!("Failed to send photo: {}", e));
+                    }
+                }
+                Command::none()
+            }
+
+            Message::DownloadFile(file_id, file_name) => {
+                let attachment = self
+                    .state
+                    .current_messages
+                    .iter()
+                    .filter_map(|m| m.attachment.clone())
+                    .find(|a| a.file_id == file_id);
+                let Some(attachment) = attachment else {
+                    return Command::none();
+                };
+
+                Command::perform(
+                    async move {
+                        // Ask where to save
+                        rfd::AsyncFileDialog::new()
+                            .set_title("Save file as")
+                            .set_file_name(&file_name)
+                            .save_file()
+                            .await
+                            .map(|f| f.path().to_path_buf())
+                    },
+                    move |path| match path {
+                        Some(path) => Message::DownloadTargetChosen(attachment.clone(), path),
+                        None => Message::Noop,
+                    },
+                )
+            }
+
+            Message::RequestReupload(message_id) => {
+                let Some(msg) = self.state.current_messages.get(&message_id).cloned() else {
+                    return Command::none();
+                };
+                let network = self.network.clone();
+                Command::perform(
+                    async move {
+                        match *network.read().await {
+                            Some(ref client) => client.request_reupload(&msg).await,
+                            None => Err(anyhow::anyhow!("Not connected")),
+                        }
+                    },
+                    |result| match result {
+                        Ok(()) => Message::Noop,
+                        Err(e) => Message::Error(format!("Could not ask for the file again: {}", e)),
+                    },
+                )
+            }
+
+            Message::DownloadTargetChosen(attachment, dest) => {
+                let file_id = attachment.file_id.clone();
+                if self.state.downloads.iter().any(|d| d.attachment.file_id == file_id) {
+                    return Command::none();
+                }
+
+                self.state.downloads.push(FileDownload {
+                    attachment,
+                    dest,
+                    control: Arc::new(TransferControl::default()),
+                    paused: false,
+                });
+                self.run_download(&file_id)
+            }
+
+            Message::DownloadFinished(file_id, result) => {
+                let Some(index) = self
+                    .state
+                    .downloads
+                    .iter()
+                    .position(|d| d.attachment.file_id == file_id)
+                else {
+                    return Command::none();
+                };
+
+                match result {
+                    Ok(TransferOutcome::Paused) => self.state.downloads[index].paused = true,
+                    Ok(TransferOutcome::Completed) => {
+                        let download = self.state.downloads.remove(index);
+                        tracing::info!("File downloaded to: {:?}", download.dest);
+                    }
+                    Ok(TransferOutcome::Cancelled) => {
+                        self.state.downloads.remove(index);
+                    }
+                    // Keep what was fetched so the user can retry with Resume
+                    Err(e) => {
+                        self.state.downloads[index].paused = true;
+                        self.state.error = Some(e);
+                    }
+                }
+                Command::none()
+            }
+
+            Message::PauseDownload(file_id) => {
+                if let Some(download) = self.state.download(&file_id) {
+                    download.control.pause();
                 }
                 Command::none()
             }
 
-            Message::DownloadFile(file_id, file_name) => {
-                let network = self.network.clone();
+            Message::ResumeDownload(file_id) => {
+                match self.state.download(&file_id) {
+                    Some(download) if download.paused => {
+                        download.control.resume();
+                        download.paused = false;
+                        self.run_download(&file_id)
+                    }
+                    _ => Command::none(),
+                }
+            }
+
+            Message::CancelDownload(file_id) => {
+                let Some(download) = self.state.download(&file_id) else {
+                    return Command::none();
+                };
+
+                // A running download removes its partial file once it sees the request
+                if !download.paused {
+                    download.control.cancel();
+                    return Command::none();
+                }
+
+                self.state.downloads.retain(|d| d.attachment.file_id != file_id);
+                Command::perform(
+                    async move {
+                        let _ = tokio::fs::remove_file(download_part_path(&file_id)).await;
+                    },
+                    |_| Message::Noop,
+                )
+            }
+
+            Message::DownloadProgressTick => {
+                // Nothing to update; the view reads progress from the transfer controls
+                Command::none()
+            }
+
+            Message::DownloadMyData => {
+                if self.state.data_export.is_some() {
+                    return Command::none();
+                }
+                Command::perform(
+                    async {
+                        rfd::AsyncFileDialog::new()
+                            .set_title("Download my data")
+                            .set_file_name("privmsg-my-data.json")
+                            .save_file()
+                            .await
+                            .map(|f| f.path().to_path_buf())
+                    },
+                    |path| match path {
+                        Some(path) => Message::DownloadMyDataTo(path),
+                        None => Message::Noop,
+                    },
+                )
+            }
+
+            Message::DownloadMyDataTo(dest) => {
+                let Some(user_id) = self.state.session.as_ref().map(|s| s.user_id.clone()) else {
+                    return Command::none();
+                };
+                let progress = Arc::new(ExportProgress::default());
+                self.state.data_export = Some(progress.clone());
+                let db = self.db.clone();
+                let network = self.network.clone();
+
+                Command::perform(
+                    async move {
+                        let (user, devices) = match *network.read().await {
+                            Some(ref client) => (client.find_user(&user_id).await?, client.list_devices().await?),
+                            None => return Err(anyhow::anyhow!("Not connected")),
+                        };
+                        crate::data_export::write(&db, user, devices, &dest, &progress)
+                    },
+                    |result| Message::DataExportFinished(result.map_err(|e| e.to_string())),
+                )
+            }
+
+            Message::CancelDataExport => {
+                if let Some(ref progress) = self.state.data_export {
+                    progress.cancel();
+                }
+                Command::none()
+            }
+
+            Message::DataExportFinished(result) => {
+                self.state.data_export = None;
+                if let Err(e) = result {
+                    self.state.error = Some(format!("Data export failed: {}", e));
+                }
+                Command::none()
+            }
+
+            // ============= Settings =============
+            Message::OpenSettings => {
+                self.state.current_screen = Screen::Settings;
+                let backup_dir = self.state.config.backup.directory(&self.state.data_dir);
+                self.state.backups = backup::list(&backup_dir).unwrap_or_default();
+                match self.state.session.as_ref() {
+                    Some(session) => Command::batch([
+                        self.refresh_profile(session.user_id.clone()),
+                        self.load_devices(),
+                    ]),
+                    None => Command::none(),
+                }
+            }
+
+            Message::ThemeChanged(theme) => {
+                self.theme = Theme::named(&theme);
+                self.state.config.ui.theme = theme;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::SetWallpaper(peer_id, wallpaper) => {
+                let previous = match peer_id {
+                    Some(peer_id) => {
+                        if let Err(e) = self.db.set_conversation_wallpaper(&peer_id, wallpaper.as_ref()) {
+                            self.state.error = Some(format!("Wallpaper not saved: {}", e));
+                            return Command::none();
+                        }
+                        match wallpaper {
+                            Some(wallpaper) => self.state.conversation_wallpapers.insert(peer_id, wallpaper),
+                            None => self.state.conversation_wallpapers.remove(&peer_id),
+                        }
+                    }
+                    None => {
+                        let previous = std::mem::replace(&mut self.state.config.ui.wallpaper, wallpaper);
+                        self.state.config.save(&self.state.data_dir).ok();
+                        previous
+                    }
+                };
+                // Imported images nothing uses any more
+                if let Some(Wallpaper::Image { path, .. }) = previous {
+                    let in_use = self
+                        .state
+                        .conversation_wallpapers
+                        .values()
+                        .chain(self.state.config.ui.wallpaper.as_ref())
+                        .any(|w| matches!(w, Wallpaper::Image { path: p, .. } if *p == path));
+                    if !in_use && path.starts_with(self.state.data_dir.join(WALLPAPER_DIR)) {
+                        std::fs::remove_file(&path).ok();
+                    }
+                }
+                Command::none()
+            }
+
+            Message::PickWallpaperImage(peer_id) => {
+                let dir = self.state.data_dir.join(WALLPAPER_DIR);
+                Command::perform(
+                    async move {
+                        let file = rfd::AsyncFileDialog::new()
+                            .set_title("Choose a wallpaper")
+                            .add_filter("Images", &["png", "jpg", "jpeg", "webp", "bmp", "gif"])
+                            .pick_file()
+                            .await?;
+                        let source = file.path().to_path_buf();
+                        Some(
+                            tokio::task::spawn_blocking(move || Wallpaper::import(&source, &dir))
+                                .await
+                                .map_err(|e| e.to_string())
+                                .and_then(|r| r),
+                        )
+                    },
+                    move |result| match result {
+                        Some(Ok(wallpaper)) => Message::SetWallpaper(peer_id.clone(), Some(wallpaper)),
+                        Some(Err(e)) => Message::Error(format!("Wallpaper not loaded: {}", e)),
+                        None => Message::Noop,
+                    },
+                )
+            }
+
+            Message::NotificationsChanged(enabled) => {
+                self.state.config.notifications.enabled = enabled;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::SoundChanged(enabled) => {
+                self.state.config.notifications.sound = enabled;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::NotificationPreviewChanged(enabled) => {
+                if self.state.is_locked(PolicySetting::NotificationPreview) {
+                    return Command::none();
+                }
+                self.state.config.notifications.preview = enabled;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::StartOnLoginChanged(enabled) => {
+                if let Err(e) = crate::packaging::set_autostart(enabled) {
+                    self.state.error = Some(format!("Failed to update autostart: {}", e));
+                    return Command::none();
+                }
+                self.state.config.integration.start_on_login = enabled;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::OsSession(event) => {
+                tracing::info!("Locking after OS event: {:?}", event);
+                self.update(Message::LockApp)
+            }
+
+            Message::PowerSourceChanged(source) => {
+                let was_on_battery = self.state.power_source == PowerSource::Battery;
+                self.state.power_source = source;
+                // Catch up on a backup that came due while on battery
+                if was_on_battery && source == PowerSource::Mains {
+                    return self.update(Message::BackupTick);
+                }
+                Command::none()
+            }
+
+            Message::LockApp => {
+                if self.state.config.security.passcode_hash.is_some() {
+                    self.state.locked = true;
+                    self.state.unlock_input.clear();
+                }
+                Command::none()
+            }
+
+            Message::UnlockInputChanged(value) => {
+                self.state.unlock_input = value;
+                Command::none()
+            }
+
+            Message::Unlock => {
+                let unlocked = match &self.state.config.security.passcode_hash {
+                    Some(hash) => applock::verify_passcode(&self.state.unlock_input, hash),
+                    None => true,
+                };
+                self.state.unlock_input.clear();
+                if unlocked {
+                    self.state.locked = false;
+                }
+                Command::none()
+            }
+
+            Message::PasscodeInputChanged(value) => {
+                self.state.passcode_input = value;
+                Command::none()
+            }
+
+            Message::SetPasscode => {
+                let passcode = std::mem::take(&mut self.state.passcode_input);
+                if passcode.chars().count() < 4 {
+                    self.state.error = Some("Passcode must be at least 4 characters".to_string());
+                    return Command::none();
+                }
+                self.state.config.security.passcode_hash = Some(applock::hash_passcode(&passcode));
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::RemovePasscode => {
+                self.state.config.security.passcode_hash = None;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::LockOnSuspendChanged(enabled) => {
+                self.state.config.security.lock_on_suspend = enabled;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::AnalyticsChanged(enabled) => {
+                self.state.config.analytics.enabled = enabled;
+                self.state.config.save(&self.state.data_dir).ok();
+                self.refresh_conversation_stats();
+                Command::none()
+            }
+
+            Message::ClipboardClearChanged(choice) => {
+                self.state.config.security.clipboard_clear_secs = choice.0;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::CopyMessage(message_id) => {
+                if let Some(msg) = self.state.current_messages.get(&message_id) {
+                    let text = match msg.attachment {
+                        Some(_) => msg.caption.clone().unwrap_or_default(),
+                        None => msg.content.clone(),
+                    };
+                    self.copy_to_clipboard(&clipboard::sanitize(&text));
+                }
+                Command::none()
+            }
+
+            Message::CopyFileName(message_id) => {
+                if let Some(name) = self
+                    .state
+                    .current_messages
+                    .get(&message_id)
+                    .and_then(|m| m.attachment.as_ref())
+                    .map(|a| a.file_name.clone())
+                {
+                    self.copy_to_clipboard(&name);
+                }
+                Command::none()
+            }
+
+            Message::CopyLink(message_id) => {
+                let server_url = self.state.config.http_url();
+                if let Some(link) = self
+                    .state
+                    .current_messages
+                    .get(&message_id)
+                    .and_then(|m| m.attachment_link(&server_url))
+                {
+                    self.copy_to_clipboard(&link);
+                }
+                Command::none()
+            }
+
+            Message::CopySelectedMessages => {
+                let selected = self.take_message_selection();
+                let text = clipboard::transcript(&selected, |user_id| {
+                    self.state
+                        .profiles
+                        .get(user_id)
+                        .and_then(|p| p.display_name.clone())
+                        .unwrap_or_else(|| user_id.to_string())
+                });
+                self.copy_to_clipboard(&text);
+                Command::none()
+            }
+
+            // ============= Message rules =============
+            Message::OpenRules => {
+                self.state.rule_draft = RuleDraft::default();
+                self.state.current_screen = Screen::Rules;
+                Command::none()
+            }
+
+            Message::RuleNameChanged(name) => {
+                self.state.rule_draft.name = name;
+                Command::none()
+            }
+
+            Message::RuleSenderChanged(sender_id) => {
+                self.state.rule_draft.sender_id = sender_id;
+                Command::none()
+            }
+
+            Message::RuleKeywordChanged(keyword) => {
+                self.state.rule_draft.keyword = keyword;
+                Command::none()
+            }
+
+            Message::RuleTypeSelected(message_type) => {
+                self.state.rule_draft.message_type = message_type;
+                Command::none()
+            }
+
+            Message::RuleActionToggled(action, on) => {
+                let actions = &mut self.state.rule_draft.actions;
+                actions.retain(|a| *a != action);
+                if on {
+                    actions.push(action);
+                }
+                Command::none()
+            }
+
+            Message::SaveRule => {
+                let Some(rule) = self.state.rule_draft.to_rule() else {
+                    return Command::none();
+                };
+                if let Err(e) = self.db.save_message_rule(&rule) {
+                    self.state.error = Some(format!("Failed to save rule: {}", e));
+                    return Command::none();
+                }
+                match self.state.message_rules.iter_mut().find(|r| r.id == rule.id) {
+                    Some(existing) => *existing = rule,
+                    None => self.state.message_rules.push(rule),
+                }
+                self.state.rule_draft = RuleDraft::default();
+                Command::none()
+            }
+
+            Message::EditRule(id) => {
+                if let Some(rule) = self.state.message_rules.iter().find(|r| r.id == id) {
+                    self.state.rule_draft = RuleDraft::edit(rule);
+                }
+                Command::none()
+            }
+
+            Message::NewRule => {
+                self.state.rule_draft = RuleDraft::default();
+                Command::none()
+            }
+
+            Message::RuleEnabledToggled(id, enabled) => {
+                let Some(rule) = self.state.message_rules.iter_mut().find(|r| r.id == id) else {
+                    return Command::none();
+                };
+                rule.enabled = enabled;
+                if let Err(e) = self.db.save_message_rule(rule) {
+                    self.state.error = Some(format!("Failed to save rule: {}", e));
+                }
+                Command::none()
+            }
+
+            Message::DeleteRule(id) => {
+                if let Err(e) = self.db.delete_message_rule(&id) {
+                    self.state.error = Some(format!("Failed to delete rule: {}", e));
+                    return Command::none();
+                }
+                self.state.message_rules.retain(|r| r.id != id);
+                if self.state.rule_draft.id.as_deref() == Some(id.as_str()) {
+                    self.state.rule_draft = RuleDraft::default();
+                }
+                Command::none()
+            }
+
+            // ============= Client policy =============
+            Message::CapabilitiesFetched(result) => {
+                match result {
+                    Ok(capabilities) => self.state.capabilities = capabilities,
+                    // Keep assuming everything is there rather than hiding features
+                    Err(e) => tracing::warn!("Server capabilities not fetched: {}", e),
+                }
+                Command::none()
+            }
+
+            Message::ClientPolicyFetched(result) => {
+                let signed = match result {
+                    Ok(Some(signed)) => signed,
+                    Ok(None) => return Command::none(),
+                    Err(e) => {
+                        tracing::warn!("Client policy not fetched: {}", e);
+                        return Command::none();
+                    }
+                };
+                let policy = match crate::policy::update(&self.db, self.state.client_policy.as_ref(), &signed) {
+                    Ok(Some(policy)) => policy,
+                    Ok(None) => return Command::none(),
+                    Err(e) => {
+                        tracing::warn!("Client policy rejected: {}", e);
+                        return Command::none();
+                    }
+                };
+                tracing::info!("Applying client policy version {}", policy.version);
+
+                // A new version's defaults replace the user's choices once
+                if let Some(preview) = policy.notification_preview {
+                    self.state.config.notifications.preview = preview == PreviewPolicy::Full;
+                }
+                if let Some(endpoint) = policy.endpoints.first() {
+                    match reqwest::Url::parse(endpoint) {
+                        Ok(url) => {
+                            // Used from the next connection on
+                            let server = &mut self.state.config.server;
+                            server.host = url.host_str().unwrap_or_default().to_string();
+                            server.port = url.port_or_known_default().unwrap_or(server.port);
+                            server.use_tls = url.scheme() == "https";
+                        }
+                        Err(e) => tracing::warn!("Ignoring policy endpoint {}: {}", endpoint, e),
+                    }
+                }
+                self.state.config.save(&self.state.data_dir).ok();
+
+                let command = match policy.last_seen_visibility {
+                    Some(visibility) if visibility != self.state.last_seen_visibility => {
+                        self.set_last_seen_visibility(visibility)
+                    }
+                    _ => Command::none(),
+                };
+                self.state.client_policy = Some(policy);
+                command
+            }
+
+            // ============= Diagnostics =============
+            Message::OpenDiagnostics => {
+                self.state.current_screen = Screen::Diagnostics;
+                self.update(Message::RunDiagnostics)
+            }
+
+            Message::RunDiagnostics => {
+                if self.state.diagnostics_running {
+                    return Command::none();
+                }
+                self.state.diagnostics_running = true;
+                self.state.diagnostics = None;
+
+                let network = self.network.clone();
+                let db = self.db.clone();
+                let config = self.state.config.clone();
+                Command::perform(
+                    async move {
+                        let network = network.read().await;
+                        match network.as_ref() {
+                            Some(client) => crate::diagnostics::run(client, &db).await,
+                            // Not logged in: still check the server and database
+                            None => match NetworkClient::new(&config, db.clone()).await {
+                                Ok(client) => crate::diagnostics::run(&client, &db).await,
+                                Err(e) => crate::diagnostics::DiagnosticsReport {
+                                    generated_at: chrono::Utc::now().timestamp(),
+                                    checks: vec![crate::diagnostics::DiagnosticCheck {
+                                        name: "server",
+                                        status: crate::diagnostics::CheckStatus::Failed,
+                                        detail: e.to_string(),
+                                        duration_ms: 0,
+                                    }],
+                                },
+                            },
+                        }
+                    },
+                    Message::DiagnosticsFinished,
+                )
+            }
+
+            Message::DiagnosticsFinished(report) => {
+                self.state.diagnostics_running = false;
+                self.state.diagnostics = Some(report);
+                Command::none()
+            }
+
+            Message::CopyDiagnostics => {
+                if let Some(report) = &self.state.diagnostics {
+                    // A bug report, not a secret: leave it on the clipboard
+                    if let Err(e) = crate::clipboard::copy(&report.to_text(), None) {
+                        self.state.error = Some(format!("Failed to copy: {}", e));
+                    }
+                }
+                Command::none()
+            }
+
+            // ============= Backups =============
+            Message::BackupTick => {
+                let backup_dir = self.state.config.backup.directory(&self.state.data_dir);
+                if !self.state.config.backup.enabled || !backup::is_due(&backup_dir) {
+                    return Command::none();
+                }
+                self.run_backup()
+            }
+
+            Message::BackupNow => self.run_backup(),
+
+            Message::BackupFinished(result) => {
+                self.state.backup_running = false;
+                match result {
+                    Ok(backups) => {
+                        self.state.backup_status = backups.first().map(|latest| {
+                            format!(
+                                "Latest backup verified ({})",
+                                AppState::format_timestamp(latest.created_at)
+                            )
+                        });
+                        self.state.backups = backups;
+                    }
+                    Err(e) => {
+                        tracing::error!("Backup failed: {}", e);
+                        self.state.backup_status = Some(format!("Backup failed: {}", e));
+                    }
+                }
+                Command::none()
+            }
+
+            Message::AutoBackupChanged(enabled) => {
+                self.state.config.backup.enabled = enabled;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::BackupKeepChanged(keep) => {
+                self.state.config.backup.keep = keep;
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::ChooseBackupDir => Command::perform(
+                async {
+                    rfd::AsyncFileDialog::new()
+                        .set_title("Choose backup folder")
+                        .pick_folder()
+                        .await
+                        .map(|f| f.path().to_path_buf())
+                },
+                |path| match path {
+                    Some(path) => Message::BackupDirChosen(path),
+                    None => Message::Noop,
+                },
+            ),
+
+            Message::BackupDirChosen(dir) => {
+                self.state.backups = backup::list(&dir).unwrap_or_default();
+                self.state.config.backup.directory = Some(dir);
+                self.state.config.save(&self.state.data_dir).ok();
+                Command::none()
+            }
+
+            Message::RestoreBackup(path) => {
+                let Some(key) = self.state.config.backup.key.clone() else {
+                    self.state.error = Some("No backup key in this profile".to_string());
+                    return Command::none();
+                };
+                if self.state.backup_running {
+                    return Command::none();
+                }
+                self.state.backup_running = true;
+                let db = self.db.clone();
+                Command::perform(
+                    async move {
+                        tokio::task::spawn_blocking(move || backup::restore(&db, &path, &key))
+                            .await
+                            .map_err(anyhow::Error::from)
+                            .and_then(|result| result)
+                            .map_err(|e| e.to_string())
+                    },
+                    Message::BackupRestored,
+                )
+            }
+
+            Message::BackupRestored(result) => {
+                self.state.backup_running = false;
+                match result {
+                    Ok(()) => {
+                        self.state.backup_status = Some("Backup restored".to_string());
+                        self.state.current_messages.clear();
+                        self.state.current_chat_peer = None;
+                        self.state.profiles = self
+                            .db
+                            .get_profiles()
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|user| (user.user_id.clone(), user))
+                            .collect();
+                        self.update(Message::LoadConversations)
+                    }
+                    Err(e) => {
+                        self.state.error = Some(format!("Restore failed: {}", e));
+                        Command::none()
+                    }
+                }
+            }
+
+            Message::SessionRevoked(reason) => {
+                tracing::warn!("Session revoked by server: {:?}", reason);
+                self.db.clear_session().ok();
+                self.state.session = None;
+                self.state.conversations.clear();
+                self.state.current_messages.clear();
+                self.state.current_chat_peer = None;
+                self.state.pending_link = None;
+                self.state.is_loading = false;
+                self.state.current_screen = Screen::Login;
+                self.state.error = Some(reason.to_string());
+
+                // The server already closed the socket; just drop the client
+                let network = self.network.clone();
+                Command::perform(
+                    async move {
+                        *network.write().await = None;
+                    },
+                    |_| Message::Noop,
+                )
+            }
+
+            Message::Logout => {
+                self.db.clear_session().ok();
+                self.state.session = None;
+                self.state.conversations.clear();
+                self.state.current_messages.clear();
+                self.state.current_screen = Screen::Login;
 
+                let network = self.network.clone();
                 Command::perform(
                     async move {
-                        // Ask where to save
-                        let path = rfd::AsyncFileDialog::new()
-                            .set_title("Save file as")
-                            .set_file_name(&file_name)
-                            .save_file()
-                            .await
-                            .map(|f| f.path().to_path_buf());
-
-                        if let Some(path) = path {
-                            if let Some(ref client) = *network.read().await {
-                                let data = client.download_file(&file_id).await?;
-                                tokio::fs::write(&path, data).await?;
-                                return Ok(path);
-                            }
+                        if let Some(ref client) = *network.read().await {
+                            client.logout().await.ok();
                         }
-                        Err(anyhow::anyhow!("Download cancelled"))
-                    },
-                    |result| match result {
-                        Ok(path) => Message::FileDownloaded(path),
-                        Err(e) => Message::Error(e.to_string()),
+                        *network.write().await = None;
                     },
+                    |_| Message::Noop,
                 )
             }
 
-            Message::FileDownloaded(path) => {
-                tracing::info!("File downloaded to: {:?}", path);
+            Message::DismissAnnouncement => {
+                if let Some(announcement) = self.state.announcement.take() {
+                    if let Err(e) = self.db.set_setting(DISMISSED_ANNOUNCEMENT_SETTING, &announcement.id) {
+                        tracing::warn!("Failed to remember dismissed announcement: {}", e);
+                    }
+                }
                 Command::none()
             }
 
-            // ============= Settings =============
-            Message::OpenSettings => {
-                self.state.current_screen = Screen::Settings;
+            // ============= Panic button =============
+            Message::ShowPanic => {
+                self.state.panic = Some(PanicConfirm::default());
                 Command::none()
             }
 
-            Message::ThemeChanged(theme) => {
-                self.state.config.ui.theme = theme.clone();
-                self.theme = if theme == "dark" {
-                    Theme::dark()
-                } else {
-                    Theme::light()
-                };
-                self.state.config.save(&self.state.data_dir).ok();
+            Message::CancelPanic => {
+                if !self.state.panic.as_ref().is_some_and(|p| p.running) {
+                    self.state.panic = None;
+                }
                 Command::none()
             }
 
-            Message::NotificationsChanged(enabled) => {
-                self.state.config.notifications.enabled = enabled;
-                self.state.config.save(&self.state.data_dir).ok();
+            Message::PanicKeepKeyToggled(keep) => {
+                if let Some(ref mut panic) = self.state.panic {
+                    panic.keep_identity_key = keep;
+                }
                 Command::none()
             }
 
-            Message::SoundChanged(enabled) => {
-                self.state.config.notifications.sound = enabled;
-                self.state.config.save(&self.state.data_dir).ok();
+            Message::PanicConfirmChanged(input) => {
+                if let Some(ref mut panic) = self.state.panic {
+                    panic.input = input;
+                }
                 Command::none()
             }
 
-            Message::Logout => {
-                self.db.clear_session().ok();
-                self.state.session = None;
-                self.state.conversations.clear();
-                self.state.current_messages.clear();
-                self.state.current_screen = Screen::Login;
+            Message::PanicWipe => {
+                let Some(ref panic) = self.state.panic else {
+                    return Command::none();
+                };
+                if !panic.armed() {
+                    return Command::none();
+                }
+                if !panic.keep_identity_key {
+                    return self.update(Message::PanicWipeTo(None));
+                }
+                Command::perform(
+                    async {
+                        rfd::AsyncFileDialog::new()
+                            .set_title("Save identity key")
+                            .set_file_name("privmsg-identity-key.txt")
+                            .save_file()
+                            .await
+                            .map(|f| f.path().to_path_buf())
+                    },
+                    |path| match path {
+                        Some(path) => Message::PanicWipeTo(Some(path)),
+                        None => Message::Noop,
+                    },
+                )
+            }
 
+            Message::PanicWipeTo(key_path) => {
+                match self.state.panic {
+                    Some(ref mut panic) if panic.armed() => panic.running = true,
+                    _ => return Command::none(),
+                }
+                let identity_key = self
+                    .state
+                    .session
+                    .as_ref()
+                    .and_then(|s| self.db.get_private_key(&s.user_id));
                 let network = self.network.clone();
+                let db = self.db.clone();
+
                 Command::perform(
                     async move {
+                        if let Some(path) = key_path {
+                            let key = identity_key.ok_or("No identity key to save")?;
+                            std::fs::write(&path, key).map_err(|e| e.to_string())?;
+                        }
+                        // The local wipe goes ahead even if the server is unreachable
                         if let Some(ref client) = *network.read().await {
-                            client.logout().await.ok();
+                            if let Err(e) = client.logout_all().await {
+                                tracing::warn!("Ending sessions on the server failed: {}", e);
+                            }
                         }
                         *network.write().await = None;
+                        if let Err(e) = db.wipe() {
+                            tracing::error!("Wiping local data failed: {}", e);
+                        }
+                        Ok(())
                     },
-                    |_| Message::Noop,
+                    Message::PanicWiped,
                 )
             }
 
+            Message::PanicWiped(result) => {
+                if let Err(e) = result {
+                    if let Some(ref mut panic) = self.state.panic {
+                        panic.running = false;
+                    }
+                    return self.update(Message::Error(e));
+                }
+                self.state.session = None;
+                self.state.conversations.clear();
+                self.state.current_messages.clear();
+                self.state.profiles.clear();
+                iced::window::close(iced::window::Id::MAIN)
+            }
+
             // ============= UI Toggles =============
             Message::ToggleSearch => {
                 self.state.show_search = !self.state.show_search;
@@ -704,31 +2683,83 @@ impl Application for PrivMsg {
 
             Message::Noop => Command::none(),
 
+            Message::FocusNext => iced::widget::focus_next(),
+            Message::FocusPrevious => iced::widget::focus_previous(),
+
             Message::WebSocketEvent(event) => {
                 // Handle WebSocket events
                 match event {
                     crate::network::WsEvent::Connected => {
                         tracing::info!("WebSocket connected");
                     }
+                    crate::network::WsEvent::SessionRevoked(reason) => {
+                        return self.update(Message::SessionRevoked(reason));
+                    }
+                    crate::network::WsEvent::Acknowledged(message_ids) => {
+                        let now = chrono::Utc::now().timestamp_millis();
+                        if let Err(e) = self.db.set_acked(&message_ids, now) {
+                            tracing::warn!("Failed to store acks: {}", e);
+                        }
+                    }
+                    crate::network::WsEvent::Delivered(message_ids) => match self.db.mark_delivered(&message_ids) {
+                        Ok(delivered) => {
+                            for message_id in delivered {
+                                self.state
+                                    .current_messages
+                                    .patch(&message_id, |m| m.status = MessageStatus::Delivered);
+                            }
+                        }
+                        Err(e) => tracing::warn!("Failed to store delivery receipts: {}", e),
+                    },
+                    crate::network::WsEvent::Announcement(announcement) => {
+                        self.show_announcement(announcement);
+                    }
+                    crate::network::WsEvent::Maintenance(maintenance) => {
+                        self.state.capabilities.maintenance = maintenance;
+                    }
+                    crate::network::WsEvent::Error(error) => {
+                        let rejected = match error.message_id {
+                            Some(ref id) if error.code == ErrorCode::RecipientUnavailable => self
+                                .state
+                                .current_messages
+                                .get(id)
+                                .map(|m| m.conversation_id.clone())
+                                .or_else(|| self.db.get_message_conversation(id))
+                                .map(|peer_id| (id.clone(), peer_id)),
+                            _ => None,
+                        };
+                        match rejected {
+                            Some((message_id, peer_id)) => {
+                                if let Err(e) = self.db.update_message_status(&message_id, MessageStatus::Failed) {
+                                    tracing::warn!("Failed to store status of {}: {}", message_id, e);
+                                }
+                                self.state
+                                    .current_messages
+                                    .patch(&message_id, |m| m.status = MessageStatus::Failed);
+                                self.set_peer_available(&peer_id, false);
+                            }
+                            None => tracing::warn!("Server error: {}", error),
+                        }
+                    }
+                    crate::network::WsEvent::DeviceRenamed { device_id, device_name } => {
+                        if let Some(device) =
+                            self.state.devices.iter_mut().find(|d| d.device_id == device_id)
+                        {
+                            device.device_name = device_name;
+                        }
+                    }
                     crate::network::WsEvent::Disconnected => {
                         tracing::warn!("WebSocket disconnected");
-                        self.state.error = Some("Connection lost. Reconnecting...".to_string());
+                        // A revoked session closes the socket too; keep its message
+                        if self.state.session.is_some() {
+                            self.state.error = Some("Connection lost. Reconnecting...".to_string());
+                        }
                     }
                     crate::network::WsEvent::Message(envelope) => {
-                        // Decrypt and process message
-                        // This is simplified - actual implementation would decrypt
-                        let msg = crate::state::ChatMessage {
-                            message_id: envelope.message_id,
-                            conversation_id: envelope.sender_id.clone(),
-                            sender_id: envelope.sender_id,
-                            message_type: crate::state::MessageType::Text,
-                            content: "Encrypted message".to_string(), // Would be decrypted
-                            timestamp: envelope.timestamp,
-                            status: crate::state::MessageStatus::Delivered,
-                            attachment: None,
-                            is_outgoing: false,
-                        };
-                        return self.update(Message::MessageReceived(msg));
+                        return self.receive_envelopes(vec![envelope]);
+                    }
+                    crate::network::WsEvent::MessageBatch(envelopes) => {
+                        return self.receive_envelopes(envelopes);
                     }
                     crate::network::WsEvent::CallSignal(signal) => {
                         // Handle call signaling
@@ -754,11 +2785,17 @@ impl Application for PrivMsg {
     }
 
     fn view(&self) -> Element<Self::Message> {
+        if self.state.locked {
+            return LockScreen::view(&self.state);
+        }
+
         let content: Element<Self::Message> = match &self.state.current_screen {
             Screen::Login => LoginScreen::view(&self.state).into(),
             Screen::Home => HomeScreen::view(&self.state).into(),
             Screen::Chat(peer_id) => ChatScreen::view(&self.state, peer_id).into(),
             Screen::Settings => SettingsScreen::view(&self.state).into(),
+            Screen::Diagnostics => DiagnosticsScreen::view(&self.state),
+            Screen::Rules => RulesScreen::view(&self.state),
             Screen::Call(peer_id) => CallScreen::view(&self.state, peer_id).into(),
         };
 
@@ -783,6 +2820,25 @@ impl Application for PrivMsg {
             content
         };
 
+        let now = chrono::Utc::now().timestamp();
+        let content = match self.state.announcement {
+            Some(ref announcement) if self.state.session.is_some() && announcement.is_active(now) => {
+                column![announcement_banner(announcement), content].into()
+            }
+            _ => content,
+        };
+        let content = if self.state.session.is_some() && !self.state.config.server.use_tls {
+            column![insecure_banner(), content].into()
+        } else {
+            content
+        };
+        let content = match self.state.capabilities.maintenance {
+            Some(ref maintenance) if self.state.session.is_some() => {
+                column![maintenance_banner(maintenance), content].into()
+            }
+            _ => content,
+        };
+
         container(content)
             .width(Length::Fill)
             .height(Length::Fill)
@@ -790,10 +2846,69 @@ impl Application for PrivMsg {
     }
 
     fn subscription(&self) -> Subscription<Self::Message> {
-        let subscriptions = vec![
-            // Tick every second for call duration
-            iced::time::every(std::time::Duration::from_secs(1)).map(|_| Message::Tick),
-        ];
+        let on_battery = self.state.power_source == PowerSource::Battery;
+        let mut subscriptions = vec![power::monitor().map(Message::PowerSourceChanged)];
+
+        // Tick every second for call duration; on battery only during a call
+        if !on_battery || self.state.call_state == Some(crate::state::CallState::Connected) {
+            subscriptions.push(
+                iced::time::every(std::time::Duration::from_secs(1)).map(|_| Message::Tick),
+            );
+        }
+
+        // Redraw download and data export progress bars
+        if self.state.downloads.iter().any(|d| !d.paused) || self.state.data_export.is_some() {
+            subscriptions.push(
+                iced::time::every(std::time::Duration::from_millis(250))
+                    .map(|_| Message::DownloadProgressTick),
+            );
+        }
+
+        // Smooth waveform progress while a voice message plays
+        if self
+            .state
+            .voice_playback
+            .as_ref()
+            .is_some_and(|p| p.is_playing())
+        {
+            subscriptions.push(
+                iced::time::every(std::time::Duration::from_millis(100))
+                    .map(|_| Message::VoicePlaybackTick),
+            );
+        }
+
+        if let Some(instance) = &self.instance {
+            subscriptions.push(instance.activations().map(Message::Activated));
+        }
+
+        // Checks whether a scheduled backup is due; on battery it waits
+        if self.state.config.backup.enabled && !on_battery {
+            subscriptions.push(
+                iced::time::every(std::time::Duration::from_secs(60 * 60))
+                    .map(|_| Message::BackupTick),
+            );
+        }
+
+        // Refetches peer profiles a batch at a time; on battery it waits
+        if self.state.session.is_some() && !on_battery {
+            subscriptions.push(
+                iced::time::every(std::time::Duration::from_secs(5 * 60))
+                    .map(|_| Message::ProfileRefreshTick),
+            );
+        }
+
+        let security = &self.state.config.security;
+        if security.passcode_hash.is_some() && security.lock_on_suspend {
+            subscriptions.push(applock::os_events().map(Message::OsSession));
+        }
+
+        subscriptions.push(iced::keyboard::on_key_press(|key, modifiers| match key {
+            iced::keyboard::Key::Named(iced::keyboard::key::Named::Tab) if modifiers.shift() => {
+                Some(Message::FocusPrevious)
+            }
+            iced::keyboard::Key::Named(iced::keyboard::key::Named::Tab) => Some(Message::FocusNext),
+            _ => None,
+        }));
 
         // WebSocket subscription would go here
         // In a real implementation, this would subscribe to WebSocket events
@@ -811,20 +2926,554 @@ impl Application for PrivMsg {
 }
 
 impl PrivMsg {
-    fn show_notification(&self, msg: &crate::state::ChatMessage) {
-        let sender = msg.sender_id.clone();
-        let body = if self.state.config.notifications.preview {
-            msg.content.clone()
+    fn load_devices(&self) -> Command<Message> {
+        let network = self.network.clone();
+        Command::perform(
+            async move {
+                match *network.read().await {
+                    Some(ref client) => client.list_devices().await,
+                    None => Err(anyhow::anyhow!("Not connected")),
+                }
+            },
+            |result| match result {
+                Ok(devices) => Message::DevicesLoaded(devices),
+                Err(e) => {
+                    tracing::debug!("Device list failed: {}", e);
+                    Message::Noop
+                }
+            },
+        )
+    }
+
+    /// Delete uploads that no message refers to
+    fn discard_uploads(&self, attachments: Vec<Attachment>) -> Command<Message> {
+        if attachments.is_empty() {
+            return Command::none();
+        }
+        let network = self.network.clone();
+        Command::perform(
+            async move {
+                if let Some(ref client) = *network.read().await {
+                    for attachment in attachments {
+                        if let Err(e) = client.delete_file(&attachment.file_id).await {
+                            tracing::warn!("Failed to delete upload {}: {}", attachment.file_id, e);
+                        }
+                    }
+                }
+            },
+            |_| Message::Noop,
+        )
+    }
+
+    /// Show the server's announcement unless the user dismissed it
+    fn show_announcement(&mut self, announcement: Option<Announcement>) {
+        let dismissed = self.db.get_setting(DISMISSED_ANNOUNCEMENT_SETTING);
+        self.state.announcement =
+            announcement.filter(|a| dismissed.as_deref() != Some(a.id.as_str()));
+    }
+
+    /// Fetch a user's profile; failures keep the cached one
+    fn refresh_profile(&self, user_id: String) -> Command<Message> {
+        let network = self.network.clone();
+        Command::perform(
+            async move {
+                match *network.read().await {
+                    Some(ref client) => client.find_user(&user_id).await,
+                    None => Err(anyhow::anyhow!("Not connected")),
+                }
+            },
+            |result| match result {
+                Ok(user) => Message::ProfileLoaded(user),
+                Err(e) => {
+                    tracing::debug!("Profile refresh failed: {}", e);
+                    Message::Noop
+                }
+            },
+        )
+    }
+
+    /// Fetch the profiles of peers not requested within `min_age` seconds
+    fn refresh_profiles(&mut self, peer_ids: Vec<String>, min_age: i64) -> Command<Message> {
+        let now = chrono::Utc::now().timestamp();
+        let mut commands = Vec::new();
+        for peer_id in peer_ids {
+            let recent = self
+                .state
+                .profiles_refreshed
+                .get(&peer_id)
+                .is_some_and(|at| now - at < min_age);
+            if recent {
+                continue;
+            }
+            self.state.profiles_refreshed.insert(peer_id.clone(), now);
+            commands.push(self.refresh_profile(peer_id));
+        }
+        Command::batch(commands)
+    }
+
+    /// Send a text message, retrying as configured, and store it as sent
+    /// or failed
+    /// Note whether the server still has `peer_id`'s account; without one
+    /// their chat is read-only
+    fn set_peer_available(&mut self, peer_id: &str, available: bool) {
+        let changed = if available {
+            self.state.unavailable_peers.remove(peer_id)
         } else {
-            "New message".to_string()
+            self.state.unavailable_peers.insert(peer_id.to_string())
+        };
+        if changed {
+            if let Err(e) = self.db.set_peer_unavailable(peer_id, !available) {
+                tracing::warn!("Failed to store availability of {}: {}", peer_id, e);
+            }
+        }
+    }
+
+    fn deliver_message(&self, mut msg: ChatMessage) -> Command<Message> {
+        let network = self.network.clone();
+        let db = self.db.clone();
+        let retries = self.state.config.delivery.auto_retries;
+
+        Command::perform(
+            async move {
+                let mut attempt = 0;
+                let result = loop {
+                    let result = match *network.read().await {
+                        Some(ref client) => client.deliver_message(&msg).await,
+                        None => Err(anyhow::anyhow!("Not connected")),
+                    };
+                    let final_error = result
+                        .as_ref()
+                        .is_err_and(|e| e.is::<PeerUnavailable>() || e.is::<KeyConflict>());
+                    if result.is_ok() || final_error || attempt >= retries {
+                        break result;
+                    }
+                    tokio::time::sleep(SEND_RETRY_BACKOFF * 2u32.pow(attempt.min(5))).await;
+                    attempt += 1;
+                };
+
+                msg.status = match result {
+                    Ok(()) => MessageStatus::Sent,
+                    Err(_) => MessageStatus::Failed,
+                };
+                if let Err(e) = db.save_message(&msg) {
+                    tracing::warn!("Failed to store message {}: {}", msg.message_id, e);
+                }
+                if let Err(e) = db.record_delivery(&msg.message_id, attempt + 1, result.is_ok()) {
+                    tracing::warn!("Failed to store delivery of {}: {}", msg.message_id, e);
+                }
+                match result {
+                    Ok(()) => Message::MessageSent(msg),
+                    Err(e) if e.is::<PeerUnavailable>() => Message::RecipientUnavailable(msg),
+                    Err(e) if e.is::<KeyConflict>() => Message::PeerKeyChanged(msg),
+                    Err(e) => Message::MessageFailed(msg, e.to_string()),
+                }
+            },
+            |message| message,
+        )
+    }
+
+    /// Back up the database in the background, then verify the new backup
+    fn run_backup(&mut self) -> Command<Message> {
+        if self.state.backup_running {
+            return Command::none();
+        }
+        let key = match self.state.config.backup.key.clone() {
+            Some(key) => key,
+            None => match backup::generate_key() {
+                Ok(key) => {
+                    self.state.config.backup.key = Some(key.clone());
+                    self.state.config.save(&self.state.data_dir).ok();
+                    key
+                }
+                Err(e) => {
+                    self.state.backup_status = Some(format!("Backup failed: {}", e));
+                    return Command::none();
+                }
+            },
+        };
+        self.state.backup_running = true;
+
+        let db = self.db.clone();
+        let dir = self.state.config.backup.directory(&self.state.data_dir);
+        let keep = self.state.config.backup.keep;
+        let scratch = self.state.data_dir.join("backup-verify.tmp");
+        Command::perform(
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    let created = backup::create(&db, &key, &dir, keep)?;
+                    backup::verify(&created.path, &key, &scratch)?;
+                    backup::list(&dir)
+                })
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result)
+                .map_err(|e| e.to_string())
+            },
+            Message::BackupFinished,
+        )
+    }
+
+    /// Store incoming envelopes in one transaction, ack them in one frame and
+    /// add them to the UI
+    fn receive_envelopes(
+        &mut self,
+        envelopes: Vec<crate::network::MessageEnvelope>,
+    ) -> Command<Message> {
+        // Decryption is simplified - actual implementation would decrypt
+        let messages: Vec<crate::state::ChatMessage> = envelopes
+            .into_iter()
+            .map(|envelope| crate::state::ChatMessage {
+                message_id: envelope.message_id,
+                conversation_id: envelope.sender_id.clone(),
+                sender_id: envelope.sender_id,
+                message_type: crate::state::MessageType::Text,
+                content: "Encrypted message".to_string(), // Would be decrypted
+                timestamp: envelope.timestamp,
+                status: crate::state::MessageStatus::Delivered,
+                attachment: None,
+                caption: None,
+                is_outgoing: false,
+                system_event: None,
+                sender_device_id: None,
+                quote: None,
+                is_starred: false,
+            })
+            .collect();
+
+        if let Err(e) = self.db.save_messages(&messages) {
+            tracing::error!("Failed to store incoming messages: {}", e);
+            return Command::none();
+        }
+
+        let ids: Vec<String> = messages.iter().map(|m| m.message_id.clone()).collect();
+        let network = self.network.clone();
+        let ack = Command::perform(
+            async move {
+                if let Some(ref client) = *network.read().await {
+                    client.send_ack(&ids)
+                } else {
+                    Ok(())
+                }
+            },
+            |result| match result {
+                Ok(()) => Message::Noop,
+                Err(e) => Message::Error(e.to_string()),
+            },
+        );
+
+        // Keep conversation titles current with the senders' profiles
+        let senders: Vec<String> = messages.iter().map(|m| m.sender_id.clone()).collect();
+        let mut commands = vec![ack, self.refresh_profiles(senders, PROFILE_RECEIPT_REFRESH)];
+        for msg in messages {
+            commands.push(self.update(Message::MessageReceived(msg)));
+        }
+        Command::batch(commands)
+    }
+
+    fn voice_duration_ms(&self, message_id: &str) -> i64 {
+        self.state
+            .current_messages
+            .get(message_id)
+            .and_then(|m| m.attachment.as_ref())
+            .and_then(|a| a.duration_ms)
+            .unwrap_or(0)
+    }
+
+    /// Download and decrypt a voice message, then play it
+    fn load_voice(&mut self, message_id: String) -> Command<Message> {
+        let Some(attachment) = self
+            .state
+            .current_messages
+            .get(&message_id)
+            .and_then(|m| m.attachment.clone())
+        else {
+            return Command::none();
+        };
+        let network = self.network.clone();
+
+        Command::perform(
+            async move {
+                if let Some(ref client) = *network.read().await {
+                    return client.download_attachment(&attachment).await;
+                }
+                Err(anyhow::anyhow!("Not connected"))
+            },
+            move |result| match result {
+                Ok(audio) => Message::VoiceLoaded(message_id, audio),
+                Err(e) => Message::Error(e.to_string()),
+            },
+        )
+    }
+
+    /// Start or continue saving an attachment from `AppState::downloads`
+    fn run_download(&self, file_id: &str) -> Command<Message> {
+        let Some(download) = self
+            .state
+            .downloads
+            .iter()
+            .find(|d| d.attachment.file_id == file_id)
+            .cloned()
+        else {
+            return Command::none();
+        };
+        let network = self.network.clone();
+        let file_id = file_id.to_string();
+
+        Command::perform(
+            async move {
+                if let Some(ref client) = *network.read().await {
+                    return client
+                        .download_attachment_to(&download.attachment, &download.dest, &download.control)
+                        .await;
+                }
+                Err(anyhow::anyhow!("Not connected"))
+            },
+            move |result| Message::DownloadFinished(file_id, result.map_err(|e| e.to_string())),
+        )
+    }
+
+    /// Play the loaded voice audio from the stored position
+    fn start_voice_playback(&mut self, message_id: &str) -> Command<Message> {
+        let Some((_, ref audio)) = self.voice_audio else {
+            return Command::none();
+        };
+
+        let duration_ms = self.voice_duration_ms(message_id);
+        let position = self
+            .state
+            .voice_playback
+            .as_ref()
+            .filter(|p| p.message_id == message_id && p.position < 1.0)
+            .map(|p| p.position)
+            .unwrap_or(0.0);
+        let start = std::time::Duration::from_millis((duration_ms as f32 * position) as u64);
+
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        match VoicePlayer::play(audio.clone(), start, move || {
+            let _ = done_tx.send(());
+        }) {
+            Ok(player) => self.voice_player = Some(player),
+            Err(e) => {
+                self.state.error = Some(format!("Cannot play voice message: {}", e));
+                return Command::none();
+            }
+        }
+
+        self.state.voice_playback = Some(VoicePlayback {
+            message_id: message_id.to_string(),
+            duration_ms,
+            position,
+            playing_since: Some((chrono::Utc::now().timestamp_millis(), position)),
+        });
+
+        let message_id = message_id.to_string();
+        Command::perform(done_rx, move |result| match result {
+            Ok(()) => Message::VoicePlaybackFinished(message_id),
+            Err(_) => Message::Noop,
+        })
+    }
+
+    /// A highlighted message notifies even in a muted conversation
+    fn show_notification(&self, msg: &ChatMessage, highlight: bool) {
+        let muted = self
+            .state
+            .conversations
+            .iter()
+            .any(|c| c.peer_id == msg.conversation_id && c.is_muted);
+        if muted && !highlight {
+            return;
+        }
+
+        let notification = Notification::message(
+            &msg.conversation_id,
+            &msg.sender_id,
+            self.peer_name(&msg.sender_id),
+            &notifications::readable_text(msg),
+            self.state.preview_policy(),
+        );
+        let notification = if highlight { notification.highlighted() } else { notification };
+        self.notifier.notify(&notification);
+    }
+
+    /// What the message rules do with an incoming message
+    fn rule_actions(&self, msg: &ChatMessage) -> Vec<RuleAction> {
+        if msg.is_outgoing || msg.message_type == MessageType::System {
+            return Vec::new();
+        }
+        let text = msg.caption.as_deref().unwrap_or(&msg.content);
+        matching_actions(&self.state.message_rules, &msg.sender_id, msg.message_type.as_str(), text)
+    }
+
+    /// Apply rule actions to a conversation and persist them
+    fn apply_rule_actions(&self, conv: &mut Conversation, actions: &[RuleAction]) {
+        let peer_ids = std::slice::from_ref(&conv.peer_id);
+        for action in actions {
+            let result = match action {
+                RuleAction::Mute => {
+                    conv.is_muted = true;
+                    self.db.set_conversations_muted(peer_ids, true)
+                }
+                RuleAction::Archive => {
+                    conv.is_archived = true;
+                    self.db.set_conversations_archived(peer_ids, true)
+                }
+                RuleAction::MarkRead => {
+                    conv.unread_count = 0;
+                    self.db.mark_conversations_read(peer_ids)
+                }
+                RuleAction::Highlight => Ok(()),
+            };
+            if let Err(e) = result {
+                tracing::warn!("Rule action {:?} on {} failed: {}", action, conv.peer_id, e);
+            }
+        }
+    }
+
+    /// End multi-select, returning the selected peer ids
+    /// Read one page of the conversation list for the current load
+    fn load_conversation_page(&self, offset: usize) -> Command<Message> {
+        let db = self.db.clone();
+        let load = self.state.conversation_load;
+        Command::perform(
+            async move { db.get_conversation_summaries(CONVERSATION_PAGE as i64, offset as i64) },
+            move |result| match result {
+                Ok(conversations) => Message::ConversationPageLoaded(load, offset, conversations),
+                Err(e) => Message::Error(e.to_string()),
+            },
+        )
+    }
+
+    fn take_selection(&mut self) -> Vec<String> {
+        self.state.selecting_conversations = false;
+        self.state.selected_conversations.drain().collect()
+    }
+
+    /// The picked messages of the open chat in chat order, leaving
+    /// selection mode
+    fn take_message_selection(&mut self) -> Vec<ChatMessage> {
+        self.state.selecting_messages = false;
+        let ids: HashSet<String> = self.state.selected_messages.drain().collect();
+        self.state
+            .current_messages
+            .iter()
+            .filter(|m| ids.contains(&m.message_id))
+            .cloned()
+            .collect()
+    }
+
+    fn set_last_seen_visibility(&mut self, visibility: LastSeenVisibility) -> Command<Message> {
+        self.state.last_seen_visibility = visibility;
+        let network = self.network.clone();
+
+        Command::perform(
+            async move {
+                if let Some(ref client) = *network.read().await {
+                    client.set_last_seen_visibility(visibility).await
+                } else {
+                    Err(anyhow::anyhow!("Not connected"))
+                }
+            },
+            |result| match result {
+                Ok(user) => Message::ProfileSaved(user),
+                Err(e) => Message::Error(e.to_string()),
+            },
+        )
+    }
+
+    /// Recompute statistics for the open chat while its details are shown
+    /// Put `text` on the clipboard, to be cleared as set in settings
+    fn copy_to_clipboard(&mut self, text: &str) {
+        let clear_after = ClipboardClear(self.state.config.security.clipboard_clear_secs).duration();
+        if let Err(e) = clipboard::copy(text, clear_after) {
+            self.state.error = Some(format!("Failed to copy: {}", e));
+        }
+    }
+
+    fn refresh_conversation_stats(&mut self) {
+        self.state.conversation_stats = None;
+        if !self.state.config.analytics.enabled || !self.state.show_chat_details {
+            return;
+        }
+        let Some(ref peer_id) = self.state.current_chat_peer else {
+            return;
         };
+        match self.db.message_samples(peer_id) {
+            Ok(samples) => {
+                let offset = chrono::Local::now().offset().local_minus_utc();
+                self.state.conversation_stats =
+                    Some(ConversationStats::compute(&samples, offset as i64));
+            }
+            Err(e) => tracing::warn!("Failed to load message times: {}", e),
+        }
+    }
+
+    fn peer_name(&self, peer_id: &str) -> Option<&str> {
+        self.state
+            .conversations
+            .iter()
+            .find(|c| c.peer_id == peer_id)
+            .and_then(|c| c.peer_name.as_deref())
+    }
+}
+
+/// The server's announcement across the top of every screen
+fn announcement_banner(announcement: &Announcement) -> Element<'static, Message> {
+    let color = match announcement.severity {
+        AnnouncementSeverity::Critical => iced::Color::from_rgb(0.9, 0.3, 0.3),
+        AnnouncementSeverity::Warning => iced::Color::from_rgb(0.9, 0.6, 0.2),
+        AnnouncementSeverity::Info | AnnouncementSeverity::Unknown => {
+            iced::Color::from_rgb(0.4, 0.6, 0.9)
+        }
+    };
+    container(
+        row![
+            text(announcement.text.clone())
+                .style(iced::theme::Text::Color(color))
+                .width(Length::Fill),
+            iced::widget::button(text("X"))
+                .on_press(Message::DismissAnnouncement)
+                .style(iced::theme::Button::Text)
+        ]
+        .spacing(10),
+    )
+    .padding(10)
+    .width(Length::Fill)
+    .style(iced::theme::Container::Box)
+    .into()
+}
 
-        notify_rust::Notification::new()
-            .summary(&format!("Message from {}", sender))
-            .body(&body)
-            .show()
-            .ok();
+/// Shown for as long as the server is in maintenance mode; not dismissible
+fn maintenance_banner(maintenance: &Maintenance) -> Element<'static, Message> {
+    let mut notice = match maintenance.ends_at {
+        Some(ends_at) => format!(
+            "Server maintenance until {}.",
+            AppState::format_timestamp(ends_at * 1000)
+        ),
+        None => "Server maintenance in progress.".to_string(),
+    };
+    if !maintenance.relay_messages {
+        notice.push_str(" Messages are held until it is over.");
+    }
+    if let Some(ref message) = maintenance.message {
+        notice.push(' ');
+        notice.push_str(message);
     }
+    container(text(notice).style(iced::theme::Text::Color(iced::Color::from_rgb(0.9, 0.6, 0.2))))
+        .padding(10)
+        .width(Length::Fill)
+        .style(iced::theme::Container::Box)
+        .into()
+}
+
+/// Shown for as long as the connection is without TLS; not dismissible
+fn insecure_banner() -> Element<'static, Message> {
+    let notice = "Unencrypted connection to the server. Messages stay end-to-end encrypted, \
+                  but your login and metadata can be read on the network.";
+    container(text(notice).style(iced::theme::Text::Color(iced::Color::from_rgb(0.9, 0.3, 0.3))))
+        .padding(10)
+        .width(Length::Fill)
+        .style(iced::theme::Container::Box)
+        .into()
 }
 
 struct ErrorContainer;
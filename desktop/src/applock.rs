@@ -0,0 +1,151 @@
+//! App lock
+//!
+//! Once a passcode is set the app can be locked, which hides everything
+//! behind a passcode prompt until it is entered again. Besides locking by
+//! hand, the app locks itself when the machine wakes from sleep or the OS
+//! session is locked. Sleep shows up as a gap in wall-clock time between
+//! two polls; whether the session is locked is asked of the OS where that
+//! is possible.
+
+use iced::Subscription;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+
+/// Rounds of SHA-256 over the salted passcode, to slow down guessing
+/// from a copied config file
+const HASH_ROUNDS: u32 = 100_000;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A wall-clock gap this much longer than the poll interval means the
+/// process wasn't running, i.e. the machine was asleep
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsEvent {
+    /// The machine woke up from sleep
+    Resumed,
+    /// The OS session was locked
+    SessionLocked,
+}
+
+fn derive(salt: &[u8], passcode: &str) -> [u8; 32] {
+    let mut digest: [u8; 32] = Sha256::new()
+        .chain_update(salt)
+        .chain_update(passcode.as_bytes())
+        .finalize()
+        .into();
+    for _ in 1..HASH_ROUNDS {
+        digest = Sha256::digest(digest).into();
+    }
+    digest
+}
+
+/// Salted hash of `passcode` for the config file, as `salt:hash` in hex
+pub fn hash_passcode(passcode: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    format!("{}:{}", hex::encode(salt), hex::encode(derive(&salt, passcode)))
+}
+
+pub fn verify_passcode(passcode: &str, stored: &str) -> bool {
+    let Some((salt, hash)) = stored.split_once(':') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (hex::decode(salt), hex::decode(hash)) else {
+        return false;
+    };
+
+    // Compare without returning early on the first differing byte
+    let derived = derive(&salt, passcode);
+    hash.len() == derived.len() && hash.iter().zip(derived).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Sleep and session lock notifications
+pub fn os_events() -> Subscription<OsEvent> {
+    iced::subscription::channel("os-events", 4, |mut output| async move {
+        use futures::SinkExt;
+
+        let mut last_poll = SystemTime::now();
+        let mut was_locked = false;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            let now = SystemTime::now();
+            let gap = now.duration_since(last_poll).unwrap_or_default();
+            last_poll = now;
+            if gap > POLL_INTERVAL + SUSPEND_THRESHOLD {
+                tracing::info!("Resumed after {}s asleep", gap.as_secs());
+                let _ = output.send(OsEvent::Resumed).await;
+            }
+
+            let locked = platform::is_session_locked().await.unwrap_or(false);
+            if locked && !was_locked {
+                let _ = output.send(OsEvent::SessionLocked).await;
+            }
+            was_locked = locked;
+        }
+    })
+}
+
+// ============= Linux =============
+
+#[cfg(target_os = "linux")]
+mod platform {
+    /// logind's LockedHint, which screen lockers set on the session
+    pub async fn is_session_locked() -> Option<bool> {
+        let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".to_string());
+        let output = tokio::process::Command::new("loginctl")
+            .args(["show-session", &session, "-p", "LockedHint", "--value"])
+            .output()
+            .await
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).trim() == "yes")
+    }
+}
+
+// ============= Windows =============
+
+#[cfg(windows)]
+mod platform {
+    use winapi::um::winuser::{CloseDesktop, OpenInputDesktop, DESKTOP_SWITCHDESKTOP};
+
+    /// The input desktop can't be opened while the lock screen owns it
+    pub async fn is_session_locked() -> Option<bool> {
+        unsafe {
+            let desktop = OpenInputDesktop(0, 0, DESKTOP_SWITCHDESKTOP);
+            if desktop.is_null() {
+                return Some(true);
+            }
+            CloseDesktop(desktop);
+        }
+        Some(false)
+    }
+}
+
+// ============= Other platforms =============
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    pub async fn is_session_locked() -> Option<bool> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passcode_hash() {
+        let stored = hash_passcode("2468");
+        assert!(verify_passcode("2468", &stored));
+        assert!(!verify_passcode("2469", &stored));
+        assert!(!verify_passcode("2468", "not-a-hash"));
+
+        // Salted, so the same passcode hashes differently each time
+        assert_ne!(hash_passcode("2468"), stored);
+    }
+}
@@ -0,0 +1,271 @@
+//! Voice recording and playback for PrivMsg Desktop
+
+use anyhow::Result;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use parking_lot::Mutex;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of amplitude buckets sent with every voice message
+pub const WAVEFORM_BUCKETS: usize = 64;
+
+// ============= Recording =============
+
+/// Captures mono audio from the default input device until finished
+pub struct VoiceRecorder {
+    stream: cpal::Stream,
+    samples: Arc<Mutex<Vec<f32>>>,
+    sample_rate: u32,
+}
+
+impl VoiceRecorder {
+    pub fn start() -> Result<Self> {
+        let host = cpal::default_host();
+        let device = host
+            .default_input_device()
+            .ok_or_else(|| anyhow::anyhow!("No microphone found"))?;
+        let config = device.default_input_config()?;
+        let sample_rate = config.sample_rate().0;
+        let channels = config.channels() as usize;
+
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let err_fn = |e| tracing::error!("Recording error: {}", e);
+
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => {
+                let samples = samples.clone();
+                device.build_input_stream(
+                    &config.into(),
+                    move |data: &[f32], _| push_mono(&samples, data, channels, |s| s),
+                    err_fn,
+                    None,
+                )?
+            }
+            cpal::SampleFormat::I16 => {
+                let samples = samples.clone();
+                device.build_input_stream(
+                    &config.into(),
+                    move |data: &[i16], _| {
+                        push_mono(&samples, data, channels, |s| s as f32 / i16::MAX as f32)
+                    },
+                    err_fn,
+                    None,
+                )?
+            }
+            cpal::SampleFormat::U16 => {
+                let samples = samples.clone();
+                device.build_input_stream(
+                    &config.into(),
+                    move |data: &[u16], _| {
+                        push_mono(&samples, data, channels, |s| {
+                            (s as f32 - 32768.0) / 32768.0
+                        })
+                    },
+                    err_fn,
+                    None,
+                )?
+            }
+            format => return Err(anyhow::anyhow!("Unsupported sample format: {}", format)),
+        };
+        stream.play()?;
+
+        Ok(Self {
+            stream,
+            samples,
+            sample_rate,
+        })
+    }
+
+    /// Stop recording and return what was captured
+    pub fn finish(self) -> RecordedAudio {
+        drop(self.stream);
+        let samples = std::mem::take(&mut *self.samples.lock());
+
+        RecordedAudio {
+            samples,
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+fn push_mono<T: Copy>(
+    samples: &Mutex<Vec<f32>>,
+    data: &[T],
+    channels: usize,
+    to_f32: impl Fn(T) -> f32,
+) {
+    let channels = channels.max(1);
+    let mut samples = samples.lock();
+    samples.extend(
+        data.chunks(channels)
+            .map(|frame| frame.iter().map(|&s| to_f32(s)).sum::<f32>() / frame.len() as f32),
+    );
+}
+
+/// Mono PCM captured by `VoiceRecorder`
+pub struct RecordedAudio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+impl RecordedAudio {
+    pub fn duration_ms(&self) -> i64 {
+        if self.sample_rate == 0 {
+            return 0;
+        }
+        self.samples.len() as i64 * 1000 / self.sample_rate as i64
+    }
+
+    pub fn waveform(&self) -> Vec<u8> {
+        waveform(&self.samples, WAVEFORM_BUCKETS)
+    }
+
+    /// Encode as a 16-bit mono WAV file
+    pub fn to_wav(&self) -> Vec<u8> {
+        let data_len = (self.samples.len() * 2) as u32;
+        let mut wav = Vec::with_capacity(44 + data_len as usize);
+
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+
+        // fmt chunk: PCM, 1 channel, 16 bits per sample
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&self.sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(self.sample_rate * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        for sample in &self.samples {
+            let s = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            wav.extend_from_slice(&s.to_le_bytes());
+        }
+
+        wav
+    }
+}
+
+/// Reduce samples to `buckets` peak amplitudes scaled to 0-255
+///
+/// Peaks are normalized to the loudest bucket so quiet recordings still
+/// draw a readable shape.
+pub fn waveform(samples: &[f32], buckets: usize) -> Vec<u8> {
+    if samples.is_empty() || buckets == 0 {
+        return vec![0; buckets];
+    }
+
+    let peaks: Vec<f32> = (0..buckets)
+        .map(|i| {
+            let start = i * samples.len() / buckets;
+            let end = ((i + 1) * samples.len() / buckets).max(start + 1).min(samples.len());
+            samples[start.min(samples.len() - 1)..end]
+                .iter()
+                .fold(0.0f32, |peak, s| peak.max(s.abs()))
+        })
+        .collect();
+
+    let max = peaks.iter().cloned().fold(0.0f32, f32::max);
+    if max <= f32::EPSILON {
+        return vec![0; buckets];
+    }
+
+    peaks
+        .iter()
+        .map(|p| ((p / max) * 255.0).round() as u8)
+        .collect()
+}
+
+// ============= Playback =============
+
+/// Plays a decoded voice message on a background thread
+pub struct VoicePlayer {
+    stop: Arc<AtomicBool>,
+}
+
+impl VoicePlayer {
+    /// Start playing `data` from `start`. `on_finish` runs when playback ends
+    /// on its own or fails, not when stopped.
+    pub fn play(
+        data: Vec<u8>,
+        start: Duration,
+        on_finish: impl FnOnce() + Send + 'static,
+    ) -> Result<Self> {
+        // Validate the audio up front so errors reach the caller
+        rodio::Decoder::new(Cursor::new(data.clone()))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+
+        std::thread::spawn(move || {
+            let result = (|| -> Result<bool> {
+                let (_stream, handle) = rodio::OutputStream::try_default()?;
+                let sink = rodio::Sink::try_new(&handle)?;
+                let source = rodio::Decoder::new(Cursor::new(data))?;
+                sink.append(rodio::Source::skip_duration(source, start));
+
+                while !sink.empty() {
+                    if stop_flag.load(Ordering::Relaxed) {
+                        sink.stop();
+                        return Ok(false);
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Ok(true)
+            })();
+
+            match result {
+                Ok(true) => on_finish(),
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::error!("Playback error: {}", e);
+                    on_finish();
+                }
+            }
+        });
+
+        Ok(Self { stop })
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for VoicePlayer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waveform_empty() {
+        assert_eq!(waveform(&[], 4), vec![0; 4]);
+        assert_eq!(waveform(&[0.0; 16], 4), vec![0; 4]);
+        assert!(waveform(&[0.5], 0).is_empty());
+    }
+
+    #[test]
+    fn test_waveform_fewer_samples_than_buckets() {
+        let bars = waveform(&[0.5, -1.0], 4);
+        assert_eq!(bars, vec![128, 128, 255, 255]);
+    }
+
+    #[test]
+    fn test_waveform_normalizes_to_peak() {
+        // A quiet recording still reaches the full bar height
+        let bars = waveform(&[0.25, -0.0625, 0.125, 0.03125], 2);
+        assert_eq!(bars, vec![255, 128]);
+        assert_eq!(waveform(&[0.2; 8], WAVEFORM_BUCKETS), vec![255; WAVEFORM_BUCKETS]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Automatic local backups
+//!
+//! A backup is a consistent copy of the client database, encrypted in the
+//! streamed file format under a backup key kept in the config file. They
+//! are named by creation time, so listing a directory gives their order,
+//! and beyond the configured number the oldest are deleted. A backup is
+//! verified by decrypting it in full and running SQLite's integrity check
+//! on the result; restoring verifies first and then swaps the database.
+
+use crate::crypto::CryptoEngine;
+use crate::database::Database;
+use anyhow::{Context, Result};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const FILE_PREFIX: &str = "privmsg-backup-";
+const FILE_EXTENSION: &str = "pmbak";
+const TIME_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+/// Scheduled backups run once this long after the newest one
+pub const BACKUP_INTERVAL_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupEntry {
+    pub path: PathBuf,
+    /// Unix timestamp (millis)
+    pub created_at: i64,
+    pub size: u64,
+}
+
+impl BackupEntry {
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let stamp = name
+            .strip_prefix(FILE_PREFIX)?
+            .strip_suffix(FILE_EXTENSION)?
+            .strip_suffix('.')?;
+        let created_at = NaiveDateTime::parse_from_str(stamp, TIME_FORMAT).ok()?;
+        let size = std::fs::metadata(&path).ok()?.len();
+        Some(Self {
+            path,
+            created_at: Utc.from_utc_datetime(&created_at).timestamp_millis(),
+            size,
+        })
+    }
+}
+
+/// A new random backup key
+pub fn generate_key() -> Result<String> {
+    CryptoEngine::new().generate_file_key()
+}
+
+/// Backups in `dir`, newest first
+pub fn list(dir: &Path) -> Result<Vec<BackupEntry>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Cannot read {:?}", dir)),
+    };
+
+    let mut backups: Vec<BackupEntry> = entries
+        .filter_map(|entry| BackupEntry::from_path(entry.ok()?.path()))
+        .collect();
+    backups.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(backups)
+}
+
+/// Whether the newest backup in `dir` is older than the backup interval
+pub fn is_due(dir: &Path) -> bool {
+    let now = Utc::now().timestamp_millis();
+    match list(dir) {
+        Ok(backups) => backups
+            .first()
+            .is_none_or(|newest| now - newest.created_at >= BACKUP_INTERVAL_SECS * 1000),
+        Err(_) => true,
+    }
+}
+
+/// Back up `db` into `dir`, then delete all but the newest `keep` backups
+pub fn create(db: &Database, key: &str, dir: &Path, keep: usize) -> Result<BackupEntry> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Cannot create {:?}", dir))?;
+
+    let name = format!(
+        "{}{}.{}",
+        FILE_PREFIX,
+        Utc::now().format(TIME_FORMAT),
+        FILE_EXTENSION
+    );
+    let path = dir.join(&name);
+    // Only complete backups carry the final name
+    let partial = dir.join(format!("{}.partial", name));
+
+    // The plaintext copy stays next to the database, never in `dir`
+    let snapshot = db.path().with_extension("snapshot");
+    db.snapshot_to(&snapshot)?;
+    let encrypted = encrypt_file(&snapshot, &partial, key);
+    std::fs::remove_file(&snapshot).ok();
+    if let Err(e) = encrypted {
+        std::fs::remove_file(&partial).ok();
+        return Err(e);
+    }
+    std::fs::rename(&partial, &path)?;
+
+    for old in list(dir)?.into_iter().skip(keep.max(1)) {
+        if let Err(e) = std::fs::remove_file(&old.path) {
+            tracing::warn!("Cannot remove old backup {:?}: {}", old.path, e);
+        }
+    }
+
+    BackupEntry::from_path(path).context("Backup disappeared")
+}
+
+/// Check that `backup` decrypts and holds an intact database. `scratch`
+/// receives the decrypted copy while checking.
+pub fn verify(backup: &Path, key: &str, scratch: &Path) -> Result<()> {
+    let result = decrypt_file(backup, scratch, key).and_then(|()| check_integrity(scratch));
+    std::fs::remove_file(scratch).ok();
+    result
+}
+
+/// Replace the contents of `db` with `backup`
+pub fn restore(db: &Database, backup: &Path, key: &str) -> Result<()> {
+    let restored = db.path().with_extension("restore");
+    let checked = decrypt_file(backup, &restored, key).and_then(|()| check_integrity(&restored));
+    if let Err(e) = checked {
+        std::fs::remove_file(&restored).ok();
+        return Err(e);
+    }
+    db.replace_with(&restored)
+}
+
+fn encrypt_file(src: &Path, dest: &Path, key: &str) -> Result<()> {
+    let mut input = std::io::BufReader::new(std::fs::File::open(src)?);
+    let output = std::io::BufWriter::new(std::fs::File::create(dest)?);
+
+    let mut writer = CryptoEngine::new().encrypting_writer(output, key)?;
+    std::io::copy(&mut input, &mut writer)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+fn decrypt_file(src: &Path, dest: &Path, key: &str) -> Result<()> {
+    let input = std::io::BufReader::new(std::fs::File::open(src)?);
+    let mut output = std::io::BufWriter::new(std::fs::File::create(dest)?);
+
+    let mut reader = CryptoEngine::new().decrypting_reader(input, key)?;
+    std::io::copy(&mut reader, &mut output).context("Backup is damaged or the key is wrong")?;
+    output.flush()?;
+    Ok(())
+}
+
+fn check_integrity(db_path: &Path) -> Result<()> {
+    let conn = rusqlite::Connection::open(db_path)?;
+    let result: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    anyhow::ensure!(result == "ok", "Integrity check failed: {}", result);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_rotation_and_restore() {
+        let dir = std::env::temp_dir().join(format!("privmsg-backup-{}", uuid::Uuid::new_v4()));
+        let backups = dir.join("backups");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let db = Database::new(&dir).unwrap();
+        let key = generate_key().unwrap();
+        assert!(is_due(&backups));
+
+        db.set_setting("theme", "dark").unwrap();
+        let first = create(&db, &key, &backups, 2).unwrap();
+        db.set_setting("theme", "light").unwrap();
+        create(&db, &key, &backups, 2).unwrap();
+        create(&db, &key, &backups, 2).unwrap();
+        assert!(!is_due(&backups));
+
+        // Only the newest two are kept
+        let listed = list(&backups).unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(!first.path.exists());
+        assert!(listed[0].created_at >= listed[1].created_at);
+
+        let scratch = dir.join("verify.tmp");
+        verify(&listed[0].path, &key, &scratch).unwrap();
+        assert!(verify(&listed[0].path, &generate_key().unwrap(), &scratch).is_err());
+        assert!(!scratch.exists());
+
+        db.set_setting("theme", "solarized").unwrap();
+        restore(&db, &listed[0].path, &key).unwrap();
+        assert_eq!(db.get_setting("theme").as_deref(), Some("light"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
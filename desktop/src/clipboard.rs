@@ -0,0 +1,136 @@
+//! Clipboard writes that clean up after themselves
+//!
+//! Message text copied out of the app would otherwise sit on the system
+//! clipboard indefinitely. With a timeout set, a background thread clears
+//! the clipboard afterwards, unless something else has been copied since.
+//!
+//! Copied text is sanitized first: invisible characters that could hide or
+//! reorder what is pasted elsewhere are dropped.
+
+use crate::state::ChatMessage;
+use anyhow::Result;
+use std::fmt;
+use std::time::Duration;
+
+/// Clear-after choice offered in settings, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClipboardClear(pub Option<u64>);
+
+impl ClipboardClear {
+    pub const OPTIONS: [Self; 4] = [Self(None), Self(Some(30)), Self(Some(60)), Self(Some(120))];
+
+    pub fn duration(self) -> Option<Duration> {
+        self.0.map(Duration::from_secs)
+    }
+}
+
+impl fmt::Display for ClipboardClear {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            None => f.write_str("Never"),
+            Some(secs) => write!(f, "After {} seconds", secs),
+        }
+    }
+}
+
+/// Put `text` on the clipboard, clearing it again after `clear_after`
+pub fn copy(text: &str, clear_after: Option<Duration>) -> Result<()> {
+    arboard::Clipboard::new()?.set_text(text)?;
+
+    if let Some(delay) = clear_after {
+        let copied = text.to_string();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            if let Err(e) = clear_if_unchanged(&copied) {
+                tracing::warn!("Failed to clear clipboard: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn clear_if_unchanged(copied: &str) -> Result<()> {
+    let mut clipboard = arboard::Clipboard::new()?;
+    if clipboard.get_text().ok().as_deref() == Some(copied) {
+        clipboard.clear()?;
+    }
+    Ok(())
+}
+
+/// `text` without control characters other than newlines and tabs,
+/// directional overrides and invisible separators, with Windows line
+/// endings normalized and the ends trimmed. Joiners stay, since emoji
+/// sequences need them.
+pub fn sanitize(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .chars()
+        .filter(|&c| match c {
+            '\n' | '\t' => true,
+            // Bidi embeddings, overrides and isolates
+            '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' => false,
+            // Zero-width space, word joiner, invisible operators and BOM
+            '\u{200B}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}' => false,
+            c => !c.is_control(),
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// What a message contributes to a transcript: its text, or an
+/// attachment's name and caption
+fn body(msg: &ChatMessage) -> String {
+    match msg.attachment {
+        Some(ref attachment) => match msg.caption.as_deref() {
+            Some(caption) => format!("[{}] {}", attachment.file_name, sanitize(caption)),
+            None => format!("[{}]", attachment.file_name),
+        },
+        None => sanitize(&msg.content),
+    }
+}
+
+/// Several messages as plain text, one per line prefixed with when and by
+/// whom it was sent: `[2024-03-01 14:05] Alice: see you there`. Lines
+/// following the first of a message are indented under it.
+pub fn transcript<'a>(
+    messages: impl IntoIterator<Item = &'a ChatMessage>,
+    sender_name: impl Fn(&str) -> String,
+) -> String {
+    messages
+        .into_iter()
+        .map(|msg| {
+            let time = chrono::DateTime::from_timestamp_millis(msg.timestamp)
+                .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            let body = body(msg).replace('\n', "\n    ");
+            format!("[{}] {}: {}", time, sender_name(&msg.sender_id), body)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copied_text_is_sanitized() {
+        assert_eq!(sanitize("  pay \u{202E}gpj.exe\u{202C} now\r\n"), "pay gpj.exe now");
+        assert_eq!(sanitize("a\u{200B}b\u{FEFF}\u{7}"), "ab");
+        assert_eq!(sanitize("line 1\r\n\tline 2"), "line 1\n\tline 2");
+        // Family emoji keeps its joiners
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(sanitize(family), family);
+
+        let mut first = ChatMessage::outgoing_text("bob", "alice", "hi\nthere\u{200B}");
+        first.timestamp = 1_700_000_000_000;
+        let mut second = ChatMessage::outgoing_text("bob", "bob", "ok");
+        second.timestamp = 1_700_000_060_000;
+        let text = transcript([&first, &second], |id| id.to_uppercase());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with('[') && lines[0].ends_with("] ALICE: hi"));
+        assert_eq!(lines[1], "    there");
+        assert!(lines[2].ends_with("] BOB: ok"));
+    }
+}
@@ -1,13 +1,29 @@
 //! Configuration management for PrivMsg Desktop
 
+use crate::wallpaper::Wallpaper;
+use privmsg_proto::notification::PreviewPolicy;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub ui: UiConfig,
     pub notifications: NotificationConfig,
+    #[serde(default)]
+    pub integration: IntegrationConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+    #[serde(default)]
+    pub analytics: AnalyticsConfig,
+    /// Feature flags by name; see `privmsg_proto::flags`
+    #[serde(default)]
+    pub flags: BTreeMap<String, bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +33,17 @@ pub struct ServerConfig {
     pub use_tls: bool,
 }
 
+impl ServerConfig {
+    /// Whether the host names this machine; it is not resolved
+    pub fn is_loopback(&self) -> bool {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        host == "localhost"
+            || host.ends_with(".localhost")
+            || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UiConfig {
     pub theme: String, // "dark" or "light"
@@ -24,6 +51,9 @@ pub struct UiConfig {
     pub compact_mode: bool,
     pub show_avatars: bool,
     pub enter_to_send: bool,
+    /// Chat background, unless a conversation has its own
+    #[serde(default)]
+    pub wallpaper: Option<Wallpaper>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +63,103 @@ pub struct NotificationConfig {
     pub preview: bool,
 }
 
+impl NotificationConfig {
+    pub fn preview_policy(&self) -> PreviewPolicy {
+        if self.preview {
+            PreviewPolicy::Full
+        } else {
+            PreviewPolicy::SenderOnly
+        }
+    }
+}
+
+/// Desktop integration set up by `packaging`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IntegrationConfig {
+    /// Link handler and menu entries are registered
+    pub installed: bool,
+    pub start_on_login: bool,
+}
+
+/// App lock and clipboard hygiene
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// Hash from `applock::hash_passcode`; the app lock is on when set
+    pub passcode_hash: Option<String>,
+    /// Lock when the machine sleeps or the OS session is locked
+    pub lock_on_suspend: bool,
+    /// Clear copied message text from the clipboard after this many seconds
+    pub clipboard_clear_secs: Option<u64>,
+    /// Connect without TLS to a server on this machine; plain connections
+    /// anywhere else are always refused
+    pub allow_insecure_localhost: bool,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            passcode_hash: None,
+            lock_on_suspend: true,
+            clipboard_clear_secs: None,
+            allow_insecure_localhost: false,
+        }
+    }
+}
+
+/// Sending messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    /// Retries of a failed send before the message is marked failed
+    pub auto_retries: u32,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self { auto_retries: 2 }
+    }
+}
+
+/// Conversation statistics shown in chat details
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnalyticsConfig {
+    pub enabled: bool,
+}
+
+/// Scheduled local backups, see `backup`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    pub enabled: bool,
+    /// Number of backups kept
+    pub keep: usize,
+    /// Defaults to `<data_dir>/backups`
+    pub directory: Option<PathBuf>,
+    /// Generated on the first backup; backups can't be restored without it
+    pub key: Option<String>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            keep: 7,
+            directory: None,
+            key: None,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn directory(&self, data_dir: &Path) -> PathBuf {
+        self.directory
+            .clone()
+            .unwrap_or_else(|| data_dir.join("backups"))
+    }
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
@@ -47,12 +174,19 @@ impl Default for AppConfig {
                 compact_mode: false,
                 show_avatars: true,
                 enter_to_send: true,
+                wallpaper: None,
             },
             notifications: NotificationConfig {
                 enabled: true,
                 sound: true,
                 preview: true,
             },
+            integration: IntegrationConfig::default(),
+            security: SecurityConfig::default(),
+            backup: BackupConfig::default(),
+            delivery: DeliveryConfig::default(),
+            analytics: AnalyticsConfig::default(),
+            flags: BTreeMap::new(),
         }
     }
 }
@@ -77,6 +211,18 @@ impl AppConfig {
         Ok(())
     }
 
+    /// Refuse a server without TLS unless it is on this machine and that
+    /// is allowed. Certificates are verified either way.
+    pub fn check_transport_security(&self) -> anyhow::Result<()> {
+        if self.server.use_tls || (self.security.allow_insecure_localhost && self.server.is_loopback()) {
+            return Ok(());
+        }
+        if self.server.is_loopback() {
+            anyhow::bail!("Refusing unencrypted connection to {}; enable TLS or allow insecure localhost connections", self.http_url());
+        }
+        anyhow::bail!("Refusing unencrypted connection to {}; enable TLS", self.http_url())
+    }
+
     pub fn http_url(&self) -> String {
         let scheme = if self.server.use_tls { "https" } else { "http" };
         format!("{}://{}:{}", scheme, self.server.host, self.server.port)
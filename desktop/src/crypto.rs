@@ -10,8 +10,12 @@ use parking_lot::RwLock;
 use rand::RngCore;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::io::{Read, Write};
 use x25519_dalek::{PublicKey, StaticSecret};
 
+/// Length of the ML-KEM-768 key following the X25519 key in hybrid keys
+const MLKEM_PUBLIC_KEY_LEN: usize = 1184;
+
 /// Crypto engine for E2EE operations
 pub struct CryptoEngine {
     identity_secret: RwLock<Option<StaticSecret>>,
@@ -81,14 +85,23 @@ impl CryptoEngine {
 
     /// Establish session with another user
     pub fn establish_session(&self, peer_id: &str, peer_public_key_b64: &str) -> Result<()> {
-        let peer_bytes = URL_SAFE_NO_PAD.decode(peer_public_key_b64)?;
+        // Newer clients tag their keys with a cipher suite. Only X25519 is
+        // understood here; an untagged key means it too. Hybrid keys start
+        // with an X25519 key, which is used alone.
+        let (key, key_len) = match peer_public_key_b64.split_once(':') {
+            Some(("x25519", key)) => (key, 32),
+            Some(("x25519-kyber768", key)) => (key, 32 + MLKEM_PUBLIC_KEY_LEN),
+            Some((suite, _)) => return Err(anyhow::anyhow!("Unsupported cipher suite: {}", suite)),
+            None => (peer_public_key_b64, 32),
+        };
+        let peer_bytes = URL_SAFE_NO_PAD.decode(key)?;
 
-        if peer_bytes.len() != 32 {
+        if peer_bytes.len() != key_len {
             return Err(anyhow::anyhow!("Invalid peer key length"));
         }
 
         let mut peer_key_bytes = [0u8; 32];
-        peer_key_bytes.copy_from_slice(&peer_bytes);
+        peer_key_bytes.copy_from_slice(&peer_bytes[..32]);
         let peer_public = PublicKey::from(peer_key_bytes);
 
         let secret_guard = self.identity_secret.read();
@@ -116,6 +129,11 @@ impl CryptoEngine {
         Ok(())
     }
 
+    /// Forget the session with a peer
+    pub fn remove_session(&self, peer_id: &str) {
+        self.sessions.write().remove(peer_id);
+    }
+
     /// Check if we have a session with a peer
     pub fn has_session(&self, peer_id: &str) -> bool {
         self.sessions.read().contains_key(peer_id)
@@ -180,36 +198,49 @@ impl CryptoEngine {
         Ok(URL_SAFE_NO_PAD.encode(&key))
     }
 
-    /// Encrypt file data
+    /// Encrypt file data held in memory (same format as `encrypting_writer`)
     pub fn encrypt_file(&self, data: &[u8], key_b64: &str) -> Result<Vec<u8>> {
-        let key_bytes = URL_SAFE_NO_PAD.decode(key_b64)?;
-
-        let cipher = Aes256Gcm::new_from_slice(&key_bytes)?;
+        let mut writer = self.encrypting_writer(Vec::with_capacity(encrypted_len(data.len())), key_b64)?;
+        writer.write_all(data)?;
+        Ok(writer.finish()?)
+    }
 
-        let mut nonce_bytes = [0u8; 12];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+    /// Decrypt file data held in memory, streamed or legacy single-shot
+    pub fn decrypt_file(&self, encrypted: &[u8], key_b64: &str) -> Result<Vec<u8>> {
+        if encrypted.starts_with(STREAM_MAGIC) {
+            let mut plaintext = Vec::new();
+            let streamed = self
+                .decrypting_reader(encrypted, key_b64)
+                .and_then(|mut reader| Ok(reader.read_to_end(&mut plaintext)?));
+
+            // A legacy file can start with the magic bytes by chance
+            match streamed {
+                Ok(_) => return Ok(plaintext),
+                Err(e) => return self.decrypt_legacy_file(encrypted, key_b64).map_err(|_| e),
+            }
+        }
 
-        let ciphertext = cipher
-            .encrypt(nonce, data)
-            .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
+        self.decrypt_legacy_file(encrypted, key_b64)
+    }
 
-        let mut result = Vec::with_capacity(12 + ciphertext.len());
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
+    /// Encrypt everything written to `writer`; call `finish` when done
+    pub fn encrypting_writer<W: Write>(&self, writer: W, key_b64: &str) -> Result<EncryptingWriter<W>> {
+        Ok(EncryptingWriter::new(writer, file_cipher(key_b64)?))
+    }
 
-        Ok(result)
+    /// Decrypt a streamed file while reading it. Tampering, reordering and
+    /// truncation surface as `InvalidData` read errors.
+    pub fn decrypting_reader<R: Read>(&self, reader: R, key_b64: &str) -> Result<DecryptingReader<R>> {
+        Ok(DecryptingReader::new(reader, file_cipher(key_b64)?)?)
     }
 
-    /// Decrypt file data
-    pub fn decrypt_file(&self, encrypted: &[u8], key_b64: &str) -> Result<Vec<u8>> {
+    /// Files uploaded before chunking: nonce followed by one AES-GCM message
+    fn decrypt_legacy_file(&self, encrypted: &[u8], key_b64: &str) -> Result<Vec<u8>> {
         if encrypted.len() < 12 {
             return Err(anyhow::anyhow!("Data too short"));
         }
 
-        let key_bytes = URL_SAFE_NO_PAD.decode(key_b64)?;
-
-        let cipher = Aes256Gcm::new_from_slice(&key_bytes)?;
+        let cipher = file_cipher(key_b64)?;
 
         let nonce = Nonce::from_slice(&encrypted[..12]);
         let ciphertext = &encrypted[12..];
@@ -227,6 +258,20 @@ impl CryptoEngine {
     }
 }
 
+// ============================================================================
+// Streaming File Encryption
+// ============================================================================
+
+// Same format as privmsg-core, from the crate both use, so files are
+// readable by all clients
+pub use privmsg_proto::stream::{encrypted_len, DecryptingReader, EncryptingWriter, FILE_CHUNK_SIZE};
+use privmsg_proto::stream::STREAM_MAGIC;
+
+fn file_cipher(key_b64: &str) -> Result<Aes256Gcm> {
+    let key_bytes = URL_SAFE_NO_PAD.decode(key_b64)?;
+    Ok(Aes256Gcm::new_from_slice(&key_bytes)?)
+}
+
 impl Default for CryptoEngine {
     fn default() -> Self {
         Self::new()
@@ -258,7 +303,15 @@ mod tests {
         let bob_pub = bob.get_public_key().unwrap();
 
         alice.establish_session("bob", &bob_pub).unwrap();
-        bob.establish_session("alice", &alice_pub).unwrap();
+        // Keys from newer clients carry a suite id
+        bob.establish_session("alice", &format!("x25519:{}", alice_pub)).unwrap();
+        assert!(bob.establish_session("carol", &format!("x25519-kyber768:{}", alice_pub)).is_err());
+
+        // Hybrid keys fall back to their X25519 half
+        let mut hybrid = URL_SAFE_NO_PAD.decode(&alice_pub).unwrap();
+        hybrid.extend_from_slice(&[7u8; MLKEM_PUBLIC_KEY_LEN]);
+        bob.establish_session("alice", &format!("x25519-kyber768:{}", URL_SAFE_NO_PAD.encode(&hybrid)))
+            .unwrap();
 
         let plaintext = "Hello, Bob!";
         let encrypted = alice.encrypt_for("bob", plaintext).unwrap();
@@ -266,4 +319,19 @@ mod tests {
 
         assert_eq!(plaintext, decrypted);
     }
+
+    #[test]
+    fn test_streamed_file_encryption() {
+        let engine = CryptoEngine::new();
+        let key = engine.generate_file_key().unwrap();
+        let data: Vec<u8> = (0..2 * FILE_CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = engine.encrypting_writer(Vec::new(), &key).unwrap();
+        writer.write_all(&data).unwrap();
+        let encrypted = writer.finish().unwrap();
+        assert_eq!(encrypted.len(), encrypted_len(data.len()));
+
+        assert_eq!(engine.decrypt_file(&encrypted, &key).unwrap(), data);
+        assert!(engine.decrypt_file(&encrypted[..encrypted.len() - 1], &key).is_err());
+    }
 }
@@ -0,0 +1,145 @@
+//! "Download my data"
+//!
+//! One JSON file with the user's profile, signed-in devices and every
+//! conversation with its messages in plain text. Only this device can
+//! produce the messages, since the server never sees them decrypted. The
+//! file is written next to its destination and renamed into place once
+//! complete, so a cancelled or failed export leaves nothing behind.
+
+use crate::database::Database;
+use crate::state::{ConversationExport, Device, User};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataExport {
+    /// Unix millis
+    pub exported_at: i64,
+    pub user: User,
+    pub devices: Vec<Device>,
+    pub conversations: Vec<ConversationExport>,
+}
+
+/// Shared between a running export and the settings screen
+#[derive(Debug, Default)]
+pub struct ExportProgress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl ExportProgress {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Conversations written and the total
+    pub fn counts(&self) -> (usize, usize) {
+        (self.done.load(Ordering::Relaxed), self.total.load(Ordering::Relaxed))
+    }
+
+    pub fn fraction(&self) -> f32 {
+        match self.counts() {
+            (_, 0) => 0.0,
+            (done, total) => done as f32 / total as f32,
+        }
+    }
+}
+
+/// Write the export to `dest`. Returns false if it was cancelled.
+pub fn write(
+    db: &Database,
+    user: User,
+    devices: Vec<Device>,
+    dest: &Path,
+    progress: &ExportProgress,
+) -> Result<bool> {
+    let peer_ids = db.get_conversation_peer_ids()?;
+    progress.total.store(peer_ids.len(), Ordering::Relaxed);
+
+    let mut conversations = Vec::with_capacity(peer_ids.len());
+    for (done, peer_id) in peer_ids.iter().enumerate() {
+        if progress.is_cancelled() {
+            return Ok(false);
+        }
+        conversations.extend(db.export_conversations(std::slice::from_ref(peer_id))?);
+        progress.done.store(done + 1, Ordering::Relaxed);
+    }
+    if progress.is_cancelled() {
+        return Ok(false);
+    }
+
+    let export = DataExport {
+        exported_at: chrono::Utc::now().timestamp_millis(),
+        user,
+        devices,
+        conversations,
+    };
+    let part = dest.with_extension("part");
+    let written = (|| -> Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&part)?);
+        serde_json::to_writer_pretty(&mut file, &export)?;
+        file.flush()?;
+        std::fs::rename(&part, dest)?;
+        Ok(())
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&part);
+    }
+    written.map(|()| true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{ChatMessage, Conversation};
+
+    #[test]
+    fn test_data_export() {
+        let dir = std::env::temp_dir().join(format!("privmsg-export-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(&dir).unwrap();
+        db.save_conversation(&Conversation {
+            id: "bob".into(),
+            peer_id: "bob".into(),
+            peer_name: None,
+            peer_avatar: None,
+            last_message: None,
+            last_message_time: None,
+            unread_count: 0,
+            is_muted: false,
+            is_pinned: false,
+            is_archived: true,
+        })
+        .unwrap();
+        db.save_message(&ChatMessage::outgoing_text("bob", "alice", "hi bob")).unwrap();
+
+        let user: User = serde_json::from_str(
+            r#"{"user_id":"alice","display_name":null,"avatar_file_id":null,"public_key":null,"last_seen_at":null}"#,
+        )
+        .unwrap();
+        let dest = dir.join("my-data.json");
+        let progress = ExportProgress::default();
+        assert!(write(&db, user.clone(), Vec::new(), &dest, &progress).unwrap());
+        assert_eq!(progress.counts(), (1, 1));
+
+        let export: DataExport = serde_json::from_slice(&std::fs::read(&dest).unwrap()).unwrap();
+        assert_eq!(export.user.user_id, "alice");
+        assert_eq!(export.conversations.len(), 1);
+        assert_eq!(export.conversations[0].messages[0].content, "hi bob");
+
+        std::fs::remove_file(&dest).unwrap();
+        progress.cancel();
+        assert!(!write(&db, user, Vec::new(), &dest, &progress).unwrap());
+        assert!(!dest.exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,21 +1,42 @@
 //! Local SQLite database for PrivMsg Desktop
 
 use crate::state::{
-    Attachment, AuthSession, ChatMessage, Conversation, MessageStatus, MessageType,
+    Attachment, AuthSession, ChatMessage, Conversation, ConversationExport, DeliveryInfo,
+    MessageStatus, MessageType, User,
 };
 use anyhow::Result;
 use parking_lot::Mutex;
+use privmsg_proto::rules::MessageRule;
+use privmsg_proto::sqlite::ensure_column;
+use privmsg_proto::stats::MessageSample;
+use privmsg_proto::truncate_graphemes;
+use crate::wallpaper::Wallpaper;
 use rusqlite::{params, Connection};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Grapheme clusters of a conversation's last message kept for the list
+const LAST_MESSAGE_PREVIEW_LEN: usize = 64;
 
 pub struct Database {
     conn: Mutex<Connection>,
+    path: PathBuf,
 }
 
 impl Database {
     pub fn new(data_dir: &Path) -> Result<Self> {
-        let db_path = data_dir.join("privmsg.db");
-        let conn = Connection::open(&db_path)?;
+        let path = data_dir.join("privmsg.db");
+        let conn = Self::open(&path)?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            path,
+        })
+    }
+
+    /// Open the database file and bring its schema up to date
+    fn open(db_path: &Path) -> Result<Connection> {
+        let conn = Connection::open(db_path)?;
 
         // Initialize schema
         conn.execute_batch(
@@ -83,6 +104,52 @@ impl Database {
                 updated_at INTEGER DEFAULT (strftime('%s', 'now'))
             );
 
+            -- Profiles cache
+            CREATE TABLE IF NOT EXISTS profiles (
+                user_id TEXT PRIMARY KEY,
+                display_name TEXT,
+                status_emoji TEXT,
+                status_text TEXT,
+                bio TEXT,
+                updated_at INTEGER DEFAULT (strftime('%s', 'now'))
+            );
+
+            -- Rules for incoming messages, in the order they apply
+            CREATE TABLE IF NOT EXISTS message_rules (
+                id TEXT PRIMARY KEY,
+                rule_json TEXT NOT NULL,
+                position INTEGER NOT NULL
+            );
+
+            -- Delivery of messages sent from this device
+            CREATE TABLE IF NOT EXISTS message_delivery (
+                message_id TEXT PRIMARY KEY,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                sent_at INTEGER,
+                acked_at INTEGER
+            );
+
+            -- Per-conversation wallpapers; apart from conversations, whose
+            -- rows are replaced on every save
+            CREATE TABLE IF NOT EXISTS conversation_wallpapers (
+                peer_id TEXT PRIMARY KEY,
+                wallpaper_json TEXT NOT NULL
+            );
+
+            -- Keys peers used before a different one turned up
+            CREATE TABLE IF NOT EXISTS archived_peer_keys (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                archived_at INTEGER NOT NULL
+            );
+
+            -- Peers whose accounts the server said are deactivated or deleted
+            CREATE TABLE IF NOT EXISTS unavailable_peers (
+                peer_id TEXT PRIMARY KEY,
+                since INTEGER NOT NULL
+            );
+
             -- Settings
             CREATE TABLE IF NOT EXISTS settings (
                 key TEXT PRIMARY KEY,
@@ -96,9 +163,63 @@ impl Database {
             "#,
         )?;
 
-        Ok(Self {
-            conn: Mutex::new(conn),
-        })
+        // Columns added after the initial schema
+        ensure_column(&conn, "messages", "caption", "TEXT")?;
+        ensure_column(&conn, "messages", "attachment_waveform", "TEXT")?;
+        ensure_column(&conn, "messages", "attachment_view_once", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "messages", "system_event", "TEXT")?;
+        ensure_column(&conn, "messages", "sender_device_id", "TEXT")?;
+        ensure_column(&conn, "messages", "quote", "TEXT")?;
+        ensure_column(&conn, "messages", "is_starred", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "messages", "attachment_expires_at", "INTEGER")?;
+        ensure_column(&conn, "conversations", "is_archived", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "peer_keys", "pending_key", "TEXT")?;
+
+        Ok(conn)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write a consistent copy of the database to `dest`
+    pub fn snapshot_to(&self, dest: &Path) -> Result<()> {
+        // VACUUM INTO refuses to overwrite
+        match std::fs::remove_file(dest) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let conn = self.conn.lock();
+        conn.execute("VACUUM INTO ?1", params![dest.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// Replace the database with the file at `source`, which is moved into place
+    pub fn replace_with(&self, source: &Path) -> Result<()> {
+        let mut conn = self.conn.lock();
+
+        // Close the current file before it is replaced
+        drop(std::mem::replace(&mut *conn, Connection::open_in_memory()?));
+        // Reopen whichever file is in place, even if the move failed
+        let moved = std::fs::rename(source, &self.path);
+        *conn = Self::open(&self.path)?;
+        Ok(moved?)
+    }
+
+    /// Add a column to an existing table if it isn't there yet
+    /// Problems SQLite finds in the database file; empty when it is intact
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut problems = Vec::new();
+        for row in rows {
+            let row = row?;
+            if row != "ok" {
+                problems.push(row);
+            }
+        }
+        Ok(problems)
     }
 
     // ============= Sessions =============
@@ -134,6 +255,7 @@ impl Database {
                     device_id: row.get(1)?,
                     user_id: row.get(2)?,
                     expires_at: row.get(3)?,
+                    announcement: None,
                 })
             },
         )
@@ -192,6 +314,116 @@ impl Database {
         .ok()
     }
 
+    /// Hold a key that differs from the trusted one until the user accepts
+    /// it, archiving the trusted key the first time. Returns whether the
+    /// conflict is new.
+    pub fn record_key_conflict(&self, user_id: &str, public_key: &str) -> Result<bool> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let pending: Option<String> = tx.query_row(
+            "SELECT pending_key FROM peer_keys WHERE user_id = ?1",
+            params![user_id],
+            |row| row.get(0),
+        )?;
+        if pending.as_deref() == Some(public_key) {
+            return Ok(false);
+        }
+        if pending.is_none() {
+            tx.execute(
+                r#"INSERT INTO archived_peer_keys (user_id, public_key, archived_at)
+                   SELECT user_id, public_key, strftime('%s', 'now') FROM peer_keys WHERE user_id = ?1"#,
+                params![user_id],
+            )?;
+        }
+        tx.execute(
+            "UPDATE peer_keys SET pending_key = ?2 WHERE user_id = ?1",
+            params![user_id, public_key],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Trust the key held for a peer; returns it, or None if there was none
+    pub fn accept_pending_key(&self, user_id: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock();
+        let pending: Option<String> = conn
+            .query_row(
+                "SELECT pending_key FROM peer_keys WHERE user_id = ?1",
+                params![user_id],
+                |row| row.get(0),
+            )
+            .ok()
+            .flatten();
+        if pending.is_some() {
+            conn.execute(
+                r#"UPDATE peer_keys SET public_key = pending_key, pending_key = NULL,
+                          updated_at = strftime('%s', 'now')
+                   WHERE user_id = ?1"#,
+                params![user_id],
+            )?;
+        }
+        Ok(pending)
+    }
+
+    /// Peers whose new key waits for the user to accept it
+    pub fn get_key_conflicts(&self) -> Result<HashSet<String>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare("SELECT user_id FROM peer_keys WHERE pending_key IS NOT NULL")?;
+        let peers = stmt
+            .query_map([], |row| row.get(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+
+        Ok(peers)
+    }
+
+    // ============= Profiles =============
+
+    pub fn save_profile(&self, user: &User) -> Result<()> {
+        let conn = self.conn.lock();
+
+        conn.execute(
+            r#"INSERT OR REPLACE INTO profiles
+               (user_id, display_name, status_emoji, status_text, bio, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, strftime('%s', 'now'))"#,
+            params![
+                user.user_id,
+                user.display_name,
+                user.status_emoji,
+                user.status_text,
+                user.bio,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_profiles(&self) -> Result<Vec<User>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare(
+            "SELECT user_id, display_name, status_emoji, status_text, bio FROM profiles",
+        )?;
+        let profiles = stmt
+            .query_map([], |row| {
+                Ok(User {
+                    user_id: row.get(0)?,
+                    display_name: row.get(1)?,
+                    avatar_file_id: None,
+                    public_key: None,
+                    last_seen_at: None,
+                    status_emoji: row.get(2)?,
+                    status_text: row.get(3)?,
+                    bio: row.get(4)?,
+                    last_seen_visibility: None,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(profiles)
+    }
+
     // ============= Conversations =============
 
     pub fn save_conversation(&self, conv: &Conversation) -> Result<()> {
@@ -201,8 +433,8 @@ impl Database {
             r#"
             INSERT OR REPLACE INTO conversations
             (id, peer_id, peer_name, peer_avatar, last_message, last_message_time,
-             unread_count, is_muted, is_pinned, updated_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, strftime('%s', 'now'))
+             unread_count, is_muted, is_pinned, is_archived, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, strftime('%s', 'now'))
             "#,
             params![
                 conv.id,
@@ -214,44 +446,93 @@ impl Database {
                 conv.unread_count,
                 conv.is_muted as i32,
                 conv.is_pinned as i32,
+                conv.is_archived as i32,
             ],
         )?;
 
         Ok(())
     }
 
-    pub fn get_conversations(&self) -> Result<Vec<Conversation>> {
+    /// A page of conversation rows for the list view, in list order, with
+    /// `last_message` cut down to a short preview so long messages aren't
+    /// loaded for every row
+    pub fn get_conversation_summaries(&self, limit: i64, offset: i64) -> Result<Vec<Conversation>> {
         let conn = self.conn.lock();
 
         let mut stmt = conn.prepare(
             r#"
             SELECT id, peer_id, peer_name, peer_avatar, last_message, last_message_time,
-                   unread_count, is_muted, is_pinned
+                   unread_count, is_muted, is_pinned, is_archived
             FROM conversations
-            ORDER BY is_pinned DESC, last_message_time DESC
+            ORDER BY is_archived ASC, is_pinned DESC, last_message_time DESC, peer_id
+            LIMIT ?1 OFFSET ?2
             "#,
         )?;
 
         let conversations = stmt
-            .query_map([], |row| {
-                Ok(Conversation {
-                    id: row.get(0)?,
-                    peer_id: row.get(1)?,
-                    peer_name: row.get(2)?,
-                    peer_avatar: row.get(3)?,
-                    last_message: row.get(4)?,
-                    last_message_time: row.get(5)?,
-                    unread_count: row.get(6)?,
-                    is_muted: row.get::<_, i32>(7)? != 0,
-                    is_pinned: row.get::<_, i32>(8)? != 0,
-                })
-            })?
+            .query_map(params![limit, offset], Self::conversation_from_row)?
             .filter_map(|r| r.ok())
             .collect();
 
         Ok(conversations)
     }
 
+    /// Rows for just these peers, to update the list in place after they
+    /// changed. Unknown peers are skipped.
+    pub fn get_conversations_by_peer(&self, peer_ids: &[String]) -> Result<Vec<Conversation>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT id, peer_id, peer_name, peer_avatar, last_message, last_message_time,
+                   unread_count, is_muted, is_pinned, is_archived
+            FROM conversations
+            WHERE peer_id = ?1
+            "#,
+        )?;
+
+        let mut conversations = Vec::with_capacity(peer_ids.len());
+        for peer_id in peer_ids {
+            match stmt.query_row(params![peer_id], Self::conversation_from_row) {
+                Ok(conversation) => conversations.push(conversation),
+                Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(conversations)
+    }
+
+    /// Peer ids of all conversations, without loading the rows
+    pub fn get_conversation_peer_ids(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare("SELECT peer_id FROM conversations")?;
+        let peer_ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(peer_ids)
+    }
+
+    /// Columns as selected by `get_conversation_summaries`
+    fn conversation_from_row(row: &rusqlite::Row) -> rusqlite::Result<Conversation> {
+        Ok(Conversation {
+            id: row.get(0)?,
+            peer_id: row.get(1)?,
+            peer_name: row.get(2)?,
+            peer_avatar: row.get(3)?,
+            last_message: row
+                .get::<_, Option<String>>(4)?
+                .map(|m| truncate_graphemes(&m, LAST_MESSAGE_PREVIEW_LEN).to_string()),
+            last_message_time: row.get(5)?,
+            unread_count: row.get(6)?,
+            is_muted: row.get::<_, i32>(7)? != 0,
+            is_pinned: row.get::<_, i32>(8)? != 0,
+            is_archived: row.get::<_, i32>(9)? != 0,
+        })
+    }
+
     pub fn update_conversation_last_message(
         &self,
         peer_id: &str,
@@ -259,19 +540,44 @@ impl Database {
         timestamp: i64,
     ) -> Result<()> {
         let conn = self.conn.lock();
+        Self::touch_conversation(&conn, peer_id, message, timestamp)
+    }
 
+    fn touch_conversation(conn: &Connection, peer_id: &str, message: &str, timestamp: i64) -> Result<()> {
         conn.execute(
             r#"
             UPDATE conversations
             SET last_message = ?1, last_message_time = ?2, updated_at = strftime('%s', 'now')
             WHERE peer_id = ?3
             "#,
-            params![message, timestamp, peer_id],
+            params![truncate_graphemes(message, LAST_MESSAGE_PREVIEW_LEN), timestamp, peer_id],
         )?;
 
         Ok(())
     }
 
+    /// Set a conversation's name and avatar from the peer's profile.
+    /// Returns whether either changed.
+    pub fn update_conversation_profile(
+        &self,
+        peer_id: &str,
+        peer_name: Option<&str>,
+        peer_avatar: Option<&str>,
+    ) -> Result<bool> {
+        let conn = self.conn.lock();
+
+        let changed = conn.execute(
+            r#"
+            UPDATE conversations
+            SET peer_name = ?2, peer_avatar = ?3, updated_at = strftime('%s', 'now')
+            WHERE peer_id = ?1 AND (peer_name IS NOT ?2 OR peer_avatar IS NOT ?3)
+            "#,
+            params![peer_id, peer_name, peer_avatar],
+        )?;
+
+        Ok(changed > 0)
+    }
+
     pub fn increment_unread_count(&self, peer_id: &str) -> Result<()> {
         let conn = self.conn.lock();
 
@@ -294,18 +600,124 @@ impl Database {
         Ok(())
     }
 
+    // ============= Bulk conversation operations =============
+    // Each runs in one transaction; conversations are given by peer id
+
+    pub fn mark_conversations_read(&self, peer_ids: &[String]) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for peer_id in peer_ids {
+            tx.execute(
+                "UPDATE conversations SET unread_count = 0 WHERE peer_id = ?1",
+                params![peer_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn set_conversations_muted(&self, peer_ids: &[String], muted: bool) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for peer_id in peer_ids {
+            tx.execute(
+                "UPDATE conversations SET is_muted = ?1, updated_at = strftime('%s', 'now') WHERE peer_id = ?2",
+                params![muted as i32, peer_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn set_conversations_archived(&self, peer_ids: &[String], archived: bool) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for peer_id in peer_ids {
+            tx.execute(
+                "UPDATE conversations SET is_archived = ?1, updated_at = strftime('%s', 'now') WHERE peer_id = ?2",
+                params![archived as i32, peer_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Delete conversations with their messages
+    pub fn delete_conversations(&self, peer_ids: &[String]) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for peer_id in peer_ids {
+            tx.execute("DELETE FROM messages WHERE conversation_id = ?1", params![peer_id])?;
+            tx.execute("DELETE FROM conversations WHERE peer_id = ?1", params![peer_id])?;
+            tx.execute("DELETE FROM conversation_wallpapers WHERE peer_id = ?1", params![peer_id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Conversations with all their messages, read in one transaction so
+    /// the export is consistent. Unknown peers are skipped.
+    pub fn export_conversations(&self, peer_ids: &[String]) -> Result<Vec<ConversationExport>> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let mut exports = Vec::with_capacity(peer_ids.len());
+        for peer_id in peer_ids {
+            let conversation = match tx.query_row(
+                r#"
+                SELECT id, peer_id, peer_name, peer_avatar, last_message, last_message_time,
+                       unread_count, is_muted, is_pinned, is_archived
+                FROM conversations
+                WHERE peer_id = ?1
+                "#,
+                params![peer_id],
+                Self::conversation_from_row,
+            ) {
+                Ok(conversation) => conversation,
+                Err(rusqlite::Error::QueryReturnedNoRows) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut stmt = tx.prepare(
+                r#"
+                SELECT message_id, conversation_id, sender_id, message_type, content, timestamp,
+                       status, is_outgoing, attachment_file_id, attachment_file_name,
+                       attachment_file_size, attachment_mime_type, attachment_duration_ms,
+                       attachment_width, attachment_height, attachment_encryption_key,
+                       attachment_local_path, caption, attachment_waveform, attachment_view_once,
+                       system_event, sender_device_id, quote, is_starred, attachment_expires_at
+                FROM messages
+                WHERE conversation_id = ?1
+                ORDER BY timestamp ASC
+                "#,
+            )?;
+            let messages = stmt
+                .query_map(params![peer_id], Self::message_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            exports.push(ConversationExport { conversation, messages });
+        }
+        Ok(exports)
+    }
+
     // ============= Messages =============
 
     pub fn save_message(&self, msg: &ChatMessage) -> Result<()> {
         let conn = self.conn.lock();
+        Self::insert_message(&conn, msg)
+    }
 
-        let message_type = match msg.message_type {
-            MessageType::Text => "text",
-            MessageType::Voice => "voice",
-            MessageType::Video => "video",
-            MessageType::Image => "image",
-            MessageType::File => "file",
-        };
+    /// Save a batch of messages in a single transaction
+    pub fn save_messages(&self, messages: &[ChatMessage]) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for msg in messages {
+            Self::insert_message(&tx, msg)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn insert_message(conn: &Connection, msg: &ChatMessage) -> Result<()> {
+        let message_type = msg.message_type.as_str();
 
         let status = match msg.status {
             MessageStatus::Pending => "pending",
@@ -325,6 +737,7 @@ impl Database {
             att_height,
             att_key,
             att_path,
+            att_waveform,
         ) = if let Some(ref att) = msg.attachment {
             (
                 Some(att.file_id.clone()),
@@ -336,9 +749,10 @@ impl Database {
                 att.height,
                 att.encryption_key.clone(),
                 att.local_path.clone(),
+                att.waveform.as_ref().and_then(|w| serde_json::to_string(w).ok()),
             )
         } else {
-            (None, None, None, None, None, None, None, None, None)
+            (None, None, None, None, None, None, None, None, None, None)
         };
 
         conn.execute(
@@ -347,8 +761,10 @@ impl Database {
             (message_id, conversation_id, sender_id, message_type, content, timestamp, status,
              is_outgoing, attachment_file_id, attachment_file_name, attachment_file_size,
              attachment_mime_type, attachment_duration_ms, attachment_width, attachment_height,
-             attachment_encryption_key, attachment_local_path)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+             attachment_encryption_key, attachment_local_path, caption, attachment_waveform,
+             attachment_view_once, system_event, sender_device_id, quote, is_starred,
+             attachment_expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25)
             "#,
             params![
                 msg.message_id,
@@ -368,15 +784,49 @@ impl Database {
                 att_height,
                 att_key,
                 att_path,
+                msg.caption,
+                att_waveform,
+                msg.attachment.as_ref().is_some_and(|a| a.view_once) as i32,
+                msg.system_event.as_ref().and_then(|e| serde_json::to_string(e).ok()),
+                msg.sender_device_id,
+                msg.quote.as_ref().and_then(|q| serde_json::to_string(q).ok()),
+                msg.is_starred as i32,
+                msg.attachment.as_ref().and_then(|a| a.expires_at),
             ],
         )?;
 
         // Update conversation
-        self.update_conversation_last_message(&msg.conversation_id, &msg.content, msg.timestamp)?;
+        Self::touch_conversation(conn, &msg.conversation_id, &msg.content, msg.timestamp)?;
 
         Ok(())
     }
 
+    /// Timestamps and directions of a conversation's messages, oldest
+    /// first, for statistics; system notices are left out
+    pub fn message_samples(&self, conversation_id: &str) -> Result<Vec<MessageSample>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT timestamp, is_outgoing FROM messages
+            WHERE conversation_id = ?1 AND message_type != 'system'
+            ORDER BY timestamp ASC
+            "#,
+        )?;
+
+        let samples = stmt
+            .query_map(params![conversation_id], |row| {
+                Ok(MessageSample {
+                    timestamp: row.get(0)?,
+                    is_outgoing: row.get::<_, i32>(1)? != 0,
+                })
+            })?
+            .filter_map(|r| r.ok())
+            .collect();
+
+        Ok(samples)
+    }
+
     pub fn get_messages(
         &self,
         conversation_id: &str,
@@ -391,7 +841,8 @@ impl Database {
                    status, is_outgoing, attachment_file_id, attachment_file_name,
                    attachment_file_size, attachment_mime_type, attachment_duration_ms,
                    attachment_width, attachment_height, attachment_encryption_key,
-                   attachment_local_path
+                   attachment_local_path, caption, attachment_waveform, attachment_view_once,
+                       system_event, sender_device_id, quote, is_starred, attachment_expires_at
             FROM messages
             WHERE conversation_id = ?1
             ORDER BY timestamp ASC
@@ -400,59 +851,118 @@ impl Database {
         )?;
 
         let messages = stmt
-            .query_map(params![conversation_id, limit, offset], |row| {
-                let message_type = match row.get::<_, String>(3)?.as_str() {
-                    "text" => MessageType::Text,
-                    "voice" => MessageType::Voice,
-                    "video" => MessageType::Video,
-                    "image" => MessageType::Image,
-                    "file" => MessageType::File,
-                    _ => MessageType::Text,
-                };
-
-                let status = match row.get::<_, String>(6)?.as_str() {
-                    "pending" => MessageStatus::Pending,
-                    "sent" => MessageStatus::Sent,
-                    "delivered" => MessageStatus::Delivered,
-                    "read" => MessageStatus::Read,
-                    "failed" => MessageStatus::Failed,
-                    _ => MessageStatus::Pending,
-                };
-
-                let attachment = if let Some(file_id) = row.get::<_, Option<String>>(8)? {
-                    Some(Attachment {
-                        file_id,
-                        file_name: row.get(9)?,
-                        file_size: row.get(10)?,
-                        mime_type: row.get(11)?,
-                        duration_ms: row.get(12)?,
-                        width: row.get(13)?,
-                        height: row.get(14)?,
-                        encryption_key: row.get(15)?,
-                        local_path: row.get(16)?,
-                    })
-                } else {
-                    None
-                };
-
-                Ok(ChatMessage {
-                    message_id: row.get(0)?,
-                    conversation_id: row.get(1)?,
-                    sender_id: row.get(2)?,
-                    message_type,
-                    content: row.get(4)?,
-                    timestamp: row.get(5)?,
-                    status,
-                    is_outgoing: row.get::<_, i32>(7)? != 0,
-                    attachment,
-                })
-            })?
+            .query_map(params![conversation_id, limit, offset], Self::message_from_row)?
             .filter_map(|r| r.ok())
             .collect();
 
         Ok(messages)
     }
 
+    /// Columns as selected by `get_messages`
+    fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<ChatMessage> {
+        let message_type = match row.get::<_, String>(3)?.as_str() {
+            "text" => MessageType::Text,
+            "voice" => MessageType::Voice,
+            "video" => MessageType::Video,
+            "image" => MessageType::Image,
+            "file" => MessageType::File,
+            "system" => MessageType::System,
+            _ => MessageType::Text,
+        };
+
+        let status = match row.get::<_, String>(6)?.as_str() {
+            "pending" => MessageStatus::Pending,
+            "sent" => MessageStatus::Sent,
+            "delivered" => MessageStatus::Delivered,
+            "read" => MessageStatus::Read,
+            "failed" => MessageStatus::Failed,
+            _ => MessageStatus::Pending,
+        };
+
+        let attachment = if let Some(file_id) = row.get::<_, Option<String>>(8)? {
+            Some(Attachment {
+                file_id,
+                file_name: row.get(9)?,
+                file_size: row.get(10)?,
+                mime_type: row.get(11)?,
+                duration_ms: row.get(12)?,
+                width: row.get(13)?,
+                height: row.get(14)?,
+                encryption_key: row.get(15)?,
+                local_path: row.get(16)?,
+                waveform: row
+                    .get::<_, Option<String>>(18)?
+                    .and_then(|w| serde_json::from_str(&w).ok()),
+                view_once: row.get::<_, i32>(19)? != 0,
+                expires_at: row.get(24)?,
+            })
+        } else {
+            None
+        };
+
+        Ok(ChatMessage {
+            message_id: row.get(0)?,
+            conversation_id: row.get(1)?,
+            sender_id: row.get(2)?,
+            message_type,
+            content: row.get(4)?,
+            timestamp: row.get(5)?,
+            status,
+            is_outgoing: row.get::<_, i32>(7)? != 0,
+            attachment,
+            caption: row.get(17)?,
+            system_event: row
+                .get::<_, Option<String>>(20)?
+                .and_then(|e| serde_json::from_str(&e).ok()),
+            sender_device_id: row.get(21)?,
+            quote: row
+                .get::<_, Option<String>>(22)?
+                .and_then(|q| serde_json::from_str(&q).ok()),
+            is_starred: row.get::<_, i32>(23)? != 0,
+        })
+    }
+
+    pub fn delete_message(&self, message_id: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        conn.execute("DELETE FROM messages WHERE message_id = ?1", params![message_id])?;
+        conn.execute("DELETE FROM message_delivery WHERE message_id = ?1", params![message_id])?;
+        Ok(())
+    }
+
+    pub fn set_message_starred(&self, message_id: &str, starred: bool) -> Result<()> {
+        let conn = self.conn.lock();
+        conn.execute(
+            "UPDATE messages SET is_starred = ?1 WHERE message_id = ?2",
+            params![starred as i32, message_id],
+        )?;
+        Ok(())
+    }
+
+    /// Delete messages in one transaction
+    pub fn delete_messages(&self, message_ids: &[String]) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for message_id in message_ids {
+            tx.execute("DELETE FROM messages WHERE message_id = ?1", params![message_id])?;
+            tx.execute("DELETE FROM message_delivery WHERE message_id = ?1", params![message_id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn set_messages_starred(&self, message_ids: &[String], starred: bool) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for message_id in message_ids {
+            tx.execute(
+                "UPDATE messages SET is_starred = ?1 WHERE message_id = ?2",
+                params![starred as i32, message_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
     pub fn update_message_status(&self, message_id: &str, status: MessageStatus) -> Result<()> {
         let conn = self.conn.lock();
 
@@ -472,6 +982,185 @@ impl Database {
         Ok(())
     }
 
+    /// Mark outgoing messages delivered unless they already got further;
+    /// returns the ids that changed
+    pub fn mark_delivered(&self, message_ids: &[String]) -> Result<Vec<String>> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let mut changed = Vec::new();
+        for message_id in message_ids {
+            let updated = tx.execute(
+                "UPDATE messages SET status = 'delivered'
+                 WHERE message_id = ?1 AND is_outgoing = 1 AND status IN ('pending', 'sent', 'failed')",
+                params![message_id],
+            )?;
+            if updated > 0 {
+                changed.push(message_id.clone());
+            }
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    // ============= Message rules =============
+
+    /// Add a rule at the end, or replace the one with its id in place
+    pub fn save_message_rule(&self, rule: &MessageRule) -> Result<()> {
+        let conn = self.conn.lock();
+
+        conn.execute(
+            r#"
+            INSERT INTO message_rules (id, rule_json, position)
+            VALUES (?1, ?2, (SELECT COALESCE(MAX(position), 0) + 1 FROM message_rules))
+            ON CONFLICT(id) DO UPDATE SET rule_json = excluded.rule_json
+            "#,
+            params![rule.id, serde_json::to_string(rule)?],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_message_rules(&self) -> Result<Vec<MessageRule>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare("SELECT rule_json FROM message_rules ORDER BY position")?;
+        let rules = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|r| r.ok())
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect();
+
+        Ok(rules)
+    }
+
+    pub fn delete_message_rule(&self, id: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        conn.execute("DELETE FROM message_rules WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    // ============= Wallpapers =============
+
+    /// Set or, with `None`, remove a conversation's own wallpaper
+    pub fn set_conversation_wallpaper(&self, peer_id: &str, wallpaper: Option<&Wallpaper>) -> Result<()> {
+        let conn = self.conn.lock();
+        match wallpaper {
+            Some(wallpaper) => conn.execute(
+                "INSERT OR REPLACE INTO conversation_wallpapers (peer_id, wallpaper_json) VALUES (?1, ?2)",
+                params![peer_id, serde_json::to_string(wallpaper)?],
+            )?,
+            None => conn.execute(
+                "DELETE FROM conversation_wallpapers WHERE peer_id = ?1",
+                params![peer_id],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// Conversations' own wallpapers by peer id
+    pub fn get_conversation_wallpapers(&self) -> Result<HashMap<String, Wallpaper>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare("SELECT peer_id, wallpaper_json FROM conversation_wallpapers")?;
+        let wallpapers = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .filter_map(|r| r.ok())
+            .filter_map(|(peer_id, json)| Some((peer_id, serde_json::from_str(&json).ok()?)))
+            .collect();
+
+        Ok(wallpapers)
+    }
+
+    pub fn set_peer_unavailable(&self, peer_id: &str, unavailable: bool) -> Result<()> {
+        let conn = self.conn.lock();
+        if unavailable {
+            conn.execute(
+                "INSERT OR IGNORE INTO unavailable_peers (peer_id, since) VALUES (?1, strftime('%s', 'now'))",
+                params![peer_id],
+            )?;
+        } else {
+            conn.execute("DELETE FROM unavailable_peers WHERE peer_id = ?1", params![peer_id])?;
+        }
+        Ok(())
+    }
+
+    pub fn get_unavailable_peers(&self) -> Result<HashSet<String>> {
+        let conn = self.conn.lock();
+
+        let mut stmt = conn.prepare("SELECT peer_id FROM unavailable_peers")?;
+        let peers = stmt
+            .query_map([], |row| row.get(0))?
+            .filter_map(|r| r.ok())
+            .collect();
+
+        Ok(peers)
+    }
+
+    /// The conversation a stored message belongs to
+    pub fn get_message_conversation(&self, message_id: &str) -> Option<String> {
+        let conn = self.conn.lock();
+
+        conn.query_row(
+            "SELECT conversation_id FROM messages WHERE message_id = ?1",
+            params![message_id],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    // ============= Delivery =============
+
+    /// Add `attempts` to a message's delivery, and set when it went out if
+    /// the last one succeeded
+    pub fn record_delivery(&self, message_id: &str, attempts: u32, sent: bool) -> Result<()> {
+        let conn = self.conn.lock();
+        let sent_at = sent.then(|| chrono::Utc::now().timestamp_millis());
+        conn.execute(
+            r#"
+            INSERT INTO message_delivery (message_id, attempts, sent_at)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(message_id) DO UPDATE SET
+                attempts = attempts + excluded.attempts,
+                sent_at = COALESCE(excluded.sent_at, sent_at)
+            "#,
+            params![message_id, attempts, sent_at],
+        )?;
+        Ok(())
+    }
+
+    /// Acks of messages not sent from this device are ignored
+    pub fn set_acked(&self, message_ids: &[String], acked_at: i64) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for message_id in message_ids {
+            tx.execute(
+                "UPDATE message_delivery SET acked_at = ?2 WHERE message_id = ?1 AND acked_at IS NULL",
+                params![message_id, acked_at],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_delivery(&self, message_id: &str) -> Result<Option<DeliveryInfo>> {
+        let conn = self.conn.lock();
+        match conn.query_row(
+            "SELECT attempts, sent_at, acked_at FROM message_delivery WHERE message_id = ?1",
+            params![message_id],
+            |row| {
+                Ok(DeliveryInfo {
+                    attempts: row.get(0)?,
+                    sent_at: row.get(1)?,
+                    acked_at: row.get(2)?,
+                })
+            },
+        ) {
+            Ok(delivery) => Ok(Some(delivery)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     // ============= Settings =============
 
     pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
@@ -508,10 +1197,24 @@ impl Database {
             DELETE FROM conversations;
             DELETE FROM messages;
             DELETE FROM peer_keys;
+            DELETE FROM archived_peer_keys;
+            DELETE FROM profiles;
             DELETE FROM settings;
+            DELETE FROM message_rules;
+            DELETE FROM message_delivery;
+            DELETE FROM conversation_wallpapers;
+            DELETE FROM unavailable_peers;
             "#,
         )?;
 
         Ok(())
     }
+
+    /// Clear everything and compact the file, so deleted rows don't linger
+    /// in free pages
+    pub fn wipe(&self) -> Result<()> {
+        self.clear_all()?;
+        self.conn.lock().execute_batch("VACUUM")?;
+        Ok(())
+    }
 }
@@ -0,0 +1,120 @@
+//! privmsg:// links
+//!
+//! - `privmsg://chat/<user_id>` opens (or starts) a chat
+//! - `privmsg://call/<user_id>` starts a voice call
+//! - `privmsg://invite/<token>` points the login screen at the inviter's
+//!   server and opens a chat with them once signed in. The token is
+//!   URL-safe base64 of the [`Invite`] JSON; it never carries credentials.
+
+use crate::packaging::URL_SCHEME;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Invite {
+    pub host: String,
+    pub port: u16,
+    #[serde(default = "default_tls")]
+    pub use_tls: bool,
+    pub user_id: String,
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeepLink {
+    Chat(String),
+    Call(String),
+    Invite(Invite),
+}
+
+impl FromStr for DeepLink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .trim()
+            .strip_prefix(URL_SCHEME)
+            .and_then(|r| r.strip_prefix("://"))
+            .ok_or_else(|| anyhow::anyhow!("Not a {}:// link", URL_SCHEME))?;
+
+        // Browsers append a trailing slash; query strings are ignored
+        let rest = rest.split(['?', '#']).next().unwrap_or_default();
+        let (kind, arg) = rest
+            .trim_end_matches('/')
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("Incomplete link"))?;
+        anyhow::ensure!(!arg.is_empty() && !arg.contains('/'), "Malformed link");
+
+        match kind {
+            "chat" => Ok(Self::Chat(arg.to_string())),
+            "call" => Ok(Self::Call(arg.to_string())),
+            "invite" => {
+                let json = URL_SAFE_NO_PAD.decode(arg.trim_end_matches('='))?;
+                let invite: Invite = serde_json::from_slice(&json)?;
+                anyhow::ensure!(
+                    !invite.host.is_empty() && !invite.user_id.is_empty(),
+                    "Incomplete invite"
+                );
+                Ok(Self::Invite(invite))
+            }
+            other => anyhow::bail!("Unknown link type: {}", other),
+        }
+    }
+}
+
+impl DeepLink {
+    /// Links passed on the command line; anything else there is ignored
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Vec<Self> {
+        args.into_iter()
+            .filter(|arg| arg.starts_with(URL_SCHEME))
+            .filter_map(|arg| match arg.parse() {
+                Ok(link) => Some(link),
+                Err(e) => {
+                    tracing::warn!("Ignoring link {:?}: {}", arg, e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_links() {
+        assert_eq!(
+            "privmsg://chat/ABC123/".parse::<DeepLink>().unwrap(),
+            DeepLink::Chat("ABC123".into())
+        );
+        assert_eq!(
+            "privmsg://call/ABC123?video=0".parse::<DeepLink>().unwrap(),
+            DeepLink::Call("ABC123".into())
+        );
+
+        let token = URL_SAFE_NO_PAD
+            .encode(r#"{"host":"chat.example.org","port":443,"user_id":"ABC123"}"#);
+        match format!("privmsg://invite/{}", token).parse::<DeepLink>().unwrap() {
+            DeepLink::Invite(invite) => {
+                assert_eq!(invite.host, "chat.example.org");
+                assert!(invite.use_tls);
+                assert_eq!(invite.user_id, "ABC123");
+            }
+            other => panic!("Unexpected link: {:?}", other),
+        }
+
+        assert!("https://chat/ABC123".parse::<DeepLink>().is_err());
+        assert!("privmsg://chat/".parse::<DeepLink>().is_err());
+        assert!("privmsg://chat/a/b".parse::<DeepLink>().is_err());
+        assert!("privmsg://invite/not-base64!".parse::<DeepLink>().is_err());
+        assert!("privmsg://settings/x".parse::<DeepLink>().is_err());
+
+        let args = vec!["privmsg-desktop".into(), "--verbose".into(), "privmsg://chat/X".into()];
+        assert_eq!(DeepLink::from_args(args), vec![DeepLink::Chat("X".into())]);
+    }
+}
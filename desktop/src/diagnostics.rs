@@ -0,0 +1,213 @@
+//! Connection and storage self-checks for the diagnostics screen
+//!
+//! The report holds no message content, keys or tokens, so users can copy
+//! it into an issue as is.
+
+use std::time::{Duration, Instant};
+
+use privmsg_proto::turn_address;
+use tokio::net::TcpStream;
+
+use crate::database::Database;
+use crate::network::NetworkClient;
+
+/// Clock skew above this breaks nothing yet but is worth fixing
+const CLOCK_SKEW_WARNING_SECS: i64 = 30;
+/// Clock skew above this makes message ordering and expiry unreliable
+const CLOCK_SKEW_FAILURE_SECS: i64 = 300;
+/// How long a TURN server gets to accept a TCP connection
+const TURN_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Failed,
+    /// Couldn't run, e.g. it needs a login
+    Skipped,
+}
+
+impl CheckStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Failed => "failed",
+            CheckStatus::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiagnosticCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiagnosticsReport {
+    /// Unix seconds
+    pub generated_at: i64,
+    pub checks: Vec<DiagnosticCheck>,
+}
+
+impl DiagnosticsReport {
+    /// Plain text for pasting into an issue
+    pub fn to_text(&self) -> String {
+        let mut out = format!(
+            "PrivMsg Desktop diagnostics\nversion: {}\nplatform: {}/{}\ngenerated: {}\n\n",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            chrono::DateTime::from_timestamp(self.generated_at, 0)
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default(),
+        );
+        for check in &self.checks {
+            out.push_str(&format!(
+                "[{}] {}: {} ({} ms)\n",
+                check.status.as_str(),
+                check.name,
+                check.detail,
+                check.duration_ms
+            ));
+        }
+        out
+    }
+}
+
+/// Run every check. `network` may be a client that never logged in; checks
+/// that need a session are then skipped.
+pub async fn run(network: &NetworkClient, db: &Database) -> DiagnosticsReport {
+    let mut checks = Vec::new();
+
+    let start = Instant::now();
+    let before = chrono::Utc::now().timestamp_millis();
+    let health = network.server_time().await;
+    let server_ms = start.elapsed().as_millis() as u64;
+    // Compare against the middle of the round trip
+    let local = (before + chrono::Utc::now().timestamp_millis()) / 2000;
+
+    match health {
+        Ok(server_time) => {
+            checks.push(check("server", server_ms, CheckStatus::Ok, "health check passed".into()));
+            let (status, detail) = clock_skew(local - server_time);
+            checks.push(check("clock", 0, status, detail));
+        }
+        Err(e) => {
+            checks.push(check("server", server_ms, CheckStatus::Failed, e.to_string()));
+            checks.push(check("clock", 0, CheckStatus::Skipped, "server unreachable".into()));
+        }
+    }
+
+    let logged_in = network.is_authenticated();
+    checks.push(if !logged_in {
+        check("websocket", 0, CheckStatus::Skipped, "not logged in".into())
+    } else if network.is_connected() {
+        check("websocket", 0, CheckStatus::Ok, "connected".into())
+    } else {
+        check("websocket", 0, CheckStatus::Failed, "disconnected".into())
+    });
+
+    let start = Instant::now();
+    let (status, detail) = if logged_in {
+        check_turn(network).await
+    } else {
+        (CheckStatus::Skipped, "not logged in".into())
+    };
+    checks.push(check("turn", start.elapsed().as_millis() as u64, status, detail));
+
+    let start = Instant::now();
+    let (status, detail) = match db.integrity_check() {
+        Ok(problems) if problems.is_empty() => (CheckStatus::Ok, "integrity check passed".into()),
+        Ok(problems) => (CheckStatus::Failed, problems.join("; ")),
+        Err(e) => (CheckStatus::Failed, e.to_string()),
+    };
+    checks.push(check("database", start.elapsed().as_millis() as u64, status, detail));
+
+    checks.push(if !logged_in {
+        check("keys", 0, CheckStatus::Skipped, "not logged in".into())
+    } else if network.has_identity() {
+        check("keys", 0, CheckStatus::Ok, "identity key loaded".into())
+    } else {
+        check("keys", 0, CheckStatus::Failed, "no identity key; log in again to create one".into())
+    });
+
+    DiagnosticsReport {
+        generated_at: chrono::Utc::now().timestamp(),
+        checks,
+    }
+}
+
+fn check(name: &'static str, duration_ms: u64, status: CheckStatus, detail: String) -> DiagnosticCheck {
+    DiagnosticCheck {
+        name,
+        status,
+        detail,
+        duration_ms,
+    }
+}
+
+/// `skew` is local minus server time, in seconds
+fn clock_skew(skew: i64) -> (CheckStatus, String) {
+    let detail = match skew {
+        0 => "in sync with the server".to_string(),
+        s if s > 0 => format!("{}s ahead of the server", s),
+        s => format!("{}s behind the server", -s),
+    };
+    let status = match skew.abs() {
+        s if s > CLOCK_SKEW_FAILURE_SECS => CheckStatus::Failed,
+        s if s > CLOCK_SKEW_WARNING_SECS => CheckStatus::Warning,
+        _ => CheckStatus::Ok,
+    };
+    (status, detail)
+}
+
+/// TCP connect to each TURN URL. UDP can't be checked this way, so an
+/// unreachable server is only a warning.
+async fn check_turn(network: &NetworkClient) -> (CheckStatus, String) {
+    let credentials = match network.get_turn_credentials().await {
+        Ok(credentials) => credentials,
+        Err(e) => return (CheckStatus::Failed, format!("no TURN credentials: {}", e)),
+    };
+    if credentials.urls.is_empty() {
+        return (CheckStatus::Warning, "server lists no TURN servers; calls need a direct path".into());
+    }
+
+    let mut status = CheckStatus::Ok;
+    let mut results = Vec::new();
+    for url in &credentials.urls {
+        let Some(addr) = turn_address(url) else {
+            status = CheckStatus::Warning;
+            results.push(format!("{}: not a turn: URL", url));
+            continue;
+        };
+        match tokio::time::timeout(TURN_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+            Ok(Ok(_)) => results.push(format!("{} reachable", addr)),
+            Ok(Err(e)) => {
+                status = CheckStatus::Warning;
+                results.push(format!("{} unreachable: {}", addr, e));
+            }
+            Err(_) => {
+                status = CheckStatus::Warning;
+                results.push(format!("{} timed out", addr));
+            }
+        }
+    }
+    (status, results.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_skew() {
+        assert_eq!(clock_skew(0), (CheckStatus::Ok, "in sync with the server".to_string()));
+        assert_eq!(clock_skew(-12).0, CheckStatus::Ok);
+        assert_eq!(clock_skew(45), (CheckStatus::Warning, "45s ahead of the server".to_string()));
+        assert_eq!(clock_skew(-600), (CheckStatus::Failed, "600s behind the server".to_string()));
+    }
+}
@@ -0,0 +1,199 @@
+//! Single-instance enforcement
+//!
+//! Two copies sharing one data directory would both write the SQLite
+//! database and hold their own WebSocket connection, so the first copy
+//! takes an exclusive lock on `<data_dir>/instance.lock` for as long as it
+//! runs. It also listens on a loopback port, recorded with a random token
+//! in `<data_dir>/instance`. A later launch that finds the lock taken
+//! connects there, sends the token followed by its command-line arguments
+//! one per line, and exits; the running copy brings its window forward and
+//! opens any privmsg:// links among them.
+
+use anyhow::{Context, Result};
+use iced::Subscription;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::deeplink::DeepLink;
+
+const LOCK_FILE: &str = "instance.lock";
+const INSTANCE_FILE: &str = "instance";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a second launch waits for a just-started first one to listen
+const FORWARD_ATTEMPTS: u32 = 10;
+const FORWARD_RETRY: Duration = Duration::from_millis(200);
+
+pub enum Acquired {
+    /// This process is the only one running
+    Primary(Instance),
+    /// Another process holds the lock
+    Running,
+}
+
+pub struct Instance {
+    _lock: File,
+    listener: TcpListener,
+    token: String,
+    path: PathBuf,
+}
+
+/// Arguments another launch handed over
+#[derive(Debug, Clone)]
+pub struct Activation {
+    pub links: Vec<DeepLink>,
+}
+
+/// Take the instance lock and start accepting activations
+pub fn acquire(data_dir: &Path) -> Result<Acquired> {
+    let lock_path = data_dir.join(LOCK_FILE);
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Cannot open {:?}", lock_path))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => return Ok(Acquired::Running),
+        Err(TryLockError::Error(e)) => return Err(e).context("Cannot lock instance file"),
+    }
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .context("Cannot bind instance socket")?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+    let token = hex::encode(rand::random::<[u8; 16]>());
+
+    let path = data_dir.join(INSTANCE_FILE);
+    std::fs::write(&path, format!("{} {}\n", port, token))
+        .with_context(|| format!("Cannot write {:?}", path))?;
+
+    Ok(Acquired::Primary(Instance {
+        _lock: lock,
+        listener,
+        token,
+        path,
+    }))
+}
+
+impl Instance {
+    /// Activations from later launches
+    pub fn activations(self: &Arc<Self>) -> Subscription<Activation> {
+        let instance = self.clone();
+        iced::subscription::channel(INSTANCE_FILE, 16, move |mut output| async move {
+            let listener = instance
+                .listener
+                .try_clone()
+                .and_then(tokio::net::TcpListener::from_std);
+            let listener = match listener {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!("Instance socket unusable: {}", e);
+                    return futures::future::pending().await;
+                }
+            };
+
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Instance socket accept failed: {}", e);
+                        tokio::time::sleep(FORWARD_RETRY).await;
+                        continue;
+                    }
+                };
+                let read = tokio::time::timeout(READ_TIMEOUT, instance.read_args(stream));
+                match read.await {
+                    Ok(Ok(args)) => {
+                        use futures::SinkExt;
+                        let links = DeepLink::from_args(args);
+                        let _ = output.send(Activation { links }).await;
+                    }
+                    Ok(Err(e)) => tracing::warn!("Rejected activation: {}", e),
+                    Err(_) => tracing::warn!("Activation connection timed out"),
+                }
+            }
+        })
+    }
+
+    async fn read_args(&self, stream: tokio::net::TcpStream) -> Result<Vec<String>> {
+        let mut lines = BufReader::new(stream).lines();
+        let token = lines.next_line().await?.unwrap_or_default();
+        anyhow::ensure!(token == self.token, "bad token");
+
+        let mut args = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            args.push(line);
+        }
+        Ok(args)
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        // Before the lock is released, so a new primary's record isn't removed
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Hand `args` to the running instance. The lock holder may still be
+/// starting up, so a missing or stale record is retried briefly.
+pub fn forward(data_dir: &Path, args: &[String]) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        match try_forward(data_dir, args) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= FORWARD_ATTEMPTS => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                std::thread::sleep(FORWARD_RETRY);
+            }
+        }
+    }
+}
+
+fn try_forward(data_dir: &Path, args: &[String]) -> Result<()> {
+    let record = std::fs::read_to_string(data_dir.join(INSTANCE_FILE))?;
+    let (port, token) = record
+        .trim()
+        .split_once(' ')
+        .context("Malformed instance file")?;
+    let port: u16 = port.parse()?;
+
+    let addr = (Ipv4Addr::LOCALHOST, port).into();
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    writeln!(stream, "{}", token)?;
+    for arg in args {
+        // One argument per line; an embedded newline would split it
+        writeln!(stream, "{}", arg.replace(['\r', '\n'], " "))?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_acquire_sees_running_instance() {
+        let dir = std::env::temp_dir().join(format!("privmsg-instance-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let first = acquire(&dir).unwrap();
+        assert!(matches!(first, Acquired::Primary(_)));
+        assert!(matches!(acquire(&dir).unwrap(), Acquired::Running));
+
+        drop(first);
+        assert!(!dir.join(INSTANCE_FILE).exists());
+        assert!(matches!(acquire(&dir).unwrap(), Acquired::Primary(_)));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -4,14 +4,29 @@
 //! Built with iced GUI framework.
 
 mod app;
+mod applock;
+mod audio;
+mod backup;
+mod camera;
+mod clipboard;
 mod config;
 mod crypto;
+mod data_export;
 mod database;
+mod deeplink;
+mod diagnostics;
+mod instance;
+mod message_store;
 mod messages;
 mod network;
+mod notifications;
+mod packaging;
+mod policy;
+mod power;
 mod screens;
 mod state;
 mod theme;
+mod wallpaper;
 mod widgets;
 
 use iced::{Application, Settings, Size};
@@ -38,7 +53,46 @@ fn main() -> iced::Result {
     tracing::info!("Data directory: {:?}", data_dir);
 
     // Load or create config
-    let config = config::AppConfig::load(&data_dir).unwrap_or_default();
+    let mut config = config::AppConfig::load(&data_dir).unwrap_or_default();
+
+    // Installers call this before removing the app
+    if std::env::args().any(|arg| arg == "--uninstall") {
+        if let Err(e) = packaging::uninstall() {
+            tracing::error!("Uninstall failed: {}", e);
+        }
+        config.integration = Default::default();
+        config.save(&data_dir).ok();
+        return Ok(());
+    }
+
+    // Register the link handler and menu entries on first run
+    if !config.integration.installed {
+        match packaging::install(config.integration.start_on_login) {
+            Ok(()) => {
+                config.integration.installed = true;
+                config.save(&data_dir).ok();
+            }
+            Err(e) => tracing::warn!("Desktop integration failed: {}", e),
+        }
+    }
+
+    // One process per data directory. The OS also launches a new process
+    // for every privmsg:// link; those hand over to the open window.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let instance = match instance::acquire(&data_dir) {
+        Ok(instance::Acquired::Primary(instance)) => Some(std::sync::Arc::new(instance)),
+        Ok(instance::Acquired::Running) => {
+            match instance::forward(&data_dir, &args) {
+                Ok(()) => tracing::info!("Already running; activated the existing window"),
+                Err(e) => tracing::error!("Already running, but it isn't responding: {}", e),
+            }
+            return Ok(());
+        }
+        Err(e) => {
+            tracing::warn!("Single-instance lock unavailable: {}", e);
+            None
+        }
+    };
 
     // Run application
     app::PrivMsg::run(Settings {
@@ -54,6 +108,8 @@ fn main() -> iced::Result {
         flags: app::Flags {
             data_dir,
             config,
+            instance,
+            links: deeplink::DeepLink::from_args(args),
         },
         ..Default::default()
     })
@@ -0,0 +1,115 @@
+//! Indexed message list for the open chat
+//!
+//! Messages stay ordered by timestamp for rendering and are indexed by
+//! message_id, so appends and status patches don't scan or rebuild the list.
+
+use std::collections::HashMap;
+
+/// What the store needs to know about a message
+pub trait StoredMessage {
+    fn id(&self) -> &str;
+    fn timestamp(&self) -> i64;
+}
+
+pub struct MessageStore<T> {
+    messages: Vec<T>,
+    index: HashMap<String, usize>,
+}
+
+impl<T> Default for MessageStore<T> {
+    fn default() -> Self {
+        Self {
+            messages: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<T: StoredMessage> MessageStore<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the contents, e.g. when a chat is opened
+    pub fn replace_all(&mut self, mut messages: Vec<T>) {
+        messages.sort_by_key(|m| m.timestamp());
+        self.index = messages
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (m.id().to_string(), i))
+            .collect();
+        self.messages = messages;
+    }
+
+    /// Add a message, or replace the one with the same id
+    ///
+    /// New messages almost always arrive last, which is a plain push; late
+    /// arrivals are inserted in place and only the entries after them are
+    /// re-indexed.
+    pub fn append(&mut self, message: T) {
+        if let Some(&idx) = self.index.get(message.id()) {
+            self.messages[idx] = message;
+            return;
+        }
+
+        let pos = match self.messages.last() {
+            Some(last) if last.timestamp() > message.timestamp() => self
+                .messages
+                .partition_point(|m| m.timestamp() <= message.timestamp()),
+            _ => self.messages.len(),
+        };
+
+        self.messages.insert(pos, message);
+        for (i, message) in self.messages.iter().enumerate().skip(pos) {
+            self.index.insert(message.id().to_string(), i);
+        }
+    }
+
+    /// Modify a message in place. Returns false if it isn't loaded.
+    pub fn patch(&mut self, id: &str, f: impl FnOnce(&mut T)) -> bool {
+        // Patches must not change the id or timestamp, or the index goes stale
+        match self.index.get(id) {
+            Some(&idx) => {
+                f(&mut self.messages[idx]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take a message out, e.g. when it is deleted
+    pub fn remove(&mut self, id: &str) -> Option<T> {
+        let idx = self.index.remove(id)?;
+        let message = self.messages.remove(idx);
+        for (i, message) in self.messages.iter().enumerate().skip(idx) {
+            self.index.insert(message.id().to_string(), i);
+        }
+        Some(message)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&T> {
+        self.index.get(id).map(|&idx| &self.messages[idx])
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.messages.iter()
+    }
+
+    /// The newest `count` messages, oldest first
+    pub fn tail(&self, count: usize) -> impl Iterator<Item = &T> {
+        self.iter().skip(self.len().saturating_sub(count))
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+        self.index.clear();
+    }
+}
@@ -1,7 +1,22 @@
 //! Application messages (events)
 
-use crate::network::WsEvent;
-use crate::state::{AuthSession, ChatMessage, Conversation, Screen, User};
+use crate::applock::OsEvent;
+use crate::backup::BackupEntry;
+use crate::camera::CapturedPhoto;
+use crate::clipboard::ClipboardClear;
+use crate::deeplink::DeepLink;
+use crate::diagnostics::DiagnosticsReport;
+use crate::instance::Activation;
+use crate::power::PowerSource;
+use crate::wallpaper::Wallpaper;
+use privmsg_proto::capabilities::ServerCapabilities;
+use privmsg_proto::policy::SignedPolicy;
+use privmsg_proto::rules::RuleAction;
+use privmsg_proto::{LastSeenVisibility, RevocationReason};
+use crate::network::{TransferOutcome, WsEvent};
+use crate::state::{
+    Attachment, AuthSession, ChatMessage, Conversation, Device, ListViewport, Screen, User,
+};
 use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
@@ -14,6 +29,7 @@ pub enum Message {
     ServerHostChanged(String),
     ServerPortChanged(String),
     UseTlsChanged(bool),
+    AllowInsecureLocalhostChanged(bool),
     UserIdChanged(String),
     AccessKeyChanged(String),
     Login,
@@ -21,17 +37,78 @@ pub enum Message {
     LoginError(String),
     TryRestoreSession,
     Logout,
+    /// The server ended the session; back to login with the reason shown
+    SessionRevoked(RevocationReason),
+    /// Hide the server's announcement banner until it sets a new one
+    DismissAnnouncement,
+    // Panic button: end all sessions, wipe this device and exit
+    ShowPanic,
+    CancelPanic,
+    PanicKeepKeyToggled(bool),
+    PanicConfirmChanged(String),
+    PanicWipe,
+    /// Wipe, first saving the identity key to the path if one was picked
+    PanicWipeTo(Option<PathBuf>),
+    /// Fails only if the identity key couldn't be saved; nothing is wiped then
+    PanicWiped(Result<(), String>),
+
+    // Another launch / privmsg:// links
+    Activated(Activation),
+    DeepLinkOpened(DeepLink),
 
     // Conversations
     LoadConversations,
-    ConversationsLoaded(Vec<Conversation>),
+    /// A page of the list: load generation, offset and rows
+    ConversationPageLoaded(u64, usize, Vec<Conversation>),
+    /// Rows re-read after they changed, placed without a reload
+    ConversationsChanged(Vec<Conversation>),
+    ConversationsRemoved(Vec<String>), // peer_ids
+    ConversationListScrolled(ListViewport),
     OpenChat(String),
     MessagesLoaded(Vec<ChatMessage>),
+    ShowEarlierMessages,
+
+    // Multi-select in the conversation list
+    ToggleSelectionMode,
+    ToggleConversationSelected(String), // peer_id
+    SelectAllConversations,
+    MarkSelectedRead,
+    MuteSelected(bool),
+    ArchiveSelected(bool),
+    DeleteSelected,
+    ExportSelected,
+    ExportSelectedTo(Vec<String>, PathBuf),
 
     // Messaging
     MessageInputChanged(String),
     SendMessage,
     MessageSent(ChatMessage),
+    MessageFailed(ChatMessage, String),
+    /// Not sent because the recipient's account is gone
+    RecipientUnavailable(ChatMessage),
+    /// Not sent because the recipient's security key changed
+    PeerKeyChanged(ChatMessage),
+    /// Trust the changed security key of a peer
+    AcceptPeerKey(String),
+    PeerKeyAccepted(String),
+    ToggleFailedActions(String), // message_id
+    ResendMessage(String),       // message_id
+    DeleteMessage(String),       // message_id
+    ShowMessageInfo(String),     // message_id
+    CloseMessageInfo,
+    ReplyToMessage(String),      // message_id
+    ReplyPrivately(String),      // message_id
+    CancelReply,
+    ForwardMessage(String),      // message_id
+    ForwardTo(String),           // peer_id
+    CancelForward,
+    ToggleStarred(String),       // message_id
+    ToggleMessageSelected(String), // message_id
+    ClearMessageSelection,
+    ForwardSelected,
+    DeleteSelectedMessages,
+    StarSelected(bool),
+    AutoRetriesChanged(u32),
     MessageReceived(ChatMessage),
 
     // Search
@@ -41,16 +118,70 @@ pub enum Message {
     StartChatWithUser(String),
     ToggleSearch,
 
+    // Profiles
+    ProfileLoaded(User),
+    ProfileRefreshTick,
+    ToggleChatDetails,
+    StatusEmojiChanged(String),
+    StatusTextChanged(String),
+    BioChanged(String),
+    SaveProfile,
+    ProfileSaved(User),
+    LastSeenVisibilityChanged(LastSeenVisibility),
+
+    // Devices
+    DevicesLoaded(Vec<Device>),
+    StartRenameDevice(String),
+    DeviceNameChanged(String),
+    SaveDeviceName,
+    CancelRenameDevice,
+    DeviceRenamed(Device),
+
     // Voice recording
     StartRecordingVoice,
     StopRecordingVoice,
     CancelRecordingVoice,
 
+    // Voice playback
+    PlayVoice(String),        // message_id
+    SeekVoice(String, f32),   // message_id, position 0.0-1.0
+    VoiceLoaded(String, Vec<u8>),
+    VoicePlaybackFinished(String),
+    VoicePlaybackTick,
+
     // File attachments
     AttachFile,
-    FileSelected(PathBuf),
+    FilesSelected(Vec<PathBuf>),
+    RemoveStagedFile(usize),
+    ClearStagedFiles,
+    AttachmentCaptionChanged(String),
+    SendAttachments,
+    AttachmentUploaded(String, usize, Attachment), // batch_id, index, attachment
+    AttachmentUploadFailed(String, String),        // batch_id, error
+    AttachmentsSent(String, Vec<ChatMessage>, Option<String>), // batch_id, sent, error that stopped the rest
+
+    // Webcam photos
+    CapturePhoto,
+    PhotoCaptured(Result<CapturedPhoto, String>),
+    PhotoViewOnceToggled(bool),
+    DiscardPhoto,
+    SendPhoto,
+    PhotoSent(Result<ChatMessage, String>),
+
     DownloadFile(String, String), // file_id, file_name
-    FileDownloaded(PathBuf),
+    /// Ask the sender of an expired attachment to upload it again
+    RequestReupload(String), // message_id
+    DownloadTargetChosen(Attachment, PathBuf),
+    DownloadFinished(String, Result<TransferOutcome, String>), // file_id, outcome
+    PauseDownload(String),
+    ResumeDownload(String),
+    CancelDownload(String),
+    DownloadProgressTick,
+    DownloadMyData,
+    DownloadMyDataTo(PathBuf),
+    CancelDataExport,
+    /// `Ok(false)` when cancelled
+    DataExportFinished(Result<bool, String>),
 
     // Calls
     StartCall(String, bool), // peer_id, is_video
@@ -68,8 +199,66 @@ pub enum Message {
     // Settings
     OpenSettings,
     ThemeChanged(String),
+    /// Set a conversation's wallpaper or, without a peer id, the global
+    /// one; `None` resets it to the default
+    SetWallpaper(Option<String>, Option<Wallpaper>),
+    /// Pick an image from disk as the wallpaper, for a conversation or all
+    PickWallpaperImage(Option<String>),
     NotificationsChanged(bool),
     SoundChanged(bool),
+    NotificationPreviewChanged(bool),
+    StartOnLoginChanged(bool),
+
+    // App lock and clipboard
+    OsSession(OsEvent),
+    PowerSourceChanged(PowerSource),
+    LockApp,
+    UnlockInputChanged(String),
+    Unlock,
+    PasscodeInputChanged(String),
+    SetPasscode,
+    RemovePasscode,
+    LockOnSuspendChanged(bool),
+    AnalyticsChanged(bool),
+    ClipboardClearChanged(ClipboardClear),
+    CopyMessage(String),   // message_id
+    CopyFileName(String),  // message_id
+    CopyLink(String),      // message_id
+    CopySelectedMessages,
+
+    // Backups
+    BackupTick,
+    BackupNow,
+    BackupFinished(Result<Vec<BackupEntry>, String>),
+    AutoBackupChanged(bool),
+    BackupKeepChanged(usize),
+    ChooseBackupDir,
+    BackupDirChosen(PathBuf),
+    RestoreBackup(PathBuf),
+    BackupRestored(Result<(), String>),
+
+    // Diagnostics
+    OpenDiagnostics,
+    RunDiagnostics,
+    DiagnosticsFinished(DiagnosticsReport),
+    CopyDiagnostics,
+
+    // Message rules
+    OpenRules,
+    RuleNameChanged(String),
+    RuleSenderChanged(String),
+    RuleKeywordChanged(String),
+    RuleTypeSelected(Option<&'static str>),
+    RuleActionToggled(RuleAction, bool),
+    SaveRule,
+    EditRule(String),
+    NewRule,
+    RuleEnabledToggled(String, bool),
+    DeleteRule(String),
+
+    // Client policy
+    ClientPolicyFetched(Result<Option<SignedPolicy>, String>),
+    CapabilitiesFetched(Result<ServerCapabilities, String>),
 
     // WebSocket
     WebSocketEvent(WsEvent),
@@ -79,4 +268,7 @@ pub enum Message {
     ClearError,
     Tick,
     Noop,
+    /// Tab and Shift+Tab, between inputs and messages
+    FocusNext,
+    FocusPrevious,
 }
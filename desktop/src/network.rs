@@ -1,20 +1,161 @@
 //! Network layer for PrivMsg Desktop
 
 use crate::config::AppConfig;
-use crate::crypto::CryptoEngine;
+use crate::crypto::{encrypted_len, CryptoEngine, FILE_CHUNK_SIZE};
+use crate::database::Database;
 use crate::state::{
-    Attachment, AuthSession, ChatMessage, MessageStatus, MessageType, User,
+    Attachment, AuthSession, ChatMessage, Device, MessageStatus, MessageType, User,
 };
 use anyhow::Result;
+use privmsg_proto::announcement::Announcement;
 use futures_util::{SinkExt, StreamExt};
 use parking_lot::Mutex;
+use privmsg_proto::capabilities::ServerCapabilities;
+use privmsg_proto::maintenance::Maintenance;
+use privmsg_proto::policy::SignedPolicy;
+use privmsg_proto::pow::{self, LoginChallenge};
+use privmsg_proto::{default_ttl_seconds, LastSeenVisibility, MessagePriority, RevocationReason, ServerError};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, Message as WsMessage},
+};
+
+// ============================================================================
+// Attachment Files
+// ============================================================================
+
+/// Scratch file for an encrypted attachment in transit
+fn temp_path() -> PathBuf {
+    std::env::temp_dir().join(format!("privmsg-{}", uuid::Uuid::new_v4()))
+}
+
+/// Encrypted bytes of a download so far; kept while paused so it can resume
+pub fn download_part_path(file_id: &str) -> PathBuf {
+    let name: String = file_id.chars().filter(char::is_ascii_alphanumeric).collect();
+    std::env::temp_dir().join(format!("privmsg-{}.part", name))
+}
+
+const TRANSFER_RUNNING: u8 = 0;
+const TRANSFER_PAUSED: u8 = 1;
+const TRANSFER_CANCELLED: u8 = 2;
+
+/// Progress of a download, and pause/cancel requests for it
+#[derive(Debug, Default)]
+pub struct TransferControl {
+    state: AtomicU8,
+    received: AtomicU64,
+    total: AtomicU64,
+}
+
+impl TransferControl {
+    pub fn pause(&self) {
+        self.state.store(TRANSFER_PAUSED, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.state.store(TRANSFER_RUNNING, Ordering::SeqCst);
+    }
+
+    pub fn cancel(&self) {
+        self.state.store(TRANSFER_CANCELLED, Ordering::SeqCst);
+    }
+
+    pub fn progress(&self) -> f32 {
+        match self.total.load(Ordering::Relaxed) {
+            0 => 0.0,
+            total => self.received.load(Ordering::Relaxed) as f32 / total as f32,
+        }
+    }
+}
+
+/// How a download ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferOutcome {
+    Completed,
+    /// Stopped on request; the partial file is kept for resuming
+    Paused,
+    /// Stopped on request; the partial file is gone
+    Cancelled,
+}
+
+fn encrypt_to_path(crypto: &CryptoEngine, src: &Path, dest: &Path, key: &str) -> Result<()> {
+    let mut input = std::io::BufReader::new(std::fs::File::open(src)?);
+    let output = std::io::BufWriter::new(std::fs::File::create(dest)?);
+
+    let mut writer = crypto.encrypting_writer(output, key)?;
+    std::io::copy(&mut input, &mut writer)?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+/// Upload body that encrypts `data` a chunk at a time as it is read, so no
+/// encrypted copy of the whole file is built in memory
+fn encrypted_body(crypto: &CryptoEngine, data: Vec<u8>, key: &str) -> Result<reqwest::Body> {
+    let writer = crypto.encrypting_writer(Vec::new(), key)?;
+    let chunks = futures::stream::unfold(Some((writer, data, 0)), |state| async move {
+        let (mut writer, data, offset) = state?;
+        if offset == data.len() {
+            return Some((writer.finish(), None));
+        }
+        let end = (offset + FILE_CHUNK_SIZE).min(data.len());
+        match writer.write_all(&data[offset..end]) {
+            Ok(()) => {
+                let sealed = std::mem::take(writer.get_mut());
+                Some((Ok(sealed), Some((writer, data, end))))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok(reqwest::Body::wrap_stream(chunks))
+}
+
+fn decrypt_to_path(crypto: &CryptoEngine, src: &Path, dest: &Path, key: &str) -> Result<()> {
+    let input = std::io::BufReader::new(std::fs::File::open(src)?);
+    let mut output = std::io::BufWriter::new(std::fs::File::create(dest)?);
+
+    if let Ok(mut reader) = crypto.decrypting_reader(input, key) {
+        if std::io::copy(&mut reader, &mut output).is_ok() {
+            output.flush()?;
+            return Ok(());
+        }
+    }
+
+    // Attachments uploaded before chunked encryption are one AES-GCM message
+    let data = crypto.decrypt_file(&std::fs::read(src)?, key)?;
+    std::fs::write(dest, data)?;
+    Ok(())
+}
+
+/// A user profile as the server returns it
+fn user_from_json(data: &serde_json::Value) -> User {
+    let field = |name: &str| data[name].as_str().map(|s| s.to_string());
+    User {
+        user_id: field("user_id").unwrap_or_default(),
+        display_name: field("display_name"),
+        avatar_file_id: field("avatar_file_id"),
+        public_key: field("public_key"),
+        // A UTC "YYYY-MM-DD HH:MM:SS" string, absent if hidden from us
+        last_seen_at: data["last_seen_at"].as_str().and_then(|s| {
+            chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .map(|dt| dt.and_utc().timestamp_millis())
+        }),
+        status_emoji: field("status_emoji"),
+        status_text: field("status_text"),
+        bio: field("bio"),
+        last_seen_visibility: serde_json::from_value(data["last_seen_visibility"].clone()).ok(),
+    }
+}
 
 // ============================================================================
 // WebSocket Event
@@ -25,9 +166,46 @@ pub enum WsEvent {
     Connected,
     Disconnected,
     Message(MessageEnvelope),
+    MessageBatch(Vec<MessageEnvelope>),
     CallSignal(CallSignal),
     Typing { user_id: String, is_typing: bool },
     Presence { user_id: String, status: String },
+    /// Another of the user's devices got a new name
+    DeviceRenamed { device_id: String, device_name: String },
+    /// The server ended this session and is closing the socket
+    SessionRevoked(RevocationReason),
+    /// The server took these messages
+    Acknowledged(Vec<String>),
+    /// The recipient's device received these messages of ours
+    Delivered(Vec<String>),
+    /// The server set or, with `None`, cleared its service notice
+    Announcement(Option<Announcement>),
+    /// The server turned maintenance mode on or, with `None`, off
+    Maintenance(Option<Maintenance>),
+    /// The server refused a frame, e.g. a message to a deactivated account
+    Error(ServerError),
+}
+
+/// The server said the account was deactivated or deleted
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is no longer available")]
+pub struct PeerUnavailable(pub String);
+
+/// The server gave the peer a key other than the trusted one; nothing is
+/// sent to them until the user accepts it
+#[derive(Debug, thiserror::Error)]
+#[error("The security key of {0} changed")]
+pub struct KeyConflict(pub String);
+
+/// `message_type` of envelopes asking the sender of an attachment to upload
+/// it again, as core names it
+const REUPLOAD_REQUEST_MESSAGE_TYPE: &str = "reupload_request";
+
+/// A file stored by the server, see `send_upload`
+struct UploadedFile {
+    file_id: String,
+    /// When the server drops it (Unix seconds), if it said
+    expires_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +217,10 @@ pub struct MessageEnvelope {
     pub encrypted_content: String,
     pub message_type: String,
     pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u32>,
+    #[serde(default)]
+    pub priority: MessagePriority,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,14 +243,16 @@ pub struct NetworkClient {
     token: Mutex<Option<String>>,
     user_id: Mutex<Option<String>>,
     crypto: Arc<CryptoEngine>,
+    /// Trusted peer keys live here
+    db: Arc<Database>,
     ws_sender: Mutex<Option<mpsc::UnboundedSender<String>>>,
     incoming_events: Arc<Mutex<VecDeque<WsEvent>>>,
 }
 
 impl NetworkClient {
-    pub async fn new(config: &AppConfig) -> Result<Self> {
+    pub async fn new(config: &AppConfig, db: Arc<Database>) -> Result<Self> {
+        config.check_transport_security()?;
         let http = Client::builder()
-            .danger_accept_invalid_certs(!config.server.use_tls)
             .timeout(std::time::Duration::from_secs(30))
             .build()?;
 
@@ -81,6 +265,7 @@ impl NetworkClient {
             token: Mutex::new(None),
             user_id: Mutex::new(None),
             crypto,
+            db,
             ws_sender: Mutex::new(None),
             incoming_events: Arc::new(Mutex::new(VecDeque::new())),
         })
@@ -90,8 +275,52 @@ impl NetworkClient {
         self.token.lock().as_ref().map(|t| format!("Bearer {}", t))
     }
 
+    pub fn is_authenticated(&self) -> bool {
+        self.token.lock().is_some()
+    }
+
+    /// Whether the WebSocket send task is still running
+    pub fn is_connected(&self) -> bool {
+        self.ws_sender.lock().as_ref().is_some_and(|sender| !sender.is_closed())
+    }
+
+    pub fn has_identity(&self) -> bool {
+        self.crypto.get_public_key().is_ok()
+    }
+
+    /// The server's clock in Unix seconds, from the health endpoint
+    pub async fn server_time(&self) -> Result<i64> {
+        let resp = self.http.get(format!("{}/health", self.base_url)).send().await?;
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Health check failed: {}", resp.status()));
+        }
+
+        let data: serde_json::Value = resp.json().await?;
+        data["timestamp"]
+            .as_i64()
+            .ok_or_else(|| anyhow::anyhow!("Health check response has no timestamp"))
+    }
+
     // ============= Authentication =============
 
+    /// None when the server asks no proof of work, or predates them
+    async fn login_challenge(&self) -> Result<Option<LoginChallenge>> {
+        let resp = self
+            .http
+            .get(format!("{}/api/v1/auth/challenge", self.base_url))
+            .send()
+            .await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("Login challenge failed: {} - {}", status, text));
+        }
+        Ok(resp.json().await?)
+    }
+
     pub async fn login(
         &self,
         user_id: &str,
@@ -102,6 +331,12 @@ impl NetworkClient {
         self.crypto.generate_identity()?;
         let public_key = self.crypto.get_public_key()?;
 
+        // Servers fighting credential stuffing want a proof of work first
+        let proof = match self.login_challenge().await? {
+            Some(challenge) => Some(tokio::task::spawn_blocking(move || pow::solve(&challenge)).await?),
+            None => None,
+        };
+
         let resp = self
             .http
             .post(format!("{}/api/v1/auth/login", self.base_url))
@@ -110,7 +345,8 @@ impl NetworkClient {
                 "access_key": access_key,
                 "device_name": device_name,
                 "device_type": std::env::consts::OS,
-                "device_public_key": public_key
+                "device_public_key": public_key,
+                "proof": proof,
             }))
             .send()
             .await?;
@@ -128,6 +364,7 @@ impl NetworkClient {
             device_id: data["device_id"].as_str().unwrap_or_default().to_string(),
             user_id: user_id.to_string(),
             expires_at: data["expires_at"].as_i64().unwrap_or(0),
+            announcement: serde_json::from_value(data["announcement"].clone()).unwrap_or(None),
         };
 
         *self.token.lock() = Some(session.token.clone());
@@ -171,10 +408,36 @@ impl NetworkClient {
         Ok(())
     }
 
+    /// End every session of this account, on all devices
+    pub async fn logout_all(&self) -> Result<()> {
+        let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+
+        let resp = self
+            .http
+            .post(format!("{}/api/v1/auth/logout-all", self.base_url))
+            .header("Authorization", auth)
+            .send()
+            .await?;
+
+        *self.token.lock() = None;
+        *self.user_id.lock() = None;
+        *self.ws_sender.lock() = None;
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Logout request failed: {}", resp.status()));
+        }
+        Ok(())
+    }
+
     // ============= WebSocket =============
 
     async fn connect_websocket(&self, token: &str) -> Result<()> {
-        let (ws_stream, _) = connect_async(&self.ws_url).await?;
+        // Authenticate during the HTTP upgrade
+        let mut request = self.ws_url.as_str().into_client_request()?;
+        request
+            .headers_mut()
+            .insert("Authorization", format!("Bearer {}", token).parse()?);
+
+        let (ws_stream, _) = connect_async(request).await?;
         let (mut write, mut read) = ws_stream.split();
 
         let (tx, mut rx) = mpsc::unbounded_channel::<String>();
@@ -182,7 +445,7 @@ impl NetworkClient {
 
         let incoming = self.incoming_events.clone();
 
-        // Authenticate
+        // Also authenticate with a frame for servers that predate upgrade auth
         let auth_msg = json!({
             "type": "authenticate",
             "payload": { "token": token }
@@ -205,6 +468,11 @@ impl NetworkClient {
                                         None
                                     }
                                 }
+                                Some("message_batch") => serde_json::from_value::<Vec<MessageEnvelope>>(
+                                    data["payload"]["messages"].clone(),
+                                )
+                                .ok()
+                                .map(WsEvent::MessageBatch),
                                 Some("call_signal") => {
                                     if let Some(payload) = data.get("payload") {
                                         serde_json::from_value::<CallSignal>(payload.clone())
@@ -244,6 +512,43 @@ impl NetworkClient {
                                     }
                                 }
                                 Some("authenticated") => Some(WsEvent::Connected),
+                                Some("device_renamed") => {
+                                    let payload = &data["payload"];
+                                    match (payload["device_id"].as_str(), payload["device_name"].as_str()) {
+                                        (Some(device_id), Some(device_name)) => Some(WsEvent::DeviceRenamed {
+                                            device_id: device_id.to_string(),
+                                            device_name: device_name.to_string(),
+                                        }),
+                                        _ => None,
+                                    }
+                                }
+                                Some("ack") => serde_json::from_value::<Vec<String>>(
+                                    data["payload"]["message_ids"].clone(),
+                                )
+                                .ok()
+                                .map(WsEvent::Acknowledged),
+                                Some("delivered") => serde_json::from_value::<Vec<String>>(
+                                    data["payload"]["message_ids"].clone(),
+                                )
+                                .ok()
+                                .map(WsEvent::Delivered),
+                                Some("announcement") => serde_json::from_value(
+                                    data["payload"]["announcement"].clone(),
+                                )
+                                .ok()
+                                .map(WsEvent::Announcement),
+                                Some("maintenance") => serde_json::from_value(
+                                    data["payload"]["maintenance"].clone(),
+                                )
+                                .ok()
+                                .map(WsEvent::Maintenance),
+                                Some("error") => serde_json::from_value(data["payload"].clone())
+                                    .ok()
+                                    .map(WsEvent::Error),
+                                Some("session_revoked") => Some(WsEvent::SessionRevoked(
+                                    serde_json::from_value(data["payload"]["reason"].clone())
+                                        .unwrap_or(RevocationReason::Unknown),
+                                )),
                                 _ => None,
                             };
 
@@ -280,6 +585,14 @@ impl NetworkClient {
         Ok(())
     }
 
+    /// Acknowledge stored messages so the server stops replaying them
+    pub fn send_ack(&self, message_ids: &[String]) -> Result<()> {
+        self.send_ws(json!({
+            "type": "ack",
+            "payload": { "message_ids": message_ids }
+        }))
+    }
+
     pub fn poll_events(&self) -> Vec<WsEvent> {
         let mut events = Vec::new();
         let mut queue = self.incoming_events.lock();
@@ -301,70 +614,170 @@ impl NetworkClient {
             .send()
             .await?;
 
-        if resp.status().as_u16() == 404 {
-            return Err(anyhow::anyhow!("User not found"));
+        match resp.status().as_u16() {
+            404 => return Err(anyhow::anyhow!("User not found")),
+            410 => return Err(PeerUnavailable(user_id.to_string()).into()),
+            _ => {}
         }
 
         let data: serde_json::Value = resp.json().await?;
+        Ok(user_from_json(&data))
+    }
 
-        Ok(User {
-            user_id: data["user_id"].as_str().unwrap_or_default().to_string(),
-            display_name: data["display_name"].as_str().map(|s| s.to_string()),
-            avatar_file_id: data["avatar_file_id"].as_str().map(|s| s.to_string()),
-            public_key: data["public_key"].as_str().map(|s| s.to_string()),
-            last_seen_at: data["last_seen_at"].as_i64(),
-        })
+    /// The current user's signed-in devices
+    pub async fn list_devices(&self) -> Result<Vec<Device>> {
+        let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+
+        let resp = self
+            .http
+            .get(format!("{}/api/v1/users/me/devices", self.base_url))
+            .header("Authorization", auth)
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Device list request failed: {}", resp.status()));
+        }
+        Ok(resp.json().await?)
     }
 
-    // ============= Messaging =============
+    pub async fn rename_device(&self, device_id: &str, device_name: &str) -> Result<Device> {
+        let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
 
-    pub async fn send_text_message(&self, recipient_id: &str, text: &str) -> Result<ChatMessage> {
-        let sender_id = self.user_id.lock().clone().ok_or_else(|| anyhow::anyhow!("Not logged in"))?;
+        let resp = self
+            .http
+            .put(format!("{}/api/v1/users/me/devices/{}", self.base_url, device_id))
+            .header("Authorization", auth)
+            .json(&serde_json::json!({ "device_name": device_name }))
+            .send()
+            .await?;
 
-        // Get recipient's public key if we don't have a session
-        if !self.crypto.has_session(recipient_id) {
-            let user = self.find_user(recipient_id).await?;
-            if let Some(pub_key) = user.public_key {
-                self.crypto.establish_session(recipient_id, &pub_key)?;
-            } else {
-                return Err(anyhow::anyhow!("Recipient has no public key"));
-            }
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Renaming device failed: {}", resp.status()));
         }
+        Ok(resp.json().await?)
+    }
 
-        // Encrypt message
-        let content = json!({ "text": text });
-        let encrypted = self.crypto.encrypt_for(recipient_id, &content.to_string())?;
+    /// Set the current user's status and bio; empty strings clear them
+    pub async fn update_profile(
+        &self,
+        status_emoji: &str,
+        status_text: &str,
+        bio: &str,
+    ) -> Result<User> {
+        self.post_profile(json!({
+            "status_emoji": status_emoji,
+            "status_text": status_text,
+            "bio": bio,
+        }))
+        .await
+    }
 
-        let message_id = uuid::Uuid::new_v4().to_string();
-        let timestamp = chrono::Utc::now().timestamp_millis();
+    /// Choose who may see when the current user was last online
+    pub async fn set_last_seen_visibility(&self, visibility: LastSeenVisibility) -> Result<User> {
+        self.post_profile(json!({ "last_seen_visibility": visibility })).await
+    }
+
+    async fn post_profile(&self, update: serde_json::Value) -> Result<User> {
+        let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+
+        let resp = self
+            .http
+            .post(format!("{}/api/v1/users/me/profile", self.base_url))
+            .header("Authorization", auth)
+            .json(&update)
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("Profile update failed: {} - {}", status, text));
+        }
+
+        let data: serde_json::Value = resp.json().await?;
+        Ok(user_from_json(&data))
+    }
+
+    // ============= Messaging =============
+
+    /// Encrypt and send an outgoing text message as it is, keeping its
+    /// message_id so the server drops copies of one sent before
+    pub async fn deliver_message(&self, msg: &ChatMessage) -> Result<()> {
+        anyhow::ensure!(
+            msg.message_type == MessageType::Text,
+            "Only text messages can be sent again"
+        );
+        self.ensure_session(&msg.conversation_id).await?;
+
+        let mut content = json!({ "text": msg.content });
+        if let Some(ref device_id) = msg.sender_device_id {
+            content["device_id"] = json!(device_id);
+        }
+        if let Some(ref quote) = msg.quote {
+            content["quote"] = json!(quote);
+        }
+        let encrypted = self.crypto.encrypt_for(&msg.conversation_id, &content.to_string())?;
 
         let envelope = MessageEnvelope {
-            message_id: message_id.clone(),
-            sender_id: sender_id.clone(),
-            recipient_id: recipient_id.to_string(),
+            message_id: msg.message_id.clone(),
+            sender_id: msg.sender_id.clone(),
+            recipient_id: msg.conversation_id.clone(),
             recipient_device_id: None,
             encrypted_content: encrypted,
             message_type: "text".to_string(),
-            timestamp,
+            timestamp: msg.timestamp,
+            ttl_seconds: default_ttl_seconds("text"),
+            priority: MessagePriority::for_message_type("text"),
         };
 
         // Send via WebSocket
         self.send_ws(json!({
             "type": "message",
             "payload": envelope
-        }))?;
+        }))
+    }
 
-        Ok(ChatMessage {
-            message_id,
-            conversation_id: recipient_id.to_string(),
-            sender_id,
-            message_type: MessageType::Text,
-            content: text.to_string(),
-            timestamp,
-            status: MessageStatus::Sent,
-            attachment: None,
-            is_outgoing: true,
-        })
+    /// Get recipient's public key if we don't have a session. Fails with
+    /// `KeyConflict` while a changed key waits for the user.
+    async fn ensure_session(&self, recipient_id: &str) -> Result<()> {
+        if self.db.get_key_conflicts()?.contains(recipient_id) {
+            return Err(KeyConflict(recipient_id.to_string()).into());
+        }
+        if !self.crypto.has_session(recipient_id) {
+            let user = self.find_user(recipient_id).await?;
+            if let Some(pub_key) = user.public_key {
+                self.check_peer_key(recipient_id, &pub_key)?;
+                self.crypto.establish_session(recipient_id, &pub_key)?;
+            } else {
+                return Err(anyhow::anyhow!("Recipient has no public key"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Trust a peer's first key; a different one later is held back
+    fn check_peer_key(&self, peer_id: &str, public_key: &str) -> Result<()> {
+        match self.db.get_peer_public_key(peer_id) {
+            None => self.db.save_peer_public_key(peer_id, public_key),
+            Some(trusted) if trusted == public_key => Ok(()),
+            Some(_) => {
+                if self.db.record_key_conflict(peer_id, public_key)? {
+                    tracing::warn!("Security key of {} changed; waiting for the user to accept it", peer_id);
+                }
+                Err(KeyConflict(peer_id.to_string()).into())
+            }
+        }
+    }
+
+    /// Trust the changed key of a peer once the user has checked it;
+    /// returns whether there was one
+    pub fn accept_peer_key(&self, peer_id: &str) -> Result<bool> {
+        let accepted = self.db.accept_pending_key(peer_id)?.is_some();
+        if accepted {
+            self.crypto.remove_session(peer_id);
+        }
+        Ok(accepted)
     }
 
     pub async fn send_file_message(
@@ -373,41 +786,117 @@ impl NetworkClient {
         data: Vec<u8>,
         file_name: &str,
         mime_type: &str,
+        caption: Option<&str>,
     ) -> Result<ChatMessage> {
-        let sender_id = self.user_id.lock().clone().ok_or_else(|| anyhow::anyhow!("Not logged in"))?;
+        let attachment = self.upload_attachment(data, file_name, mime_type).await?;
+        self.send_attachment_message(recipient_id, &attachment, caption).await
+    }
 
-        // Generate file encryption key
+    /// Encrypt and upload a file without sending a message for it yet.
+    ///
+    /// Used by the composer to upload a whole batch before any message goes
+    /// out, so the recipient never sees half of it.
+    pub async fn upload_attachment(
+        &self,
+        data: Vec<u8>,
+        file_name: &str,
+        mime_type: &str,
+    ) -> Result<Attachment> {
         let file_key = self.crypto.generate_file_key()?;
+        let file_size = data.len();
+        let uploaded = self.upload_file(data, file_name, mime_type, &file_key).await?;
+
+        Ok(Attachment {
+            file_id: uploaded.file_id,
+            file_name: file_name.to_string(),
+            file_size: file_size as i64,
+            mime_type: mime_type.to_string(),
+            duration_ms: None,
+            width: None,
+            height: None,
+            encryption_key: Some(file_key),
+            local_path: None,
+            waveform: None,
+            view_once: false,
+            expires_at: uploaded.expires_at,
+        })
+    }
 
-        // Encrypt file
-        let encrypted_data = self.crypto.encrypt_file(&data, &file_key)?;
-
-        // Upload encrypted file
-        let file_id = self.upload_file(encrypted_data, file_name, mime_type, &file_key).await?;
-
-        // Ensure we have session with recipient
-        if !self.crypto.has_session(recipient_id) {
-            let user = self.find_user(recipient_id).await?;
-            if let Some(pub_key) = user.public_key {
-                self.crypto.establish_session(recipient_id, &pub_key)?;
-            } else {
-                return Err(anyhow::anyhow!("Recipient has no public key"));
+    /// Like `upload_attachment`, but encrypts and uploads the file from disk
+    /// in chunks so large files are never held in memory
+    pub async fn upload_attachment_from_path(
+        &self,
+        path: &Path,
+        file_name: &str,
+        mime_type: &str,
+    ) -> Result<Attachment> {
+        let file_key = self.crypto.generate_file_key()?;
+        let file_size = tokio::fs::metadata(path).await?.len() as i64;
+
+        let encrypted_path = temp_path();
+        let crypto = self.crypto.clone();
+        let (src, dest, key) = (path.to_path_buf(), encrypted_path.clone(), file_key.clone());
+        let uploaded = match tokio::task::spawn_blocking(move || encrypt_to_path(&crypto, &src, &dest, &key)).await? {
+            Ok(()) => {
+                self.upload_file_from_path(&encrypted_path, file_name, mime_type, &file_key)
+                    .await
             }
-        }
+            Err(e) => Err(e),
+        };
+        let _ = tokio::fs::remove_file(&encrypted_path).await;
+        let uploaded = uploaded?;
+
+        Ok(Attachment {
+            file_id: uploaded.file_id,
+            file_name: file_name.to_string(),
+            file_size,
+            mime_type: mime_type.to_string(),
+            duration_ms: None,
+            width: None,
+            height: None,
+            encryption_key: Some(file_key),
+            local_path: None,
+            waveform: None,
+            view_once: false,
+            expires_at: uploaded.expires_at,
+        })
+    }
+
+    /// Send a message referencing an already uploaded attachment
+    pub async fn send_attachment_message(
+        &self,
+        recipient_id: &str,
+        attachment: &Attachment,
+        caption: Option<&str>,
+    ) -> Result<ChatMessage> {
+        let sender_id = self.user_id.lock().clone().ok_or_else(|| anyhow::anyhow!("Not logged in"))?;
+        self.ensure_session(recipient_id).await?;
 
         // Create message content with file info
-        let content = json!({
-            "file_id": file_id,
-            "file_name": file_name,
-            "file_size": data.len(),
-            "mime_type": mime_type,
-            "encryption_key": file_key
+        let mut content = json!({
+            "file_id": attachment.file_id,
+            "file_name": attachment.file_name,
+            "file_size": attachment.file_size,
+            "mime_type": attachment.mime_type,
+            "encryption_key": attachment.encryption_key,
+            "expires_at": attachment.expires_at
         });
+        if let Some(caption) = caption {
+            content["caption"] = json!(caption);
+        }
+        if let (Some(width), Some(height)) = (attachment.width, attachment.height) {
+            content["width"] = json!(width);
+            content["height"] = json!(height);
+        }
+        if attachment.view_once {
+            content["view_once"] = json!(true);
+        }
         let encrypted = self.crypto.encrypt_for(recipient_id, &content.to_string())?;
 
         let message_id = uuid::Uuid::new_v4().to_string();
         let timestamp = chrono::Utc::now().timestamp_millis();
 
+        let mime_type = attachment.mime_type.as_str();
         let msg_type = if mime_type.starts_with("image/") {
             "image"
         } else if mime_type.starts_with("audio/") {
@@ -426,6 +915,8 @@ impl NetworkClient {
             encrypted_content: encrypted,
             message_type: msg_type.to_string(),
             timestamp,
+            ttl_seconds: default_ttl_seconds(msg_type),
+            priority: MessagePriority::for_message_type(msg_type),
         };
 
         self.send_ws(json!({
@@ -445,21 +936,16 @@ impl NetworkClient {
             conversation_id: recipient_id.to_string(),
             sender_id,
             message_type,
-            content: file_name.to_string(),
+            content: attachment.file_name.clone(),
             timestamp,
             status: MessageStatus::Sent,
-            attachment: Some(Attachment {
-                file_id,
-                file_name: file_name.to_string(),
-                file_size: data.len() as i64,
-                mime_type: mime_type.to_string(),
-                duration_ms: None,
-                width: None,
-                height: None,
-                encryption_key: Some(file_key),
-                local_path: None,
-            }),
+            attachment: Some(attachment.clone()),
+            caption: caption.map(|c| c.to_string()),
             is_outgoing: true,
+            system_event: None,
+            sender_device_id: None,
+            quote: None,
+            is_starred: false,
         })
     }
 
@@ -468,33 +954,28 @@ impl NetworkClient {
         recipient_id: &str,
         audio_data: Vec<u8>,
         duration_ms: i64,
+        waveform: Vec<u8>,
     ) -> Result<ChatMessage> {
         let sender_id = self.user_id.lock().clone().ok_or_else(|| anyhow::anyhow!("Not logged in"))?;
 
         // Generate file encryption key
         let file_key = self.crypto.generate_file_key()?;
 
-        // Encrypt audio
-        let encrypted_data = self.crypto.encrypt_file(&audio_data, &file_key)?;
-
-        // Upload
-        let file_id = self.upload_file(encrypted_data, "voice.ogg", "audio/ogg", &file_key).await?;
+        // Encrypt and upload audio
+        let file_size = audio_data.len();
+        let uploaded = self.upload_file(audio_data, "voice.wav", "audio/wav", &file_key).await?;
 
-        // Ensure session
-        if !self.crypto.has_session(recipient_id) {
-            let user = self.find_user(recipient_id).await?;
-            if let Some(pub_key) = user.public_key {
-                self.crypto.establish_session(recipient_id, &pub_key)?;
-            }
-        }
+        self.ensure_session(recipient_id).await?;
 
         let content = json!({
-            "file_id": file_id,
-            "file_name": "voice.ogg",
-            "file_size": audio_data.len(),
-            "mime_type": "audio/ogg",
+            "file_id": uploaded.file_id,
+            "file_name": "voice.wav",
+            "file_size": file_size,
+            "mime_type": "audio/wav",
             "duration_ms": duration_ms,
-            "encryption_key": file_key
+            "waveform": waveform,
+            "encryption_key": file_key,
+            "expires_at": uploaded.expires_at
         });
         let encrypted = self.crypto.encrypt_for(recipient_id, &content.to_string())?;
 
@@ -509,6 +990,8 @@ impl NetworkClient {
             encrypted_content: encrypted,
             message_type: "voice".to_string(),
             timestamp,
+            ttl_seconds: default_ttl_seconds("voice"),
+            priority: MessagePriority::for_message_type("voice"),
         };
 
         self.send_ws(json!({
@@ -525,36 +1008,104 @@ impl NetworkClient {
             timestamp,
             status: MessageStatus::Sent,
             attachment: Some(Attachment {
-                file_id,
-                file_name: "voice.ogg".to_string(),
-                file_size: audio_data.len() as i64,
-                mime_type: "audio/ogg".to_string(),
+                file_id: uploaded.file_id,
+                file_name: "voice.wav".to_string(),
+                file_size: file_size as i64,
+                mime_type: "audio/wav".to_string(),
                 duration_ms: Some(duration_ms),
                 width: None,
                 height: None,
                 encryption_key: Some(file_key),
                 local_path: None,
+                waveform: Some(waveform),
+                view_once: false,
+                expires_at: uploaded.expires_at,
             }),
+            caption: None,
             is_outgoing: true,
+            system_event: None,
+            sender_device_id: None,
+            quote: None,
+            is_starred: false,
         })
     }
 
+    /// Ask the sender of a received attachment to upload it again after
+    /// the server dropped it
+    pub async fn request_reupload(&self, msg: &ChatMessage) -> Result<()> {
+        let file_id = match msg.attachment {
+            Some(ref attachment) if !msg.is_outgoing => attachment.file_id.clone(),
+            _ => return Err(anyhow::anyhow!("Only received attachments can be asked for again")),
+        };
+        let sender_id = self.user_id.lock().clone().ok_or_else(|| anyhow::anyhow!("Not logged in"))?;
+        self.ensure_session(&msg.conversation_id).await?;
+
+        let content = json!({
+            "message_id": msg.message_id,
+            "file_id": file_id
+        });
+        let envelope = MessageEnvelope {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            sender_id,
+            recipient_id: msg.conversation_id.clone(),
+            recipient_device_id: None,
+            encrypted_content: self.crypto.encrypt_for(&msg.conversation_id, &content.to_string())?,
+            message_type: REUPLOAD_REQUEST_MESSAGE_TYPE.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            ttl_seconds: default_ttl_seconds(REUPLOAD_REQUEST_MESSAGE_TYPE),
+            priority: MessagePriority::for_message_type(REUPLOAD_REQUEST_MESSAGE_TYPE),
+        };
+
+        self.send_ws(json!({
+            "type": "message",
+            "payload": envelope
+        }))
+    }
+
     // ============= Files =============
 
+    /// Encrypt `data` under `encryption_key` while uploading it, like
+    /// `upload_file_from_path` does for a file on disk
     async fn upload_file(
         &self,
         data: Vec<u8>,
         file_name: &str,
         mime_type: &str,
         encryption_key: &str,
-    ) -> Result<String> {
+    ) -> Result<UploadedFile> {
+        let len = encrypted_len(data.len()) as u64;
+        let body = encrypted_body(&self.crypto, data, encryption_key)?;
+        let part = reqwest::multipart::Part::stream_with_length(body, len);
+        self.send_upload(part, file_name, mime_type, encryption_key).await
+    }
+
+    async fn upload_file_from_path(
+        &self,
+        path: &Path,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key: &str,
+    ) -> Result<UploadedFile> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file));
+
+        let part = reqwest::multipart::Part::stream_with_length(body, len);
+        self.send_upload(part, file_name, mime_type, encryption_key).await
+    }
+
+    async fn send_upload(
+        &self,
+        part: reqwest::multipart::Part,
+        file_name: &str,
+        mime_type: &str,
+        encryption_key: &str,
+    ) -> Result<UploadedFile> {
         let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
 
         let key_hash = self.crypto.hash(encryption_key.as_bytes());
 
-        let part = reqwest::multipart::Part::bytes(data)
-            .file_name(file_name.to_string())
-            .mime_str(mime_type)?;
+        let part = part.file_name(file_name.to_string()).mime_str(mime_type)?;
 
         let form = reqwest::multipart::Form::new()
             .part("file", part)
@@ -573,7 +1124,10 @@ impl NetworkClient {
         }
 
         let data: serde_json::Value = resp.json().await?;
-        Ok(data["file_id"].as_str().unwrap_or_default().to_string())
+        Ok(UploadedFile {
+            file_id: data["file_id"].as_str().unwrap_or_default().to_string(),
+            expires_at: data["expires_at"].as_i64(),
+        })
     }
 
     pub async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
@@ -594,6 +1148,124 @@ impl NetworkClient {
         Ok(bytes.to_vec())
     }
 
+    /// Remove an uploaded file from the server
+    pub async fn delete_file(&self, file_id: &str) -> Result<()> {
+        let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+
+        let resp = self
+            .http
+            .delete(format!("{}/api/v1/files/{}", self.base_url, file_id))
+            .header("Authorization", auth)
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Deleting file failed: {}", resp.status()));
+        }
+        Ok(())
+    }
+
+    /// Download an attachment and decrypt it with its file key
+    pub async fn download_attachment(&self, attachment: &Attachment) -> Result<Vec<u8>> {
+        let data = self.download_file(&attachment.file_id).await?;
+
+        match attachment.encryption_key {
+            Some(ref key) => self.crypto.decrypt_file(&data, key),
+            None => Ok(data),
+        }
+    }
+
+    /// Download an attachment and decrypt it to `dest` in chunks
+    ///
+    /// The download stops when `control` is paused or cancelled. A paused
+    /// download continues from where it stopped when called again.
+    pub async fn download_attachment_to(
+        &self,
+        attachment: &Attachment,
+        dest: &Path,
+        control: &TransferControl,
+    ) -> Result<TransferOutcome> {
+        let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+        let part_path = download_part_path(&attachment.file_id);
+
+        let mut offset = match tokio::fs::metadata(&part_path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        };
+
+        let mut req = self
+            .http
+            .get(format!("{}/api/v1/files/{}", self.base_url, attachment.file_id))
+            .header("Authorization", auth);
+        if offset > 0 {
+            req = req.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+        }
+        let mut resp = req.send().await?;
+
+        // Nothing left to fetch if the part file is already complete
+        let complete = resp.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE;
+        if !complete && !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Download failed: {}", resp.status()));
+        }
+
+        if !complete {
+            // A full response means the server ignored the range
+            if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                offset = 0;
+            }
+            control.received.store(offset, Ordering::Relaxed);
+            control
+                .total
+                .store(offset + resp.content_length().unwrap_or(0), Ordering::Relaxed);
+
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(offset > 0)
+                .truncate(offset == 0)
+                .open(&part_path)
+                .await?;
+
+            while let Some(chunk) = resp.chunk().await? {
+                match control.state.load(Ordering::SeqCst) {
+                    TRANSFER_PAUSED => {
+                        file.flush().await?;
+                        return Ok(TransferOutcome::Paused);
+                    }
+                    TRANSFER_CANCELLED => {
+                        drop(file);
+                        let _ = tokio::fs::remove_file(&part_path).await;
+                        return Ok(TransferOutcome::Cancelled);
+                    }
+                    _ => {}
+                }
+
+                file.write_all(&chunk).await?;
+                control.received.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+            file.flush().await?;
+        }
+
+        let result = match attachment.encryption_key.clone() {
+            Some(key) => {
+                let crypto = self.crypto.clone();
+                let (src, dest) = (part_path.clone(), dest.to_path_buf());
+                tokio::task::spawn_blocking(move || decrypt_to_path(&crypto, &src, &dest, &key))
+                    .await?
+            }
+            None => tokio::fs::copy(&part_path, dest)
+                .await
+                .map(|_| ())
+                .map_err(Into::into),
+        };
+        let _ = tokio::fs::remove_file(&part_path).await;
+
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(dest).await;
+        }
+        result.map(|_| TransferOutcome::Completed)
+    }
+
     // ============= Calls =============
 
     pub async fn initiate_call(&self, peer_id: &str, is_video: bool) -> Result<String> {
@@ -682,6 +1354,44 @@ impl NetworkClient {
         Ok(creds)
     }
 
+    /// The operator's signed client policy, or `None` if the server has none
+    /// What the server has turned on; servers without the endpoint have
+    /// everything
+    pub async fn get_capabilities(&self) -> Result<ServerCapabilities> {
+        let resp = self
+            .http
+            .get(format!("{}/api/v1/capabilities", self.base_url))
+            .send()
+            .await?;
+
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(ServerCapabilities::default());
+        }
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Capabilities request failed: {}", resp.status()));
+        }
+        Ok(resp.json().await?)
+    }
+
+    pub async fn get_client_policy(&self) -> Result<Option<SignedPolicy>> {
+        let auth = self.auth_header().ok_or_else(|| anyhow::anyhow!("Not authenticated"))?;
+
+        let resp = self
+            .http
+            .get(format!("{}/api/v1/client-policy", self.base_url))
+            .header("Authorization", auth)
+            .send()
+            .await?;
+
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Client policy request failed: {}", resp.status()));
+        }
+        Ok(Some(resp.json().await?))
+    }
+
     // ============= Typing indicator =============
 
     pub fn send_typing(&self, recipient_id: &str, is_typing: bool) -> Result<()> {
@@ -0,0 +1,41 @@
+//! System notifications through notify-rust
+
+use crate::state::{ChatMessage, MessageType};
+use privmsg_proto::notification::{Notification, NotificationSink};
+
+/// Shows notifications through the desktop's notification service
+pub struct DesktopSink;
+
+impl NotificationSink for DesktopSink {
+    fn notify(&self, notification: &Notification) {
+        // Highlighted by a message rule
+        let summary = if notification.highlighted {
+            format!("Important: {}", notification.title())
+        } else {
+            notification.title()
+        };
+        notify_rust::Notification::new()
+            .summary(&summary)
+            .body(&notification.body())
+            .show()
+            .ok();
+    }
+}
+
+/// What a message says, for its preview; attachments without a caption
+/// are named by kind
+pub fn readable_text(msg: &ChatMessage) -> String {
+    if msg.message_type == MessageType::Text {
+        return msg.content.clone();
+    }
+    if let Some(caption) = msg.caption.as_ref().filter(|c| !c.is_empty()) {
+        return caption.clone();
+    }
+    match msg.message_type {
+        MessageType::Voice => "Voice message",
+        MessageType::Video => "Video",
+        MessageType::Image => "Photo",
+        _ => "File",
+    }
+    .to_string()
+}
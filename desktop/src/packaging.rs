@@ -0,0 +1,269 @@
+//! Desktop integration: privmsg:// link handler, menu entries and autostart
+//!
+//! Registered for the running executable on first run, so portable builds
+//! and package installs behave the same. `privmsg-desktop --uninstall`
+//! removes everything again; installers run it before deleting files.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// URL scheme used for invite links
+pub const URL_SCHEME: &str = "privmsg";
+
+const APP_NAME: &str = "PrivMsg";
+
+/// Register the link handler and menu entries, and autostart if requested
+pub fn install(start_on_login: bool) -> Result<()> {
+    let exe = std::env::current_exe().context("Cannot locate executable")?;
+    platform::install(&exe)?;
+    platform::set_autostart(&exe, start_on_login)?;
+    tracing::info!("Installed desktop integration for {:?}", exe);
+    Ok(())
+}
+
+/// Start (or stop starting) the app when the user logs in
+pub fn set_autostart(enabled: bool) -> Result<()> {
+    let exe = std::env::current_exe().context("Cannot locate executable")?;
+    platform::set_autostart(&exe, enabled)
+}
+
+/// Remove everything `install` created. Missing entries are not an error.
+pub fn uninstall() -> Result<()> {
+    let exe = std::env::current_exe().context("Cannot locate executable")?;
+    platform::set_autostart(&exe, false)?;
+    platform::uninstall()?;
+    tracing::info!("Removed desktop integration");
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Cannot remove {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+// ============= Linux =============
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+    use std::process::Command;
+
+    const DESKTOP_FILE: &str = "privmsg.desktop";
+
+    fn applications_dir() -> Result<PathBuf> {
+        Ok(dirs::data_dir()
+            .context("No data directory")?
+            .join("applications"))
+    }
+
+    fn autostart_dir() -> Result<PathBuf> {
+        Ok(dirs::config_dir()
+            .context("No config directory")?
+            .join("autostart"))
+    }
+
+    /// Quote an argument for a desktop entry's Exec key
+    fn exec_quote(arg: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in arg.chars() {
+            if matches!(c, '"' | '`' | '$' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    fn desktop_entry(exe: &Path, autostart: bool) -> String {
+        let exec = exec_quote(&exe.to_string_lossy());
+        let mut entry = format!(
+            "[Desktop Entry]\n\
+             Type=Application\n\
+             Name={APP_NAME}\n\
+             Comment=Private E2EE Messenger\n\
+             Icon=privmsg\n\
+             Terminal=false\n\
+             Categories=Network;InstantMessaging;\n\
+             StartupWMClass=privmsg-desktop\n"
+        );
+        if autostart {
+            entry.push_str(&format!("Exec={exec}\nX-GNOME-Autostart-enabled=true\n"));
+        } else {
+            entry.push_str(&format!(
+                "Exec={exec} %u\nMimeType=x-scheme-handler/{URL_SCHEME};\n"
+            ));
+        }
+        entry
+    }
+
+    /// Helpers from desktop-file-utils and xdg-utils aren't always installed;
+    /// the entries still work without them after the next desktop login
+    fn run_optional(program: &str, args: &[&str]) {
+        match Command::new(program).args(args).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => tracing::warn!("{} exited with {}", program, status),
+            Err(e) => tracing::debug!("{} not available: {}", program, e),
+        }
+    }
+
+    pub fn install(exe: &Path) -> Result<()> {
+        let dir = applications_dir()?;
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(DESKTOP_FILE), desktop_entry(exe, false))?;
+
+        run_optional("update-desktop-database", &[&dir.to_string_lossy()]);
+        run_optional(
+            "xdg-mime",
+            &["default", DESKTOP_FILE, &format!("x-scheme-handler/{URL_SCHEME}")],
+        );
+        Ok(())
+    }
+
+    pub fn set_autostart(exe: &Path, enabled: bool) -> Result<()> {
+        let path = autostart_dir()?.join(DESKTOP_FILE);
+        if enabled {
+            std::fs::create_dir_all(autostart_dir()?)?;
+            std::fs::write(path, desktop_entry(exe, true))?;
+            Ok(())
+        } else {
+            remove_if_exists(&path)
+        }
+    }
+
+    pub fn uninstall() -> Result<()> {
+        let dir = applications_dir()?;
+        remove_if_exists(&dir.join(DESKTOP_FILE))?;
+        run_optional("update-desktop-database", &[&dir.to_string_lossy()]);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_desktop_entry_quotes_exec() {
+            let entry = desktop_entry(Path::new("/opt/Priv Msg/$bin"), false);
+            assert!(entry.contains("Exec=\"/opt/Priv Msg/\\$bin\" %u\n"));
+            assert!(entry.contains("MimeType=x-scheme-handler/privmsg;"));
+
+            let autostart = desktop_entry(Path::new("/usr/bin/privmsg-desktop"), true);
+            assert!(autostart.contains("Exec=\"/usr/bin/privmsg-desktop\"\n"));
+            assert!(!autostart.contains("MimeType"));
+        }
+    }
+}
+
+// ============= Windows =============
+
+#[cfg(windows)]
+mod platform {
+    use super::*;
+    use std::process::Command;
+
+    const PROTOCOL_KEY: &str = r"HKCU\Software\Classes\privmsg";
+    const RUN_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Run";
+
+    fn reg(args: &[&str]) -> Result<()> {
+        let status = Command::new("reg").args(args).status().context("Cannot run reg.exe")?;
+        anyhow::ensure!(status.success(), "reg {} failed with {}", args.join(" "), status);
+        Ok(())
+    }
+
+    /// Like `reg`, for removals where the key may already be gone
+    fn reg_delete(args: &[&str]) {
+        let _ = Command::new("reg").arg("delete").args(args).arg("/f").status();
+    }
+
+    fn shortcut_paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        if let Some(dir) = dirs::data_dir() {
+            let programs = dir.join(r"Microsoft\Windows\Start Menu\Programs");
+            paths.push(programs.join(format!("{APP_NAME}.lnk")));
+        }
+        if let Some(dir) = dirs::desktop_dir() {
+            paths.push(dir.join(format!("{APP_NAME}.lnk")));
+        }
+        paths
+    }
+
+    /// .lnk files are written through the shell's COM object
+    fn create_shortcut(path: &Path, exe: &Path) -> Result<()> {
+        let quote = |p: &Path| p.to_string_lossy().replace('\'', "''");
+        let script = format!(
+            "$s = (New-Object -ComObject WScript.Shell).CreateShortcut('{}'); \
+             $s.TargetPath = '{}'; $s.WorkingDirectory = '{}'; $s.Save()",
+            quote(path),
+            quote(exe),
+            quote(exe.parent().unwrap_or(exe)),
+        );
+        let status = Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+            .status()
+            .context("Cannot run PowerShell")?;
+        anyhow::ensure!(status.success(), "Creating shortcut {:?} failed", path);
+        Ok(())
+    }
+
+    pub fn install(exe: &Path) -> Result<()> {
+        let exe_str = exe.to_string_lossy();
+        let command = format!("\"{}\" \"%1\"", exe_str);
+
+        reg(&["add", PROTOCOL_KEY, "/ve", "/d", &format!("URL:{APP_NAME} Protocol"), "/f"])?;
+        reg(&["add", PROTOCOL_KEY, "/v", "URL Protocol", "/d", "", "/f"])?;
+        let icon = format!("\"{}\",0", exe_str);
+        reg(&["add", &format!(r"{PROTOCOL_KEY}\DefaultIcon"), "/ve", "/d", &icon, "/f"])?;
+        let open_key = format!(r"{PROTOCOL_KEY}\shell\open\command");
+        reg(&["add", &open_key, "/ve", "/d", &command, "/f"])?;
+
+        for path in shortcut_paths() {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            create_shortcut(&path, exe)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_autostart(exe: &Path, enabled: bool) -> Result<()> {
+        if enabled {
+            let command = format!("\"{}\"", exe.to_string_lossy());
+            reg(&["add", RUN_KEY, "/v", APP_NAME, "/d", &command, "/f"])
+        } else {
+            reg_delete(&[RUN_KEY, "/v", APP_NAME]);
+            Ok(())
+        }
+    }
+
+    pub fn uninstall() -> Result<()> {
+        reg_delete(&[PROTOCOL_KEY]);
+        for path in shortcut_paths() {
+            remove_if_exists(&path)?;
+        }
+        Ok(())
+    }
+}
+
+// ============= Other platforms =============
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use super::*;
+
+    pub fn install(_exe: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_autostart(_exe: &Path, _enabled: bool) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        Ok(())
+    }
+}
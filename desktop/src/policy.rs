@@ -0,0 +1,65 @@
+//! Client policy set by the server operator
+//!
+//! The signing key is pinned the first time a policy is seen; after that,
+//! policies signed by another key and versions older than the current one
+//! are rejected. The verified document is kept in the settings table and
+//! checked again on startup.
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use privmsg_proto::policy::{ClientPolicy, SignedPolicy};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::database::Database;
+
+const KEY_SETTING: &str = "policy_public_key";
+const POLICY_SETTING: &str = "client_policy";
+
+/// The stored policy, if it still verifies against the pinned key
+pub fn load(db: &Database) -> Option<ClientPolicy> {
+    let key = db.get_setting(KEY_SETTING)?;
+    let json = db.get_setting(POLICY_SETTING)?;
+    let signed: SignedPolicy = serde_json::from_str(&json).ok()?;
+    verify(&key, &signed)
+        .inspect_err(|e| tracing::warn!("Dropping stored client policy: {}", e))
+        .ok()
+}
+
+/// Verify and store a policy from the server. Returns it if it is newer
+/// than `current`, `None` if nothing changed.
+pub fn update(
+    db: &Database,
+    current: Option<&ClientPolicy>,
+    signed: &SignedPolicy,
+) -> Result<Option<ClientPolicy>> {
+    let pinned = db.get_setting(KEY_SETTING);
+    let policy = verify(pinned.as_deref().unwrap_or(&signed.public_key), signed)?;
+    if pinned.is_none() {
+        tracing::info!("Pinning client policy key {}", signed.public_key);
+        db.set_setting(KEY_SETTING, &signed.public_key)?;
+    }
+
+    if let Some(current) = current {
+        if policy.version < current.version {
+            bail!("Server sent policy version {} after {}", policy.version, current.version);
+        }
+        if policy.version == current.version {
+            return Ok(None);
+        }
+    }
+    db.set_setting(POLICY_SETTING, &serde_json::to_string(signed)?)?;
+    Ok(Some(policy))
+}
+
+/// Check the signature against `public_key` and parse the policy
+fn verify(public_key: &str, signed: &SignedPolicy) -> Result<ClientPolicy> {
+    if signed.public_key != public_key {
+        bail!("Client policy signed by an unknown key");
+    }
+    let public_key = STANDARD.decode(public_key)?;
+    let signature = STANDARD.decode(&signed.signature)?;
+    UnparsedPublicKey::new(&ED25519, &public_key)
+        .verify(&signed.signing_bytes(), &signature)
+        .map_err(|_| anyhow::anyhow!("Bad client policy signature"))?;
+    Ok(serde_json::from_str(&signed.policy)?)
+}
@@ -0,0 +1,102 @@
+//! Power source monitoring
+//!
+//! On battery the app does less in the background: the once-a-second tick
+//! only runs during a call and scheduled backups wait until the machine is
+//! plugged in again. The power source is polled, since neither platform
+//! offers a change notification that fits an iced subscription.
+
+use iced::Subscription;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PowerSource {
+    #[default]
+    Mains,
+    Battery,
+}
+
+/// The power source at start and whenever it changes
+pub fn monitor() -> Subscription<PowerSource> {
+    iced::subscription::channel("power-source", 4, |mut output| async move {
+        use futures::SinkExt;
+
+        let mut last = None;
+        loop {
+            // Unknown means a desktop without a battery
+            let source = platform::power_source().await.unwrap_or_default();
+            if last != Some(source) {
+                tracing::info!("Power source: {:?}", source);
+                let _ = output.send(source).await;
+                last = Some(source);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    })
+}
+
+// ============= Linux =============
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::PowerSource;
+
+    /// On battery when no mains or USB supply is online but a battery is
+    /// present
+    pub async fn power_source() -> Option<PowerSource> {
+        let mut entries = tokio::fs::read_dir("/sys/class/power_supply").await.ok()?;
+        let mut has_battery = false;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let kind = tokio::fs::read_to_string(path.join("type"))
+                .await
+                .unwrap_or_default();
+            match kind.trim() {
+                "Battery" => has_battery = true,
+                "Mains" | "USB" => {
+                    let online = tokio::fs::read_to_string(path.join("online"))
+                        .await
+                        .unwrap_or_default();
+                    if online.trim() == "1" {
+                        return Some(PowerSource::Mains);
+                    }
+                }
+                _ => {}
+            }
+        }
+        has_battery.then_some(PowerSource::Battery)
+    }
+}
+
+// ============= Windows =============
+
+#[cfg(windows)]
+mod platform {
+    use super::PowerSource;
+    use winapi::um::winbase::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    pub async fn power_source() -> Option<PowerSource> {
+        let mut status: SYSTEM_POWER_STATUS = unsafe { std::mem::zeroed() };
+        if unsafe { GetSystemPowerStatus(&mut status) } == 0 {
+            return None;
+        }
+        // 0 offline, 1 online, 255 unknown
+        match status.ACLineStatus {
+            0 => Some(PowerSource::Battery),
+            1 => Some(PowerSource::Mains),
+            _ => None,
+        }
+    }
+}
+
+// ============= Other platforms =============
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use super::PowerSource;
+
+    pub async fn power_source() -> Option<PowerSource> {
+        None
+    }
+}
@@ -1,14 +1,50 @@
 //! Chat screen for PrivMsg Desktop
 
 use crate::messages::Message;
-use crate::state::{AppState, ChatMessage, MessageStatus, MessageType};
+use crate::state::{
+    AppState, ChatMessage, DeliveryInfo, MessageStatus, MessageType, PhotoCapture, StagedAttachment,
+    SystemEvent, VoicePlayback,
+};
+use crate::widgets::waveform::Waveform;
 use iced::widget::{
-    button, column, container, row, scrollable, text, text_input, Column, Space,
+    button, checkbox, column, container, image, progress_bar, row, scrollable, text, text_input,
+    tooltip, Column, Row, Space,
 };
-use iced::{Alignment, Element, Length};
+use crate::screens::settings::SettingsScreen;
+use crate::theme::Theme;
+use crate::wallpaper::{contrasting_text, Wallpaper};
+use crate::widgets::backdrop::Backdrop;
+use crate::widgets::context_menu::ContextMenu;
+use iced::{Alignment, Background, Color, Element, Length};
+use privmsg_proto::policy::Feature;
+use privmsg_proto::quote::Quote;
+use privmsg_proto::stats::ConversationStats;
 
 pub struct ChatScreen;
 
+/// A wallpaper or a bubble on one, with text that reads on it
+struct Surface {
+    background: Option<Background>,
+    text: Color,
+    radius: f32,
+}
+
+impl container::StyleSheet for Surface {
+    type Style = iced::Theme;
+
+    fn appearance(&self, _style: &Self::Style) -> container::Appearance {
+        container::Appearance {
+            background: self.background,
+            text_color: Some(self.text),
+            border: iced::Border {
+                radius: self.radius.into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
 impl ChatScreen {
     pub fn view(state: &AppState, peer_id: &str) -> Element<'static, Message> {
         // Header
@@ -18,14 +54,41 @@ impl ChatScreen {
         let messages = Self::messages_view(state);
 
         // Input area
-        let input = Self::input_area(state);
+        let input = Self::input_area(state, peer_id);
 
         // Main layout
-        let content = column![header, messages, input]
-            .width(Length::Fill)
-            .height(Length::Fill);
+        let mut content = column![header, messages].width(Length::Fill).height(Length::Fill);
+        if !state.downloads.is_empty() {
+            content = content.push(Self::downloads_bar(state));
+        }
+        let waiting = state.pending_outgoing_count();
+        if waiting > 0 {
+            let label = match waiting {
+                1 => "1 message waiting to send".to_string(),
+                n => format!("{} messages waiting to send", n),
+            };
+            content = content.push(
+                container(text(label).size(12))
+                    .width(Length::Fill)
+                    .padding([4, 16])
+                    .center_x(),
+            );
+        }
+        if state.selecting_messages {
+            content = content.push(Self::selection_bar(state));
+        }
+        let content = content.push(input);
+
+        let mut layout = row![content];
+        if !state.forwarding.is_empty() {
+            layout = layout.push(Self::forward_panel(state));
+        } else if let Some((ref msg, ref delivery)) = state.message_info {
+            layout = layout.push(Self::message_info_panel(state, msg, delivery.as_ref()));
+        } else if state.show_chat_details {
+            layout = layout.push(Self::details_panel(state, peer_id));
+        }
 
-        container(content)
+        container(layout)
             .width(Length::Fill)
             .height(Length::Fill)
             .into()
@@ -55,7 +118,27 @@ impl ChatScreen {
         .center_x()
         .center_y();
 
-        let peer_info = column![text(name).size(16), text("online").size(12),].spacing(2);
+        let profile = state.profiles.get(peer_id);
+        let status = match profile {
+            Some(p) => p.status_line().unwrap_or_else(|| p.last_seen_text()),
+            None => "last seen recently".to_string(),
+        };
+        let peer_info = button(column![text(name).size(16), text(status).size(12),].spacing(2))
+            .padding(0)
+            .style(iced::theme::Button::Text)
+            .on_press(Message::ToggleChatDetails);
+
+        // Hovering the name shows the bio
+        let peer_info: Element<'static, Message> = match profile.and_then(|p| p.bio.clone()) {
+            Some(bio) => tooltip(
+                peer_info,
+                container(text(bio).size(12)).padding(8).max_width(320),
+                tooltip::Position::Bottom,
+            )
+            .style(iced::theme::Container::Box)
+            .into(),
+            None => peer_info.into(),
+        };
 
         // Call buttons
         let voice_call_btn = button(text("Call").size(12))
@@ -66,6 +149,10 @@ impl ChatScreen {
             .padding(8)
             .on_press(Message::StartCall(peer_id.to_string(), true));
 
+        let calls = (state.can_use(Feature::Calls) && !state.is_read_only(peer_id)).then(|| {
+            row![voice_call_btn, Space::with_width(8), video_call_btn]
+        });
+
         row![
             back_btn,
             Space::with_width(8),
@@ -73,15 +160,241 @@ impl ChatScreen {
             Space::with_width(12),
             peer_info,
             Space::with_width(Length::Fill),
-            voice_call_btn,
-            Space::with_width(8),
-            video_call_btn,
         ]
+        .push_maybe(calls)
         .padding(12)
         .align_items(Alignment::Center)
         .into()
     }
 
+    fn details_panel(state: &AppState, peer_id: &str) -> Element<'static, Message> {
+        let profile = state.profiles.get(peer_id);
+        let name = profile
+            .and_then(|p| p.display_name.clone())
+            .unwrap_or_else(|| peer_id.to_string());
+
+        let mut details = column![
+            row![
+                text("Details").size(18),
+                Space::with_width(Length::Fill),
+                button(text("x").size(14))
+                    .padding([4, 10])
+                    .on_press(Message::ToggleChatDetails),
+            ]
+            .align_items(Alignment::Center),
+            Space::with_height(12),
+            text(name).size(16),
+            text(peer_id.to_string()).size(12),
+        ]
+        .spacing(6);
+
+        if let Some(p) = profile {
+            details = details.push(text(p.last_seen_text()).size(12));
+        }
+
+        if let Some(status) = profile.and_then(|p| p.status_line()) {
+            details = details.push(Space::with_height(12));
+            details = details.push(text("Status").size(12));
+            details = details.push(text(status).size(14));
+        }
+        if let Some(bio) = profile.and_then(|p| p.bio.clone()) {
+            details = details.push(Space::with_height(12));
+            details = details.push(text("Bio").size(12));
+            details = details.push(text(bio).size(14));
+        }
+        details = details.push(Space::with_height(12));
+        details = details.push(text("Wallpaper").size(12));
+        details = details.push(SettingsScreen::wallpaper_picker(
+            Some(peer_id),
+            state.conversation_wallpapers.get(peer_id),
+        ));
+
+        if let Some(ref stats) = state.conversation_stats {
+            details = details.push(Space::with_height(12));
+            details = details.push(Self::stats_section(stats));
+        }
+
+        container(scrollable(details.padding(16)))
+            .width(260)
+            .height(Length::Fill)
+            .into()
+    }
+
+    /// Sending device and, for messages sent from here, how delivery went
+    /// Actions on the messages picked with "Select"
+    fn selection_bar(state: &AppState) -> Element<'static, Message> {
+        let count = state.selected_messages.len();
+        let action = |label: String, message: Message| {
+            button(text(label).size(12))
+                .padding([4, 10])
+                .on_press_maybe((count > 0).then_some(message))
+        };
+        let starred = state
+            .current_messages
+            .iter()
+            .filter(|m| state.selected_messages.contains(&m.message_id))
+            .all(|m| m.is_starred);
+        let star = if count > 0 && starred {
+            action(format!("Unstar {}", count), Message::StarSelected(false))
+        } else {
+            action(format!("Star {}", count), Message::StarSelected(true))
+        };
+
+        container(
+            row![
+                text(format!("{} selected", count)).size(13),
+                Space::with_width(Length::Fill),
+                action("Copy".to_string(), Message::CopySelectedMessages),
+                action(format!("Forward {}", count), Message::ForwardSelected),
+                star,
+                action(format!("Delete {}", count), Message::DeleteSelectedMessages),
+                button(text("Cancel").size(12))
+                    .padding([4, 10])
+                    .on_press(Message::ClearMessageSelection),
+            ]
+            .spacing(6)
+            .align_items(Alignment::Center),
+        )
+        .width(Length::Fill)
+        .padding([6, 16])
+        .into()
+    }
+
+    /// Conversations to forward messages to
+    fn forward_panel(state: &AppState) -> Element<'static, Message> {
+        let summary = match state.forwarding.as_slice() {
+            [msg] => privmsg_proto::preview_text(&msg.content, 80).into_owned(),
+            messages => format!("{} messages", messages.len()),
+        };
+        let targets = state
+            .conversations
+            .iter()
+            .filter(|c| !c.is_archived && !state.is_read_only(&c.peer_id))
+            .map(|c| {
+                let name = c.peer_name.clone().unwrap_or_else(|| c.peer_id.clone());
+                button(text(name).size(14))
+                    .width(Length::Fill)
+                    .padding([6, 10])
+                    .style(iced::theme::Button::Text)
+                    .on_press(Message::ForwardTo(c.peer_id.clone()))
+                    .into()
+            });
+
+        let panel = column![
+            row![
+                text("Forward to").size(18),
+                Space::with_width(Length::Fill),
+                button(text("x").size(14))
+                    .padding([4, 10])
+                    .on_press(Message::CancelForward),
+            ]
+            .align_items(Alignment::Center),
+            text(summary).size(12),
+            Space::with_height(8),
+            Column::with_children(targets).spacing(2),
+        ]
+        .spacing(6);
+
+        container(scrollable(panel.padding(16)))
+            .width(260)
+            .height(Length::Fill)
+            .into()
+    }
+
+    fn message_info_panel(
+        state: &AppState,
+        msg: &ChatMessage,
+        delivery: Option<&DeliveryInfo>,
+    ) -> Element<'static, Message> {
+        let device = match msg.sender_device_id.as_deref() {
+            Some(device_id) => match state.devices.iter().find(|d| d.device_id == device_id) {
+                Some(device) => format!("{} {}", device.icon(), device.device_name),
+                None => device_id.to_string(),
+            },
+            None => "Unknown".to_string(),
+        };
+        // Seconds matter here: sends and acks are usually moments apart
+        let time = |millis: Option<i64>| {
+            millis
+                .and_then(chrono::DateTime::from_timestamp_millis)
+                .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "-".to_string())
+        };
+
+        let mut info = column![
+            row![
+                text("Message info").size(18),
+                Space::with_width(Length::Fill),
+                button(text("x").size(14))
+                    .padding([4, 10])
+                    .on_press(Message::CloseMessageInfo),
+            ]
+            .align_items(Alignment::Center),
+            Space::with_height(12),
+            text("Sent from").size(12),
+            text(device).size(14),
+            text(time(Some(msg.timestamp))).size(12),
+        ]
+        .spacing(6);
+
+        if let Some(delivery) = delivery {
+            info = info
+                .push(Space::with_height(12))
+                .push(text("Delivery").size(12))
+                .push(text("Transport: WebSocket").size(14))
+                .push(text(format!("Attempts: {}", delivery.attempts)).size(14))
+                .push(text(format!("Sent: {}", time(delivery.sent_at))).size(14))
+                .push(text(format!("Server ack: {}", time(delivery.acked_at))).size(14));
+        }
+
+        container(scrollable(info.padding(16)))
+            .width(260)
+            .height(Length::Fill)
+            .into()
+    }
+
+    /// Counts, reply times and bar charts from locally computed statistics
+    fn stats_section(stats: &ConversationStats) -> Element<'static, Message> {
+        let mut section = column![
+            text("Statistics").size(12),
+            text(format!("{} sent, {} received", stats.sent, stats.received)).size(14),
+        ]
+        .spacing(6);
+        if stats.total() == 0 {
+            return section.into();
+        }
+
+        if let Some(seconds) = stats.my_response_seconds {
+            section = section.push(text(format!("You usually reply in {}", reply_label(seconds))).size(12));
+        }
+        if let Some(seconds) = stats.their_response_seconds {
+            section = section.push(text(format!("They usually reply in {}", reply_label(seconds))).size(12));
+        }
+        if let Some(hour) = stats.busiest_hour() {
+            section = section.push(text(format!("Busiest around {:02}:00", hour)).size(12));
+        }
+
+        let offset = chrono::Local::now().offset().local_minus_utc() as i64;
+        let today = (chrono::Utc::now().timestamp() + offset).div_euclid(86_400);
+        let days: Vec<u64> = (today - (CHART_DAYS as i64 - 1)..=today)
+            .map(|day| {
+                stats
+                    .messages_per_day
+                    .iter()
+                    .find(|d| d.day == day)
+                    .map_or(0, |d| d.count)
+            })
+            .collect();
+
+        section
+            .push(Space::with_height(6))
+            .push(text(format!("Last {} days", CHART_DAYS)).size(12))
+            .push(bar_chart(&days, 12.0))
+            .push(text("By hour of day").size(12))
+            .push(bar_chart(&stats.by_hour, 7.0))
+            .into()
+    }
+
     fn messages_view(state: &AppState) -> Element<'static, Message> {
         if state.current_messages.is_empty() {
             return container(
@@ -99,32 +412,122 @@ impl ChatScreen {
             .into();
         }
 
-        let messages: Vec<Element<'static, Message>> = state
-            .current_messages
-            .iter()
-            .map(|msg| Self::message_bubble(msg))
-            .collect();
+        // Over a wallpaper, bubbles get the theme's colors so text stays
+        // readable
+        let wallpaper = state.current_chat_peer.as_deref().and_then(|p| state.wallpaper(p));
+        let bubbles = wallpaper.map(|_| {
+            let palette = Theme::named(&state.config.ui.theme);
+            (palette.outgoing_bubble, palette.incoming_bubble)
+        });
 
-        scrollable(
+        // Long chats only build widgets for the newest page(s)
+        let mut messages: Vec<Element<'static, Message>> = Vec::new();
+        if state.current_messages.len() > state.chat_window {
+            messages.push(
+                container(
+                    button(text("Show earlier messages").size(12))
+                        .padding([6, 12])
+                        .on_press(Message::ShowEarlierMessages),
+                )
+                .width(Length::Fill)
+                .center_x()
+                .into(),
+            );
+        }
+        messages.extend(
+            state
+                .current_messages
+                .tail(state.chat_window)
+                .map(|msg| {
+                    if let Some(ref event) = msg.system_event {
+                        return Self::system_notice(state, msg, event);
+                    }
+                    let actions_open = state.failed_actions.as_deref() == Some(msg.message_id.as_str());
+                    let bubble = bubbles.map(|(outgoing, incoming)| if msg.is_outgoing { outgoing } else { incoming });
+                    let selected = state.selected_messages.contains(&msg.message_id);
+                    let bubble = Self::message_bubble(msg, state.voice_playback.as_ref(), actions_open, selected, bubble);
+                    if !state.selecting_messages {
+                        return bubble;
+                    }
+                    let id = msg.message_id.clone();
+                    row![
+                        checkbox("", selected).on_toggle(move |_| Message::ToggleMessageSelected(id.clone())),
+                        bubble,
+                    ]
+                    .align_items(Alignment::Center)
+                    .into()
+                }),
+        );
+
+        let messages = scrollable(
             Column::with_children(messages)
                 .spacing(8)
                 .padding(16)
                 .width(Length::Fill),
         )
-        .height(Length::Fill)
+        .height(Length::Fill);
+
+        let Some(wallpaper) = wallpaper else {
+            return messages.into();
+        };
+        let backdrop = container(messages)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .style(iced::theme::Container::Custom(Box::new(Surface {
+                background: wallpaper.background(),
+                text: wallpaper.text_color(),
+                radius: 0.0,
+            })));
+        match wallpaper {
+            Wallpaper::Image { path, .. } => Backdrop::new(image::Handle::from_path(path), backdrop).into(),
+            _ => backdrop.into(),
+        }
+    }
+
+    /// Centered notice for a conversation event
+    fn system_notice(state: &AppState, msg: &ChatMessage, event: &SystemEvent) -> Element<'static, Message> {
+        let name = |user_id: &str| {
+            if state.session.as_ref().is_some_and(|s| s.user_id == user_id) {
+                return "You".to_string();
+            }
+            state
+                .profiles
+                .get(user_id)
+                .and_then(|p| p.display_name.clone())
+                .unwrap_or_else(|| user_id.to_string())
+        };
+
+        container(
+            column![
+                text(event.describe(name)).size(12),
+                text(AppState::format_timestamp(msg.timestamp)).size(10),
+            ]
+            .spacing(2)
+            .align_items(Alignment::Center),
+        )
+        .width(Length::Fill)
+        .center_x()
         .into()
     }
 
-    fn message_bubble(msg: &ChatMessage) -> Element<'static, Message> {
+    fn message_bubble(
+        msg: &ChatMessage,
+        playback: Option<&VoicePlayback>,
+        actions_open: bool,
+        selected: bool,
+        background: Option<Color>,
+    ) -> Element<'static, Message> {
         let is_outgoing = msg.is_outgoing;
 
         // Message content based on type
         let content = match msg.message_type {
             MessageType::Text => Self::text_message_content(msg),
-            MessageType::Voice => Self::voice_message_content(msg),
+            MessageType::Voice => Self::voice_message_content(msg, playback),
             MessageType::Video => Self::video_message_content(msg),
             MessageType::Image => Self::image_message_content(msg),
             MessageType::File => Self::file_message_content(msg),
+            // Without an event there's nothing better than the stored text
+            MessageType::System => text(&msg.content).size(12).into(),
         };
 
         // Time and status
@@ -141,10 +544,34 @@ impl ChatScreen {
             ""
         };
 
-        let time_row = row![text(&time).size(11), Space::with_width(4), text(status_icon).size(11),]
+        // Failed messages can be tapped for resend/delete
+        let status: Element<'static, Message> = if msg.is_outgoing && msg.status == MessageStatus::Failed {
+            button(text(status_icon).size(11))
+                .padding([0, 4])
+                .style(iced::theme::Button::Text)
+                .on_press(Message::ToggleFailedActions(msg.message_id.clone()))
+                .into()
+        } else {
+            text(status_icon).size(11).into()
+        };
+        let starred = if msg.is_starred { "*" } else { "" };
+        let time_row = row![text(starred).size(11), text(&time).size(11), Space::with_width(4), status]
+            .spacing(2)
             .align_items(Alignment::Center);
 
-        let bubble_content = column![content, time_row]
+        // Caption beneath media
+        let content: Element<'static, Message> = match msg.caption.as_deref() {
+            Some(caption) if msg.message_type != MessageType::Text => {
+                column![content, text(caption).size(14)].spacing(6).into()
+            }
+            _ => content,
+        };
+        let content: Element<'static, Message> = match msg.quote {
+            Some(ref quote) => column![Self::quote_view(quote), content].spacing(6).into(),
+            None => content,
+        };
+
+        let mut bubble_content = column![content, time_row]
             .spacing(4)
             .align_items(if is_outgoing {
                 Alignment::End
@@ -152,9 +579,37 @@ impl ChatScreen {
                 Alignment::Start
             });
 
-        let bubble = container(bubble_content)
-            .padding(12)
+        if actions_open {
+            bubble_content = bubble_content.push(
+                row![
+                    text("Not sent").size(11),
+                    Space::with_width(8),
+                    button(text("Resend").size(12))
+                        .padding([4, 10])
+                        .on_press(Message::ResendMessage(msg.message_id.clone())),
+                    button(text("Delete").size(12))
+                        .padding([4, 10])
+                        .on_press(Message::DeleteMessage(msg.message_id.clone())),
+                ]
+                .spacing(6)
+                .align_items(Alignment::Center),
+            );
+        }
+
+        // Emoji-only messages stand on their own
+        let jumbo = msg.jumbo_emoji_count().is_some();
+        let mut bubble = container(bubble_content)
+            .padding(if jumbo { 2 } else { 12 })
             .max_width(500);
+        if let Some(background) = background.filter(|_| !jumbo) {
+            bubble = bubble.style(iced::theme::Container::Custom(Box::new(Surface {
+                background: Some(background.into()),
+                text: contrasting_text(background),
+                radius: 12.0,
+            })));
+        }
+
+        let bubble = ContextMenu::new(bubble, Self::message_actions(msg, selected));
 
         // Align left or right based on sender
         let bubble_row = if is_outgoing {
@@ -166,30 +621,102 @@ impl ChatScreen {
         bubble_row.width(Length::Fill).into()
     }
 
+    /// What the context menu offers for a message
+    fn message_actions(msg: &ChatMessage, selected: bool) -> Vec<(String, Message)> {
+        let id = || msg.message_id.clone();
+        let is_text = msg.message_type == MessageType::Text;
+        let mut actions = Vec::new();
+        if is_text {
+            actions.push(("Copy".to_string(), Message::CopyMessage(id())));
+        } else if msg.attachment.is_some() {
+            if msg.caption.is_some() {
+                actions.push(("Copy caption".to_string(), Message::CopyMessage(id())));
+            }
+            actions.push(("Copy file name".to_string(), Message::CopyFileName(id())));
+            actions.push(("Copy link".to_string(), Message::CopyLink(id())));
+        }
+        let select = if selected { "Deselect" } else { "Select" };
+        actions.push((select.to_string(), Message::ToggleMessageSelected(id())));
+        actions.push(("Reply".to_string(), Message::ReplyToMessage(id())));
+        if !msg.is_outgoing {
+            actions.push(("Reply privately".to_string(), Message::ReplyPrivately(id())));
+        }
+        if is_text {
+            actions.push(("Forward...".to_string(), Message::ForwardMessage(id())));
+        }
+        let star = if msg.is_starred { "Unstar" } else { "Star" };
+        actions.push((star.to_string(), Message::ToggleStarred(id())));
+        actions.push(("Info".to_string(), Message::ShowMessageInfo(id())));
+        actions.push(("Delete for me".to_string(), Message::DeleteMessage(id())));
+        actions
+    }
+
+    /// The message a reply quotes, above the reply
+    fn quote_view(quote: &Quote) -> Element<'static, Message> {
+        let from = match quote.conversation_id {
+            Some(ref conversation_id) => format!("{}, in {}", quote.sender_id, conversation_id),
+            None => quote.sender_id.clone(),
+        };
+        container(column![text(from).size(11), text(&quote.text).size(12)].spacing(2))
+            .padding([4, 8])
+            .style(iced::theme::Container::Box)
+            .into()
+    }
+
     fn text_message_content(msg: &ChatMessage) -> Element<'static, Message> {
-        text(&msg.content).size(14).into()
+        let size = match msg.jumbo_emoji_count() {
+            Some(1) => 48,
+            Some(2) => 40,
+            Some(_) => 32,
+            None => 14,
+        };
+        text(&msg.content).size(size).into()
     }
 
-    fn voice_message_content(msg: &ChatMessage) -> Element<'static, Message> {
-        let duration = msg
+    fn voice_message_content(
+        msg: &ChatMessage,
+        playback: Option<&VoicePlayback>,
+    ) -> Element<'static, Message> {
+        let duration_ms = msg
             .attachment
             .as_ref()
             .and_then(|a| a.duration_ms)
-            .map(|d| AppState::format_duration(d / 1000))
-            .unwrap_or_else(|| "0:00".to_string());
+            .unwrap_or(0);
 
-        let file_id = msg
+        // Only the last played or scrubbed message has a position
+        let playback = playback.filter(|p| p.message_id == msg.message_id);
+        let is_playing = playback.is_some_and(|p| p.is_playing());
+        let position = playback.map(|p| p.position).unwrap_or(0.0);
+
+        let time = if playback.is_some() {
+            AppState::format_duration((duration_ms as f32 * position) as i64 / 1000)
+        } else {
+            AppState::format_duration(duration_ms / 1000)
+        };
+
+        let play_btn = button(text(if is_playing { "||" } else { ">" }).size(16))
+            .padding(10)
+            .on_press(Message::PlayVoice(msg.message_id.clone()));
+
+        let waveform: Element<'static, Message> = match msg
             .attachment
             .as_ref()
-            .map(|a| a.file_id.clone())
-            .unwrap_or_default();
+            .and_then(|a| a.waveform.clone())
+        {
+            Some(samples) => {
+                let message_id = msg.message_id.clone();
+                Waveform::new(samples, position, move |pos| {
+                    Message::SeekVoice(message_id.clone(), pos)
+                })
+                .view(192.0, 32.0)
+            }
+            None => text("Voice message").size(14).into(),
+        };
 
         row![
-            button(text(">").size(16))
-                .padding(10)
-                .on_press(Message::DownloadFile(file_id, "voice.ogg".to_string())),
+            play_btn,
             Space::with_width(8),
-            column![text("Voice message").size(14), text(&duration).size(12),].spacing(2),
+            column![waveform, text(&time).size(12),].spacing(2),
         ]
         .align_items(Alignment::Center)
         .into()
@@ -226,9 +753,7 @@ impl ChatScreen {
             .height(200)
             .center_x()
             .center_y(),
-            button(text("Download").size(12))
-                .padding(8)
-                .on_press(Message::DownloadFile(file_id, file_name)),
+            Self::download_button(msg, file_id, file_name),
         ]
         .spacing(8)
         .align_items(Alignment::Center)
@@ -247,15 +772,22 @@ impl ChatScreen {
             .map(|a| a.file_name.clone())
             .unwrap_or_else(|| "image.jpg".to_string());
 
+        if msg.attachment.as_ref().is_some_and(|a| a.view_once) {
+            let label = if msg.is_outgoing { "View-once photo sent" } else { "View-once photo" };
+            return container(text(label).size(14))
+                .width(250)
+                .padding(16)
+                .center_x()
+                .into();
+        }
+
         column![
             container(text("Image").size(14).horizontal_alignment(iced::alignment::Horizontal::Center))
                 .width(250)
                 .height(200)
                 .center_x()
                 .center_y(),
-            button(text("Download").size(12))
-                .padding(8)
-                .on_press(Message::DownloadFile(file_id, file_name)),
+            Self::download_button(msg, file_id, file_name),
         ]
         .spacing(8)
         .align_items(Alignment::Center)
@@ -280,15 +812,123 @@ impl ChatScreen {
             Space::with_width(12),
             column![text(&file_name).size(14), text(&file_size).size(12),].spacing(2),
             Space::with_width(12),
-            button(text("Download").size(12))
+            Self::download_button(msg, file_id, file_name.clone()),
+        ]
+        .align_items(Alignment::Center)
+        .into()
+    }
+
+    /// Download button of an attachment, greyed out with the reason once
+    /// the server has dropped the file
+    fn download_button(msg: &ChatMessage, file_id: String, file_name: String) -> Element<'static, Message> {
+        let download = button(text("Download").size(12)).padding(8);
+        if !msg.attachment.as_ref().is_some_and(|a| a.is_expired()) {
+            return download.on_press(Message::DownloadFile(file_id, file_name)).into();
+        }
+
+        let reason = if msg.is_outgoing {
+            "The server deleted this file at the end of its storage period. Send it again to share it."
+        } else {
+            "The server deleted this file at the end of its storage period. Ask the sender to upload it again."
+        };
+        let download = tooltip(
+            download,
+            container(text(reason).size(12)).padding(8).max_width(280),
+            tooltip::Position::Top,
+        )
+        .style(iced::theme::Container::Box);
+        if msg.is_outgoing {
+            return download.into();
+        }
+        row![
+            download,
+            button(text("Ask to re-upload").size(12))
                 .padding(8)
-                .on_press(Message::DownloadFile(file_id, file_name.clone())),
+                .on_press(Message::RequestReupload(msg.message_id.clone())),
         ]
+        .spacing(6)
         .align_items(Alignment::Center)
         .into()
     }
 
-    fn input_area(state: &AppState) -> Element<'static, Message> {
+    /// Attachments being saved, with progress and controls
+    fn downloads_bar(state: &AppState) -> Element<'static, Message> {
+        let rows: Vec<Element<'static, Message>> = state
+            .downloads
+            .iter()
+            .map(|download| {
+                let file_id = download.attachment.file_id.clone();
+                let progress = download.control.progress();
+
+                let toggle = if download.paused {
+                    button(text("Resume").size(12))
+                        .padding(6)
+                        .on_press(Message::ResumeDownload(file_id.clone()))
+                } else {
+                    button(text("Pause").size(12))
+                        .padding(6)
+                        .on_press(Message::PauseDownload(file_id.clone()))
+                };
+
+                row![
+                    text(&download.attachment.file_name).size(12).width(160),
+                    progress_bar(0.0..=1.0, progress).height(6),
+                    Space::with_width(8),
+                    text(format!("{:.0}%", progress * 100.0)).size(12),
+                    Space::with_width(8),
+                    toggle,
+                    Space::with_width(4),
+                    button(text("Cancel").size(12))
+                        .padding(6)
+                        .on_press(Message::CancelDownload(file_id)),
+                ]
+                .align_items(Alignment::Center)
+                .into()
+            })
+            .collect();
+
+        container(Column::with_children(rows).spacing(6))
+            .padding([8, 12])
+            .into()
+    }
+
+    fn input_area(state: &AppState, peer_id: &str) -> Element<'static, Message> {
+        // The account is gone; history stays readable
+        if state.is_read_only(peer_id) {
+            return container(
+                column![
+                    text("This user is no longer available").size(14),
+                    text("You can still read this conversation, but not send to it").size(12),
+                ]
+                .spacing(4)
+                .align_items(Alignment::Center),
+            )
+            .width(Length::Fill)
+            .padding(16)
+            .center_x()
+            .into();
+        }
+
+        // Nothing goes out on a key the user hasn't accepted
+        if state.key_conflicts.contains(peer_id) {
+            return container(
+                column![
+                    text("This user's security key changed").size(14),
+                    text("Someone may be impersonating them. Check with them in person or over a call before accepting the new key.")
+                        .size(12),
+                    button(text("Accept new key").size(14))
+                        .padding(8)
+                        .on_press(Message::AcceptPeerKey(peer_id.to_string())),
+                ]
+                .spacing(6)
+                .align_items(Alignment::Center),
+            )
+            .width(Length::Fill)
+            .padding(16)
+            .center_x()
+            .into();
+        }
+
         // Recording indicator
         if state.is_recording_voice {
             let duration = state
@@ -315,10 +955,27 @@ impl ChatScreen {
             .into();
         }
 
+        // Staged attachments replace the regular input until sent or cleared
+        let upload_elsewhere = state
+            .attachment_upload
+            .as_ref()
+            .is_some_and(|u| u.peer_id != peer_id);
+        if !state.staged_files.is_empty() && !upload_elsewhere {
+            return Self::staging_area(state);
+        }
+
+        if let Some(capture) = state.photo_capture.as_ref().filter(|c| c.peer_id == peer_id) {
+            return Self::photo_preview(capture);
+        }
+
         // Regular input
+        let files = state.can_use(Feature::FileTransfer);
         let attach_btn = button(text("Attach").size(12))
             .padding(10)
-            .on_press(Message::AttachFile);
+            .on_press_maybe(files.then_some(Message::AttachFile));
+        let camera_btn = button(text("Camera").size(12))
+            .padding(10)
+            .on_press_maybe(files.then_some(Message::CapturePhoto));
 
         let input = text_input("Message", &state.message_input)
             .on_input(Message::MessageInputChanged)
@@ -326,6 +983,24 @@ impl ChatScreen {
             .padding(12)
             .width(Length::Fill);
 
+        let replying = state.reply_quote.as_ref().map(|quote| {
+            let label = if quote.is_private_reply() {
+                "Replying privately to"
+            } else {
+                "Replying to"
+            };
+            row![
+                text(format!("{} {}: ", label, quote.sender_id)).size(12),
+                text(&quote.text).size(12).width(Length::Fill),
+                button(text("x").size(12))
+                    .padding([2, 8])
+                    .style(iced::theme::Button::Text)
+                    .on_press(Message::CancelReply),
+            ]
+            .padding([8, 12, 0, 12])
+            .align_items(Alignment::Center)
+        });
+
         let send_or_voice = if state.message_input.trim().is_empty() {
             button(text("Mic").size(12))
                 .padding(10)
@@ -337,10 +1012,201 @@ impl ChatScreen {
         };
 
         container(
-            row![attach_btn, Space::with_width(8), input, Space::with_width(8), send_or_voice,]
+            Column::new().push_maybe(replying).push(
+                row![
+                    attach_btn,
+                    Space::with_width(8),
+                    camera_btn,
+                    Space::with_width(8),
+                    input,
+                    Space::with_width(8),
+                    send_or_voice,
+                ]
                 .padding(12)
                 .align_items(Alignment::Center),
+            ),
         )
         .into()
     }
+
+    /// Webcam snapshot awaiting send; the JPEG only ever lives in memory
+    fn photo_preview(capture: &PhotoCapture) -> Element<'static, Message> {
+        let Some(ref photo) = capture.photo else {
+            return container(
+                row![
+                    text("Taking photo...").size(14),
+                    Space::with_width(Length::Fill),
+                    button(text("Cancel").size(12))
+                        .padding(10)
+                        .on_press(Message::DiscardPhoto),
+                ]
+                .align_items(Alignment::Center),
+            )
+            .padding(12)
+            .into();
+        };
+
+        let preview = image(image::Handle::from_memory(photo.jpeg.clone()))
+            .width(Length::Shrink)
+            .height(240);
+
+        let mut view_once = checkbox("View once", capture.view_once);
+        let mut retake_btn = button(text("Retake").size(12)).padding(10);
+        let mut cancel_btn = button(text("Cancel").size(12)).padding(10);
+        let mut send_btn = button(text(if capture.sending { "Sending..." } else { "Send" }).size(12)).padding(10);
+        if !capture.sending {
+            view_once = view_once.on_toggle(Message::PhotoViewOnceToggled);
+            retake_btn = retake_btn.on_press(Message::CapturePhoto);
+            cancel_btn = cancel_btn.on_press(Message::DiscardPhoto);
+            send_btn = send_btn.on_press(Message::SendPhoto);
+        }
+
+        container(
+            column![
+                preview,
+                row![
+                    view_once,
+                    Space::with_width(Length::Fill),
+                    cancel_btn,
+                    Space::with_width(8),
+                    retake_btn,
+                    Space::with_width(8),
+                    send_btn,
+                ]
+                .align_items(Alignment::Center),
+            ]
+            .spacing(8),
+        )
+        .padding(12)
+        .into()
+    }
+
+    fn staging_area(state: &AppState) -> Element<'static, Message> {
+        let uploading = state.attachment_upload.is_some();
+
+        let items: Vec<Element<'static, Message>> = state
+            .staged_files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| Self::staged_item(index, file, uploading))
+            .collect();
+
+        let files = scrollable(Row::with_children(items).spacing(8))
+            .direction(scrollable::Direction::Horizontal(
+                scrollable::Properties::default(),
+            ))
+            .width(Length::Fill);
+
+        let total_size: i64 = state.staged_files.iter().map(|f| f.file_size).sum();
+        let summary = text(format!(
+            "{} file(s), {}",
+            state.staged_files.len(),
+            AppState::format_file_size(total_size)
+        ))
+        .size(12);
+
+        let mut caption = text_input("Add a caption...", &state.attachment_caption)
+            .padding(12)
+            .width(Length::Fill);
+        let mut cancel_btn = button(text("Cancel").size(12)).padding(10);
+        let mut send_btn = button(text("Send").size(12)).padding(10);
+        if !uploading {
+            caption = caption
+                .on_input(Message::AttachmentCaptionChanged)
+                .on_submit(Message::SendAttachments);
+            cancel_btn = cancel_btn.on_press(Message::ClearStagedFiles);
+            send_btn = send_btn.on_press(Message::SendAttachments);
+        }
+
+        let mut content = column![
+            files,
+            summary,
+            row![cancel_btn, Space::with_width(8), caption, Space::with_width(8), send_btn]
+                .align_items(Alignment::Center),
+        ]
+        .spacing(8);
+
+        // Combined progress across the whole batch
+        if let Some(ref upload) = state.attachment_upload {
+            content = content.push(
+                row![
+                    progress_bar(0.0..=1.0, upload.progress()).height(6),
+                    Space::with_width(8),
+                    text(format!("{:.0}%", upload.progress() * 100.0)).size(12),
+                ]
+                .align_items(Alignment::Center),
+            );
+        }
+
+        container(content).padding(12).into()
+    }
+
+    fn staged_item(index: usize, file: &StagedAttachment, uploading: bool) -> Element<'static, Message> {
+        let preview: Element<'static, Message> = if file.is_image() {
+            image(image::Handle::from_path(&file.path))
+                .width(64)
+                .height(64)
+                .into()
+        } else {
+            container(text("File").size(14))
+                .width(64)
+                .height(64)
+                .center_x()
+                .center_y()
+                .into()
+        };
+
+        let mut remove_btn = button(text("x").size(12)).padding([2, 6]);
+        if !uploading {
+            remove_btn = remove_btn.on_press(Message::RemoveStagedFile(index));
+        }
+
+        let name: String = if file.file_name.chars().count() > 14 {
+            format!("{}...", file.file_name.chars().take(12).collect::<String>())
+        } else {
+            file.file_name.clone()
+        };
+
+        container(
+            column![
+                row![preview, remove_btn].spacing(2),
+                text(name).size(11),
+                text(AppState::format_file_size(file.file_size)).size(10),
+            ]
+            .spacing(2)
+            .width(90),
+        )
+        .padding(4)
+        .into()
+    }
+}
+
+/// Days shown in the messages-per-day chart
+const CHART_DAYS: usize = 14;
+const CHART_HEIGHT: f32 = 48.0;
+
+/// A row of bars scaled to the largest value
+fn bar_chart(values: &[u64], bar_width: f32) -> Element<'static, Message> {
+    let max = values.iter().copied().max().unwrap_or(0).max(1) as f32;
+    Row::with_children(values.iter().map(|&value| {
+        let height = if value == 0 { 1.0 } else { (value as f32 / max * CHART_HEIGHT).max(2.0) };
+        container(Space::new(bar_width, height))
+            .style(iced::theme::Container::Box)
+            .into()
+    }))
+    .spacing(2)
+    .height(CHART_HEIGHT)
+    .align_items(Alignment::End)
+    .into()
+}
+
+/// A median reply time, e.g. "3 min" or "2 h 10 min"
+fn reply_label(seconds: i64) -> String {
+    let minutes = seconds / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, 0) => "under a minute".to_string(),
+        (0, m) => format!("{} min", m),
+        (h, 0) => format!("{} h", h),
+        (h, m) => format!("{} h {} min", h, m),
+    }
 }
@@ -0,0 +1,102 @@
+//! Diagnostics screen for PrivMsg Desktop
+
+use crate::diagnostics::CheckStatus;
+use crate::messages::Message;
+use crate::state::AppState;
+use iced::widget::{button, column, container, row, scrollable, text, Space};
+use iced::{Alignment, Color, Element, Length};
+
+pub struct DiagnosticsScreen;
+
+impl DiagnosticsScreen {
+    pub fn view(state: &AppState) -> Element<'static, Message> {
+        let header = row![
+            button(text("<").size(20))
+                .padding([8, 14])
+                .on_press(Message::GoBack),
+            Space::with_width(16),
+            text("Diagnostics").size(24),
+        ]
+        .padding(16)
+        .align_items(Alignment::Center);
+
+        let actions = row![
+            button(text(if state.diagnostics_running { "Running..." } else { "Run again" }).size(14))
+                .padding([8, 16])
+                .on_press_maybe((!state.diagnostics_running).then_some(Message::RunDiagnostics)),
+            Space::with_width(8),
+            button(text("Copy report").size(14))
+                .padding([8, 16])
+                .on_press_maybe(state.diagnostics.is_some().then_some(Message::CopyDiagnostics)),
+        ];
+
+        let mut checks = column![].spacing(10);
+        if let Some(report) = &state.diagnostics {
+            for check in &report.checks {
+                let color = match check.status {
+                    CheckStatus::Ok => Color::from_rgb(0.3, 0.7, 0.4),
+                    CheckStatus::Warning => Color::from_rgb(0.9, 0.6, 0.2),
+                    CheckStatus::Failed => Color::from_rgb(0.9, 0.3, 0.3),
+                    CheckStatus::Skipped => Color::from_rgb(0.5, 0.5, 0.5),
+                };
+                checks = checks.push(
+                    row![
+                        text(check.status.as_str())
+                            .size(13)
+                            .width(Length::Fixed(70.0))
+                            .style(iced::theme::Text::Color(color)),
+                        column![
+                            text(check.name).size(14),
+                            text(check.detail.clone()).size(12),
+                        ]
+                        .spacing(2)
+                        .width(Length::Fill),
+                        text(format!("{} ms", check.duration_ms)).size(12),
+                    ]
+                    .spacing(8)
+                    .align_items(Alignment::Center),
+                );
+            }
+        } else if state.diagnostics_running {
+            checks = checks.push(text("Checking...").size(14));
+        }
+
+        let mut flags = column![text("Feature flags").size(16)].spacing(6);
+        for flag in state.feature_flags() {
+            flags = flags.push(
+                row![
+                    text(if flag.enabled { "on" } else { "off" })
+                        .size(13)
+                        .width(Length::Fixed(70.0)),
+                    text(flag.flag.name()).size(14).width(Length::Fill),
+                    text(flag.source.as_str()).size(12),
+                ]
+                .spacing(8)
+                .align_items(Alignment::Center),
+            );
+        }
+
+        let content = column![
+            header,
+            container(
+                column![
+                    text("Attach the copied report when filing an issue. It contains no messages, keys or tokens.")
+                        .size(12),
+                    actions,
+                    Space::with_height(12),
+                    scrollable(column![checks, Space::with_height(16), flags]).height(Length::Fill),
+                ]
+                .spacing(12)
+                .padding(20)
+                .max_width(600),
+            )
+            .width(Length::Fill)
+            .center_x(),
+        ];
+
+        container(content)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+}
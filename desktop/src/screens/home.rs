@@ -1,11 +1,12 @@
 //! Home screen with conversation list for PrivMsg Desktop
 
 use crate::messages::Message;
-use crate::state::{AppState, Conversation};
+use crate::state::{AppState, Conversation, ListViewport, CONVERSATION_ROW_HEIGHT};
 use iced::widget::{
-    button, column, container, row, scrollable, text, text_input, Space, Column,
+    button, checkbox, column, container, row, scrollable, text, text_input, Space, Column,
 };
 use iced::{Alignment, Element, Length};
+use privmsg_proto::preview_text;
 
 pub struct HomeScreen;
 
@@ -36,8 +37,16 @@ impl HomeScreen {
     }
 
     fn header(state: &AppState) -> Element<'static, Message> {
+        if state.selecting_conversations {
+            return Self::selection_bar(state);
+        }
+
         let title = text("Chats").size(24);
 
+        let select_btn = button(text("Select").size(14))
+            .padding(8)
+            .on_press_maybe((!state.conversations.is_empty()).then_some(Message::ToggleSelectionMode));
+
         let search_btn = button(text("Search").size(14))
             .padding(8)
             .on_press(Message::ToggleSearch);
@@ -57,6 +66,8 @@ impl HomeScreen {
             Space::with_width(Length::Fill),
             user_info,
             Space::with_width(10),
+            select_btn,
+            Space::with_width(5),
             search_btn,
             Space::with_width(5),
             settings_btn,
@@ -66,6 +77,39 @@ impl HomeScreen {
         .into()
     }
 
+    /// Replaces the header while conversations are being selected
+    fn selection_bar(state: &AppState) -> Element<'static, Message> {
+        let count = state.selected_conversations.len();
+        let action = |label: &str, message: Message| {
+            button(text(label).size(14))
+                .padding(8)
+                .on_press_maybe((count > 0).then_some(message))
+        };
+        let all_label = if count == state.conversations.len() { "None" } else { "All" };
+
+        row![
+            text(format!("{} selected", count)).size(18),
+            Space::with_width(Length::Fill),
+            button(text(all_label).size(14))
+                .padding(8)
+                .on_press(Message::SelectAllConversations),
+            action("Mark read", Message::MarkSelectedRead),
+            action("Mute", Message::MuteSelected(true)),
+            action("Unmute", Message::MuteSelected(false)),
+            action("Archive", Message::ArchiveSelected(true)),
+            action("Unarchive", Message::ArchiveSelected(false)),
+            action("Export", Message::ExportSelected),
+            action("Delete", Message::DeleteSelected),
+            button(text("Done").size(14))
+                .padding(8)
+                .on_press(Message::ToggleSelectionMode),
+        ]
+        .spacing(5)
+        .padding(16)
+        .align_items(Alignment::Center)
+        .into()
+    }
+
     fn search_bar(state: &AppState) -> Element<'static, Message> {
         let input = text_input("Search user by ID...", &state.search_query)
             .on_input(Message::SearchQueryChanged)
@@ -136,22 +180,39 @@ impl HomeScreen {
             .into();
         }
 
-        let list: Vec<Element<'static, Message>> = state
-            .conversations
-            .iter()
-            .map(|conv| Self::conversation_item(conv))
-            .collect();
-
-        scrollable(
-            Column::with_children(list)
-                .spacing(1)
-                .width(Length::Fill),
-        )
-        .height(Length::Fill)
-        .into()
+        // Only build widgets for rows near the viewport; spacers stand in
+        // for the rest so the scrollbar still reflects the full list
+        let count = state.conversations.len();
+        let range = state.conversation_viewport.visible_range(count);
+        let above = range.start as f32 * CONVERSATION_ROW_HEIGHT;
+        let below = (count - range.end) as f32 * CONVERSATION_ROW_HEIGHT;
+
+        let mut list: Vec<Element<'static, Message>> = Vec::with_capacity(range.len() + 2);
+        list.push(Space::with_height(above).into());
+        list.extend(
+            state.conversations[range].iter().map(|conv| {
+                let selected = state
+                    .selecting_conversations
+                    .then(|| state.selected_conversations.contains(&conv.peer_id));
+                Self::conversation_item(conv, selected)
+            }),
+        );
+        list.push(Space::with_height(below).into());
+
+        scrollable(Column::with_children(list).width(Length::Fill))
+            .height(Length::Fill)
+            .on_scroll(|viewport| {
+                Message::ConversationListScrolled(ListViewport {
+                    offset_y: viewport.absolute_offset().y,
+                    height: viewport.bounds().height,
+                })
+            })
+            .into()
     }
 
-    fn conversation_item(conv: &Conversation) -> Element<'static, Message> {
+    /// `selected` is set while selecting, in which case a click toggles the
+    /// row's selection instead of opening the chat
+    fn conversation_item(conv: &Conversation, selected: Option<bool>) -> Element<'static, Message> {
         let name = conv.peer_name.as_deref().unwrap_or(&conv.peer_id);
         let first_char = name.chars().next().unwrap_or('?').to_uppercase().to_string();
 
@@ -169,14 +230,15 @@ impl HomeScreen {
 
         // Name and last message
         let last_msg = conv.last_message.as_deref().unwrap_or("");
-        let last_msg_preview = if last_msg.len() > 40 {
-            format!("{}...", &last_msg[..37])
+        let last_msg_preview = preview_text(last_msg, 40).into_owned();
+
+        let title = if conv.is_archived {
+            format!("{} (archived)", name)
         } else {
-            last_msg.to_string()
+            name.to_string()
         };
-
         let text_column = column![
-            text(name).size(16),
+            text(title).size(16),
             text(last_msg_preview).size(13),
         ]
         .spacing(4);
@@ -205,21 +267,38 @@ impl HomeScreen {
             column![text(&time_text).size(12),].align_items(Alignment::End)
         };
 
-        let content = row![
-            avatar,
-            Space::with_width(12),
-            text_column,
-            Space::with_width(Length::Fill),
-            time_column,
-        ]
-        .align_items(Alignment::Center)
-        .padding(12);
+        let mut content = row![].align_items(Alignment::Center).padding(12);
+        if let Some(selected) = selected {
+            let peer_id = conv.peer_id.clone();
+            content = content
+                .push(checkbox("", selected).on_toggle(move |_| {
+                    Message::ToggleConversationSelected(peer_id.clone())
+                }))
+                .push(Space::with_width(8));
+        }
+        let content = content
+            .push(avatar)
+            .push(Space::with_width(12))
+            .push(text_column)
+            .push(Space::with_width(Length::Fill))
+            .push(time_column);
+
+        let on_press = match selected {
+            Some(_) => Message::ToggleConversationSelected(conv.peer_id.clone()),
+            None => Message::OpenChat(conv.peer_id.clone()),
+        };
 
-        button(content)
-            .width(Length::Fill)
-            .padding(0)
-            .on_press(Message::OpenChat(conv.peer_id.clone()))
-            .into()
+        // Fixed height keeps row positions computable for virtualization
+        container(
+            button(content)
+                .width(Length::Fill)
+                .height(Length::Fill)
+                .padding(0)
+                .on_press(on_press),
+        )
+        .height(CONVERSATION_ROW_HEIGHT)
+        .padding([0, 0, 1, 0])
+        .into()
     }
 }
 
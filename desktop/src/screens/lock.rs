@@ -0,0 +1,48 @@
+//! Lock screen for PrivMsg Desktop
+
+use crate::messages::Message;
+use crate::state::AppState;
+use iced::widget::{button, column, container, text, text_input, Space};
+use iced::{Alignment, Element, Length};
+
+pub struct LockScreen;
+
+impl LockScreen {
+    pub fn view(state: &AppState) -> Element<'static, Message> {
+        let form = column![
+            text_input("Passcode", &state.unlock_input)
+                .on_input(Message::UnlockInputChanged)
+                .on_submit(Message::Unlock)
+                .padding(12)
+                .secure(true),
+            button(
+                text("Unlock")
+                    .horizontal_alignment(iced::alignment::Horizontal::Center),
+            )
+            .width(Length::Fill)
+            .padding(14)
+            .on_press(Message::Unlock),
+        ]
+        .spacing(10)
+        .max_width(300);
+
+        let content = column![
+            Space::with_height(Length::FillPortion(1)),
+            text("PrivMsg is locked").size(24),
+            text("Enter your passcode to continue").size(14),
+            Space::with_height(20),
+            form,
+            Space::with_height(Length::FillPortion(1)),
+        ]
+        .align_items(Alignment::Center)
+        .spacing(10)
+        .padding(40);
+
+        container(content)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .into()
+    }
+}
@@ -18,7 +18,7 @@ impl LoginScreen {
             .size(16);
 
         // Server settings
-        let server_section = column![
+        let mut server_section = column![
             text("Server").size(14),
             row![
                 text_input("Server address", &state.config.server.host)
@@ -35,6 +35,27 @@ impl LoginScreen {
                 .on_toggle(Message::UseTlsChanged),
         ]
         .spacing(8);
+        if !state.config.server.use_tls {
+            let warning = if state.config.server.is_loopback() {
+                "Without TLS your login can be read by anything on this computer."
+            } else {
+                "Connections without TLS are only allowed to this computer."
+            };
+            server_section = server_section.push(
+                text(warning)
+                    .size(12)
+                    .style(iced::theme::Text::Color(iced::Color::from_rgb(0.9, 0.3, 0.3))),
+            );
+            if state.config.server.is_loopback() {
+                server_section = server_section.push(
+                    checkbox(
+                        "Allow unencrypted connections to this computer",
+                        state.config.security.allow_insecure_localhost,
+                    )
+                    .on_toggle(Message::AllowInsecureLocalhostChanged),
+                );
+            }
+        }
 
         // Credentials
         let credentials_section = column![
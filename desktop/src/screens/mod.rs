@@ -2,6 +2,9 @@
 
 pub mod call;
 pub mod chat;
+pub mod diagnostics;
 pub mod home;
+pub mod lock;
 pub mod login;
+pub mod rules;
 pub mod settings;
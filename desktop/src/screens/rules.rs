@@ -0,0 +1,144 @@
+//! Message rules editor for PrivMsg Desktop
+
+use crate::messages::Message;
+use crate::state::{AppState, RULE_MESSAGE_TYPES};
+use iced::widget::{button, checkbox, column, container, pick_list, row, scrollable, text, text_input, Space};
+use iced::{Alignment, Element, Length};
+use privmsg_proto::rules::{MessageRule, RuleAction};
+
+/// Label of the message type picker's "no condition" entry
+const ANY_TYPE: &str = "any";
+
+pub struct RulesScreen;
+
+impl RulesScreen {
+    pub fn view(state: &AppState) -> Element<'static, Message> {
+        let header = row![
+            button(text("<").size(20))
+                .padding([8, 14])
+                .on_press(Message::GoBack),
+            Space::with_width(16),
+            text("Message Rules").size(24),
+        ]
+        .padding(16)
+        .align_items(Alignment::Center);
+
+        let mut rules = column![].spacing(10);
+        if state.message_rules.is_empty() {
+            rules = rules.push(text("No rules yet").size(14));
+        }
+        for rule in &state.message_rules {
+            rules = rules.push(Self::rule_row(rule));
+        }
+
+        let content = column![
+            header,
+            container(
+                column![
+                    text("Rules act on incoming messages in this order. A message can match several rules.")
+                        .size(12),
+                    scrollable(rules).height(Length::FillPortion(1)),
+                    Space::with_height(12),
+                    Self::editor(state),
+                ]
+                .spacing(12)
+                .padding(20)
+                .max_width(600),
+            )
+            .width(Length::Fill)
+            .center_x(),
+        ];
+
+        container(content)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+
+    fn rule_row(rule: &MessageRule) -> Element<'static, Message> {
+        let mut conditions = Vec::new();
+        if let Some(ref sender_id) = rule.sender_id {
+            conditions.push(format!("from {}", sender_id));
+        }
+        if let Some(ref keyword) = rule.keyword {
+            conditions.push(format!("containing \"{}\"", keyword));
+        }
+        if let Some(ref message_type) = rule.message_type {
+            conditions.push(format!("of type {}", message_type));
+        }
+        let actions: Vec<&str> = rule.actions.iter().map(|a| a.label()).collect();
+
+        let id = rule.id.clone();
+        row![
+            checkbox("", rule.enabled).on_toggle(move |on| Message::RuleEnabledToggled(id.clone(), on)),
+            column![
+                text(rule.name.clone()).size(14),
+                text(format!("{}: {}", conditions.join(", "), actions.join(", "))).size(12),
+            ]
+            .spacing(2)
+            .width(Length::Fill),
+            button(text("Edit").size(12))
+                .padding([6, 12])
+                .on_press(Message::EditRule(rule.id.clone())),
+            button(text("Delete").size(12))
+                .padding([6, 12])
+                .on_press(Message::DeleteRule(rule.id.clone())),
+        ]
+        .spacing(8)
+        .align_items(Alignment::Center)
+        .into()
+    }
+
+    fn editor(state: &AppState) -> Element<'static, Message> {
+        let draft = &state.rule_draft;
+        let title = if draft.id.is_some() { "Edit rule" } else { "New rule" };
+
+        let types: Vec<&'static str> = std::iter::once(ANY_TYPE).chain(RULE_MESSAGE_TYPES).collect();
+        let type_picker = pick_list(types, Some(draft.message_type.unwrap_or(ANY_TYPE)), |t| {
+            Message::RuleTypeSelected((t != ANY_TYPE).then_some(t))
+        })
+        .width(Length::Fixed(120.0));
+
+        let mut actions = row![].spacing(12);
+        for action in RuleAction::ALL {
+            actions = actions.push(
+                checkbox(action.label(), draft.actions.contains(&action))
+                    .on_toggle(move |on| Message::RuleActionToggled(action, on)),
+            );
+        }
+
+        let cancel = draft.id.is_some().then(|| {
+            button(text("Cancel").size(14))
+                .padding([8, 16])
+                .on_press(Message::NewRule)
+        });
+
+        column![
+            text(title).size(18),
+            text_input("Rule name", &draft.name)
+                .on_input(Message::RuleNameChanged)
+                .padding(10),
+            text("Match messages").size(14),
+            text_input("From user ID (any sender if empty)", &draft.sender_id)
+                .on_input(Message::RuleSenderChanged)
+                .padding(10),
+            text_input("Containing keyword (any text if empty)", &draft.keyword)
+                .on_input(Message::RuleKeywordChanged)
+                .padding(10),
+            row![text("Of type:").size(14), Space::with_width(12), type_picker]
+                .align_items(Alignment::Center),
+            text("Then").size(14),
+            actions,
+            text("Highlighted messages notify even in muted chats").size(12),
+            row![
+                button(text("Save rule").size(14))
+                    .padding([8, 16])
+                    .on_press_maybe(draft.to_rule().map(|_| Message::SaveRule)),
+                Space::with_width(8),
+            ]
+            .push_maybe(cancel),
+        ]
+        .spacing(8)
+        .into()
+    }
+}
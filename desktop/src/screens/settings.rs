@@ -1,15 +1,59 @@
 //! Settings screen for PrivMsg Desktop
 
+use crate::clipboard::ClipboardClear;
 use crate::messages::Message;
-use crate::state::AppState;
+use crate::state::{timer_label, AppState, PANIC_CONFIRM_WORD};
+use privmsg_proto::notification::PreviewPolicy;
+use privmsg_proto::policy::{Feature, PolicySetting};
+use privmsg_proto::LastSeenVisibility;
+use crate::wallpaper::Wallpaper;
 use iced::widget::{
-    button, checkbox, column, container, pick_list, row, text, Space,
+    button, checkbox, column, container, pick_list, progress_bar, row, text, text_input, Row, Space,
 };
 use iced::{Alignment, Element, Length};
 
 pub struct SettingsScreen;
 
 impl SettingsScreen {
+    /// Presets, an image from disk and a reset, for one conversation or,
+    /// without a peer id, every chat
+    pub fn wallpaper_picker(peer_id: Option<&str>, current: Option<&Wallpaper>) -> Element<'static, Message> {
+        let peer_id = peer_id.map(str::to_string);
+        let mut choices = Row::new().spacing(6).align_items(Alignment::Center);
+        for (name, wallpaper) in Wallpaper::presets() {
+            let label = if current == Some(&wallpaper) {
+                format!("* {}", name)
+            } else {
+                name.to_string()
+            };
+            choices = choices.push(
+                button(text(label).size(12))
+                    .padding([4, 8])
+                    .on_press(Message::SetWallpaper(peer_id.clone(), Some(wallpaper))),
+            );
+        }
+        let image_label = if matches!(current, Some(Wallpaper::Image { .. })) {
+            "* Image..."
+        } else {
+            "Image..."
+        };
+        let reset_label = if peer_id.is_some() { "Use default" } else { "Default" };
+        column![
+            choices,
+            row![
+                button(text(image_label).size(12))
+                    .padding([4, 8])
+                    .on_press(Message::PickWallpaperImage(peer_id.clone())),
+                button(text(reset_label).size(12))
+                    .padding([4, 8])
+                    .on_press_maybe(current.map(|_| Message::SetWallpaper(peer_id, None))),
+            ]
+            .spacing(6),
+        ]
+        .spacing(6)
+        .into()
+    }
+
     pub fn view(state: &AppState) -> Element<'static, Message> {
         // Header
         let header = row![
@@ -44,6 +88,272 @@ impl SettingsScreen {
             column![]
         };
 
+        // Devices section
+        let current_device = state.session.as_ref().map(|s| s.device_id.as_str());
+        let mut devices_section = column![text("Devices").size(18), Space::with_height(12)].spacing(8);
+        for device in &state.devices {
+            let this_device = current_device == Some(device.device_id.as_str());
+            let name: Element<'static, Message> = match state.device_rename {
+                Some((ref id, ref input)) if *id == device.device_id => row![
+                    text_input("Device name", input)
+                        .on_input(Message::DeviceNameChanged)
+                        .on_submit(Message::SaveDeviceName)
+                        .padding(6)
+                        .width(Length::Fixed(220.0)),
+                    Space::with_width(8),
+                    button(text("Save").size(13))
+                        .padding([4, 10])
+                        .on_press(Message::SaveDeviceName),
+                    Space::with_width(4),
+                    button(text("Cancel").size(13))
+                        .padding([4, 10])
+                        .on_press(Message::CancelRenameDevice),
+                ]
+                .align_items(Alignment::Center)
+                .into(),
+                _ => row![
+                    text(if this_device {
+                        format!("{} (this device)", device.device_name)
+                    } else {
+                        device.device_name.clone()
+                    })
+                    .size(14),
+                    Space::with_width(8),
+                    button(text("Rename").size(12))
+                        .padding([2, 8])
+                        .on_press(Message::StartRenameDevice(device.device_id.clone())),
+                ]
+                .align_items(Alignment::Center)
+                .into(),
+            };
+            devices_section = devices_section.push(
+                row![
+                    text(device.icon()).size(20),
+                    Space::with_width(10),
+                    column![
+                        name,
+                        text(format!("Last active {}", device.last_active_at)).size(12),
+                    ]
+                    .spacing(2),
+                ]
+                .align_items(Alignment::Center),
+            );
+        }
+        let devices_section = devices_section.push(Space::with_height(20));
+
+        // Profile section
+        let profile_section = column![
+            text("Profile").size(18),
+            Space::with_height(12),
+            row![
+                text_input("🙂", &state.status_emoji_input)
+                    .on_input(Message::StatusEmojiChanged)
+                    .padding(8)
+                    .width(Length::Fixed(60.0)),
+                Space::with_width(8),
+                text_input("What's your status?", &state.status_text_input)
+                    .on_input(Message::StatusTextChanged)
+                    .padding(8)
+                    .width(Length::Fixed(300.0)),
+            ],
+            text_input("Bio", &state.bio_input)
+                .on_input(Message::BioChanged)
+                .on_submit(Message::SaveProfile)
+                .padding(8)
+                .width(Length::Fixed(368.0)),
+            button(text("Save profile").size(14))
+                .padding([8, 16])
+                .on_press(Message::SaveProfile),
+            Space::with_height(20),
+        ]
+        .spacing(8);
+
+        // Privacy section
+        let visibilities = vec![
+            LastSeenVisibility::Everyone,
+            LastSeenVisibility::Contacts,
+            LastSeenVisibility::Nobody,
+        ];
+        let last_seen: Element<'static, Message> =
+            if state.is_locked(PolicySetting::LastSeenVisibility) {
+                row![
+                    text(state.last_seen_visibility.to_string()).size(14),
+                    Space::with_width(12),
+                    locked_note(),
+                ]
+                .align_items(Alignment::Center)
+                .into()
+            } else {
+                pick_list(
+                    visibilities,
+                    Some(state.last_seen_visibility),
+                    Message::LastSeenVisibilityChanged,
+                )
+                .width(Length::Fixed(150.0))
+                .into()
+            };
+        let privacy_section = column![
+            text("Privacy").size(18),
+            Space::with_height(12),
+            row![
+                text("Show last seen to:").size(14),
+                Space::with_width(12),
+                last_seen,
+            ]
+            .align_items(Alignment::Center),
+            text("Contacts are people you have exchanged messages with").size(12),
+            checkbox("Show conversation statistics", state.config.analytics.enabled)
+                .on_toggle(Message::AnalyticsChanged),
+            text("Computed on this computer from message times; nothing is sent anywhere").size(12),
+            Space::with_height(20),
+        ]
+        .spacing(8);
+
+        // Security section
+        let security = &state.config.security;
+        let passcode_row = if security.passcode_hash.is_some() {
+            row![
+                button(text("Lock now").size(14))
+                    .padding([8, 16])
+                    .on_press(Message::LockApp),
+                Space::with_width(8),
+                button(text("Remove passcode").size(14))
+                    .padding([8, 16])
+                    .on_press(Message::RemovePasscode),
+            ]
+        } else {
+            row![
+                text_input("New passcode", &state.passcode_input)
+                    .on_input(Message::PasscodeInputChanged)
+                    .on_submit(Message::SetPasscode)
+                    .padding(8)
+                    .secure(true)
+                    .width(Length::Fixed(200.0)),
+                Space::with_width(8),
+                button(text("Set passcode").size(14))
+                    .padding([8, 16])
+                    .on_press(Message::SetPasscode),
+            ]
+        };
+        let lock_on_suspend = checkbox(
+            "Lock when the computer sleeps or is locked",
+            security.lock_on_suspend,
+        );
+        let lock_on_suspend = if security.passcode_hash.is_some() {
+            lock_on_suspend.on_toggle(Message::LockOnSuspendChanged)
+        } else {
+            lock_on_suspend
+        };
+        let security_section = column![
+            text("Security").size(18),
+            Space::with_height(12),
+            text("App lock").size(14),
+            passcode_row.align_items(Alignment::Center),
+            lock_on_suspend,
+            row![
+                text("Clear copied messages from clipboard:").size(14),
+                Space::with_width(12),
+                pick_list(
+                    ClipboardClear::OPTIONS,
+                    Some(ClipboardClear(security.clipboard_clear_secs)),
+                    Message::ClipboardClearChanged
+                )
+                .width(Length::Fixed(170.0)),
+            ]
+            .align_items(Alignment::Center),
+            Space::with_height(20),
+        ]
+        .spacing(8);
+
+        // Backups section
+        let backup_config = &state.config.backup;
+        let backup_dir = backup_config.directory(&state.data_dir);
+        let mut backup_list = column![].spacing(4);
+        for entry in &state.backups {
+            backup_list = backup_list.push(
+                row![
+                    text(AppState::format_timestamp(entry.created_at)).size(13),
+                    Space::with_width(8),
+                    text(format!("{:.1} MB", entry.size as f64 / (1024.0 * 1024.0))).size(13),
+                    Space::with_width(Length::Fill),
+                    button(text("Restore").size(13))
+                        .padding([4, 10])
+                        .on_press_maybe(
+                            (!state.backup_running)
+                                .then(|| Message::RestoreBackup(entry.path.clone())),
+                        ),
+                ]
+                .align_items(Alignment::Center)
+                .width(Length::Fixed(368.0)),
+            );
+        }
+        let backup_section = column![
+            text("Backups").size(18),
+            Space::with_height(12),
+            checkbox("Back up daily", backup_config.enabled)
+                .on_toggle(Message::AutoBackupChanged),
+            row![
+                text("Keep:").size(14),
+                Space::with_width(12),
+                pick_list(
+                    vec![3usize, 7, 14, 30],
+                    Some(backup_config.keep),
+                    Message::BackupKeepChanged
+                )
+                .width(Length::Fixed(80.0)),
+                Space::with_width(8),
+                text("backups").size(14),
+            ]
+            .align_items(Alignment::Center),
+            row![
+                text(backup_dir.to_string_lossy().into_owned()).size(13),
+                Space::with_width(8),
+                button(text("Change...").size(13))
+                    .padding([4, 10])
+                    .on_press(Message::ChooseBackupDir),
+            ]
+            .align_items(Alignment::Center),
+            button(text(if state.backup_running { "Working..." } else { "Back up now" }).size(14))
+                .padding([8, 16])
+                .on_press_maybe((!state.backup_running).then_some(Message::BackupNow)),
+            text(state.backup_status.clone().unwrap_or_default()).size(12),
+            backup_list,
+            Space::with_height(20),
+        ]
+        .spacing(8);
+
+        // Your data section
+        let data_section = column![
+            text("Your data").size(18),
+            Space::with_height(12),
+            text("Save your profile, devices and all chats stored on this computer, decrypted, as a JSON file")
+                .size(12),
+        ]
+        .spacing(8);
+        let data_section = match state.data_export {
+            Some(ref progress) => {
+                let (done, total) = progress.counts();
+                data_section.push(
+                    row![
+                        progress_bar(0.0..=1.0, progress.fraction()).width(Length::Fixed(200.0)),
+                        Space::with_width(8),
+                        text(format!("{} of {} chats", done, total)).size(12),
+                        Space::with_width(8),
+                        button(text("Cancel").size(13))
+                            .padding([4, 10])
+                            .on_press(Message::CancelDataExport),
+                    ]
+                    .align_items(Alignment::Center),
+                )
+            }
+            None => data_section.push(
+                button(text("Download my data").size(14))
+                    .padding([8, 16])
+                    .on_press(Message::DownloadMyData),
+            ),
+        }
+        .push(Space::with_height(20));
+
         // Appearance section
         let themes: Vec<String> = vec!["dark".to_string(), "light".to_string()];
         let current_theme = state.config.ui.theme.clone();
@@ -58,11 +368,23 @@ impl SettingsScreen {
                     .width(Length::Fixed(150.0)),
             ]
             .align_items(Alignment::Center),
+            text("Chat wallpaper:").size(14),
+            Self::wallpaper_picker(None, state.config.ui.wallpaper.as_ref()),
             Space::with_height(20),
         ]
         .spacing(8);
 
         // Notifications section
+        let preview_locked = state.is_locked(PolicySetting::NotificationPreview);
+        let preview = checkbox(
+            "Show message text in notifications",
+            state.preview_policy() == PreviewPolicy::Full,
+        );
+        let preview = if preview_locked {
+            preview
+        } else {
+            preview.on_toggle(Message::NotificationPreviewChanged)
+        };
         let notifications_section = column![
             text("Notifications").size(18),
             Space::with_height(12),
@@ -70,6 +392,60 @@ impl SettingsScreen {
                 .on_toggle(Message::NotificationsChanged),
             checkbox("Notification sounds", state.config.notifications.sound)
                 .on_toggle(Message::SoundChanged),
+            row![preview, Space::with_width(12)]
+                .push_maybe(preview_locked.then(locked_note))
+                .align_items(Alignment::Center),
+            row![
+                button(text("Message rules").size(14))
+                    .padding([8, 16])
+                    .on_press(Message::OpenRules),
+                Space::with_width(12),
+                text(format!("{} active", state.message_rules.iter().filter(|r| r.enabled).count()))
+                    .size(12),
+            ]
+            .align_items(Alignment::Center),
+            Space::with_height(20),
+        ]
+        .spacing(8);
+
+        // Messaging section
+        let messaging_section = column![
+            text("Messaging").size(18),
+            Space::with_height(12),
+            row![
+                text("Retry failed messages automatically:").size(14),
+                Space::with_width(12),
+                pick_list(
+                    vec![0u32, 1, 2, 3, 5],
+                    Some(state.config.delivery.auto_retries),
+                    Message::AutoRetriesChanged
+                )
+                .width(Length::Fixed(70.0)),
+                Space::with_width(8),
+                text("times").size(14),
+            ]
+            .align_items(Alignment::Center),
+            text("Tap the ! on a message that still fails to resend or delete it").size(12),
+        ]
+        .spacing(8);
+        let disappearing = state.client_policy.as_ref().and_then(|p| p.disappearing_seconds);
+        let messaging_section = messaging_section
+            .push_maybe(disappearing.map(|seconds| {
+                row![
+                    text(format!("New chats disappear after {}", timer_label(seconds))).size(14),
+                    Space::with_width(12),
+                    locked_note(),
+                ]
+                .align_items(Alignment::Center)
+            }))
+            .push(Space::with_height(20));
+
+        // Startup section
+        let startup_section = column![
+            text("Startup").size(18),
+            Space::with_height(12),
+            checkbox("Start PrivMsg when I log in", state.config.integration.start_on_login)
+                .on_toggle(Message::StartOnLoginChanged),
             Space::with_height(20),
         ]
         .spacing(8);
@@ -86,7 +462,10 @@ impl SettingsScreen {
                     state.config.server.host, state.config.server.port
                 ))
                 .size(14),
-            ],
+                Space::with_width(12),
+            ]
+            .push_maybe(state.is_locked(PolicySetting::Endpoints).then(locked_note))
+            .align_items(Alignment::Center),
             row![
                 text("TLS:").size(14),
                 Space::with_width(8),
@@ -97,9 +476,21 @@ impl SettingsScreen {
                 })
                 .size(14),
             ],
-            Space::with_height(20),
         ]
         .spacing(8);
+        let disabled: Vec<&str> = [
+            (Feature::Calls, "calls"),
+            (Feature::FileTransfer, "sending files"),
+        ]
+        .into_iter()
+        .filter(|(feature, _)| !state.allows(*feature))
+        .map(|(_, name)| name)
+        .collect();
+        let server_section = server_section
+            .push_maybe((!disabled.is_empty()).then(|| {
+                text(format!("Your organization turned off {}", disabled.join(" and "))).size(12)
+            }))
+            .push(Space::with_height(20));
 
         // About section
         let about_section = column![
@@ -110,10 +501,53 @@ impl SettingsScreen {
                 Space::with_width(8),
                 text(env!("CARGO_PKG_VERSION")).size(14),
             ],
+            button(text("Diagnostics").size(14))
+                .padding([8, 16])
+                .on_press(Message::OpenDiagnostics),
             Space::with_height(20),
         ]
         .spacing(8);
 
+        // Panic button, armed by typing the confirmation word
+        let panic_section = column![
+            text("Panic").size(18),
+            Space::with_height(12),
+            text("Sign out on every device, erase everything stored on this computer and quit")
+                .size(12),
+        ]
+        .spacing(8);
+        let panic_section = match state.panic {
+            Some(ref panic) => panic_section
+                .push(
+                    checkbox("Save my identity key to a file first", panic.keep_identity_key)
+                        .on_toggle(Message::PanicKeepKeyToggled),
+                )
+                .push(text(format!("Type {} to confirm", PANIC_CONFIRM_WORD)).size(12))
+                .push(
+                    row![
+                        text_input(PANIC_CONFIRM_WORD, &panic.input)
+                            .on_input(Message::PanicConfirmChanged)
+                            .padding(6)
+                            .width(Length::Fixed(120.0)),
+                        Space::with_width(8),
+                        button(text(if panic.running { "Wiping..." } else { "Wipe and quit" }).size(13))
+                            .padding([4, 10])
+                            .on_press_maybe(panic.armed().then_some(Message::PanicWipe)),
+                        Space::with_width(4),
+                        button(text("Cancel").size(13))
+                            .padding([4, 10])
+                            .on_press_maybe((!panic.running).then_some(Message::CancelPanic)),
+                    ]
+                    .align_items(Alignment::Center),
+                ),
+            None => panic_section.push(
+                button(text("Wipe this device...").size(14))
+                    .padding([8, 16])
+                    .on_press(Message::ShowPanic),
+            ),
+        }
+        .push(Space::with_height(20));
+
         // Logout button
         let logout_section = column![
             Space::with_height(20),
@@ -134,10 +568,19 @@ impl SettingsScreen {
             container(
                 column![
                     user_section,
+                    devices_section,
+                    profile_section,
+                    privacy_section,
+                    security_section,
+                    backup_section,
+                    data_section,
                     appearance_section,
                     notifications_section,
+                    messaging_section,
+                    startup_section,
                     server_section,
                     about_section,
+                    panic_section,
                     logout_section,
                 ]
                 .padding(20)
@@ -153,3 +596,8 @@ impl SettingsScreen {
             .into()
     }
 }
+
+/// Shown next to settings the server's client policy locks
+fn locked_note() -> Element<'static, Message> {
+    text("Set by your organization").size(12).into()
+}
@@ -1,8 +1,28 @@
 //! Application state management
 
+use crate::backup::BackupEntry;
+use crate::camera::CapturedPhoto;
 use crate::config::AppConfig;
+use crate::data_export::ExportProgress;
+use crate::deeplink::DeepLink;
+use crate::diagnostics::DiagnosticsReport;
+use crate::message_store::{MessageStore, StoredMessage};
+use crate::power::PowerSource;
+use crate::wallpaper::Wallpaper;
 use serde::{Deserialize, Serialize};
+use crate::network::TransferControl;
+use privmsg_proto::announcement::Announcement;
+use privmsg_proto::capabilities::ServerCapabilities;
+use privmsg_proto::notification::PreviewPolicy;
+use privmsg_proto::flags::{self, FlagState};
+use privmsg_proto::policy::{ClientPolicy, Feature, PolicySetting};
+use privmsg_proto::quote::Quote;
+use privmsg_proto::stats::ConversationStats;
+use privmsg_proto::rules::{MessageRule, RuleAction};
+use privmsg_proto::LastSeenVisibility;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Screen {
@@ -10,6 +30,8 @@ pub enum Screen {
     Home,
     Chat(String), // peer_id
     Settings,
+    Diagnostics,
+    Rules,
     Call(String), // peer_id
 }
 
@@ -30,6 +52,72 @@ pub enum MessageType {
     Video,
     Image,
     File,
+    /// A conversation event shown as a notice, see `SystemEvent`
+    System,
+}
+
+impl MessageType {
+    /// Name on the wire and in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageType::Text => "text",
+            MessageType::Voice => "voice",
+            MessageType::Video => "video",
+            MessageType::Image => "image",
+            MessageType::File => "file",
+            MessageType::System => "system",
+        }
+    }
+}
+
+/// Something that happened in a conversation. Stored as data rather than
+/// text so the notice can be worded at display time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SystemEvent {
+    KeyChanged { user_id: String },
+    /// `seconds` of `None` turns disappearing messages off
+    DisappearingTimerChanged { changed_by: String, seconds: Option<u64> },
+    MemberAdded { added_by: String, member_id: String },
+    CallMissed { caller_id: String, is_video: bool },
+}
+
+/// "1 day", "5 minutes" and the like
+pub fn timer_label(seconds: u64) -> String {
+    let (count, unit) = [(604_800, "week"), (86_400, "day"), (3_600, "hour"), (60, "minute")]
+        .into_iter()
+        .find(|(unit_secs, _)| seconds >= *unit_secs && seconds % unit_secs == 0)
+        .map(|(unit_secs, unit)| (seconds / unit_secs, unit))
+        .unwrap_or((seconds, "second"));
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+impl SystemEvent {
+    /// The notice text, with user ids turned into names by `name`
+    pub fn describe(&self, name: impl Fn(&str) -> String) -> String {
+        match self {
+            SystemEvent::KeyChanged { user_id } => {
+                format!("The security key of {} changed", name(user_id))
+            }
+            SystemEvent::DisappearingTimerChanged { changed_by, seconds: Some(seconds) } => format!(
+                "{} set disappearing messages to {}",
+                name(changed_by),
+                timer_label(*seconds)
+            ),
+            SystemEvent::DisappearingTimerChanged { changed_by, seconds: None } => {
+                format!("{} turned off disappearing messages", name(changed_by))
+            }
+            SystemEvent::MemberAdded { added_by, member_id } => {
+                format!("{} added {}", name(added_by), name(member_id))
+            }
+            SystemEvent::CallMissed { caller_id, is_video: false } => {
+                format!("Missed call from {}", name(caller_id))
+            }
+            SystemEvent::CallMissed { caller_id, is_video: true } => {
+                format!("Missed video call from {}", name(caller_id))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -48,6 +136,9 @@ pub struct AuthSession {
     pub device_id: String,
     pub user_id: String,
     pub expires_at: i64,
+    /// The server's service notice at login; not stored
+    #[serde(default)]
+    pub announcement: Option<Announcement>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,6 +148,35 @@ pub struct User {
     pub avatar_file_id: Option<String>,
     pub public_key: Option<String>,
     pub last_seen_at: Option<i64>,
+    #[serde(default)]
+    pub status_emoji: Option<String>,
+    #[serde(default)]
+    pub status_text: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    /// Only known for the current user
+    #[serde(default)]
+    pub last_seen_visibility: Option<LastSeenVisibility>,
+}
+
+impl User {
+    /// Status emoji and text as one line, if either is set
+    pub fn status_line(&self) -> Option<String> {
+        let parts: Vec<&str> = [&self.status_emoji, &self.status_text]
+            .into_iter()
+            .filter_map(|part| part.as_deref())
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(" "))
+    }
+
+    /// Users may hide when they were last online, so a missing time
+    /// reads as "recently" rather than "never"
+    pub fn last_seen_text(&self) -> String {
+        match self.last_seen_at {
+            Some(at) => format!("last seen {}", AppState::format_timestamp(at)),
+            None => "last seen recently".to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,6 +190,40 @@ pub struct Conversation {
     pub unread_count: i32,
     pub is_muted: bool,
     pub is_pinned: bool,
+    /// Listed below the others, e.g. by a message rule
+    #[serde(default)]
+    pub is_archived: bool,
+}
+
+/// One of the user's signed-in devices, as the server lists them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    pub device_name: String,
+    pub device_type: String,
+    /// Server time, e.g. `2024-01-31 09:30:00`
+    pub created_at: String,
+    pub last_active_at: String,
+}
+
+impl Device {
+    /// Icon for the kind of device, by its `device_type`
+    pub fn icon(&self) -> &'static str {
+        match self.device_type.to_ascii_lowercase().as_str() {
+            "android" | "ios" | "phone" => "📱",
+            "tablet" | "ipad" => "📲",
+            "web" | "browser" => "🌐",
+            "windows" | "linux" | "macos" | "desktop" => "💻",
+            _ => "🖥",
+        }
+    }
+}
+
+/// A conversation and its messages, oldest first, as written by a chat export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationExport {
+    pub conversation: Conversation,
+    pub messages: Vec<ChatMessage>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -82,7 +236,135 @@ pub struct ChatMessage {
     pub timestamp: i64,
     pub status: MessageStatus,
     pub attachment: Option<Attachment>,
+    /// Text shown beneath a media attachment
+    #[serde(default)]
+    pub caption: Option<String>,
     pub is_outgoing: bool,
+    /// Set on `MessageType::System` messages
+    #[serde(default)]
+    pub system_event: Option<SystemEvent>,
+    /// Device the message was sent from, when the sender said
+    #[serde(default)]
+    pub sender_device_id: Option<String>,
+    /// The message this one replies to
+    #[serde(default)]
+    pub quote: Option<Quote>,
+    /// Marked by the user to find again; never sent
+    #[serde(default)]
+    pub is_starred: bool,
+}
+
+/// How a message sent from this device was delivered. Messages only go
+/// out over the WebSocket; there is no REST fallback.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryInfo {
+    /// Attempts so far, counting retries and resends
+    pub attempts: u32,
+    /// Unix millis of the last attempt that went out
+    pub sent_at: Option<i64>,
+    /// Unix millis the server acknowledged it
+    pub acked_at: Option<i64>,
+}
+
+/// Word typed to arm the panic button
+pub const PANIC_CONFIRM_WORD: &str = "WIPE";
+
+/// The panic button's confirmation step
+#[derive(Debug, Clone, Default)]
+pub struct PanicConfirm {
+    /// Save the identity key to a file before wiping
+    pub keep_identity_key: bool,
+    /// Must read `PANIC_CONFIRM_WORD` before the wipe can start
+    pub input: String,
+    /// Wipe in progress
+    pub running: bool,
+}
+
+impl PanicConfirm {
+    pub fn armed(&self) -> bool {
+        !self.running && self.input.trim() == PANIC_CONFIRM_WORD
+    }
+}
+
+impl ChatMessage {
+    /// A new text message, not sent yet
+    pub fn outgoing_text(recipient_id: &str, sender_id: &str, text: &str) -> Self {
+        Self {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            conversation_id: recipient_id.to_string(),
+            sender_id: sender_id.to_string(),
+            message_type: MessageType::Text,
+            content: text.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            status: MessageStatus::Pending,
+            attachment: None,
+            caption: None,
+            is_outgoing: true,
+            system_event: None,
+            sender_device_id: None,
+            quote: None,
+            is_starred: false,
+        }
+    }
+
+    /// How many emoji the text is, when it should render large and without
+    /// a bubble
+    pub fn jumbo_emoji_count(&self) -> Option<usize> {
+        match self.message_type {
+            MessageType::Text => privmsg_proto::emoji::jumbo_emoji_count(&self.content),
+            _ => None,
+        }
+    }
+
+    /// Where the server serves the attachment's encrypted file, without
+    /// which key it can't be read
+    pub fn attachment_link(&self, server_url: &str) -> Option<String> {
+        self.attachment
+            .as_ref()
+            .map(|a| format!("{}/api/v1/files/{}", server_url, a.file_id))
+    }
+
+    /// A quote of this message to reply with; see [`Quote::sent_in`]
+    pub fn to_quote(&self) -> Quote {
+        let text = match self.message_type {
+            MessageType::Text => &self.content,
+            _ => self.caption.as_ref().unwrap_or(&self.content),
+        };
+        Quote {
+            conversation_id: Some(self.conversation_id.clone()),
+            ..Quote::new(&self.message_id, &self.sender_id, text)
+        }
+    }
+
+    /// A local notice about `event`; never sent
+    pub fn system(conversation_id: &str, event: SystemEvent) -> Self {
+        Self {
+            message_id: uuid::Uuid::new_v4().to_string(),
+            conversation_id: conversation_id.to_string(),
+            sender_id: String::new(),
+            message_type: MessageType::System,
+            content: event.describe(|id| id.to_string()),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            status: MessageStatus::Read,
+            attachment: None,
+            caption: None,
+            is_outgoing: false,
+            system_event: Some(event),
+            sender_device_id: None,
+            quote: None,
+            is_starred: false,
+        }
+    }
+}
+
+impl StoredMessage for ChatMessage {
+    fn id(&self) -> &str {
+        &self.message_id
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -96,6 +378,230 @@ pub struct Attachment {
     pub height: Option<i32>,
     pub encryption_key: Option<String>,
     pub local_path: Option<String>,
+    /// Voice message amplitude buckets (0-255)
+    #[serde(default)]
+    pub waveform: Option<Vec<u8>>,
+    /// Photo the recipient can open once; the sender keeps no key for it
+    #[serde(default)]
+    pub view_once: bool,
+    /// When the server drops the file (Unix seconds), if known
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+impl Attachment {
+    /// Whether the server has dropped the file, so downloading it fails
+    /// until the sender uploads it again
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|at| at <= chrono::Utc::now().timestamp())
+    }
+}
+
+/// Playback position of the voice message last played or scrubbed
+#[derive(Debug, Clone)]
+pub struct VoicePlayback {
+    pub message_id: String,
+    pub duration_ms: i64,
+    /// Position as a fraction of the duration
+    pub position: f32,
+    /// Wall clock millis and position when playback last (re)started
+    pub playing_since: Option<(i64, f32)>,
+}
+
+impl VoicePlayback {
+    pub fn is_playing(&self) -> bool {
+        self.playing_since.is_some()
+    }
+
+    /// Advance `position` based on time spent playing
+    pub fn advance(&mut self, now_ms: i64) {
+        if let Some((started_at, start_position)) = self.playing_since {
+            if self.duration_ms > 0 {
+                let elapsed = (now_ms - started_at) as f32 / self.duration_ms as f32;
+                self.position = (start_position + elapsed).min(1.0);
+            }
+        }
+    }
+}
+
+/// A webcam snapshot being taken, previewed or sent from a chat composer
+#[derive(Debug, Clone)]
+pub struct PhotoCapture {
+    pub peer_id: String,
+    /// None while the camera is still taking the picture
+    pub photo: Option<CapturedPhoto>,
+    pub view_once: bool,
+    pub sending: bool,
+}
+
+/// Message types a rule can be limited to, by wire name
+pub const RULE_MESSAGE_TYPES: [&str; 5] = ["text", "image", "voice", "video", "file"];
+
+/// The rule being written or edited on the rules screen
+#[derive(Debug, Clone)]
+pub struct RuleDraft {
+    /// Set while editing a saved rule
+    pub id: Option<String>,
+    pub name: String,
+    pub enabled: bool,
+    pub sender_id: String,
+    pub keyword: String,
+    pub message_type: Option<&'static str>,
+    pub actions: Vec<RuleAction>,
+}
+
+impl Default for RuleDraft {
+    fn default() -> Self {
+        Self {
+            id: None,
+            name: String::new(),
+            enabled: true,
+            sender_id: String::new(),
+            keyword: String::new(),
+            message_type: None,
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl RuleDraft {
+    pub fn edit(rule: &MessageRule) -> Self {
+        Self {
+            id: Some(rule.id.clone()),
+            name: rule.name.clone(),
+            enabled: rule.enabled,
+            sender_id: rule.sender_id.clone().unwrap_or_default(),
+            keyword: rule.keyword.clone().unwrap_or_default(),
+            message_type: RULE_MESSAGE_TYPES
+                .into_iter()
+                .find(|t| rule.message_type.as_deref() == Some(*t)),
+            actions: rule.actions.clone(),
+        }
+    }
+
+    /// The rule to save; `None` until it has a name, a condition and an action
+    pub fn to_rule(&self) -> Option<MessageRule> {
+        let field = |value: &str| Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        let rule = MessageRule {
+            id: self
+                .id
+                .clone()
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            name: field(&self.name)?,
+            enabled: self.enabled,
+            sender_id: field(&self.sender_id),
+            keyword: field(&self.keyword),
+            message_type: self.message_type.map(str::to_string),
+            actions: self.actions.clone(),
+        };
+        (rule.has_conditions() && !rule.actions.is_empty()).then_some(rule)
+    }
+}
+
+/// A file picked in the composer but not yet sent
+#[derive(Debug, Clone)]
+pub struct StagedAttachment {
+    pub path: PathBuf,
+    pub file_name: String,
+    pub file_size: i64,
+    pub mime_type: String,
+}
+
+impl StagedAttachment {
+    pub fn from_path(path: PathBuf) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(&path)?;
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("file")
+            .to_string();
+        let mime_type = mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .to_string();
+
+        Ok(Self {
+            path,
+            file_name,
+            file_size: metadata.len() as i64,
+            mime_type,
+        })
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.mime_type.starts_with("image/")
+    }
+}
+
+/// Progress of a staged batch being uploaded
+#[derive(Debug, Clone)]
+pub struct AttachmentUpload {
+    pub batch_id: String,
+    pub peer_id: String,
+    pub total_bytes: i64,
+    pub uploaded_bytes: i64,
+    /// Uploaded attachments, indexed like `AppState::staged_files`
+    pub uploaded: Vec<Option<Attachment>>,
+}
+
+impl AttachmentUpload {
+    pub fn is_complete(&self) -> bool {
+        self.uploaded.iter().all(Option::is_some)
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.total_bytes == 0 {
+            return if self.is_complete() { 1.0 } else { 0.0 };
+        }
+        self.uploaded_bytes as f32 / self.total_bytes as f32
+    }
+}
+
+/// An attachment being saved to disk, with pause/resume/cancel controls
+#[derive(Debug, Clone)]
+pub struct FileDownload {
+    pub attachment: Attachment,
+    pub dest: PathBuf,
+    pub control: Arc<TransferControl>,
+    pub paused: bool,
+}
+
+/// Messages rendered per page of the chat view; older pages are shown on request
+pub const CHAT_PAGE_SIZE: usize = 100;
+
+/// Height of one row in the conversation list, including the 1px divider
+pub const CONVERSATION_ROW_HEIGHT: f32 = 73.0;
+
+/// Rows rendered beyond each edge of the viewport so fast scrolling doesn't flash blank
+const CONVERSATION_OVERSCAN: usize = 4;
+
+/// Scroll position of the conversation list, reported by the scrollable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListViewport {
+    pub offset_y: f32,
+    pub height: f32,
+}
+
+impl Default for ListViewport {
+    fn default() -> Self {
+        // Until the first scroll event, assume a tall window
+        Self {
+            offset_y: 0.0,
+            height: 1200.0,
+        }
+    }
+}
+
+impl ListViewport {
+    /// Indices of the rows that need widgets out of `count` rows
+    pub fn visible_range(&self, count: usize) -> std::ops::Range<usize> {
+        let first = (self.offset_y.max(0.0) / CONVERSATION_ROW_HEIGHT) as usize;
+        let visible = (self.height.max(0.0) / CONVERSATION_ROW_HEIGHT).ceil() as usize + 1;
+
+        let start = first.saturating_sub(CONVERSATION_OVERSCAN).min(count);
+        let end = (first + visible + CONVERSATION_OVERSCAN).min(count);
+        start..end
+    }
 }
 
 pub struct AppState {
@@ -110,10 +616,20 @@ pub struct AppState {
     pub session: Option<AuthSession>,
     pub login_user_id: String,
     pub login_access_key: String,
+    /// Link opened before sign-in, acted on after LoginSuccess
+    pub pending_link: Option<DeepLink>,
 
     // Data
     pub conversations: Vec<Conversation>,
-    pub current_messages: Vec<ChatMessage>,
+    /// Bumped by each full load, so pages of an older one are dropped
+    pub conversation_load: u64,
+    pub conversation_viewport: ListViewport,
+    /// Multi-select mode in the conversation list, with the selected peer ids
+    pub selecting_conversations: bool,
+    pub selected_conversations: HashSet<String>,
+    pub current_messages: MessageStore<ChatMessage>,
+    /// Number of newest messages the chat view renders
+    pub chat_window: usize,
     pub current_chat_peer: Option<String>,
 
     // Search
@@ -121,11 +637,56 @@ pub struct AppState {
     pub search_query: String,
     pub found_user: Option<User>,
 
+    // Profiles
+    /// Peer profiles, filled from the local cache and refreshed on opening a chat
+    pub profiles: HashMap<String, User>,
+    /// Unix seconds each peer's profile was last requested by the refresh job
+    /// or on receipt, so conversation titles stay current
+    pub profiles_refreshed: HashMap<String, i64>,
+    /// Conversations' own wallpapers by peer id
+    pub conversation_wallpapers: HashMap<String, Wallpaper>,
+    /// Peers deactivated or deleted on the server; their chats are read-only
+    pub unavailable_peers: HashSet<String>,
+    /// Peers whose changed security key the user hasn't accepted yet
+    pub key_conflicts: HashSet<String>,
+    pub show_chat_details: bool,
+    /// Statistics of the open chat, shown in details when analytics are on
+    pub conversation_stats: Option<ConversationStats>,
+    pub status_emoji_input: String,
+    pub status_text_input: String,
+    pub bio_input: String,
+    pub last_seen_visibility: LastSeenVisibility,
+    /// The user's signed-in devices, listed when settings open
+    pub devices: Vec<Device>,
+    /// Device being renamed and the name typed so far
+    pub device_rename: Option<(String, String)>,
+
     // Messaging
     pub message_input: String,
+    /// Quote the next message sent from the composer replies to
+    pub reply_quote: Option<Quote>,
+    /// Failed message whose resend/delete actions are shown
+    pub failed_actions: Option<String>,
+    /// Message shown in the info panel, with its delivery if sent from here
+    pub message_info: Option<(ChatMessage, Option<DeliveryInfo>)>,
+    /// Messages waiting for a conversation to be forwarded to, oldest first
+    pub forwarding: Vec<ChatMessage>,
+    /// Whether the open chat shows checkboxes to pick messages
+    pub selecting_messages: bool,
+    /// Messages picked in the open chat, by id
+    pub selected_messages: HashSet<String>,
     pub is_recording_voice: bool,
     pub recording_start_time: Option<i64>,
-    pub selected_file: Option<PathBuf>,
+    pub voice_playback: Option<VoicePlayback>,
+    pub staged_files: Vec<StagedAttachment>,
+    pub attachment_caption: String,
+    pub attachment_upload: Option<AttachmentUpload>,
+    pub photo_capture: Option<PhotoCapture>,
+    pub downloads: Vec<FileDownload>,
+    /// Running "download my data" export
+    pub data_export: Option<Arc<ExportProgress>>,
+    /// Panic button confirmation, while shown in settings
+    pub panic: Option<PanicConfirm>,
 
     // Calls
     pub call_state: Option<CallState>,
@@ -137,6 +698,35 @@ pub struct AppState {
     pub call_start_time: Option<i64>,
     pub call_duration: Option<i64>,
 
+    // App lock
+    /// Everything is hidden behind the passcode prompt
+    pub locked: bool,
+    pub unlock_input: String,
+    pub passcode_input: String,
+
+    // Backups
+    /// Newest first; listed when settings open
+    pub backups: Vec<BackupEntry>,
+    pub backup_running: bool,
+    pub backup_status: Option<String>,
+
+    // Diagnostics
+    pub diagnostics: Option<DiagnosticsReport>,
+    pub diagnostics_running: bool,
+
+    // Message rules
+    pub message_rules: Vec<MessageRule>,
+    pub rule_draft: RuleDraft,
+
+    /// The server operator's verified client policy
+    pub client_policy: Option<ClientPolicy>,
+    /// Features the server has turned on, fetched at login
+    pub capabilities: ServerCapabilities,
+    /// The server's service notice, unless the user dismissed it
+    pub announcement: Option<Announcement>,
+    /// Background work is cut back on battery
+    pub power_source: PowerSource,
+
     // UI State
     pub is_loading: bool,
     pub error: Option<String>,
@@ -144,6 +734,7 @@ pub struct AppState {
 
 impl AppState {
     pub fn new(data_dir: PathBuf, config: AppConfig, initial_screen: Screen) -> Self {
+        let locked = config.security.passcode_hash.is_some();
         Self {
             data_dir,
             config,
@@ -151,16 +742,48 @@ impl AppState {
             session: None,
             login_user_id: String::new(),
             login_access_key: String::new(),
+            pending_link: None,
             conversations: Vec::new(),
-            current_messages: Vec::new(),
+            conversation_load: 0,
+            conversation_viewport: ListViewport::default(),
+            selecting_conversations: false,
+            selected_conversations: HashSet::new(),
+            current_messages: MessageStore::new(),
+            chat_window: CHAT_PAGE_SIZE,
             current_chat_peer: None,
             show_search: false,
             search_query: String::new(),
             found_user: None,
+            profiles: HashMap::new(),
+            profiles_refreshed: HashMap::new(),
+            conversation_wallpapers: HashMap::new(),
+            unavailable_peers: HashSet::new(),
+            key_conflicts: HashSet::new(),
+            show_chat_details: false,
+            conversation_stats: None,
+            status_emoji_input: String::new(),
+            status_text_input: String::new(),
+            bio_input: String::new(),
+            last_seen_visibility: LastSeenVisibility::default(),
+            devices: Vec::new(),
+            device_rename: None,
             message_input: String::new(),
+            reply_quote: None,
+            failed_actions: None,
+            message_info: None,
+            forwarding: Vec::new(),
+            selecting_messages: false,
+            selected_messages: HashSet::new(),
             is_recording_voice: false,
             recording_start_time: None,
-            selected_file: None,
+            voice_playback: None,
+            staged_files: Vec::new(),
+            attachment_caption: String::new(),
+            attachment_upload: None,
+            photo_capture: None,
+            downloads: Vec::new(),
+            data_export: None,
+            panic: None,
             call_state: None,
             call_id: None,
             call_peer_id: None,
@@ -169,11 +792,130 @@ impl AppState {
             call_video_enabled: true,
             call_start_time: None,
             call_duration: None,
+            locked,
+            unlock_input: String::new(),
+            passcode_input: String::new(),
+            backups: Vec::new(),
+            backup_running: false,
+            backup_status: None,
+            diagnostics: None,
+            diagnostics_running: false,
+            message_rules: Vec::new(),
+            rule_draft: RuleDraft::default(),
+            client_policy: None,
+            capabilities: ServerCapabilities::default(),
+            announcement: None,
+            power_source: PowerSource::default(),
             is_loading: false,
             error: None,
         }
     }
 
+    /// Whether the client policy keeps the user from changing `setting`
+    pub fn is_locked(&self, setting: PolicySetting) -> bool {
+        self.client_policy.as_ref().is_some_and(|p| p.is_locked(setting))
+    }
+
+    /// Whether the client policy leaves `feature` on
+    pub fn allows(&self, feature: Feature) -> bool {
+        self.client_policy.as_ref().is_none_or(|p| p.allows(feature))
+    }
+
+    /// Feature flags from the config, `PRIVMSG_FLAGS` and the client policy
+    pub fn feature_flags(&self) -> Vec<FlagState> {
+        let policy = self
+            .client_policy
+            .as_ref()
+            .map(|p| flags::from_names(&p.flags))
+            .unwrap_or_default();
+        flags::resolve(&flags::from_names(&self.config.flags), &flags::from_env(), &policy)
+    }
+
+    /// Whether the chat with `peer_id` keeps its history but can't be
+    /// written to
+    pub fn is_read_only(&self, peer_id: &str) -> bool {
+        self.unavailable_peers.contains(peer_id)
+    }
+
+    /// The background of `peer_id`'s chat: its own or the global one
+    pub fn wallpaper(&self, peer_id: &str) -> Option<&Wallpaper> {
+        self.conversation_wallpapers
+            .get(peer_id)
+            .or(self.config.ui.wallpaper.as_ref())
+    }
+
+    /// Whether the server has what `feature` needs
+    pub fn supports(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Calls => self.capabilities.calls,
+            Feature::FileTransfer => {
+                self.capabilities.file_upload && self.capabilities.maintenance.is_none()
+            }
+            _ => true,
+        }
+    }
+
+    /// Why `feature` can't be used, completing a sentence such as
+    /// "Calls ...", or `None` when it can
+    pub fn unavailable(&self, feature: Feature) -> Option<&'static str> {
+        if !self.allows(feature) {
+            Some("turned off by your organization")
+        } else if matches!(feature, Feature::FileTransfer) && self.capabilities.maintenance.is_some() {
+            Some("paused during server maintenance")
+        } else if !self.supports(feature) {
+            Some("not available on this server")
+        } else {
+            None
+        }
+    }
+
+    pub fn can_use(&self, feature: Feature) -> bool {
+        self.unavailable(feature).is_none()
+    }
+
+    /// How much notifications reveal: the policy's choice while it locks
+    /// previews, the user's otherwise
+    pub fn preview_policy(&self) -> PreviewPolicy {
+        match self.client_policy.as_ref() {
+            Some(policy) if policy.is_locked(PolicySetting::NotificationPreview) => policy
+                .notification_preview
+                .unwrap_or_else(|| self.config.notifications.preview_policy()),
+            _ => self.config.notifications.preview_policy(),
+        }
+    }
+
+    pub fn download(&mut self, file_id: &str) -> Option<&mut FileDownload> {
+        self.downloads
+            .iter_mut()
+            .find(|d| d.attachment.file_id == file_id)
+    }
+
+    /// Insert or replace a conversation at its sorted position (pinned first,
+    /// then most recent) without re-sorting or reloading the whole list
+    pub fn place_conversation(&mut self, conv: Conversation) {
+        if let Some(idx) = self.conversations.iter().position(|c| c.peer_id == conv.peer_id) {
+            self.conversations.remove(idx);
+        }
+
+        let key = |c: &Conversation| {
+            (c.is_archived, !c.is_pinned, std::cmp::Reverse(c.last_message_time))
+        };
+        let idx = self
+            .conversations
+            .partition_point(|c| key(c) <= key(&conv));
+        self.conversations.insert(idx, conv);
+    }
+
+    /// Outgoing messages in the open chat that are still pending or failed
+    pub fn pending_outgoing_count(&self) -> usize {
+        self.current_messages
+            .iter()
+            .filter(|m| {
+                m.is_outgoing && matches!(m.status, MessageStatus::Pending | MessageStatus::Failed)
+            })
+            .count()
+    }
+
     pub fn total_unread(&self) -> Option<i32> {
         let total: i32 = self.conversations.iter().map(|c| c.unread_count).sum();
         if total > 0 {
@@ -21,6 +21,15 @@ pub struct Theme {
 }
 
 impl Theme {
+    /// The theme `[ui] theme` names; light unless it is "dark"
+    pub fn named(name: &str) -> Self {
+        if name == "dark" {
+            Self::dark()
+        } else {
+            Self::light()
+        }
+    }
+
     pub fn dark() -> Self {
         Self {
             background: Color::from_rgb(0.11, 0.11, 0.12),      // #1c1c1e
@@ -0,0 +1,151 @@
+//! Chat backgrounds
+//!
+//! One wallpaper in `[ui]` applies to every chat; a conversation can
+//! override it. Bubbles get a solid background over a wallpaper, with text
+//! picked to contrast with it.
+
+use iced::gradient::Linear;
+use iced::{Background, Color, Radians};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Wallpaper {
+    /// `#rrggbb`
+    Color { color: String },
+    /// Top to bottom, `#rrggbb` each
+    Gradient { from: String, to: String },
+    /// Scaled to cover the chat; `dark` is measured when it is picked
+    Image { path: PathBuf, dark: bool },
+}
+
+impl Wallpaper {
+    /// Colors and gradients offered in settings
+    pub fn presets() -> Vec<(&'static str, Wallpaper)> {
+        let color = |c: &str| Wallpaper::Color { color: c.to_string() };
+        let gradient = |from: &str, to: &str| Wallpaper::Gradient {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        vec![
+            ("Slate", color("#2f3b4c")),
+            ("Sand", color("#e9dfc9")),
+            ("Forest", color("#1f3d2b")),
+            ("Dusk", gradient("#2b1d4e", "#d16b5c")),
+            ("Ocean", gradient("#0f3057", "#00a6a6")),
+            ("Dawn", gradient("#fde2e4", "#cde3f5")),
+        ]
+    }
+
+    /// An image wallpaper from a copy of `source` in `dir`, so it survives
+    /// the original being moved. Blocks while the image is decoded.
+    pub fn import(source: &Path, dir: &Path) -> Result<Self, String> {
+        let img = image::open(source).map_err(|e| e.to_string())?;
+        let thumb = img.thumbnail(32, 32).to_rgb8();
+        let pixels = thumb.pixels().len().max(1) as f32;
+        let luminance = thumb
+            .pixels()
+            .map(|p| luminance(Color::from_rgb8(p[0], p[1], p[2])))
+            .sum::<f32>()
+            / pixels;
+
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let extension = source.extension().and_then(|e| e.to_str()).unwrap_or("img");
+        let path = dir.join(format!("{}.{}", uuid::Uuid::new_v4(), extension));
+        std::fs::copy(source, &path).map_err(|e| e.to_string())?;
+        Ok(Wallpaper::Image {
+            path,
+            dark: luminance < 0.5,
+        })
+    }
+
+    /// Color and gradient wallpapers; images are drawn by
+    /// [`crate::widgets::backdrop`]
+    pub fn background(&self) -> Option<Background> {
+        match self {
+            Wallpaper::Color { color } => parse_hex(color).map(Background::Color),
+            Wallpaper::Gradient { from, to } => {
+                let (from, to) = (parse_hex(from)?, parse_hex(to)?);
+                Some(
+                    Linear::new(Radians(std::f32::consts::PI))
+                        .add_stop(0.0, from)
+                        .add_stop(1.0, to)
+                        .into(),
+                )
+            }
+            Wallpaper::Image { .. } => None,
+        }
+    }
+
+    pub fn is_dark(&self) -> bool {
+        match self {
+            Wallpaper::Color { color } => parse_hex(color).is_some_and(|c| luminance(c) < 0.5),
+            Wallpaper::Gradient { from, to } => match (parse_hex(from), parse_hex(to)) {
+                (Some(from), Some(to)) => (luminance(from) + luminance(to)) / 2.0 < 0.5,
+                _ => false,
+            },
+            Wallpaper::Image { dark, .. } => *dark,
+        }
+    }
+
+    /// For text drawn straight on the wallpaper, like system notices
+    pub fn text_color(&self) -> Color {
+        if self.is_dark() {
+            Color::WHITE
+        } else {
+            Color::BLACK
+        }
+    }
+}
+
+/// Black or white, whichever reads better on `background`
+pub fn contrasting_text(background: Color) -> Color {
+    if luminance(background) < 0.5 {
+        Color::WHITE
+    } else {
+        Color::BLACK
+    }
+}
+
+/// Perceived brightness from 0 (black) to 1 (white)
+fn luminance(color: Color) -> f32 {
+    0.299 * color.r + 0.587 * color.g + 0.114 * color.b
+}
+
+/// `#rrggbb`, with or without the `#`
+pub fn parse_hex(hex: &str) -> Option<Color> {
+    let hex = hex.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Color::from_rgb8(channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wallpaper_contrast() {
+        assert_eq!(parse_hex("#ffffff"), Some(Color::WHITE));
+        assert_eq!(parse_hex("000000"), Some(Color::BLACK));
+        assert_eq!(parse_hex("#fff"), None);
+        assert_eq!(parse_hex("#gg0000"), None);
+
+        let presets = Wallpaper::presets();
+        let text = |name: &str| presets.iter().find(|(n, _)| *n == name).unwrap().1.text_color();
+        assert_eq!(text("Slate"), Color::WHITE);
+        assert_eq!(text("Sand"), Color::BLACK);
+        assert_eq!(text("Dusk"), Color::WHITE);
+        assert_eq!(text("Dawn"), Color::BLACK);
+        assert!(presets.iter().all(|(_, w)| w.background().is_some()));
+
+        assert_eq!(contrasting_text(Color::from_rgb(0.0, 0.48, 1.0)), Color::WHITE);
+        assert_eq!(contrasting_text(Color::from_rgb(0.9, 0.9, 0.92)), Color::BLACK);
+
+        let json = serde_json::to_string(&presets[3].1).unwrap();
+        assert_eq!(serde_json::from_str::<Wallpaper>(&json).unwrap(), presets[3].1);
+    }
+}
@@ -0,0 +1,155 @@
+//! An image drawn behind other content, scaled to cover it
+
+use crate::messages::Message;
+use iced::advanced::image::{self as image_renderer, FilterMethod};
+use iced::advanced::layout::{self, Layout};
+use iced::advanced::renderer;
+use iced::advanced::widget::{Operation, Tree, Widget};
+use iced::advanced::{overlay, Clipboard, Shell};
+use iced::widget::image::Handle;
+use iced::{event, mouse, Element, Event, Length, Rectangle, Renderer, Size, Theme, Vector};
+
+pub struct Backdrop<'a> {
+    image: Handle,
+    content: Element<'a, Message>,
+}
+
+impl<'a> Backdrop<'a> {
+    pub fn new(image: Handle, content: impl Into<Element<'a, Message>>) -> Self {
+        Self {
+            image,
+            content: content.into(),
+        }
+    }
+}
+
+/// `image` scaled to fill `bounds` without distortion, centred; the parts
+/// that overflow are clipped by the layer
+fn cover(image: Size<u32>, bounds: Rectangle) -> Rectangle {
+    if image.width == 0 || image.height == 0 {
+        return bounds;
+    }
+    let scale = (bounds.width / image.width as f32).max(bounds.height / image.height as f32);
+    let size = Size::new(image.width as f32 * scale, image.height as f32 * scale);
+    Rectangle {
+        x: bounds.x + (bounds.width - size.width) / 2.0,
+        y: bounds.y + (bounds.height - size.height) / 2.0,
+        width: size.width,
+        height: size.height,
+    }
+}
+
+impl<'a> Widget<Message, Theme, Renderer> for Backdrop<'a> {
+    fn size(&self) -> Size<Length> {
+        self.content.as_widget().size()
+    }
+
+    fn children(&self) -> Vec<Tree> {
+        vec![Tree::new(&self.content)]
+    }
+
+    fn diff(&self, tree: &mut Tree) {
+        tree.diff_children(std::slice::from_ref(&self.content));
+    }
+
+    fn layout(&self, tree: &mut Tree, renderer: &Renderer, limits: &layout::Limits) -> layout::Node {
+        self.content
+            .as_widget()
+            .layout(&mut tree.children[0], renderer, limits)
+    }
+
+    fn draw(
+        &self,
+        tree: &Tree,
+        renderer: &mut Renderer,
+        theme: &Theme,
+        style: &renderer::Style,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        viewport: &Rectangle,
+    ) {
+        let bounds = layout.bounds();
+        let target = cover(image_renderer::Renderer::dimensions(renderer, &self.image), bounds);
+        // Separate layers keep the image under the content's quads and text
+        renderer::Renderer::with_layer(renderer, bounds, |renderer| {
+            image_renderer::Renderer::draw(renderer, self.image.clone(), FilterMethod::Linear, target);
+        });
+        renderer::Renderer::with_layer(renderer, bounds, |renderer| {
+            self.content.as_widget().draw(
+                &tree.children[0],
+                renderer,
+                theme,
+                style,
+                layout,
+                cursor,
+                viewport,
+            );
+        });
+    }
+
+    fn operate(
+        &self,
+        tree: &mut Tree,
+        layout: Layout<'_>,
+        renderer: &Renderer,
+        operation: &mut dyn Operation<Message>,
+    ) {
+        self.content
+            .as_widget()
+            .operate(&mut tree.children[0], layout, renderer, operation);
+    }
+
+    fn on_event(
+        &mut self,
+        tree: &mut Tree,
+        event: Event,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        renderer: &Renderer,
+        clipboard: &mut dyn Clipboard,
+        shell: &mut Shell<'_, Message>,
+        viewport: &Rectangle,
+    ) -> event::Status {
+        self.content.as_widget_mut().on_event(
+            &mut tree.children[0],
+            event,
+            layout,
+            cursor,
+            renderer,
+            clipboard,
+            shell,
+            viewport,
+        )
+    }
+
+    fn mouse_interaction(
+        &self,
+        tree: &Tree,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        viewport: &Rectangle,
+        renderer: &Renderer,
+    ) -> mouse::Interaction {
+        self.content
+            .as_widget()
+            .mouse_interaction(&tree.children[0], layout, cursor, viewport, renderer)
+    }
+
+    fn overlay<'b>(
+        &'b mut self,
+        tree: &'b mut Tree,
+        layout: Layout<'_>,
+        renderer: &Renderer,
+        translation: Vector,
+    ) -> Option<overlay::Element<'b, Message, Theme, Renderer>> {
+        self.content
+            .as_widget_mut()
+            .overlay(&mut tree.children[0], layout, renderer, translation)
+    }
+}
+
+impl<'a> From<Backdrop<'a>> for Element<'a, Message> {
+    fn from(backdrop: Backdrop<'a>) -> Self {
+        Element::new(backdrop)
+    }
+}
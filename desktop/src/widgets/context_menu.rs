@@ -0,0 +1,469 @@
+//! A menu of actions on a widget, opened by right-click, long-press or the
+//! keyboard
+//!
+//! The keyboard opens it with the Menu key or Shift+F10 while the widget is
+//! focused (clicked, or reached with Tab) or under the pointer. Up/Down,
+//! Home/End pick an item, Enter or Space runs it, Escape closes the menu.
+
+use crate::messages::Message;
+use iced::advanced::layout::{self, Layout};
+use iced::advanced::renderer::{self, Quad};
+use iced::advanced::text::{self as text_renderer, Text};
+use iced::advanced::widget::{operation, tree, Operation, Tree, Widget};
+use iced::advanced::{overlay, Clipboard, Shell};
+use iced::keyboard::{self, key::Named, Key};
+use iced::time::{Duration, Instant};
+use iced::widget::text::{LineHeight, Shaping};
+use iced::{
+    alignment, event, mouse, touch, window, Border, Color, Element, Event, Length, Point, Rectangle,
+    Renderer, Size, Theme, Vector,
+};
+
+/// How long a finger rests on the widget before the menu opens
+const LONG_PRESS: Duration = Duration::from_millis(500);
+/// How far a finger may drift and still count as resting
+const LONG_PRESS_SLOP: f32 = 10.0;
+const MENU_WIDTH: f32 = 180.0;
+const ITEM_HEIGHT: f32 = 28.0;
+const PADDING: f32 = 4.0;
+const TEXT_SIZE: f32 = 14.0;
+
+pub struct ContextMenu<'a> {
+    content: Element<'a, Message>,
+    /// Labels and what they dispatch, in menu order
+    items: Vec<(String, Message)>,
+}
+
+impl<'a> ContextMenu<'a> {
+    pub fn new(content: impl Into<Element<'a, Message>>, items: Vec<(String, Message)>) -> Self {
+        Self {
+            content: content.into(),
+            items,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Top left corner of the open menu
+    open_at: Option<Point>,
+    /// Highlighted item, by keyboard or pointer
+    selected: Option<usize>,
+    focused: bool,
+    /// When and where a finger went down, until it lifts or drifts
+    press: Option<(Instant, Point)>,
+}
+
+impl State {
+    fn open(&mut self, position: Point, selected: Option<usize>) {
+        self.open_at = Some(position);
+        self.selected = selected;
+        self.press = None;
+    }
+
+    fn close(&mut self) {
+        self.open_at = None;
+        self.selected = None;
+    }
+}
+
+impl operation::Focusable for State {
+    fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    fn focus(&mut self) {
+        self.focused = true;
+    }
+
+    fn unfocus(&mut self) {
+        self.focused = false;
+        self.close();
+    }
+}
+
+fn is_menu_key(key: &Key, modifiers: keyboard::Modifiers) -> bool {
+    match key {
+        Key::Named(Named::ContextMenu) => true,
+        Key::Named(Named::F10) => modifiers.shift(),
+        _ => false,
+    }
+}
+
+impl<'a> Widget<Message, Theme, Renderer> for ContextMenu<'a> {
+    fn tag(&self) -> tree::Tag {
+        tree::Tag::of::<State>()
+    }
+
+    fn state(&self) -> tree::State {
+        tree::State::new(State::default())
+    }
+
+    fn size(&self) -> Size<Length> {
+        self.content.as_widget().size()
+    }
+
+    fn children(&self) -> Vec<Tree> {
+        vec![Tree::new(&self.content)]
+    }
+
+    fn diff(&self, tree: &mut Tree) {
+        tree.diff_children(std::slice::from_ref(&self.content));
+    }
+
+    fn layout(&self, tree: &mut Tree, renderer: &Renderer, limits: &layout::Limits) -> layout::Node {
+        self.content
+            .as_widget()
+            .layout(&mut tree.children[0], renderer, limits)
+    }
+
+    fn draw(
+        &self,
+        tree: &Tree,
+        renderer: &mut Renderer,
+        theme: &Theme,
+        style: &renderer::Style,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        viewport: &Rectangle,
+    ) {
+        self.content.as_widget().draw(
+            &tree.children[0],
+            renderer,
+            theme,
+            style,
+            layout,
+            cursor,
+            viewport,
+        );
+
+        // Shows where the keyboard will open the menu
+        if tree.state.downcast_ref::<State>().focused {
+            renderer::Renderer::fill_quad(
+                renderer,
+                Quad {
+                    bounds: layout.bounds(),
+                    border: Border {
+                        color: theme.extended_palette().primary.strong.color,
+                        width: 1.0,
+                        radius: 12.0.into(),
+                    },
+                    ..Quad::default()
+                },
+                Color::TRANSPARENT,
+            );
+        }
+    }
+
+    fn operate(
+        &self,
+        tree: &mut Tree,
+        layout: Layout<'_>,
+        renderer: &Renderer,
+        operation: &mut dyn Operation<Message>,
+    ) {
+        operation.focusable(tree.state.downcast_mut::<State>(), None);
+        self.content
+            .as_widget()
+            .operate(&mut tree.children[0], layout, renderer, operation);
+    }
+
+    fn on_event(
+        &mut self,
+        tree: &mut Tree,
+        event: Event,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        renderer: &Renderer,
+        clipboard: &mut dyn Clipboard,
+        shell: &mut Shell<'_, Message>,
+        viewport: &Rectangle,
+    ) -> event::Status {
+        let bounds = layout.bounds();
+        let state = tree.state.downcast_mut::<State>();
+
+        match event {
+            Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Right)) => {
+                if let Some(position) = cursor.position_over(bounds) {
+                    state.focused = true;
+                    state.open(position, None);
+                    return event::Status::Captured;
+                }
+            }
+            Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left)) => {
+                state.focused = cursor.is_over(bounds);
+            }
+            Event::Keyboard(keyboard::Event::KeyPressed { ref key, modifiers, .. })
+                if is_menu_key(key, modifiers) && (state.focused || cursor.is_over(bounds)) =>
+            {
+                state.open(bounds.position() + Vector::new(PADDING, PADDING), Some(0));
+                return event::Status::Captured;
+            }
+            Event::Touch(touch::Event::FingerPressed { position, .. }) if bounds.contains(position) => {
+                let now = Instant::now();
+                state.press = Some((now, position));
+                shell.request_redraw(window::RedrawRequest::At(now + LONG_PRESS));
+            }
+            Event::Touch(touch::Event::FingerMoved { position, .. }) => {
+                if state
+                    .press
+                    .is_some_and(|(_, start)| start.distance(position) > LONG_PRESS_SLOP)
+                {
+                    state.press = None;
+                }
+            }
+            Event::Touch(touch::Event::FingerLifted { .. } | touch::Event::FingerLost { .. }) => {
+                state.press = None;
+            }
+            Event::Window(_, window::Event::RedrawRequested(now)) => {
+                if let Some((start, position)) = state.press {
+                    if now.duration_since(start) >= LONG_PRESS {
+                        state.focused = true;
+                        state.open(position, None);
+                    } else {
+                        shell.request_redraw(window::RedrawRequest::At(start + LONG_PRESS));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        self.content.as_widget_mut().on_event(
+            &mut tree.children[0],
+            event,
+            layout,
+            cursor,
+            renderer,
+            clipboard,
+            shell,
+            viewport,
+        )
+    }
+
+    fn mouse_interaction(
+        &self,
+        tree: &Tree,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        viewport: &Rectangle,
+        renderer: &Renderer,
+    ) -> mouse::Interaction {
+        self.content
+            .as_widget()
+            .mouse_interaction(&tree.children[0], layout, cursor, viewport, renderer)
+    }
+
+    fn overlay<'b>(
+        &'b mut self,
+        tree: &'b mut Tree,
+        layout: Layout<'_>,
+        renderer: &Renderer,
+        translation: Vector,
+    ) -> Option<overlay::Element<'b, Message, Theme, Renderer>> {
+        let state = tree.state.downcast_mut::<State>();
+        if let Some(position) = state.open_at {
+            return Some(overlay::Element::new(Box::new(Menu {
+                state,
+                items: &self.items,
+                position: position + translation,
+            })));
+        }
+        self.content
+            .as_widget_mut()
+            .overlay(&mut tree.children[0], layout, renderer, translation)
+    }
+}
+
+impl<'a> From<ContextMenu<'a>> for Element<'a, Message> {
+    fn from(menu: ContextMenu<'a>) -> Self {
+        Element::new(menu)
+    }
+}
+
+/// The open menu, drawn over everything else
+struct Menu<'a> {
+    state: &'a mut State,
+    items: &'a [(String, Message)],
+    position: Point,
+}
+
+impl<'a> Menu<'a> {
+    /// The item under `cursor`, in a menu laid out at `bounds`
+    fn item_at(&self, bounds: Rectangle, cursor: mouse::Cursor) -> Option<usize> {
+        let position = cursor.position_over(bounds)?;
+        let index = ((position.y - bounds.y - PADDING) / ITEM_HEIGHT).floor();
+        (index >= 0.0 && (index as usize) < self.items.len()).then_some(index as usize)
+    }
+
+    fn activate(&mut self, index: usize, shell: &mut Shell<'_, Message>) {
+        if let Some((_, message)) = self.items.get(index) {
+            shell.publish(message.clone());
+        }
+        self.state.close();
+    }
+}
+
+impl<'a> overlay::Overlay<Message, Theme, Renderer> for Menu<'a> {
+    fn layout(&mut self, _renderer: &Renderer, bounds: Size) -> layout::Node {
+        let size = Size::new(
+            MENU_WIDTH,
+            self.items.len() as f32 * ITEM_HEIGHT + 2.0 * PADDING,
+        );
+        // Kept inside the window, flipping up or left near an edge
+        let x = if self.position.x + size.width > bounds.width {
+            (self.position.x - size.width).max(0.0)
+        } else {
+            self.position.x
+        };
+        let y = if self.position.y + size.height > bounds.height {
+            (self.position.y - size.height).max(0.0)
+        } else {
+            self.position.y
+        };
+        layout::Node::new(size).move_to(Point::new(x, y))
+    }
+
+    fn draw(
+        &self,
+        renderer: &mut Renderer,
+        theme: &Theme,
+        _style: &renderer::Style,
+        layout: Layout<'_>,
+        _cursor: mouse::Cursor,
+    ) {
+        let bounds = layout.bounds();
+        let palette = theme.extended_palette();
+
+        renderer::Renderer::fill_quad(
+            renderer,
+            Quad {
+                bounds,
+                border: Border {
+                    color: palette.background.strong.color,
+                    width: 1.0,
+                    radius: 6.0.into(),
+                },
+                ..Quad::default()
+            },
+            palette.background.base.color,
+        );
+
+        for (index, (label, _)) in self.items.iter().enumerate() {
+            let item = Rectangle {
+                x: bounds.x + PADDING,
+                y: bounds.y + PADDING + index as f32 * ITEM_HEIGHT,
+                width: bounds.width - 2.0 * PADDING,
+                height: ITEM_HEIGHT,
+            };
+            let selected = self.state.selected == Some(index);
+            if selected {
+                renderer::Renderer::fill_quad(
+                    renderer,
+                    Quad {
+                        bounds: item,
+                        border: Border {
+                            radius: 4.0.into(),
+                            ..Border::default()
+                        },
+                        ..Quad::default()
+                    },
+                    palette.primary.base.color,
+                );
+            }
+            text_renderer::Renderer::fill_text(
+                renderer,
+                Text {
+                    content: label,
+                    bounds: Size::new(item.width - 2.0 * PADDING, item.height),
+                    size: TEXT_SIZE.into(),
+                    line_height: LineHeight::default(),
+                    font: text_renderer::Renderer::default_font(renderer),
+                    horizontal_alignment: alignment::Horizontal::Left,
+                    vertical_alignment: alignment::Vertical::Center,
+                    shaping: Shaping::Advanced,
+                },
+                Point::new(item.x + 2.0 * PADDING, item.center_y()),
+                if selected {
+                    palette.primary.base.text
+                } else {
+                    palette.background.base.text
+                },
+                item,
+            );
+        }
+    }
+
+    fn on_event(
+        &mut self,
+        event: Event,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        _renderer: &Renderer,
+        _clipboard: &mut dyn Clipboard,
+        shell: &mut Shell<'_, Message>,
+    ) -> event::Status {
+        let bounds = layout.bounds();
+        match event {
+            Event::Mouse(mouse::Event::CursorMoved { .. }) => {
+                if let Some(index) = self.item_at(bounds, cursor) {
+                    self.state.selected = Some(index);
+                }
+                event::Status::Ignored
+            }
+            Event::Mouse(mouse::Event::ButtonPressed(_)) => {
+                match self.item_at(bounds, cursor) {
+                    Some(index) => self.activate(index, shell),
+                    // A click elsewhere only closes the menu
+                    None if !cursor.is_over(bounds) => self.state.close(),
+                    None => {}
+                }
+                event::Status::Captured
+            }
+            Event::Touch(touch::Event::FingerPressed { position, .. }) => {
+                match self.item_at(bounds, mouse::Cursor::Available(position)) {
+                    Some(index) => self.activate(index, shell),
+                    None if !bounds.contains(position) => self.state.close(),
+                    None => {}
+                }
+                event::Status::Captured
+            }
+            Event::Keyboard(keyboard::Event::KeyPressed { key, .. }) => {
+                let last = self.items.len().saturating_sub(1);
+                let selected = self.state.selected;
+                match key {
+                    Key::Named(Named::ArrowDown) => {
+                        self.state.selected = Some(selected.map_or(0, |i| (i + 1).min(last)));
+                    }
+                    Key::Named(Named::ArrowUp) => {
+                        self.state.selected = Some(selected.map_or(last, |i| i.saturating_sub(1)));
+                    }
+                    Key::Named(Named::Home) => self.state.selected = Some(0),
+                    Key::Named(Named::End) => self.state.selected = Some(last),
+                    Key::Named(Named::Enter | Named::Space) => match selected {
+                        Some(index) => self.activate(index, shell),
+                        None => self.state.selected = Some(0),
+                    },
+                    Key::Named(Named::Escape | Named::Tab) => self.state.close(),
+                    _ => {}
+                }
+                // Keys don't reach the rest of the UI while the menu is open
+                event::Status::Captured
+            }
+            _ => event::Status::Ignored,
+        }
+    }
+
+    fn mouse_interaction(
+        &self,
+        layout: Layout<'_>,
+        cursor: mouse::Cursor,
+        _viewport: &Rectangle,
+        _renderer: &Renderer,
+    ) -> mouse::Interaction {
+        if self.item_at(layout.bounds(), cursor).is_some() {
+            mouse::Interaction::Pointer
+        } else {
+            mouse::Interaction::Idle
+        }
+    }
+}
@@ -1,4 +1,8 @@
 //! Custom widgets for PrivMsg Desktop
 
+pub mod backdrop;
+pub mod context_menu;
+pub mod waveform;
+
 // Future custom widgets will go here
-// For example: Avatar, VoiceWaveform, VideoPlayer, etc.
+// For example: Avatar, VideoPlayer, etc.
@@ -0,0 +1,129 @@
+//! Scrubbable waveform for voice messages
+
+use crate::messages::Message;
+use iced::mouse;
+use iced::widget::canvas::{self, event, Canvas, Event, Frame, Geometry, Path};
+use iced::{Color, Element, Length, Point, Rectangle, Renderer, Size, Theme};
+
+const BAR_GAP: f32 = 1.0;
+const MIN_BAR_HEIGHT: f32 = 2.0;
+
+/// Amplitude bars with the played part highlighted; clicking or dragging
+/// seeks to that position.
+pub struct Waveform {
+    samples: Vec<u8>,
+    progress: f32,
+    on_seek: Box<dyn Fn(f32) -> Message>,
+}
+
+impl Waveform {
+    pub fn new(samples: Vec<u8>, progress: f32, on_seek: impl Fn(f32) -> Message + 'static) -> Self {
+        Self {
+            samples,
+            progress: progress.clamp(0.0, 1.0),
+            on_seek: Box::new(on_seek),
+        }
+    }
+
+    pub fn view(self, width: f32, height: f32) -> Element<'static, Message> {
+        Canvas::new(self)
+            .width(Length::Fixed(width))
+            .height(Length::Fixed(height))
+            .into()
+    }
+
+    fn seek(&self, bounds: Rectangle, cursor: mouse::Cursor) -> Option<Message> {
+        let position = cursor.position_in(bounds)?;
+        Some((self.on_seek)((position.x / bounds.width).clamp(0.0, 1.0)))
+    }
+}
+
+/// Whether the left button is held down over the waveform
+#[derive(Default)]
+pub struct State {
+    dragging: bool,
+}
+
+impl canvas::Program<Message> for Waveform {
+    type State = State;
+
+    fn update(
+        &self,
+        state: &mut State,
+        event: Event,
+        bounds: Rectangle,
+        cursor: mouse::Cursor,
+    ) -> (event::Status, Option<Message>) {
+        match event {
+            Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left)) => {
+                if let Some(msg) = self.seek(bounds, cursor) {
+                    state.dragging = true;
+                    return (event::Status::Captured, Some(msg));
+                }
+            }
+            Event::Mouse(mouse::Event::CursorMoved { .. }) if state.dragging => {
+                return (event::Status::Captured, self.seek(bounds, cursor));
+            }
+            Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left)) => {
+                state.dragging = false;
+            }
+            _ => {}
+        }
+        (event::Status::Ignored, None)
+    }
+
+    fn draw(
+        &self,
+        _state: &State,
+        renderer: &Renderer,
+        theme: &Theme,
+        bounds: Rectangle,
+        _cursor: mouse::Cursor,
+    ) -> Vec<Geometry> {
+        let mut frame = Frame::new(renderer, bounds.size());
+        if self.samples.is_empty() {
+            return vec![frame.into_geometry()];
+        }
+
+        let palette = theme.extended_palette();
+        let played = palette.primary.base.color;
+        let unplayed = Color {
+            a: 0.4,
+            ..palette.background.base.text
+        };
+
+        let bar_width = (bounds.width / self.samples.len() as f32 - BAR_GAP).max(1.0);
+        let step = bounds.width / self.samples.len() as f32;
+
+        for (i, &sample) in self.samples.iter().enumerate() {
+            let height = (sample as f32 / 255.0 * bounds.height).max(MIN_BAR_HEIGHT);
+            let x = i as f32 * step;
+            let y = (bounds.height - height) / 2.0;
+            let color = if (i as f32 + 0.5) / self.samples.len() as f32 <= self.progress {
+                played
+            } else {
+                unplayed
+            };
+
+            frame.fill(
+                &Path::rectangle(Point::new(x, y), Size::new(bar_width, height)),
+                color,
+            );
+        }
+
+        vec![frame.into_geometry()]
+    }
+
+    fn mouse_interaction(
+        &self,
+        _state: &State,
+        bounds: Rectangle,
+        cursor: mouse::Cursor,
+    ) -> mouse::Interaction {
+        if cursor.is_over(bounds) {
+            mouse::Interaction::Pointer
+        } else {
+            mouse::Interaction::default()
+        }
+    }
+}
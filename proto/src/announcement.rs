@@ -0,0 +1,64 @@
+//! Service notices an operator broadcasts to every client
+//!
+//! Set through the admin API, e.g. ahead of a maintenance window. Clients
+//! get the current one in the login response and in an `announcement`
+//! WebSocket frame, sent on connect and whenever it is set or cleared, and
+//! show it as a banner users can dismiss.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest announcement text in characters
+pub const MAX_ANNOUNCEMENT_LEN: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnouncementSeverity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+    /// A severity added by a newer server; shown like `Info`
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    /// New for every announcement, so a dismissed one stays dismissed
+    /// without hiding the next
+    pub id: String,
+    pub text: String,
+    #[serde(default)]
+    pub severity: AnnouncementSeverity,
+    /// Unix seconds; shown until cleared when `None`
+    pub expires_at: Option<i64>,
+}
+
+impl Announcement {
+    /// Whether it should still be shown at `now` (Unix seconds)
+    pub fn is_active(&self, now: i64) -> bool {
+        self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announcement_expiry_and_unknown_severity() {
+        let announcement: Announcement = serde_json::from_str(
+            r#"{"id":"a1","text":"Down at 22:00","severity":"apocalyptic","expires_at":100}"#,
+        )
+        .unwrap();
+        assert_eq!(announcement.severity, AnnouncementSeverity::Unknown);
+        assert!(announcement.is_active(99));
+        assert!(!announcement.is_active(100));
+
+        let announcement = Announcement {
+            expires_at: None,
+            ..announcement
+        };
+        assert!(announcement.is_active(i64::MAX));
+    }
+}
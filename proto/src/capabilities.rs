@@ -0,0 +1,70 @@
+//! Optional features a server has turned on
+//!
+//! Served without authentication at `/api/v1/capabilities`. Clients hide
+//! what a server lacks instead of failing when it is used. Servers from
+//! before capabilities existed have every feature, so a missing endpoint or
+//! field means supported.
+
+use serde::{Deserialize, Serialize};
+
+use crate::maintenance::Maintenance;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerCapabilities {
+    /// A TURN relay is configured, so calls can connect
+    pub calls: bool,
+    /// Attachments can be uploaded
+    pub file_upload: bool,
+    /// Largest upload in bytes; 0 when unknown
+    pub max_file_size: u64,
+    /// Set while the server is in maintenance mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<Maintenance>,
+}
+
+impl Default for ServerCapabilities {
+    fn default() -> Self {
+        Self {
+            calls: true,
+            file_upload: true,
+            max_file_size: 0,
+            maintenance: None,
+        }
+    }
+}
+
+impl ServerCapabilities {
+    /// Whether a file of `size` bytes can be uploaded
+    pub fn accepts_file(&self, size: u64) -> bool {
+        self.file_upload
+            && self.maintenance.is_none()
+            && (self.max_file_size == 0 || size <= self.max_file_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_capabilities_mean_supported() {
+        let caps: ServerCapabilities = serde_json::from_str(r#"{"calls":false,"later":1}"#).unwrap();
+        assert!(!caps.calls);
+        assert!(caps.file_upload);
+        assert!(caps.accepts_file(u64::MAX));
+
+        let caps = ServerCapabilities {
+            max_file_size: 10,
+            ..Default::default()
+        };
+        assert!(caps.accepts_file(10));
+        assert!(!caps.accepts_file(11));
+        assert!(!ServerCapabilities {
+            maintenance: Some(Maintenance::default()),
+            ..caps.clone()
+        }
+        .accepts_file(1));
+        assert!(!ServerCapabilities { file_upload: false, ..caps }.accepts_file(1));
+    }
+}
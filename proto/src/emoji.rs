@@ -0,0 +1,216 @@
+//! Emoji-only messages and `:shortcode:` conversion
+//!
+//! Shared so every frontend agrees on which messages render as large emoji
+//! without a bubble, and senders convert shortcodes the same way.
+
+use std::borrow::Cow;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Most emoji a message can hold and still render large
+pub const MAX_JUMBO_EMOJI: usize = 3;
+
+/// Shortcodes and their emoji, sorted by name for binary search
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "\u{1F44D}"),
+    ("-1", "\u{1F44E}"),
+    ("100", "\u{1F4AF}"),
+    ("angry", "\u{1F620}"),
+    ("beer", "\u{1F37A}"),
+    ("blush", "\u{1F60A}"),
+    ("broken_heart", "\u{1F494}"),
+    ("cake", "\u{1F370}"),
+    ("clap", "\u{1F44F}"),
+    ("coffee", "\u{2615}"),
+    ("confused", "\u{1F615}"),
+    ("cry", "\u{1F622}"),
+    ("eyes", "\u{1F440}"),
+    ("facepalm", "\u{1F926}"),
+    ("fire", "\u{1F525}"),
+    ("gift", "\u{1F381}"),
+    ("grin", "\u{1F601}"),
+    ("grinning", "\u{1F600}"),
+    ("heart", "\u{2764}\u{FE0F}"),
+    ("heart_eyes", "\u{1F60D}"),
+    ("hugs", "\u{1F917}"),
+    ("innocent", "\u{1F607}"),
+    ("joy", "\u{1F602}"),
+    ("kiss", "\u{1F618}"),
+    ("laughing", "\u{1F606}"),
+    ("muscle", "\u{1F4AA}"),
+    ("neutral_face", "\u{1F610}"),
+    ("ok_hand", "\u{1F44C}"),
+    ("partying_face", "\u{1F973}"),
+    ("pizza", "\u{1F355}"),
+    ("poop", "\u{1F4A9}"),
+    ("pray", "\u{1F64F}"),
+    ("rage", "\u{1F621}"),
+    ("raised_hands", "\u{1F64C}"),
+    ("rocket", "\u{1F680}"),
+    ("rofl", "\u{1F923}"),
+    ("scream", "\u{1F631}"),
+    ("see_no_evil", "\u{1F648}"),
+    ("shrug", "\u{1F937}"),
+    ("skull", "\u{1F480}"),
+    ("slightly_smiling_face", "\u{1F642}"),
+    ("smile", "\u{1F604}"),
+    ("smiley", "\u{1F603}"),
+    ("smirk", "\u{1F60F}"),
+    ("sob", "\u{1F62D}"),
+    ("sparkles", "\u{2728}"),
+    ("star", "\u{2B50}"),
+    ("sunglasses", "\u{1F60E}"),
+    ("sunny", "\u{2600}\u{FE0F}"),
+    ("sweat_smile", "\u{1F605}"),
+    ("tada", "\u{1F389}"),
+    ("thinking", "\u{1F914}"),
+    ("thumbsdown", "\u{1F44E}"),
+    ("thumbsup", "\u{1F44D}"),
+    ("upside_down_face", "\u{1F643}"),
+    ("wave", "\u{1F44B}"),
+    ("white_check_mark", "\u{2705}"),
+    ("wink", "\u{1F609}"),
+    ("x", "\u{274C}"),
+    ("zzz", "\u{1F4A4}"),
+];
+
+/// The emoji for a shortcode name (without colons), e.g. `tada`
+pub fn shortcode(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .binary_search_by(|(n, _)| (*n).cmp(name))
+        .ok()
+        .map(|i| SHORTCODES[i].1)
+}
+
+/// `text` with known `:shortcodes:` replaced by their emoji; unknown ones
+/// and stray colons, as in times like 12:30:45, are left alone
+pub fn replace_shortcodes(text: &str) -> Cow<'_, str> {
+    if !text.contains(':') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut replaced = false;
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')))
+            .unwrap_or(after.len());
+        match (after[name_len..].starts_with(':'), shortcode(&after[..name_len])) {
+            (true, Some(emoji)) if name_len > 0 => {
+                out.push_str(emoji);
+                rest = &after[name_len + 1..];
+                replaced = true;
+            }
+            _ => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    if !replaced {
+        return Cow::Borrowed(text);
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+/// Emoji by default
+fn is_pictographic(c: char) -> bool {
+    matches!(
+        c,
+        '\u{1F000}'..='\u{1FAFF}' | '\u{2600}'..='\u{27BF}' | '\u{2300}'..='\u{23FF}' | '\u{2B00}'..='\u{2BFF}'
+    )
+}
+
+/// Text by default; emoji when followed by U+FE0F or, for keycaps, U+20E3
+fn is_emoji_base(c: char) -> bool {
+    matches!(
+        c,
+        '0'..='9'
+            | '#'
+            | '*'
+            | '\u{A9}'
+            | '\u{AE}'
+            | '\u{203C}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{21AA}'
+            | '\u{24C2}'
+            | '\u{25AA}'..='\u{25FE}'
+            | '\u{2934}'
+            | '\u{2935}'
+            | '\u{3030}'
+            | '\u{303D}'
+            | '\u{3297}'
+            | '\u{3299}'
+    )
+}
+
+/// Whether a grapheme cluster is a single emoji, including ZWJ sequences,
+/// skin tones, flags and keycaps
+fn is_emoji(grapheme: &str) -> bool {
+    let (mut pictographic, mut base, mut presentation) = (false, false, false);
+    for c in grapheme.chars() {
+        match c {
+            // Joiner and subdivision flag tags
+            '\u{200D}' | '\u{E0020}'..='\u{E007F}' => {}
+            '\u{FE0F}' | '\u{20E3}' => presentation = true,
+            c if is_pictographic(c) => pictographic = true,
+            c if is_emoji_base(c) => base = true,
+            _ => return false,
+        }
+    }
+    pictographic || (base && presentation)
+}
+
+/// How many emoji `text` is, if it is nothing but 1 to [`MAX_JUMBO_EMOJI`]
+/// of them (whitespace aside); such messages render large and without a
+/// bubble
+pub fn jumbo_emoji_count(text: &str) -> Option<usize> {
+    let mut count = 0;
+    for grapheme in text.graphemes(true) {
+        if grapheme.chars().all(char::is_whitespace) {
+            continue;
+        }
+        if count == MAX_JUMBO_EMOJI || !is_emoji(grapheme) {
+            return None;
+        }
+        count += 1;
+    }
+    (count > 0).then_some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jumbo_emoji() {
+        assert_eq!(jumbo_emoji_count("\u{1F389}"), Some(1));
+        // Family ZWJ sequence, skin tone, flag, keycap and heart with VS16
+        assert_eq!(
+            jumbo_emoji_count("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467} \u{1F44D}\u{1F3FD}\u{1F1FA}\u{1F1E6}"),
+            Some(3)
+        );
+        assert_eq!(jumbo_emoji_count(" 1\u{FE0F}\u{20E3} \u{2764}\u{FE0F} "), Some(2));
+        assert_eq!(jumbo_emoji_count("\u{1F389}\u{1F389}\u{1F389}\u{1F389}"), None);
+        assert_eq!(jumbo_emoji_count("ok \u{1F44D}"), None);
+        assert_eq!(jumbo_emoji_count("123"), None);
+        assert_eq!(jumbo_emoji_count("\u{A9}"), None);
+        assert_eq!(jumbo_emoji_count("  "), None);
+        assert_eq!(jumbo_emoji_count(""), None);
+    }
+
+    #[test]
+    fn test_shortcodes() {
+        assert!(SHORTCODES.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(replace_shortcodes("Ship it :rocket::tada:"), "Ship it \u{1F680}\u{1F389}");
+        assert_eq!(replace_shortcodes(":+1: at 12:30:45"), "\u{1F44D} at 12:30:45");
+        assert_eq!(replace_shortcodes("::x: :nope: :"), ":\u{274C} :nope: :");
+        assert!(matches!(replace_shortcodes("a:b:c"), Cow::Borrowed(_)));
+    }
+}
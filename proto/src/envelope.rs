@@ -0,0 +1,237 @@
+//! Canonical byte encoding of message envelopes
+//!
+//! JSON leaves field order, whitespace and number formatting up to the
+//! encoder, so two correct encoders can produce different bytes for the same
+//! envelope. Signatures need exactly one encoding, and binary WebSocket
+//! frames use the same one.
+//!
+//! The encoding is a version byte followed by every field in declaration
+//! order:
+//!
+//! - strings: 4-byte big-endian length, then UTF-8 bytes
+//! - optional values: `0` for none, or `1` followed by the value
+//! - `timestamp`: 8-byte big-endian two's complement
+//! - `ttl_seconds`: 4-byte big-endian
+//! - `priority` and `delivery_mode`: their wire names, as strings
+//!
+//! Decoding is strict: trailing bytes, bad UTF-8 and option tags other than
+//! 0 and 1 are rejected, so every envelope has exactly one valid encoding.
+
+use std::fmt;
+
+use crate::{DeliveryMode, MessagePriority};
+
+/// First byte of every encoded envelope
+pub const ENVELOPE_ENCODING_VERSION: u8 = 1;
+
+/// Prefix of the bytes an envelope signature covers, so a signature over an
+/// envelope can't be replayed as a signature over anything else
+const SIGNING_CONTEXT: &[u8] = b"privmsg-envelope-v1\0";
+
+/// The fields of a message envelope, in canonical order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalEnvelope {
+    pub message_id: String,
+    pub sender_id: String,
+    pub recipient_id: String,
+    pub recipient_device_id: Option<String>,
+    pub encrypted_content: String,
+    pub message_type: String,
+    pub timestamp: i64,
+    pub ttl_seconds: Option<u32>,
+    pub priority: MessagePriority,
+    pub delivery_mode: DeliveryMode,
+}
+
+/// Why bytes aren't a canonical envelope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    Truncated,
+    TrailingBytes,
+    InvalidUtf8,
+    InvalidTag(u8),
+    UnknownValue(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported envelope encoding version {}", v),
+            DecodeError::Truncated => f.write_str("envelope is truncated"),
+            DecodeError::TrailingBytes => f.write_str("trailing bytes after envelope"),
+            DecodeError::InvalidUtf8 => f.write_str("envelope field is not UTF-8"),
+            DecodeError::InvalidTag(t) => write!(f, "invalid option tag {}", t),
+            DecodeError::UnknownValue(v) => write!(f, "unknown value {:?}", v),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl CanonicalEnvelope {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + self.encrypted_content.len());
+        out.push(ENVELOPE_ENCODING_VERSION);
+        put_str(&mut out, &self.message_id);
+        put_str(&mut out, &self.sender_id);
+        put_str(&mut out, &self.recipient_id);
+        put_option(&mut out, self.recipient_device_id.as_deref(), put_str);
+        put_str(&mut out, &self.encrypted_content);
+        put_str(&mut out, &self.message_type);
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        put_option(&mut out, self.ttl_seconds, |out, ttl| out.extend_from_slice(&ttl.to_be_bytes()));
+        put_str(&mut out, self.priority.as_str());
+        put_str(&mut out, self.delivery_mode.as_str());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { bytes };
+        let version = reader.take::<1>()?[0];
+        if version != ENVELOPE_ENCODING_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let envelope = Self {
+            message_id: reader.string()?,
+            sender_id: reader.string()?,
+            recipient_id: reader.string()?,
+            recipient_device_id: reader.option(Reader::string)?,
+            encrypted_content: reader.string()?,
+            message_type: reader.string()?,
+            timestamp: i64::from_be_bytes(reader.take()?),
+            ttl_seconds: reader.option(|r| Ok(u32::from_be_bytes(r.take()?)))?,
+            priority: {
+                let priority = reader.string()?;
+                priority.parse().map_err(|_| DecodeError::UnknownValue(priority))?
+            },
+            delivery_mode: {
+                let mode = reader.string()?;
+                mode.parse().map_err(|_| DecodeError::UnknownValue(mode))?
+            },
+        };
+        if !reader.bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(envelope)
+    }
+
+    /// The bytes a sender signs and a recipient verifies
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut out = SIGNING_CONTEXT.to_vec();
+        out.extend(self.encode());
+        out
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn put_option<T>(out: &mut Vec<u8>, value: Option<T>, put: impl FnOnce(&mut Vec<u8>, T)) {
+    match value {
+        Some(value) => {
+            out.push(1);
+            put(out, value);
+        }
+        None => out.push(0),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let (head, rest) = self.bytes.split_first_chunk::<N>().ok_or(DecodeError::Truncated)?;
+        self.bytes = rest;
+        Ok(*head)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = u32::from_be_bytes(self.take()?) as usize;
+        if self.bytes.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        String::from_utf8(value.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn option<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T, DecodeError>) -> Result<Option<T>, DecodeError> {
+        match self.take::<1>()?[0] {
+            0 => Ok(None),
+            1 => read(self).map(Some),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> CanonicalEnvelope {
+        CanonicalEnvelope {
+            message_id: "m1".to_string(),
+            sender_id: "alice".to_string(),
+            recipient_id: "bob".to_string(),
+            recipient_device_id: Some("phone".to_string()),
+            encrypted_content: "x25519:Y2lwaGVy".to_string(),
+            message_type: "text".to_string(),
+            timestamp: -1,
+            ttl_seconds: Some(60),
+            priority: MessagePriority::High,
+            delivery_mode: DeliveryMode::OnlineOnly,
+        }
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let original = envelope();
+        let bytes = original.encode();
+        assert_eq!(bytes[0], ENVELOPE_ENCODING_VERSION);
+        assert_eq!(&bytes[1..7], &[0, 0, 0, 2, b'm', b'1']);
+        assert_eq!(CanonicalEnvelope::decode(&bytes).unwrap(), original);
+
+        let bare = CanonicalEnvelope {
+            recipient_device_id: None,
+            ttl_seconds: None,
+            ..original.clone()
+        };
+        assert_eq!(CanonicalEnvelope::decode(&bare.encode()).unwrap(), bare);
+        assert_eq!(bare.encode().len(), bytes.len() - 9 - 4);
+
+        // The signed bytes are the encoding behind a fixed context
+        assert!(original.signing_bytes().starts_with(SIGNING_CONTEXT));
+        assert!(original.signing_bytes().ends_with(&bytes));
+    }
+
+    #[test]
+    fn test_envelope_decode_is_strict() {
+        let bytes = envelope().encode();
+        for len in 0..bytes.len() {
+            assert!(CanonicalEnvelope::decode(&bytes[..len]).is_err());
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(CanonicalEnvelope::decode(&trailing), Err(DecodeError::TrailingBytes));
+
+        let mut version = bytes.clone();
+        version[0] = 2;
+        assert_eq!(CanonicalEnvelope::decode(&version), Err(DecodeError::UnsupportedVersion(2)));
+
+        // The option tag after the three ids
+        let mut tag = bytes.clone();
+        let at = 1 + (4 + 2) + (4 + 5) + (4 + 3);
+        tag[at] = 2;
+        assert_eq!(CanonicalEnvelope::decode(&tag), Err(DecodeError::InvalidTag(2)));
+
+        let mut utf8 = bytes;
+        utf8[5] = 0xff;
+        assert_eq!(CanonicalEnvelope::decode(&utf8), Err(DecodeError::InvalidUtf8));
+    }
+}
@@ -0,0 +1,161 @@
+//! Feature flags for experimental subsystems
+//!
+//! Flags let a larger change ship switched off and be turned on for some
+//! clients first. A flag's value comes from, lowest to highest precedence:
+//! its built-in default, the client's configuration, the [`FLAGS_ENV`]
+//! environment variable, and the server's client policy. The last one lets
+//! an operator stage a rollout without a client release.
+//!
+//! Flags are named by their snake_case names on the wire and in specs, so
+//! names a client doesn't know are skipped rather than rejected.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Environment variable with a flag spec, e.g. `ratchet,-binary_envelopes`
+pub const FLAGS_ENV: &str = "PRIVMSG_FLAGS";
+
+/// Experimental subsystems that can be switched at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flag {
+    /// Envelopes as binary WebSocket frames in the canonical encoding
+    BinaryEnvelopes,
+    /// Forward-secret sessions that advance their keys with every message
+    Ratchet,
+    /// Files sent peer to peer instead of through the server
+    DirectTransfer,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 3] = [Flag::BinaryEnvelopes, Flag::Ratchet, Flag::DirectTransfer];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::BinaryEnvelopes => "binary_envelopes",
+            Flag::Ratchet => "ratchet",
+            Flag::DirectTransfer => "direct_transfer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Flag> {
+        Flag::ALL.into_iter().find(|flag| flag.name() == name)
+    }
+
+    /// Value when nothing sets the flag
+    pub fn default_enabled(&self) -> bool {
+        match self {
+            Flag::BinaryEnvelopes | Flag::Ratchet => false,
+            Flag::DirectTransfer => true,
+        }
+    }
+}
+
+/// Where a flag's value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagSource {
+    Default,
+    Config,
+    Environment,
+    Policy,
+}
+
+impl FlagSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlagSource::Default => "default",
+            FlagSource::Config => "config",
+            FlagSource::Environment => "environment",
+            FlagSource::Policy => "server policy",
+        }
+    }
+}
+
+/// A flag's resolved value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagState {
+    pub flag: Flag,
+    pub enabled: bool,
+    pub source: FlagSource,
+}
+
+/// Values set by one source
+pub type FlagValues = BTreeMap<Flag, bool>;
+
+/// Parse a comma-separated spec: `name` turns a flag on, `-name` off.
+/// Unknown names are skipped.
+pub fn parse_spec(spec: &str) -> FlagValues {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| match item.strip_prefix('-') {
+            Some(name) => Flag::from_name(name.trim()).map(|flag| (flag, false)),
+            None => Flag::from_name(item.strip_prefix('+').unwrap_or(item)).map(|flag| (flag, true)),
+        })
+        .collect()
+}
+
+/// Flags by name, as a policy carries them. Unknown names are skipped.
+pub fn from_names(values: &BTreeMap<String, bool>) -> FlagValues {
+    values
+        .iter()
+        .filter_map(|(name, enabled)| Flag::from_name(name).map(|flag| (flag, *enabled)))
+        .collect()
+}
+
+/// The flags set in [`FLAGS_ENV`]
+pub fn from_env() -> FlagValues {
+    std::env::var(FLAGS_ENV)
+        .map(|spec| parse_spec(&spec))
+        .unwrap_or_default()
+}
+
+/// Every flag's value; later layers win
+pub fn resolve(config: &FlagValues, env: &FlagValues, policy: &FlagValues) -> Vec<FlagState> {
+    Flag::ALL
+        .into_iter()
+        .map(|flag| {
+            let layers = [
+                (policy, FlagSource::Policy),
+                (env, FlagSource::Environment),
+                (config, FlagSource::Config),
+            ];
+            let (enabled, source) = layers
+                .into_iter()
+                .find_map(|(values, source)| values.get(&flag).map(|enabled| (*enabled, source)))
+                .unwrap_or((flag.default_enabled(), FlagSource::Default));
+            FlagState { flag, enabled, source }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_flags() {
+        let env = parse_spec(" ratchet, -direct_transfer,teleport,,+binary_envelopes");
+        assert_eq!(env.len(), 3);
+        assert!(!env[&Flag::DirectTransfer]);
+
+        let config = FlagValues::from([(Flag::Ratchet, false)]);
+        let policy = from_names(&BTreeMap::from([
+            ("binary_envelopes".to_string(), false),
+            ("warp".to_string(), true),
+        ]));
+        let states = resolve(&config, &env, &policy);
+
+        let state = |flag| states.iter().find(|s| s.flag == flag).copied().unwrap();
+        assert_eq!(state(Flag::BinaryEnvelopes).source, FlagSource::Policy);
+        assert!(!state(Flag::BinaryEnvelopes).enabled);
+        assert_eq!(state(Flag::Ratchet).source, FlagSource::Environment);
+        assert!(state(Flag::Ratchet).enabled);
+
+        let states = resolve(&config, &FlagValues::new(), &FlagValues::new());
+        assert_eq!(states[1].source, FlagSource::Config);
+        assert_eq!(states[2].source, FlagSource::Default);
+        assert!(states[2].enabled);
+    }
+}
@@ -0,0 +1,546 @@
+//! Wire types shared by the PrivMsg server and clients
+//!
+//! Both the `error` WebSocket frame and HTTP error bodies carry a
+//! [`ServerError`]; clients branch on its [`ErrorCode`] rather than the
+//! human-readable message.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use unicode_segmentation::UnicodeSegmentation;
+
+pub mod announcement;
+pub mod capabilities;
+pub mod emoji;
+pub mod envelope;
+pub mod flags;
+pub mod maintenance;
+pub mod notification;
+pub mod policy;
+pub mod pow;
+pub mod quote;
+pub mod rules;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+#[cfg(feature = "stream")]
+pub mod stream;
+
+/// Machine-readable reason for a rejected request or frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    // Authentication
+    Unauthorized,
+    InvalidCredentials,
+    AuthFailed,
+    AuthTimeout,
+    AccountDeleted,
+    Forbidden,
+
+    // Requests
+    BadRequest,
+    ParseError,
+    NotFound,
+    UserExists,
+    /// Logging in needs a proof of work; see [`pow`]
+    ChallengeRequired,
+    InvalidSender,
+    RecipientBlocked,
+    /// The recipient's account was deactivated or deleted
+    RecipientUnavailable,
+    RateLimited,
+    /// Servers before 1.0 called this `FILE_TOO_LARGE`
+    #[serde(alias = "FILE_TOO_LARGE")]
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+
+    // Server faults
+    /// The server is in maintenance mode; see [`maintenance::Maintenance`]
+    Maintenance,
+    DatabaseError,
+    IoError,
+    InternalError,
+
+    /// A code added by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::InvalidCredentials => "INVALID_CREDENTIALS",
+            ErrorCode::AuthFailed => "AUTH_FAILED",
+            ErrorCode::AuthTimeout => "AUTH_TIMEOUT",
+            ErrorCode::AccountDeleted => "ACCOUNT_DELETED",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::BadRequest => "BAD_REQUEST",
+            ErrorCode::ParseError => "PARSE_ERROR",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::UserExists => "USER_EXISTS",
+            ErrorCode::ChallengeRequired => "CHALLENGE_REQUIRED",
+            ErrorCode::InvalidSender => "INVALID_SENDER",
+            ErrorCode::RecipientBlocked => "RECIPIENT_BLOCKED",
+            ErrorCode::RecipientUnavailable => "RECIPIENT_UNAVAILABLE",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::PayloadTooLarge => "PAYLOAD_TOO_LARGE",
+            ErrorCode::RangeNotSatisfiable => "RANGE_NOT_SATISFIABLE",
+            ErrorCode::Maintenance => "MAINTENANCE",
+            ErrorCode::DatabaseError => "DATABASE_ERROR",
+            ErrorCode::IoError => "IO_ERROR",
+            ErrorCode::InternalError => "INTERNAL_ERROR",
+            ErrorCode::Unknown => "UNKNOWN",
+        }
+    }
+
+    /// Whether the same request may succeed if retried later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::RateLimited
+                | ErrorCode::Maintenance
+                | ErrorCode::DatabaseError
+                | ErrorCode::IoError
+                | ErrorCode::InternalError
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Payload of an `error` frame and the `error` field of HTTP error bodies
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// The envelope that was rejected, for errors about a single message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            message_id: None,
+        }
+    }
+
+    pub fn for_message(mut self, message_id: impl Into<String>) -> Self {
+        self.message_id = Some(message_id.into());
+        self
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+/// Why the server ended a session, sent in the `session_revoked` frame
+/// right before it closes the socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationReason {
+    /// The device was removed from the account, possibly from another device
+    DeviceRemoved,
+    /// The device logged out
+    LoggedOut,
+    /// An admin deactivated the account
+    AccountDeactivated,
+    /// An admin deleted the account
+    AccountDeleted,
+    /// The session expired or was invalidated some other way
+    Expired,
+    /// A reason added by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+impl fmt::Display for RevocationReason {
+    /// Suitable for showing to the user on the login screen
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RevocationReason::DeviceRemoved => "This device was removed from your account",
+            RevocationReason::LoggedOut => "You were logged out",
+            RevocationReason::AccountDeactivated => "Your account has been deactivated",
+            RevocationReason::AccountDeleted => "Your account has been deleted",
+            RevocationReason::Expired | RevocationReason::Unknown => {
+                "Your session has ended, please log in again"
+            }
+        })
+    }
+}
+
+/// Who may see when a user was last online, and their presence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LastSeenVisibility {
+    Nobody,
+    /// Users the account has exchanged messages with
+    Contacts,
+    #[default]
+    Everyone,
+}
+
+impl LastSeenVisibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LastSeenVisibility::Nobody => "nobody",
+            LastSeenVisibility::Contacts => "contacts",
+            LastSeenVisibility::Everyone => "everyone",
+        }
+    }
+
+    /// Whether someone else may see it, given whether they are a contact
+    pub fn allows(&self, is_contact: bool) -> bool {
+        match self {
+            LastSeenVisibility::Nobody => false,
+            LastSeenVisibility::Contacts => is_contact,
+            LastSeenVisibility::Everyone => true,
+        }
+    }
+}
+
+impl fmt::Display for LastSeenVisibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for LastSeenVisibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nobody" => Ok(LastSeenVisibility::Nobody),
+            "contacts" => Ok(LastSeenVisibility::Contacts),
+            "everyone" => Ok(LastSeenVisibility::Everyone),
+            _ => Err(format!("Unknown last seen visibility: {}", s)),
+        }
+    }
+}
+
+/// How urgently the server should replay a stored envelope; higher
+/// priorities go out first when a device reconnects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", from = "String")]
+pub enum MessagePriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl MessagePriority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessagePriority::Low => "low",
+            MessagePriority::Normal => "normal",
+            MessagePriority::High => "high",
+        }
+    }
+
+    /// The priority clients give envelopes of `message_type`
+    pub fn for_message_type(message_type: &str) -> Self {
+        match message_type {
+            "call_signal" | "key_exchange" | "device_sync" => MessagePriority::High,
+            "typing_indicator" | "read_receipt" | "read_sync" | "draft_sync" => MessagePriority::Low,
+            _ => MessagePriority::Normal,
+        }
+    }
+}
+
+impl fmt::Display for MessagePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for MessagePriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(MessagePriority::Low),
+            "normal" => Ok(MessagePriority::Normal),
+            "high" => Ok(MessagePriority::High),
+            _ => Err(format!("Unknown message priority: {}", s)),
+        }
+    }
+}
+
+/// Priorities added by a newer client count as normal
+impl From<String> for MessagePriority {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
+
+/// Whether the server keeps an envelope for devices that are offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", from = "String")]
+pub enum DeliveryMode {
+    /// Queued until every recipient device has it, or its TTL runs out
+    #[default]
+    StoreAndForward,
+    /// Only delivered to devices connected when it arrives, e.g. live
+    /// location updates that are stale by the time anyone reconnects
+    OnlineOnly,
+}
+
+impl DeliveryMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryMode::StoreAndForward => "store_and_forward",
+            DeliveryMode::OnlineOnly => "online_only",
+        }
+    }
+}
+
+impl std::str::FromStr for DeliveryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "store_and_forward" => Ok(DeliveryMode::StoreAndForward),
+            "online_only" => Ok(DeliveryMode::OnlineOnly),
+            _ => Err(format!("Unknown delivery mode: {}", s)),
+        }
+    }
+}
+
+/// Modes added by a newer client fall back to store-and-forward
+impl From<String> for DeliveryMode {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
+
+/// Prefix of application-defined message types, `x-<vendor>-<name>`
+pub const CUSTOM_MESSAGE_TYPE_PREFIX: &str = "x-";
+
+/// Longest custom message type the server relays
+pub const MAX_CUSTOM_MESSAGE_TYPE_LEN: usize = 64;
+
+/// Whether `message_type` is a well-formed `x-<vendor>-<name>` type. Vendor
+/// and name are lowercase ASCII letters and digits; the name may also
+/// contain inner dashes.
+pub fn is_custom_message_type(message_type: &str) -> bool {
+    let Some(rest) = message_type.strip_prefix(CUSTOM_MESSAGE_TYPE_PREFIX) else {
+        return false;
+    };
+    let Some((vendor, name)) = rest.split_once('-') else {
+        return false;
+    };
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    message_type.len() <= MAX_CUSTOM_MESSAGE_TYPE_LEN
+        && !vendor.is_empty()
+        && vendor.chars().all(allowed)
+        && name.split('-').all(|part| !part.is_empty() && part.chars().all(allowed))
+}
+
+/// How long the server should hold envelopes of `message_type` for devices
+/// that are offline. `None` leaves it to the server's maximum; `Some(0)`
+/// means only devices online right now get the envelope.
+pub fn default_ttl_seconds(message_type: &str) -> Option<u32> {
+    match message_type {
+        "typing_indicator" => Some(0),
+        // A call nobody answered within a minute is over
+        "call_signal" => Some(60),
+        _ => None,
+    }
+}
+
+/// `host:port` to reach a `turn:` or `turns:` URL at, using the scheme's
+/// default port when the URL has none. Query parameters such as
+/// `?transport=udp` are ignored.
+pub fn turn_address(url: &str) -> Option<String> {
+    let (rest, default_port) = if let Some(rest) = url.strip_prefix("turns:") {
+        (rest, 5349)
+    } else {
+        (url.strip_prefix("turn:")?, 3478)
+    };
+    let host_port = rest.split('?').next()?;
+    if host_port.is_empty() {
+        return None;
+    }
+
+    // A bare IPv6 address has colons but no port
+    let has_port = match host_port.rsplit_once(':') {
+        Some((host, port)) => port.parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']')),
+        None => false,
+    };
+    Some(if has_port {
+        host_port.to_string()
+    } else {
+        format!("{}:{}", host_port, default_port)
+    })
+}
+
+/// The first `max` grapheme clusters of `text`. Cutting on graphemes
+/// rather than bytes or chars keeps emoji sequences, flags and combining
+/// marks whole.
+pub fn truncate_graphemes(text: &str, max: usize) -> &str {
+    match text.grapheme_indices(true).nth(max) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// `text` shortened to at most `max` grapheme clusters for a one-line
+/// preview, ending in "..." when anything was cut
+pub fn preview_text(text: &str, max: usize) -> Cow<'_, str> {
+    if text.graphemes(true).nth(max).is_none() {
+        return Cow::Borrowed(text);
+    }
+    let kept = truncate_graphemes(text, max.saturating_sub(3));
+    Cow::Owned(format!("{}...", kept.trim_end()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code_wire_format() {
+        for code in [
+            ErrorCode::AuthFailed,
+            ErrorCode::PayloadTooLarge,
+            ErrorCode::RecipientBlocked,
+            ErrorCode::RecipientUnavailable,
+            ErrorCode::RangeNotSatisfiable,
+            ErrorCode::Maintenance,
+        ] {
+            let json = serde_json::to_string(&code).unwrap();
+            assert_eq!(json, format!("\"{}\"", code.as_str()));
+            assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), code);
+        }
+
+        let old: ErrorCode = serde_json::from_str("\"FILE_TOO_LARGE\"").unwrap();
+        assert_eq!(old, ErrorCode::PayloadTooLarge);
+        let newer: ErrorCode = serde_json::from_str("\"QUOTA_EXCEEDED\"").unwrap();
+        assert_eq!(newer, ErrorCode::Unknown);
+
+        let rejected = ServerError::new(ErrorCode::RecipientUnavailable, "gone").for_message("m1");
+        let json = serde_json::to_value(&rejected).unwrap();
+        assert_eq!(json["message_id"], "m1");
+        let plain = serde_json::to_value(ServerError::new(ErrorCode::BadRequest, "no")).unwrap();
+        assert!(plain.get("message_id").is_none());
+    }
+
+    #[test]
+    fn test_revocation_reason_wire_format() {
+        let json = serde_json::to_string(&RevocationReason::DeviceRemoved).unwrap();
+        assert_eq!(json, "\"device_removed\"");
+        let newer: RevocationReason = serde_json::from_str("\"password_reset\"").unwrap();
+        assert_eq!(newer, RevocationReason::Unknown);
+    }
+
+    #[test]
+    fn test_last_seen_visibility() {
+        for visibility in [
+            LastSeenVisibility::Nobody,
+            LastSeenVisibility::Contacts,
+            LastSeenVisibility::Everyone,
+        ] {
+            let json = serde_json::to_string(&visibility).unwrap();
+            assert_eq!(json, format!("\"{}\"", visibility));
+            assert_eq!(visibility.as_str().parse::<LastSeenVisibility>(), Ok(visibility));
+        }
+        assert!(!LastSeenVisibility::Nobody.allows(true));
+        assert!(LastSeenVisibility::Contacts.allows(true));
+        assert!(!LastSeenVisibility::Contacts.allows(false));
+    }
+
+    #[test]
+    fn test_message_priority() {
+        let json = serde_json::to_string(&MessagePriority::High).unwrap();
+        assert_eq!(json, "\"high\"");
+        let newer: MessagePriority = serde_json::from_str("\"urgent\"").unwrap();
+        assert_eq!(newer, MessagePriority::Normal);
+        assert!(MessagePriority::High > MessagePriority::Normal);
+        assert_eq!("low".parse::<MessagePriority>(), Ok(MessagePriority::Low));
+
+        assert_eq!(MessagePriority::for_message_type("call_signal"), MessagePriority::High);
+        assert_eq!(MessagePriority::for_message_type("text"), MessagePriority::Normal);
+        assert_eq!(default_ttl_seconds("typing_indicator"), Some(0));
+        assert_eq!(default_ttl_seconds("text"), None);
+    }
+
+    #[test]
+    fn test_delivery_mode_wire_format() {
+        let json = serde_json::to_string(&DeliveryMode::OnlineOnly).unwrap();
+        assert_eq!(json, "\"online_only\"");
+        assert_eq!(serde_json::from_str::<DeliveryMode>(&json).unwrap(), DeliveryMode::OnlineOnly);
+        let newer: DeliveryMode = serde_json::from_str("\"broadcast\"").unwrap();
+        assert_eq!(newer, DeliveryMode::StoreAndForward);
+    }
+
+    #[test]
+    fn test_custom_message_types() {
+        assert!(is_custom_message_type("x-acme-poll"));
+        assert!(is_custom_message_type("x-acme-poll-vote2"));
+        assert!(!is_custom_message_type("x-acme"));
+        assert!(!is_custom_message_type("x-acme-"));
+        assert!(!is_custom_message_type("x--poll"));
+        assert!(!is_custom_message_type("x-Acme-poll"));
+        assert!(!is_custom_message_type("acme-poll"));
+        assert!(!is_custom_message_type(&format!("x-acme-{}", "a".repeat(64))));
+    }
+
+    #[test]
+    fn test_turn_address() {
+        assert_eq!(turn_address("turn:example.com:3480").as_deref(), Some("example.com:3480"));
+        assert_eq!(turn_address("turns:example.com").as_deref(), Some("example.com:5349"));
+        assert_eq!(turn_address("turn:10.0.0.1?transport=udp").as_deref(), Some("10.0.0.1:3478"));
+        assert_eq!(turn_address("turn:[::1]:3478").as_deref(), Some("[::1]:3478"));
+        assert_eq!(turn_address("stun:example.com"), None);
+        assert_eq!(turn_address("turn:"), None);
+    }
+
+    #[test]
+    fn test_truncate_graphemes() {
+        assert_eq!(truncate_graphemes("hello", 10), "hello");
+        assert_eq!(truncate_graphemes("hello", 2), "he");
+        assert_eq!(truncate_graphemes("", 3), "");
+
+        // CJK: three bytes per char, one grapheme each
+        assert_eq!(truncate_graphemes("\u{4f60}\u{597d}\u{4e16}\u{754c}", 2), "\u{4f60}\u{597d}");
+
+        // A ZWJ family, a flag and a skin-toned thumb are one grapheme each
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        let flag = "\u{1f1ef}\u{1f1f5}";
+        let thumb = "\u{1f44d}\u{1f3fd}";
+        let text = format!("{}{}{}", family, flag, thumb);
+        assert_eq!(truncate_graphemes(&text, 1), family);
+        assert_eq!(truncate_graphemes(&text, 2), format!("{}{}", family, flag));
+
+        // A combining accent stays with its base letter
+        assert_eq!(truncate_graphemes("e\u{301}x", 1), "e\u{301}");
+    }
+
+    #[test]
+    fn test_preview_text() {
+        assert!(matches!(preview_text("short", 40), Cow::Borrowed("short")));
+        assert_eq!(preview_text("abcdefghij", 10), "abcdefghij");
+        assert_eq!(preview_text("abcdefghijk", 10), "abcdefg...");
+        assert_eq!(preview_text("see you tomorrow", 11), "see you...");
+
+        let cjk = "\u{4f60}\u{597d}".repeat(30);
+        let preview = preview_text(&cjk, 40);
+        assert_eq!(preview.graphemes(true).count(), 40);
+        assert!(preview.ends_with("..."));
+
+        let thumbs = "\u{1f44d}\u{1f3fd}".repeat(10);
+        assert_eq!(preview_text(&thumbs, 5), format!("{}...", "\u{1f44d}\u{1f3fd}".repeat(2)));
+    }
+}
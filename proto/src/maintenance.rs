@@ -0,0 +1,38 @@
+//! Maintenance mode an operator turns on ahead of planned downtime
+//!
+//! While it is on the server rejects new logins and file uploads with
+//! [`ErrorCode::Maintenance`](crate::ErrorCode::Maintenance) and may stop
+//! relaying messages. Connected clients get a `maintenance` WebSocket frame
+//! when it is turned on or off and on connect; `/health` and
+//! `/api/v1/capabilities` carry it too.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest maintenance notice in characters
+pub const MAX_MAINTENANCE_MESSAGE_LEN: usize = 500;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Maintenance {
+    /// Unix seconds the server is expected back; unknown when `None`
+    #[serde(default)]
+    pub ends_at: Option<i64>,
+    /// Whether messages are still relayed between connected clients
+    #[serde(default)]
+    pub relay_messages: bool,
+    /// Operator's note shown to users, e.g. what is being upgraded
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maintenance_defaults() {
+        let maintenance: Maintenance = serde_json::from_str(r#"{"ends_at":200}"#).unwrap();
+        assert_eq!(maintenance.ends_at, Some(200));
+        assert!(!maintenance.relay_messages);
+        assert!(maintenance.message.is_none());
+    }
+}
@@ -0,0 +1,143 @@
+//! Notifications shown by every client
+//!
+//! Clients build a [`Notification`] from an incoming message or call and
+//! hand it to their platform's [`NotificationSink`]. Title and body come
+//! from here so what a notification reveals under each [`PreviewPolicy`]
+//! is the same everywhere.
+
+use crate::preview_text;
+use serde::{Deserialize, Serialize};
+
+/// Longest message preview, in grapheme clusters
+pub const NOTIFICATION_PREVIEW_LEN: usize = 100;
+
+/// How much of an incoming message a notification may reveal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewPolicy {
+    /// Sender and message text
+    #[default]
+    Full,
+    /// Sender only
+    SenderOnly,
+    /// Neither; only that something arrived
+    Hidden,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationKind {
+    Message,
+    IncomingCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    /// Conversation to open when the notification is clicked
+    pub conversation_id: String,
+    pub sender_id: String,
+    pub sender_name: Option<String>,
+    /// Message text, already cut down; `None` unless the policy allows it
+    pub preview: Option<String>,
+    pub policy: PreviewPolicy,
+    /// A message rule asked for this one to stand out
+    pub highlighted: bool,
+}
+
+impl Notification {
+    /// Notification for a message whose readable form is `text`
+    pub fn message(
+        conversation_id: &str,
+        sender_id: &str,
+        sender_name: Option<&str>,
+        text: &str,
+        policy: PreviewPolicy,
+    ) -> Self {
+        let preview = (policy == PreviewPolicy::Full && !text.is_empty())
+            .then(|| preview_text(text, NOTIFICATION_PREVIEW_LEN).into_owned());
+        Self {
+            kind: NotificationKind::Message,
+            conversation_id: conversation_id.to_string(),
+            sender_id: sender_id.to_string(),
+            sender_name: sender_name.map(str::to_string),
+            preview,
+            policy,
+            highlighted: false,
+        }
+    }
+
+    pub fn incoming_call(sender_id: &str, sender_name: Option<&str>, policy: PreviewPolicy) -> Self {
+        Self {
+            kind: NotificationKind::IncomingCall,
+            conversation_id: sender_id.to_string(),
+            sender_id: sender_id.to_string(),
+            sender_name: sender_name.map(str::to_string),
+            preview: None,
+            policy,
+            highlighted: false,
+        }
+    }
+
+    /// Mark the notification as wanted by a highlight rule
+    pub fn highlighted(mut self) -> Self {
+        self.highlighted = true;
+        self
+    }
+
+    /// Display name, falling back to the user id
+    pub fn sender_label(&self) -> &str {
+        self.sender_name.as_deref().unwrap_or(&self.sender_id)
+    }
+
+    pub fn title(&self) -> String {
+        match (self.kind, self.policy) {
+            (NotificationKind::Message, PreviewPolicy::Hidden) => "PrivMsg".to_string(),
+            (NotificationKind::Message, _) => format!("Message from {}", self.sender_label()),
+            (NotificationKind::IncomingCall, _) => "Incoming call".to_string(),
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match (self.kind, self.policy) {
+            (NotificationKind::Message, _) => {
+                self.preview.clone().unwrap_or_else(|| "New message".to_string())
+            }
+            (NotificationKind::IncomingCall, PreviewPolicy::Hidden) => "Someone is calling".to_string(),
+            (NotificationKind::IncomingCall, _) => format!("Call from {}", self.sender_label()),
+        }
+    }
+}
+
+/// Where a platform shows notifications
+pub trait NotificationSink: Send + Sync {
+    fn notify(&self, notification: &Notification);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview_policy() {
+        let text = "see you at eight";
+        let full = Notification::message("bob", "bob", Some("Bob"), text, PreviewPolicy::Full);
+        assert_eq!(full.title(), "Message from Bob");
+        assert_eq!(full.body(), text);
+
+        let sender_only = Notification::message("bob", "bob", None, text, PreviewPolicy::SenderOnly);
+        assert_eq!(sender_only.preview, None);
+        assert_eq!(sender_only.title(), "Message from bob");
+        assert_eq!(sender_only.body(), "New message");
+
+        let hidden = Notification::message("bob", "bob", Some("Bob"), text, PreviewPolicy::Hidden);
+        assert!(!hidden.title().contains("Bob"));
+        assert!(!hidden.body().contains("eight"));
+
+        let call = Notification::incoming_call("bob", Some("Bob"), PreviewPolicy::Hidden);
+        assert!(!call.body().contains("Bob"));
+
+        let long = "word ".repeat(100);
+        let preview = Notification::message("bob", "bob", None, &long, PreviewPolicy::Full).body();
+        assert!(preview.ends_with("...") && preview.len() <= NOTIFICATION_PREVIEW_LEN);
+    }
+}
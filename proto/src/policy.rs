@@ -0,0 +1,149 @@
+//! Client policy set by the server operator
+//!
+//! Organizations pre-set client defaults and lock some of them. The server
+//! signs the policy with an Ed25519 key; clients pin that key the first time
+//! they see it (or take it from their configuration) and ignore documents
+//! signed by anything else.
+//!
+//! The signature covers [`SIGNING_CONTEXT`] followed by the policy JSON
+//! exactly as sent, so clients verify the bytes before parsing them.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{notification::PreviewPolicy, LastSeenVisibility};
+
+/// Prefix of the bytes a policy signature covers, so the key can't be made
+/// to sign anything else
+pub const SIGNING_CONTEXT: &[u8] = b"privmsg-client-policy-v1\0";
+
+/// What clients can be told to switch off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Calls,
+    FileTransfer,
+    /// Files sent peer to peer instead of through the server
+    DirectTransfer,
+    /// Delivery to peers on the same network without the server
+    LanDelivery,
+    /// A feature added by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+/// Settings a policy can pre-set and lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicySetting {
+    DisappearingTimer,
+    NotificationPreview,
+    LastSeenVisibility,
+    Endpoints,
+    #[serde(other)]
+    Unknown,
+}
+
+impl PolicySetting {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PolicySetting::DisappearingTimer => "Disappearing messages",
+            PolicySetting::NotificationPreview => "Notification previews",
+            PolicySetting::LastSeenVisibility => "Last seen",
+            PolicySetting::Endpoints => "Server addresses",
+            PolicySetting::Unknown => "Unknown setting",
+        }
+    }
+}
+
+/// Defaults and constraints for every client of a server
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientPolicy {
+    /// Raised by the operator on every change; clients never go back to an
+    /// older version
+    pub version: u64,
+    /// Unix seconds
+    pub issued_at: i64,
+    /// Timer new conversations start with
+    #[serde(default)]
+    pub disappearing_seconds: Option<u64>,
+    #[serde(default)]
+    pub notification_preview: Option<PreviewPolicy>,
+    #[serde(default)]
+    pub last_seen_visibility: Option<LastSeenVisibility>,
+    /// Server base URLs to use, most preferred first
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    #[serde(default)]
+    pub disabled_features: Vec<Feature>,
+    /// Settings above that users may not change; the others are defaults
+    #[serde(default)]
+    pub locked: Vec<PolicySetting>,
+    /// Feature flags by name (see [`crate::flags`]); these win over the
+    /// client's own settings
+    #[serde(default)]
+    pub flags: BTreeMap<String, bool>,
+}
+
+impl ClientPolicy {
+    pub fn allows(&self, feature: Feature) -> bool {
+        !self.disabled_features.contains(&feature)
+    }
+
+    pub fn is_locked(&self, setting: PolicySetting) -> bool {
+        self.locked.contains(&setting)
+    }
+}
+
+/// A policy as served, with its signature. Fields are standard base64
+/// except `policy`, the signed JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedPolicy {
+    pub policy: String,
+    pub signature: String,
+    /// Ed25519 public key the policy was signed with
+    pub public_key: String,
+}
+
+impl SignedPolicy {
+    /// The bytes the signature covers
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        signing_bytes(&self.policy)
+    }
+}
+
+/// The bytes a signature over `policy_json` covers
+pub fn signing_bytes(policy_json: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(SIGNING_CONTEXT.len() + policy_json.len());
+    bytes.extend_from_slice(SIGNING_CONTEXT);
+    bytes.extend_from_slice(policy_json.as_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_document() {
+        let json = r#"{"version":3,"issued_at":1,"notification_preview":"hidden",
+            "disabled_features":["calls","teleport"],"locked":["notification_preview","wallpaper"]}"#;
+        let policy: ClientPolicy = serde_json::from_str(json).unwrap();
+
+        assert_eq!(policy.notification_preview, Some(PreviewPolicy::Hidden));
+        assert!(!policy.allows(Feature::Calls));
+        assert!(policy.allows(Feature::FileTransfer));
+        // Newer servers' names don't break older clients
+        assert_eq!(policy.disabled_features[1], Feature::Unknown);
+        assert!(policy.is_locked(PolicySetting::NotificationPreview));
+        assert!(!policy.is_locked(PolicySetting::Endpoints));
+
+        let signed = SignedPolicy {
+            policy: json.to_string(),
+            signature: String::new(),
+            public_key: String::new(),
+        };
+        assert!(signed.signing_bytes().starts_with(SIGNING_CONTEXT));
+        assert!(signed.signing_bytes().ends_with(json.as_bytes()));
+    }
+}
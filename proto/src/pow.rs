@@ -0,0 +1,87 @@
+//! Login proof of work
+//!
+//! A server facing credential stuffing can require a proof of work with
+//! each login. The client fetches a [`LoginChallenge`] from
+//! `/api/v1/auth/challenge`, finds a nonce with [`solve`] and sends the
+//! [`LoginProof`] as the login request's `proof`. A person logging in waits
+//! a moment; a bot trying many keys pays for every attempt.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Hardest difficulty a server may ask for, so a misconfigured one can't
+/// keep clients busy forever
+pub const MAX_DIFFICULTY: u8 = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginChallenge {
+    /// Opaque and single-use
+    pub challenge: String,
+    /// Leading zero bits the proof's hash needs; each one doubles the work
+    pub difficulty: u8,
+    /// Unix seconds; log in before then
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginProof {
+    pub challenge: String,
+    pub nonce: u64,
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Whether SHA-256 of the challenge followed by the little-endian nonce
+/// starts with `difficulty` zero bits
+pub fn verify(challenge: &str, nonce: u64, difficulty: u8) -> bool {
+    let hash = Sha256::new()
+        .chain_update(challenge.as_bytes())
+        .chain_update(nonce.to_le_bytes())
+        .finalize();
+    leading_zero_bits(&hash) >= u32::from(difficulty)
+}
+
+/// Find a proof for `challenge`. Takes about `2^difficulty` hashes, so run
+/// it off the UI thread.
+pub fn solve(challenge: &LoginChallenge) -> LoginProof {
+    let difficulty = challenge.difficulty.min(MAX_DIFFICULTY);
+    let nonce = (0..=u64::MAX)
+        .find(|nonce| verify(&challenge.challenge, *nonce, difficulty))
+        .unwrap_or_default();
+    LoginProof {
+        challenge: challenge.challenge.clone(),
+        nonce,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_proof() {
+        assert_eq!(leading_zero_bits(&[0, 0, 0x10, 0xff]), 19);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+
+        let challenge = LoginChallenge {
+            challenge: "c2VjcmV0".to_string(),
+            difficulty: 12,
+            expires_at: 0,
+        };
+        let proof = solve(&challenge);
+        assert!(verify(&proof.challenge, proof.nonce, 12));
+        assert!(verify(&proof.challenge, proof.nonce, 0));
+        // The first nonce that works
+        assert!((0..proof.nonce).all(|n| !verify(&proof.challenge, n, 12)));
+    }
+}
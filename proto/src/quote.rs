@@ -0,0 +1,78 @@
+//! Quoted replies
+//!
+//! A reply carries a [`Quote`] of the message it answers inside the
+//! encrypted payload, under `quote`. The quote holds a preview of the text
+//! so it can be shown even where the original isn't stored, e.g. by a
+//! device that joined later.
+
+use serde::{Deserialize, Serialize};
+
+use crate::preview_text;
+
+/// Longest quoted preview in grapheme clusters
+pub const MAX_QUOTE_TEXT_LEN: usize = 120;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quote {
+    pub message_id: String,
+    /// Who wrote the quoted message
+    pub sender_id: String,
+    /// Where the quoted message is, when that isn't the conversation the
+    /// reply goes to: a reply sent privately to someone who wrote in a
+    /// group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    /// Preview of the quoted text, at most [`MAX_QUOTE_TEXT_LEN`]
+    #[serde(default)]
+    pub text: String,
+}
+
+impl Quote {
+    pub fn new(message_id: &str, sender_id: &str, text: &str) -> Self {
+        Self {
+            message_id: message_id.to_string(),
+            sender_id: sender_id.to_string(),
+            conversation_id: None,
+            text: preview_text(text, MAX_QUOTE_TEXT_LEN).into_owned(),
+        }
+    }
+
+    /// The quote as sent in `conversation_id`: the original's conversation
+    /// is kept only when it is another one
+    pub fn sent_in(mut self, conversation_id: &str) -> Self {
+        if self.conversation_id.as_deref() == Some(conversation_id) {
+            self.conversation_id = None;
+        }
+        self
+    }
+
+    /// Whether this quotes a message from another conversation
+    pub fn is_private_reply(&self) -> bool {
+        self.conversation_id.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        let long = "word ".repeat(100);
+        let quote = Quote::new("m1", "bob", &long);
+        assert!(quote.text.ends_with("..."));
+        assert!(quote.text.chars().count() <= MAX_QUOTE_TEXT_LEN);
+
+        let quote = Quote {
+            conversation_id: Some("team".to_string()),
+            ..Quote::new("m1", "bob", "lunch?")
+        };
+        assert!(quote.clone().sent_in("bob").is_private_reply());
+        assert!(!quote.clone().sent_in("team").is_private_reply());
+
+        let json = serde_json::to_value(Quote::new("m1", "bob", "hi")).unwrap();
+        assert!(json.get("conversation_id").is_none());
+        let parsed: Quote = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.text, "hi");
+    }
+}
@@ -0,0 +1,146 @@
+//! User-defined rules for incoming messages
+//!
+//! A rule matches on sender, keyword and message type and acts on the
+//! conversation the message arrived in. Each client keeps its own list and
+//! evaluates it with [`matching_actions`], so a rule behaves the same on
+//! every platform.
+
+use serde::{Deserialize, Serialize};
+
+/// What a matching rule does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Mute the conversation
+    Mute,
+    /// Move the conversation out of the main list
+    Archive,
+    /// Mark the conversation read up to the message
+    MarkRead,
+    /// Notify even if the conversation is muted, flagged so the platform
+    /// can use a distinct sound or style
+    Highlight,
+}
+
+impl RuleAction {
+    pub const ALL: [RuleAction; 4] = [
+        RuleAction::Mute,
+        RuleAction::Archive,
+        RuleAction::MarkRead,
+        RuleAction::Highlight,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            RuleAction::Mute => "Mute",
+            RuleAction::Archive => "Archive",
+            RuleAction::MarkRead => "Mark read",
+            RuleAction::Highlight => "Highlight",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageRule {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Only messages from this user
+    #[serde(default)]
+    pub sender_id: Option<String>,
+    /// Only messages whose text or caption contains this, ignoring case
+    #[serde(default)]
+    pub keyword: Option<String>,
+    /// Only messages of this `message_type`, e.g. "image"
+    #[serde(default)]
+    pub message_type: Option<String>,
+    pub actions: Vec<RuleAction>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl MessageRule {
+    /// Whether the rule has at least one condition; a rule without any
+    /// would act on every message
+    pub fn has_conditions(&self) -> bool {
+        self.sender_id.is_some() || self.keyword.is_some() || self.message_type.is_some()
+    }
+
+    /// Whether an incoming message meets every condition of the rule
+    pub fn matches(&self, sender_id: &str, message_type: &str, text: &str) -> bool {
+        self.enabled
+            && self.has_conditions()
+            && self.sender_id.as_deref().is_none_or(|s| s == sender_id)
+            && self.message_type.as_deref().is_none_or(|t| t == message_type)
+            && self
+                .keyword
+                .as_deref()
+                .is_none_or(|k| text.to_lowercase().contains(&k.to_lowercase()))
+    }
+}
+
+/// Actions of all rules that match, in rule order and without duplicates
+pub fn matching_actions(
+    rules: &[MessageRule],
+    sender_id: &str,
+    message_type: &str,
+    text: &str,
+) -> Vec<RuleAction> {
+    let mut actions = Vec::new();
+    for rule in rules.iter().filter(|r| r.matches(sender_id, message_type, text)) {
+        for action in &rule.actions {
+            if !actions.contains(action) {
+                actions.push(*action);
+            }
+        }
+    }
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(sender_id: Option<&str>, keyword: Option<&str>, actions: &[RuleAction]) -> MessageRule {
+        MessageRule {
+            id: "r".into(),
+            name: "rule".into(),
+            enabled: true,
+            sender_id: sender_id.map(str::to_string),
+            keyword: keyword.map(str::to_string),
+            message_type: None,
+            actions: actions.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_matching_actions() {
+        let rules = vec![
+            rule(Some("bot"), None, &[RuleAction::Mute, RuleAction::MarkRead]),
+            rule(None, Some("URGENT"), &[RuleAction::Highlight, RuleAction::Mute]),
+        ];
+
+        assert_eq!(
+            matching_actions(&rules, "bot", "text", "urgent: disk full"),
+            vec![RuleAction::Mute, RuleAction::MarkRead, RuleAction::Highlight]
+        );
+        assert_eq!(matching_actions(&rules, "bob", "text", "hi"), vec![]);
+        assert_eq!(
+            matching_actions(&rules, "bob", "image", "Urgent"),
+            vec![RuleAction::Highlight, RuleAction::Mute]
+        );
+
+        // Neither disabled rules nor rules without conditions match
+        let mut disabled = rule(Some("bot"), None, &[RuleAction::Archive]);
+        disabled.enabled = false;
+        assert!(!disabled.matches("bot", "text", ""));
+        assert!(!rule(None, None, &[RuleAction::Archive]).matches("bot", "text", ""));
+
+        let json = r#"{"id":"r","name":"n","sender_id":"bot","actions":["mark_read"]}"#;
+        let parsed: MessageRule = serde_json::from_str(json).unwrap();
+        assert!(parsed.enabled && parsed.matches("bot", "file", ""));
+    }
+}
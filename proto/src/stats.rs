@@ -0,0 +1,156 @@
+//! Conversation statistics computed on the device
+//!
+//! Clients feed [`ConversationStats::compute`] the timestamps and directions
+//! of a conversation's messages from their local store; nothing about them
+//! leaves the device. Content is never looked at.
+
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// A reply later than this after the message it answers starts a new
+/// exchange and doesn't count as a response time
+pub const MAX_RESPONSE_SECONDS: i64 = SECONDS_PER_DAY;
+
+/// What statistics need to know about one message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageSample {
+    /// Unix milliseconds
+    pub timestamp: i64,
+    pub is_outgoing: bool,
+}
+
+/// Messages on one day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DayCount {
+    /// Days since 1970-01-01 in the local time zone
+    pub day: i64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationStats {
+    pub sent: u64,
+    pub received: u64,
+    /// Days with messages, oldest first
+    pub messages_per_day: Vec<DayCount>,
+    /// Messages by local hour of the day
+    pub by_hour: [u64; 24],
+    /// Median seconds the user took to answer the peer
+    pub my_response_seconds: Option<i64>,
+    /// Median seconds the peer took to answer the user
+    pub their_response_seconds: Option<i64>,
+}
+
+impl ConversationStats {
+    /// Statistics of `samples`, which must be sorted by timestamp. Days and
+    /// hours are local to `utc_offset_seconds` east of UTC.
+    pub fn compute(samples: &[MessageSample], utc_offset_seconds: i64) -> Self {
+        let mut stats = Self::default();
+        let mut mine = Vec::new();
+        let mut theirs = Vec::new();
+        // The first message of the run the next reply answers
+        let mut unanswered: Option<MessageSample> = None;
+
+        for sample in samples {
+            if sample.is_outgoing {
+                stats.sent += 1;
+            } else {
+                stats.received += 1;
+            }
+
+            let local = sample.timestamp.div_euclid(1000) + utc_offset_seconds;
+            let day = local.div_euclid(SECONDS_PER_DAY);
+            match stats.messages_per_day.last_mut() {
+                Some(last) if last.day == day => last.count += 1,
+                _ => stats.messages_per_day.push(DayCount { day, count: 1 }),
+            }
+            stats.by_hour[(local.rem_euclid(SECONDS_PER_DAY) / 3600) as usize] += 1;
+
+            match unanswered {
+                Some(first) if first.is_outgoing != sample.is_outgoing => {
+                    let seconds = (sample.timestamp - first.timestamp) / 1000;
+                    let responses = if sample.is_outgoing { &mut mine } else { &mut theirs };
+                    if seconds <= MAX_RESPONSE_SECONDS {
+                        responses.push(seconds);
+                    }
+                    unanswered = Some(*sample);
+                }
+                Some(_) => {}
+                None => unanswered = Some(*sample),
+            }
+        }
+
+        stats.my_response_seconds = median(&mut mine);
+        stats.their_response_seconds = median(&mut theirs);
+        stats
+    }
+
+    pub fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+
+    /// The local hour with the most messages
+    pub fn busiest_hour(&self) -> Option<u8> {
+        (0..24u8)
+            .filter(|&hour| self.by_hour[hour as usize] > 0)
+            .max_by_key(|&hour| (self.by_hour[hour as usize], std::cmp::Reverse(hour)))
+    }
+}
+
+fn median(values: &mut [i64]) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    Some(values[values.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(seconds: i64, is_outgoing: bool) -> MessageSample {
+        MessageSample {
+            timestamp: seconds * 1000,
+            is_outgoing,
+        }
+    }
+
+    #[test]
+    fn test_conversation_stats() {
+        let hour = 3600;
+        let samples = [
+            sample(9 * hour, false),
+            sample(9 * hour + 30, false),
+            // Answers the first of the two
+            sample(9 * hour + 120, true),
+            sample(9 * hour + 180, false),
+            sample(SECONDS_PER_DAY + 20 * hour, true),
+            // Too late to count as an answer
+            sample(3 * SECONDS_PER_DAY, false),
+        ];
+
+        let stats = ConversationStats::compute(&samples, 0);
+        assert_eq!((stats.sent, stats.received, stats.total()), (2, 4, 6));
+        assert_eq!(
+            stats.messages_per_day,
+            vec![
+                DayCount { day: 0, count: 4 },
+                DayCount { day: 1, count: 1 },
+                DayCount { day: 3, count: 1 },
+            ]
+        );
+        assert_eq!(stats.by_hour[9], 4);
+        assert_eq!(stats.busiest_hour(), Some(9));
+        assert_eq!(stats.my_response_seconds, Some(120));
+        assert_eq!(stats.their_response_seconds, Some(60));
+
+        // Ten hours west of UTC the first message falls on the previous day
+        let stats = ConversationStats::compute(&samples[..1], -10 * hour);
+        assert_eq!(stats.messages_per_day, vec![DayCount { day: -1, count: 1 }]);
+        assert_eq!(stats.by_hour[23], 1);
+
+        assert_eq!(ConversationStats::compute(&[], 0).busiest_hour(), None);
+    }
+}
@@ -0,0 +1,247 @@
+//! Streamed attachment encryption
+//!
+//! Attachments are encrypted with AES-256-GCM in `FILE_CHUNK_SIZE` chunks
+//! (the STREAM construction), so neither side holds a whole file in memory.
+//! The format is a magic, a random nonce prefix, then the sealed chunks.
+//! Each chunk's nonce is the prefix, a big-endian chunk counter and a flag
+//! set on the last chunk, so reordering and truncation fail
+//! authentication. Every client reads and writes files through this
+//! module, so they agree on the format.
+
+use std::io::{self, Read, Write};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, Nonce};
+use rand::RngCore;
+
+/// Plaintext bytes per encrypted chunk
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// First bytes of a streamed file
+pub const STREAM_MAGIC: &[u8] = b"PMS1";
+
+const STREAM_PREFIX_LEN: usize = 7;
+
+/// Magic and nonce prefix
+pub const STREAM_HEADER_LEN: usize = 4 + STREAM_PREFIX_LEN;
+
+/// GCM tag length, added to every chunk
+pub const TAG_LEN: usize = 16;
+
+/// Size of the encrypted form of `plaintext_len` bytes
+pub fn encrypted_len(plaintext_len: usize) -> usize {
+    // An empty file still has one (empty) final chunk
+    let chunks = plaintext_len.div_ceil(FILE_CHUNK_SIZE).max(1);
+    STREAM_HEADER_LEN + plaintext_len + chunks * TAG_LEN
+}
+
+/// Per-chunk nonce: random prefix, big-endian chunk counter, last-chunk flag
+fn chunk_nonce(prefix: &[u8; STREAM_PREFIX_LEN], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..STREAM_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[STREAM_PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Writer adapter that encrypts in `FILE_CHUNK_SIZE` chunks
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    prefix: [u8; STREAM_PREFIX_LEN],
+    counter: u32,
+    buf: Vec<u8>,
+    header_written: bool,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Encrypt everything written to `inner` under `cipher`, with a fresh
+    /// nonce prefix; call `finish` when done
+    pub fn new(inner: W, cipher: Aes256Gcm) -> Self {
+        let mut prefix = [0u8; STREAM_PREFIX_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut prefix);
+
+        Self {
+            inner,
+            cipher,
+            prefix,
+            counter: 0,
+            buf: Vec::with_capacity(FILE_CHUNK_SIZE),
+            header_written: false,
+        }
+    }
+
+    /// The inner writer, e.g. to take the chunks sealed so far out of a
+    /// `Vec` while more is written
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Encrypt the final chunk and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        if !self.header_written {
+            self.inner.write_all(STREAM_MAGIC)?;
+            self.inner.write_all(&self.prefix)?;
+            self.header_written = true;
+        }
+
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), self.buf.as_slice())
+            .map_err(|_| invalid_data("Chunk encryption failed"))?;
+        self.inner.write_all(&sealed)?;
+
+        self.buf.clear();
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("File too large"))?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full chunk is only sealed once more data arrives, since the
+        // final chunk must carry the last-chunk flag
+        if self.buf.len() == FILE_CHUNK_SIZE && !data.is_empty() {
+            self.seal_chunk(false)?;
+        }
+
+        let n = data.len().min(FILE_CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader adapter that decrypts and authenticates chunk by chunk
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    prefix: [u8; STREAM_PREFIX_LEN],
+    counter: u32,
+    /// Ciphertext read ahead of the current chunk
+    sealed: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Read the header of a streamed file from `inner`. Tampering,
+    /// reordering and truncation surface as `InvalidData` read errors.
+    pub fn new(mut inner: R, cipher: Aes256Gcm) -> io::Result<Self> {
+        let mut header = [0u8; STREAM_HEADER_LEN];
+        inner.read_exact(&mut header)?;
+        if !header.starts_with(STREAM_MAGIC) {
+            return Err(invalid_data("Not a streamed file"));
+        }
+
+        let mut prefix = [0u8; STREAM_PREFIX_LEN];
+        prefix.copy_from_slice(&header[STREAM_MAGIC.len()..]);
+
+        Ok(Self {
+            inner,
+            cipher,
+            prefix,
+            counter: 0,
+            sealed: Vec::with_capacity(FILE_CHUNK_SIZE + TAG_LEN + 1),
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn open_next_chunk(&mut self) -> io::Result<()> {
+        // Read one byte past a full chunk to learn whether it is the last
+        let want = FILE_CHUNK_SIZE + TAG_LEN + 1;
+        while self.sealed.len() < want {
+            let start = self.sealed.len();
+            self.sealed.resize(want, 0);
+            let n = self.inner.read(&mut self.sealed[start..])?;
+            self.sealed.truncate(start + n);
+            if n == 0 {
+                break;
+            }
+        }
+
+        let last = self.sealed.len() < want;
+        let len = self.sealed.len().min(FILE_CHUNK_SIZE + TAG_LEN);
+        if len < TAG_LEN {
+            return Err(invalid_data("Encrypted file is truncated"));
+        }
+
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), &self.sealed[..len])
+            .map_err(|_| invalid_data("Chunk authentication failed"))?;
+        self.sealed.drain(..len);
+        self.pos = 0;
+        self.done = last;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("File too large"))?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_next_chunk()?;
+        }
+
+        let n = out.len().min(self.plain.len() - self.pos);
+        out[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::KeyInit;
+
+    #[test]
+    fn test_stream_round_trip() {
+        let cipher = Aes256Gcm::new_from_slice(&[7u8; 32]).unwrap();
+        for len in [0, 100, FILE_CHUNK_SIZE, 2 * FILE_CHUNK_SIZE + 100] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let mut writer = EncryptingWriter::new(Vec::new(), cipher.clone());
+            writer.write_all(&data).unwrap();
+            let encrypted = writer.finish().unwrap();
+            assert_eq!(encrypted.len(), encrypted_len(len));
+
+            let mut plaintext = Vec::new();
+            DecryptingReader::new(encrypted.as_slice(), cipher.clone())
+                .unwrap()
+                .read_to_end(&mut plaintext)
+                .unwrap();
+            assert_eq!(plaintext, data);
+
+            // Dropping the last byte fails the final chunk
+            let mut truncated = DecryptingReader::new(&encrypted[..encrypted.len() - 1], cipher.clone()).unwrap();
+            assert!(truncated.read_to_end(&mut Vec::new()).is_err());
+        }
+    }
+}
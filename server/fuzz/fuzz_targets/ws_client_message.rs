@@ -0,0 +1,14 @@
+//! Parse arbitrary bytes as a `WsClientMessage`; anything that parses must survive
+//! a round trip through JSON.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use privmsg_server::models::WsClientMessage;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = serde_json::from_slice::<WsClientMessage>(data) {
+        let json = serde_json::to_vec(&msg).expect("parsed message re-serializes");
+        serde_json::from_slice::<WsClientMessage>(&json).expect("serialized message parses");
+    }
+});
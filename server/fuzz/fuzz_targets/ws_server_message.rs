@@ -0,0 +1,14 @@
+//! Parse arbitrary bytes as a `WsServerMessage`; anything that parses must survive
+//! a round trip through JSON.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use privmsg_server::models::WsServerMessage;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = serde_json::from_slice::<WsServerMessage>(data) {
+        let json = serde_json::to_vec(&msg).expect("parsed message re-serializes");
+        serde_json::from_slice::<WsServerMessage>(&json).expect("serialized message parses");
+    }
+});
@@ -0,0 +1,212 @@
+//! Proof of work on login
+//!
+//! With `[login_challenge]` enabled, clients fetch a challenge before
+//! logging in and send a proof with the login; see
+//! [`privmsg_proto::pow`]. Challenges are single-use and kept in memory, so
+//! behind a load balancer a client must log in at the server that issued
+//! its challenge.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use privmsg_proto::pow::{self, LoginChallenge, LoginProof, MAX_DIFFICULTY};
+use rand::RngCore;
+
+use crate::config::LoginChallengeConfig;
+use crate::error::{AppError, Result};
+
+/// Outstanding challenges before new ones are refused, so requesting them
+/// in a loop can't exhaust memory
+const MAX_OUTSTANDING: usize = 10_000;
+
+pub struct LoginChallenges {
+    enabled: bool,
+    difficulty: u8,
+    ttl: Duration,
+    exempt: Vec<(IpAddr, u8)>,
+    issued: DashMap<String, Instant>,
+}
+
+impl LoginChallenges {
+    pub fn new(config: &LoginChallengeConfig) -> anyhow::Result<Self> {
+        if config.difficulty > MAX_DIFFICULTY {
+            anyhow::bail!(
+                "login_challenge.difficulty is {}, clients give up above {}",
+                config.difficulty,
+                MAX_DIFFICULTY
+            );
+        }
+        let exempt = config
+            .exempt
+            .iter()
+            .map(|entry| {
+                parse_cidr(entry)
+                    .ok_or_else(|| anyhow::anyhow!("login_challenge.exempt: {:?} is not an address or range", entry))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            enabled: config.enabled && config.difficulty > 0,
+            difficulty: config.difficulty,
+            ttl: Duration::from_secs(config.ttl_seconds),
+            exempt,
+            issued: DashMap::new(),
+        })
+    }
+
+    /// Whether a login from `ip` needs a proof
+    pub fn required_for(&self, ip: IpAddr) -> bool {
+        self.enabled && !self.exempt.iter().any(|(net, bits)| in_range(ip, *net, *bits))
+    }
+
+    /// A challenge for a login from `ip`; None when it needs no proof
+    pub fn issue(&self, ip: IpAddr) -> Result<Option<LoginChallenge>> {
+        if !self.required_for(ip) {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        if self.issued.len() >= MAX_OUTSTANDING {
+            self.issued.retain(|_, expires| *expires > now);
+            if self.issued.len() >= MAX_OUTSTANDING {
+                return Err(AppError::RateLimited);
+            }
+        }
+
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let challenge = hex::encode(bytes);
+        self.issued.insert(challenge.clone(), now + self.ttl);
+        Ok(Some(LoginChallenge {
+            challenge,
+            difficulty: self.difficulty,
+            expires_at: chrono::Utc::now().timestamp() + self.ttl.as_secs() as i64,
+        }))
+    }
+
+    /// Check the proof sent with a login from `ip`, using up its challenge
+    pub fn check(&self, ip: IpAddr, proof: Option<&LoginProof>) -> Result<()> {
+        if !self.required_for(ip) {
+            return Ok(());
+        }
+        let proof = proof.ok_or(AppError::ChallengeRequired)?;
+        let unexpired = self
+            .issued
+            .remove(&proof.challenge)
+            .is_some_and(|(_, expires)| expires > Instant::now());
+        if unexpired && pow::verify(&proof.challenge, proof.nonce, self.difficulty) {
+            Ok(())
+        } else {
+            tracing::warn!("Rejected login proof from {}", ip);
+            Err(AppError::ChallengeRequired)
+        }
+    }
+}
+
+/// `10.0.0.0/8`, or a single address
+fn parse_cidr(entry: &str) -> Option<(IpAddr, u8)> {
+    let (addr, bits) = match entry.trim().split_once('/') {
+        Some((addr, bits)) => (addr.parse::<IpAddr>().ok()?, bits.parse::<u8>().ok()?),
+        None => {
+            let addr = entry.trim().parse::<IpAddr>().ok()?;
+            (addr, if addr.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if bits > max {
+        return None;
+    }
+    // ::ffff:a.b.c.d/104 covers the same clients as a.b.c.d/8
+    match addr {
+        IpAddr::V6(v6) if bits >= 96 => match v6.to_ipv4_mapped() {
+            Some(v4) => Some((IpAddr::V4(v4), bits - 96)),
+            None => Some((addr, bits)),
+        },
+        _ => Some((addr, bits)),
+    }
+}
+
+fn in_range(ip: IpAddr, net: IpAddr, bits: u8) -> bool {
+    // IPv4 clients on a dual-stack socket show up as ::ffff:a.b.c.d
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => net_contains(IpAddr::V4(v4), net, bits) || net_contains(ip, net, bits),
+            None => net_contains(ip, net, bits),
+        },
+        v4 => net_contains(v4, net, bits),
+    }
+}
+
+fn net_contains(ip: IpAddr, net: IpAddr, bits: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(bits)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(bits)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_challenges() {
+        let config = LoginChallengeConfig {
+            enabled: true,
+            difficulty: 8,
+            ttl_seconds: 60,
+            exempt: vec!["10.0.0.0/8".to_string(), "2001:db8::1".to_string()],
+        };
+        let challenges = LoginChallenges::new(&config).unwrap();
+        let public: IpAddr = "203.0.113.7".parse().unwrap();
+
+        for exempt in ["10.1.2.3", "::ffff:10.9.9.9", "2001:db8::1"] {
+            let ip = exempt.parse().unwrap();
+            assert!(challenges.issue(ip).unwrap().is_none(), "{}", exempt);
+            assert!(challenges.check(ip, None).is_ok());
+        }
+
+        assert!(matches!(challenges.check(public, None), Err(AppError::ChallengeRequired)));
+        let challenge = challenges.issue(public).unwrap().unwrap();
+        assert_eq!(challenge.difficulty, 8);
+        let proof = pow::solve(&challenge);
+        challenges.check(public, Some(&proof)).unwrap();
+        // Single use
+        assert!(challenges.check(public, Some(&proof)).is_err());
+
+        // A proof for a challenge this server never issued
+        let forged = pow::solve(&LoginChallenge {
+            challenge: "made-up".to_string(),
+            ..challenge
+        });
+        assert!(challenges.check(public, Some(&forged)).is_err());
+
+        // Exempt ranges written in IPv4-mapped form match both forms of a client
+        let mapped = LoginChallenges::new(&LoginChallengeConfig {
+            exempt: vec!["::ffff:10.0.0.0/104".to_string(), "::/0".to_string()],
+            ..config.clone()
+        })
+        .unwrap();
+        for exempt in ["10.1.2.3", "::ffff:10.9.9.9", "::ffff:203.0.113.7"] {
+            assert!(!mapped.required_for(exempt.parse().unwrap()), "{}", exempt);
+        }
+        assert!(mapped.required_for(public));
+        assert_eq!(
+            parse_cidr("::ffff:192.168.0.0/112"),
+            Some(("192.168.0.0".parse().unwrap(), 16))
+        );
+
+        let bad = LoginChallengeConfig {
+            exempt: vec!["10.0.0.0/33".to_string()],
+            ..config.clone()
+        };
+        assert!(LoginChallenges::new(&bad).is_err());
+        let disabled = LoginChallenges::new(&LoginChallengeConfig::default()).unwrap();
+        assert!(!disabled.required_for(public));
+    }
+}
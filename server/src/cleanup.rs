@@ -0,0 +1,143 @@
+//! Removal of expired messages, files and sessions
+//!
+//! Runs on a jittered interval and on demand from the admin API. Servers
+//! that share a database coordinate through a lock row, so only one of
+//! them cleans at a time.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::models::{CleanupMetrics, CleanupReport, CleanupTrigger};
+use crate::storage::Storage;
+
+const LOCK_NAME: &str = "cleanup";
+
+/// Longer than any cleanup run should take; a holder that dies mid-run
+/// blocks the others for at most this long
+const LOCK_TTL_MINUTES: i64 = 10;
+
+pub struct CleanupService {
+    storage: Arc<Storage>,
+    /// Identifies this server as the lock holder
+    holder: String,
+    metrics: Mutex<CleanupMetrics>,
+}
+
+impl CleanupService {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self {
+            storage,
+            holder: uuid::Uuid::new_v4().to_string(),
+            metrics: Mutex::new(CleanupMetrics::default()),
+        }
+    }
+
+    pub fn metrics(&self) -> CleanupMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Clean up now. Returns `None` if another server is already cleaning.
+    pub async fn run(&self, trigger: CleanupTrigger) -> anyhow::Result<Option<CleanupReport>> {
+        let ttl = chrono::Duration::minutes(LOCK_TTL_MINUTES);
+        if !self.storage.try_acquire_lock(LOCK_NAME, &self.holder, ttl).await? {
+            self.metrics.lock().unwrap().skipped += 1;
+            return Ok(None);
+        }
+
+        let started_at = chrono::Utc::now().to_rfc3339();
+        let start = Instant::now();
+        let result = self.storage.cleanup_expired().await;
+
+        if let Err(e) = self.storage.release_lock(LOCK_NAME, &self.holder).await {
+            tracing::warn!("Releasing cleanup lock failed: {}", e);
+        }
+
+        let removed = match result {
+            Ok(removed) => removed,
+            Err(e) => {
+                self.metrics.lock().unwrap().failures += 1;
+                return Err(e);
+            }
+        };
+
+        let report = CleanupReport {
+            trigger,
+            started_at,
+            duration_ms: start.elapsed().as_millis() as u64,
+            removed,
+        };
+
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.runs += 1;
+        metrics.removed.add(&removed);
+        metrics.last_run = Some(report.clone());
+
+        Ok(Some(report))
+    }
+
+    /// Run every `interval`, each time after a random delay of up to `jitter`
+    pub fn spawn(self: Arc<Self>, interval: Duration, jitter: Duration) {
+        tokio::spawn(async move {
+            loop {
+                let delay = rand::thread_rng().gen_range(Duration::ZERO..=jitter);
+                tokio::time::sleep(delay).await;
+
+                match self.run(CleanupTrigger::Scheduled).await {
+                    Ok(Some(report)) if !report.removed.is_empty() => {
+                        let removed = report.removed;
+                        tracing::info!(
+                            "Cleanup: removed {} messages, {} files, {} sessions",
+                            removed.messages,
+                            removed.files,
+                            removed.sessions
+                        );
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => tracing::debug!("Cleanup skipped, another server holds the lock"),
+                    Err(e) => tracing::error!("Cleanup failed: {}", e),
+                }
+                if let Err(e) = self.storage.record_daily_stats().await {
+                    tracing::error!("Recording daily stats failed: {}", e);
+                }
+
+                tokio::time::sleep(interval.saturating_sub(delay)).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cleanup_lock_is_exclusive() {
+        let path = std::env::temp_dir().join(format!("privmsg-cleanup-{}.db", uuid::Uuid::new_v4()));
+        let storage = Arc::new(Storage::new(path.to_str().unwrap()).await.unwrap());
+        let ttl = chrono::Duration::minutes(1);
+
+        assert!(storage.try_acquire_lock(LOCK_NAME, "a", ttl).await.unwrap());
+        assert!(!storage.try_acquire_lock(LOCK_NAME, "b", ttl).await.unwrap());
+        assert!(storage.try_acquire_lock(LOCK_NAME, "a", ttl).await.unwrap());
+
+        // A server that finds the lock taken skips its run
+        let service = CleanupService::new(Arc::clone(&storage));
+        assert!(service.run(CleanupTrigger::Manual).await.unwrap().is_none());
+
+        storage.release_lock(LOCK_NAME, "a").await.unwrap();
+        let report = service.run(CleanupTrigger::Manual).await.unwrap().unwrap();
+        assert!(report.removed.is_empty());
+
+        let metrics = service.metrics();
+        assert_eq!((metrics.runs, metrics.skipped, metrics.failures), (1, 1, 0));
+        assert_eq!(metrics.last_run.unwrap().trigger, CleanupTrigger::Manual);
+
+        // Expired locks can be taken over
+        assert!(storage.try_acquire_lock(LOCK_NAME, "a", -ttl).await.unwrap());
+        assert!(storage.try_acquire_lock(LOCK_NAME, "b", ttl).await.unwrap());
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,6 +1,15 @@
 //! Configuration management for PrivMsg Server
+//!
+//! Settings come from the config file, then `PRIVMSG__*` environment
+//! variables, then `--set` flags, each layer overriding the one before.
+//! Both name a field by its section and key: `PRIVMSG__SERVER__PORT=9000`
+//! or `--set server.port=9000`.
 
+use privmsg_proto::notification::PreviewPolicy;
+use privmsg_proto::policy::{Feature, PolicySetting};
+use privmsg_proto::LastSeenVisibility;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
 use tokio::fs;
 
@@ -12,6 +21,12 @@ pub struct Config {
     pub turn: TurnConfig,
     pub admin: AdminConfig,
     pub limits: LimitsConfig,
+    #[serde(default)]
+    pub flood: FloodConfig,
+    #[serde(default)]
+    pub login_challenge: LoginChallengeConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,7 +41,29 @@ pub struct StorageConfig {
     pub files_path: String,
     pub max_message_age_hours: u64,
     pub max_file_age_hours: u64,
+    /// Extensions can keep a file at most this long after its upload
+    #[serde(default = "default_max_file_lifetime_hours")]
+    pub max_file_lifetime_hours: u64,
     pub cleanup_interval_minutes: u64,
+    /// Each cleanup run is delayed by a random amount up to this, so servers
+    /// sharing a database don't all start cleaning at the same moment
+    #[serde(default = "default_cleanup_jitter_seconds")]
+    pub cleanup_jitter_seconds: u64,
+    /// Accept attachment uploads; when off, clients hide file sending
+    #[serde(default = "default_file_uploads")]
+    pub file_uploads: bool,
+}
+
+fn default_max_file_lifetime_hours() -> u64 {
+    720 // 30 days
+}
+
+fn default_cleanup_jitter_seconds() -> u64 {
+    300
+}
+
+fn default_file_uploads() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,7 +84,11 @@ pub struct TurnConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AdminConfig {
+    /// Break-glass key for admin CLI commands when no authenticator is at hand
     pub master_key: String,
+    /// Base32 TOTP secret; when set, admin CLI commands prompt for a code
+    #[serde(default)]
+    pub totp_secret: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,9 +97,292 @@ pub struct LimitsConfig {
     pub max_message_size_kb: u64,
     pub max_pending_messages: u64,
     pub rate_limit_messages_per_minute: u64,
+    /// Seconds an unauthenticated WebSocket may stay open before it is closed
+    #[serde(default = "default_ws_auth_timeout_seconds")]
+    pub ws_auth_timeout_seconds: u64,
+    /// Maximum envelopes per `message_batch` frame when replaying pending messages
+    #[serde(default = "default_ws_batch_size")]
+    pub ws_batch_size: usize,
+    /// Frames queued per WebSocket before presence/typing updates are
+    /// dropped and messages wait for the client to catch up
+    #[serde(default = "default_ws_queue_capacity")]
+    pub ws_queue_capacity: usize,
+}
+
+fn default_ws_auth_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_ws_batch_size() -> usize {
+    200
+}
+
+fn default_ws_queue_capacity() -> usize {
+    256
+}
+
+/// Escalation for clients over `limits.rate_limit_messages_per_minute`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FloodConfig {
+    /// How long a flooding connection's frames are ignored
+    pub mute_seconds: u64,
+    /// Mutes within `strike_window_minutes` before the user is banned
+    pub mutes_before_ban: u32,
+    pub strike_window_minutes: u64,
+    pub ban_minutes: u64,
+    /// User bans from one address within `strike_window_minutes` before
+    /// the address itself is banned
+    pub bans_before_ip_ban: u32,
+    pub ip_ban_hours: u64,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            mute_seconds: 60,
+            mutes_before_ban: 3,
+            strike_window_minutes: 15,
+            ban_minutes: 30,
+            bans_before_ip_ban: 3,
+            ip_ban_hours: 24,
+        }
+    }
+}
+
+/// Proof of work asked of clients logging in, against credential stuffing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginChallengeConfig {
+    pub enabled: bool,
+    /// Leading zero bits; each one doubles the work, 20 takes a phone about
+    /// a second
+    pub difficulty: u8,
+    /// How long a challenge can be used
+    pub ttl_seconds: u64,
+    /// Addresses and CIDR ranges that log in without a proof, e.g.
+    /// `10.0.0.0/8`
+    pub exempt: Vec<String>,
+}
+
+impl Default for LoginChallengeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            difficulty: 20,
+            ttl_seconds: 120,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// Client defaults served signed at `/api/v1/client-policy`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    pub enabled: bool,
+    /// PKCS#8 Ed25519 key; generated on first start if missing. Clients pin
+    /// its public key, so keep it with the database backups.
+    pub signing_key_path: String,
+    /// Raise on every change; clients ignore versions they've seen
+    pub version: u64,
+    pub disappearing_seconds: Option<u64>,
+    pub notification_preview: Option<PreviewPolicy>,
+    pub last_seen_visibility: Option<LastSeenVisibility>,
+    pub endpoints: Vec<String>,
+    pub disabled_features: Vec<Feature>,
+    /// Settings users may not change
+    pub locked: Vec<PolicySetting>,
+    /// Feature flags to turn on or off in clients, by name
+    pub flags: BTreeMap<String, bool>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            signing_key_path: "./data/policy_signing_key.pk8".to_string(),
+            version: 1,
+            disappearing_seconds: None,
+            notification_preview: None,
+            last_seen_visibility: None,
+            endpoints: Vec::new(),
+            disabled_features: Vec::new(),
+            locked: Vec::new(),
+            flags: BTreeMap::new(),
+        }
+    }
+}
+
+/// Environment variables starting with this override config fields
+pub const ENV_PREFIX: &str = "PRIVMSG__";
+
+/// One field set from outside the config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Override {
+    /// Where it came from, for errors: the variable or flag
+    pub source: String,
+    /// Section and field names, e.g. `["server", "port"]`
+    pub path: Vec<String>,
+    pub value: String,
+}
+
+impl Override {
+    /// `PRIVMSG__SECTION__FIELD`; other variables are `None`
+    pub fn from_env(name: &str, value: &str) -> Option<Self> {
+        let key = name.strip_prefix(ENV_PREFIX)?;
+        Some(Self {
+            source: name.to_string(),
+            path: key.split("__").map(str::to_lowercase).collect(),
+            value: value.to_string(),
+        })
+    }
+
+    /// `section.field=value`, as given to `--set`
+    pub fn from_flag(flag: &str) -> anyhow::Result<Self> {
+        let (key, value) = flag
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("--set {}: expected KEY=VALUE", flag))?;
+        Ok(Self {
+            source: format!("--set {}", key),
+            path: key.split('.').map(|s| s.trim().to_string()).collect(),
+            value: value.to_string(),
+        })
+    }
+
+    /// Set the field in `config`, typed like the value already there
+    fn apply(&self, config: &mut toml::Value) -> Result<(), String> {
+        let (field, sections) = match self.path.split_last() {
+            Some((field, sections)) if !field.is_empty() && sections.iter().all(|s| !s.is_empty()) => {
+                (field, sections)
+            }
+            _ => return Err("expected a section and a field".to_string()),
+        };
+        let mut table = config.as_table_mut().ok_or("config is not a table")?;
+        for section in sections {
+            table = table
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| format!("{} is not a section", section))?;
+        }
+        let value = match table.get(field) {
+            Some(toml::Value::String(_)) => toml::Value::String(self.value.clone()),
+            Some(toml::Value::Integer(_)) => self
+                .value
+                .trim()
+                .parse()
+                .map(toml::Value::Integer)
+                .map_err(|_| format!("expected a whole number, got {:?}", self.value))?,
+            Some(toml::Value::Float(_)) => self
+                .value
+                .trim()
+                .parse()
+                .map(toml::Value::Float)
+                .map_err(|_| format!("expected a number, got {:?}", self.value))?,
+            Some(toml::Value::Boolean(_)) => self
+                .value
+                .trim()
+                .parse()
+                .map(toml::Value::Boolean)
+                .map_err(|_| format!("expected true or false, got {:?}", self.value))?,
+            // A TOML array, or a comma-separated list of strings
+            Some(toml::Value::Array(_)) => match parse_literal(&self.value) {
+                Some(array @ toml::Value::Array(_)) => array,
+                _ => toml::Value::Array(
+                    self.value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(|s| toml::Value::String(s.to_string()))
+                        .collect(),
+                ),
+            },
+            Some(toml::Value::Table(_)) => return Err(format!("{} is a section; set its fields", field)),
+            // Unset optional fields
+            _ => parse_literal(&self.value).unwrap_or_else(|| toml::Value::String(self.value.clone())),
+        };
+        table.insert(field.clone(), value);
+        Ok(())
+    }
+
+    fn is_set_in(&self, config: &toml::Value) -> bool {
+        self.path
+            .iter()
+            .try_fold(config, |value, key| value.get(key))
+            .is_some()
+    }
+}
+
+/// `raw` as a TOML value, e.g. `42`, `true` or `["a", "b"]`
+fn parse_literal(raw: &str) -> Option<toml::Value> {
+    toml::from_str::<toml::Table>(&format!("v = {}", raw))
+        .ok()?
+        .remove("v")
 }
 
 impl Config {
+    /// Load `path`, then apply `PRIVMSG__*` environment variables and then
+    /// `--set` flags
+    pub async fn load_layered(path: &str, flags: &[String]) -> anyhow::Result<Self> {
+        let config = Self::load(path).await?;
+        let mut overrides: Vec<Override> = std::env::vars()
+            .filter_map(|(name, value)| Override::from_env(&name, &value))
+            .collect();
+        // Environment order is arbitrary; make it stable
+        overrides.sort_by(|a, b| a.source.cmp(&b.source));
+        for flag in flags {
+            overrides.push(Override::from_flag(flag)?);
+        }
+        config.with_overrides(&overrides)
+    }
+
+    /// This config with `overrides` applied in order. Every override that
+    /// doesn't fit is listed in the error, by its variable or flag.
+    pub fn with_overrides(self, overrides: &[Override]) -> anyhow::Result<Self> {
+        if overrides.is_empty() {
+            return Ok(self);
+        }
+        let base = toml::Value::try_from(&self)?;
+        let mut merged = base.clone();
+        let mut errors: Vec<String> = overrides
+            .iter()
+            .filter_map(|o| o.apply(&mut merged).err().map(|e| format!("{}: {}", o.source, e)))
+            .collect();
+
+        if errors.is_empty() {
+            match merged.try_into::<Config>() {
+                Ok(config) => {
+                    // Fields serde doesn't know are dropped on the way through
+                    let known = toml::Value::try_from(&config)?;
+                    errors.extend(
+                        overrides
+                            .iter()
+                            .filter(|o| !o.is_set_in(&known))
+                            .map(|o| format!("{}: no such setting", o.source)),
+                    );
+                    if errors.is_empty() {
+                        return Ok(config);
+                    }
+                }
+                // Out of range, or an unset field given the wrong type; find
+                // the culprits by trying each override on its own
+                Err(e) => {
+                    errors.extend(overrides.iter().filter_map(|o| {
+                        let mut alone = base.clone();
+                        o.apply(&mut alone).ok()?;
+                        let e = alone.try_into::<Config>().err()?;
+                        Some(format!("{}: {}", o.source, e.message().trim()))
+                    }));
+                    if errors.is_empty() {
+                        errors.push(e.message().trim().to_string());
+                    }
+                }
+            }
+        }
+        anyhow::bail!("Invalid configuration:\n  {}", errors.join("\n  "))
+    }
+
     pub async fn load(path: &str) -> anyhow::Result<Self> {
         if Path::new(path).exists() {
             let content = fs::read_to_string(path).await?;
@@ -86,7 +410,10 @@ impl Default for Config {
                 files_path: "./data/files".to_string(),
                 max_message_age_hours: 168, // 7 days
                 max_file_age_hours: 72,     // 3 days
+                max_file_lifetime_hours: default_max_file_lifetime_hours(),
                 cleanup_interval_minutes: 60,
+                cleanup_jitter_seconds: default_cleanup_jitter_seconds(),
+                file_uploads: default_file_uploads(),
             },
             tls: None,
             turn: TurnConfig {
@@ -102,13 +429,80 @@ impl Default for Config {
             },
             admin: AdminConfig {
                 master_key: "CHANGE-THIS-ADMIN-KEY-IMMEDIATELY".to_string(),
+                totp_secret: None,
             },
             limits: LimitsConfig {
                 max_file_size_mb: 100,
                 max_message_size_kb: 64,
                 max_pending_messages: 10000,
                 rate_limit_messages_per_minute: 120,
+                ws_auth_timeout_seconds: default_ws_auth_timeout_seconds(),
+                ws_batch_size: default_ws_batch_size(),
+                ws_queue_capacity: default_ws_queue_capacity(),
             },
+            flood: FloodConfig::default(),
+            login_challenge: LoginChallengeConfig::default(),
+            policy: PolicyConfig::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<Override> {
+        vars.iter()
+            .filter_map(|(name, value)| Override::from_env(name, value))
+            .collect()
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let mut overrides = env(&[
+            ("PRIVMSG__SERVER__PORT", "9000"),
+            ("PRIVMSG__TURN__ENABLED", "true"),
+            ("PRIVMSG__TURN__URLS", "turn:a.example:3478, turn:b.example:3478"),
+            ("PRIVMSG__TLS__CERT_PATH", "/certs/cert.pem"),
+            ("PRIVMSG__TLS__KEY_PATH", "/certs/key.pem"),
+            ("RUST_LOG", "debug"),
+        ]);
+        assert_eq!(overrides.len(), 5);
+        // Flags come last and win
+        overrides.push(Override::from_flag("server.port=9443").unwrap());
+
+        let config = Config::default().with_overrides(&overrides).unwrap();
+        assert_eq!(config.server.port, 9443);
+        assert!(config.turn.enabled);
+        assert_eq!(config.turn.urls, ["turn:a.example:3478", "turn:b.example:3478"]);
+        assert_eq!(config.tls.unwrap().key_path, "/certs/key.pem");
+        assert!(Override::from_flag("server.port").is_err());
+    }
+
+    #[test]
+    fn test_invalid_overrides_are_all_reported() {
+        let overrides = env(&[
+            ("PRIVMSG__SERVER__PORT", "eighty"),
+            ("PRIVMSG__STORAGE__CLEANUP_INTERVAL_MINUTES", "70000000000000000000"),
+            ("PRIVMSG__SERVER__NOPE", "1"),
+            ("PRIVMSG__SERVER", "x"),
+        ]);
+        let error = Config::default().with_overrides(&overrides).unwrap_err().to_string();
+        assert!(error.contains("PRIVMSG__SERVER__PORT: expected a whole number"), "{}", error);
+        assert!(error.contains("PRIVMSG__STORAGE__CLEANUP_INTERVAL_MINUTES"), "{}", error);
+        assert!(error.contains("PRIVMSG__SERVER: server is a section"), "{}", error);
+
+        // Fields serde would ignore are caught too
+        let error = Config::default()
+            .with_overrides(&env(&[("PRIVMSG__SERVER__NOPE", "1")]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("PRIVMSG__SERVER__NOPE: no such setting"), "{}", error);
+
+        let error = Config::default()
+            .with_overrides(&env(&[("PRIVMSG__SERVER__PORT", "70000")]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Invalid configuration:\n  PRIVMSG__SERVER__PORT:"), "{}", error);
+    }
+}
@@ -50,14 +50,18 @@ pub fn hash_access_key(key: &str) -> String {
 /// Verify an access key against a stored hash
 pub fn verify_access_key(key: &str, hash: &str) -> bool {
     let computed_hash = hash_access_key(key);
-    // Constant-time comparison using constant length comparison
-    if computed_hash.len() != hash.len() {
+    constant_time_eq(&computed_hash, hash)
+}
+
+/// Compare two secrets without short-circuiting on the first mismatch
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
         return false;
     }
-    computed_hash.as_bytes()
+    a.as_bytes()
         .iter()
-        .zip(hash.as_bytes().iter())
-        .fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+        .zip(b.as_bytes().iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 /// Generate a session token
@@ -0,0 +1,384 @@
+//! `privmsg-server doctor`: checks a deployment before (or after) it starts
+//!
+//! Each check reports what it found and, when something is off, what to do
+//! about it. With `fix` set, doctor also repairs what it safely can: missing
+//! data directories, an uninitialized or outdated database, and a missing
+//! TLS certificate (self-signed, for testing only).
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use privmsg_proto::turn_address;
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+use crate::storage::{Storage, SCHEMA_VERSION};
+
+/// How long a TURN server gets to accept a TCP connection
+const TURN_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Free space below this is worth a warning
+const LOW_DISK_SPACE_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+    /// Was broken and `fix` repaired it
+    Fixed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Ok => " OK ",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+            Status::Fixed => "FIXD",
+        })
+    }
+}
+
+/// Outcome of one check
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about a warning or failure
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: Status::Ok, detail: detail.into(), hint: None }
+    }
+
+    fn fixed(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, status: Status::Fixed, detail: detail.into(), hint: None }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name, status: Status::Warn, detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name, status: Status::Fail, detail: detail.into(), hint: Some(hint.into()) }
+    }
+}
+
+/// Run every check, repairing what can be repaired when `fix` is set
+pub async fn run(config: &Config, fix: bool) -> Vec<Check> {
+    let mut checks = check_secrets(config);
+
+    let database_dir = Path::new(&config.storage.database_path)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+    checks.push(check_dir("database directory", &database_dir, fix).await);
+    checks.push(check_dir("files directory", Path::new(&config.storage.files_path), fix).await);
+    checks.push(check_database(&config.storage.database_path, fix).await);
+    checks.push(check_disk_space(config));
+    checks.push(check_tls(config, fix).await);
+    checks.extend(check_turn(config).await);
+
+    checks
+}
+
+fn check_secrets(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    if config.admin.master_key.starts_with("CHANGE-THIS") || config.admin.master_key.len() < 16 {
+        checks.push(Check::fail(
+            "admin key",
+            "the master key is the default or too short",
+            "set [admin] master_key to the output of `openssl rand -base64 32`",
+        ));
+    } else if config.admin.totp_secret.is_none() {
+        checks.push(Check::warn(
+            "admin key",
+            "admin commands need the master key on the command line",
+            "run `privmsg-server generate-totp-secret` and set [admin] totp_secret",
+        ));
+    } else {
+        checks.push(Check::ok("admin key", "TOTP codes enabled, master key kept for break-glass"));
+    }
+
+    if config.turn.enabled && config.turn.credential.starts_with("change-this") {
+        checks.push(Check::fail(
+            "turn credential",
+            "the TURN credential is the default",
+            "set [turn] credential and static-auth-secret in turnserver.conf to the same random secret",
+        ));
+    }
+
+    checks
+}
+
+/// The directory exists and we can create files in it
+async fn check_dir(name: &'static str, dir: &Path, fix: bool) -> Check {
+    let mut created = false;
+    if !dir.is_dir() {
+        if !fix {
+            return Check::warn(
+                name,
+                format!("{} does not exist", dir.display()),
+                "run `privmsg-server doctor --fix` or create it",
+            );
+        }
+        if let Err(e) = tokio::fs::create_dir_all(dir).await {
+            return Check::fail(
+                name,
+                format!("could not create {}: {}", dir.display(), e),
+                "create it by hand and give the server user write access",
+            );
+        }
+        created = true;
+    }
+
+    let probe = dir.join(format!(".privmsg-doctor-{}", uuid::Uuid::new_v4()));
+    if let Err(e) = tokio::fs::write(&probe, b"").await {
+        return Check::fail(
+            name,
+            format!("{} is not writable: {}", dir.display(), e),
+            "give the user running the server write access to it",
+        );
+    }
+    let _ = tokio::fs::remove_file(&probe).await;
+
+    if created {
+        Check::fixed(name, format!("created {}", dir.display()))
+    } else {
+        Check::ok(name, format!("{} is writable", dir.display()))
+    }
+}
+
+async fn check_database(database_path: &str, fix: bool) -> Check {
+    const NAME: &str = "database schema";
+
+    if !Path::new(database_path).exists() {
+        return if fix {
+            match Storage::new(database_path).await {
+                Ok(_) => Check::fixed(NAME, format!("created {} at schema {}", database_path, SCHEMA_VERSION)),
+                Err(e) => Check::fail(NAME, format!("could not create {}: {}", database_path, e), "check the database directory above"),
+            }
+        } else {
+            Check::warn(
+                NAME,
+                format!("{} does not exist yet", database_path),
+                "it is created on first start, or by `privmsg-server doctor --fix`",
+            )
+        };
+    }
+
+    let version = match Storage::schema_version(database_path).await {
+        Ok(version) => version,
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("could not open {}: {}", database_path, e),
+                "check that it is an SQLite database and readable by the server user",
+            )
+        }
+    };
+
+    if version > SCHEMA_VERSION {
+        Check::fail(
+            NAME,
+            format!("schema {} is newer than this server's {}", version, SCHEMA_VERSION),
+            "upgrade the server binary, or restore a backup made by this version",
+        )
+    } else if version < SCHEMA_VERSION {
+        if !fix {
+            return Check::warn(
+                NAME,
+                format!("schema {} will be upgraded to {} on next start", version, SCHEMA_VERSION),
+                "back up the database first, then start the server or run `doctor --fix`",
+            );
+        }
+        match Storage::new(database_path).await {
+            Ok(_) => Check::fixed(NAME, format!("upgraded schema {} to {}", version, SCHEMA_VERSION)),
+            Err(e) => Check::fail(NAME, format!("upgrade failed: {}", e), "restore a backup and report the error"),
+        }
+    } else {
+        Check::ok(NAME, format!("schema {} is current", version))
+    }
+}
+
+fn check_disk_space(config: &Config) -> Check {
+    const NAME: &str = "disk space";
+
+    // The files directory may not exist yet; measure the nearest ancestor that does
+    let path = nearest_existing(Path::new(&config.storage.files_path));
+    let available = match fs2::available_space(&path) {
+        Ok(available) => available,
+        Err(e) => return Check::warn(NAME, format!("could not read free space on {}: {}", path.display(), e), "check the files directory"),
+    };
+
+    let max_file = config.limits.max_file_size_mb * 1024 * 1024;
+    let detail = format!("{} MB free on {}", available / (1024 * 1024), path.display());
+    if available < max_file {
+        Check::fail(NAME, detail, "uploads of the maximum file size will fail; free up space")
+    } else if available < LOW_DISK_SPACE_BYTES {
+        Check::warn(NAME, detail, "less than 1 GB left; lower max_file_age_hours or add space")
+    } else {
+        Check::ok(NAME, detail)
+    }
+}
+
+fn nearest_existing(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+async fn check_tls(config: &Config, fix: bool) -> Check {
+    const NAME: &str = "tls";
+
+    let Some(tls) = &config.tls else {
+        return Check::ok(NAME, "not configured; terminate TLS at a reverse proxy");
+    };
+
+    let cert_exists = Path::new(&tls.cert_path).exists();
+    let key_exists = Path::new(&tls.key_path).exists();
+    if !cert_exists && !key_exists && fix {
+        return match write_self_signed(&config.server.host, &tls.cert_path, &tls.key_path).await {
+            Ok(()) => Check::fixed(NAME, format!("wrote a self-signed certificate to {} (testing only)", tls.cert_path)),
+            Err(e) => Check::fail(NAME, format!("could not write a self-signed certificate: {}", e), "create the certificate by hand"),
+        };
+    }
+
+    let certs = match std::fs::read(&tls.cert_path) {
+        Ok(pem) => rustls_pemfile::certs(&mut pem.as_slice()).filter_map(Result::ok).count(),
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("cannot read {}: {}", tls.cert_path, e),
+                "fix [tls] cert_path, or run `doctor --fix` for a self-signed test certificate",
+            )
+        }
+    };
+    if certs == 0 {
+        return Check::fail(NAME, format!("{} holds no PEM certificates", tls.cert_path), "point cert_path at the full-chain PEM file");
+    }
+
+    match std::fs::read(&tls.key_path) {
+        Ok(pem) => match rustls_pemfile::private_key(&mut pem.as_slice()) {
+            Ok(Some(_)) => Check::ok(NAME, format!("{} certificate(s) and a private key loaded", certs)),
+            _ => Check::fail(NAME, format!("{} holds no PEM private key", tls.key_path), "point key_path at the private key PEM file"),
+        },
+        Err(e) => Check::fail(NAME, format!("cannot read {}: {}", tls.key_path, e), "fix [tls] key_path"),
+    }
+}
+
+async fn write_self_signed(host: &str, cert_path: &str, key_path: &str) -> anyhow::Result<()> {
+    let mut names = vec!["localhost".to_string()];
+    if !matches!(host, "0.0.0.0" | "::" | "localhost") {
+        names.push(host.to_string());
+    }
+    let cert = rcgen::generate_simple_self_signed(names)?;
+
+    for path in [cert_path, key_path] {
+        if let Some(parent) = Path::new(path).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+    }
+    tokio::fs::write(cert_path, cert.serialize_pem()?).await?;
+    tokio::fs::write(key_path, cert.serialize_private_key_pem()).await?;
+    Ok(())
+}
+
+/// Try a TCP connection to each configured TURN URL. UDP can't be checked
+/// this way, and the server's view of the network may differ from a client's,
+/// so an unreachable server is a warning rather than a failure.
+async fn check_turn(config: &Config) -> Vec<Check> {
+    if !config.turn.enabled {
+        return vec![Check::ok("turn", "disabled")];
+    }
+
+    let mut checks = Vec::new();
+    for url in &config.turn.urls {
+        let Some(addr) = turn_address(url) else {
+            checks.push(Check::fail("turn", format!("cannot parse {}", url), "use turn:host:port or turns:host:port"));
+            continue;
+        };
+        checks.push(match tokio::time::timeout(TURN_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+            Ok(Ok(_)) => Check::ok("turn", format!("{} accepts TCP connections", addr)),
+            Ok(Err(e)) => Check::warn("turn", format!("{}: {}", addr, e), "check that coturn is running and the firewall allows the port"),
+            Err(_) => Check::warn("turn", format!("{}: timed out", addr), "check the firewall and the TURN server's listening address"),
+        });
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_doctor_fix() {
+        let root = std::env::temp_dir().join(format!("privmsg-doctor-{}", uuid::Uuid::new_v4()));
+        let mut config = Config::default();
+        config.storage.database_path = root.join("db/privmsg.db").to_string_lossy().into_owned();
+        config.storage.files_path = root.join("files").to_string_lossy().into_owned();
+        config.tls = Some(crate::config::TlsConfig {
+            cert_path: root.join("tls/cert.pem").to_string_lossy().into_owned(),
+            key_path: root.join("tls/key.pem").to_string_lossy().into_owned(),
+        });
+        config.turn.enabled = false;
+
+        let status = |checks: &[Check], name: &str| checks.iter().find(|c| c.name == name).unwrap().status;
+
+        let checks = run(&config, false).await;
+        assert_eq!(status(&checks, "admin key"), Status::Fail);
+        assert_eq!(status(&checks, "files directory"), Status::Warn);
+        assert_eq!(status(&checks, "database schema"), Status::Warn);
+        assert_eq!(status(&checks, "tls"), Status::Fail);
+        assert!(!root.exists(), "doctor without --fix must not touch the disk");
+
+        let checks = run(&config, true).await;
+        assert_eq!(status(&checks, "files directory"), Status::Fixed);
+        assert_eq!(status(&checks, "database schema"), Status::Fixed);
+        assert_eq!(status(&checks, "tls"), Status::Fixed);
+
+        let checks = run(&config, false).await;
+        assert_eq!(status(&checks, "files directory"), Status::Ok);
+        assert_eq!(status(&checks, "database schema"), Status::Ok);
+        assert_eq!(status(&checks, "tls"), Status::Ok);
+        assert_eq!(Storage::schema_version(&config.storage.database_path).await.unwrap(), SCHEMA_VERSION);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_doctor_upgrades_v1_schema() {
+        let root = std::env::temp_dir().join(format!("privmsg-doctor-{}", uuid::Uuid::new_v4()));
+        let path = &root.join("privmsg.db").to_string_lossy().into_owned();
+
+        // A database as a server from before groups left it
+        drop(Storage::new(path).await.unwrap());
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(&format!("sqlite:{}", path))
+            .await
+            .unwrap();
+        sqlx::query("DROP TABLE group_members; DROP TABLE groups; PRAGMA user_version = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        assert_eq!(check_database(path, false).await.status, Status::Warn);
+        assert_eq!(check_database(path, true).await.status, Status::Fixed);
+        assert_eq!(Storage::schema_version(path).await.unwrap(), SCHEMA_VERSION);
+
+        let storage = Storage::new(path).await.unwrap();
+        assert!(storage.get_group("missing").await.unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -5,6 +5,7 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
+use privmsg_proto::{ErrorCode, ServerError};
 use serde_json::json;
 use thiserror::Error;
 
@@ -25,9 +26,20 @@ pub enum AppError {
     #[error("User already exists")]
     UserAlreadyExists,
 
+    /// The account was deactivated; unlike `NotFound` it existed
+    #[error("{0} is no longer available")]
+    UserUnavailable(String),
+
     #[error("Invalid request: {0}")]
     BadRequest(String),
 
+    /// The login lacked a valid proof of work
+    #[error("Login needs a new proof of work")]
+    ChallengeRequired,
+
+    #[error("Temporarily banned until {0}")]
+    Banned(String),
+
     #[error("Rate limit exceeded")]
     #[allow(dead_code)]
     RateLimited,
@@ -35,6 +47,13 @@ pub enum AppError {
     #[error("File too large")]
     FileTooLarge,
 
+    #[error("Requested range not satisfiable")]
+    RangeNotSatisfiable,
+
+    /// Carries when the server is expected back, in Unix seconds
+    #[error("Server is down for maintenance")]
+    Maintenance(Option<i64>),
+
     #[error("Database error: {0}")]
     Database(#[from] sqlx::Error),
 
@@ -48,33 +67,41 @@ pub enum AppError {
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, error_code, message) = match &self {
-            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "UNAUTHORIZED", self.to_string()),
-            AppError::InvalidCredentials => (StatusCode::UNAUTHORIZED, "INVALID_CREDENTIALS", self.to_string()),
-            AppError::Forbidden => (StatusCode::FORBIDDEN, "FORBIDDEN", self.to_string()),
-            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, "NOT_FOUND", msg.clone()),
-            AppError::UserAlreadyExists => (StatusCode::CONFLICT, "USER_EXISTS", self.to_string()),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, "BAD_REQUEST", msg.clone()),
-            AppError::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "RATE_LIMITED", self.to_string()),
-            AppError::FileTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "FILE_TOO_LARGE", self.to_string()),
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, self.to_string()),
+            AppError::InvalidCredentials => (StatusCode::UNAUTHORIZED, ErrorCode::InvalidCredentials, self.to_string()),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, ErrorCode::Forbidden, self.to_string()),
+            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, ErrorCode::NotFound, msg.clone()),
+            AppError::UserAlreadyExists => (StatusCode::CONFLICT, ErrorCode::UserExists, self.to_string()),
+            AppError::UserUnavailable(_) => (StatusCode::GONE, ErrorCode::RecipientUnavailable, self.to_string()),
+            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, ErrorCode::BadRequest, msg.clone()),
+            AppError::ChallengeRequired => (StatusCode::PRECONDITION_REQUIRED, ErrorCode::ChallengeRequired, self.to_string()),
+            AppError::Banned(_) => (StatusCode::FORBIDDEN, ErrorCode::Forbidden, self.to_string()),
+            AppError::RateLimited => (StatusCode::TOO_MANY_REQUESTS, ErrorCode::RateLimited, self.to_string()),
+            AppError::FileTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge, self.to_string()),
+            AppError::RangeNotSatisfiable => (StatusCode::RANGE_NOT_SATISFIABLE, ErrorCode::RangeNotSatisfiable, self.to_string()),
+            AppError::Maintenance(ends_at) => {
+                let message = match ends_at.and_then(|at| chrono::DateTime::from_timestamp(at, 0)) {
+                    Some(at) => format!("{} until {}", self, at.to_rfc3339()),
+                    None => self.to_string(),
+                };
+                (StatusCode::SERVICE_UNAVAILABLE, ErrorCode::Maintenance, message)
+            }
             AppError::Database(e) => {
                 tracing::error!("Database error: {:?}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "DATABASE_ERROR", "Database error".to_string())
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::DatabaseError, "Database error".to_string())
             }
             AppError::Io(e) => {
                 tracing::error!("IO error: {:?}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "IO_ERROR", "IO error".to_string())
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::IoError, "IO error".to_string())
             }
             AppError::Internal(e) => {
                 tracing::error!("Internal error: {:?}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR", "Internal server error".to_string())
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, "Internal server error".to_string())
             }
         };
 
         let body = Json(json!({
-            "error": {
-                "code": error_code,
-                "message": message
-            }
+            "error": ServerError::new(error_code, message)
         }));
 
         (status, body).into_response()
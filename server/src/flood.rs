@@ -0,0 +1,297 @@
+//! Flood detection for WebSocket connections
+//!
+//! Every frame a client sends counts against its connection's rate. A
+//! connection over `limits.rate_limit_messages_per_minute` is muted for a
+//! while. A user muted too often is banned for a while, and an address whose
+//! users keep getting banned is banned as a whole. Bans are kept in memory;
+//! they are temporary and end with the process.
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::config::{Config, FloodConfig};
+use crate::models::{Ban, BanTarget};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// What to do with a frame a client sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Ignore the frame. `started` is set for the frame that caused the mute,
+    /// so the client is told once.
+    Muted { started: bool, seconds: u64 },
+    /// Close the connection
+    Banned(Ban),
+}
+
+#[derive(Default)]
+struct ConnectionRate {
+    frames: VecDeque<Instant>,
+    muted_until: Option<Instant>,
+}
+
+pub struct FloodGuard {
+    config: FloodConfig,
+    frames_per_minute: usize,
+    /// Keyed by connection, not device, so unauthenticated sockets count too
+    rates: DashMap<String, ConnectionRate>,
+    /// Recent mutes per user
+    user_strikes: DashMap<String, VecDeque<Instant>>,
+    /// Recent bans (or mutes of unauthenticated sockets) per address
+    ip_strikes: DashMap<IpAddr, VecDeque<Instant>>,
+    bans: DashMap<BanTarget, (Ban, Instant)>,
+}
+
+impl FloodGuard {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.flood.clone(),
+            frames_per_minute: config.limits.rate_limit_messages_per_minute.max(1) as usize,
+            rates: DashMap::new(),
+            user_strikes: DashMap::new(),
+            ip_strikes: DashMap::new(),
+            bans: DashMap::new(),
+        }
+    }
+
+    /// The ban keeping `user_id` or `ip` from connecting, if any
+    pub fn check_connect(&self, user_id: Option<&str>, ip: IpAddr) -> Option<Ban> {
+        self.active_ban(user_id, ip, Instant::now())
+    }
+
+    /// Count a frame from connection `connection_id`
+    pub fn check_frame(&self, connection_id: &str, user_id: Option<&str>, ip: IpAddr) -> Verdict {
+        self.check_frame_at(connection_id, user_id, ip, Instant::now())
+    }
+
+    fn check_frame_at(&self, connection_id: &str, user_id: Option<&str>, ip: IpAddr, now: Instant) -> Verdict {
+        if let Some(ban) = self.active_ban(user_id, ip, now) {
+            return Verdict::Banned(ban);
+        }
+
+        let mute = Duration::from_secs(self.config.mute_seconds);
+        {
+            let mut rate = self.rates.entry(connection_id.to_string()).or_default();
+            if let Some(until) = rate.muted_until {
+                if until > now {
+                    return Verdict::Muted {
+                        started: false,
+                        seconds: until.duration_since(now).as_secs(),
+                    };
+                }
+                rate.muted_until = None;
+            }
+
+            rate.frames.push_back(now);
+            while rate.frames.front().is_some_and(|t| now.duration_since(*t) > RATE_WINDOW) {
+                rate.frames.pop_front();
+            }
+            if rate.frames.len() <= self.frames_per_minute {
+                return Verdict::Allow;
+            }
+
+            rate.frames.clear();
+            rate.muted_until = Some(now + mute);
+        }
+
+        tracing::warn!(
+            "Muting flooding connection: user={}, ip={}",
+            user_id.unwrap_or("-"),
+            ip
+        );
+        if let Some(ban) = self.strike(user_id, ip, now) {
+            return Verdict::Banned(ban);
+        }
+        Verdict::Muted {
+            started: true,
+            seconds: mute.as_secs(),
+        }
+    }
+
+    /// Record a mute, escalating to bans when they pile up
+    fn strike(&self, user_id: Option<&str>, ip: IpAddr, now: Instant) -> Option<Ban> {
+        let window = Duration::from_secs(self.config.strike_window_minutes * 60);
+
+        if let Some(user_id) = user_id {
+            let strikes = record(&mut self.user_strikes.entry(user_id.to_string()).or_default(), now, window);
+            if strikes < self.config.mutes_before_ban as usize {
+                return None;
+            }
+            self.user_strikes.remove(user_id);
+            let user_ban = self.ban(
+                BanTarget::User(user_id.to_string()),
+                "Repeated flooding",
+                Duration::from_secs(self.config.ban_minutes * 60),
+                now,
+            );
+            tracing::warn!("Banned user {} for flooding", user_id);
+            return Some(self.strike_ip(ip, now, window).unwrap_or(user_ban));
+        }
+
+        // Nobody to blame but the address
+        self.strike_ip(ip, now, window)
+    }
+
+    fn strike_ip(&self, ip: IpAddr, now: Instant, window: Duration) -> Option<Ban> {
+        let strikes = record(&mut self.ip_strikes.entry(ip).or_default(), now, window);
+        if strikes < self.config.bans_before_ip_ban as usize {
+            return None;
+        }
+        self.ip_strikes.remove(&ip);
+        tracing::warn!("Banned address {} for repeated flooding", ip);
+        Some(self.ban(
+            BanTarget::Ip(ip),
+            "Repeated flooding from this address",
+            Duration::from_secs(self.config.ip_ban_hours * 3600),
+            now,
+        ))
+    }
+
+    fn ban(&self, target: BanTarget, reason: &str, duration: Duration, now: Instant) -> Ban {
+        let created_at = chrono::Utc::now();
+        let ban = Ban {
+            target: target.clone(),
+            reason: reason.to_string(),
+            created_at: created_at.to_rfc3339(),
+            expires_at: (created_at + chrono::Duration::from_std(duration).unwrap_or_default()).to_rfc3339(),
+        };
+        self.bans.insert(target, (ban.clone(), now + duration));
+        ban
+    }
+
+    fn active_ban(&self, user_id: Option<&str>, ip: IpAddr, now: Instant) -> Option<Ban> {
+        let user = user_id.map(|u| BanTarget::User(u.to_string()));
+        [Some(BanTarget::Ip(ip)), user].into_iter().flatten().find_map(|target| {
+            let entry = self.bans.get(&target)?;
+            let (ban, until) = entry.value();
+            if *until > now {
+                return Some(ban.clone());
+            }
+            drop(entry);
+            self.bans.remove(&target);
+            None
+        })
+    }
+
+    /// Drop the rate of a closed connection
+    pub fn forget(&self, connection_id: &str) {
+        self.rates.remove(connection_id);
+    }
+
+    /// Bans still in force
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        self.bans.retain(|_, (_, until)| *until > now);
+        let mut bans: Vec<Ban> = self.bans.iter().map(|entry| entry.value().0.clone()).collect();
+        bans.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        bans
+    }
+
+    /// Lift a ban early. Returns whether there was one.
+    pub fn clear_ban(&self, target: &BanTarget) -> bool {
+        match target {
+            BanTarget::User(user_id) => {
+                self.user_strikes.remove(user_id);
+            }
+            BanTarget::Ip(ip) => {
+                self.ip_strikes.remove(ip);
+            }
+        }
+        self.bans.remove(target).is_some()
+    }
+}
+
+/// Add a strike at `now` and return how many fall within `window`
+fn record(strikes: &mut VecDeque<Instant>, now: Instant, window: Duration) -> usize {
+    strikes.push_back(now);
+    while strikes.front().is_some_and(|t| now.duration_since(*t) > window) {
+        strikes.pop_front();
+    }
+    strikes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> FloodGuard {
+        let mut config = Config::default();
+        config.limits.rate_limit_messages_per_minute = 3;
+        config.flood = FloodConfig {
+            mute_seconds: 10,
+            mutes_before_ban: 2,
+            strike_window_minutes: 10,
+            ban_minutes: 5,
+            bans_before_ip_ban: 2,
+            ip_ban_hours: 1,
+        };
+        FloodGuard::new(&config)
+    }
+
+    /// Send frames until the connection is muted or banned
+    fn flood(guard: &FloodGuard, conn: &str, user: &str, ip: IpAddr, now: Instant) -> Verdict {
+        loop {
+            match guard.check_frame_at(conn, Some(user), ip, now) {
+                Verdict::Allow => {}
+                verdict => return verdict,
+            }
+        }
+    }
+
+    #[test]
+    fn test_mute_and_escalation() {
+        let guard = guard();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(guard.check_frame_at("c1", Some("alice"), ip, start), Verdict::Allow);
+        }
+        assert_eq!(
+            guard.check_frame_at("c1", Some("alice"), ip, start),
+            Verdict::Muted { started: true, seconds: 10 }
+        );
+        assert!(matches!(
+            guard.check_frame_at("c1", Some("alice"), ip, start + Duration::from_secs(5)),
+            Verdict::Muted { started: false, .. }
+        ));
+
+        // A second mute after the first runs out bans the user
+        let later = start + Duration::from_secs(11);
+        let Verdict::Banned(ban) = flood(&guard, "c1", "alice", ip, later) else {
+            panic!("expected a ban");
+        };
+        assert_eq!(ban.target, BanTarget::User("alice".to_string()));
+        assert!(guard.check_connect(Some("alice"), ip).is_some());
+        assert!(guard.check_connect(Some("bob"), ip).is_none());
+
+        // Another banned user from the same address bans the address
+        flood(&guard, "c2", "bob", ip, later);
+        let Verdict::Banned(ban) = flood(&guard, "c2", "bob", ip, later + Duration::from_secs(11)) else {
+            panic!("expected a ban");
+        };
+        assert_eq!(ban.target, BanTarget::Ip(ip));
+        assert!(guard.check_connect(Some("carol"), ip).is_some());
+        assert_eq!(guard.bans().len(), 3);
+
+        assert!(guard.clear_ban(&BanTarget::Ip(ip)));
+        assert!(guard.clear_ban(&BanTarget::User("alice".to_string())));
+        assert!(!guard.clear_ban(&BanTarget::User("alice".to_string())));
+        assert!(guard.check_connect(Some("alice"), ip).is_none());
+        assert!(guard.check_connect(Some("bob"), ip).is_some());
+    }
+
+    #[test]
+    fn test_ban_expiry() {
+        let guard = guard();
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let now = Instant::now();
+        guard.ban(BanTarget::Ip(ip), "test", Duration::from_secs(60), now);
+        assert!(matches!(guard.check_frame_at("c", None, ip, now), Verdict::Banned(_)));
+        assert_eq!(guard.check_frame_at("c", None, ip, now + Duration::from_secs(61)), Verdict::Allow);
+    }
+}
@@ -11,22 +11,23 @@ use crate::{
     AppState,
 };
 
-/// Admin authentication middleware check
-fn verify_admin_key(provided: &str, expected: &str) -> Result<()> {
-    if provided != expected {
-        return Err(AppError::Forbidden);
-    }
-    Ok(())
-}
+use super::AdminUser;
 
-/// Create a new user (admin only)
+/// Create a new user (admin or moderator)
 pub async fn create_user(
     State(state): State<AppState>,
+    admin: AdminUser,
     Json(req): Json<CreateUserRequest>,
 ) -> Result<Json<CreateUserResponse>> {
-    verify_admin_key(&req.admin_key, &state.config.admin.master_key)?;
+    admin.require(Permission::CreateUsers)?;
 
-    let user_id = req.user_id.unwrap_or_else(|| crypto::generate_user_id());
+    // Only role managers can hand out elevated roles
+    let role = req.role.unwrap_or(Role::User);
+    if role != Role::User {
+        admin.require(Permission::ManageRoles)?;
+    }
+
+    let user_id = req.user_id.unwrap_or_else(crypto::generate_user_id);
     let access_key = crypto::generate_access_key();
     let key_hash = crypto::hash_access_key(&access_key);
 
@@ -35,55 +36,236 @@ pub async fn create_user(
         return Err(AppError::UserAlreadyExists);
     }
 
-    state.storage.create_user(&user_id, &key_hash).await?;
+    state.storage.create_user(&user_id, &key_hash, role).await?;
 
-    tracing::info!("Admin created user: {}", user_id);
+    tracing::info!("{} created user: {} (role={})", admin.actor(), user_id, role);
 
     Ok(Json(CreateUserResponse {
         user_id,
         access_key,
+        role,
     }))
 }
 
 /// Delete a user (admin only)
 pub async fn delete_user(
     State(state): State<AppState>,
+    admin: AdminUser,
     Path(user_id): Path<String>,
-    Json(req): Json<AdminKeyRequest>,
 ) -> Result<Json<serde_json::Value>> {
-    verify_admin_key(&req.admin_key, &state.config.admin.master_key)?;
+    admin.require(Permission::DeleteUsers)?;
 
     // Disconnect user if online
-    state.ws_manager.send_to_user(
-        &user_id,
-        WsServerMessage::Error {
-            code: "ACCOUNT_DELETED".to_string(),
-            message: "Your account has been deleted".to_string(),
-        },
-    );
+    state
+        .ws_manager
+        .revoke_user(&user_id, RevocationReason::AccountDeleted);
 
     // Delete user and cascade
     state.storage.delete_user(&user_id).await?;
 
-    tracing::info!("Admin deleted user: {}", user_id);
+    tracing::info!("{} deleted user: {}", admin.actor(), user_id);
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+/// Deactivate a user and end all their sessions (admin only)
+pub async fn deactivate_user(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    admin.require(Permission::DeleteUsers)?;
+
+    if admin.user_id.as_deref() == Some(user_id.as_str()) {
+        return Err(AppError::BadRequest("Cannot deactivate yourself".to_string()));
+    }
+    if state.storage.get_user(&user_id).await?.is_none() {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    state.storage.deactivate_user(&user_id).await?;
+    state
+        .ws_manager
+        .revoke_user(&user_id, RevocationReason::AccountDeactivated);
+
+    tracing::info!("{} deactivated user: {}", admin.actor(), user_id);
 
     Ok(Json(serde_json::json!({ "success": true })))
 }
 
-/// Get server statistics (admin only)
+/// Grant or revoke a role (admin only)
+pub async fn set_user_role(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path(user_id): Path<String>,
+    Json(req): Json<SetRoleRequest>,
+) -> Result<Json<serde_json::Value>> {
+    admin.require(Permission::ManageRoles)?;
+
+    // Prevent admins from locking themselves out
+    if admin.user_id.as_deref() == Some(user_id.as_str()) && req.role != Role::Admin {
+        return Err(AppError::BadRequest("Cannot demote yourself".to_string()));
+    }
+
+    if !state.storage.set_user_role(&user_id, req.role).await? {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    tracing::info!("{} set role of {} to {}", admin.actor(), user_id, req.role);
+
+    Ok(Json(serde_json::json!({
+        "user_id": user_id,
+        "role": req.role
+    })))
+}
+
+/// Get server statistics (admin or moderator)
 pub async fn get_stats(
     State(state): State<AppState>,
-    Json(req): Json<AdminKeyRequest>,
+    admin: AdminUser,
 ) -> Result<Json<ServerStats>> {
-    verify_admin_key(&req.admin_key, &state.config.admin.master_key)?;
+    admin.require(Permission::ViewStats)?;
 
     let mut stats = state.storage.get_stats().await?;
     stats.online_users = state.ws_manager.online_user_count() as i64;
+    stats.ws_queues = state.ws_manager.queue_stats();
 
     Ok(Json(stats))
 }
 
-#[derive(Debug, serde::Deserialize)]
-pub struct AdminKeyRequest {
-    pub admin_key: String,
+/// Cleanup activity of this server (admin or moderator)
+pub async fn get_cleanup_metrics(
+    State(state): State<AppState>,
+    admin: AdminUser,
+) -> Result<Json<CleanupMetrics>> {
+    admin.require(Permission::ViewStats)?;
+
+    Ok(Json(state.cleanup.metrics()))
+}
+
+/// Run cleanup now instead of waiting for the schedule (admin only)
+pub async fn run_cleanup(
+    State(state): State<AppState>,
+    admin: AdminUser,
+) -> Result<Json<serde_json::Value>> {
+    admin.require(Permission::RunCleanup)?;
+
+    let report = state.cleanup.run(CleanupTrigger::Manual).await?;
+
+    tracing::info!("{} triggered cleanup", admin.actor());
+
+    Ok(Json(serde_json::json!({
+        "ran": report.is_some(),
+        "report": report
+    })))
+}
+
+/// Flood bans in force (admin or moderator)
+pub async fn list_bans(
+    State(state): State<AppState>,
+    admin: AdminUser,
+) -> Result<Json<Vec<Ban>>> {
+    admin.require(Permission::ManageBans)?;
+
+    Ok(Json(state.flood.bans()))
+}
+
+/// Publish a service announcement to every client, replacing the current
+/// one (admin only)
+pub async fn set_announcement(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Json(req): Json<SetAnnouncementRequest>,
+) -> Result<Json<Announcement>> {
+    admin.require(Permission::ManageAnnouncements)?;
+
+    let announcement = req
+        .validated(chrono::Utc::now().timestamp())
+        .map_err(AppError::BadRequest)?;
+    state.storage.set_announcement(&announcement).await?;
+    state
+        .ws_manager
+        .broadcast_all(WsServerMessage::Announcement {
+            announcement: Some(announcement.clone()),
+        })
+        .await;
+
+    tracing::info!("{} set announcement {}", admin.actor(), announcement.id);
+
+    Ok(Json(announcement))
+}
+
+/// Take the current announcement down (admin only)
+pub async fn clear_announcement(
+    State(state): State<AppState>,
+    admin: AdminUser,
+) -> Result<Json<serde_json::Value>> {
+    admin.require(Permission::ManageAnnouncements)?;
+
+    if !state.storage.clear_announcement().await? {
+        return Err(AppError::NotFound("No announcement".to_string()));
+    }
+    state
+        .ws_manager
+        .broadcast_all(WsServerMessage::Announcement { announcement: None })
+        .await;
+
+    tracing::info!("{} cleared the announcement", admin.actor());
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+/// Turn maintenance mode on, or update it (admin only)
+pub async fn set_maintenance(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Json(req): Json<SetMaintenanceRequest>,
+) -> Result<Json<Maintenance>> {
+    admin.require(Permission::ManageMaintenance)?;
+
+    let maintenance = req
+        .validated(chrono::Utc::now().timestamp())
+        .map_err(AppError::BadRequest)?;
+    state.maintenance.set(Some(maintenance.clone())).await?;
+
+    tracing::info!("{} turned maintenance mode on", admin.actor());
+
+    Ok(Json(maintenance))
+}
+
+/// Turn maintenance mode off (admin only)
+pub async fn clear_maintenance(
+    State(state): State<AppState>,
+    admin: AdminUser,
+) -> Result<Json<serde_json::Value>> {
+    admin.require(Permission::ManageMaintenance)?;
+
+    if !state.maintenance.set(None).await? {
+        return Err(AppError::NotFound("Maintenance mode is off".to_string()));
+    }
+
+    tracing::info!("{} turned maintenance mode off", admin.actor());
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+/// Lift a flood ban early (admin or moderator)
+pub async fn clear_ban(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path((kind, value)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>> {
+    admin.require(Permission::ManageBans)?;
+
+    let target = BanTarget::parse(&kind, &value)
+        .ok_or_else(|| AppError::BadRequest(format!("Invalid ban target: {}/{}", kind, value)))?;
+    if !state.flood.clear_ban(&target) {
+        return Err(AppError::NotFound(format!("No ban on {}", target)));
+    }
+
+    tracing::info!("{} lifted the ban on {}", admin.actor(), target);
+
+    Ok(Json(serde_json::json!({
+        "cleared": target
+    })))
 }
@@ -1,6 +1,11 @@
 //! Authentication handlers
 
-use axum::{extract::State, Json};
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    Json,
+};
 use crate::{
     crypto,
     error::{AppError, Result},
@@ -10,11 +15,26 @@ use crate::{
 
 use super::AuthUser;
 
+/// A proof-of-work challenge for the next login; null when logins from
+/// this address need none
+pub async fn get_challenge(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Result<Json<Option<LoginChallenge>>> {
+    state.maintenance.check()?;
+    Ok(Json(state.login_challenges.issue(addr.ip())?))
+}
+
 /// Login with user ID and access key
 pub async fn login(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(req): Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>> {
+    state.maintenance.check()?;
+    // Before the credentials, so guessing costs the work
+    state.login_challenges.check(addr.ip(), req.proof.as_ref())?;
+
     // Verify credentials
     let valid = state
         .storage
@@ -61,6 +81,7 @@ pub async fn login(
         device_id,
         expires_at: expires_at.timestamp(),
         user: user.into(),
+        announcement: state.storage.get_announcement().await?,
     }))
 }
 
@@ -105,3 +126,18 @@ pub async fn logout(
 
     Ok(Json(serde_json::json!({ "success": true })))
 }
+
+/// Invalidate every session of the user and disconnect all their devices
+pub async fn logout_all(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<Json<serde_json::Value>> {
+    state.storage.invalidate_all_user_sessions(&auth.user_id).await?;
+    state
+        .ws_manager
+        .revoke_user(&auth.user_id, RevocationReason::LoggedOut);
+
+    tracing::info!("User {} logged out from all devices", auth.user_id);
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
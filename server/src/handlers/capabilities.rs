@@ -0,0 +1,51 @@
+//! Server capabilities handler
+
+use axum::{extract::State, Json};
+use privmsg_proto::capabilities::ServerCapabilities;
+use privmsg_proto::maintenance::Maintenance;
+
+use crate::{config::Config, AppState};
+
+/// Optional features this server has turned on
+pub async fn get_capabilities(State(state): State<AppState>) -> Json<ServerCapabilities> {
+    Json(capabilities(&state.config, state.maintenance.current()))
+}
+
+/// Uploads are off while `maintenance` is on
+pub fn capabilities(config: &Config, maintenance: Option<Maintenance>) -> ServerCapabilities {
+    ServerCapabilities {
+        calls: config.turn.enabled,
+        file_upload: config.storage.file_uploads && maintenance.is_none(),
+        max_file_size: config.limits.max_file_size_mb * 1024 * 1024,
+        maintenance,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_follow_config() {
+        let mut config = Config::default();
+        assert_eq!(
+            capabilities(&config, None),
+            ServerCapabilities {
+                calls: true,
+                file_upload: true,
+                max_file_size: 100 * 1024 * 1024,
+                maintenance: None,
+            }
+        );
+
+        let caps = capabilities(&config, Some(Maintenance::default()));
+        assert!(!caps.file_upload);
+        assert!(caps.maintenance.is_some());
+
+        config.turn.enabled = false;
+        config.storage.file_uploads = false;
+        let caps = capabilities(&config, None);
+        assert!(!caps.calls);
+        assert!(!caps.accepts_file(1));
+    }
+}
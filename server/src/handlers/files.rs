@@ -3,14 +3,16 @@
 use axum::{
     body::Body,
     extract::{Multipart, Path, State},
-    http::{header, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     response::Response,
     Json,
 };
-use chrono::DateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::io::SeekFrom;
 use std::path::PathBuf;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 
 use crate::{
     error::{AppError, Result},
@@ -25,14 +27,88 @@ fn parse_datetime_to_timestamp(s: &str) -> i64 {
         .unwrap_or_else(|_| chrono::Utc::now().timestamp())
 }
 
+/// Parse a stored timestamp, written either as RFC 3339 or by SQLite's
+/// `datetime('now')`
+fn parse_stored_datetime(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .map(|dt| dt.and_utc())
+        })
+}
+
+/// New expiry for a request to keep a file `hours` longer from `now`. Never
+/// earlier than the current expiry, never later than `lifetime_hours` after
+/// the upload.
+fn extended_expiry(
+    created_at: DateTime<Utc>,
+    current: DateTime<Utc>,
+    now: DateTime<Utc>,
+    hours: u64,
+    lifetime_hours: u64,
+) -> DateTime<Utc> {
+    let requested = now + chrono::Duration::hours(hours.min(lifetime_hours) as i64);
+    let limit = created_at + chrono::Duration::hours(lifetime_hours as i64);
+    requested.min(limit).max(current)
+}
+
 use super::AuthUser;
 
+/// The uploader, or a recipient who has downloaded the file
+async fn may_manage_file(state: &AppState, metadata: &FileMetadata, user_id: &str) -> Result<bool> {
+    Ok(metadata.uploader_id == user_id
+        || state.storage.has_downloaded_file(&metadata.file_id, user_id).await?)
+}
+
+/// Resolve a `Range` header against a file of `len` bytes into an inclusive
+/// byte range. Only single ranges are supported; anything else (including a
+/// malformed header) yields `Ok(None)` and the whole file is served.
+fn parse_range(value: &str, len: u64) -> Result<Option<(u64, u64)>> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return Err(AppError::RangeNotSatisfiable),
+            Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+            Err(_) => return Ok(None),
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, len.saturating_sub(1)),
+            Err(_) => return Ok(None),
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return Ok(None),
+        },
+    };
+
+    if range.0 >= len {
+        return Err(AppError::RangeNotSatisfiable);
+    }
+    Ok(Some(range))
+}
+
 /// Upload an encrypted file
 pub async fn upload_file(
     State(state): State<AppState>,
     auth: AuthUser,
     mut multipart: Multipart,
 ) -> Result<Json<FileUploadResponse>> {
+    if !state.config.storage.file_uploads {
+        return Err(AppError::BadRequest("File uploads are disabled on this server".to_string()));
+    }
+    state.maintenance.check()?;
     let max_size = state.config.limits.max_file_size_mb * 1024 * 1024;
     let files_path = PathBuf::from(&state.config.storage.files_path);
 
@@ -124,11 +200,12 @@ pub async fn upload_file(
     }))
 }
 
-/// Download an encrypted file
+/// Download an encrypted file, or a single byte range of it
 pub async fn download_file(
     State(state): State<AppState>,
-    _auth: AuthUser,
+    auth: AuthUser,
     Path(file_id): Path<String>,
+    headers: HeaderMap,
 ) -> Result<Response> {
     // Get file metadata
     let metadata = state
@@ -144,28 +221,96 @@ pub async fn download_file(
         return Err(AppError::NotFound("File not found".to_string()));
     }
 
-    let data = fs::read(&file_path).await?;
+    let mut file = fs::File::open(&file_path).await?;
+    let len = file.metadata().await?.len();
 
-    // Increment download count
-    state.storage.increment_download_count(&file_id).await?;
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(value) => parse_range(value, len)?,
+        None => None,
+    };
 
-    // Build response
-    let response = Response::builder()
-        .status(StatusCode::OK)
+    // Parallel downloads fetch many ranges; count each download once
+    if range.is_none_or(|(start, _)| start == 0) {
+        state.storage.increment_download_count(&file_id).await?;
+    }
+    if auth.user_id != metadata.uploader_id {
+        state.storage.record_file_download(&file_id, &auth.user_id).await?;
+    }
+
+    let mut builder = Response::builder()
         .header(header::CONTENT_TYPE, &metadata.mime_type)
-        .header(header::CONTENT_LENGTH, data.len())
+        .header(header::ACCEPT_RANGES, "bytes")
         .header(
             header::CONTENT_DISPOSITION,
             format!("attachment; filename=\"{}\"", metadata.file_name),
         )
-        .header("X-Encryption-Key-Hash", &metadata.encryption_key_hash)
-        .body(Body::from(data))
+        .header("X-Encryption-Key-Hash", &metadata.encryption_key_hash);
+
+    let body = match range {
+        Some((start, end)) => {
+            builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_LENGTH, end - start + 1)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+
+            file.seek(SeekFrom::Start(start)).await?;
+            Body::from_stream(ReaderStream::new(file.take(end - start + 1)))
+        }
+        None => {
+            builder = builder
+                .status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, len);
+            Body::from_stream(ReaderStream::new(file))
+        }
+    };
+
+    let response = builder
+        .body(body)
         .map_err(|e| AppError::Internal(anyhow::anyhow!("Failed to build response: {}", e)))?;
 
     Ok(response)
 }
 
-/// Delete a file
+/// Keep a file longer, up to `storage.max_file_lifetime_hours` after its
+/// upload. The uploader and recipients who downloaded it may extend it.
+pub async fn extend_file(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(file_id): Path<String>,
+    Json(req): Json<ExtendFileRequest>,
+) -> Result<Json<FileExpiryResponse>> {
+    if req.hours == 0 {
+        return Err(AppError::BadRequest("hours must be positive".to_string()));
+    }
+
+    let metadata = state
+        .storage
+        .get_file_metadata(&file_id)
+        .await?
+        .ok_or(AppError::NotFound("File not found".to_string()))?;
+
+    if !may_manage_file(&state, &metadata, &auth.user_id).await? {
+        return Err(AppError::Forbidden);
+    }
+
+    let now = Utc::now();
+    let expires_at = extended_expiry(
+        parse_stored_datetime(&metadata.created_at).unwrap_or(now),
+        parse_stored_datetime(&metadata.expires_at).unwrap_or(now),
+        now,
+        req.hours,
+        state.config.storage.max_file_lifetime_hours,
+    );
+    state.storage.set_file_expiry(&file_id, expires_at).await?;
+
+    Ok(Json(FileExpiryResponse {
+        file_id,
+        expires_at: expires_at.timestamp(),
+    }))
+}
+
+/// Delete a file. Besides the uploader, recipients who downloaded it may
+/// delete it early.
 pub async fn delete_file(
     State(state): State<AppState>,
     auth: AuthUser,
@@ -178,8 +323,7 @@ pub async fn delete_file(
         .await?
         .ok_or(AppError::NotFound("File not found".to_string()))?;
 
-    // Only uploader can delete
-    if metadata.uploader_id != auth.user_id {
+    if !may_manage_file(&state, &metadata, &auth.user_id).await? {
         return Err(AppError::Forbidden);
     }
 
@@ -194,3 +338,42 @@ pub async fn delete_file(
 
     Ok(Json(serde_json::json!({ "success": true })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000).unwrap(), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000).unwrap(), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000).unwrap(), Some((900, 999)));
+        assert_eq!(parse_range("bytes=990-2000", 1000).unwrap(), Some((990, 999)));
+
+        // Unsupported or malformed ranges fall back to the whole file
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000).unwrap(), None);
+        assert_eq!(parse_range("items=0-1", 1000).unwrap(), None);
+        assert_eq!(parse_range("bytes=9-1", 1000).unwrap(), None);
+
+        assert!(matches!(parse_range("bytes=1000-", 1000), Err(AppError::RangeNotSatisfiable)));
+        assert!(matches!(parse_range("bytes=-0", 1000), Err(AppError::RangeNotSatisfiable)));
+    }
+
+    #[test]
+    fn test_extended_expiry() {
+        let created = parse_stored_datetime("2026-01-01 00:00:00").unwrap();
+        let hour = chrono::Duration::hours(1);
+        let current = created + hour * 72;
+        let now = created + hour * 24;
+
+        assert_eq!(extended_expiry(created, current, now, 100, 720), now + hour * 100);
+        // Shorter than what's left doesn't shorten it
+        assert_eq!(extended_expiry(created, current, now, 1, 720), current);
+        // Capped at the lifetime since upload
+        assert_eq!(extended_expiry(created, current, now, 10_000, 720), created + hour * 720);
+        assert_eq!(
+            parse_stored_datetime("2026-01-01T00:00:00+00:00"),
+            Some(created)
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Group membership handlers
+//!
+//! Only members can see a group or add to it. Anyone can leave; only the
+//! creator can remove others.
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use crate::{
+    error::{AppError, Result},
+    models::*,
+    AppState,
+};
+
+use super::AuthUser;
+
+/// Create a group with the caller as its first member
+pub async fn create_group(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(req): Json<CreateGroupRequest>,
+) -> Result<Json<Group>> {
+    let name = req.validated_name().map_err(AppError::BadRequest)?;
+
+    let mut member_ids = vec![auth.user_id.clone()];
+    for member_id in &req.member_ids {
+        if !member_ids.contains(member_id) {
+            member_ids.push(member_id.clone());
+        }
+    }
+    check_new_members(&state, 0, &member_ids).await?;
+
+    let group = state
+        .storage
+        .create_group(name, &auth.user_id, &member_ids)
+        .await?;
+    Ok(Json(group))
+}
+
+/// Groups the caller is a member of
+pub async fn list_groups(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<Json<Vec<Group>>> {
+    let groups = state.storage.list_user_groups(&auth.user_id).await?;
+    Ok(Json(groups))
+}
+
+pub async fn get_group(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<String>,
+) -> Result<Json<Group>> {
+    Ok(Json(member_group(&state, &auth, &group_id).await?))
+}
+
+pub async fn add_members(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(group_id): Path<String>,
+    Json(req): Json<AddGroupMembersRequest>,
+) -> Result<Json<Group>> {
+    let group = member_group(&state, &auth, &group_id).await?;
+    let mut new_members: Vec<String> = Vec::new();
+    for member_id in req.member_ids {
+        if !group.member_ids.contains(&member_id) && !new_members.contains(&member_id) {
+            new_members.push(member_id);
+        }
+    }
+    check_new_members(&state, group.member_ids.len(), &new_members).await?;
+
+    state.storage.add_group_members(&group_id, &new_members).await?;
+    Ok(Json(member_group(&state, &auth, &group_id).await?))
+}
+
+/// Leave a group, or as its creator remove someone from it
+pub async fn remove_member(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path((group_id, user_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>> {
+    let group = member_group(&state, &auth, &group_id).await?;
+    if user_id != auth.user_id && group.created_by != auth.user_id {
+        return Err(AppError::Forbidden);
+    }
+    if !state.storage.remove_group_member(&group_id, &user_id).await? {
+        return Err(AppError::NotFound("Not a member".to_string()));
+    }
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+/// The group, if the caller is in it. Others get the same answer as for a
+/// group that doesn't exist.
+async fn member_group(state: &AppState, auth: &AuthUser, group_id: &str) -> Result<Group> {
+    state
+        .storage
+        .get_group(group_id)
+        .await?
+        .filter(|group| group.member_ids.contains(&auth.user_id))
+        .ok_or(AppError::NotFound("Group not found".to_string()))
+}
+
+/// Every new member must be an active user, and the group must stay
+/// within `MAX_GROUP_MEMBERS`
+async fn check_new_members(state: &AppState, current: usize, member_ids: &[String]) -> Result<()> {
+    if current + member_ids.len() > MAX_GROUP_MEMBERS {
+        return Err(AppError::BadRequest(format!(
+            "groups have at most {} members",
+            MAX_GROUP_MEMBERS
+        )));
+    }
+    for member_id in member_ids {
+        match state.storage.get_user(member_id).await? {
+            Some(user) if user.is_active => {}
+            _ => return Err(AppError::UserUnavailable(member_id.clone())),
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,17 @@
 //! Health check endpoint
 
-use axum::Json;
+use axum::{extract::State, Json};
 use serde_json::{json, Value};
 
-pub async fn health_check() -> Json<Value> {
+use crate::AppState;
+
+/// Stays `ok` during maintenance so load balancers keep routing connected
+/// clients; `maintenance` is set while it is on
+pub async fn health_check(State(state): State<AppState>) -> Json<Value> {
     Json(json!({
         "status": "ok",
         "version": env!("CARGO_PKG_VERSION"),
-        "timestamp": chrono::Utc::now().timestamp()
+        "timestamp": chrono::Utc::now().timestamp(),
+        "maintenance": state.maintenance.current(),
     }))
 }
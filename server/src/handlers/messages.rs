@@ -1,6 +1,9 @@
 //! Message handlers
 
-use axum::{extract::State, Json};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
 use chrono::DateTime;
 use crate::{
     error::Result,
@@ -37,6 +40,9 @@ pub async fn get_pending_messages(
             encrypted_content: pm.encrypted_content,
             message_type: pm.message_type.into(),
             timestamp: parse_datetime_to_timestamp(&pm.created_at),
+            ttl_seconds: None,
+            priority: pm.priority.into(),
+            delivery_mode: DeliveryMode::StoreAndForward,
         })
         .collect();
 
@@ -46,15 +52,77 @@ pub async fn get_pending_messages(
 /// Acknowledge (delete) received messages
 pub async fn acknowledge_messages(
     State(state): State<AppState>,
-    _auth: AuthUser,
+    auth: AuthUser,
     Json(req): Json<AcknowledgeMessagesRequest>,
 ) -> Result<Json<serde_json::Value>> {
-    state
+    acknowledge(&state, &auth.user_id, &req.message_ids).await?;
+
+    Ok(Json(serde_json::json!({
+        "acknowledged": req.message_ids.len()
+    })))
+}
+
+/// Stop holding a message the caller sent that wasn't delivered yet, e.g.
+/// because they deleted it for everyone
+pub async fn retract_message(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(message_id): Path<String>,
+) -> Result<Json<serde_json::Value>> {
+    let removed = state
         .storage
-        .delete_pending_messages(&req.message_ids)
+        .delete_sent_pending(&auth.user_id, &message_id)
         .await?;
 
     Ok(Json(serde_json::json!({
-        "acknowledged": req.message_ids.len()
+        "removed": removed
     })))
 }
+
+/// Stop holding messages a device of `user_id` received and tell their
+/// senders they were delivered: right away if they are online, otherwise
+/// when they next connect
+pub(crate) async fn acknowledge(state: &AppState, user_id: &str, message_ids: &[String]) -> Result<()> {
+    let delivered = state
+        .storage
+        .delete_pending_messages(user_id, message_ids)
+        .await?;
+
+    let mut by_sender: Vec<(String, Vec<String>)> = Vec::new();
+    for (message_id, sender_id) in delivered {
+        // Syncs between the user's own devices need no receipt
+        if sender_id == user_id {
+            continue;
+        }
+        match by_sender.iter_mut().find(|(id, _)| *id == sender_id) {
+            Some((_, ids)) => ids.push(message_id),
+            None => by_sender.push((sender_id, vec![message_id])),
+        }
+    }
+
+    for (sender_id, message_ids) in by_sender {
+        if state.ws_manager.is_user_online(&sender_id) {
+            state
+                .ws_manager
+                .send_to_user(
+                    &sender_id,
+                    WsServerMessage::Delivered {
+                        recipient_id: user_id.to_string(),
+                        message_ids,
+                    },
+                )
+                .await;
+        } else {
+            state
+                .storage
+                .store_delivery_receipts(
+                    &sender_id,
+                    user_id,
+                    &message_ids,
+                    state.config.storage.max_message_age_hours as i64,
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
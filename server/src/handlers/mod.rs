@@ -2,18 +2,46 @@
 
 pub mod admin;
 pub mod auth;
+pub mod capabilities;
 pub mod files;
+pub mod groups;
 pub mod health;
 pub mod messages;
+pub mod policy;
 pub mod turn;
 pub mod users;
 pub mod websocket;
 
 use axum::{
-    extract::FromRequestParts,
-    http::{header::AUTHORIZATION, request::Parts},
+    body::Body,
+    extract::{FromRequestParts, Request},
+    http::{header::AUTHORIZATION, request::Parts, HeaderValue},
+    middleware::Next,
+    response::Response,
 };
-use crate::{error::AppError, AppState};
+use crate::{
+    error::AppError,
+    models::{Permission, Role},
+    AppState,
+};
+
+/// Header carrying the master key for break-glass admin access
+pub const ADMIN_KEY_HEADER: &str = "x-admin-key";
+
+/// Body field that carried the master key before `X-Admin-Key`
+const LEGACY_ADMIN_KEY_FIELD: &str = "admin_key";
+
+/// Largest admin request body searched for the legacy key
+const LEGACY_BODY_LIMIT: usize = 64 * 1024;
+
+/// Extract the bearer token from the Authorization header
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
 
 /// Authenticated user context extracted from request
 #[derive(Debug, Clone)]
@@ -27,17 +55,8 @@ impl FromRequestParts<AppState> for AuthUser {
     type Rejection = AppError;
 
     async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
-        // Extract Authorization header
-        let auth_header = parts
-            .headers
-            .get(AUTHORIZATION)
-            .and_then(|v| v.to_str().ok())
-            .ok_or(AppError::Unauthorized)?;
-
-        // Parse "Bearer <token>"
-        let token = auth_header
-            .strip_prefix("Bearer ")
-            .ok_or(AppError::Unauthorized)?;
+        // Parse "Authorization: Bearer <token>"
+        let token = bearer_token(parts).ok_or(AppError::Unauthorized)?;
 
         // Validate session
         let session = state
@@ -57,3 +76,97 @@ impl FromRequestParts<AppState> for AuthUser {
         })
     }
 }
+
+/// Admin context extracted from request: either a session token belonging to
+/// an admin/moderator account, or the configured master key
+#[derive(Debug, Clone)]
+pub struct AdminUser {
+    /// Acting user ID, or None when authenticated with the master key
+    pub user_id: Option<String>,
+    pub role: Role,
+}
+
+impl AdminUser {
+    /// Check that the acting account is allowed to perform an action
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        if self.role.has_permission(permission) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+
+    /// Name of the actor for audit logging
+    pub fn actor(&self) -> &str {
+        self.user_id.as_deref().unwrap_or("master-key")
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for AdminUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        // Master key grants full admin rights
+        if let Some(key) = parts.headers.get(ADMIN_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+            if !crate::crypto::constant_time_eq(key, &state.config.admin.master_key) {
+                return Err(AppError::Forbidden);
+            }
+            return Ok(AdminUser {
+                user_id: None,
+                role: Role::Admin,
+            });
+        }
+
+        // Otherwise the session must belong to an elevated account
+        let auth = AuthUser::from_request_parts(parts, state).await?;
+        let user = state
+            .storage
+            .get_user(&auth.user_id)
+            .await?
+            .ok_or(AppError::Unauthorized)?;
+
+        let role = user.role();
+        if !user.is_active || role == Role::User {
+            return Err(AppError::Forbidden);
+        }
+
+        Ok(AdminUser {
+            user_id: Some(auth.user_id),
+            role,
+        })
+    }
+}
+
+/// Accept the master key in the JSON body of admin requests, as servers
+/// before roles did, by moving it to `X-Admin-Key`. Deprecated: each use is
+/// logged, and it is removed in the next release.
+pub async fn legacy_admin_key(request: Request, next: Next) -> Result<Response, AppError> {
+    let headers = request.headers();
+    if !request.uri().path().starts_with("/api/v1/admin/")
+        || headers.contains_key(ADMIN_KEY_HEADER)
+        || headers.contains_key(AUTHORIZATION)
+    {
+        return Ok(next.run(request).await);
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, LEGACY_BODY_LIMIT)
+        .await
+        .map_err(|_| AppError::BadRequest("Request body too large".to_string()))?;
+    let key = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|body| body.get(LEGACY_ADMIN_KEY_FIELD)?.as_str().map(str::to_owned));
+    if let Some(key) = key {
+        tracing::warn!(
+            "{} {}: admin_key in the request body is deprecated and will be removed in the next release; send the X-Admin-Key header instead",
+            parts.method,
+            parts.uri.path()
+        );
+        if let Ok(value) = HeaderValue::from_str(&key) {
+            parts.headers.insert(ADMIN_KEY_HEADER, value);
+        }
+    }
+
+    Ok(next.run(Request::from_parts(parts, Body::from(bytes))).await)
+}
@@ -0,0 +1,23 @@
+//! Client policy handler
+
+use axum::{extract::State, Json};
+use privmsg_proto::policy::SignedPolicy;
+use crate::{
+    error::{AppError, Result},
+    AppState,
+};
+
+use super::AuthUser;
+
+/// Get the signed client policy; 404 when the server has none
+pub async fn get_client_policy(
+    State(state): State<AppState>,
+    _auth: AuthUser,
+) -> Result<Json<SignedPolicy>> {
+    state
+        .client_policy
+        .as_deref()
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound("No client policy".to_string()))
+}
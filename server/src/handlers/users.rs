@@ -23,13 +23,13 @@ pub async fn get_current_user(
         .await?
         .ok_or(AppError::NotFound("User not found".to_string()))?;
 
-    Ok(Json(user.into()))
+    Ok(Json(UserProfile::own(user)))
 }
 
 /// Get another user's profile by ID
 pub async fn get_user(
     State(state): State<AppState>,
-    _auth: AuthUser, // Must be authenticated
+    auth: AuthUser,
     Path(user_id): Path<String>,
 ) -> Result<Json<UserProfile>> {
     let user = state
@@ -39,10 +39,16 @@ pub async fn get_user(
         .ok_or(AppError::NotFound("User not found".to_string()))?;
 
     if !user.is_active {
-        return Err(AppError::NotFound("User not found".to_string()));
+        return Err(AppError::UserUnavailable(user_id));
+    }
+
+    let show_last_seen = state.storage.can_see_last_seen(&auth.user_id, &user).await?;
+    let mut profile = UserProfile::from(user);
+    if !show_last_seen {
+        profile.last_seen_at = None;
     }
 
-    Ok(Json(user.into()))
+    Ok(Json(profile))
 }
 
 /// Update current user's profile
@@ -51,15 +57,8 @@ pub async fn update_profile(
     auth: AuthUser,
     Json(req): Json<UpdateProfileRequest>,
 ) -> Result<Json<UserProfile>> {
-    state
-        .storage
-        .update_user_profile(
-            &auth.user_id,
-            req.display_name.as_deref(),
-            req.avatar_file_id.as_deref(),
-            req.public_key.as_deref(),
-        )
-        .await?;
+    req.validate().map_err(AppError::BadRequest)?;
+    state.storage.update_user_profile(&auth.user_id, &req).await?;
 
     let user = state
         .storage
@@ -67,7 +66,7 @@ pub async fn update_profile(
         .await?
         .ok_or(AppError::NotFound("User not found".to_string()))?;
 
-    Ok(Json(user.into()))
+    Ok(Json(UserProfile::own(user)))
 }
 
 /// List current user's devices
@@ -79,6 +78,89 @@ pub async fn list_devices(
     Ok(Json(devices))
 }
 
+/// A user's signed-in devices with their public keys, so senders can
+/// encrypt a copy of each message for every one of them
+pub async fn get_device_keys(
+    State(state): State<AppState>,
+    _auth: AuthUser,
+    Path(user_id): Path<String>,
+) -> Result<Json<Vec<DeviceKey>>> {
+    let user = state
+        .storage
+        .get_user(&user_id)
+        .await?
+        .ok_or(AppError::NotFound("User not found".to_string()))?;
+
+    if !user.is_active {
+        return Err(AppError::UserUnavailable(user_id));
+    }
+
+    let devices = state.storage.list_user_devices(&user_id).await?;
+    Ok(Json(devices.into_iter().map(DeviceKey::from).collect()))
+}
+
+/// Rename one of the current user's devices and tell the others
+pub async fn rename_device(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(device_id): Path<String>,
+    Json(req): Json<RenameDeviceRequest>,
+) -> Result<Json<Device>> {
+    let device_name = req.validated_name().map_err(AppError::BadRequest)?;
+    let device = state
+        .storage
+        .get_device(&device_id)
+        .await?
+        .ok_or(AppError::NotFound("Device not found".to_string()))?;
+
+    if device.user_id != auth.user_id {
+        return Err(AppError::Forbidden);
+    }
+
+    state.storage.rename_device(&device_id, device_name).await?;
+    state
+        .ws_manager
+        .send_to_other_devices(
+            &auth.user_id,
+            &auth.device_id,
+            WsServerMessage::DeviceRenamed {
+                device_id: device_id.clone(),
+                device_name: device_name.to_string(),
+            },
+        )
+        .await;
+
+    Ok(Json(Device {
+        device_name: device_name.to_string(),
+        ..device
+    }))
+}
+
+/// Store the certificate one of the current user's devices made for
+/// another of them. The server doesn't check it; senders do.
+pub async fn certify_device(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(device_id): Path<String>,
+    Json(req): Json<CertifyDeviceRequest>,
+) -> Result<Json<serde_json::Value>> {
+    if req.signature.is_empty() {
+        return Err(AppError::BadRequest("signature is empty".to_string()));
+    }
+    let device = state
+        .storage
+        .get_device(&device_id)
+        .await?
+        .ok_or(AppError::NotFound("Device not found".to_string()))?;
+
+    if device.user_id != auth.user_id {
+        return Err(AppError::Forbidden);
+    }
+
+    state.storage.set_device_signature(&device_id, &req.signature).await?;
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
 /// Remove a device
 pub async fn remove_device(
     State(state): State<AppState>,
@@ -104,7 +186,9 @@ pub async fn remove_device(
     state.storage.delete_device(&device_id).await?;
 
     // Disconnect if online
-    state.ws_manager.unregister(&device_id);
+    state
+        .ws_manager
+        .revoke_device(&device_id, RevocationReason::DeviceRemoved);
 
     Ok(Json(serde_json::json!({ "success": true })))
 }
@@ -3,19 +3,41 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ConnectInfo, State,
     },
-    response::IntoResponse,
+    http::{
+        header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+        HeaderMap,
+    },
+    response::Response,
 };
 use chrono::DateTime;
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
+    error::{AppError, Result},
     models::*,
+    flood::Verdict,
+    websocket::Outbox,
     AppState,
 };
 
+use super::messages;
+
+/// Subprotocol selected for clients that authenticate via Sec-WebSocket-Protocol
+pub const WS_SUBPROTOCOL: &str = "privmsg.v1";
+
+/// Prefix of the Sec-WebSocket-Protocol entry carrying the session token
+/// (e.g. `Sec-WebSocket-Protocol: privmsg.v1, privmsg.auth.<token>`)
+pub const WS_TOKEN_PROTOCOL_PREFIX: &str = "privmsg.auth.";
+
+/// How often an open socket rechecks that its session is still valid
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Parse datetime string to timestamp
 fn parse_datetime_to_timestamp(s: &str) -> i64 {
     DateTime::parse_from_rfc3339(s)
@@ -23,88 +45,347 @@ fn parse_datetime_to_timestamp(s: &str) -> i64 {
         .unwrap_or_else(|_| chrono::Utc::now().timestamp())
 }
 
+/// Extract a session token supplied with the HTTP upgrade request
+fn upgrade_token(headers: &HeaderMap) -> Option<String> {
+    // Authorization: Bearer <token>
+    if let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        return Some(token.to_string());
+    }
+
+    // Sec-WebSocket-Protocol: privmsg.v1, privmsg.auth.<token>
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find_map(|p| p.trim().strip_prefix(WS_TOKEN_PROTOCOL_PREFIX))
+        .map(|t| t.to_string())
+}
+
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    // Authenticate during the upgrade when the client supplied a token, so
+    // invalid sessions are rejected before a socket is ever opened. Clients
+    // that don't must send an `authenticate` frame instead.
+    let session = match upgrade_token(&headers) {
+        Some(token) => Some(
+            state
+                .storage
+                .validate_session(&token)
+                .await?
+                .ok_or(AppError::Unauthorized)?,
+        ),
+        None => None,
+    };
+    let ip = addr.ip();
+    if let Some(ban) = state.flood.check_connect(session.as_ref().map(|s| s.user_id.as_str()), ip) {
+        return Err(AppError::Banned(ban.expires_at));
+    }
+
+    Ok(ws
+        .protocols([WS_SUBPROTOCOL])
+        .on_upgrade(move |socket| handle_socket(socket, state, session, ip)))
+}
+
+/// Online users allowed to see `user_id`'s presence under their
+/// last-seen privacy setting
+async fn presence_audience(state: &AppState, user_id: &str) -> Vec<String> {
+    let visibility = match state.storage.get_user(user_id).await {
+        Ok(Some(user)) => user.last_seen_visibility(),
+        _ => return Vec::new(),
+    };
+    let contacts = match visibility {
+        LastSeenVisibility::Nobody => return Vec::new(),
+        LastSeenVisibility::Contacts => {
+            Some(state.storage.list_contacts(user_id).await.unwrap_or_default())
+        }
+        LastSeenVisibility::Everyone => None,
+    };
+
+    state
+        .ws_manager
+        .get_online_users()
+        .into_iter()
+        .filter(|other| other != user_id)
+        .filter(|other| contacts.as_ref().is_none_or(|c| c.contains(other)))
+        .collect()
+}
+
+/// Group pending messages into `message_batch` frames of at most `batch_size`
+fn pending_batches(pending: Vec<PendingMessage>, batch_size: usize) -> Vec<WsServerMessage> {
+    let envelopes: Vec<MessageEnvelope> = pending
+        .into_iter()
+        .map(|pm| MessageEnvelope {
+            message_id: pm.message_id,
+            sender_id: pm.sender_id,
+            recipient_id: pm.recipient_id,
+            recipient_device_id: pm.recipient_device_id,
+            encrypted_content: pm.encrypted_content,
+            message_type: pm.message_type.into(),
+            timestamp: parse_datetime_to_timestamp(&pm.created_at),
+            ttl_seconds: None,
+            priority: pm.priority.into(),
+            delivery_mode: DeliveryMode::StoreAndForward,
+        })
+        .collect();
+
+    envelopes
+        .chunks(batch_size.max(1))
+        .map(|chunk| WsServerMessage::MessageBatch {
+            messages: chunk.to_vec(),
+        })
+        .collect()
+}
+
+/// A text frame is a JSON [`WsClientMessage`]; a binary frame is a single
+/// envelope in the canonical encoding, the same as a `message` frame
+fn parse_client_frame(frame: &Message) -> std::result::Result<WsClientMessage, String> {
+    match frame {
+        Message::Text(text) => serde_json::from_str(text).map_err(|e| e.to_string()),
+        Message::Binary(bytes) => CanonicalEnvelope::decode(bytes)
+            .map(|envelope| WsClientMessage::Message(envelope.into()))
+            .map_err(|e| e.to_string()),
+        _ => Err("Unexpected frame".to_string()),
+    }
+}
+
+/// Whether `signal` is a call offer worth holding for an offline recipient.
+/// Offers that set up direct file transfers are left out; the sender falls
+/// back to an upload when they go unanswered.
+fn is_held_offer(signal: &CallSignal) -> bool {
+    signal.signal_type == CallSignalType::Offer
+        && serde_json::from_str::<serde_json::Value>(&signal.payload)
+            .map(|payload| payload.get("kind").and_then(|k| k.as_str()) != Some("file_transfer"))
+            .unwrap_or(true)
+}
+
+/// Signals after which a held offer should no longer ring
+fn ends_call_offer(signal_type: &CallSignalType) -> bool {
+    matches!(
+        signal_type,
+        CallSignalType::Answer
+            | CallSignalType::Accepted
+            | CallSignalType::Rejected
+            | CallSignalType::Busy
+            | CallSignalType::Hangup
+    )
+}
+
+/// Register an authenticated connection and replay pending messages.
+/// Returns the token cancelled when the session is revoked.
+async fn on_authenticated(
+    state: &AppState,
+    tx: &Arc<Outbox>,
+    session: &Session,
+) -> CancellationToken {
+    // Messages relayed live while the replay is fetched wait behind it
+    tx.pause();
+
+    // Register connection
+    let revoked = state.ws_manager.register(
+        &session.user_id,
+        &session.device_id,
+        tx.clone(),
+    );
+
+    // Send authenticated response
+    let mut frames = vec![WsServerMessage::Authenticated {
+        user_id: session.user_id.clone(),
+        device_id: session.device_id.clone(),
+    }];
+
+    if let Some(maintenance) = state.maintenance.current() {
+        frames.push(WsServerMessage::Maintenance {
+            maintenance: Some(maintenance),
+        });
+    }
+
+    if let Ok(Some(announcement)) = state.storage.get_announcement().await {
+        frames.push(WsServerMessage::Announcement {
+            announcement: Some(announcement),
+        });
+    }
+
+    // Ringing calls go ahead of everything else so the client can show them
+    // before working through its backlog
+    if let Ok(offers) = state.storage.get_call_offers(&session.user_id).await {
+        frames.extend(offers.into_iter().map(WsServerMessage::CallSignal));
+    }
+
+    // Deliver pending messages in batches; the client acks each batch at once
+    if let Ok(pending) = state.storage.get_pending_messages(
+        &session.user_id,
+        Some(&session.device_id),
+    ).await {
+        frames.extend(pending_batches(pending, state.config.limits.ws_batch_size));
+    }
+
+    // Receipts for messages that were delivered while the user was away
+    if let Ok(receipts) = state.storage.take_delivery_receipts(&session.user_id).await {
+        frames.extend(receipts.into_iter().map(|(recipient_id, message_ids)| {
+            WsServerMessage::Delivered {
+                recipient_id,
+                message_ids,
+            }
+        }));
+    }
+    tx.resume_with(frames);
+
+    tracing::info!(
+        "WebSocket authenticated: user={}, device={}",
+        session.user_id,
+        session.device_id
+    );
+    revoked
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState) {
+async fn handle_socket(socket: WebSocket, state: AppState, session: Option<Session>, ip: IpAddr) {
     let (mut ws_sender, mut ws_receiver) = socket.split();
+    // Flood detection tracks sockets, authenticated or not
+    let connection_id = uuid::Uuid::new_v4().to_string();
 
-    // Channel for sending messages to this client
-    let (tx, mut rx) = mpsc::unbounded_channel::<WsServerMessage>();
+    // Queue of frames for this client
+    let tx = Outbox::new(state.config.limits.ws_queue_capacity);
 
     let mut user_id: Option<String> = None;
     let mut device_id: Option<String> = None;
+    // Revocations through the API cancel this right away; anything else
+    // (CLI deactivation, expiry) is caught by rechecking the session
+    let mut revoked = CancellationToken::new();
+    let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    session_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
     // Task to forward messages from channel to WebSocket
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+    let outbox = tx.clone();
+    let mut send_task = tokio::spawn(async move {
+        while let Some(msg) = outbox.recv().await {
             if let Ok(json) = serde_json::to_string(&msg) {
                 if ws_sender.send(Message::Text(json)).await.is_err() {
-                    break;
+                    // Nobody is reading; don't let senders wait on the queue
+                    outbox.close();
+                    return;
                 }
             }
         }
+        let _ = ws_sender.send(Message::Close(None)).await;
     });
 
+    // Already authenticated during the HTTP upgrade
+    if let Some(session) = session {
+        revoked = on_authenticated(&state, &tx, &session).await;
+        user_id = Some(session.user_id);
+        device_id = Some(session.device_id);
+    }
+
+    // Unauthenticated sockets must send an `authenticate` frame in time
+    let auth_deadline = tokio::time::Instant::now()
+        + Duration::from_secs(state.config.limits.ws_auth_timeout_seconds);
+
     // Handle incoming messages
-    while let Some(result) = ws_receiver.next().await {
+    loop {
+        let receive = async {
+            if user_id.is_some() {
+                Ok(ws_receiver.next().await)
+            } else {
+                tokio::time::timeout_at(auth_deadline, ws_receiver.next()).await
+            }
+        };
+
+        let next = tokio::select! {
+            next = receive => match next {
+                Ok(next) => next,
+                Err(_) => {
+                    let _ = tx.send(WsServerMessage::Error {
+                        code: ErrorCode::AuthTimeout,
+                        message: "Authentication timed out".to_string(),
+                        message_id: None,
+                    }).await;
+                    break;
+                }
+            },
+            // The SessionRevoked frame is already queued
+            _ = revoked.cancelled() => break,
+            _ = session_check.tick() => {
+                if let Some(ref did) = device_id {
+                    if let Ok(false) = state.storage.is_device_session_active(did).await {
+                        let _ = tx.send(WsServerMessage::SessionRevoked {
+                            reason: RevocationReason::Expired,
+                        }).await;
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+
+        let Some(result) = next else {
+            break;
+        };
+
         match result {
-            Ok(Message::Text(text)) => {
-                match serde_json::from_str::<WsClientMessage>(&text) {
+            Ok(frame @ (Message::Text(_) | Message::Binary(_))) => {
+                match state.flood.check_frame(&connection_id, user_id.as_deref(), ip) {
+                    Verdict::Allow => {}
+                    Verdict::Muted { started, seconds } => {
+                        if started {
+                            let _ = tx.send(WsServerMessage::Error {
+                                code: ErrorCode::RateLimited,
+                                message: format!("Too many messages; ignoring this connection for {}s", seconds),
+                                message_id: None,
+                            }).await;
+                        }
+                        continue;
+                    }
+                    Verdict::Banned(ban) => {
+                        let _ = tx.send(WsServerMessage::Error {
+                            code: ErrorCode::Forbidden,
+                            message: format!("Temporarily banned until {}", ban.expires_at),
+                            message_id: None,
+                        }).await;
+                        break;
+                    }
+                }
+
+                match parse_client_frame(&frame) {
                     Ok(client_msg) => {
                         match client_msg {
                             WsClientMessage::Authenticate { token } => {
+                                // Already authenticated (e.g. during the upgrade)
+                                if let (Some(ref uid), Some(ref did)) = (&user_id, &device_id) {
+                                    let _ = tx.send(WsServerMessage::Authenticated {
+                                        user_id: uid.clone(),
+                                        device_id: did.clone(),
+                                    }).await;
+                                    continue;
+                                }
+
                                 // Validate session
                                 if let Ok(Some(session)) = state.storage.validate_session(&token).await {
-                                    user_id = Some(session.user_id.clone());
-                                    device_id = Some(session.device_id.clone());
-
-                                    // Register connection
-                                    state.ws_manager.register(
-                                        &session.user_id,
-                                        &session.device_id,
-                                        tx.clone(),
-                                    );
-
-                                    // Send authenticated response
-                                    let _ = tx.send(WsServerMessage::Authenticated {
-                                        user_id: session.user_id.clone(),
-                                        device_id: session.device_id.clone(),
-                                    });
-
-                                    // Deliver pending messages
-                                    if let Ok(pending) = state.storage.get_pending_messages(
-                                        &session.user_id,
-                                        Some(&session.device_id),
-                                    ).await {
-                                        for pm in pending {
-                                            let envelope = MessageEnvelope {
-                                                message_id: pm.message_id,
-                                                sender_id: pm.sender_id,
-                                                recipient_id: pm.recipient_id,
-                                                recipient_device_id: pm.recipient_device_id,
-                                                encrypted_content: pm.encrypted_content,
-                                                message_type: pm.message_type.into(),
-                                                timestamp: parse_datetime_to_timestamp(&pm.created_at),
-                                            };
-                                            let _ = tx.send(WsServerMessage::Message(envelope));
-                                        }
+                                    if let Some(ban) = state.flood.check_connect(Some(&session.user_id), ip) {
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            code: ErrorCode::Forbidden,
+                                            message: format!("Temporarily banned until {}", ban.expires_at),
+                                            message_id: None,
+                                        }).await;
+                                        break;
                                     }
-
-                                    tracing::info!(
-                                        "WebSocket authenticated: user={}, device={}",
-                                        session.user_id,
-                                        session.device_id
-                                    );
+                                    revoked = on_authenticated(&state, &tx, &session).await;
+                                                    user_id = Some(session.user_id);
+                                    device_id = Some(session.device_id);
                                 } else {
                                     let _ = tx.send(WsServerMessage::Error {
-                                        code: "AUTH_FAILED".to_string(),
+                                        code: ErrorCode::AuthFailed,
                                         message: "Invalid or expired token".to_string(),
-                                    });
+                                        message_id: None,
+                                    }).await;
                                 }
                             }
 
@@ -113,32 +394,81 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                     // Verify sender
                                     if envelope.sender_id != *uid {
                                         let _ = tx.send(WsServerMessage::Error {
-                                            code: "INVALID_SENDER".to_string(),
+                                            code: ErrorCode::InvalidSender,
                                             message: "Sender ID mismatch".to_string(),
-                                        });
+                                            message_id: Some(envelope.message_id.clone()),
+                                        }).await;
+                                        continue;
+                                    }
+                                    if !envelope.message_type.is_valid() {
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            code: ErrorCode::BadRequest,
+                                            message: format!(
+                                                "Custom message types look like x-<vendor>-<name>, got {}",
+                                                envelope.message_type
+                                            ),
+                                            message_id: Some(envelope.message_id.clone()),
+                                        }).await;
+                                        continue;
+                                    }
+                                    if !state.maintenance.relays_messages() {
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            code: ErrorCode::Maintenance,
+                                            message: "Messages are not relayed during maintenance".to_string(),
+                                            message_id: Some(envelope.message_id.clone()),
+                                        }).await;
+                                        continue;
+                                    }
+
+                                    // Envelopes addressed to the sender themselves sync
+                                    // state between their devices (e.g. read positions)
+                                    // and only go to the other devices below
+                                    let to_own_devices = envelope.recipient_id == *uid;
+                                    // Nobody would ever collect messages for a
+                                    // deactivated or deleted account
+                                    if !to_own_devices
+                                        && !state.storage.is_user_active(&envelope.recipient_id).await.unwrap_or(true)
+                                    {
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            code: ErrorCode::RecipientUnavailable,
+                                            message: format!("{} is no longer available", envelope.recipient_id),
+                                            message_id: Some(envelope.message_id.clone()),
+                                        }).await;
                                         continue;
                                     }
 
                                     // Try to deliver directly if recipient is online
-                                    if state.ws_manager.is_user_online(&envelope.recipient_id) {
+                                    if !to_own_devices
+                                        && state.ws_manager.is_user_online(&envelope.recipient_id)
+                                    {
                                         if let Some(ref device) = envelope.recipient_device_id {
                                             state.ws_manager.send_to_device(
                                                 device,
                                                 WsServerMessage::Message(envelope.clone()),
-                                            );
+                                            ).await;
                                         } else {
                                             state.ws_manager.send_to_user(
                                                 &envelope.recipient_id,
                                                 WsServerMessage::Message(envelope.clone()),
-                                            );
+                                            ).await;
                                         }
                                     }
 
-                                    // Store for offline delivery
-                                    let _ = state.storage.store_pending_message(
-                                        &envelope,
-                                        state.config.storage.max_message_age_hours as i64,
-                                    ).await;
+                                    // Store for offline delivery, unless only the
+                                    // devices online right now should get it
+                                    if envelope.delivery_mode == DeliveryMode::StoreAndForward {
+                                        let _ = state.storage.store_pending_message(
+                                            &envelope,
+                                            state.config.storage.max_message_age_hours as i64,
+                                        ).await;
+                                    }
+                                    if !to_own_devices {
+                                        let _ = state.storage.record_relayed_message().await;
+                                        let _ = state
+                                            .storage
+                                            .record_contact(uid, &envelope.recipient_id)
+                                            .await;
+                                    }
 
                                     // Acknowledge to sender
                                     let msg_id = envelope.message_id.clone();
@@ -148,17 +478,21 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                         uid,
                                         did,
                                         WsServerMessage::Message(envelope),
-                                    );
+                                    ).await;
 
                                     let _ = tx.send(WsServerMessage::Acknowledged {
                                         message_ids: vec![msg_id],
-                                    });
+                                    }).await;
                                 }
                             }
 
                             WsClientMessage::Acknowledge { message_ids } => {
-                                let _ = state.storage.delete_pending_messages(&message_ids).await;
-                                let _ = tx.send(WsServerMessage::Acknowledged { message_ids });
+                                if let Some(ref uid) = user_id {
+                                    if let Err(e) = messages::acknowledge(&state, uid, &message_ids).await {
+                                        tracing::warn!("Acknowledging messages of {} failed: {}", uid, e);
+                                    }
+                                }
+                                let _ = tx.send(WsServerMessage::Acknowledged { message_ids }).await;
                             }
 
                             WsClientMessage::Typing { recipient_id, is_typing } => {
@@ -169,25 +503,14 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                             user_id: uid.clone(),
                                             is_typing,
                                         },
-                                    );
+                                    ).await;
                                 }
                             }
 
                             WsClientMessage::Presence { status } => {
                                 if let Some(ref uid) = user_id {
-                                    // Broadcast to contacts (in production, you'd have a contacts list)
-                                    let online_users = state.ws_manager.get_online_users();
-                                    for other_user in online_users {
-                                        if other_user != *uid {
-                                            state.ws_manager.send_to_user(
-                                                &other_user,
-                                                WsServerMessage::Presence {
-                                                    user_id: uid.clone(),
-                                                    status: status.clone(),
-                                                },
-                                            );
-                                        }
-                                    }
+                                    let audience = presence_audience(&state, uid).await;
+                                    state.ws_manager.broadcast_presence(uid, status, &audience).await;
                                 }
                             }
 
@@ -198,32 +521,43 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                         continue;
                                     }
 
+                                    // An offer to someone offline is held briefly so it
+                                    // rings when they reconnect; any reply ends it
+                                    if is_held_offer(&signal) {
+                                        if !state.ws_manager.is_user_online(&signal.recipient_id) {
+                                            let ttl = privmsg_proto::default_ttl_seconds("call_signal").unwrap_or(60);
+                                            if let Err(e) = state.storage.store_call_offer(&signal, chrono::Duration::seconds(ttl as i64)).await {
+                                                tracing::warn!("Failed to hold call offer: {}", e);
+                                            }
+                                        }
+                                    } else if ends_call_offer(&signal.signal_type) {
+                                        let _ = state.storage.clear_call_offer(&signal.call_id).await;
+                                    }
+
                                     // Forward call signal to recipient
                                     let recipient = signal.recipient_id.clone();
                                     state.ws_manager.send_to_user(
                                         &recipient,
                                         WsServerMessage::CallSignal(signal),
-                                    );
+                                    ).await;
                                 }
                             }
 
                             WsClientMessage::Ping => {
-                                let _ = tx.send(WsServerMessage::Pong);
+                                let _ = tx.send(WsServerMessage::Pong).await;
                             }
                         }
                     }
                     Err(e) => {
                         tracing::warn!("Failed to parse WebSocket message: {}", e);
                         let _ = tx.send(WsServerMessage::Error {
-                            code: "PARSE_ERROR".to_string(),
+                            code: ErrorCode::ParseError,
                             message: format!("Invalid message format: {}", e),
-                        });
+                            message_id: None,
+                        }).await;
                     }
                 }
             }
-            Ok(Message::Binary(_)) => {
-                // Binary messages not supported
-            }
             Ok(Message::Ping(_)) => {
                 // Handled by the WebSocket library
             }
@@ -241,6 +575,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     }
 
     // Cleanup
+    state.flood.forget(&connection_id);
     if let Some(did) = device_id {
         state.ws_manager.unregister(&did);
 
@@ -250,12 +585,242 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
 
             // If no more devices online, broadcast offline status
             if !state.ws_manager.is_user_online(&uid) {
-                let online_users = state.ws_manager.get_online_users();
-                state.ws_manager.broadcast_user_offline(&uid, &online_users);
+                let audience = presence_audience(&state, &uid).await;
+                state.ws_manager.broadcast_user_offline(&uid, &audience).await;
             }
         }
     }
 
-    // Abort send task
-    send_task.abort();
+    // Let queued frames (e.g. a timeout error) flush, then close
+    tx.close();
+    if tokio::time::timeout(Duration::from_secs(1), &mut send_task).await.is_err() {
+        send_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_token_from_authorization() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer abc123".parse().unwrap());
+        assert_eq!(upgrade_token(&headers).as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_upgrade_token_from_subprotocol() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            "privmsg.v1, privmsg.auth.tok_-9".parse().unwrap(),
+        );
+        assert_eq!(upgrade_token(&headers).as_deref(), Some("tok_-9"));
+
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, "privmsg.v1".parse().unwrap());
+        assert_eq!(upgrade_token(&headers), None);
+    }
+
+    #[test]
+    fn test_binary_envelope_frame() {
+        let canonical = CanonicalEnvelope {
+            message_id: "m1".to_string(),
+            sender_id: "alice".to_string(),
+            recipient_id: "bob".to_string(),
+            recipient_device_id: None,
+            encrypted_content: "ciphertext".to_string(),
+            message_type: "call_signal".to_string(),
+            timestamp: 5,
+            ttl_seconds: Some(60),
+            priority: MessagePriority::High,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        };
+        let Ok(WsClientMessage::Message(envelope)) = parse_client_frame(&Message::Binary(canonical.encode()))
+        else {
+            panic!("expected a message frame");
+        };
+        assert_eq!(envelope.message_type, MessageType::CallSignal);
+        assert_eq!((envelope.timestamp, envelope.ttl_seconds), (5, Some(60)));
+
+        assert!(parse_client_frame(&Message::Binary(vec![1, 0])).is_err());
+        assert!(matches!(
+            parse_client_frame(&Message::Text(r#"{"type":"ping"}"#.into())),
+            Ok(WsClientMessage::Ping)
+        ));
+    }
+
+    #[test]
+    fn test_pending_batches() {
+        let pending: Vec<PendingMessage> = (0..5)
+            .map(|i| PendingMessage {
+                id: i,
+                message_id: format!("m{}", i),
+                sender_id: "alice".to_string(),
+                recipient_id: "bob".to_string(),
+                recipient_device_id: None,
+                encrypted_content: "ciphertext".to_string(),
+                message_type: "text".to_string(),
+                priority: "normal".to_string(),
+                created_at: "2024-01-01T00:00:00Z".to_string(),
+                expires_at: "2024-01-08T00:00:00Z".to_string(),
+            })
+            .collect();
+
+        let sizes: Vec<usize> = pending_batches(pending, 2)
+            .into_iter()
+            .map(|frame| match frame {
+                WsServerMessage::MessageBatch { messages } => messages.len(),
+                _ => panic!("expected a batch frame"),
+            })
+            .collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert!(pending_batches(Vec::new(), 2).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_ttl_and_priority() {
+        let path = std::env::temp_dir().join(format!("privmsg-pending-{}.db", uuid::Uuid::new_v4()));
+        let storage = crate::storage::Storage::new(path.to_str().unwrap()).await.unwrap();
+        storage.create_user("alice", "hash", Role::User).await.unwrap();
+
+        let envelope = |id: &str, message_type: MessageType| MessageEnvelope {
+            message_id: id.to_string(),
+            sender_id: "alice".to_string(),
+            recipient_id: "bob".to_string(),
+            recipient_device_id: None,
+            encrypted_content: "ciphertext".to_string(),
+            ttl_seconds: privmsg_proto::default_ttl_seconds(&message_type.to_string()),
+            priority: MessagePriority::for_message_type(&message_type.to_string()),
+            message_type,
+            timestamp: 0,
+            delivery_mode: DeliveryMode::StoreAndForward,
+        };
+        for (id, message_type) in [
+            ("receipt", MessageType::ReadReceipt),
+            ("text", MessageType::Text),
+            ("typing", MessageType::TypingIndicator),
+            ("call", MessageType::CallSignal),
+        ] {
+            storage.store_pending_message(&envelope(id, message_type), 168).await.unwrap();
+        }
+
+        // Typing indicators are never stored; calls replay first and expire soon
+        let pending = storage.get_pending_messages("bob", None).await.unwrap();
+        let ids: Vec<&str> = pending.iter().map(|pm| pm.message_id.as_str()).collect();
+        assert_eq!(ids, vec!["call", "text", "receipt"]);
+        assert!(pending[0].expires_at < pending[1].expires_at);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_delivery_receipts() {
+        let path = std::env::temp_dir().join(format!("privmsg-receipts-{}.db", uuid::Uuid::new_v4()));
+        let storage = crate::storage::Storage::new(path.to_str().unwrap()).await.unwrap();
+        storage.create_user("alice", "hash", Role::User).await.unwrap();
+
+        for id in ["m1", "m2"] {
+            let envelope = MessageEnvelope {
+                message_id: id.to_string(),
+                sender_id: "alice".to_string(),
+                recipient_id: "bob".to_string(),
+                recipient_device_id: None,
+                encrypted_content: "ciphertext".to_string(),
+                message_type: MessageType::Text,
+                timestamp: 0,
+                ttl_seconds: None,
+                priority: MessagePriority::Normal,
+                delivery_mode: DeliveryMode::StoreAndForward,
+            };
+            storage.store_pending_message(&envelope, 168).await.unwrap();
+        }
+        let ids = vec!["m1".to_string(), "m2".to_string()];
+
+        // Only the recipient's acks take messages off the queue
+        assert!(storage.delete_pending_messages("alice", &ids).await.unwrap().is_empty());
+        let delivered = storage.delete_pending_messages("bob", &ids[..1]).await.unwrap();
+        assert_eq!(delivered, vec![("m1".to_string(), "alice".to_string())]);
+        assert_eq!(storage.get_pending_messages("bob", None).await.unwrap().len(), 1);
+
+        storage.store_delivery_receipts("alice", "bob", &ids, 168).await.unwrap();
+        storage.store_delivery_receipts("alice", "bob", &ids[..1], 168).await.unwrap();
+        assert_eq!(
+            storage.take_delivery_receipts("alice").await.unwrap(),
+            vec![("bob".to_string(), ids.clone())]
+        );
+        assert!(storage.take_delivery_receipts("alice").await.unwrap().is_empty());
+
+        let frame = serde_json::to_value(WsServerMessage::Delivered {
+            recipient_id: "bob".to_string(),
+            message_ids: ids,
+        })
+        .unwrap();
+        assert_eq!(frame["type"], "delivered");
+        assert_eq!(frame["payload"]["message_ids"][1], "m2");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_held_call_offers() {
+        let path = std::env::temp_dir().join(format!("privmsg-calls-{}.db", uuid::Uuid::new_v4()));
+        let storage = crate::storage::Storage::new(path.to_str().unwrap()).await.unwrap();
+
+        let signal = |call_id: &str, signal_type: CallSignalType, payload: &str| CallSignal {
+            call_id: call_id.to_string(),
+            sender_id: "alice".to_string(),
+            recipient_id: "bob".to_string(),
+            signal_type,
+            payload: payload.to_string(),
+        };
+        assert!(is_held_offer(&signal("c", CallSignalType::Offer, r#"{"sdp":"v=0"}"#)));
+        assert!(!is_held_offer(&signal("c", CallSignalType::Offer, r#"{"kind":"file_transfer"}"#)));
+        assert!(!is_held_offer(&signal("c", CallSignalType::IceCandidate, "{}")));
+        assert!(ends_call_offer(&CallSignalType::Hangup));
+        assert!(!ends_call_offer(&CallSignalType::Ringing));
+
+        let ttl = chrono::Duration::seconds(60);
+        storage.store_call_offer(&signal("c1", CallSignalType::Offer, "{}"), ttl).await.unwrap();
+        storage.store_call_offer(&signal("c2", CallSignalType::Offer, "{}"), ttl).await.unwrap();
+        storage.store_call_offer(&signal("old", CallSignalType::Offer, "{}"), -ttl).await.unwrap();
+
+        let offers = storage.get_call_offers("bob").await.unwrap();
+        let ids: Vec<&str> = offers.iter().map(|s| s.call_id.as_str()).collect();
+        assert_eq!(ids, vec!["c1", "c2"]);
+        assert!(storage.get_call_offers("alice").await.unwrap().is_empty());
+
+        storage.clear_call_offer("c1").await.unwrap();
+        assert_eq!(storage.get_call_offers("bob").await.unwrap().len(), 1);
+        assert_eq!(storage.cleanup_expired().await.unwrap().messages, 1);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_recipient() {
+        let path = std::env::temp_dir().join(format!("privmsg-recipients-{}.db", uuid::Uuid::new_v4()));
+        let storage = crate::storage::Storage::new(path.to_str().unwrap()).await.unwrap();
+        storage.create_user("bob", "hash", Role::User).await.unwrap();
+        storage.create_user("carol", "hash", Role::User).await.unwrap();
+
+        assert!(storage.is_user_active("bob").await.unwrap());
+        storage.deactivate_user("bob").await.unwrap();
+        storage.delete_user("carol").await.unwrap();
+        assert!(!storage.is_user_active("bob").await.unwrap());
+        assert!(!storage.is_user_active("carol").await.unwrap());
+
+        // The rejection names the envelope so the sender can find the chat
+        let frame = serde_json::to_value(WsServerMessage::Error {
+            code: ErrorCode::RecipientUnavailable,
+            message: "bob is no longer available".to_string(),
+            message_id: Some("m1".to_string()),
+        })
+        .unwrap();
+        let error: privmsg_proto::ServerError = serde_json::from_value(frame["payload"].clone()).unwrap();
+        assert_eq!(error.code, ErrorCode::RecipientUnavailable);
+        assert_eq!(error.message_id.as_deref(), Some("m1"));
+
+        std::fs::remove_file(&path).ok();
+    }
 }
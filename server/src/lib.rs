@@ -0,0 +1,46 @@
+//! PrivMsg Server library
+//!
+//! The server modules live here so the binary and the integration tests
+//! share the same code.
+
+pub mod challenge;
+pub mod cleanup;
+pub mod config;
+pub mod crypto;
+pub mod doctor;
+pub mod error;
+pub mod flood;
+pub mod handlers;
+pub mod maintenance;
+pub mod models;
+pub mod policy;
+pub mod storage;
+pub mod systemd;
+pub mod totp;
+pub mod websocket;
+
+use std::sync::Arc;
+
+use privmsg_proto::policy::SignedPolicy;
+
+use crate::challenge::LoginChallenges;
+use crate::cleanup::CleanupService;
+use crate::config::Config;
+use crate::flood::FloodGuard;
+use crate::maintenance::MaintenanceMode;
+use crate::storage::Storage;
+use crate::websocket::WebSocketManager;
+
+/// Application state shared across handlers
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub storage: Arc<Storage>,
+    pub ws_manager: Arc<WebSocketManager>,
+    pub cleanup: Arc<CleanupService>,
+    pub flood: Arc<FloodGuard>,
+    pub login_challenges: Arc<LoginChallenges>,
+    pub maintenance: Arc<MaintenanceMode>,
+    /// Signed at startup from `[policy]`; None when disabled
+    pub client_policy: Option<Arc<SignedPolicy>>,
+}
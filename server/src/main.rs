@@ -7,28 +7,27 @@
 //! - WebRTC signaling for calls
 //! - File transfer relay
 
-mod config;
-mod crypto;
-mod error;
-mod handlers;
-mod models;
-mod storage;
-mod websocket;
-
+use std::net::SocketAddr;
 use std::sync::Arc;
 use axum::{
-    routing::{get, post, delete},
+    routing::{get, post, put, delete},
     Router,
 };
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use tokio::net::TcpListener;
 use tower_http::cors::{CorsLayer, Any};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::config::Config;
-use crate::storage::Storage;
-use crate::websocket::WebSocketManager;
+use privmsg_server::config::Config;
+use privmsg_server::flood::FloodGuard;
+use privmsg_server::maintenance::{self, MaintenanceMode};
+use privmsg_server::models::{DailyStats, Role, SetMaintenanceRequest, StatsRange};
+use privmsg_server::challenge::LoginChallenges;
+use privmsg_server::cleanup::CleanupService;
+use privmsg_server::storage::Storage;
+use privmsg_server::websocket::WebSocketManager;
+use privmsg_server::{crypto, doctor, handlers, policy, systemd, totp, AppState};
 
 /// PrivMsg Server CLI
 #[derive(Parser)]
@@ -41,15 +40,20 @@ struct Cli {
     /// Config file path
     #[arg(short, long, default_value = "config.toml")]
     config: String,
+
+    /// Override a config setting, e.g. `--set server.port=9000`; wins over
+    /// the file and PRIVMSG__* environment variables
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    set: Vec<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Generate a new access key
     GenerateKey {
-        /// Admin master key for authorization
+        /// Break-glass admin master key; prompts for a TOTP code when omitted
         #[arg(long)]
-        admin_key: String,
+        admin_key: Option<String>,
 
         /// Optional user ID (will be generated if not provided)
         #[arg(long)]
@@ -58,32 +62,110 @@ enum Commands {
 
     /// List all registered keys
     ListKeys {
-        /// Admin master key
+        /// Break-glass admin master key; prompts for a TOTP code when omitted
         #[arg(long)]
-        admin_key: String,
+        admin_key: Option<String>,
     },
 
     /// Revoke an access key
     RevokeKey {
-        /// Admin master key
+        /// Break-glass admin master key; prompts for a TOTP code when omitted
         #[arg(long)]
-        admin_key: String,
+        admin_key: Option<String>,
 
         /// User ID to revoke
         #[arg(long)]
         user_id: String,
     },
 
+    /// Grant a role (admin, moderator, user) to a user
+    GrantRole {
+        /// Break-glass admin master key; prompts for a TOTP code when omitted
+        #[arg(long)]
+        admin_key: Option<String>,
+
+        /// User ID to update
+        #[arg(long)]
+        user_id: String,
+
+        /// Role to grant
+        #[arg(long)]
+        role: Role,
+    },
+
+    /// Revoke any elevated role, resetting the user to a regular account
+    RevokeRole {
+        /// Break-glass admin master key; prompts for a TOTP code when omitted
+        #[arg(long)]
+        admin_key: Option<String>,
+
+        /// User ID to update
+        #[arg(long)]
+        user_id: String,
+    },
+
+    /// Print daily usage history for reporting scripts
+    Stats {
+        /// Break-glass admin master key; prompts for a TOTP code when omitted
+        #[arg(long)]
+        admin_key: Option<String>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = StatsFormat::Json)]
+        format: StatsFormat,
+
+        /// How far back to go, e.g. 30d or 12w
+        #[arg(long, default_value = "30d")]
+        range: StatsRange,
+    },
+
+    /// Turn maintenance mode on, or off with --off; running servers pick
+    /// the change up within a few seconds
+    Maintenance {
+        /// Break-glass admin master key; prompts for a TOTP code when omitted
+        #[arg(long)]
+        admin_key: Option<String>,
+
+        /// Turn maintenance mode off
+        #[arg(long, conflicts_with_all = ["minutes", "relay_messages", "message"])]
+        off: bool,
+
+        /// Expected downtime in minutes, shown to users
+        #[arg(long)]
+        minutes: Option<u32>,
+
+        /// Keep relaying messages between connected clients
+        #[arg(long)]
+        relay_messages: bool,
+
+        /// Note shown to users, e.g. what is being upgraded
+        #[arg(long)]
+        message: Option<String>,
+    },
+
+    /// Generate a TOTP secret for `[admin] totp_secret`
+    GenerateTotpSecret {
+        /// Account name shown in the authenticator app
+        #[arg(long, default_value = "admin")]
+        account: String,
+    },
+
+    /// Check config, directories, database, TLS and TURN before going live
+    Doctor {
+        /// Create missing directories and the database, upgrade an old
+        /// schema, and write a self-signed TLS certificate for testing
+        #[arg(long)]
+        fix: bool,
+    },
+
     /// Run the server
     Run,
 }
 
-/// Application state shared across handlers
-#[derive(Clone)]
-pub struct AppState {
-    pub config: Arc<Config>,
-    pub storage: Arc<Storage>,
-    pub ws_manager: Arc<WebSocketManager>,
+#[derive(Clone, Copy, ValueEnum)]
+enum StatsFormat {
+    Json,
+    Csv,
 }
 
 #[tokio::main]
@@ -99,18 +181,41 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     // Load config
-    let config = Config::load(&cli.config).await?;
+    let config = Config::load_layered(&cli.config, &cli.set).await?;
     let config = Arc::new(config);
 
     match cli.command.unwrap_or(Commands::Run) {
         Commands::GenerateKey { admin_key, user_id } => {
-            generate_key(&config, &admin_key, user_id).await?;
+            generate_key(&config, admin_key.as_deref(), user_id).await?;
         }
         Commands::ListKeys { admin_key } => {
-            list_keys(&config, &admin_key).await?;
+            list_keys(&config, admin_key.as_deref()).await?;
         }
         Commands::RevokeKey { admin_key, user_id } => {
-            revoke_key(&config, &admin_key, &user_id).await?;
+            revoke_key(&config, admin_key.as_deref(), &user_id).await?;
+        }
+        Commands::GrantRole { admin_key, user_id, role } => {
+            set_role(&config, admin_key.as_deref(), &user_id, role).await?;
+        }
+        Commands::RevokeRole { admin_key, user_id } => {
+            set_role(&config, admin_key.as_deref(), &user_id, Role::User).await?;
+        }
+        Commands::Stats { admin_key, format, range } => {
+            print_stats(&config, admin_key.as_deref(), format, range).await?;
+        }
+        Commands::Maintenance { admin_key, off, minutes, relay_messages, message } => {
+            let request = (!off).then(|| SetMaintenanceRequest {
+                ends_at: minutes.map(|m| chrono::Utc::now().timestamp() + i64::from(m) * 60),
+                relay_messages,
+                message,
+            });
+            set_maintenance(&config, admin_key.as_deref(), request).await?;
+        }
+        Commands::GenerateTotpSecret { account } => {
+            generate_totp_secret(&account);
+        }
+        Commands::Doctor { fix } => {
+            doctor(&config, fix).await?;
         }
         Commands::Run => {
             run_server(config).await?;
@@ -120,18 +225,61 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn generate_key(config: &Config, admin_key: &str, user_id: Option<String>) -> anyhow::Result<()> {
-    if admin_key != config.admin.master_key {
-        anyhow::bail!("Invalid admin key");
+/// Check a break-glass master key if one was given, otherwise prompt for
+/// the current TOTP code
+fn authorize(config: &Config, admin_key: Option<&str>) -> anyhow::Result<()> {
+    if let Some(admin_key) = admin_key {
+        if !crypto::constant_time_eq(admin_key, &config.admin.master_key) {
+            anyhow::bail!("Invalid admin key");
+        }
+        if config.admin.totp_secret.is_some() {
+            eprintln!("Warning: used the break-glass master key; prefer the TOTP code");
+        }
+        return Ok(());
+    }
+
+    let Some(secret) = &config.admin.totp_secret else {
+        anyhow::bail!(
+            "No TOTP secret configured: pass --admin-key, or set [admin] totp_secret \
+             (see generate-totp-secret)"
+        );
+    };
+
+    eprint!("Admin code: ");
+    std::io::Write::flush(&mut std::io::stderr())?;
+    let mut code = String::new();
+    std::io::stdin().read_line(&mut code)?;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    if !totp::verify(secret, &code, now) {
+        anyhow::bail!("Invalid admin code");
     }
+    Ok(())
+}
+
+fn generate_totp_secret(account: &str) {
+    let secret = totp::generate_secret();
+
+    println!("=== New Admin TOTP Secret ===");
+    println!("Secret: {}", secret);
+    println!("URI: {}", totp::provisioning_uri(&secret, account));
+    println!("=============================");
+    println!("Add it to config.toml under [admin] as totp_secret = \"{}\"", secret);
+    println!("and enroll the secret or URI in an authenticator app.");
+}
+
+async fn generate_key(config: &Config, admin_key: Option<&str>, user_id: Option<String>) -> anyhow::Result<()> {
+    authorize(config, admin_key)?;
 
     let storage = Storage::new(&config.storage.database_path).await?;
 
-    let user_id = user_id.unwrap_or_else(|| crypto::generate_user_id());
+    let user_id = user_id.unwrap_or_else(crypto::generate_user_id);
     let access_key = crypto::generate_access_key();
     let key_hash = crypto::hash_access_key(&access_key);
 
-    storage.create_user(&user_id, &key_hash).await?;
+    storage.create_user(&user_id, &key_hash, Role::User).await?;
 
     println!("=== New Access Key Generated ===");
     println!("User ID: {}", user_id);
@@ -143,30 +291,27 @@ async fn generate_key(config: &Config, admin_key: &str, user_id: Option<String>)
     Ok(())
 }
 
-async fn list_keys(config: &Config, admin_key: &str) -> anyhow::Result<()> {
-    if admin_key != config.admin.master_key {
-        anyhow::bail!("Invalid admin key");
-    }
+async fn list_keys(config: &Config, admin_key: Option<&str>) -> anyhow::Result<()> {
+    authorize(config, admin_key)?;
 
     let storage = Storage::new(&config.storage.database_path).await?;
     let users = storage.list_users().await?;
 
     println!("=== Registered Users ===");
     for user in users {
-        println!("User ID: {} | Created: {} | Active: {}",
+        println!("User ID: {} | Created: {} | Active: {} | Role: {}",
             user.user_id,
             user.created_at,
-            user.is_active
+            user.is_active,
+            user.role
         );
     }
 
     Ok(())
 }
 
-async fn revoke_key(config: &Config, admin_key: &str, user_id: &str) -> anyhow::Result<()> {
-    if admin_key != config.admin.master_key {
-        anyhow::bail!("Invalid admin key");
-    }
+async fn revoke_key(config: &Config, admin_key: Option<&str>, user_id: &str) -> anyhow::Result<()> {
+    authorize(config, admin_key)?;
 
     let storage = Storage::new(&config.storage.database_path).await?;
     storage.deactivate_user(user_id).await?;
@@ -176,6 +321,93 @@ async fn revoke_key(config: &Config, admin_key: &str, user_id: &str) -> anyhow::
     Ok(())
 }
 
+async fn set_role(config: &Config, admin_key: Option<&str>, user_id: &str, role: Role) -> anyhow::Result<()> {
+    authorize(config, admin_key)?;
+
+    let storage = Storage::new(&config.storage.database_path).await?;
+    if !storage.set_user_role(user_id, role).await? {
+        anyhow::bail!("User {} not found", user_id);
+    }
+
+    println!("User {} now has role: {}", user_id, role);
+
+    Ok(())
+}
+
+async fn set_maintenance(
+    config: &Config,
+    admin_key: Option<&str>,
+    request: Option<SetMaintenanceRequest>,
+) -> anyhow::Result<()> {
+    authorize(config, admin_key)?;
+
+    let storage = Storage::new(&config.storage.database_path).await?;
+    match request {
+        Some(request) => {
+            let maintenance = request
+                .validated(chrono::Utc::now().timestamp())
+                .map_err(|e| anyhow::anyhow!(e))?;
+            storage.set_maintenance(&maintenance).await?;
+            println!("Maintenance mode is on");
+            if !maintenance.relay_messages {
+                println!("Messages are not relayed; pass --relay-messages to keep them flowing");
+            }
+        }
+        None => {
+            if !storage.clear_maintenance().await? {
+                anyhow::bail!("Maintenance mode is already off");
+            }
+            println!("Maintenance mode is off");
+        }
+    }
+
+    Ok(())
+}
+
+async fn print_stats(
+    config: &Config,
+    admin_key: Option<&str>,
+    format: StatsFormat,
+    range: StatsRange,
+) -> anyhow::Result<()> {
+    authorize(config, admin_key)?;
+
+    let storage = Storage::new(&config.storage.database_path).await?;
+    // Make today's totals current even if the server hasn't snapshotted yet
+    storage.record_daily_stats().await?;
+    let stats: Vec<DailyStats> = storage.get_daily_stats(range.0).await?;
+
+    match format {
+        StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        StatsFormat::Csv => {
+            println!("{}", DailyStats::CSV_HEADER);
+            for day in &stats {
+                println!("{}", day.to_csv_row());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn doctor(config: &Config, fix: bool) -> anyhow::Result<()> {
+    let checks = doctor::run(config, fix).await;
+
+    for check in &checks {
+        println!("[{}] {}: {}", check.status, check.name, check.detail);
+        if let Some(hint) = &check.hint {
+            println!("       -> {}", hint);
+        }
+    }
+
+    let failed = checks.iter().filter(|c| c.status == doctor::Status::Fail).count();
+    if failed > 0 {
+        anyhow::bail!("{} check(s) failed", failed);
+    }
+
+    Ok(())
+}
+
 async fn run_server(config: Arc<Config>) -> anyhow::Result<()> {
     tracing::info!("Starting PrivMsg Server v{}", env!("CARGO_PKG_VERSION"));
 
@@ -185,39 +417,79 @@ async fn run_server(config: Arc<Config>) -> anyhow::Result<()> {
     // Initialize WebSocket manager
     let ws_manager = Arc::new(WebSocketManager::new());
 
+    let cleanup = Arc::new(CleanupService::new(Arc::clone(&storage)));
+
+    systemd::spawn_watchdog(Arc::clone(&storage));
+
+    let client_policy = policy::load(&config.policy).await?.map(Arc::new);
+
+    let maintenance = Arc::new(MaintenanceMode::load(Arc::clone(&storage), Arc::clone(&ws_manager)).await?);
+    if maintenance.current().is_some() {
+        tracing::warn!("Starting in maintenance mode");
+    }
+
     // Create app state
-    let storage_for_cleanup = Arc::clone(&storage);
     let state = AppState {
         config: config.clone(),
         storage,
         ws_manager,
+        cleanup: Arc::clone(&cleanup),
+        flood: Arc::new(FloodGuard::new(&config)),
+        login_challenges: Arc::new(LoginChallenges::new(&config.login_challenge)?),
+        maintenance: Arc::clone(&maintenance),
+        client_policy,
     };
 
     // Build routes
     let app = Router::new()
         // Health check
         .route("/health", get(handlers::health::health_check))
+        .route("/api/v1/capabilities", get(handlers::capabilities::get_capabilities))
 
         // Authentication
+        .route("/api/v1/auth/challenge", get(handlers::auth::get_challenge))
         .route("/api/v1/auth/login", post(handlers::auth::login))
         .route("/api/v1/auth/refresh", post(handlers::auth::refresh_token))
         .route("/api/v1/auth/logout", post(handlers::auth::logout))
+        .route("/api/v1/auth/logout-all", post(handlers::auth::logout_all))
 
         // User management
         .route("/api/v1/users/me", get(handlers::users::get_current_user))
         .route("/api/v1/users/:user_id", get(handlers::users::get_user))
         .route("/api/v1/users/me/profile", post(handlers::users::update_profile))
         .route("/api/v1/users/me/devices", get(handlers::users::list_devices))
-        .route("/api/v1/users/me/devices/:device_id", delete(handlers::users::remove_device))
+        .route("/api/v1/users/:user_id/devices", get(handlers::users::get_device_keys))
+        .route(
+            "/api/v1/users/me/devices/:device_id",
+            put(handlers::users::rename_device).delete(handlers::users::remove_device),
+        )
+        .route(
+            "/api/v1/users/me/devices/:device_id/certificate",
+            put(handlers::users::certify_device),
+        )
+
+        // Groups
+        .route(
+            "/api/v1/groups",
+            get(handlers::groups::list_groups).post(handlers::groups::create_group),
+        )
+        .route("/api/v1/groups/:group_id", get(handlers::groups::get_group))
+        .route("/api/v1/groups/:group_id/members", post(handlers::groups::add_members))
+        .route(
+            "/api/v1/groups/:group_id/members/:user_id",
+            delete(handlers::groups::remove_member),
+        )
 
         // Messages
         .route("/api/v1/messages/pending", get(handlers::messages::get_pending_messages))
         .route("/api/v1/messages/ack", post(handlers::messages::acknowledge_messages))
+        .route("/api/v1/messages/:message_id", delete(handlers::messages::retract_message))
 
         // Files
         .route("/api/v1/files/upload", post(handlers::files::upload_file))
         .route("/api/v1/files/:file_id", get(handlers::files::download_file))
         .route("/api/v1/files/:file_id", delete(handlers::files::delete_file))
+        .route("/api/v1/files/:file_id/extend", post(handlers::files::extend_file))
 
         // WebSocket for real-time communication
         .route("/ws", get(handlers::websocket::websocket_handler))
@@ -225,12 +497,26 @@ async fn run_server(config: Arc<Config>) -> anyhow::Result<()> {
         // Admin routes
         .route("/api/v1/admin/users", post(handlers::admin::create_user))
         .route("/api/v1/admin/users/:user_id", delete(handlers::admin::delete_user))
+        .route("/api/v1/admin/users/:user_id/role", post(handlers::admin::set_user_role))
+        .route("/api/v1/admin/users/:user_id/deactivate", post(handlers::admin::deactivate_user))
         .route("/api/v1/admin/stats", get(handlers::admin::get_stats))
+        .route("/api/v1/admin/cleanup", get(handlers::admin::get_cleanup_metrics))
+        .route("/api/v1/admin/cleanup", post(handlers::admin::run_cleanup))
+        .route("/api/v1/admin/announcement", put(handlers::admin::set_announcement))
+        .route("/api/v1/admin/announcement", delete(handlers::admin::clear_announcement))
+        .route("/api/v1/admin/maintenance", put(handlers::admin::set_maintenance))
+        .route("/api/v1/admin/maintenance", delete(handlers::admin::clear_maintenance))
+        .route("/api/v1/admin/bans", get(handlers::admin::list_bans))
+        .route("/api/v1/admin/bans/:kind/:value", delete(handlers::admin::clear_ban))
 
         // TURN credentials
         .route("/api/v1/turn/credentials", get(handlers::turn::get_credentials))
 
+        // Client policy
+        .route("/api/v1/client-policy", get(handlers::policy::get_client_policy))
+
         // Add middleware
+        .layer(axum::middleware::from_fn(handlers::legacy_admin_key))
         .layer(TraceLayer::new_for_http())
         .layer(
             CorsLayer::new()
@@ -245,28 +531,54 @@ async fn run_server(config: Arc<Config>) -> anyhow::Result<()> {
 
     let listener = TcpListener::bind(&addr).await?;
 
+    maintenance.spawn(maintenance::POLL_INTERVAL);
+
     // Start cleanup task
-    let cleanup_interval = config.storage.cleanup_interval_minutes;
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(
-            std::time::Duration::from_secs(cleanup_interval * 60)
-        );
-        loop {
-            interval.tick().await;
-            match storage_for_cleanup.cleanup_expired().await {
-                Ok((msgs, files)) => {
-                    if msgs > 0 || files > 0 {
-                        tracing::info!("Cleanup: removed {} messages, {} files", msgs, files);
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("Cleanup failed: {}", e);
-                }
+    cleanup.spawn(
+        std::time::Duration::from_secs(config.storage.cleanup_interval_minutes * 60),
+        std::time::Duration::from_secs(config.storage.cleanup_jitter_seconds),
+    );
+
+    systemd::ready(&format!("Listening on {}", addr));
+
+    // Flood bans need the peer address
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    tracing::info!("Server stopped");
+    Ok(())
+}
+
+/// Ctrl-C, or SIGTERM from systemd or Docker
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Listening for Ctrl-C failed: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Listening for SIGTERM failed: {}", e);
+                std::future::pending::<()>().await;
             }
         }
-    });
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    axum::serve(listener, app).await?;
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 
-    Ok(())
+    tracing::info!("Shutting down");
+    systemd::stopping();
 }
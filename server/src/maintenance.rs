@@ -0,0 +1,155 @@
+//! Maintenance mode
+//!
+//! Turned on and off through the admin API or the `maintenance` CLI command
+//! and kept in the database, so it survives restarts and reaches every
+//! server sharing it. Handlers check the copy held here; servers that did
+//! not make the change pick it up on the next poll.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use privmsg_proto::maintenance::Maintenance;
+
+use crate::error::{AppError, Result};
+use crate::models::WsServerMessage;
+use crate::storage::Storage;
+use crate::websocket::WebSocketManager;
+
+/// How often a change made through the CLI or another server is noticed
+pub const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+pub struct MaintenanceMode {
+    storage: Arc<Storage>,
+    ws_manager: Arc<WebSocketManager>,
+    current: RwLock<Option<Maintenance>>,
+}
+
+impl MaintenanceMode {
+    pub async fn load(storage: Arc<Storage>, ws_manager: Arc<WebSocketManager>) -> anyhow::Result<Self> {
+        let current = storage.get_maintenance().await?;
+        Ok(Self {
+            storage,
+            ws_manager,
+            current: RwLock::new(current),
+        })
+    }
+
+    /// The maintenance in force; None when the server is running normally
+    pub fn current(&self) -> Option<Maintenance> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Fail with [`AppError::Maintenance`] while maintenance is on
+    pub fn check(&self) -> Result<()> {
+        match self.current() {
+            Some(maintenance) => Err(AppError::Maintenance(maintenance.ends_at)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether messages between connected clients are relayed
+    pub fn relays_messages(&self) -> bool {
+        self.current().is_none_or(|m| m.relay_messages)
+    }
+
+    /// Turn maintenance on, or off with `None`; returns whether anything
+    /// changed
+    pub async fn set(&self, maintenance: Option<Maintenance>) -> anyhow::Result<bool> {
+        let stored = match &maintenance {
+            Some(maintenance) => {
+                self.storage.set_maintenance(maintenance).await?;
+                false
+            }
+            // Also covers maintenance turned on elsewhere since the last poll
+            None => self.storage.clear_maintenance().await?,
+        };
+        Ok(self.apply(maintenance).await || stored)
+    }
+
+    /// Reload from the database, telling clients if it changed
+    pub async fn refresh(&self) -> anyhow::Result<bool> {
+        let maintenance = self.storage.get_maintenance().await?;
+        Ok(self.apply(maintenance).await)
+    }
+
+    async fn apply(&self, maintenance: Option<Maintenance>) -> bool {
+        {
+            let mut current = self.current.write().unwrap();
+            if *current == maintenance {
+                return false;
+            }
+            current.clone_from(&maintenance);
+        }
+
+        match &maintenance {
+            Some(m) => tracing::warn!(
+                "Maintenance mode on (relay messages: {}, ends at: {:?})",
+                m.relay_messages,
+                m.ends_at
+            ),
+            None => tracing::info!("Maintenance mode off"),
+        }
+        self.ws_manager
+            .broadcast_all(WsServerMessage::Maintenance { maintenance })
+            .await;
+        true
+    }
+
+    /// Poll the database every `interval`
+    pub fn spawn(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = self.refresh().await {
+                    tracing::error!("Reloading maintenance mode failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::Outbox;
+
+    #[tokio::test]
+    async fn test_maintenance_toggle() {
+        let path = std::env::temp_dir().join(format!("privmsg-maintenance-{}.db", uuid::Uuid::new_v4()));
+        let storage = Arc::new(Storage::new(path.to_str().unwrap()).await.unwrap());
+        let ws_manager = Arc::new(WebSocketManager::new());
+        let mode = MaintenanceMode::load(Arc::clone(&storage), Arc::clone(&ws_manager))
+            .await
+            .unwrap();
+        assert!(mode.check().is_ok());
+        assert!(mode.relays_messages());
+
+        let outbox = Outbox::new(8);
+        ws_manager.register("user1", "device1", outbox.clone());
+        let maintenance = Maintenance {
+            ends_at: Some(1_900_000_000),
+            relay_messages: false,
+            message: Some("Database upgrade".to_string()),
+        };
+        assert!(mode.set(Some(maintenance.clone())).await.unwrap());
+        assert!(!mode.set(Some(maintenance.clone())).await.unwrap());
+        assert!(matches!(mode.check(), Err(AppError::Maintenance(Some(1_900_000_000)))));
+        assert!(!mode.relays_messages());
+        assert!(matches!(
+            outbox.recv().await,
+            Some(WsServerMessage::Maintenance { maintenance: Some(m) }) if m == maintenance
+        ));
+        assert_eq!(outbox.depth(), 0);
+
+        // Another server, or the CLI, turned it off
+        storage.clear_maintenance().await.unwrap();
+        assert!(mode.refresh().await.unwrap());
+        assert!(mode.check().is_ok());
+        assert!(matches!(
+            outbox.recv().await,
+            Some(WsServerMessage::Maintenance { maintenance: None })
+        ));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
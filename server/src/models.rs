@@ -2,6 +2,12 @@
 
 use serde::{Deserialize, Serialize};
 
+pub use privmsg_proto::announcement::{Announcement, AnnouncementSeverity, MAX_ANNOUNCEMENT_LEN};
+pub use privmsg_proto::maintenance::{Maintenance, MAX_MAINTENANCE_MESSAGE_LEN};
+pub use privmsg_proto::envelope::CanonicalEnvelope;
+pub use privmsg_proto::pow::{LoginChallenge, LoginProof};
+pub use privmsg_proto::{DeliveryMode, ErrorCode, LastSeenVisibility, MessagePriority, RevocationReason};
+
 // ============================================================================
 // User Models
 // ============================================================================
@@ -16,6 +22,21 @@ pub struct User {
     pub created_at: String,
     pub last_seen_at: Option<String>,
     pub is_active: bool,
+    pub role: String, // "admin", "moderator", "user"
+    pub status_emoji: Option<String>,
+    pub status_text: Option<String>,
+    pub bio: Option<String>,
+    pub last_seen_visibility: String,
+}
+
+impl User {
+    pub fn role(&self) -> Role {
+        self.role.parse().unwrap_or(Role::User)
+    }
+
+    pub fn last_seen_visibility(&self) -> LastSeenVisibility {
+        self.last_seen_visibility.parse().unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +46,23 @@ pub struct UserProfile {
     pub avatar_file_id: Option<String>,
     pub public_key: Option<String>,
     pub last_seen_at: Option<String>,
+    pub status_emoji: Option<String>,
+    pub status_text: Option<String>,
+    pub bio: Option<String>,
+    /// Only included in the user's own profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_visibility: Option<LastSeenVisibility>,
+}
+
+impl UserProfile {
+    /// The profile as the user themselves sees it, with privacy settings
+    pub fn own(user: User) -> Self {
+        let visibility = user.last_seen_visibility();
+        Self {
+            last_seen_visibility: Some(visibility),
+            ..user.into()
+        }
+    }
 }
 
 impl From<User> for UserProfile {
@@ -35,6 +73,71 @@ impl From<User> for UserProfile {
             avatar_file_id: user.avatar_file_id,
             public_key: user.public_key,
             last_seen_at: user.last_seen_at,
+            status_emoji: user.status_emoji,
+            status_text: user.status_text,
+            bio: user.bio,
+            last_seen_visibility: None,
+        }
+    }
+}
+
+// ============================================================================
+// Roles
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Moderator,
+    User,
+}
+
+/// Actions on the admin API that are gated by role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    CreateUsers,
+    DeleteUsers,
+    ViewStats,
+    ManageRoles,
+    RunCleanup,
+    ManageBans,
+    ManageAnnouncements,
+    ManageMaintenance,
+}
+
+impl Role {
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Moderator => matches!(
+                permission,
+                Permission::CreateUsers | Permission::ViewStats | Permission::ManageBans
+            ),
+            Role::User => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Admin => write!(f, "admin"),
+            Role::Moderator => write!(f, "moderator"),
+            Role::User => write!(f, "user"),
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "moderator" => Ok(Role::Moderator),
+            "user" => Ok(Role::User),
+            _ => Err(format!("Unknown role: {}", s)),
         }
     }
 }
@@ -51,10 +154,80 @@ pub struct Device {
     pub device_type: String, // "android", "windows", "linux"
     pub push_token: Option<String>,
     pub public_key: String, // Per-device public key for multi-device E2EE
+    /// The user's identity key signing this device's key, set by the
+    /// device holding it; clients check it
+    pub key_signature: Option<String>,
     pub created_at: String,
     pub last_active_at: String,
 }
 
+/// A device of another user, as much as senders need to encrypt for it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceKey {
+    pub device_id: String,
+    pub public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl From<Device> for DeviceKey {
+    fn from(device: Device) -> Self {
+        Self {
+            device_id: device.device_id,
+            public_key: device.public_key,
+            signature: device.key_signature,
+        }
+    }
+}
+
+// ============================================================================
+// Group Models
+// ============================================================================
+
+/// A group conversation. The server only keeps its membership; senders
+/// encrypt each message for every member and send the copies one by one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub group_id: String,
+    pub name: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub member_ids: Vec<String>,
+}
+
+/// Longest group name in characters
+pub const MAX_GROUP_NAME_LEN: usize = 64;
+
+/// Most members a group can have, the creator included
+pub const MAX_GROUP_MEMBERS: usize = 256;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    /// Members besides the creator, who is always one
+    #[serde(default)]
+    pub member_ids: Vec<String>,
+}
+
+impl CreateGroupRequest {
+    /// The trimmed name, if it is neither empty nor too long
+    pub fn validated_name(&self) -> Result<&str, String> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err("name is empty".to_string());
+        }
+        if name.chars().count() > MAX_GROUP_NAME_LEN {
+            return Err(format!("name is longer than {} characters", MAX_GROUP_NAME_LEN));
+        }
+        Ok(name)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddGroupMembersRequest {
+    pub member_ids: Vec<String>,
+}
+
 // ============================================================================
 // Session Models
 // ============================================================================
@@ -82,6 +255,7 @@ pub struct PendingMessage {
     pub recipient_device_id: Option<String>, // None = all devices
     pub encrypted_content: String, // Base64-encoded encrypted message
     pub message_type: String,      // "text", "voice", "video", "file", "call_signal"
+    pub priority: String,
     pub created_at: String,
     pub expires_at: String,
 }
@@ -95,10 +269,35 @@ pub struct MessageEnvelope {
     pub encrypted_content: String,
     pub message_type: MessageType,
     pub timestamp: i64,
+    /// How long to hold the envelope for offline devices, capped by the
+    /// server's maximum. Zero means it is never stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u32>,
+    #[serde(default)]
+    pub priority: MessagePriority,
+    #[serde(default)]
+    pub delivery_mode: DeliveryMode,
+}
+
+impl From<CanonicalEnvelope> for MessageEnvelope {
+    fn from(envelope: CanonicalEnvelope) -> Self {
+        Self {
+            message_id: envelope.message_id,
+            sender_id: envelope.sender_id,
+            recipient_id: envelope.recipient_id,
+            recipient_device_id: envelope.recipient_device_id,
+            encrypted_content: envelope.encrypted_content,
+            message_type: envelope.message_type.into(),
+            timestamp: envelope.timestamp,
+            ttl_seconds: envelope.ttl_seconds,
+            priority: envelope.priority,
+            delivery_mode: envelope.delivery_mode,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
+#[serde(from = "String", into = "String")]
 pub enum MessageType {
     Text,
     Voice,
@@ -110,22 +309,37 @@ pub enum MessageType {
     ReadReceipt,
     TypingIndicator,
     DeviceSync,
+    /// Application-defined `x-<vendor>-<name>` type, relayed as-is
+    Custom(String),
+    /// A type this server doesn't know, e.g. from a newer client; also
+    /// relayed as-is
+    Other(String),
 }
 
-impl ToString for MessageType {
-    fn to_string(&self) -> String {
-        match self {
-            MessageType::Text => "text".to_string(),
-            MessageType::Voice => "voice".to_string(),
-            MessageType::Video => "video".to_string(),
-            MessageType::File => "file".to_string(),
-            MessageType::Image => "image".to_string(),
-            MessageType::CallSignal => "call_signal".to_string(),
-            MessageType::KeyExchange => "key_exchange".to_string(),
-            MessageType::ReadReceipt => "read_receipt".to_string(),
-            MessageType::TypingIndicator => "typing_indicator".to_string(),
-            MessageType::DeviceSync => "device_sync".to_string(),
-        }
+impl MessageType {
+    /// Whether the server should relay it. Types in the custom namespace
+    /// have to be well-formed.
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, MessageType::Other(t) if t.starts_with(privmsg_proto::CUSTOM_MESSAGE_TYPE_PREFIX))
+    }
+}
+
+impl std::fmt::Display for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            MessageType::Text => "text",
+            MessageType::Voice => "voice",
+            MessageType::Video => "video",
+            MessageType::File => "file",
+            MessageType::Image => "image",
+            MessageType::CallSignal => "call_signal",
+            MessageType::KeyExchange => "key_exchange",
+            MessageType::ReadReceipt => "read_receipt",
+            MessageType::TypingIndicator => "typing_indicator",
+            MessageType::DeviceSync => "device_sync",
+            MessageType::Custom(t) | MessageType::Other(t) => t,
+        };
+        write!(f, "{}", s)
     }
 }
 
@@ -142,11 +356,18 @@ impl From<String> for MessageType {
             "read_receipt" => MessageType::ReadReceipt,
             "typing_indicator" => MessageType::TypingIndicator,
             "device_sync" => MessageType::DeviceSync,
-            _ => MessageType::Text,
+            _ if privmsg_proto::is_custom_message_type(&s) => MessageType::Custom(s),
+            _ => MessageType::Other(s),
         }
     }
 }
 
+impl From<MessageType> for String {
+    fn from(message_type: MessageType) -> Self {
+        message_type.to_string()
+    }
+}
+
 // ============================================================================
 // File Models
 // ============================================================================
@@ -171,6 +392,18 @@ pub struct FileUploadResponse {
     pub expires_at: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtendFileRequest {
+    /// Keep the file at least this many hours from now
+    pub hours: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileExpiryResponse {
+    pub file_id: String,
+    pub expires_at: i64,
+}
+
 // ============================================================================
 // WebSocket Models
 // ============================================================================
@@ -206,15 +439,29 @@ pub enum WsServerMessage {
     #[serde(rename = "authenticated")]
     Authenticated { user_id: String, device_id: String },
 
+    /// `message_id` names the envelope, for errors about one message
     #[serde(rename = "error")]
-    Error { code: String, message: String },
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message_id: Option<String>,
+    },
 
     #[serde(rename = "message")]
     Message(MessageEnvelope),
 
+    /// Many envelopes in one frame, used when replaying a pending backlog
+    #[serde(rename = "message_batch")]
+    MessageBatch { messages: Vec<MessageEnvelope> },
+
     #[serde(rename = "ack")]
     Acknowledged { message_ids: Vec<String> },
 
+    /// Messages from this user that a device of `recipient_id` received
+    #[serde(rename = "delivered")]
+    Delivered { recipient_id: String, message_ids: Vec<String> },
+
     #[serde(rename = "typing")]
     Typing { user_id: String, is_typing: bool },
 
@@ -232,6 +479,24 @@ pub enum WsServerMessage {
 
     #[serde(rename = "user_offline")]
     UserOffline { user_id: String },
+
+    /// One of the user's other devices got a new name
+    #[serde(rename = "device_renamed")]
+    DeviceRenamed { device_id: String, device_name: String },
+
+    /// The session is no longer valid; the server closes the socket next
+    #[serde(rename = "session_revoked")]
+    SessionRevoked { reason: RevocationReason },
+
+    /// The current service notice, sent on connect and whenever it changes;
+    /// `None` when it was cleared
+    #[serde(rename = "announcement")]
+    Announcement { announcement: Option<Announcement> },
+
+    /// Maintenance mode, sent on connect while it is on and whenever it is
+    /// turned on or off; `None` when it was turned off
+    #[serde(rename = "maintenance")]
+    Maintenance { maintenance: Option<Maintenance> },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -279,6 +544,9 @@ pub struct LoginRequest {
     pub device_name: String,
     pub device_type: String,
     pub device_public_key: String,
+    /// Required when the server asks for a proof of work
+    #[serde(default)]
+    pub proof: Option<LoginProof>,
 }
 
 #[derive(Debug, Serialize)]
@@ -287,6 +555,9 @@ pub struct LoginResponse {
     pub device_id: String,
     pub expires_at: i64,
     pub user: UserProfile,
+    /// The operator's current service notice, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub announcement: Option<Announcement>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -305,6 +576,127 @@ pub struct UpdateProfileRequest {
     pub display_name: Option<String>,
     pub avatar_file_id: Option<String>,
     pub public_key: Option<String>,
+    /// Omitted fields are left as they are; an empty string clears one
+    pub status_emoji: Option<String>,
+    pub status_text: Option<String>,
+    pub bio: Option<String>,
+    pub last_seen_visibility: Option<LastSeenVisibility>,
+}
+
+/// Length limits in characters
+pub const MAX_STATUS_EMOJI_LEN: usize = 8;
+pub const MAX_STATUS_TEXT_LEN: usize = 100;
+pub const MAX_BIO_LEN: usize = 500;
+
+impl UpdateProfileRequest {
+    /// Check the free-text fields against their length limits
+    pub fn validate(&self) -> Result<(), String> {
+        let fields = [
+            ("status_emoji", &self.status_emoji, MAX_STATUS_EMOJI_LEN),
+            ("status_text", &self.status_text, MAX_STATUS_TEXT_LEN),
+            ("bio", &self.bio, MAX_BIO_LEN),
+        ];
+        for (name, value, max) in fields {
+            if value.as_ref().is_some_and(|v| v.chars().count() > max) {
+                return Err(format!("{} is longer than {} characters", name, max));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameDeviceRequest {
+    pub device_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CertifyDeviceRequest {
+    pub signature: String,
+}
+
+/// Longest device name in characters
+pub const MAX_DEVICE_NAME_LEN: usize = 64;
+
+impl RenameDeviceRequest {
+    /// The trimmed name, if it is neither empty nor too long
+    pub fn validated_name(&self) -> Result<&str, String> {
+        let name = self.device_name.trim();
+        if name.is_empty() {
+            return Err("device_name is empty".to_string());
+        }
+        if name.chars().count() > MAX_DEVICE_NAME_LEN {
+            return Err(format!("device_name is longer than {} characters", MAX_DEVICE_NAME_LEN));
+        }
+        Ok(name)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAnnouncementRequest {
+    pub text: String,
+    #[serde(default)]
+    pub severity: AnnouncementSeverity,
+    /// Unix seconds; shown until cleared when omitted
+    pub expires_at: Option<i64>,
+}
+
+impl SetAnnouncementRequest {
+    /// The announcement to publish, if the text fits and it hasn't expired
+    /// already
+    pub fn validated(self, now: i64) -> Result<Announcement, String> {
+        let text = self.text.trim();
+        if text.is_empty() {
+            return Err("text is empty".to_string());
+        }
+        if text.chars().count() > MAX_ANNOUNCEMENT_LEN {
+            return Err(format!("text is longer than {} characters", MAX_ANNOUNCEMENT_LEN));
+        }
+        if self.expires_at.is_some_and(|at| at <= now) {
+            return Err("expires_at is in the past".to_string());
+        }
+        Ok(Announcement {
+            id: uuid::Uuid::new_v4().to_string(),
+            text: text.to_string(),
+            severity: self.severity,
+            expires_at: self.expires_at,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetMaintenanceRequest {
+    /// Unix seconds the server is expected back
+    pub ends_at: Option<i64>,
+    /// Keep relaying messages between connected clients
+    #[serde(default)]
+    pub relay_messages: bool,
+    pub message: Option<String>,
+}
+
+impl SetMaintenanceRequest {
+    /// The maintenance to turn on, if the message fits and the end isn't in
+    /// the past
+    pub fn validated(self, now: i64) -> Result<Maintenance, String> {
+        let message = self
+            .message
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty());
+        if message
+            .as_ref()
+            .is_some_and(|m| m.chars().count() > MAX_MAINTENANCE_MESSAGE_LEN)
+        {
+            return Err(format!("message is longer than {} characters", MAX_MAINTENANCE_MESSAGE_LEN));
+        }
+        if self.ends_at.is_some_and(|at| at <= now) {
+            return Err("ends_at is in the past".to_string());
+        }
+        Ok(Maintenance {
+            ends_at: self.ends_at,
+            relay_messages: self.relay_messages,
+            message,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -323,14 +715,20 @@ pub struct TurnCredentialsResponse {
 
 #[derive(Debug, Deserialize)]
 pub struct CreateUserRequest {
-    pub admin_key: String,
     pub user_id: Option<String>,
+    pub role: Option<Role>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct CreateUserResponse {
     pub user_id: String,
     pub access_key: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRoleRequest {
+    pub role: Role,
 }
 
 #[derive(Debug, Serialize)]
@@ -341,4 +739,278 @@ pub struct ServerStats {
     pub pending_messages: i64,
     pub stored_files: i64,
     pub storage_used_mb: f64,
+    /// Outgoing WebSocket queues; filled in by the WebSocket manager
+    pub ws_queues: QueueStats,
+}
+
+/// Depth of the per-connection WebSocket send queues
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueStats {
+    /// Frames waiting across all connections
+    pub queued_frames: i64,
+    /// Deepest queue right now
+    pub max_depth: i64,
+    /// Deepest any open connection's queue has been
+    pub high_water: i64,
+    /// Presence and typing frames dropped because a queue was full
+    pub dropped_frames: i64,
+    /// Connections closed because they stopped reading
+    pub slow_disconnects: i64,
+}
+
+/// One day of usage history
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct DailyStats {
+    /// `YYYY-MM-DD`, UTC
+    pub day: String,
+    pub total_users: i64,
+    pub active_users: i64,
+    pub messages_relayed: i64,
+    pub stored_files: i64,
+    pub storage_bytes: i64,
+}
+
+impl DailyStats {
+    pub const CSV_HEADER: &'static str =
+        "day,total_users,active_users,messages_relayed,stored_files,storage_bytes";
+
+    pub fn to_csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.day,
+            self.total_users,
+            self.active_users,
+            self.messages_relayed,
+            self.stored_files,
+            self.storage_bytes
+        )
+    }
+}
+
+// ============================================================================
+// Cleanup
+// ============================================================================
+
+/// Rows removed by cleanup, by category
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ExpiredCounts {
+    pub messages: u64,
+    pub files: u64,
+    pub sessions: u64,
+}
+
+impl ExpiredCounts {
+    pub fn is_empty(&self) -> bool {
+        self.messages == 0 && self.files == 0 && self.sessions == 0
+    }
+
+    pub fn add(&mut self, other: &ExpiredCounts) {
+        self.messages += other.messages;
+        self.files += other.files;
+        self.sessions += other.sessions;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CleanupTrigger {
+    Scheduled,
+    Manual,
+}
+
+/// Outcome of one cleanup run on this server
+#[derive(Debug, Clone, Serialize)]
+pub struct CleanupReport {
+    pub trigger: CleanupTrigger,
+    pub started_at: String,
+    pub duration_ms: u64,
+    pub removed: ExpiredCounts,
+}
+
+/// Who a flood ban applies to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum BanTarget {
+    User(String),
+    Ip(std::net::IpAddr),
+}
+
+impl BanTarget {
+    /// From the `/admin/bans/:kind/:value` path
+    pub fn parse(kind: &str, value: &str) -> Option<Self> {
+        match kind {
+            "user" => Some(BanTarget::User(value.to_string())),
+            "ip" => value.parse().ok().map(BanTarget::Ip),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::User(user_id) => write!(f, "user {}", user_id),
+            BanTarget::Ip(ip) => write!(f, "address {}", ip),
+        }
+    }
+}
+
+/// A temporary ban imposed by flood detection
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    pub created_at: String,
+    pub expires_at: String,
+}
+
+/// Cleanup activity of this server since it started
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CleanupMetrics {
+    pub runs: u64,
+    /// Runs skipped because another server held the cleanup lock
+    pub skipped: u64,
+    pub failures: u64,
+    pub removed: ExpiredCounts,
+    pub last_run: Option<CleanupReport>,
+}
+
+/// A look-back period such as `30d` or `12w`, in days
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsRange(pub u32);
+
+impl std::str::FromStr for StatsRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid range: {} (expected e.g. 30d or 12w)", s);
+        let (count, unit) = s.split_at(s.len().saturating_sub(1));
+        let count: u32 = count.parse().map_err(|_| invalid())?;
+        let days = match unit {
+            "d" => count,
+            "w" => count.checked_mul(7).ok_or_else(invalid)?,
+            _ => return Err(invalid()),
+        };
+        if days == 0 {
+            return Err(invalid());
+        }
+        Ok(StatsRange(days))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_range() {
+        assert_eq!("30d".parse::<StatsRange>(), Ok(StatsRange(30)));
+        assert_eq!("2w".parse::<StatsRange>(), Ok(StatsRange(14)));
+        assert!("0d".parse::<StatsRange>().is_err());
+        assert!("30".parse::<StatsRange>().is_err());
+        assert!("d".parse::<StatsRange>().is_err());
+        assert!("".parse::<StatsRange>().is_err());
+    }
+
+    #[test]
+    fn test_role_permissions() {
+        assert!(Role::Admin.has_permission(Permission::ManageRoles));
+        assert!(Role::Moderator.has_permission(Permission::CreateUsers));
+        assert!(!Role::Moderator.has_permission(Permission::DeleteUsers));
+        assert!(!Role::User.has_permission(Permission::ViewStats));
+        assert!(!Role::Moderator.has_permission(Permission::ManageAnnouncements));
+        assert!(!Role::Moderator.has_permission(Permission::ManageMaintenance));
+    }
+
+    #[test]
+    fn test_maintenance_limits() {
+        let request = |message: Option<&str>, ends_at| SetMaintenanceRequest {
+            ends_at,
+            relay_messages: true,
+            message: message.map(str::to_string),
+        };
+        let maintenance = request(Some(" Database upgrade "), Some(101)).validated(100).unwrap();
+        assert_eq!(maintenance.message.as_deref(), Some("Database upgrade"));
+        assert!(maintenance.relay_messages);
+        assert_eq!(request(Some("  "), None).validated(100).unwrap().message, None);
+        assert!(request(None, Some(100)).validated(100).is_err());
+        assert!(request(Some(&"é".repeat(MAX_MAINTENANCE_MESSAGE_LEN + 1)), None)
+            .validated(100)
+            .is_err());
+    }
+
+    #[test]
+    fn test_announcement_limits() {
+        let request = |text: &str, expires_at| SetAnnouncementRequest {
+            text: text.to_string(),
+            severity: AnnouncementSeverity::Warning,
+            expires_at,
+        };
+        let announcement = request(" Maintenance at 22:00 ", Some(101)).validated(100).unwrap();
+        assert_eq!(announcement.text, "Maintenance at 22:00");
+        assert_ne!(
+            announcement.id,
+            request("Maintenance at 22:00", None).validated(100).unwrap().id
+        );
+        assert!(request("Late", Some(100)).validated(100).is_err());
+        assert!(request("  ", None).validated(100).is_err());
+        assert!(request(&"é".repeat(MAX_ANNOUNCEMENT_LEN + 1), None).validated(100).is_err());
+    }
+
+    #[test]
+    fn test_device_name_limits() {
+        let request = |name: &str| RenameDeviceRequest {
+            device_name: name.to_string(),
+        };
+        assert_eq!(request("  Work laptop ").validated_name(), Ok("Work laptop"));
+        assert!(request(" ").validated_name().is_err());
+        assert!(request(&"é".repeat(MAX_DEVICE_NAME_LEN)).validated_name().is_ok());
+        assert!(request(&"é".repeat(MAX_DEVICE_NAME_LEN + 1)).validated_name().is_err());
+    }
+
+    #[test]
+    fn test_group_name_limits() {
+        let request = |name: &str| CreateGroupRequest {
+            name: name.to_string(),
+            member_ids: Vec::new(),
+        };
+        assert_eq!(request(" Team ").validated_name(), Ok("Team"));
+        assert!(request("").validated_name().is_err());
+        assert!(request(&"é".repeat(MAX_GROUP_NAME_LEN + 1)).validated_name().is_err());
+    }
+
+    #[test]
+    fn test_update_profile_limits() {
+        let request = |bio: &str| UpdateProfileRequest {
+            display_name: None,
+            avatar_file_id: None,
+            public_key: None,
+            status_emoji: Some("🌴".into()),
+            status_text: Some(String::new()),
+            bio: Some(bio.into()),
+            last_seen_visibility: None,
+        };
+        assert!(request(&"é".repeat(MAX_BIO_LEN)).validate().is_ok());
+        assert!(request(&"é".repeat(MAX_BIO_LEN + 1)).validate().is_err());
+    }
+
+    #[test]
+    fn test_role_round_trip() {
+        for role in [Role::Admin, Role::Moderator, Role::User] {
+            assert_eq!(role.to_string().parse::<Role>().unwrap(), role);
+        }
+        assert!("root".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_message_type_pass_through() {
+        for wire in ["call_signal", "x-acme-poll", "read_sync"] {
+            let json = format!("\"{}\"", wire);
+            let message_type: MessageType = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&message_type).unwrap(), json);
+            assert!(message_type.is_valid());
+        }
+        assert_eq!(MessageType::from("x-acme-poll".to_string()), MessageType::Custom("x-acme-poll".into()));
+        assert!(!MessageType::from("x-Acme".to_string()).is_valid());
+    }
 }
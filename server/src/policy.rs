@@ -0,0 +1,104 @@
+//! Signed client policy
+//!
+//! The policy is built from `[policy]` and signed once at startup, so a
+//! config change takes effect on restart like every other setting.
+
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::Utc;
+use privmsg_proto::policy::{signing_bytes, ClientPolicy, SignedPolicy};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use tokio::fs;
+
+use crate::config::PolicyConfig;
+
+/// Load the signing key, generating it on first use
+pub async fn load_signing_key(path: &str) -> anyhow::Result<Ed25519KeyPair> {
+    let pkcs8 = if Path::new(path).exists() {
+        fs::read(path).await?
+    } else {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("Failed to generate policy signing key"))?;
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        fs::write(path, pkcs8.as_ref()).await?;
+        tracing::info!("Created policy signing key at {}", path);
+        pkcs8.as_ref().to_vec()
+    };
+    Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|_| anyhow::anyhow!("Invalid policy signing key at {}", path))
+}
+
+/// The policy clients get, or None when `[policy]` is disabled
+pub async fn load(config: &PolicyConfig) -> anyhow::Result<Option<SignedPolicy>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    let key = load_signing_key(&config.signing_key_path).await?;
+    Ok(Some(sign(&key, &client_policy(config))?))
+}
+
+pub fn client_policy(config: &PolicyConfig) -> ClientPolicy {
+    ClientPolicy {
+        version: config.version,
+        issued_at: Utc::now().timestamp(),
+        disappearing_seconds: config.disappearing_seconds,
+        notification_preview: config.notification_preview,
+        last_seen_visibility: config.last_seen_visibility,
+        endpoints: config.endpoints.clone(),
+        disabled_features: config.disabled_features.clone(),
+        locked: config.locked.clone(),
+        flags: config.flags.clone(),
+    }
+}
+
+pub fn sign(key: &Ed25519KeyPair, policy: &ClientPolicy) -> anyhow::Result<SignedPolicy> {
+    let policy = serde_json::to_string(policy)?;
+    let signature = key.sign(&signing_bytes(&policy));
+    Ok(SignedPolicy {
+        signature: STANDARD.encode(signature.as_ref()),
+        public_key: STANDARD.encode(key.public_key().as_ref()),
+        policy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    #[tokio::test]
+    async fn test_signed_policy() {
+        let path = std::env::temp_dir()
+            .join(format!("privmsg-policy-{}", uuid::Uuid::new_v4()))
+            .join("key.pk8");
+        let path = path.to_str().unwrap();
+
+        let mut config = PolicyConfig {
+            signing_key_path: path.to_string(),
+            disappearing_seconds: Some(86400),
+            ..Default::default()
+        };
+        assert!(load(&config).await.unwrap().is_none());
+
+        config.enabled = true;
+        let signed = load(&config).await.unwrap().unwrap();
+        let public_key = STANDARD.decode(&signed.public_key).unwrap();
+        let signature = STANDARD.decode(&signed.signature).unwrap();
+        UnparsedPublicKey::new(&ED25519, &public_key)
+            .verify(&signed.signing_bytes(), &signature)
+            .unwrap();
+        let policy: ClientPolicy = serde_json::from_str(&signed.policy).unwrap();
+        assert_eq!(policy.disappearing_seconds, Some(86400));
+
+        // The key is kept, so clients' pins survive restarts
+        let again = load(&config).await.unwrap().unwrap();
+        assert_eq!(again.public_key, signed.public_key);
+        let _ = std::fs::remove_dir_all(Path::new(path).parent().unwrap());
+    }
+}
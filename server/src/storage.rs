@@ -7,6 +7,10 @@ use std::path::Path;
 use crate::crypto;
 use crate::models::*;
 
+/// Version of the schema `initialize_schema` creates, kept in the
+/// database's `PRAGMA user_version`. Bump it whenever the schema changes.
+pub const SCHEMA_VERSION: i64 = 2;
+
 pub struct Storage {
     pool: Pool<Sqlite>,
 }
@@ -42,7 +46,8 @@ impl Storage {
                 public_key TEXT,
                 created_at TEXT NOT NULL DEFAULT (datetime('now')),
                 last_seen_at TEXT,
-                is_active INTEGER NOT NULL DEFAULT 1
+                is_active INTEGER NOT NULL DEFAULT 1,
+                role TEXT NOT NULL DEFAULT 'user'
             );
 
             CREATE TABLE IF NOT EXISTS devices (
@@ -68,6 +73,16 @@ impl Storage {
                 FOREIGN KEY (device_id) REFERENCES devices(device_id) ON DELETE CASCADE
             );
 
+            -- Pairs of users who have messaged each other, both directions
+            CREATE TABLE IF NOT EXISTS contacts (
+                user_id TEXT NOT NULL,
+                contact_id TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                PRIMARY KEY (user_id, contact_id),
+                FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE,
+                FOREIGN KEY (contact_id) REFERENCES users(user_id) ON DELETE CASCADE
+            );
+
             CREATE TABLE IF NOT EXISTS pending_messages (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 message_id TEXT NOT NULL UNIQUE,
@@ -81,6 +96,15 @@ impl Storage {
                 FOREIGN KEY (sender_id) REFERENCES users(user_id) ON DELETE CASCADE
             );
 
+            CREATE TABLE IF NOT EXISTS delivery_receipts (
+                message_id TEXT PRIMARY KEY,
+                sender_id TEXT NOT NULL,
+                recipient_id TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                expires_at TEXT NOT NULL,
+                FOREIGN KEY (sender_id) REFERENCES users(user_id) ON DELETE CASCADE
+            );
+
             CREATE TABLE IF NOT EXISTS files (
                 file_id TEXT PRIMARY KEY,
                 uploader_id TEXT NOT NULL,
@@ -94,16 +118,144 @@ impl Storage {
                 FOREIGN KEY (uploader_id) REFERENCES users(user_id) ON DELETE CASCADE
             );
 
+            CREATE TABLE IF NOT EXISTS file_downloads (
+                file_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                PRIMARY KEY (file_id, user_id)
+            );
+
+            CREATE TABLE IF NOT EXISTS daily_stats (
+                day TEXT PRIMARY KEY,
+                total_users INTEGER NOT NULL DEFAULT 0,
+                active_users INTEGER NOT NULL DEFAULT 0,
+                messages_relayed INTEGER NOT NULL DEFAULT 0,
+                stored_files INTEGER NOT NULL DEFAULT 0,
+                storage_bytes INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS pending_calls (
+                call_id TEXT PRIMARY KEY,
+                recipient_id TEXT NOT NULL,
+                signal TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                expires_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS maintenance_locks (
+                name TEXT PRIMARY KEY,
+                holder TEXT NOT NULL,
+                expires_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS announcements (
+                id TEXT PRIMARY KEY,
+                announcement TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+
+            CREATE TABLE IF NOT EXISTS maintenance_mode (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                maintenance TEXT NOT NULL,
+                started_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+
             CREATE INDEX IF NOT EXISTS idx_devices_user ON devices(user_id);
             CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(user_id);
             CREATE INDEX IF NOT EXISTS idx_pending_recipient ON pending_messages(recipient_id);
             CREATE INDEX IF NOT EXISTS idx_pending_expires ON pending_messages(expires_at);
+            CREATE INDEX IF NOT EXISTS idx_receipts_sender ON delivery_receipts(sender_id);
             CREATE INDEX IF NOT EXISTS idx_files_expires ON files(expires_at);
+            CREATE INDEX IF NOT EXISTS idx_pending_calls_recipient ON pending_calls(recipient_id);
             "#,
         )
         .execute(&self.pool)
         .await?;
 
+        // Columns added after the initial schema
+        self.ensure_column("users", "role", "TEXT NOT NULL DEFAULT 'user'").await?;
+        self.ensure_column("users", "status_emoji", "TEXT").await?;
+        self.ensure_column("users", "status_text", "TEXT").await?;
+        self.ensure_column("users", "bio", "TEXT").await?;
+        self.ensure_column("users", "last_seen_visibility", "TEXT NOT NULL DEFAULT 'everyone'")
+            .await?;
+        self.ensure_column("pending_messages", "priority", "TEXT NOT NULL DEFAULT 'normal'")
+            .await?;
+        self.ensure_column("devices", "key_signature", "TEXT").await?;
+
+        // Never lower the version a newer server wrote
+        let (version,): (i64,) = sqlx::query_as("PRAGMA user_version").fetch_one(&self.pool).await?;
+        self.migrate(version).await?;
+        if version < SCHEMA_VERSION {
+            sqlx::query(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+                .execute(&self.pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Upgrade a database at schema `version` step by step. Each step runs
+    /// in one transaction, and new databases start at 0 and run them all.
+    async fn migrate(&self, version: i64) -> anyhow::Result<()> {
+        // 2: group conversations
+        if version < 2 {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS groups (
+                    group_id TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    created_by TEXT NOT NULL,
+                    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+                );
+
+                CREATE TABLE IF NOT EXISTS group_members (
+                    group_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    added_at TEXT NOT NULL DEFAULT (datetime('now')),
+                    PRIMARY KEY (group_id, user_id),
+                    FOREIGN KEY (group_id) REFERENCES groups(group_id) ON DELETE CASCADE,
+                    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+                );
+
+                CREATE INDEX IF NOT EXISTS idx_group_members_user ON group_members(user_id);
+                "#,
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("PRAGMA user_version = 2").execute(&mut *tx).await?;
+            tx.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Schema version of an existing database, read without creating or
+    /// migrating it. Databases from before versioning report 0.
+    pub async fn schema_version(database_path: &str) -> anyhow::Result<i64> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(&format!("sqlite:{}?mode=ro", database_path))
+            .await?;
+        let (version,): (i64,) = sqlx::query_as("PRAGMA user_version").fetch_one(&pool).await?;
+        pool.close().await;
+        Ok(version)
+    }
+
+    /// Add a column to an existing table if a database created by an older
+    /// server version doesn't have it yet
+    async fn ensure_column(&self, table: &str, column: &str, definition: &str) -> anyhow::Result<()> {
+        let columns: Vec<(String,)> =
+            sqlx::query_as(&format!("SELECT name FROM pragma_table_info('{}')", table))
+                .fetch_all(&self.pool)
+                .await?;
+
+        if !columns.iter().any(|(name,)| name == column) {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(&self.pool)
+                .await?;
+        }
+
         Ok(())
     }
 
@@ -111,12 +263,13 @@ impl Storage {
     // User Operations
     // ========================================================================
 
-    pub async fn create_user(&self, user_id: &str, key_hash: &str) -> anyhow::Result<()> {
+    pub async fn create_user(&self, user_id: &str, key_hash: &str, role: Role) -> anyhow::Result<()> {
         sqlx::query(
-            "INSERT INTO users (user_id, key_hash, created_at) VALUES (?, ?, datetime('now'))",
+            "INSERT INTO users (user_id, key_hash, role, created_at) VALUES (?, ?, ?, datetime('now'))",
         )
         .bind(user_id)
         .bind(key_hash)
+        .bind(role.to_string())
         .execute(&self.pool)
         .await?;
 
@@ -126,7 +279,8 @@ impl Storage {
     pub async fn get_user(&self, user_id: &str) -> anyhow::Result<Option<User>> {
         let user = sqlx::query_as::<_, User>(
             "SELECT user_id, key_hash, display_name, avatar_file_id, public_key,
-                    created_at, last_seen_at, is_active
+                    created_at, last_seen_at, is_active, role,
+                    status_emoji, status_text, bio, last_seen_visibility
              FROM users WHERE user_id = ?",
         )
         .bind(user_id)
@@ -145,23 +299,31 @@ impl Storage {
         }
     }
 
+    /// Fields passed as `None` keep their value. Status and bio are cleared
+    /// by an empty string.
     pub async fn update_user_profile(
         &self,
         user_id: &str,
-        display_name: Option<&str>,
-        avatar_file_id: Option<&str>,
-        public_key: Option<&str>,
+        update: &UpdateProfileRequest,
     ) -> anyhow::Result<()> {
         sqlx::query(
             "UPDATE users SET
                 display_name = COALESCE(?, display_name),
                 avatar_file_id = COALESCE(?, avatar_file_id),
-                public_key = COALESCE(?, public_key)
+                public_key = COALESCE(?, public_key),
+                status_emoji = NULLIF(COALESCE(?, status_emoji), ''),
+                status_text = NULLIF(COALESCE(?, status_text), ''),
+                bio = NULLIF(COALESCE(?, bio), ''),
+                last_seen_visibility = COALESCE(?, last_seen_visibility)
              WHERE user_id = ?",
         )
-        .bind(display_name)
-        .bind(avatar_file_id)
-        .bind(public_key)
+        .bind(&update.display_name)
+        .bind(&update.avatar_file_id)
+        .bind(&update.public_key)
+        .bind(&update.status_emoji)
+        .bind(&update.status_text)
+        .bind(&update.bio)
+        .bind(update.last_seen_visibility.map(|v| v.as_str()))
         .bind(user_id)
         .execute(&self.pool)
         .await?;
@@ -178,6 +340,21 @@ impl Storage {
         Ok(())
     }
 
+    /// Whether the database answers, for health checks
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// False for deactivated and deleted accounts
+    pub async fn is_user_active(&self, user_id: &str) -> anyhow::Result<bool> {
+        let active: Option<(bool,)> = sqlx::query_as("SELECT is_active FROM users WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(active.is_some_and(|(active,)| active))
+    }
+
     pub async fn deactivate_user(&self, user_id: &str) -> anyhow::Result<()> {
         sqlx::query("UPDATE users SET is_active = 0 WHERE user_id = ?")
             .bind(user_id)
@@ -196,7 +373,8 @@ impl Storage {
     pub async fn list_users(&self) -> anyhow::Result<Vec<User>> {
         let users = sqlx::query_as::<_, User>(
             "SELECT user_id, key_hash, display_name, avatar_file_id, public_key,
-                    created_at, last_seen_at, is_active
+                    created_at, last_seen_at, is_active, role,
+                    status_emoji, status_text, bio, last_seen_visibility
              FROM users ORDER BY created_at DESC",
         )
         .fetch_all(&self.pool)
@@ -205,6 +383,17 @@ impl Storage {
         Ok(users)
     }
 
+    /// Change a user's role. Returns false if the user doesn't exist.
+    pub async fn set_user_role(&self, user_id: &str, role: Role) -> anyhow::Result<bool> {
+        let result = sqlx::query("UPDATE users SET role = ? WHERE user_id = ?")
+            .bind(role.to_string())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     pub async fn delete_user(&self, user_id: &str) -> anyhow::Result<()> {
         sqlx::query("DELETE FROM users WHERE user_id = ?")
             .bind(user_id)
@@ -214,6 +403,166 @@ impl Storage {
         Ok(())
     }
 
+    // ========================================================================
+    // Contacts
+    // ========================================================================
+
+    /// Remember that two users have messaged each other
+    pub async fn record_contact(&self, user_id: &str, contact_id: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO contacts (user_id, contact_id) VALUES (?, ?), (?, ?)",
+        )
+        .bind(user_id)
+        .bind(contact_id)
+        .bind(contact_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn are_contacts(&self, user_id: &str, contact_id: &str) -> anyhow::Result<bool> {
+        let row: Option<(i64,)> =
+            sqlx::query_as("SELECT 1 FROM contacts WHERE user_id = ? AND contact_id = ?")
+                .bind(user_id)
+                .bind(contact_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn list_contacts(&self, user_id: &str) -> anyhow::Result<Vec<String>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT contact_id FROM contacts WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Whether `viewer` may see when `user` was last online
+    pub async fn can_see_last_seen(&self, viewer: &str, user: &User) -> anyhow::Result<bool> {
+        if viewer == user.user_id {
+            return Ok(true);
+        }
+        Ok(match user.last_seen_visibility() {
+            LastSeenVisibility::Contacts => self.are_contacts(&user.user_id, viewer).await?,
+            visibility => visibility.allows(false),
+        })
+    }
+
+    // ========================================================================
+    // Groups
+    // ========================================================================
+
+    /// Create a group with `member_ids`, which should include the creator
+    pub async fn create_group(&self, name: &str, created_by: &str, member_ids: &[String]) -> anyhow::Result<Group> {
+        let group_id = uuid::Uuid::new_v4().to_string();
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO groups (group_id, name, created_by, created_at) VALUES (?, ?, ?, datetime('now'))")
+            .bind(&group_id)
+            .bind(name)
+            .bind(created_by)
+            .execute(&mut *tx)
+            .await?;
+        for member_id in member_ids {
+            sqlx::query("INSERT OR IGNORE INTO group_members (group_id, user_id) VALUES (?, ?)")
+                .bind(&group_id)
+                .bind(member_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        self.get_group(&group_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Group {} vanished", group_id))
+    }
+
+    pub async fn get_group(&self, group_id: &str) -> anyhow::Result<Option<Group>> {
+        let row: Option<(String, String, String, String)> = sqlx::query_as(
+            "SELECT group_id, name, created_by, created_at FROM groups WHERE group_id = ?",
+        )
+        .bind(group_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((group_id, name, created_by, created_at)) = row else {
+            return Ok(None);
+        };
+
+        let members: Vec<(String,)> = sqlx::query_as(
+            "SELECT user_id FROM group_members WHERE group_id = ? ORDER BY added_at ASC, user_id ASC",
+        )
+        .bind(&group_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(Group {
+            group_id,
+            name,
+            created_by,
+            created_at,
+            member_ids: members.into_iter().map(|(id,)| id).collect(),
+        }))
+    }
+
+    /// Groups `user_id` is a member of
+    pub async fn list_user_groups(&self, user_id: &str) -> anyhow::Result<Vec<Group>> {
+        let ids: Vec<(String,)> = sqlx::query_as("SELECT group_id FROM group_members WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut groups = Vec::with_capacity(ids.len());
+        for (group_id,) in ids {
+            if let Some(group) = self.get_group(&group_id).await? {
+                groups.push(group);
+            }
+        }
+        Ok(groups)
+    }
+
+    pub async fn add_group_members(&self, group_id: &str, member_ids: &[String]) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for member_id in member_ids {
+            sqlx::query("INSERT OR IGNORE INTO group_members (group_id, user_id) VALUES (?, ?)")
+                .bind(group_id)
+                .bind(member_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Remove a member; the group goes with its last one. Returns whether
+    /// `user_id` was a member.
+    pub async fn remove_group_member(&self, group_id: &str, user_id: &str) -> anyhow::Result<bool> {
+        let removed = sqlx::query("DELETE FROM group_members WHERE group_id = ? AND user_id = ?")
+            .bind(group_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected()
+            > 0;
+
+        sqlx::query(
+            "DELETE FROM groups WHERE group_id = ?
+             AND NOT EXISTS (SELECT 1 FROM group_members WHERE group_id = ?)",
+        )
+        .bind(group_id)
+        .bind(group_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(removed)
+    }
+
     // ========================================================================
     // Device Operations
     // ========================================================================
@@ -245,7 +594,7 @@ impl Storage {
     pub async fn get_device(&self, device_id: &str) -> anyhow::Result<Option<Device>> {
         let device = sqlx::query_as::<_, Device>(
             "SELECT device_id, user_id, device_name, device_type, push_token, public_key,
-                    created_at, last_active_at
+                    key_signature, created_at, last_active_at
              FROM devices WHERE device_id = ?",
         )
         .bind(device_id)
@@ -258,7 +607,7 @@ impl Storage {
     pub async fn list_user_devices(&self, user_id: &str) -> anyhow::Result<Vec<Device>> {
         let devices = sqlx::query_as::<_, Device>(
             "SELECT device_id, user_id, device_name, device_type, push_token, public_key,
-                    created_at, last_active_at
+                    key_signature, created_at, last_active_at
              FROM devices WHERE user_id = ? ORDER BY last_active_at DESC",
         )
         .bind(user_id)
@@ -268,6 +617,24 @@ impl Storage {
         Ok(devices)
     }
 
+    pub async fn rename_device(&self, device_id: &str, device_name: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE devices SET device_name = ? WHERE device_id = ?")
+            .bind(device_name)
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_device_signature(&self, device_id: &str, signature: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE devices SET key_signature = ? WHERE device_id = ?")
+            .bind(signature)
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn update_device_activity(&self, device_id: &str) -> anyhow::Result<()> {
         sqlx::query("UPDATE devices SET last_active_at = datetime('now') WHERE device_id = ?")
             .bind(device_id)
@@ -335,6 +702,23 @@ impl Storage {
         Ok(session)
     }
 
+    /// Whether the device still has a valid session on an active account.
+    /// Checked per device, not per token, because refreshing replaces the
+    /// token an open socket authenticated with.
+    pub async fn is_device_session_active(&self, device_id: &str) -> anyhow::Result<bool> {
+        let active: Option<(i64,)> = sqlx::query_as(
+            "SELECT 1 FROM sessions s JOIN users u ON u.user_id = s.user_id
+             WHERE s.device_id = ? AND s.is_valid = 1 AND s.expires_at > datetime('now')
+               AND u.is_active = 1
+             LIMIT 1",
+        )
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(active.is_some())
+    }
+
     pub async fn invalidate_session(&self, token: &str) -> anyhow::Result<()> {
         let token_hash = crypto::hash_access_key(token);
 
@@ -359,13 +743,23 @@ impl Storage {
     // Message Operations
     // ========================================================================
 
-    pub async fn store_pending_message(&self, envelope: &MessageEnvelope, ttl_hours: i64) -> anyhow::Result<()> {
-        let expires_at = Utc::now() + Duration::hours(ttl_hours);
+    /// Hold `envelope` for offline devices until its TTL runs out, for at
+    /// most `max_ttl_hours`. Envelopes with a zero TTL aren't stored.
+    pub async fn store_pending_message(&self, envelope: &MessageEnvelope, max_ttl_hours: i64) -> anyhow::Result<()> {
+        let max_ttl = Duration::hours(max_ttl_hours);
+        let ttl = match envelope.ttl_seconds {
+            Some(0) => return Ok(()),
+            Some(secs) => Duration::seconds(secs as i64).min(max_ttl),
+            None => max_ttl,
+        };
+        // SQLite's own format, so TTLs shorter than a day compare correctly
+        // against datetime('now')
+        let expires_at = (Utc::now() + ttl).format("%Y-%m-%d %H:%M:%S").to_string();
 
         sqlx::query(
             "INSERT INTO pending_messages
-             (message_id, sender_id, recipient_id, recipient_device_id, encrypted_content, message_type, created_at, expires_at)
-             VALUES (?, ?, ?, ?, ?, ?, datetime('now'), ?)",
+             (message_id, sender_id, recipient_id, recipient_device_id, encrypted_content, message_type, priority, created_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, datetime('now'), ?)",
         )
         .bind(&envelope.message_id)
         .bind(&envelope.sender_id)
@@ -373,7 +767,8 @@ impl Storage {
         .bind(&envelope.recipient_device_id)
         .bind(&envelope.encrypted_content)
         .bind(envelope.message_type.to_string())
-        .bind(expires_at.to_rfc3339())
+        .bind(envelope.priority.as_str())
+        .bind(expires_at)
         .execute(&self.pool)
         .await?;
 
@@ -384,11 +779,12 @@ impl Storage {
         let messages = if let Some(did) = device_id {
             sqlx::query_as::<_, PendingMessage>(
                 "SELECT id, message_id, sender_id, recipient_id, recipient_device_id,
-                        encrypted_content, message_type, created_at, expires_at
+                        encrypted_content, message_type, priority, created_at, expires_at
                  FROM pending_messages
                  WHERE recipient_id = ? AND (recipient_device_id IS NULL OR recipient_device_id = ?)
                  AND expires_at > datetime('now')
-                 ORDER BY created_at ASC",
+                 ORDER BY CASE priority WHEN 'high' THEN 0 WHEN 'normal' THEN 1 ELSE 2 END,
+                          created_at ASC, id ASC",
             )
             .bind(user_id)
             .bind(did)
@@ -397,10 +793,11 @@ impl Storage {
         } else {
             sqlx::query_as::<_, PendingMessage>(
                 "SELECT id, message_id, sender_id, recipient_id, recipient_device_id,
-                        encrypted_content, message_type, created_at, expires_at
+                        encrypted_content, message_type, priority, created_at, expires_at
                  FROM pending_messages
                  WHERE recipient_id = ? AND expires_at > datetime('now')
-                 ORDER BY created_at ASC",
+                 ORDER BY CASE priority WHEN 'high' THEN 0 WHEN 'normal' THEN 1 ELSE 2 END,
+                          created_at ASC, id ASC",
             )
             .bind(user_id)
             .fetch_all(&self.pool)
@@ -410,17 +807,232 @@ impl Storage {
         Ok(messages)
     }
 
-    pub async fn delete_pending_messages(&self, message_ids: &[String]) -> anyhow::Result<()> {
+    /// Delete messages `recipient_id` received, returning the id and sender
+    /// of each one that was still pending. Ids of messages to someone else
+    /// are ignored.
+    pub async fn delete_pending_messages(
+        &self,
+        recipient_id: &str,
+        message_ids: &[String],
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        // One transaction so batched acks cost a single commit
+        let mut tx = self.pool.begin().await?;
+        let mut deleted = Vec::new();
         for message_id in message_ids {
+            let sender: Option<(String,)> = sqlx::query_as(
+                "SELECT sender_id FROM pending_messages WHERE message_id = ? AND recipient_id = ?",
+            )
+            .bind(message_id)
+            .bind(recipient_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some((sender_id,)) = sender else {
+                continue;
+            };
             sqlx::query("DELETE FROM pending_messages WHERE message_id = ?")
                 .bind(message_id)
-                .execute(&self.pool)
+                .execute(&mut *tx)
                 .await?;
+            deleted.push((message_id.clone(), sender_id));
         }
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+
+    /// Drop the copies of a message `sender_id` sent that no device has
+    /// received yet: the message itself and the copies for each group
+    /// member or device, whose ids start with `message_id/`
+    pub async fn delete_sent_pending(&self, sender_id: &str, message_id: &str) -> anyhow::Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM pending_messages
+             WHERE sender_id = ?
+               AND (message_id = ? OR substr(message_id, 1, length(?) + 1) = ? || '/')",
+        )
+        .bind(sender_id)
+        .bind(message_id)
+        .bind(message_id)
+        .bind(message_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Hold delivery receipts for a sender who is offline, for at most
+    /// `max_ttl_hours`
+    pub async fn store_delivery_receipts(
+        &self,
+        sender_id: &str,
+        recipient_id: &str,
+        message_ids: &[String],
+        max_ttl_hours: i64,
+    ) -> anyhow::Result<()> {
+        let expires_at = (Utc::now() + Duration::hours(max_ttl_hours))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string();
+        let mut tx = self.pool.begin().await?;
+        for message_id in message_ids {
+            sqlx::query(
+                "INSERT OR IGNORE INTO delivery_receipts (message_id, sender_id, recipient_id, created_at, expires_at)
+                 VALUES (?, ?, ?, datetime('now'), ?)",
+            )
+            .bind(message_id)
+            .bind(sender_id)
+            .bind(recipient_id)
+            .bind(&expires_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Remove and return the unexpired receipts held for `sender_id`, as
+    /// the message ids delivered to each recipient, oldest first
+    pub async fn take_delivery_receipts(&self, sender_id: &str) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+        let mut tx = self.pool.begin().await?;
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT recipient_id, message_id FROM delivery_receipts
+             WHERE sender_id = ? AND expires_at > datetime('now')
+             ORDER BY created_at ASC, rowid ASC",
+        )
+        .bind(sender_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM delivery_receipts WHERE sender_id = ?")
+            .bind(sender_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        let mut receipts: Vec<(String, Vec<String>)> = Vec::new();
+        for (recipient_id, message_id) in rows {
+            match receipts.iter_mut().find(|(id, _)| *id == recipient_id) {
+                Some((_, message_ids)) => message_ids.push(message_id),
+                None => receipts.push((recipient_id, vec![message_id])),
+            }
+        }
+        Ok(receipts)
+    }
+
+    /// Hold a call offer for an offline recipient for `ttl`, replacing any
+    /// earlier offer for the same call
+    pub async fn store_call_offer(&self, signal: &CallSignal, ttl: Duration) -> anyhow::Result<()> {
+        let expires_at = (Utc::now() + ttl).format("%Y-%m-%d %H:%M:%S").to_string();
+        sqlx::query(
+            "INSERT OR REPLACE INTO pending_calls (call_id, recipient_id, signal, created_at, expires_at)
+             VALUES (?, ?, ?, datetime('now'), ?)",
+        )
+        .bind(&signal.call_id)
+        .bind(&signal.recipient_id)
+        .bind(serde_json::to_string(signal)?)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Unexpired call offers for `user_id`, oldest first
+    pub async fn get_call_offers(&self, user_id: &str) -> anyhow::Result<Vec<CallSignal>> {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            "SELECT signal FROM pending_calls
+             WHERE recipient_id = ? AND expires_at > datetime('now')
+             ORDER BY created_at ASC",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(signal,)| serde_json::from_str(&signal).ok())
+            .collect())
+    }
+
+    pub async fn clear_call_offer(&self, call_id: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM pending_calls WHERE call_id = ?")
+            .bind(call_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // ========================================================================
+    // Announcements
+    // ========================================================================
+
+    /// Replace the current announcement
+    pub async fn set_announcement(&self, announcement: &Announcement) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM announcements").execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO announcements (id, announcement) VALUES (?, ?)")
+            .bind(&announcement.id)
+            .bind(serde_json::to_string(announcement)?)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Returns whether there was one
+    pub async fn clear_announcement(&self) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM announcements")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The current announcement, unless it expired
+    pub async fn get_announcement(&self) -> anyhow::Result<Option<Announcement>> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT announcement FROM announcements LIMIT 1")
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row
+            .and_then(|(announcement,)| serde_json::from_str::<Announcement>(&announcement).ok())
+            .filter(|a| a.is_active(Utc::now().timestamp())))
+    }
+
+    // ========================================================================
+    // Maintenance mode
+    // ========================================================================
+
+    /// Turn maintenance mode on, or update it if it already is
+    pub async fn set_maintenance(&self, maintenance: &Maintenance) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO maintenance_mode (id, maintenance) VALUES (1, ?)
+             ON CONFLICT(id) DO UPDATE SET maintenance = excluded.maintenance",
+        )
+        .bind(serde_json::to_string(maintenance)?)
+        .execute(&self.pool)
+        .await?;
 
         Ok(())
     }
 
+    /// Returns whether it was on
+    pub async fn clear_maintenance(&self) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM maintenance_mode")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_maintenance(&self) -> anyhow::Result<Option<Maintenance>> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT maintenance FROM maintenance_mode WHERE id = 1")
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.and_then(|(maintenance,)| serde_json::from_str(&maintenance).ok()))
+    }
+
     pub async fn count_pending_messages(&self) -> anyhow::Result<i64> {
         let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM pending_messages")
             .fetch_one(&self.pool)
@@ -490,6 +1102,43 @@ impl Storage {
             .bind(file_id)
             .execute(&self.pool)
             .await?;
+        sqlx::query("DELETE FROM file_downloads WHERE file_id = ?")
+            .bind(file_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remember that `user_id` fetched the file. Only the uploader and
+    /// users who downloaded a file may extend or delete it.
+    pub async fn record_file_download(&self, file_id: &str, user_id: &str) -> anyhow::Result<()> {
+        sqlx::query("INSERT OR IGNORE INTO file_downloads (file_id, user_id) VALUES (?, ?)")
+            .bind(file_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn has_downloaded_file(&self, file_id: &str, user_id: &str) -> anyhow::Result<bool> {
+        let row: Option<(i64,)> =
+            sqlx::query_as("SELECT 1 FROM file_downloads WHERE file_id = ? AND user_id = ?")
+                .bind(file_id)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn set_file_expiry(&self, file_id: &str, expires_at: DateTime<Utc>) -> anyhow::Result<()> {
+        sqlx::query("UPDATE files SET expires_at = ? WHERE file_id = ?")
+            .bind(expires_at.to_rfc3339())
+            .bind(file_id)
+            .execute(&self.pool)
+            .await?;
 
         Ok(())
     }
@@ -514,7 +1163,7 @@ impl Storage {
     // Cleanup Operations
     // ========================================================================
 
-    pub async fn cleanup_expired(&self) -> anyhow::Result<(i64, i64)> {
+    pub async fn cleanup_expired(&self) -> anyhow::Result<ExpiredCounts> {
         // Get expired file IDs before deleting metadata (for potential file system cleanup)
         let _expired_files: Vec<(String,)> = sqlx::query_as(
             "SELECT file_id FROM files WHERE expires_at <= datetime('now')",
@@ -528,6 +1177,14 @@ impl Storage {
         )
         .execute(&self.pool)
         .await?;
+        let calls_result = sqlx::query(
+            "DELETE FROM pending_calls WHERE expires_at <= datetime('now')",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("DELETE FROM delivery_receipts WHERE expires_at <= datetime('now')")
+            .execute(&self.pool)
+            .await?;
 
         // Delete expired file metadata
         let files_result = sqlx::query(
@@ -535,13 +1192,51 @@ impl Storage {
         )
         .execute(&self.pool)
         .await?;
+        sqlx::query("DELETE FROM file_downloads WHERE file_id NOT IN (SELECT file_id FROM files)")
+            .execute(&self.pool)
+            .await?;
 
         // Delete expired sessions
-        sqlx::query("DELETE FROM sessions WHERE expires_at <= datetime('now') OR is_valid = 0")
+        let sessions_result =
+            sqlx::query("DELETE FROM sessions WHERE expires_at <= datetime('now') OR is_valid = 0")
+                .execute(&self.pool)
+                .await?;
+
+        Ok(ExpiredCounts {
+            messages: messages_result.rows_affected() + calls_result.rows_affected(),
+            files: files_result.rows_affected(),
+            sessions: sessions_result.rows_affected(),
+        })
+    }
+
+    /// Take the named maintenance lock for `ttl`, unless another holder has
+    /// it. Re-acquiring a lock you already hold extends it. The expiry keeps
+    /// a crashed holder from blocking everyone else for good.
+    pub async fn try_acquire_lock(&self, name: &str, holder: &str, ttl: Duration) -> anyhow::Result<bool> {
+        let expires_at = (Utc::now() + ttl).format("%Y-%m-%d %H:%M:%S").to_string();
+        let result = sqlx::query(
+            "INSERT INTO maintenance_locks (name, holder, expires_at) VALUES (?, ?, ?)
+             ON CONFLICT(name) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+             WHERE maintenance_locks.holder = excluded.holder
+                OR maintenance_locks.expires_at <= datetime('now')",
+        )
+        .bind(name)
+        .bind(holder)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn release_lock(&self, name: &str, holder: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM maintenance_locks WHERE name = ? AND holder = ?")
+            .bind(name)
+            .bind(holder)
             .execute(&self.pool)
             .await?;
 
-        Ok((messages_result.rows_affected() as i64, files_result.rows_affected() as i64))
+        Ok(())
     }
 
     // ========================================================================
@@ -568,6 +1263,59 @@ impl Storage {
             pending_messages,
             stored_files,
             storage_used_mb: storage_bytes as f64 / (1024.0 * 1024.0),
+            ws_queues: QueueStats::default(),
         })
     }
+
+    /// Count a relayed message towards today's history
+    pub async fn record_relayed_message(&self) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO daily_stats (day, messages_relayed) VALUES (date('now'), 1)
+             ON CONFLICT(day) DO UPDATE SET messages_relayed = messages_relayed + 1",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Store today's user and storage totals in the history, replacing any
+    /// earlier snapshot from today
+    pub async fn record_daily_stats(&self) -> anyhow::Result<()> {
+        let stats = self.get_stats().await?;
+        let storage_bytes = self.get_total_file_size().await?;
+
+        sqlx::query(
+            "INSERT INTO daily_stats (day, total_users, active_users, stored_files, storage_bytes)
+             VALUES (date('now'), ?, ?, ?, ?)
+             ON CONFLICT(day) DO UPDATE SET
+                total_users = excluded.total_users,
+                active_users = excluded.active_users,
+                stored_files = excluded.stored_files,
+                storage_bytes = excluded.storage_bytes",
+        )
+        .bind(stats.total_users)
+        .bind(stats.active_users)
+        .bind(stats.stored_files)
+        .bind(storage_bytes)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// History for the last `days` days including today, oldest first
+    pub async fn get_daily_stats(&self, days: u32) -> anyhow::Result<Vec<DailyStats>> {
+        let stats = sqlx::query_as::<_, DailyStats>(
+            "SELECT day, total_users, active_users, messages_relayed, stored_files, storage_bytes
+             FROM daily_stats
+             WHERE day > date('now', ?)
+             ORDER BY day",
+        )
+        .bind(format!("-{} days", days))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(stats)
+    }
 }
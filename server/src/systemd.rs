@@ -0,0 +1,143 @@
+//! systemd service notifications
+//!
+//! Under `Type=notify` systemd passes a socket in `NOTIFY_SOCKET` and the
+//! server reports there when it is ready, when it stops, and, with
+//! `WatchdogSec=` set, that it is still healthy. Without the socket every
+//! call does nothing, so the server runs the same anywhere else.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::storage::Storage;
+
+/// Send `state`, e.g. `READY=1`; returns whether anyone was listening
+pub fn notify(state: &str) -> bool {
+    match send(state) {
+        Ok(sent) => sent,
+        Err(e) => {
+            tracing::warn!("systemd notification failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Listeners are bound and the database is migrated
+pub fn ready(status: &str) -> bool {
+    notify(&format!("READY=1\nSTATUS={}", status))
+}
+
+/// Graceful shutdown has begun
+pub fn stopping() -> bool {
+    notify("STOPPING=1")
+}
+
+#[cfg(unix)]
+fn send(state: &str) -> std::io::Result<bool> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        // Abstract sockets have no file
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn send(_state: &str) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// How often systemd expects a ping, if the watchdog is on for this
+/// process
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // Set for another process, e.g. a wrapper script that exec'd us
+    if let Some(pid) = pid {
+        if pid.trim().parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.trim().parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Ping the watchdog at half its interval while the database answers, so a
+/// server that hangs is restarted by systemd
+pub fn spawn_watchdog(storage: Arc<Storage>) {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    tracing::info!("systemd watchdog every {:?}", interval);
+    let period = interval / 2;
+    tokio::spawn(async move {
+        loop {
+            match tokio::time::timeout(period, storage.ping()).await {
+                Ok(Ok(())) => {
+                    notify("WATCHDOG=1");
+                }
+                Ok(Err(e)) => tracing::warn!("Skipping watchdog ping, database check failed: {}", e),
+                Err(_) => tracing::warn!("Skipping watchdog ping, database check timed out"),
+            }
+            tokio::time::sleep(period).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(parse_watchdog(Some("30000000"), None, 7), Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog(Some("30000000"), Some("7"), 7), Some(Duration::from_secs(30)));
+        assert_eq!(parse_watchdog(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 7), None);
+        assert_eq!(parse_watchdog(Some("soon"), None, 7), None);
+        assert_eq!(parse_watchdog(None, None, 7), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = std::env::temp_dir().join(format!("privmsg-notify-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        // The only test that touches NOTIFY_SOCKET
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        assert!(ready("Listening on 127.0.0.1:8443"));
+        assert!(stopping());
+        std::env::remove_var("NOTIFY_SOCKET");
+        assert!(!stopping());
+
+        let mut buf = [0u8; 256];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=Listening on 127.0.0.1:8443");
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STOPPING=1");
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
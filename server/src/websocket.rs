@@ -1,8 +1,193 @@
 //! WebSocket connection management for PrivMsg Server
 
 use dashmap::DashMap;
-use tokio::sync::mpsc;
-use crate::models::{WsServerMessage, PresenceStatus};
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use crate::models::{WsServerMessage, PresenceStatus, QueueStats, RevocationReason};
+
+/// How long a message may wait for room in a full queue before the
+/// connection is given up on as too slow
+const SLOW_CONSUMER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Presence and typing updates are superseded by the next one, so a full
+/// queue drops the oldest of them instead of waiting
+fn is_droppable(message: &WsServerMessage) -> bool {
+    matches!(
+        message,
+        WsServerMessage::Typing { .. }
+            | WsServerMessage::Presence { .. }
+            | WsServerMessage::UserOnline { .. }
+            | WsServerMessage::UserOffline { .. }
+    )
+}
+
+/// The outbox was closed because its socket is gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Push {
+    Queued,
+    /// Queued, at the cost of an older droppable frame
+    Replaced,
+    /// Not queued itself; the queue is full of frames that must not be dropped
+    Dropped,
+}
+
+#[derive(Default)]
+struct Queue {
+    frames: VecDeque<WsServerMessage>,
+    /// Held back until the pending-message replay is queued
+    paused: bool,
+    high_water: usize,
+}
+
+/// Bounded queue of frames waiting to be written to one socket
+///
+/// Droppable frames (see [`is_droppable`]) never wait: when the queue is
+/// full the oldest droppable frame makes room. Everything else waits for
+/// the socket to catch up.
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    ready: Notify,
+    space: Notify,
+    closed: AtomicBool,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            queue: Mutex::new(Queue::default()),
+            capacity: capacity.max(1),
+            ready: Notify::new(),
+            space: Notify::new(),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    fn push(&self, message: WsServerMessage) -> std::result::Result<Push, Box<WsServerMessage>> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut outcome = Push::Queued;
+        if queue.frames.len() >= self.capacity {
+            match queue.frames.iter().position(is_droppable) {
+                Some(oldest) => {
+                    queue.frames.remove(oldest);
+                    outcome = Push::Replaced;
+                }
+                None if is_droppable(&message) => return Ok(Push::Dropped),
+                None => return Err(Box::new(message)),
+            }
+        }
+        queue.frames.push_back(message);
+        queue.high_water = queue.high_water.max(queue.frames.len());
+        drop(queue);
+        self.ready.notify_one();
+        Ok(outcome)
+    }
+
+    /// Queue `message`, waiting for room if it may not be dropped
+    pub async fn send(&self, message: WsServerMessage) -> std::result::Result<(), Closed> {
+        self.send_counted(message).await.map(|_| ())
+    }
+
+    async fn send_counted(&self, mut message: WsServerMessage) -> std::result::Result<Push, Closed> {
+        loop {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(Closed);
+            }
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            match self.push(message) {
+                Ok(outcome) => return Ok(outcome),
+                Err(returned) => message = *returned,
+            }
+            space.await;
+        }
+    }
+
+    /// Queue `message` regardless of capacity, for the last frame a
+    /// connection gets before it is closed
+    pub fn send_now(&self, message: WsServerMessage) {
+        self.queue.lock().unwrap().frames.push_back(message);
+        self.ready.notify_one();
+    }
+
+    /// Hold queued frames back until [`resume_with`](Self::resume_with)
+    pub fn pause(&self) {
+        self.queue.lock().unwrap().paused = true;
+    }
+
+    /// Put `frames` ahead of everything queued while paused and start
+    /// sending again. Live copies of replayed messages are dropped.
+    pub fn resume_with(&self, frames: Vec<WsServerMessage>) {
+        let replayed: HashSet<String> = frames
+            .iter()
+            .flat_map(|frame| match frame {
+                WsServerMessage::MessageBatch { messages } => messages.iter().collect::<Vec<_>>(),
+                WsServerMessage::Message(envelope) => vec![envelope],
+                _ => Vec::new(),
+            })
+            .map(|envelope| envelope.message_id.clone())
+            .collect();
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.frames.retain(|frame| {
+            !matches!(frame, WsServerMessage::Message(envelope) if replayed.contains(&envelope.message_id))
+        });
+        for frame in frames.into_iter().rev() {
+            queue.frames.push_front(frame);
+        }
+        queue.paused = false;
+        queue.high_water = queue.high_water.max(queue.frames.len());
+        drop(queue);
+        self.ready.notify_one();
+        self.space.notify_waiters();
+    }
+
+    /// Next frame for the socket; `None` once closed and drained
+    pub async fn recv(&self) -> Option<WsServerMessage> {
+        loop {
+            let ready = self.ready.notified();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if !queue.paused {
+                    if let Some(frame) = queue.frames.pop_front() {
+                        drop(queue);
+                        self.space.notify_waiters();
+                        return Some(frame);
+                    }
+                }
+                if self.closed.load(Ordering::Acquire) {
+                    return None;
+                }
+            }
+            ready.await;
+        }
+    }
+
+    /// Stop accepting frames; whatever is queued is still delivered
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.queue.lock().unwrap().paused = false;
+        self.ready.notify_one();
+        self.space.notify_waiters();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.queue.lock().unwrap().frames.len()
+    }
+
+    /// Deepest the queue has been
+    pub fn high_water(&self) -> usize {
+        self.queue.lock().unwrap().high_water
+    }
+}
 
 /// Represents an active WebSocket connection
 #[derive(Clone)]
@@ -10,7 +195,9 @@ pub struct Connection {
     #[allow(dead_code)]
     pub user_id: String,
     pub device_id: String,
-    pub sender: mpsc::UnboundedSender<WsServerMessage>,
+    pub outbox: Arc<Outbox>,
+    /// Cancelled to make the socket's handler close the connection
+    pub revoked: CancellationToken,
 }
 
 /// Manages all active WebSocket connections
@@ -19,6 +206,10 @@ pub struct WebSocketManager {
     connections: DashMap<String, Vec<Connection>>,
     /// Map of device_id -> user_id for quick lookup
     device_to_user: DashMap<String, String>,
+    /// Droppable frames discarded because a queue was full
+    dropped_frames: AtomicU64,
+    /// Connections closed for not keeping up
+    slow_disconnects: AtomicU64,
 }
 
 impl WebSocketManager {
@@ -26,27 +217,38 @@ impl WebSocketManager {
         Self {
             connections: DashMap::new(),
             device_to_user: DashMap::new(),
+            dropped_frames: AtomicU64::new(0),
+            slow_disconnects: AtomicU64::new(0),
         }
     }
 
-    /// Register a new connection
-    pub fn register(&self, user_id: &str, device_id: &str, sender: mpsc::UnboundedSender<WsServerMessage>) {
+    /// Register a new connection. The returned token is cancelled when the
+    /// session is revoked.
+    pub fn register(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        outbox: Arc<Outbox>,
+    ) -> CancellationToken {
+        let revoked = CancellationToken::new();
         let connection = Connection {
             user_id: user_id.to_string(),
             device_id: device_id.to_string(),
-            sender,
+            outbox,
+            revoked: revoked.clone(),
         };
 
         // Add to user's connections
         self.connections
             .entry(user_id.to_string())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(connection);
 
         // Map device to user
         self.device_to_user.insert(device_id.to_string(), user_id.to_string());
 
         tracing::info!("Connection registered: user={}, device={}", user_id, device_id);
+        revoked
     }
 
     /// Unregister a connection
@@ -66,6 +268,29 @@ impl WebSocketManager {
         }
     }
 
+    /// Tell a device its session is over and disconnect it
+    pub fn revoke_device(&self, device_id: &str, reason: RevocationReason) {
+        let connection = self.device_to_user.get(device_id).and_then(|user_id| {
+            self.connections
+                .get(user_id.value())
+                .and_then(|connections| connections.iter().find(|c| c.device_id == device_id).cloned())
+        });
+
+        if let Some(conn) = connection {
+            conn.outbox.send_now(WsServerMessage::SessionRevoked { reason });
+            conn.revoked.cancel();
+            self.unregister(device_id);
+            tracing::info!("Session revoked: device={}, reason={:?}", device_id, reason);
+        }
+    }
+
+    /// Revoke every connected device of a user
+    pub fn revoke_user(&self, user_id: &str, reason: RevocationReason) {
+        for device_id in self.get_user_devices(user_id) {
+            self.revoke_device(&device_id, reason);
+        }
+    }
+
     /// Check if a user is online (has any active connections)
     pub fn is_user_online(&self, user_id: &str) -> bool {
         self.connections.get(user_id).map(|c| !c.is_empty()).unwrap_or(false)
@@ -84,80 +309,129 @@ impl WebSocketManager {
             .unwrap_or_default()
     }
 
-    /// Send message to a specific user (all devices)
-    pub fn send_to_user(&self, user_id: &str, message: WsServerMessage) {
-        if let Some(connections) = self.connections.get(user_id) {
-            for conn in connections.iter() {
-                if let Err(e) = conn.sender.send(message.clone()) {
-                    tracing::warn!("Failed to send to device {}: {}", conn.device_id, e);
-                }
+    fn user_connections(&self, user_id: &str) -> Vec<Connection> {
+        self.connections.get(user_id).map(|c| c.clone()).unwrap_or_default()
+    }
+
+    /// Queue `message` for one connection. A connection that can't take a
+    /// message within [`SLOW_CONSUMER_TIMEOUT`] is disconnected; the client
+    /// gets stored messages again when it reconnects.
+    async fn deliver(&self, conn: &Connection, message: WsServerMessage) {
+        match tokio::time::timeout(SLOW_CONSUMER_TIMEOUT, conn.outbox.send_counted(message)).await {
+            Ok(Ok(Push::Queued)) => {}
+            Ok(Ok(Push::Replaced | Push::Dropped)) => {
+                self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Err(Closed)) => {
+                tracing::debug!("Dropped frame for closed connection: device={}", conn.device_id);
+            }
+            Err(_) => {
+                tracing::warn!(
+                    "Disconnecting slow consumer: device={}, queued={}",
+                    conn.device_id,
+                    conn.outbox.depth()
+                );
+                self.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                conn.revoked.cancel();
+                self.unregister(&conn.device_id);
             }
         }
     }
 
+    /// Send message to a specific user (all devices)
+    pub async fn send_to_user(&self, user_id: &str, message: WsServerMessage) {
+        for conn in self.user_connections(user_id) {
+            self.deliver(&conn, message.clone()).await;
+        }
+    }
+
     /// Send message to a specific device
-    pub fn send_to_device(&self, device_id: &str, message: WsServerMessage) {
-        if let Some(user_id) = self.device_to_user.get(device_id) {
-            if let Some(connections) = self.connections.get(user_id.value()) {
-                for conn in connections.iter() {
-                    if conn.device_id == device_id {
-                        if let Err(e) = conn.sender.send(message) {
-                            tracing::warn!("Failed to send to device {}: {}", device_id, e);
-                        }
-                        return;
-                    }
-                }
-            }
+    pub async fn send_to_device(&self, device_id: &str, message: WsServerMessage) {
+        let Some(user_id) = self.device_to_user.get(device_id).map(|u| u.value().clone()) else {
+            return;
+        };
+        if let Some(conn) = self
+            .user_connections(&user_id)
+            .into_iter()
+            .find(|c| c.device_id == device_id)
+        {
+            self.deliver(&conn, message).await;
         }
     }
 
     /// Send message to all devices of a user except the specified one
-    pub fn send_to_other_devices(&self, user_id: &str, exclude_device_id: &str, message: WsServerMessage) {
-        if let Some(connections) = self.connections.get(user_id) {
-            for conn in connections.iter() {
-                if conn.device_id != exclude_device_id {
-                    if let Err(e) = conn.sender.send(message.clone()) {
-                        tracing::warn!("Failed to send to device {}: {}", conn.device_id, e);
-                    }
-                }
+    pub async fn send_to_other_devices(&self, user_id: &str, exclude_device_id: &str, message: WsServerMessage) {
+        for conn in self.user_connections(user_id) {
+            if conn.device_id != exclude_device_id {
+                self.deliver(&conn, message.clone()).await;
             }
         }
     }
 
+    /// Send message to every connected device
+    pub async fn broadcast_all(&self, message: WsServerMessage) {
+        let connections: Vec<Connection> = self
+            .connections
+            .iter()
+            .flat_map(|entry| entry.value().clone())
+            .collect();
+        for conn in connections {
+            self.deliver(&conn, message.clone()).await;
+        }
+    }
+
     /// Broadcast presence change to all contacts
-    pub fn broadcast_presence(&self, user_id: &str, status: PresenceStatus, contact_ids: &[String]) {
+    pub async fn broadcast_presence(&self, user_id: &str, status: PresenceStatus, contact_ids: &[String]) {
         let message = WsServerMessage::Presence {
             user_id: user_id.to_string(),
             status,
         };
 
         for contact_id in contact_ids {
-            self.send_to_user(contact_id, message.clone());
+            self.send_to_user(contact_id, message.clone()).await;
         }
     }
 
     /// Broadcast user online notification
-    pub fn broadcast_user_online(&self, user_id: &str, contact_ids: &[String]) {
+    pub async fn broadcast_user_online(&self, user_id: &str, contact_ids: &[String]) {
         let message = WsServerMessage::UserOnline {
             user_id: user_id.to_string(),
         };
 
         for contact_id in contact_ids {
-            self.send_to_user(contact_id, message.clone());
+            self.send_to_user(contact_id, message.clone()).await;
         }
     }
 
     /// Broadcast user offline notification
-    pub fn broadcast_user_offline(&self, user_id: &str, contact_ids: &[String]) {
+    pub async fn broadcast_user_offline(&self, user_id: &str, contact_ids: &[String]) {
         let message = WsServerMessage::UserOffline {
             user_id: user_id.to_string(),
         };
 
         for contact_id in contact_ids {
-            self.send_to_user(contact_id, message.clone());
+            self.send_to_user(contact_id, message.clone()).await;
         }
     }
 
+    /// Queue depths across all connections
+    pub fn queue_stats(&self) -> QueueStats {
+        let mut stats = QueueStats {
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed) as i64,
+            slow_disconnects: self.slow_disconnects.load(Ordering::Relaxed) as i64,
+            ..QueueStats::default()
+        };
+        for entry in self.connections.iter() {
+            for conn in entry.value() {
+                let depth = conn.outbox.depth() as i64;
+                stats.queued_frames += depth;
+                stats.max_depth = stats.max_depth.max(depth);
+                stats.high_water = stats.high_water.max(conn.outbox.high_water() as i64);
+            }
+        }
+        stats
+    }
+
     /// Get all online user IDs
     pub fn get_online_users(&self) -> Vec<String> {
         self.connections.iter().map(|entry| entry.key().clone()).collect()
@@ -177,16 +451,13 @@ mod tests {
     #[test]
     fn test_connection_management() {
         let manager = WebSocketManager::new();
-        let (tx, _rx) = mpsc::unbounded_channel();
-
         // Register connection
-        manager.register("user1", "device1", tx.clone());
+        manager.register("user1", "device1", Outbox::new(8));
         assert!(manager.is_user_online("user1"));
         assert!(!manager.is_user_online("user2"));
 
         // Register another device for same user
-        let (tx2, _rx2) = mpsc::unbounded_channel();
-        manager.register("user1", "device2", tx2);
+        manager.register("user1", "device2", Outbox::new(8));
         assert_eq!(manager.get_user_devices("user1").len(), 2);
 
         // Unregister one device
@@ -198,4 +469,112 @@ mod tests {
         manager.unregister("device2");
         assert!(!manager.is_user_online("user1"));
     }
+
+    #[tokio::test]
+    async fn test_revoke_device() {
+        let manager = WebSocketManager::new();
+        let outbox = Outbox::new(8);
+        let revoked = manager.register("user1", "device1", outbox.clone());
+        let other = manager.register("user1", "device2", Outbox::new(8));
+
+        manager.revoke_device("device1", RevocationReason::DeviceRemoved);
+        assert!(revoked.is_cancelled());
+        assert!(!other.is_cancelled());
+        assert_eq!(manager.get_user_devices("user1"), vec!["device2".to_string()]);
+        assert!(matches!(
+            outbox.recv().await,
+            Some(WsServerMessage::SessionRevoked { reason: RevocationReason::DeviceRemoved })
+        ));
+
+        manager.revoke_user("user1", RevocationReason::AccountDeleted);
+        assert!(other.is_cancelled());
+        assert!(!manager.is_user_online("user1"));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_all() {
+        let manager = WebSocketManager::new();
+        let outboxes = [Outbox::new(8), Outbox::new(8), Outbox::new(8)];
+        manager.register("user1", "device1", outboxes[0].clone());
+        manager.register("user1", "device2", outboxes[1].clone());
+        manager.register("user2", "device3", outboxes[2].clone());
+
+        manager
+            .broadcast_all(WsServerMessage::Announcement { announcement: None })
+            .await;
+        for outbox in &outboxes {
+            assert!(matches!(
+                outbox.recv().await,
+                Some(WsServerMessage::Announcement { announcement: None })
+            ));
+        }
+    }
+
+    fn message(id: &str) -> WsServerMessage {
+        WsServerMessage::Message(crate::models::MessageEnvelope {
+            message_id: id.to_string(),
+            sender_id: "user2".to_string(),
+            recipient_id: "user1".to_string(),
+            recipient_device_id: None,
+            encrypted_content: String::new(),
+            message_type: crate::models::MessageType::Text,
+            timestamp: 0,
+            ttl_seconds: None,
+            priority: Default::default(),
+            delivery_mode: Default::default(),
+        })
+    }
+
+    fn message_id(frame: Option<WsServerMessage>) -> String {
+        match frame {
+            Some(WsServerMessage::Message(envelope)) => envelope.message_id,
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_backpressure() {
+        let manager = WebSocketManager::new();
+        let outbox = Outbox::new(2);
+        manager.register("user1", "device1", outbox.clone());
+
+        // Presence makes way for newer frames
+        manager.broadcast_user_online("user2", &["user1".to_string()]).await;
+        manager.send_to_user("user1", message("m1")).await;
+        manager.send_to_user("user1", message("m2")).await;
+        assert_eq!(outbox.depth(), 2);
+        assert_eq!(manager.queue_stats().dropped_frames, 1);
+
+        // Messages wait for the socket to catch up
+        let send = manager.send_to_user("user1", message("m3"));
+        tokio::pin!(send);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut send).await.is_err());
+        assert_eq!(message_id(outbox.recv().await), "m1");
+        send.await;
+        assert_eq!(message_id(outbox.recv().await), "m2");
+        assert_eq!(message_id(outbox.recv().await), "m3");
+
+        let stats = manager.queue_stats();
+        assert_eq!((stats.queued_frames, stats.high_water), (0, 2));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_replay_order() {
+        let outbox = Outbox::new(8);
+        outbox.pause();
+        outbox.send(message("live")).await.unwrap();
+        outbox.send(message("m1")).await.unwrap();
+        outbox.resume_with(vec![WsServerMessage::MessageBatch {
+            messages: match message("m1") {
+                WsServerMessage::Message(envelope) => vec![envelope],
+                _ => unreachable!(),
+            },
+        }]);
+
+        assert!(matches!(outbox.recv().await, Some(WsServerMessage::MessageBatch { .. })));
+        assert_eq!(message_id(outbox.recv().await), "live");
+        outbox.close();
+        assert!(outbox.recv().await.is_none());
+        assert_eq!(outbox.send(message("late")).await, Err(Closed));
+    }
 }